@@ -0,0 +1,193 @@
+use crate::{MemoryBank, MemoryLayout};
+
+use std::ops::Range;
+
+/// A named region of the [Input](MemoryBank::Input) or [Output](MemoryBank::Output) bank.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Channel {
+    name: String,
+    bank: MemoryBank,
+    offset: u32,
+    size: u32,
+}
+
+impl Channel {
+    /// The name of the channel.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The bank that contains the channel.
+    pub fn bank(&self) -> MemoryBank {
+        self.bank
+    }
+
+    /// The index of the first value of the channel, relative to the start of its bank.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// The amount of values in the channel.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// The range of indices in the memory slice occupied by the channel.
+    ///
+    /// # Panics
+    /// If the channel does not fit in its bank of `layout`.
+    pub fn range(&self, layout: &MemoryLayout) -> Range<usize> {
+        assert!(
+            self.offset + self.size <= layout.bank_size(self.bank),
+            "channel `{}` does not fit in the {:?} bank",
+            self.name,
+            self.bank,
+        );
+        let start = (layout.bank_start(self.bank) + self.offset) as usize;
+        start..start + self.size as usize
+    }
+
+    /// The values of the channel in a memory slice laid out according to `layout`, such as the
+    /// [layout](crate::RunnerInfo::layout) of a runner.
+    pub fn get<'a>(&self, layout: &MemoryLayout, memory: &'a [i64]) -> &'a [i64] {
+        &memory[self.range(layout)]
+    }
+
+    /// The values of the channel mutably, see [get](Self::get).
+    pub fn get_mut<'a>(&self, layout: &MemoryLayout, memory: &'a mut [i64]) -> &'a mut [i64] {
+        &mut memory[self.range(layout)]
+    }
+}
+
+/// Splits the [Input](MemoryBank::Input) and [Output](MemoryBank::Output) banks into named
+/// channels, such as "vision" and "proprioception" inputs and "action" and "value" outputs.
+///
+/// The channels of a bank are laid out next to each other in the order they were added. When
+/// compiling with channels (see [Compiler::set_channels](crate::Compiler::set_channels)), the
+/// input load and output store instructions are split into one group per channel, so a load
+/// always reads from one channel instead of from anywhere in the bank. A bank without channels
+/// is treated as a single channel that spans the whole bank.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Channels {
+    inputs: Vec<Channel>,
+    outputs: Vec<Channel>,
+}
+
+impl Channels {
+    /// Create an empty set of channels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The same channels, with an input channel of `size` values appended.
+    ///
+    /// # Panics
+    /// If there already is an input channel with the same name.
+    pub fn with_input(mut self, name: impl Into<String>, size: u32) -> Self {
+        push(&mut self.inputs, MemoryBank::Input, name.into(), size);
+        self
+    }
+
+    /// The same channels, with an output channel of `size` values appended.
+    ///
+    /// # Panics
+    /// If there already is an output channel with the same name.
+    pub fn with_output(mut self, name: impl Into<String>, size: u32) -> Self {
+        push(&mut self.outputs, MemoryBank::Output, name.into(), size);
+        self
+    }
+
+    /// The input channels, in the order they are laid out.
+    pub fn inputs(&self) -> &[Channel] {
+        &self.inputs
+    }
+
+    /// The output channels, in the order they are laid out.
+    pub fn outputs(&self) -> &[Channel] {
+        &self.outputs
+    }
+
+    /// The input channel with the given name.
+    pub fn input(&self, name: &str) -> Option<&Channel> {
+        self.inputs.iter().find(|channel| channel.name == name)
+    }
+
+    /// The output channel with the given name.
+    pub fn output(&self, name: &str) -> Option<&Channel> {
+        self.outputs.iter().find(|channel| channel.name == name)
+    }
+
+    /// The combined size of the input channels.
+    pub fn input_size(&self) -> u32 {
+        end(&self.inputs)
+    }
+
+    /// The combined size of the output channels.
+    pub fn output_size(&self) -> u32 {
+        end(&self.outputs)
+    }
+
+    /// A layout with `memory_size` values of memory, and input and output banks that fit the
+    /// channels exactly.
+    pub fn layout(&self, memory_size: u32) -> MemoryLayout {
+        MemoryLayout::new(memory_size, self.output_size(), self.input_size())
+    }
+}
+
+fn push(channels: &mut Vec<Channel>, bank: MemoryBank, name: String, size: u32) {
+    assert!(
+        channels.iter().all(|channel| channel.name != name),
+        "duplicate {bank:?} channel `{name}`",
+    );
+    let offset = end(channels);
+    offset.checked_add(size).expect("channels too large");
+    channels.push(Channel {
+        name,
+        bank,
+        offset,
+        size,
+    });
+}
+
+fn end(channels: &[Channel]) -> u32 {
+    channels
+        .last()
+        .map_or(0, |channel| channel.offset + channel.size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_contiguous() {
+        let channels = Channels::new()
+            .with_input("vision", 3)
+            .with_input("proprioception", 2)
+            .with_output("action", 4)
+            .with_output("value", 1);
+        let layout = channels.layout(2);
+
+        assert_eq!(layout, MemoryLayout::new(2, 5, 5));
+        let vision = channels.input("vision").unwrap();
+        assert_eq!(vision.range(&layout), 7..10);
+        let proprioception = channels.input("proprioception").unwrap();
+        assert_eq!(proprioception.offset(), 3);
+        assert_eq!(proprioception.range(&layout), 10..12);
+        assert_eq!(channels.output("value").unwrap().range(&layout), 6..7);
+        assert_eq!(channels.output("vision"), None);
+
+        let mut memory: Vec<_> = (0..12).collect();
+        assert_eq!(vision.get(&layout, &memory), [7, 8, 9]);
+        proprioception
+            .get_mut(&layout, &mut memory)
+            .copy_from_slice(&[-1, -2]);
+        assert_eq!(memory[10..], [-1, -2]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn duplicate_name() {
+        let _ = Channels::new().with_input("a", 1).with_input("a", 2);
+    }
+}
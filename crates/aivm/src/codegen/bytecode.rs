@@ -0,0 +1,1108 @@
+use crate::{
+    codegen::{
+        self,
+        fuel::{self, Fuel},
+        instruction::{Decoder, InstructionSink},
+        Instruction,
+    },
+    compile::REGISTER_COUNT,
+    CompareKind, DeadlineExceeded, HostFunctions, MemoryBank, MemoryLayout, OutputInit, StepResult,
+};
+
+use std::{fmt, num::NonZeroU32, sync::Arc, time::Instant};
+
+const MAGIC: &[u8; 8] = b"AIVMBC\0\0";
+/// The version of the serialized format, which only changes when saved programs can no longer
+/// be loaded by an older version of this crate.
+///
+/// Version 2 added the [OutputInit] of the layout. Programs that clear the output are still
+/// saved in version 1, so older versions of this crate can load them. Version 3 added the
+/// `host_call` instruction, only programs that use it are saved in version 3. Version 4 added
+/// the constants, only programs that have constants are saved in version 4.
+const FORMAT_VERSION: u16 = 5;
+
+/// A code generator that lowers code to a portable bytecode, see [Program].
+///
+/// The bytecode only contains decoded instructions, so it does not depend on the
+/// [frequencies](crate::InstructionFrequencies) the code was compiled with. This makes it
+/// suitable for saving trained agents that must keep running identically.
+#[derive(Debug, Default)]
+pub struct Bytecode {
+    functions: Vec<Vec<Instruction>>,
+    layout: MemoryLayout,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+}
+
+impl Bytecode {
+    /// Create a new generator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl codegen::private::CodeGeneratorImpl for Bytecode {
+    type Runner = Program;
+    type Emitter<'a> = Decoder<Emitter<'a>>;
+
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.host = host.clone();
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.functions.clear();
+        self.functions
+            .resize(usize::try_from(function_count.get()).unwrap(), vec![]);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Decoder(Emitter {
+            func: &mut self.functions[usize::try_from(idx).unwrap()],
+        })
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        let mut program = Program::new(std::mem::take(&mut self.functions), self.layout);
+        program.host = self.host.clone();
+        program.constants = self.constants.clone();
+        program
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Vec<Instruction>,
+}
+
+impl<'a> InstructionSink for Emitter<'a> {
+    fn instruction(&mut self, instruction: Instruction) {
+        self.func.push(instruction);
+    }
+}
+
+/// Runner returned by the [Bytecode] code generator.
+///
+/// The program can be saved with [to_bytes](Self::to_bytes) and loaded again with
+/// [from_bytes](Self::from_bytes). The serialized format is versioned and uses little endian
+/// numbers everywhere, so it can be loaded on any host by this and later versions of this crate.
+///
+/// Host functions are not saved, a loaded program that calls the host needs them to be set
+/// again with [set_host_functions](Self::set_host_functions).
+#[derive(Debug, Clone)]
+pub struct Program {
+    functions: Vec<Vec<Instruction>>,
+    layout: MemoryLayout,
+    max_call_depth: usize,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+}
+
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        // The host functions can not be compared and are not part of the saved program
+        self.functions == other.functions
+            && self.layout == other.layout
+            && self.constants == other.constants
+    }
+}
+
+impl Eq for Program {}
+
+impl Program {
+    fn new(functions: Vec<Vec<Instruction>>, layout: MemoryLayout) -> Self {
+        // Callees always have a higher index than their caller, so visiting the functions in
+        // reverse order means the depth of every callee is known when it's needed.
+        let mut depths = vec![1; functions.len()];
+        for f in (0..functions.len()).rev() {
+            depths[f] += functions[f]
+                .iter()
+                .filter_map(|inst| match inst {
+                    Instruction::Call { idx } => Some(depths[usize::try_from(*idx).unwrap()]),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+        }
+
+        Self {
+            max_call_depth: depths[0],
+            functions,
+            layout,
+            host: HostFunctions::new(),
+            constants: Arc::new([]),
+        }
+    }
+
+    /// The memory layout the program was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The instructions of every function, indexed by function.
+    pub fn functions(&self) -> &[Vec<Instruction>] {
+        &self.functions
+    }
+
+    /// The values of the [Const](MemoryBank::Const) bank.
+    pub fn constants(&self) -> &[i64] {
+        &self.constants
+    }
+
+    /// The amount of host functions the program needs, which is one more than the highest id
+    /// that it calls.
+    pub fn host_function_count(&self) -> u32 {
+        self.functions
+            .iter()
+            .flatten()
+            .filter_map(|inst| match inst {
+                Instruction::HostCall { id } => Some(id + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Set the functions that `host_call` instructions call, replacing the ones the program was
+    /// compiled with.
+    ///
+    /// # Panics
+    /// If there are fewer functions than [host_function_count](Self::host_function_count).
+    pub fn set_host_functions(&mut self, host: HostFunctions) {
+        assert!(host.len() >= self.host_function_count());
+        self.host = host;
+    }
+
+    /// Serialize the program, so it can be loaded with [from_bytes](Self::from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let version: u16 = if self.layout.scratch_size != 0 {
+            5
+        } else if !self.constants.is_empty() {
+            4
+        } else if self.host_function_count() > 0 {
+            3
+        } else if self.layout.output_init != OutputInit::Clear {
+            2
+        } else {
+            1
+        };
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_le_bytes());
+        for size in [
+            self.layout.memory_size,
+            self.layout.output_size,
+            self.layout.input_size,
+        ] {
+            write_u32(&mut bytes, size);
+        }
+        if version >= 2 {
+            match self.layout.output_init {
+                OutputInit::Clear => bytes.push(0),
+                OutputInit::Keep => bytes.push(1),
+                OutputInit::Fill(value) => {
+                    bytes.push(2);
+                    bytes.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+        if version >= 4 {
+            write_u32(&mut bytes, self.constants.len() as u32);
+            for value in self.constants.iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        if version >= 5 {
+            write_u32(&mut bytes, self.layout.scratch_size);
+        }
+
+        write_u32(&mut bytes, self.functions.len() as u32);
+        for func in &self.functions {
+            write_u32(&mut bytes, func.len() as u32);
+            for &instruction in func {
+                write_instruction(&mut bytes, instruction);
+            }
+        }
+
+        bytes
+    }
+
+    /// Load a program that was serialized with [to_bytes](Self::to_bytes).
+    ///
+    /// Every instruction is validated, so a program that loads successfully can not access
+    /// memory or registers out of bounds.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(LoadError::Malformed);
+        }
+
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version > FORMAT_VERSION {
+            return Err(LoadError::UnsupportedVersion { found: version });
+        }
+
+        let layout = MemoryLayout::new(reader.u32()?, reader.u32()?, reader.u32()?);
+        let layout = if version >= 2 {
+            layout.with_output_init(reader.output_init()?)
+        } else {
+            layout
+        };
+        let constants = if version >= 4 {
+            let count = reader.u32()?;
+            (0..count)
+                .map(|_| reader.i64())
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![]
+        };
+        let layout = layout.with_const_size(constants.len() as u32);
+        let layout = if version >= 5 {
+            layout.with_scratch_size(reader.u32()?)
+        } else {
+            layout
+        };
+        layout
+            .memory_size
+            .checked_add(layout.output_size)
+            .and_then(|size| size.checked_add(layout.input_size))
+            .and_then(|size| size.checked_add(layout.scratch_size))
+            .ok_or(LoadError::Malformed)?;
+
+        let function_count = reader.u32()?;
+        if function_count == 0 {
+            return Err(LoadError::Malformed);
+        }
+        let functions = (0..function_count)
+            .map(|f| {
+                let instruction_count = reader.u32()?;
+                let mut func = vec![];
+                for i in 0..instruction_count {
+                    let instruction = reader.instruction()?;
+                    if version < 3 && matches!(instruction, Instruction::HostCall { .. }) {
+                        return Err(LoadError::Malformed);
+                    }
+                    let remaining = instruction_count - i - 1;
+                    if !is_valid(instruction, f, function_count, remaining, layout) {
+                        return Err(LoadError::Malformed);
+                    }
+                    func.push(instruction);
+                }
+
+                Ok(func)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !reader.0.is_empty() {
+            return Err(LoadError::Malformed);
+        }
+
+        let mut program = Self::new(functions, layout);
+        program.constants = constants.into();
+        Ok(program)
+    }
+
+    fn run(&self, memory: &mut [i64], fuel: &mut Fuel) -> Result<(), OutOfFuel> {
+        assert!(self.layout.size() as usize <= memory.len());
+        self.layout.init_step(memory);
+
+        let mut frames = Vec::with_capacity(self.max_call_depth * REGISTER_COUNT);
+        self.call_function(memory, &mut frames, 0, fuel)
+    }
+
+    /// Run a function, consuming one unit of `fuel` for every executed instruction.
+    fn call_function(
+        &self,
+        memory: &mut [i64],
+        frames: &mut Vec<i64>,
+        idx: u32,
+        fuel: &mut Fuel,
+    ) -> Result<(), OutOfFuel> {
+        use Instruction::*;
+
+        let frame_start = frames.len();
+        frames.resize(frame_start + REGISTER_COUNT, 0);
+
+        let func = &self.functions[usize::try_from(idx).unwrap()];
+        let mut pc = 0;
+        while let Some(&instruction) = func.get(pc) {
+            pc += 1;
+            if !fuel.consume() {
+                return Err(OutOfFuel);
+            }
+
+            if let Call { idx } = instruction {
+                self.call_function(memory, frames, idx, fuel)?;
+                continue;
+            }
+
+            let regs = &mut frames[frame_start..];
+            let reg = |r: u8| regs[usize::from(r)];
+            let value = match instruction {
+                Call { .. } => unreachable!(),
+                Nop => continue,
+                HostCall { id } => {
+                    self.host
+                        .call(id, &mut memory[..self.layout.size() as usize]);
+                    continue;
+                }
+
+                IntAdd { dst, a, b } => (dst, reg(a).wrapping_add(reg(b))),
+                IntSub { dst, a, b } => (dst, reg(a).wrapping_sub(reg(b))),
+                IntMul { dst, a, b } => (dst, reg(a).wrapping_mul(reg(b))),
+                IntMulHigh { dst, a, b } => {
+                    let product = i128::from(reg(a)) * i128::from(reg(b));
+                    (dst, (product >> 64) as i64)
+                }
+                IntMulHighUnsigned { dst, a, b } => {
+                    let product = u128::from(reg(a) as u64) * u128::from(reg(b) as u64);
+                    (dst, (product >> 64) as i64)
+                }
+                IntNeg { dst, src } => (dst, reg(src).wrapping_neg()),
+                IntAbs { dst, src } => (dst, reg(src).wrapping_abs()),
+                IntInc { dst } => (dst, reg(dst).wrapping_add(1)),
+                IntDec { dst } => (dst, reg(dst).wrapping_sub(1)),
+                IntMin { dst, a, b } => (dst, reg(a).min(reg(b))),
+                IntMax { dst, a, b } => (dst, reg(a).max(reg(b))),
+
+                BitOr { dst, a, b } => (dst, reg(a) | reg(b)),
+                BitAnd { dst, a, b } => (dst, reg(a) & reg(b)),
+                BitXor { dst, a, b } => (dst, reg(a) ^ reg(b)),
+                BitNot { dst, src } => (dst, !reg(src)),
+                BitShiftLeft { dst, src, amount } => (dst, reg(src) << amount),
+                BitShiftRight { dst, src, amount } => (dst, reg(src) >> amount),
+                BitRotateLeft { dst, src, amount } => {
+                    (dst, reg(src).rotate_left(u32::from(amount)))
+                }
+                BitRotateRight { dst, src, amount } => {
+                    (dst, reg(src).rotate_right(u32::from(amount)))
+                }
+                BitSelect { dst, mask, a, b } => {
+                    let mask = reg(mask);
+                    (dst, (reg(a) & mask) | (reg(b) & !mask))
+                }
+                BitPopcnt { dst, src } => (dst, i64::from(reg(src).count_ones())),
+                BitReverse { dst, src } => (dst, reg(src).reverse_bits()),
+
+                BranchCmp {
+                    a,
+                    b,
+                    compare_kind,
+                    offset,
+                } => {
+                    let (a, b) = (reg(a), reg(b));
+                    let taken = match compare_kind {
+                        CompareKind::Eq => a == b,
+                        CompareKind::Neq => a != b,
+                        CompareKind::Gt => a > b,
+                        CompareKind::Lt => a < b,
+                    };
+                    if taken {
+                        pc += offset as usize;
+                    }
+                    continue;
+                }
+                BranchZero { src, offset } => {
+                    if reg(src) == 0 {
+                        pc += offset as usize;
+                    }
+                    continue;
+                }
+                BranchNonZero { src, offset } => {
+                    if reg(src) != 0 {
+                        pc += offset as usize;
+                    }
+                    continue;
+                }
+
+                MemLoad {
+                    dst,
+                    bank: MemoryBank::Const,
+                    addr,
+                } => (dst, self.constants[addr as usize]),
+                MemLoad { dst, bank, addr } => {
+                    (dst, memory[self.layout.address(bank, addr) as usize])
+                }
+                MemStore { bank, addr, src } => {
+                    if bank.is_writable() {
+                        memory[self.layout.address(bank, addr) as usize] = reg(src);
+                    }
+                    continue;
+                }
+            };
+
+            regs[usize::from(value.0)] = value.1;
+        }
+
+        frames.truncate(frame_start);
+
+        Ok(())
+    }
+}
+
+struct OutOfFuel;
+
+impl crate::Runner for Program {
+    fn step(&self, memory: &mut [i64]) {
+        let _ = self.step_bounded(memory, u64::MAX);
+    }
+
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        let mut remaining = Fuel::bounded(fuel);
+        match self.run(memory, &mut remaining) {
+            Ok(()) => StepResult::Completed {
+                fuel_used: fuel - remaining.remaining(),
+            },
+            Err(OutOfFuel) => StepResult::OutOfFuel,
+        }
+    }
+
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        fuel::restore_on_abort(self.layout, memory, |memory| {
+            self.run(memory, &mut Fuel::until(deadline)).is_ok()
+        })
+    }
+}
+
+impl crate::RunnerInfo for Program {
+    fn function_count(&self) -> u32 {
+        self.functions.len() as u32
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.functions.iter().map(Vec::len).sum()
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+/// Whether an instruction of function `f` can be run safely, given the amount of instructions
+/// that follow it in the function.
+fn is_valid(
+    instruction: Instruction,
+    f: u32,
+    function_count: u32,
+    remaining: u32,
+    layout: MemoryLayout,
+) -> bool {
+    use Instruction::*;
+
+    let reg = |r: u8| usize::from(r) < REGISTER_COUNT;
+    match instruction {
+        Call { idx } => idx > f && idx < function_count,
+        Nop | HostCall { .. } => true,
+
+        IntAdd { dst, a, b }
+        | IntSub { dst, a, b }
+        | IntMul { dst, a, b }
+        | IntMulHigh { dst, a, b }
+        | IntMulHighUnsigned { dst, a, b }
+        | IntMin { dst, a, b }
+        | IntMax { dst, a, b }
+        | BitOr { dst, a, b }
+        | BitAnd { dst, a, b }
+        | BitXor { dst, a, b } => reg(dst) && reg(a) && reg(b),
+        IntNeg { dst, src }
+        | IntAbs { dst, src }
+        | BitNot { dst, src }
+        | BitPopcnt { dst, src }
+        | BitReverse { dst, src } => reg(dst) && reg(src),
+        IntInc { dst } | IntDec { dst } => reg(dst),
+        BitShiftLeft { dst, src, amount }
+        | BitShiftRight { dst, src, amount }
+        | BitRotateLeft { dst, src, amount }
+        | BitRotateRight { dst, src, amount } => reg(dst) && reg(src) && amount < 64,
+        BitSelect { dst, mask, a, b } => reg(dst) && reg(mask) && reg(a) && reg(b),
+
+        BranchCmp { a, b, offset, .. } => reg(a) && reg(b) && offset <= remaining,
+        BranchZero { src, offset } | BranchNonZero { src, offset } => {
+            reg(src) && offset <= remaining
+        }
+
+        MemLoad { dst, bank, addr } => reg(dst) && addr < layout.bank_size(bank),
+        MemStore { bank, addr, src } => reg(src) && addr < layout.bank_size(bank),
+    }
+}
+
+// The opcodes are part of the serialized format, so they must never be changed or reused.
+const OP_CALL: u8 = 0;
+const OP_NOP: u8 = 1;
+const OP_INT_ADD: u8 = 2;
+const OP_INT_SUB: u8 = 3;
+const OP_INT_MUL: u8 = 4;
+const OP_INT_MUL_HIGH: u8 = 5;
+const OP_INT_MUL_HIGH_UNSIGNED: u8 = 6;
+const OP_INT_NEG: u8 = 7;
+const OP_INT_ABS: u8 = 8;
+const OP_INT_INC: u8 = 9;
+const OP_INT_DEC: u8 = 10;
+const OP_INT_MIN: u8 = 11;
+const OP_INT_MAX: u8 = 12;
+const OP_BIT_OR: u8 = 13;
+const OP_BIT_AND: u8 = 14;
+const OP_BIT_XOR: u8 = 15;
+const OP_BIT_NOT: u8 = 16;
+const OP_BIT_SHIFT_LEFT: u8 = 17;
+const OP_BIT_SHIFT_RIGHT: u8 = 18;
+const OP_BIT_ROTATE_LEFT: u8 = 19;
+const OP_BIT_ROTATE_RIGHT: u8 = 20;
+const OP_BIT_SELECT: u8 = 21;
+const OP_BIT_POPCNT: u8 = 22;
+const OP_BIT_REVERSE: u8 = 23;
+const OP_BRANCH_CMP: u8 = 24;
+const OP_BRANCH_ZERO: u8 = 25;
+const OP_BRANCH_NON_ZERO: u8 = 26;
+const OP_MEM_LOAD: u8 = 27;
+const OP_MEM_STORE: u8 = 28;
+const OP_HOST_CALL: u8 = 29;
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_instruction(bytes: &mut Vec<u8>, instruction: Instruction) {
+    use Instruction::*;
+
+    match instruction {
+        Call { idx } => {
+            bytes.push(OP_CALL);
+            write_u32(bytes, idx);
+        }
+        Nop => bytes.push(OP_NOP),
+        HostCall { id } => {
+            bytes.push(OP_HOST_CALL);
+            write_u32(bytes, id);
+        }
+
+        IntAdd { dst, a, b } => bytes.extend_from_slice(&[OP_INT_ADD, dst, a, b]),
+        IntSub { dst, a, b } => bytes.extend_from_slice(&[OP_INT_SUB, dst, a, b]),
+        IntMul { dst, a, b } => bytes.extend_from_slice(&[OP_INT_MUL, dst, a, b]),
+        IntMulHigh { dst, a, b } => bytes.extend_from_slice(&[OP_INT_MUL_HIGH, dst, a, b]),
+        IntMulHighUnsigned { dst, a, b } => {
+            bytes.extend_from_slice(&[OP_INT_MUL_HIGH_UNSIGNED, dst, a, b])
+        }
+        IntNeg { dst, src } => bytes.extend_from_slice(&[OP_INT_NEG, dst, src]),
+        IntAbs { dst, src } => bytes.extend_from_slice(&[OP_INT_ABS, dst, src]),
+        IntInc { dst } => bytes.extend_from_slice(&[OP_INT_INC, dst]),
+        IntDec { dst } => bytes.extend_from_slice(&[OP_INT_DEC, dst]),
+        IntMin { dst, a, b } => bytes.extend_from_slice(&[OP_INT_MIN, dst, a, b]),
+        IntMax { dst, a, b } => bytes.extend_from_slice(&[OP_INT_MAX, dst, a, b]),
+
+        BitOr { dst, a, b } => bytes.extend_from_slice(&[OP_BIT_OR, dst, a, b]),
+        BitAnd { dst, a, b } => bytes.extend_from_slice(&[OP_BIT_AND, dst, a, b]),
+        BitXor { dst, a, b } => bytes.extend_from_slice(&[OP_BIT_XOR, dst, a, b]),
+        BitNot { dst, src } => bytes.extend_from_slice(&[OP_BIT_NOT, dst, src]),
+        BitShiftLeft { dst, src, amount } => {
+            bytes.extend_from_slice(&[OP_BIT_SHIFT_LEFT, dst, src, amount])
+        }
+        BitShiftRight { dst, src, amount } => {
+            bytes.extend_from_slice(&[OP_BIT_SHIFT_RIGHT, dst, src, amount])
+        }
+        BitRotateLeft { dst, src, amount } => {
+            bytes.extend_from_slice(&[OP_BIT_ROTATE_LEFT, dst, src, amount])
+        }
+        BitRotateRight { dst, src, amount } => {
+            bytes.extend_from_slice(&[OP_BIT_ROTATE_RIGHT, dst, src, amount])
+        }
+        BitSelect { dst, mask, a, b } => bytes.extend_from_slice(&[OP_BIT_SELECT, dst, mask, a, b]),
+        BitPopcnt { dst, src } => bytes.extend_from_slice(&[OP_BIT_POPCNT, dst, src]),
+        BitReverse { dst, src } => bytes.extend_from_slice(&[OP_BIT_REVERSE, dst, src]),
+
+        BranchCmp {
+            a,
+            b,
+            compare_kind,
+            offset,
+        } => {
+            let kind = match compare_kind {
+                CompareKind::Eq => 0,
+                CompareKind::Neq => 1,
+                CompareKind::Gt => 2,
+                CompareKind::Lt => 3,
+            };
+            bytes.extend_from_slice(&[OP_BRANCH_CMP, a, b, kind]);
+            write_u32(bytes, offset);
+        }
+        BranchZero { src, offset } => {
+            bytes.extend_from_slice(&[OP_BRANCH_ZERO, src]);
+            write_u32(bytes, offset);
+        }
+        BranchNonZero { src, offset } => {
+            bytes.extend_from_slice(&[OP_BRANCH_NON_ZERO, src]);
+            write_u32(bytes, offset);
+        }
+
+        MemLoad { dst, bank, addr } => {
+            bytes.extend_from_slice(&[OP_MEM_LOAD, dst, bank_code(bank)]);
+            write_u32(bytes, addr);
+        }
+        MemStore { bank, addr, src } => {
+            bytes.extend_from_slice(&[OP_MEM_STORE, src, bank_code(bank)]);
+            write_u32(bytes, addr);
+        }
+    }
+}
+
+fn bank_code(bank: MemoryBank) -> u8 {
+    match bank {
+        MemoryBank::Memory => 0,
+        MemoryBank::Output => 1,
+        MemoryBank::Input => 2,
+        MemoryBank::Const => 3,
+        MemoryBank::Scratch => 4,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if len > self.0.len() {
+            return Err(LoadError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, LoadError> {
+        let bytes = self.take(8)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn output_init(&mut self) -> Result<OutputInit, LoadError> {
+        match self.u8()? {
+            0 => Ok(OutputInit::Clear),
+            1 => Ok(OutputInit::Keep),
+            2 => Ok(OutputInit::Fill(self.i64()?)),
+            _ => Err(LoadError::Malformed),
+        }
+    }
+
+    fn bank(&mut self) -> Result<MemoryBank, LoadError> {
+        match self.u8()? {
+            0 => Ok(MemoryBank::Memory),
+            1 => Ok(MemoryBank::Output),
+            2 => Ok(MemoryBank::Input),
+            3 => Ok(MemoryBank::Const),
+            4 => Ok(MemoryBank::Scratch),
+            _ => Err(LoadError::Malformed),
+        }
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, LoadError> {
+        use Instruction::*;
+
+        let op = self.u8()?;
+        Ok(match op {
+            OP_CALL => Call { idx: self.u32()? },
+            OP_NOP => Nop,
+            OP_HOST_CALL => HostCall { id: self.u32()? },
+
+            OP_INT_ADD
+            | OP_INT_SUB
+            | OP_INT_MUL
+            | OP_INT_MUL_HIGH
+            | OP_INT_MUL_HIGH_UNSIGNED
+            | OP_INT_MIN
+            | OP_INT_MAX
+            | OP_BIT_OR
+            | OP_BIT_AND
+            | OP_BIT_XOR => {
+                let (dst, a, b) = (self.u8()?, self.u8()?, self.u8()?);
+                match op {
+                    OP_INT_ADD => IntAdd { dst, a, b },
+                    OP_INT_SUB => IntSub { dst, a, b },
+                    OP_INT_MUL => IntMul { dst, a, b },
+                    OP_INT_MUL_HIGH => IntMulHigh { dst, a, b },
+                    OP_INT_MUL_HIGH_UNSIGNED => IntMulHighUnsigned { dst, a, b },
+                    OP_INT_MIN => IntMin { dst, a, b },
+                    OP_INT_MAX => IntMax { dst, a, b },
+                    OP_BIT_OR => BitOr { dst, a, b },
+                    OP_BIT_AND => BitAnd { dst, a, b },
+                    _ => BitXor { dst, a, b },
+                }
+            }
+            OP_INT_NEG | OP_INT_ABS | OP_BIT_NOT | OP_BIT_POPCNT | OP_BIT_REVERSE => {
+                let (dst, src) = (self.u8()?, self.u8()?);
+                match op {
+                    OP_INT_NEG => IntNeg { dst, src },
+                    OP_INT_ABS => IntAbs { dst, src },
+                    OP_BIT_NOT => BitNot { dst, src },
+                    OP_BIT_POPCNT => BitPopcnt { dst, src },
+                    _ => BitReverse { dst, src },
+                }
+            }
+            OP_INT_INC => IntInc { dst: self.u8()? },
+            OP_INT_DEC => IntDec { dst: self.u8()? },
+            OP_BIT_SHIFT_LEFT | OP_BIT_SHIFT_RIGHT | OP_BIT_ROTATE_LEFT | OP_BIT_ROTATE_RIGHT => {
+                let (dst, src, amount) = (self.u8()?, self.u8()?, self.u8()?);
+                match op {
+                    OP_BIT_SHIFT_LEFT => BitShiftLeft { dst, src, amount },
+                    OP_BIT_SHIFT_RIGHT => BitShiftRight { dst, src, amount },
+                    OP_BIT_ROTATE_LEFT => BitRotateLeft { dst, src, amount },
+                    _ => BitRotateRight { dst, src, amount },
+                }
+            }
+            OP_BIT_SELECT => BitSelect {
+                dst: self.u8()?,
+                mask: self.u8()?,
+                a: self.u8()?,
+                b: self.u8()?,
+            },
+
+            OP_BRANCH_CMP => {
+                let (a, b) = (self.u8()?, self.u8()?);
+                let compare_kind = match self.u8()? {
+                    0 => CompareKind::Eq,
+                    1 => CompareKind::Neq,
+                    2 => CompareKind::Gt,
+                    3 => CompareKind::Lt,
+                    _ => return Err(LoadError::Malformed),
+                };
+                BranchCmp {
+                    a,
+                    b,
+                    compare_kind,
+                    offset: self.u32()?,
+                }
+            }
+            OP_BRANCH_ZERO => BranchZero {
+                src: self.u8()?,
+                offset: self.u32()?,
+            },
+            OP_BRANCH_NON_ZERO => BranchNonZero {
+                src: self.u8()?,
+                offset: self.u32()?,
+            },
+
+            OP_MEM_LOAD => MemLoad {
+                dst: self.u8()?,
+                bank: self.bank()?,
+                addr: self.u32()?,
+            },
+            OP_MEM_STORE => MemStore {
+                src: self.u8()?,
+                bank: self.bank()?,
+                addr: self.u32()?,
+            },
+
+            _ => return Err(LoadError::Malformed),
+        })
+    }
+}
+
+/// The error returned when a saved bytecode [Program] can not be loaded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The bytes were not produced by [Program::to_bytes], are truncated or contain an invalid
+    /// instruction.
+    Malformed,
+    /// The program was saved in a newer format than this version of the crate supports.
+    UnsupportedVersion {
+        /// The format version of the saved program.
+        found: u16,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed bytecode"),
+            Self::UnsupportedVersion { found } => write!(
+                f,
+                "bytecode saved in format version {found}, supported up to {FORMAT_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::{Interpreter, NullGen},
+        testing::{random_code, random_memory, Xorshift},
+        Compiler, Runner as _,
+    };
+
+    #[test]
+    fn matches_spec() {
+        crate::spec::check(Bytecode::new()).unwrap();
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut rng = Xorshift::default();
+
+        let mut bytecode = Compiler::new(Bytecode::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut null = NullGen::new();
+        null.set_record_instructions(true);
+        let mut null = Compiler::new(null);
+
+        for _ in 0..100 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+
+            let program = bytecode.compile(&code, 3, 8, 4, 4);
+            let loaded = Program::from_bytes(&program.to_bytes()).unwrap();
+            assert_eq!(loaded, program);
+
+            null.compile(&code, 3, 8, 4, 4);
+            let statistics = null.generator().statistics();
+            assert!(program
+                .functions()
+                .iter()
+                .eq(statistics.functions.iter().map(|f| &f.instructions)));
+
+            let reference = interpreter.compile(&code, 3, 8, 4, 4);
+            let mut expected = memory.clone();
+            let result = reference.step_bounded(&mut expected, u64::MAX);
+            let mut actual = memory.clone();
+            assert_eq!(loaded.step_bounded(&mut actual, u64::MAX), result);
+            assert_eq!(actual, expected);
+
+            // Exactly enough fuel completes, one less runs out
+            let StepResult::Completed { fuel_used } = result else {
+                unreachable!()
+            };
+            assert_eq!(loaded.step_bounded(&mut memory.clone(), fuel_used), result);
+            if fuel_used > 0 {
+                assert_eq!(
+                    loaded.step_bounded(&mut memory.clone(), fuel_used - 1),
+                    StepResult::OutOfFuel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stable_format() {
+        let program = Program::new(
+            vec![
+                vec![
+                    Instruction::MemLoad {
+                        dst: 1,
+                        bank: MemoryBank::Input,
+                        addr: 0,
+                    },
+                    Instruction::Call { idx: 1 },
+                ],
+                vec![Instruction::BranchZero { src: 0, offset: 0 }],
+            ],
+            MemoryLayout::new(1, 2, 3),
+        );
+
+        #[rustfmt::skip]
+        let expected = [
+            b'A', b'I', b'V', b'M', b'B', b'C', 0, 0,
+            1, 0,
+            1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0,
+            2, 0, 0, 0,
+            2, 0, 0, 0,
+            OP_MEM_LOAD, 1, 2, 0, 0, 0, 0,
+            OP_CALL, 1, 0, 0, 0,
+            1, 0, 0, 0,
+            OP_BRANCH_ZERO, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(program.to_bytes(), expected);
+    }
+
+    #[test]
+    fn output_init_round_trip() {
+        for output_init in [OutputInit::Clear, OutputInit::Keep, OutputInit::Fill(-3)] {
+            let layout = MemoryLayout::new(1, 2, 3).with_output_init(output_init);
+            let program = Program::new(vec![vec![Instruction::Nop]], layout);
+            let bytes = program.to_bytes();
+            assert_eq!(
+                bytes[8],
+                if output_init == OutputInit::Clear {
+                    1
+                } else {
+                    2
+                }
+            );
+            assert_eq!(Program::from_bytes(&bytes), Ok(program));
+        }
+    }
+
+    #[test]
+    fn host_call() {
+        let mut host = HostFunctions::new();
+        let set = host.register(|memory| memory[1] = 5);
+
+        let layout = MemoryLayout::new(1, 1, 0);
+        let mut program = Program::new(vec![vec![Instruction::HostCall { id: set }]], layout);
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[8], 3);
+        let mut loaded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, program);
+        assert_eq!(loaded.host_function_count(), 1);
+
+        program.set_host_functions(host.clone());
+        loaded.set_host_functions(host);
+        for program in [program, loaded] {
+            let mut memory = [1, 2];
+            program.step(&mut memory);
+            assert_eq!(memory, [1, 5]);
+        }
+
+        // Older versions can not contain host calls
+        let mut older = bytes;
+        older[8] = 2;
+        assert_eq!(Program::from_bytes(&older), Err(LoadError::Malformed));
+    }
+
+    #[test]
+    fn constants_round_trip() {
+        let mut compiler = Compiler::new(Bytecode::new());
+        compiler.set_constants([3, -4]);
+        let program = compiler.compile(&[0; 4], 1, 1, 1, 1);
+        assert_eq!(program.constants(), [3, -4]);
+        assert_eq!(program.layout().const_size, 2);
+
+        let load = Instruction::MemLoad {
+            dst: 0,
+            bank: MemoryBank::Const,
+            addr: 1,
+        };
+        let store = Instruction::MemStore {
+            bank: MemoryBank::Output,
+            addr: 0,
+            src: 0,
+        };
+        let mut program = Program::new(vec![vec![load, store]], program.layout());
+        program.constants = Arc::new([3, -4]);
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[8], 4);
+        let loaded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, program);
+
+        let mut memory = [0, 0, 0];
+        loaded.step(&mut memory);
+        assert_eq!(memory, [0, -4, 0]);
+
+        // The constants can not be read out of bounds
+        let mut truncated = program.clone();
+        truncated.constants = Arc::new([3]);
+        truncated.layout = truncated.layout.with_const_size(1);
+        assert_eq!(
+            Program::from_bytes(&truncated.to_bytes()),
+            Err(LoadError::Malformed)
+        );
+    }
+
+    #[test]
+    fn scratch_round_trip() {
+        let layout = MemoryLayout::new(1, 1, 0).with_scratch_size(2);
+        let program = Program::new(
+            vec![vec![
+                Instruction::MemLoad {
+                    dst: 0,
+                    bank: MemoryBank::Scratch,
+                    addr: 1,
+                },
+                Instruction::IntInc { dst: 0 },
+                Instruction::MemStore {
+                    bank: MemoryBank::Scratch,
+                    addr: 1,
+                    src: 0,
+                },
+                Instruction::MemStore {
+                    bank: MemoryBank::Output,
+                    addr: 0,
+                    src: 0,
+                },
+            ]],
+            layout,
+        );
+        let bytes = program.to_bytes();
+        assert_eq!(bytes[8], 5);
+        let loaded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, program);
+
+        // The scratch bank does not carry over between steps
+        let mut memory = [0, 0, 0, 0];
+        for _ in 0..2 {
+            loaded.step(&mut memory);
+            assert_eq!(memory, [0, 1, 0, 1]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn missing_host_functions() {
+        let program = Program::new(
+            vec![vec![Instruction::HostCall { id: 0 }]],
+            MemoryLayout::new(1, 1, 1),
+        );
+        program.step(&mut [0; 3]);
+    }
+
+    #[test]
+    fn rejects_invalid() {
+        let mut compiler = Compiler::new(Bytecode::new());
+        let bytes = compiler.compile(&[0; 16], 1, 1, 1, 1).to_bytes();
+
+        for len in 0..bytes.len() {
+            assert_eq!(
+                Program::from_bytes(&bytes[..len]),
+                Err(LoadError::Malformed)
+            );
+        }
+
+        let mut newer = bytes.clone();
+        newer[8] = FORMAT_VERSION as u8 + 1;
+        assert_eq!(
+            Program::from_bytes(&newer),
+            Err(LoadError::UnsupportedVersion {
+                found: FORMAT_VERSION + 1
+            })
+        );
+
+        let out_of_bounds = |instruction| {
+            Program::new(vec![vec![instruction]], MemoryLayout::new(1, 1, 1)).to_bytes()
+        };
+        for instruction in [
+            Instruction::Call { idx: 0 },
+            Instruction::IntInc { dst: 64 },
+            Instruction::BitShiftLeft {
+                dst: 0,
+                src: 0,
+                amount: 64,
+            },
+            Instruction::BranchNonZero { src: 0, offset: 1 },
+            Instruction::MemStore {
+                bank: MemoryBank::Output,
+                addr: 1,
+                src: 0,
+            },
+        ] {
+            assert_eq!(
+                Program::from_bytes(&out_of_bounds(instruction)),
+                Err(LoadError::Malformed)
+            );
+        }
+    }
+}
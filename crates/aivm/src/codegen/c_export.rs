@@ -0,0 +1,780 @@
+use crate::{
+    codegen,
+    compile::{CompareKind, REGISTER_COUNT},
+    MemoryBank, MemoryLayout,
+};
+
+use std::{
+    fmt::{self, Write},
+    num::NonZeroU32,
+    sync::Arc,
+};
+#[cfg(feature = "c-export-cc")]
+use std::{
+    fs, io,
+    io::{Read, Write as _},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Functions for the operations that have no portable C operator. Two's complement
+/// conversions between `int64_t` and `uint64_t` are assumed, like on every platform in use.
+const HELPERS: &str = "\
+static inline uint64_t aivm_mul_high_unsigned(uint64_t a, uint64_t b) {
+    uint64_t a_lo = a & 0xFFFFFFFF, a_hi = a >> 32;
+    uint64_t b_lo = b & 0xFFFFFFFF, b_hi = b >> 32;
+    uint64_t lo_lo = a_lo * b_lo, hi_lo = a_hi * b_lo, lo_hi = a_lo * b_hi;
+    uint64_t cross = (lo_lo >> 32) + (hi_lo & 0xFFFFFFFF) + lo_hi;
+    return (hi_lo >> 32) + (cross >> 32) + a_hi * b_hi;
+}
+
+static inline uint64_t aivm_mul_high(uint64_t a, uint64_t b) {
+    uint64_t high = aivm_mul_high_unsigned(a, b);
+    if ((int64_t)a < 0) high -= b;
+    if ((int64_t)b < 0) high -= a;
+    return high;
+}
+
+static inline uint64_t aivm_shift_right(uint64_t x, unsigned n) {
+    uint64_t sign = 0 - (x >> 63);
+    return n == 0 ? x : (x >> n) | (sign << (64 - n));
+}
+
+static inline uint64_t aivm_rotate_left(uint64_t x, unsigned n) {
+    return (x << n) | (x >> ((64 - n) & 63));
+}
+
+static inline uint64_t aivm_rotate_right(uint64_t x, unsigned n) {
+    return (x >> n) | (x << ((64 - n) & 63));
+}
+
+static inline uint64_t aivm_popcnt(uint64_t x) {
+    uint64_t count = 0;
+    for (; x != 0; x &= x - 1) count++;
+    return count;
+}
+
+static inline uint64_t aivm_reverse(uint64_t x) {
+    uint64_t reversed = 0;
+    for (int i = 0; i < 64; i++, x >>= 1) reversed = (reversed << 1) | (x & 1);
+    return reversed;
+}
+";
+
+/// A code generator that translates AIVM code into a self-contained C source file.
+///
+/// Instead of a runner that can be called directly, compiling produces a [CSource] that can be
+/// compiled by any C99 compiler, for example to embed a trained agent in firmware. C source can
+/// not call back into the host, so compiling code that reaches a host call instruction returns
+/// an error.
+pub struct CExport {
+    symbol: String,
+    layout: MemoryLayout,
+    constants: Arc<[i64]>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Default)]
+struct Function {
+    body: String,
+    /// Whether the body uses the registers, so they have to be declared without a warning about
+    /// an unused variable.
+    uses_registers: bool,
+    calls: Vec<u32>,
+    host_call: bool,
+    /// The amount of VM instructions, set when the function is finalized.
+    instruction_count: u32,
+}
+
+impl codegen::private::CodeGeneratorImpl for CExport {
+    type Runner = Result<CSource, HostCallUnsupported>;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.functions.clear();
+        self.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            Function::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Emitter {
+            func: &mut self.functions[usize::try_from(idx).unwrap()],
+            symbol: &self.symbol,
+            layout: self.layout,
+            constants: &self.constants,
+            instruction_count: 0,
+            branch_targets: vec![],
+        }
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        let symbol = &self.symbol;
+        let layout = self.layout;
+        let mut source = String::new();
+
+        writeln!(
+            source,
+            "/* Generated by aivm {}. */\n\n#include <stddef.h>\n#include <stdint.h>\n",
+            env!("CARGO_PKG_VERSION"),
+        )
+        .unwrap();
+        source.push_str(HELPERS);
+
+        // Leave out functions that are never called, C compilers warn about them
+        let mut reachable = vec![false; self.functions.len()];
+        let mut stack = vec![0];
+        reachable[0] = true;
+        while let Some(f) = stack.pop() {
+            for &callee in &self.functions[f].calls {
+                let callee = usize::try_from(callee).unwrap();
+                if !reachable[callee] {
+                    reachable[callee] = true;
+                    stack.push(callee);
+                }
+            }
+        }
+        let functions = || {
+            self.functions
+                .iter()
+                .enumerate()
+                .filter(|&(f, _)| reachable[f])
+        };
+        if let Some((f, _)) = functions().find(|(_, func)| func.host_call) {
+            return Err(HostCallUnsupported { function: f as u32 });
+        }
+
+        for (f, _) in functions() {
+            write!(
+                source,
+                "\nstatic void {symbol}_function_{f}(int64_t *memory);"
+            )
+            .unwrap();
+        }
+        source.push('\n');
+        for (f, func) in functions() {
+            write!(
+                source,
+                "\nstatic void {symbol}_function_{f}(int64_t *memory) {{\n"
+            )
+            .unwrap();
+            if func.uses_registers {
+                writeln!(source, "    uint64_t r[{REGISTER_COUNT}] = {{0}};").unwrap();
+            }
+            writeln!(source, "{}}}", func.body).unwrap();
+        }
+
+        let output = layout.bank_range(MemoryBank::Output);
+        write!(source, "\nvoid {symbol}(int64_t *memory) {{\n").unwrap();
+        if let (Some(value), false) = (layout.output_init.value(), output.is_empty()) {
+            // The most negative value can not be written as a literal
+            writeln!(
+                source,
+                "    for (size_t i = {}; i < {}; i++) memory[i] = (int64_t)UINT64_C({:#x});",
+                output.start, output.end, value as u64,
+            )
+            .unwrap();
+        }
+        let scratch = layout.bank_range(MemoryBank::Scratch);
+        if !scratch.is_empty() {
+            writeln!(
+                source,
+                "    for (size_t i = {}; i < {}; i++) memory[i] = 0;",
+                scratch.start, scratch.end,
+            )
+            .unwrap();
+        }
+        writeln!(source, "    {symbol}_function_0(memory);\n}}").unwrap();
+
+        Ok(CSource {
+            source,
+            symbol: symbol.clone(),
+            function_count: self.functions.len() as u32,
+            instruction_count: self
+                .functions
+                .iter()
+                .map(|func| func.instruction_count as usize)
+                .sum(),
+            layout,
+        })
+    }
+}
+
+impl CExport {
+    /// Create a new generator, the entry point of the generated code will be named `aivm_step`.
+    pub fn new() -> Self {
+        Self::with_symbol("aivm_step")
+    }
+
+    /// Create a new generator, the entry point of the generated code will be named `symbol`.
+    ///
+    /// The other functions in the source are static and their names start with `symbol`, so
+    /// the code of multiple agents can be linked into one binary.
+    ///
+    /// # Panics
+    /// If `symbol` is not a valid C identifier.
+    pub fn with_symbol(symbol: impl Into<String>) -> Self {
+        let symbol = symbol.into();
+        assert!(
+            symbol.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && symbol
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "`{symbol}` is not a valid C identifier",
+        );
+
+        Self {
+            symbol,
+            layout: MemoryLayout::default(),
+            constants: Arc::new([]),
+            functions: vec![],
+        }
+    }
+}
+
+impl Default for CExport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The error returned by the [CExport] code generator when the code calls the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostCallUnsupported {
+    /// The first function that is called by the code and contains a host call instruction.
+    pub function: u32,
+}
+
+impl fmt::Display for HostCallUnsupported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "function {} calls the host, which C source can not do",
+            self.function
+        )
+    }
+}
+
+impl std::error::Error for HostCallUnsupported {}
+
+/// C source code generated by the [CExport] code generator.
+///
+/// The source defines a single function with external linkage and the signature
+/// `void symbol(int64_t *memory)`, which behaves like [Runner::step](crate::Runner::step)
+/// except that it does not check the size of the memory. The memory must contain at least
+/// [layout().size()](MemoryLayout::size) values.
+pub struct CSource {
+    source: String,
+    symbol: String,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+impl CSource {
+    /// The generated C source.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Take the generated C source.
+    pub fn into_source(self) -> String {
+        self.source
+    }
+
+    /// The name of the entry point.
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// Compile the source with the C compiler of the system into a program that the returned
+    /// runner steps memories with, to test exported code against the other code generators.
+    ///
+    /// The compiler is taken from the `CC` environment variable, or `cc` if it is not set, and
+    /// must accept the options of GCC and Clang. Compiling fails if the compiler can not be run
+    /// or reports any warning.
+    #[cfg(feature = "c-export-cc")]
+    pub fn compile_native(&self) -> io::Result<Runner> {
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "aivm-c-export-{}-{}",
+            std::process::id(),
+            BUILDS.fetch_add(1, Ordering::Relaxed),
+        ));
+        fs::create_dir_all(&dir)?;
+        let build = || {
+            let size = self.layout.size();
+            let main = format!(
+                "#include <stdint.h>\n#include <stdio.h>\n#ifdef _WIN32\n#include <fcntl.h>\n\
+                 #include <io.h>\n#endif\n\nvoid {symbol}(int64_t *memory);\n\n\
+                 int main(void) {{\n    static int64_t memory[{len}];\n#ifdef _WIN32\n    \
+                 _setmode(_fileno(stdin), _O_BINARY);\n    \
+                 _setmode(_fileno(stdout), _O_BINARY);\n#endif\n    \
+                 while (fread(memory, sizeof(int64_t), {size}, stdin) == {size}) {{\n        \
+                 {symbol}(memory);\n        \
+                 if (fwrite(memory, sizeof(int64_t), {size}, stdout) != {size}) return 1;\n        \
+                 fflush(stdout);\n    }}\n    return 0;\n}}\n",
+                symbol = self.symbol,
+                len = size.max(1),
+            );
+            fs::write(dir.join("main.c"), main)?;
+            fs::write(dir.join("code.c"), &self.source)?;
+
+            let exe = dir.join(if cfg!(windows) { "code.exe" } else { "code" });
+            let compiler = std::env::var_os("CC").unwrap_or_else(|| "cc".into());
+            let output = Command::new(compiler)
+                .args(["-std=c99", "-O1", "-Wall", "-Werror", "-o"])
+                .arg(&exe)
+                .args([dir.join("main.c"), dir.join("code.c")])
+                .output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "C compiler failed: {}",
+                    String::from_utf8_lossy(&output.stderr),
+                )));
+            }
+
+            Command::new(&exe)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+        };
+
+        match build() {
+            Ok(mut child) => {
+                let pipes = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+                Ok(Runner {
+                    child,
+                    pipes: Mutex::new(pipes),
+                    dir,
+                    function_count: self.function_count,
+                    instruction_count: self.instruction_count,
+                    layout: self.layout,
+                })
+            }
+            Err(err) => {
+                let _ = fs::remove_dir_all(&dir);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Runs a [CSource] that was compiled with the C compiler of the system, see
+/// [compile_native](CSource::compile_native).
+///
+/// The code runs in a child process that exits when the runner is dropped. Every step sends
+/// the memory to the process and waits for the result, which is slow but tests the exact
+/// source that is exported.
+#[cfg(feature = "c-export-cc")]
+pub struct Runner {
+    child: Child,
+    pipes: Mutex<(ChildStdin, ChildStdout)>,
+    dir: PathBuf,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+#[cfg(feature = "c-export-cc")]
+impl Runner {
+    /// Run the code once on `memory`, like [Runner::step](crate::Runner::step).
+    ///
+    /// # Panics
+    /// If `memory` is smaller than the layout, or if the process of the code exited.
+    pub fn step(&self, memory: &mut [i64]) {
+        let size = self.layout.size() as usize;
+        assert!(size <= memory.len());
+        if size == 0 {
+            // Without memory, the code can not have any effect
+            return;
+        }
+
+        let mut pipes = self.pipes.lock().unwrap();
+        let (stdin, stdout) = &mut *pipes;
+        let mut bytes: Vec<_> = memory[..size]
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect();
+        stdin
+            .write_all(&bytes)
+            .and_then(|()| stdin.flush())
+            .and_then(|()| stdout.read_exact(&mut bytes))
+            .expect("the process of the C code exited");
+        for (value, bytes) in memory.iter_mut().zip(bytes.chunks_exact(8)) {
+            *value = i64::from_ne_bytes(bytes.try_into().unwrap());
+        }
+    }
+}
+
+#[cfg(feature = "c-export-cc")]
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+#[cfg(feature = "c-export-cc")]
+impl crate::BatchRunner for Runner {
+    fn step_strided(&self, memories: &mut [i64], stride: usize) {
+        assert_eq!(memories.len() % stride, 0);
+        for memory in memories.chunks_exact_mut(stride) {
+            self.step(memory);
+        }
+    }
+}
+
+#[cfg(feature = "c-export-cc")]
+impl Drop for Runner {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl crate::RunnerInfo for CSource {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Function,
+    symbol: &'a str,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    instruction_count: u32,
+    /// The instructions that are the target of a branch, and need a label.
+    branch_targets: Vec<u32>,
+}
+
+impl<'a> Emitter<'a> {
+    /// Write a statement that uses the registers.
+    fn line(&mut self, line: std::fmt::Arguments) {
+        self.func.uses_registers = true;
+        writeln!(self.func.body, "    {line};").unwrap();
+    }
+
+    fn place_labels(&mut self) {
+        let i = self.instruction_count;
+        if let Some(t) = self.branch_targets.iter().position(|&t| t == i) {
+            self.branch_targets.swap_remove(t);
+            writeln!(self.func.body, "l{i}:;").unwrap();
+        }
+    }
+
+    fn branch(&mut self, condition: std::fmt::Arguments, offset: u32) {
+        // The instruction counter already points at the next instruction
+        let target = self.instruction_count + offset;
+        if !self.branch_targets.contains(&target) {
+            self.branch_targets.push(target);
+        }
+        self.line(format_args!("if ({condition}) goto l{target}"));
+    }
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        self.place_labels();
+        self.instruction_count += 1;
+    }
+
+    fn finalize(mut self) {
+        self.place_labels();
+        self.func.instruction_count = self.instruction_count;
+        debug_assert!(self.branch_targets.is_empty());
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.func.calls.push(idx);
+        writeln!(
+            self.func.body,
+            "    {}_function_{idx}(memory);",
+            self.symbol
+        )
+        .unwrap();
+    }
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, _id: u32) {
+        self.func.host_call = true;
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] + r[{b}]"));
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] - r[{b}]"));
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] * r[{b}]"));
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = aivm_mul_high(r[{a}], r[{b}])"));
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!(
+            "r[{dst}] = aivm_mul_high_unsigned(r[{a}], r[{b}])"
+        ));
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("r[{dst}] = 0 - r[{src}]"));
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        self.line(format_args!(
+            "r[{dst}] = (int64_t)r[{src}] < 0 ? 0 - r[{src}] : r[{src}]"
+        ));
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.line(format_args!("r[{dst}] += 1"));
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.line(format_args!("r[{dst}] -= 1"));
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!(
+            "r[{dst}] = (int64_t)r[{a}] < (int64_t)r[{b}] ? r[{a}] : r[{b}]"
+        ));
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!(
+            "r[{dst}] = (int64_t)r[{a}] > (int64_t)r[{b}] ? r[{a}] : r[{b}]"
+        ));
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] | r[{b}]"));
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] & r[{b}]"));
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("r[{dst}] = r[{a}] ^ r[{b}]"));
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("r[{dst}] = ~r[{src}]"));
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!("r[{dst}] = r[{src}] << {amount}"));
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!(
+            "r[{dst}] = aivm_shift_right(r[{src}], {amount})"
+        ));
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!(
+            "r[{dst}] = aivm_rotate_left(r[{src}], {amount})"
+        ));
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!(
+            "r[{dst}] = aivm_rotate_right(r[{src}], {amount})"
+        ));
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.line(format_args!(
+            "r[{dst}] = (r[{a}] & r[{mask}]) | (r[{b}] & ~r[{mask}])"
+        ));
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("r[{dst}] = aivm_popcnt(r[{src}])"));
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("r[{dst}] = aivm_reverse(r[{src}])"));
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        match compare_kind {
+            CompareKind::Eq => self.branch(format_args!("r[{a}] == r[{b}]"), offset),
+            CompareKind::Neq => self.branch(format_args!("r[{a}] != r[{b}]"), offset),
+            CompareKind::Gt => {
+                self.branch(format_args!("(int64_t)r[{a}] > (int64_t)r[{b}]"), offset)
+            }
+            CompareKind::Lt => {
+                self.branch(format_args!("(int64_t)r[{a}] < (int64_t)r[{b}]"), offset)
+            }
+        }
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("r[{src}] == 0"), offset);
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("r[{src}] != 0"), offset);
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        if bank == MemoryBank::Const {
+            let value = self.constants[usize::try_from(addr).unwrap()] as u64;
+            self.line(format_args!("r[{dst}] = UINT64_C({value:#x})"));
+            return;
+        }
+        let addr = self.layout.address(bank, addr);
+        self.line(format_args!("r[{dst}] = (uint64_t)memory[{addr}]"));
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let addr = self.layout.address(bank, addr);
+            self.line(format_args!("memory[{addr}] = (int64_t)r[{src}]"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_point() {
+        let mut compiler = crate::Compiler::new(CExport::new());
+        let code = compiler
+            .compile(&[0xFFFF_0000_0000_1234; 8], 1, 2, 2, 2)
+            .unwrap();
+
+        assert_eq!(code.symbol(), "aivm_step");
+        assert_eq!(code.layout(), MemoryLayout::new(2, 2, 2));
+        assert!(code
+            .source()
+            .contains("\nvoid aivm_step(int64_t *memory) {\n"));
+    }
+
+    #[test]
+    fn host_call() {
+        use codegen::private::{CodeGeneratorImpl, Emitter};
+
+        // Function 1 calls the host, but only an error if it is called
+        let mut gen = CExport::new();
+        let mut compile = |call: bool| {
+            gen.begin(NonZeroU32::new(2).unwrap(), MemoryLayout::new(1, 0, 0));
+            let mut emitter = gen.begin_function(0);
+            if call {
+                emitter.emit_call(1);
+            }
+            emitter.finalize();
+            let mut emitter = gen.begin_function(1);
+            emitter.emit_host_call(0);
+            emitter.finalize();
+            gen.finish()
+        };
+
+        assert!(compile(false).is_ok());
+        assert_eq!(
+            compile(true).err(),
+            Some(HostCallUnsupported { function: 1 })
+        );
+    }
+
+    #[cfg(feature = "c-export-cc")]
+    #[test]
+    fn matches_spec() {
+        for vector in crate::spec::test_vectors() {
+            let layout = vector.layout;
+            let code = crate::Compiler::new(CExport::new())
+                .compile(
+                    &vector.code,
+                    vector.lowest_function_level,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                )
+                .unwrap();
+
+            let mut memory = vector.memory.clone();
+            code.compile_native().unwrap().step(&mut memory);
+            assert_eq!(memory, vector.expected, "{}", vector.name);
+        }
+    }
+
+    #[cfg(feature = "c-export-cc")]
+    #[test]
+    fn matches_interpreter() {
+        use crate::{
+            codegen::Interpreter,
+            testing::{differential, random_code, random_memory, Backend, Xorshift},
+            Compiler, OutputInit,
+        };
+
+        let mut rng = Xorshift::default();
+
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut compiler = Compiler::new(CExport::new());
+        let c = |code: &[u64], level, layout: MemoryLayout, memory: &mut [i64]| {
+            compiler.set_output_init(layout.output_init);
+            let source = compiler
+                .compile(
+                    code,
+                    level,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                )
+                .unwrap();
+            source.compile_native().unwrap().step(memory);
+        };
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::from_fn("c", c),
+        ];
+        for p in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+            let output_init = [
+                OutputInit::Clear,
+                OutputInit::Keep,
+                OutputInit::Fill(i64::MIN),
+            ][p % 3];
+            let layout = MemoryLayout::new(8, 4, 4).with_output_init(output_init);
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+        }
+    }
+
+    #[cfg(feature = "c-export-cc")]
+    #[test]
+    fn compile_error() {
+        // A symbol that clashes with a standard function fails to link
+        let source = crate::Compiler::new(CExport::with_symbol("main"))
+            .compile(&[0; 4], 1, 1, 0, 0)
+            .unwrap();
+        assert!(source.compile_native().is_err());
+    }
+}
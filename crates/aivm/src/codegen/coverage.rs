@@ -0,0 +1,89 @@
+/// Which instructions of the code were executed at least once, collected over any number of
+/// steps by [step_with_coverage](super::InterpreterRunner::step_with_coverage).
+///
+/// Instructions are identified by their index in the code that was compiled. Code that is never
+/// executed has no influence on the behavior, so the share of executed instructions measures
+/// how much of a genome is dead weight, for example to add parsimony pressure to the fitness.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    executed: Vec<bool>,
+}
+
+impl Coverage {
+    /// Create a coverage where nothing has been executed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the instruction at `index` in the code was executed.
+    pub fn is_executed(&self, index: usize) -> bool {
+        self.executed.get(index).copied().unwrap_or(false)
+    }
+
+    /// The indices of the executed instructions, in ascending order.
+    pub fn executed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.executed
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &executed)| executed.then_some(i))
+    }
+
+    /// The amount of executed instructions.
+    pub fn executed_count(&self) -> usize {
+        self.executed.iter().filter(|&&executed| executed).count()
+    }
+
+    /// The share of the `code_len` code words that were executed, between 0.0 and 1.0. Words
+    /// that end a function are never executed, so they count as dead weight too.
+    pub fn fraction(&self, code_len: usize) -> f64 {
+        if code_len == 0 {
+            return 0.0;
+        }
+
+        self.executed().take_while(|&i| i < code_len).count() as f64 / code_len as f64
+    }
+
+    /// Add the instructions executed according to `other`, for example to combine the
+    /// coverage of episodes that were run on different threads.
+    pub fn merge(&mut self, other: &Coverage) {
+        if self.executed.len() < other.executed.len() {
+            self.executed.resize(other.executed.len(), false);
+        }
+        for (executed, &other) in self.executed.iter_mut().zip(&other.executed) {
+            *executed |= other;
+        }
+    }
+
+    /// Forget everything that was executed so far.
+    pub fn clear(&mut self) {
+        self.executed.clear();
+    }
+
+    #[inline]
+    pub(crate) fn mark(&mut self, index: usize) {
+        if index >= self.executed.len() {
+            self.executed.resize(index + 1, false);
+        }
+        self.executed[index] = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge() {
+        let mut a = Coverage::new();
+        a.mark(1);
+        let mut b = Coverage::new();
+        b.mark(4);
+        b.mark(1);
+        a.merge(&b);
+
+        assert_eq!(a.executed().collect::<Vec<_>>(), [1, 4]);
+        assert!(!a.is_executed(2) && !a.is_executed(100));
+        assert_eq!(a.executed_count(), 2);
+        assert_eq!(a.fraction(4), 0.25);
+    }
+}
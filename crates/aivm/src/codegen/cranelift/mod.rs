@@ -0,0 +1,1253 @@
+#[cfg(feature = "cranelift-object")]
+mod object;
+
+#[cfg(feature = "cranelift-object")]
+pub use object::{CraneliftObject, ObjectCode};
+
+use crate::{
+    codegen::{
+        self,
+        fuel::{self, FuelContext},
+        CompileReport, FunctionReport,
+    },
+    compile::CompareKind,
+    DeadlineExceeded, HostFunctions, MemoryBank, MemoryLayout, StepResult,
+};
+
+use cranelift::{
+    codegen::{
+        ir::{self, UserFuncName},
+        isa::{self, TargetIsa},
+        settings::{self, Configurable},
+        Context,
+    },
+    frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
+    prelude::*,
+};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    fmt, mem,
+    num::NonZeroU32,
+    sync::Arc,
+    time::Instant,
+};
+
+const VAR_MEM_START: u32 = 64;
+const VAR_FUEL: u32 = 65;
+
+/// How much effort cranelift spends on optimizing the generated code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OptLevel {
+    /// No optimizations, which gives the highest compile throughput.
+    #[default]
+    None,
+    /// Optimize for the speed of the generated code.
+    Speed,
+    /// Optimize for both the speed and the size of the generated code.
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    fn as_setting(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Speed => "speed",
+            Self::SpeedAndSize => "speed_and_size",
+        }
+    }
+}
+
+/// The error returned when cranelift can not generate code for a target machine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedTarget {
+    reason: String,
+}
+
+impl UnsupportedTarget {
+    fn new(reason: impl fmt::Display) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for UnsupportedTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported target: {}", self.reason)
+    }
+}
+
+impl std::error::Error for UnsupportedTarget {}
+
+/// A code generator that uses cranelift to JIT compile AIVM code into native machine code.
+pub struct Cranelift {
+    gen: Generator<JITModule>,
+    isa: Arc<dyn TargetIsa>,
+    host: HostFunctions,
+}
+
+impl codegen::private::CodeGeneratorImpl for Cranelift {
+    type Runner = Runner;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.host = host.clone();
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.gen.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.gen.begin(function_count, layout, |i| match i {
+            0 => ("main".to_owned(), Linkage::Export),
+            _ => (i.to_string(), Linkage::Local),
+        });
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        self.gen.begin_function(idx)
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        self.gen.define_cur_function();
+        self.gen.module.finalize_definitions().unwrap();
+        self.gen.report.compile_time = self.gen.compile_start.elapsed();
+
+        #[cfg(feature = "perf-map")]
+        codegen::perf_map::append(
+            self.gen
+                .functions
+                .iter()
+                .zip(&self.gen.report.functions)
+                .enumerate()
+                .map(|(f, (&func, report))| {
+                    let start = self.gen.module.get_finalized_function(func);
+                    (
+                        start,
+                        report.code_size,
+                        format!("aivm::cranelift::function_{f}"),
+                    )
+                }),
+        );
+
+        let module = self
+            .gen
+            .replace_module(Self::create_jit_module(self.isa.clone()));
+
+        Runner {
+            main: module.get_finalized_function(self.gen.functions[0]),
+            unmetered_main: module.get_finalized_function(self.gen.unmetered_functions[0]),
+            _code: Arc::new(Code(Some(module))),
+            function_count: self.gen.functions.len() as u32,
+            instruction_count: self.gen.instruction_count,
+            code_size: self.gen.code_size(),
+            layout: self.gen.layout,
+            host: self.host.clone(),
+        }
+    }
+}
+
+impl Cranelift {
+    /// Create a new generator that does not optimize the generated code.
+    ///
+    /// # Panics
+    /// If cranelift does not support the host machine.
+    pub fn new() -> Self {
+        Self::with_opt_level(OptLevel::None)
+    }
+
+    /// Create a new generator with the given optimization level.
+    ///
+    /// # Panics
+    /// If cranelift does not support the host machine.
+    pub fn with_opt_level(opt_level: OptLevel) -> Self {
+        Self::try_with_opt_level(opt_level).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [with_opt_level](Self::with_opt_level), but returns an error if cranelift does not
+    /// support the host machine.
+    pub fn try_with_opt_level(opt_level: OptLevel) -> Result<Self, UnsupportedTarget> {
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false").unwrap();
+        // All functions end up in the same memory region, so there is no need for position
+        // independent code.
+        flag_builder.set("is_pic", "false").unwrap();
+
+        let isa = host_isa(flag_builder, opt_level)?;
+
+        Ok(Self {
+            gen: Generator::new(Self::create_jit_module(isa.clone()), true),
+            isa,
+            host: HostFunctions::new(),
+        })
+    }
+
+    /// The name of the instruction set code is generated for, for example `x64` or `aarch64`.
+    pub fn isa_name(&self) -> &'static str {
+        self.isa.name()
+    }
+
+    /// Enable or disable capturing the CLIF IR of every compiled function, see [clif](Self::clif).
+    pub fn set_capture_clif(&mut self, capture: bool) {
+        self.gen.clif = capture.then(Vec::new);
+    }
+
+    /// The CLIF IR of the functions in the last compilation, indexed by function, after
+    /// cranelift optimized it.
+    ///
+    /// Returns `None` if capturing was not enabled with [set_capture_clif](Self::set_capture_clif).
+    pub fn clif(&self) -> Option<&[String]> {
+        self.gen.clif.as_deref()
+    }
+
+    /// Statistics about the code generated by the last compilation.
+    ///
+    /// Cranelift does not report the spills and used registers of a function. The stack size
+    /// is the size of the whole stack frame, excluding saved registers. The code size includes
+    /// both the copy of the function with and without fuel checks.
+    pub fn report(&self) -> &CompileReport {
+        &self.gen.report
+    }
+
+    fn create_jit_module(isa: Arc<dyn TargetIsa>) -> JITModule {
+        JITModule::new(JITBuilder::with_isa(isa, default_libcall_names()))
+    }
+}
+
+impl Default for Cranelift {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The state shared by the cranelift based code generators.
+struct Generator<M: Module> {
+    func_ctx: FunctionBuilderContext,
+    func_refs: HashMap<u32, ir::entities::FuncRef>,
+    functions: Vec<FuncId>,
+    /// The copies of the functions without fuel checks, when the functions take a fuel context.
+    unmetered_functions: Vec<FuncId>,
+    /// The fuel checks of the current function, which are removed from its unmetered copy.
+    fuel_checks: Vec<FuelCheck>,
+    upcoming_blocks: HashMap<u32, Block>,
+    module: M,
+    ctx: Context,
+    cur_function: Option<u32>,
+    layout: MemoryLayout,
+    native_ops: bool,
+    /// Whether the functions take a pointer to a [FuelContext], and return whether the fuel ran
+    /// out.
+    fuel: bool,
+    clif: Option<Vec<String>>,
+    compile_start: Instant,
+    /// Statistics of the defined functions, the compile time is set by the code generator once
+    /// the code is finished.
+    report: CompileReport,
+    /// The amount of VM instructions emitted since the last call to `begin`.
+    instruction_count: usize,
+    /// The values of the `Const` bank, which are embedded in the code.
+    constants: Arc<[i64]>,
+}
+
+impl<M: Module> Generator<M> {
+    fn new(module: M, fuel: bool) -> Self {
+        let ctx = module.make_context();
+        let native_ops = supports_native_ops(module.isa());
+
+        Self {
+            func_ctx: FunctionBuilderContext::new(),
+            func_refs: HashMap::new(),
+            functions: vec![],
+            unmetered_functions: vec![],
+            fuel_checks: vec![],
+            upcoming_blocks: HashMap::new(),
+            module,
+            ctx,
+            cur_function: None,
+            layout: MemoryLayout::default(),
+            native_ops,
+            fuel,
+            clif: None,
+            compile_start: Instant::now(),
+            report: CompileReport::default(),
+            instruction_count: 0,
+            constants: Arc::new([]),
+        }
+    }
+
+    /// Declare all functions, `symbol` gives the name and linkage of the function with the
+    /// given index.
+    fn begin<F>(&mut self, function_count: NonZeroU32, layout: MemoryLayout, mut symbol: F)
+    where
+        F: FnMut(u32) -> (String, Linkage),
+    {
+        let function_count = function_count.get();
+
+        self.compile_start = Instant::now();
+        self.layout = layout;
+        self.cur_function = None;
+        self.functions.clear();
+        self.functions.reserve(function_count.try_into().unwrap());
+        self.unmetered_functions.clear();
+        if let Some(clif) = &mut self.clif {
+            clif.clear();
+        }
+        self.report = CompileReport::default();
+        self.instruction_count = 0;
+
+        let sig = self.make_signature();
+        for i in 0..function_count {
+            let (name, linkage) = symbol(i);
+            let func = self.module.declare_function(&name, linkage, &sig).unwrap();
+            self.functions.push(func);
+
+            // Steps that run until the code returns call a copy without the fuel checks
+            if self.fuel {
+                let name = format!("{name}_unmetered");
+                let func = self
+                    .module
+                    .declare_function(&name, Linkage::Local, &sig)
+                    .unwrap();
+                self.unmetered_functions.push(func);
+            }
+        }
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Emitter<'_> {
+        self.define_cur_function();
+        self.cur_function = Some(idx);
+
+        self.func_refs.clear();
+        self.fuel_checks.clear();
+        self.upcoming_blocks.clear();
+        self.module.clear_context(&mut self.ctx);
+
+        self.ctx.func.signature = self.make_signature();
+        self.ctx.func.name =
+            UserFuncName::user(0, self.functions[usize::try_from(idx).unwrap()].as_u32());
+
+        let pointer_type = self.module.target_config().pointer_type();
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.func_ctx);
+
+        for i in 0..64 {
+            builder.declare_var(Variable::from_u32(i), ir::types::I64);
+        }
+        builder.declare_var(Variable::from_u32(VAR_MEM_START), pointer_type);
+
+        let main_block = builder.create_block();
+        builder.append_block_params_for_function_params(main_block);
+        builder.seal_block(main_block);
+        builder.switch_to_block(main_block);
+
+        let mem_start = builder.block_params(main_block)[0];
+        builder.def_var(Variable::from_u32(VAR_MEM_START), mem_start);
+
+        let fuel = self.fuel.then(|| {
+            builder.declare_var(Variable::from_u32(VAR_FUEL), pointer_type);
+            let fuel_ptr = builder.block_params(main_block)[1];
+            builder.def_var(Variable::from_u32(VAR_FUEL), fuel_ptr);
+
+            let mut refuel_sig = self.module.make_signature();
+            refuel_sig.params.push(ir::AbiParam::new(pointer_type));
+            refuel_sig.returns.push(ir::AbiParam::new(ir::types::I64));
+
+            let mut host_call_sig = self.module.make_signature();
+            host_call_sig.params.push(ir::AbiParam::new(pointer_type));
+            host_call_sig.params.push(ir::AbiParam::new(pointer_type));
+
+            Fuel {
+                out_of_fuel: builder.create_block(),
+                refuel_sig: builder.import_signature(refuel_sig),
+                host_call_sig: builder.import_signature(host_call_sig),
+                block_cost_inst: None,
+                block_cost: 0,
+            }
+        });
+
+        let mut emitter = Emitter {
+            builder,
+            func_refs: &mut self.func_refs,
+            module: &mut self.module,
+            functions: &self.functions,
+
+            upcoming_blocks: &mut self.upcoming_blocks,
+            next_instruction: 0,
+            instruction_count: &mut self.instruction_count,
+            layout: self.layout,
+            constants: &self.constants,
+            native_ops: self.native_ops,
+            fuel,
+            fuel_checks: &mut self.fuel_checks,
+        };
+        emitter.begin_fuel_block();
+
+        emitter
+    }
+
+    /// Swap in a fresh module, returning the one that contains the compiled functions.
+    fn replace_module(&mut self, mut module: M) -> M {
+        mem::swap(&mut module, &mut self.module);
+        self.module.clear_context(&mut self.ctx);
+        self.cur_function = None;
+
+        module
+    }
+
+    fn make_signature(&self) -> Signature {
+        let mut sig = self.module.make_signature();
+        let pointer_type = self.module.target_config().pointer_type();
+        sig.params.push(ir::AbiParam::new(pointer_type));
+        if self.fuel {
+            sig.params.push(ir::AbiParam::new(pointer_type));
+            sig.returns.push(ir::AbiParam::new(ir::types::I8));
+        }
+
+        sig
+    }
+
+    fn define_cur_function(&mut self) {
+        if let Some(f) = self.cur_function.take() {
+            let f = usize::try_from(f).unwrap();
+            let unmetered = self.fuel.then(|| self.unmetered_copy(f));
+            self.module
+                .define_function(self.functions[f], &mut self.ctx)
+                .unwrap();
+
+            if let Some(clif) = &mut self.clif {
+                clif.push(self.ctx.func.display().to_string());
+            }
+
+            let compiled = self.ctx.compiled_code().unwrap();
+            let mut report = FunctionReport {
+                code_size: compiled.code_info().total_size as usize,
+                stack_size: compiled.frame_size,
+                spills: None,
+                used_regs_mask: None,
+            };
+
+            if let Some(func) = unmetered {
+                self.ctx.clear();
+                self.ctx.func = func;
+                self.module
+                    .define_function(self.unmetered_functions[f], &mut self.ctx)
+                    .unwrap();
+                let compiled = self.ctx.compiled_code().unwrap();
+                report.code_size += compiled.code_info().total_size as usize;
+            }
+
+            self.report.functions.push(report);
+        }
+    }
+
+    /// Copy the current function without its fuel checks, calling the copies of its callees
+    /// without fuel checks.
+    fn unmetered_copy(&self, f: usize) -> ir::Function {
+        let mut func = self.ctx.func.clone();
+        func.name = UserFuncName::user(0, self.unmetered_functions[f].as_u32());
+
+        // The remaining fuel is never read, so the refuel blocks become unreachable
+        for check in &self.fuel_checks {
+            func.dfg.replace(check.load).iconst(ir::types::I64, 0);
+            func.dfg.replace(check.store).nop();
+            func.dfg.replace(check.branch).jump(check.block, &[]);
+        }
+
+        let callees: Vec<_> = func
+            .params
+            .user_named_funcs()
+            .iter()
+            .map(|(name_ref, name)| (name_ref, name.clone()))
+            .collect();
+        for (name_ref, mut name) in callees {
+            let callee = self
+                .functions
+                .iter()
+                .position(|func| func.as_u32() == name.index)
+                .unwrap();
+            name.index = self.unmetered_functions[callee].as_u32();
+            func.params.reset_user_func_name(name_ref, name);
+        }
+
+        func
+    }
+
+    /// The size of the machine code of all defined functions.
+    fn code_size(&self) -> usize {
+        self.report.functions.iter().map(|f| f.code_size).sum()
+    }
+}
+
+fn host_isa(
+    flag_builder: settings::Builder,
+    opt_level: OptLevel,
+) -> Result<Arc<dyn TargetIsa>, UnsupportedTarget> {
+    let isa_builder = cranelift_native::builder().map_err(UnsupportedTarget::new)?;
+    finish_isa(isa_builder, flag_builder, opt_level)
+}
+
+fn finish_isa(
+    isa_builder: isa::Builder,
+    mut flag_builder: settings::Builder,
+    opt_level: OptLevel,
+) -> Result<Arc<dyn TargetIsa>, UnsupportedTarget> {
+    flag_builder
+        .set("opt_level", opt_level.as_setting())
+        .unwrap();
+
+    isa_builder
+        .finish(settings::Flags::new(flag_builder))
+        .map_err(UnsupportedTarget::new)
+}
+
+/// Whether the ISA can lower `iabs`, `smin`, `smax` and `bitselect` on scalar integers.
+fn supports_native_ops(isa: &dyn TargetIsa) -> bool {
+    matches!(isa.name(), "x64" | "aarch64" | "riscv64" | "s390x")
+}
+
+pub struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    func_refs: &'a mut HashMap<u32, ir::entities::FuncRef>,
+    module: &'a mut dyn Module,
+    functions: &'a [FuncId],
+
+    upcoming_blocks: &'a mut HashMap<u32, Block>,
+    next_instruction: u32,
+    /// The amount of instructions of all functions emitted so far.
+    instruction_count: &'a mut usize,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    native_ops: bool,
+    fuel: Option<Fuel>,
+    fuel_checks: &'a mut Vec<FuelCheck>,
+}
+
+/// The fuel metering of a function.
+///
+/// Every block consumes the fuel for all of its instructions when it is entered. The cost is
+/// only known at the end of the block, so it starts out as a placeholder constant.
+struct Fuel {
+    /// Returns from the function, signalling that the fuel ran out.
+    out_of_fuel: Block,
+    /// The signature of [FuelContext::refuel].
+    refuel_sig: ir::SigRef,
+    /// The signature of [FuelContext::host_call].
+    host_call_sig: ir::SigRef,
+    block_cost_inst: Option<ir::Inst>,
+    block_cost: u32,
+}
+
+/// The instructions of a fuel check at the start of a block.
+struct FuelCheck {
+    /// Loads the remaining fuel.
+    load: ir::Inst,
+    /// Stores the fuel that is left after the block.
+    store: ir::Inst,
+    /// Branches to `block` if there was enough fuel, or to the refuel block otherwise.
+    branch: ir::Inst,
+    block: Block,
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        if let Some(block) = self.upcoming_blocks.remove(&self.next_instruction) {
+            self.end_fuel_block();
+            self.builder.ins().jump(block, &[]);
+            self.builder.seal_block(block);
+            self.builder.switch_to_block(block);
+            self.begin_fuel_block();
+        }
+
+        self.next_instruction += 1;
+        *self.instruction_count += 1;
+        if let Some(fuel) = &mut self.fuel {
+            fuel.block_cost += 1;
+        }
+    }
+
+    fn finalize(mut self) {
+        if let Some(block) = self.upcoming_blocks.remove(&self.next_instruction) {
+            self.end_fuel_block();
+            self.builder.ins().jump(block, &[]);
+            self.builder.seal_block(block);
+            self.builder.switch_to_block(block);
+            self.begin_fuel_block();
+        }
+
+        self.end_fuel_block();
+        match &self.fuel {
+            Some(fuel) => {
+                let out_of_fuel = fuel.out_of_fuel;
+                let completed = self.builder.ins().iconst(ir::types::I8, 0);
+                self.builder.ins().return_(&[completed]);
+
+                self.builder.seal_block(out_of_fuel);
+                self.builder.switch_to_block(out_of_fuel);
+                let exhausted = self.builder.ins().iconst(ir::types::I8, 1);
+                self.builder.ins().return_(&[exhausted]);
+            }
+            None => {
+                self.builder.ins().return_(&[]);
+            }
+        }
+        self.builder.finalize();
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        let func_ref = *self.func_refs.entry(idx).or_insert_with(|| {
+            self.module.declare_func_in_func(
+                self.functions[usize::try_from(idx).unwrap()],
+                self.builder.func,
+            )
+        });
+
+        let mem_start = self.builder.use_var(Variable::from_u32(VAR_MEM_START));
+        let Some(fuel) = &self.fuel else {
+            self.builder.ins().call(func_ref, &[mem_start]);
+            return;
+        };
+
+        // Return right away when the callee ran out of fuel
+        let out_of_fuel = fuel.out_of_fuel;
+        let fuel_ptr = self.builder.use_var(Variable::from_u32(VAR_FUEL));
+        let call = self.builder.ins().call(func_ref, &[mem_start, fuel_ptr]);
+        let exhausted = self.builder.inst_results(call)[0];
+        let resume_block = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(exhausted, out_of_fuel, &[], resume_block, &[]);
+        self.builder.seal_block(resume_block);
+        self.builder.switch_to_block(resume_block);
+    }
+
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, id: u32) {
+        // Only the JIT runs with a context that can call back into the host
+        let Some(fuel) = &self.fuel else {
+            panic!("host calls are not supported by the cranelift object export");
+        };
+
+        let fuel_ptr = self.builder.use_var(Variable::from_u32(VAR_FUEL));
+        let mem_start = self.builder.use_var(Variable::from_u32(VAR_MEM_START));
+        let id = self.builder.ins().iconst(ir::types::I32, i64::from(id));
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            id,
+            fuel_ptr,
+            FuelContext::HOST_CALL_ID_OFFSET,
+        );
+
+        let pointer_type = self.module.target_config().pointer_type();
+        let host_call = self.builder.ins().load(
+            pointer_type,
+            MemFlags::trusted(),
+            fuel_ptr,
+            FuelContext::HOST_CALL_OFFSET,
+        );
+        self.builder
+            .ins()
+            .call_indirect(fuel.host_call_sig, host_call, &[fuel_ptr, mem_start]);
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().iadd(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().isub(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().imul(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().smulhi(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().umulhi(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        let src = self.use_var(src);
+        let res = self.builder.ins().ineg(src);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        let src = self.use_var(src);
+
+        let res = if self.native_ops {
+            self.builder.ins().iabs(src)
+        } else {
+            let shifted = self.builder.ins().sshr_imm(src, 63);
+            let sum = self.builder.ins().iadd(src, shifted);
+            self.builder.ins().bxor(sum, shifted)
+        };
+
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_inc(&mut self, dst: u8) {
+        let a = self.use_var(dst);
+        let res = self.builder.ins().iadd_imm(a, 1);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_dec(&mut self, dst: u8) {
+        let a = self.use_var(dst);
+        let res = self.builder.ins().iadd_imm(a, -1);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+
+        let res = if self.native_ops {
+            self.builder.ins().smin(a, b)
+        } else {
+            let use_a = self.builder.ins().icmp(IntCC::SignedLessThan, a, b);
+            self.builder.ins().select(use_a, a, b)
+        };
+
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+
+        let res = if self.native_ops {
+            self.builder.ins().smax(a, b)
+        } else {
+            let use_a = self.builder.ins().icmp(IntCC::SignedLessThan, b, a);
+            self.builder.ins().select(use_a, a, b)
+        };
+
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().bor(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().band(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+        let res = self.builder.ins().bxor(a, b);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        let src = self.use_var(src);
+        let res = self.builder.ins().bnot(src);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        let a = self.use_var(src);
+        let res = self.builder.ins().ishl_imm(a, amount as i64);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        let a = self.use_var(src);
+        let res = self.builder.ins().sshr_imm(a, amount as i64);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        let a = self.use_var(src);
+        let res = self.builder.ins().rotl_imm(a, amount as i64);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        let a = self.use_var(src);
+        let res = self.builder.ins().rotr_imm(a, amount as i64);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        let mask = self.use_var(mask);
+        let a = self.use_var(a);
+        let b = self.use_var(b);
+
+        let res = if self.native_ops {
+            self.builder.ins().bitselect(mask, a, b)
+        } else {
+            let true_bits = self.builder.ins().band(a, mask);
+            let mask_not = self.builder.ins().bnot(mask);
+            let false_bits = self.builder.ins().band(b, mask_not);
+            self.builder.ins().bor(true_bits, false_bits)
+        };
+
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        let src = self.use_var(src);
+        let res = self.builder.ins().popcnt(src);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        let src = self.use_var(src);
+        let res = self.builder.ins().bitrev(src);
+        self.builder.def_var(Self::var(dst), res);
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        let x = self.use_var(a);
+        let y = self.use_var(b);
+
+        let cond = match compare_kind {
+            CompareKind::Eq => IntCC::Equal,
+            CompareKind::Neq => IntCC::NotEqual,
+            CompareKind::Gt => IntCC::SignedGreaterThan,
+            CompareKind::Lt => IntCC::SignedLessThan,
+        };
+        self.branch_ins(offset, |builder, jump_block, resume_block| {
+            let cond = builder.ins().icmp(cond, x, y);
+            builder.ins().brif(cond, jump_block, &[], resume_block, &[])
+        });
+    }
+
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        let src = self.use_var(src);
+
+        self.branch_ins(offset, |builder, jump_block, resume_block| {
+            builder.ins().brif(src, resume_block, &[], jump_block, &[])
+        });
+    }
+
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        let src = self.use_var(src);
+
+        self.branch_ins(offset, |builder, jump_block, resume_block| {
+            builder.ins().brif(src, jump_block, &[], resume_block, &[])
+        });
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        let addr = self.layout.address(bank, addr);
+        if bank == MemoryBank::Const {
+            let v = self
+                .builder
+                .ins()
+                .iconst(ir::types::I64, self.constants[addr as usize]);
+            self.builder.def_var(Self::var(dst), v);
+            return;
+        }
+
+        let mem_start = self.builder.use_var(Variable::from_u32(VAR_MEM_START));
+
+        let v = self.builder.ins().load(
+            ir::types::I64,
+            MemFlags::trusted(),
+            mem_start,
+            addr.checked_mul(8).map(i32::try_from).unwrap().unwrap(),
+        );
+        self.builder.def_var(Self::var(dst), v);
+    }
+
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if !bank.is_writable() {
+            return;
+        }
+
+        let addr = self.layout.address(bank, addr);
+        let v = self.use_var(src);
+
+        let mem_start = self.builder.use_var(Variable::from_u32(VAR_MEM_START));
+        self.builder.ins().store(
+            MemFlags::trusted(),
+            v,
+            mem_start,
+            addr.checked_mul(8).map(i32::try_from).unwrap().unwrap(),
+        );
+    }
+}
+
+impl<'a> Emitter<'a> {
+    fn use_var(&mut self, v: u8) -> ir::entities::Value {
+        self.builder.use_var(Self::var(v))
+    }
+
+    fn var(v: u8) -> Variable {
+        Variable::from_u32(v as u32)
+    }
+
+    fn branch_ins<F>(&mut self, offset: u32, instruction_func: F)
+    where
+        F: FnOnce(&mut FunctionBuilder, Block, Block) -> ir::Inst,
+    {
+        let resume_block = self.builder.create_block();
+        let target_instruction = self.next_instruction + offset;
+        let jump_block = *self
+            .upcoming_blocks
+            .entry(target_instruction)
+            .or_insert_with(|| self.builder.create_block());
+
+        self.end_fuel_block();
+        instruction_func(&mut self.builder, jump_block, resume_block);
+
+        self.builder.seal_block(resume_block);
+        self.builder.switch_to_block(resume_block);
+        self.begin_fuel_block();
+    }
+
+    /// Consume the fuel of the block that starts here, or return if the context does not refuel
+    /// when it runs out.
+    fn begin_fuel_block(&mut self) {
+        let Some(fuel) = &mut self.fuel else {
+            return;
+        };
+
+        let fuel_ptr = self.builder.use_var(Variable::from_u32(VAR_FUEL));
+        let remaining = self.builder.ins().load(
+            ir::types::I64,
+            MemFlags::trusted(),
+            fuel_ptr,
+            FuelContext::REMAINING_OFFSET,
+        );
+        let cost = self.builder.ins().iconst(ir::types::I64, 0);
+        fuel.block_cost_inst = Some(self.builder.func.dfg.value_def(cost).unwrap_inst());
+        fuel.block_cost = 0;
+
+        let left = self.builder.ins().isub(remaining, cost);
+        let store = self.builder.ins().store(
+            MemFlags::trusted(),
+            left,
+            fuel_ptr,
+            FuelContext::REMAINING_OFFSET,
+        );
+
+        let enough = self
+            .builder
+            .ins()
+            .icmp(IntCC::UnsignedGreaterThanOrEqual, remaining, cost);
+        let block = self.builder.create_block();
+        let refuel_block = self.builder.create_block();
+        self.builder.set_cold_block(refuel_block);
+        let branch = self
+            .builder
+            .ins()
+            .brif(enough, block, &[], refuel_block, &[]);
+        self.fuel_checks.push(FuelCheck {
+            load: self.builder.func.dfg.value_def(remaining).unwrap_inst(),
+            store,
+            branch,
+            block,
+        });
+        self.builder.seal_block(refuel_block);
+        self.builder.switch_to_block(refuel_block);
+
+        let pointer_type = self.module.target_config().pointer_type();
+        let refuel = self.builder.ins().load(
+            pointer_type,
+            MemFlags::trusted(),
+            fuel_ptr,
+            FuelContext::REFUEL_OFFSET,
+        );
+        let call = self
+            .builder
+            .ins()
+            .call_indirect(fuel.refuel_sig, refuel, &[fuel_ptr]);
+        let refueled = self.builder.inst_results(call)[0];
+        self.builder
+            .ins()
+            .brif(refueled, block, &[], fuel.out_of_fuel, &[]);
+        self.builder.seal_block(block);
+        self.builder.switch_to_block(block);
+    }
+
+    /// Fill in the cost of the current block, now that all of its instructions are known.
+    fn end_fuel_block(&mut self) {
+        if let Some(Fuel {
+            block_cost_inst: Some(inst),
+            block_cost,
+            ..
+        }) = self.fuel
+        {
+            self.builder
+                .func
+                .dfg
+                .replace(inst)
+                .iconst(ir::types::I64, i64::from(block_cost));
+        }
+    }
+}
+
+/// Runs the code compiled by [Cranelift].
+///
+/// Cloning a runner is cheap, the clones share the machine code.
+#[derive(Clone)]
+pub struct Runner {
+    main: *const u8,
+    /// The copy of `main` without fuel checks.
+    unmetered_main: *const u8,
+    /// Keeps the machine code that `main` points into alive.
+    _code: Arc<Code>,
+    function_count: u32,
+    instruction_count: usize,
+    code_size: usize,
+    layout: MemoryLayout,
+    host: HostFunctions,
+}
+
+/// The module that owns the machine code, which is freed once no runner uses it anymore.
+struct Code(Option<JITModule>);
+
+// Stepping only calls the finalized code, which never changes. The module is only used to free
+// the code when the last runner is dropped.
+unsafe impl Send for Runner {}
+unsafe impl Sync for Runner {}
+unsafe impl Send for Code {}
+unsafe impl Sync for Code {}
+
+impl crate::Runner for Runner {
+    fn step(&self, memory: &mut [i64]) {
+        let mut context =
+            FuelContext::bounded(u64::MAX).with_host_functions(&self.host, self.layout);
+        self.run(memory, &mut context, false);
+    }
+
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        let mut context = FuelContext::bounded(fuel).with_host_functions(&self.host, self.layout);
+        if !self.run(memory, &mut context, true) {
+            return StepResult::OutOfFuel;
+        }
+
+        StepResult::Completed {
+            fuel_used: fuel - context.remaining,
+        }
+    }
+
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        fuel::restore_on_abort(self.layout, memory, |memory| {
+            let mut context =
+                FuelContext::until(deadline).with_host_functions(&self.host, self.layout);
+            self.run(memory, &mut context, true)
+        })
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// The size of the machine code of the functions.
+    fn code_size(&self) -> Option<usize> {
+        Some(self.code_size)
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl Runner {
+    /// Call the main function, returning false if it ran out of fuel. Without `metered`, the
+    /// copy without fuel checks is called, which never runs out.
+    fn run(&self, memory: &mut [i64], context: &mut FuelContext, metered: bool) -> bool {
+        // It would be unsound to call the compiled code with an invalid pointer.
+        assert!(self.layout.size() as usize <= memory.len());
+
+        let main = if metered {
+            self.main
+        } else {
+            self.unmetered_main
+        };
+        let main: extern "C" fn(*mut i64, *mut FuelContext) -> u8 = unsafe { mem::transmute(main) };
+
+        self.layout.init_step(memory);
+
+        main(memory.as_mut_ptr(), context) == 0
+    }
+}
+
+impl Drop for Code {
+    fn drop(&mut self) {
+        if let Some(module) = self.0.take() {
+            // We can do this because the pointers given out by the module
+            // are never exposed.
+            unsafe {
+                module.free_memory();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{random_code, random_memory, Xorshift},
+        Compiler,
+    };
+
+    #[test]
+    fn capture_clif() {
+        let mut gen = Cranelift::new();
+        gen.set_capture_clif(true);
+        let mut compiler = Compiler::new(gen);
+
+        // Two functions separated by an end of function instruction.
+        compiler.compile(&[u64::MAX, 0, u64::MAX], 1, 1, 0, 0);
+
+        let clif = compiler.generator().clif().unwrap();
+        assert_eq!(clif.len(), 2);
+        assert!(clif.iter().all(|f| f.starts_with("function ")));
+
+        compiler.generator_mut().set_capture_clif(false);
+        assert!(compiler.generator().clif().is_none());
+    }
+
+    #[test]
+    fn report() {
+        let mut compiler = Compiler::new(Cranelift::new());
+        compiler.compile(&[u64::MAX, 0, u64::MAX], 1, 1, 0, 0);
+
+        let report = compiler.generator().report();
+        assert_eq!(report.functions.len(), 2);
+        assert!(report.functions.iter().all(|f| f.code_size > 0));
+        assert!(report.compile_time > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn fuel_matches_interpreter() {
+        use crate::{codegen::Interpreter, Runner as _};
+
+        let mut rng = Xorshift::default();
+
+        let mut cranelift = Compiler::new(Cranelift::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+
+            let reference = interpreter.compile(&code, 3, 8, 4, 4);
+            let mut expected = memory.clone();
+            let result = reference.step_bounded(&mut expected, u64::MAX);
+            let StepResult::Completed { fuel_used } = result else {
+                unreachable!()
+            };
+
+            let runner = cranelift.compile(&code, 3, 8, 4, 4);
+            let mut actual = memory.clone();
+            assert_eq!(runner.step_bounded(&mut actual, fuel_used), result);
+            assert_eq!(actual, expected);
+            if fuel_used > 0 {
+                assert_eq!(
+                    runner.step_bounded(&mut memory.clone(), fuel_used - 1),
+                    StepResult::OutOfFuel
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        use crate::{
+            codegen::Interpreter,
+            testing::{differential, Backend},
+        };
+
+        let mut rng = Xorshift::default();
+
+        let mut cranelift = Compiler::new(Cranelift::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::new("cranelift", &mut cranelift),
+        ];
+        let layout = MemoryLayout::new(8, 4, 4).with_scratch_size(2);
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 18);
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+        }
+    }
+
+    #[test]
+    fn clones_share_code() {
+        use crate::{codegen::Interpreter, Runner as _};
+
+        let mut rng = Xorshift::default();
+
+        let code = random_code(&mut rng, 256);
+        let memory = random_memory(&mut rng, 16);
+
+        let mut expected = memory.clone();
+        Compiler::new(Interpreter::new())
+            .compile(&code, 3, 8, 4, 4)
+            .step(&mut expected);
+
+        // The clones keep the code alive after the original and its compiler are gone
+        let clones = {
+            let runner = Compiler::new(Cranelift::new()).compile(&code, 3, 8, 4, 4);
+            vec![runner.clone(), runner.clone()]
+        };
+        std::thread::scope(|scope| {
+            for runner in clones {
+                let mut actual = memory.clone();
+                let expected = &expected;
+                scope.spawn(move || {
+                    runner.step(&mut actual);
+                    assert_eq!(&actual, expected);
+                });
+            }
+        });
+    }
+
+    #[cfg(feature = "perf-map")]
+    #[test]
+    fn perf_map() {
+        Compiler::new(Cranelift::new()).compile(&[u64::MAX, 0, u64::MAX], 1, 1, 0, 0);
+
+        let map = std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+        for name in ["function_0", "function_1"] {
+            assert!(map
+                .lines()
+                .any(|line| line.ends_with(&format!(" aivm::cranelift::{name}"))));
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use super::{finish_isa, host_isa, Generator, OptLevel, UnsupportedTarget};
+use crate::{codegen, MemoryBank, MemoryLayout};
+
+use cranelift::{
+    codegen::{
+        ir::{self, UserFuncName},
+        isa::{self, TargetIsa},
+        settings::{self, Configurable},
+    },
+    frontend::FunctionBuilder,
+    prelude::*,
+};
+use cranelift_module::{default_libcall_names, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use target_lexicon::Triple;
+
+use std::{num::NonZeroU32, str::FromStr, sync::Arc};
+
+/// A code generator that uses cranelift to compile AIVM code ahead of time into a relocatable
+/// object file.
+///
+/// Instead of a runner that can be called directly, compiling produces an [ObjectCode] that can
+/// be linked into another binary.
+pub struct CraneliftObject {
+    gen: Generator<ObjectModule>,
+    symbol: String,
+    isa: Arc<dyn TargetIsa>,
+}
+
+impl codegen::private::CodeGeneratorImpl for CraneliftObject {
+    type Runner = ObjectCode;
+    type Emitter<'a> = super::Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.gen.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        let symbol = &self.symbol;
+        self.gen.begin(function_count, layout, |i| {
+            (format!("{symbol}.{i}"), Linkage::Local)
+        });
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        self.gen.begin_function(idx)
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        self.gen.define_cur_function();
+        self.define_entry();
+
+        let module = self
+            .gen
+            .replace_module(Self::create_object_module(self.isa.clone()));
+        let bytes = module.finish().emit().unwrap();
+
+        ObjectCode {
+            bytes,
+            symbol: self.symbol.clone(),
+            function_count: self.gen.functions.len() as u32,
+            instruction_count: self.gen.instruction_count,
+            code_size: self.gen.code_size(),
+            layout: self.gen.layout,
+        }
+    }
+}
+
+impl CraneliftObject {
+    /// Create a new generator for the host machine that optimizes for [speed](OptLevel::Speed),
+    /// the entry point of the compiled code will be exported as `symbol`.
+    ///
+    /// # Panics
+    /// If cranelift does not support the host machine.
+    pub fn new(symbol: impl Into<String>) -> Self {
+        Self::with_opt_level(symbol, OptLevel::Speed)
+    }
+
+    /// Like [new](Self::new), but with the given optimization level.
+    ///
+    /// # Panics
+    /// If cranelift does not support the host machine.
+    pub fn with_opt_level(symbol: impl Into<String>, opt_level: OptLevel) -> Self {
+        Self::try_with_opt_level(symbol, opt_level).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like [with_opt_level](Self::with_opt_level), but returns an error if cranelift does not
+    /// support the host machine.
+    pub fn try_with_opt_level(
+        symbol: impl Into<String>,
+        opt_level: OptLevel,
+    ) -> Result<Self, UnsupportedTarget> {
+        let isa = host_isa(Self::flag_builder(), opt_level)?;
+        Ok(Self::with_isa(symbol.into(), isa))
+    }
+
+    /// Create a new generator that cross compiles for the target with the given triple, for
+    /// example `aarch64-unknown-linux-gnu`.
+    pub fn for_target(
+        symbol: impl Into<String>,
+        triple: &str,
+        opt_level: OptLevel,
+    ) -> Result<Self, UnsupportedTarget> {
+        let triple = Triple::from_str(triple).map_err(UnsupportedTarget::new)?;
+        let isa_builder = isa::lookup(triple).map_err(UnsupportedTarget::new)?;
+        let isa = finish_isa(isa_builder, Self::flag_builder(), opt_level)?;
+        Ok(Self::with_isa(symbol.into(), isa))
+    }
+
+    /// The name of the instruction set code is generated for, for example `x64` or `aarch64`.
+    pub fn isa_name(&self) -> &'static str {
+        self.isa.name()
+    }
+
+    fn with_isa(symbol: String, isa: Arc<dyn TargetIsa>) -> Self {
+        Self {
+            gen: Generator::new(Self::create_object_module(isa.clone()), false),
+            symbol,
+            isa,
+        }
+    }
+
+    /// Define the exported entry point, which initializes the output, clears the scratch bank
+    /// and calls the main function.
+    fn define_entry(&mut self) {
+        let gen = &mut self.gen;
+        let sig = gen.make_signature();
+        let entry = gen
+            .module
+            .declare_function(&self.symbol, Linkage::Export, &sig)
+            .unwrap();
+
+        gen.module.clear_context(&mut gen.ctx);
+        gen.ctx.func.signature = sig;
+        gen.ctx.func.name = UserFuncName::user(0, entry.as_u32());
+
+        let pointer_type = gen.module.target_config().pointer_type();
+        let main_ref = gen
+            .module
+            .declare_func_in_func(gen.functions[0], &mut gen.ctx.func);
+        let mut builder = FunctionBuilder::new(&mut gen.ctx.func, &mut gen.func_ctx);
+
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        builder.seal_block(entry_block);
+        let mem_start = builder.block_params(entry_block)[0];
+
+        let fills = [
+            (
+                gen.layout.bank_range(MemoryBank::Output),
+                gen.layout.output_init.value(),
+            ),
+            (gen.layout.bank_range(MemoryBank::Scratch), Some(0)),
+        ];
+        for (range, value) in fills {
+            let Some(value) = value.filter(|_| !range.is_empty()) else {
+                continue;
+            };
+            let clear_block = builder.create_block();
+            let done_block = builder.create_block();
+            let ptr = builder.append_block_param(clear_block, pointer_type);
+
+            let start = builder.ins().iadd_imm(mem_start, range.start as i64 * 8);
+            let end = builder.ins().iadd_imm(mem_start, range.end as i64 * 8);
+            builder.ins().jump(clear_block, &[start]);
+
+            builder.switch_to_block(clear_block);
+            let value = builder.ins().iconst(ir::types::I64, value);
+            builder.ins().store(MemFlags::trusted(), value, ptr, 0);
+            let next = builder.ins().iadd_imm(ptr, 8);
+            let more = builder.ins().icmp(IntCC::UnsignedLessThan, next, end);
+            builder
+                .ins()
+                .brif(more, clear_block, &[next], done_block, &[]);
+            builder.seal_block(clear_block);
+
+            builder.switch_to_block(done_block);
+            builder.seal_block(done_block);
+        }
+
+        builder.ins().call(main_ref, &[mem_start]);
+        builder.ins().return_(&[]);
+        builder.finalize();
+
+        gen.module.define_function(entry, &mut gen.ctx).unwrap();
+    }
+
+    fn flag_builder() -> settings::Builder {
+        let mut flag_builder = settings::builder();
+        // The object can end up in a position independent executable or shared library.
+        flag_builder.set("is_pic", "true").unwrap();
+
+        flag_builder
+    }
+
+    fn create_object_module(isa: Arc<dyn TargetIsa>) -> ObjectModule {
+        ObjectModule::new(ObjectBuilder::new(isa, "aivm", default_libcall_names()).unwrap())
+    }
+}
+
+/// A relocatable object file containing compiled AIVM code.
+///
+/// The object exports a single function with the C signature `void symbol(int64_t *memory)`,
+/// which behaves like [Runner::step](crate::Runner::step) except that it does not check the size
+/// of the memory. The memory must contain at least [layout().size()](MemoryLayout::size) values.
+pub struct ObjectCode {
+    bytes: Vec<u8>,
+    symbol: String,
+    function_count: u32,
+    instruction_count: usize,
+    code_size: usize,
+    layout: MemoryLayout,
+}
+
+impl ObjectCode {
+    /// The contents of the object file.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Take the contents of the object file.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// The name of the exported entry point.
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl crate::RunnerInfo for ObjectCode {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// The size of the machine code of the functions, without the entry point and the rest of
+    /// the object file.
+    fn code_size(&self) -> Option<usize> {
+        Some(self.code_size)
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compiler;
+
+    use object::{Object, ObjectSymbol};
+
+    #[test]
+    fn exports_entry_symbol() {
+        let mut compiler = Compiler::new(CraneliftObject::new("aivm_agent"));
+        let code = compiler.compile(&[0xFFFF_0000_0000_1234; 8], 1, 2, 2, 2);
+
+        assert_eq!(code.symbol(), "aivm_agent");
+        assert_eq!(code.layout(), MemoryLayout::new(2, 2, 2));
+
+        let file = object::File::parse(code.bytes()).unwrap();
+        let entry = file
+            .symbols()
+            .find(|s| s.name() == Ok("aivm_agent"))
+            .unwrap();
+        assert!(entry.is_global());
+        assert!(entry.is_definition());
+    }
+
+    #[test]
+    fn cross_compile_spec() {
+        for triple in [
+            "x86_64-unknown-linux-gnu",
+            "aarch64-unknown-linux-gnu",
+            "aarch64-apple-darwin",
+        ] {
+            let gen = CraneliftObject::for_target("aivm_agent", triple, OptLevel::Speed).unwrap();
+            let mut compiler = Compiler::new(gen);
+
+            // Make sure every instruction can be lowered for the target.
+            for vector in crate::spec::test_vectors() {
+                let layout = vector.layout;
+                let code = compiler.compile(
+                    &vector.code,
+                    vector.lowest_function_level,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                );
+                object::File::parse(code.bytes()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_target() {
+        assert!(CraneliftObject::for_target("aivm_agent", "nonsense", OptLevel::None).is_err());
+    }
+}
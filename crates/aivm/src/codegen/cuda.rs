@@ -0,0 +1,612 @@
+use crate::{
+    codegen,
+    compile::{CompareKind, REGISTER_COUNT},
+    BatchRunner, MemoryBank, MemoryLayout,
+};
+
+use cudarc::{
+    driver::{CudaContext, CudaFunction, CudaStream, LaunchConfig, PushKernelArg},
+    nvrtc::Ptx,
+};
+use std::{fmt, fmt::Write, num::NonZeroU32, sync::Arc};
+
+/// The amount of memories one block of threads runs on.
+const BLOCK_SIZE: u32 = 128;
+/// The name of the kernel in the generated PTX.
+const KERNEL: &str = "aivm_step";
+
+/// The error returned when no CUDA device can be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CudaError {
+    reason: String,
+}
+
+impl CudaError {
+    fn new(reason: impl fmt::Display) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CudaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CUDA error: {}", self.reason)
+    }
+}
+
+impl std::error::Error for CudaError {}
+
+/// A code generator that translates AIVM code to PTX, which runs the code on thousands of
+/// memories at once on an NVIDIA GPU.
+///
+/// Code is run through the [BatchRunner] trait, every thread runs the code once on its own
+/// memory, which stays in global memory.
+pub struct Cuda {
+    stream: Arc<CudaStream>,
+    translator: Translator,
+}
+
+impl Cuda {
+    /// Create a new generator that runs code on the CUDA device with the given ordinal.
+    ///
+    /// Fails if the CUDA driver is not installed or the device does not exist.
+    pub fn new(ordinal: usize) -> Result<Self, CudaError> {
+        // The driver is loaded when it is first used, which panics if it is missing
+        if !unsafe { cudarc::driver::sys::is_culib_present() } {
+            return Err(CudaError::new("the CUDA driver library was not found"));
+        }
+        let context = CudaContext::new(ordinal).map_err(CudaError::new)?;
+
+        Ok(Self::with_stream(context.default_stream()))
+    }
+
+    /// Create a new generator that runs code on a stream that was created by the caller.
+    pub fn with_stream(stream: Arc<CudaStream>) -> Self {
+        Self {
+            stream,
+            translator: Translator::default(),
+        }
+    }
+}
+
+impl codegen::private::CodeGeneratorImpl for Cuda {
+    type Runner = Runner;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.translator.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.translator.begin(function_count, layout);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        self.translator.begin_function(idx)
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        let source = self.translator.render();
+        let module = self
+            .stream
+            .context()
+            .load_module(Ptx::from_src(source.as_str()))
+            .expect("failed to load generated PTX");
+        let kernel = module.load_function(KERNEL).unwrap();
+
+        Runner {
+            stream: self.stream.clone(),
+            kernel,
+            source,
+            function_count: self.translator.functions.len() as u32,
+            instruction_count: self.translator.instruction_count(),
+            layout: self.translator.layout,
+        }
+    }
+}
+
+/// Runs code generated by the [Cuda] code generator on the GPU.
+pub struct Runner {
+    stream: Arc<CudaStream>,
+    kernel: CudaFunction,
+    source: String,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+impl Runner {
+    /// The generated PTX.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl BatchRunner for Runner {
+    fn step_strided(&self, memories: &mut [i64], stride: usize) {
+        assert!(stride >= self.layout.size() as usize);
+        assert_eq!(memories.len() % stride, 0);
+        let count = u32::try_from(memories.len() / stride).unwrap();
+        if count == 0 {
+            return;
+        }
+        let stride = u32::try_from(stride).unwrap();
+
+        let mut buffer = self.stream.clone_htod(memories).unwrap();
+        let mut launch = self.stream.launch_builder(&self.kernel);
+        launch.arg(&mut buffer).arg(&stride).arg(&count);
+        let config = LaunchConfig {
+            grid_dim: (count.div_ceil(BLOCK_SIZE), 1, 1),
+            block_dim: (BLOCK_SIZE, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        // SAFETY: the kernel takes the memories, the stride and the count, and only accesses the
+        // memories of threads that are smaller than the count
+        unsafe { launch.launch(config) }.unwrap();
+
+        self.stream.memcpy_dtoh(&buffer, memories).unwrap();
+        self.stream.synchronize().unwrap();
+    }
+}
+
+/// Translates code to PTX, separate from the device so it can be tested without a GPU.
+#[derive(Default)]
+struct Translator {
+    layout: MemoryLayout,
+    constants: Arc<[i64]>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Default)]
+struct Function {
+    lines: Vec<String>,
+    /// The instructions that are the target of a branch.
+    branch_targets: Vec<u32>,
+    /// The amount of VM instructions, set when the function is finalized.
+    instruction_count: u32,
+}
+
+impl Translator {
+    fn instruction_count(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|func| func.instruction_count as usize)
+            .sum()
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.functions.clear();
+        self.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            Function::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Emitter<'_> {
+        let func = &mut self.functions[usize::try_from(idx).unwrap()];
+        func.lines.clear();
+        func.branch_targets.clear();
+
+        Emitter {
+            func,
+            layout: self.layout,
+            constants: &self.constants,
+            instruction_count: 0,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut source = format!(
+            "// Generated by aivm {}.\n\n.version 7.0\n.target sm_50\n.address_size 64\n",
+            env!("CARGO_PKG_VERSION")
+        );
+
+        // Functions have to be defined before they are called, and callees always have a higher
+        // index than their caller
+        for (idx, func) in self.functions.iter().enumerate().rev() {
+            func.render(idx, &mut source);
+        }
+
+        write!(
+            source,
+            "\n.visible .entry {KERNEL}(\n    \
+             .param .u64 memories_param,\n    \
+             .param .u32 stride_param,\n    \
+             .param .u32 count_param\n)\n{{\n    \
+             .reg .b64 %base;\n    \
+             .reg .b64 %offset;\n    \
+             .reg .b64 %fill;\n    \
+             .reg .b32 %index;\n    \
+             .reg .b32 %tmp<2>;\n    \
+             .reg .pred %p;\n\n    \
+             ld.param.u64 %base, [memories_param];\n    \
+             cvta.to.global.u64 %base, %base;\n    \
+             mov.u32 %tmp0, %ctaid.x;\n    \
+             mov.u32 %tmp1, %ntid.x;\n    \
+             mov.u32 %index, %tid.x;\n    \
+             mad.lo.u32 %index, %tmp0, %tmp1, %index;\n    \
+             ld.param.u32 %tmp0, [count_param];\n    \
+             setp.ge.u32 %p, %index, %tmp0;\n    \
+             @%p bra done;\n    \
+             ld.param.u32 %tmp0, [stride_param];\n    \
+             mul.wide.u32 %offset, %index, %tmp0;\n    \
+             shl.b64 %offset, %offset, 3;\n    \
+             add.s64 %base, %base, %offset;\n"
+        )
+        .unwrap();
+        let fills = [
+            (
+                self.layout.bank_range(MemoryBank::Output),
+                self.layout.output_init.value(),
+            ),
+            (self.layout.bank_range(MemoryBank::Scratch), Some(0)),
+        ];
+        for (range, value) in fills {
+            let Some(value) = value.filter(|_| !range.is_empty()) else {
+                continue;
+            };
+            writeln!(source, "    mov.b64 %fill, {:#X};", value as u64).unwrap();
+            for i in range {
+                writeln!(source, "    st.global.u64 [%base+{}], %fill;", i * 8).unwrap();
+            }
+        }
+        source.push_str(
+            "    {\n        \
+             .param .u64 arg;\n        \
+             st.param.u64 [arg], %base;\n        \
+             call function_0, (arg);\n    \
+             }\n\
+             done:\n    \
+             ret;\n}\n",
+        );
+
+        source
+    }
+}
+
+impl Function {
+    fn render(&self, idx: usize, source: &mut String) {
+        write!(
+            source,
+            "\n.func function_{idx}(.param .u64 base_param)\n{{\n    \
+             .reg .b64 %base;\n    \
+             .reg .b64 %r<{REGISTER_COUNT}>;\n    \
+             .reg .b64 %t<2>;\n    \
+             .reg .b32 %w;\n    \
+             .reg .pred %p;\n\n    \
+             ld.param.u64 %base, [base_param];\n"
+        )
+        .unwrap();
+        // The assembler removes the ones that are overwritten before they are read
+        for r in 0..REGISTER_COUNT {
+            writeln!(source, "    mov.b64 %r{r}, 0;").unwrap();
+        }
+
+        for line in &self.lines {
+            if line.ends_with(':') {
+                writeln!(source, "{line}").unwrap();
+            } else {
+                writeln!(source, "    {line}").unwrap();
+            }
+        }
+
+        source.push_str("    ret;\n}\n");
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Function,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    instruction_count: u32,
+}
+
+impl<'a> Emitter<'a> {
+    fn line(&mut self, line: std::fmt::Arguments) {
+        self.func.lines.push(line.to_string());
+    }
+
+    fn place_branch_target(&mut self) {
+        let i = self.instruction_count;
+        if self.func.branch_targets.contains(&i) {
+            self.line(format_args!("l{i}:"));
+        }
+    }
+
+    fn branch(&mut self, compare: &str, a: u8, b: std::fmt::Arguments, offset: u32) {
+        // The instruction counter already points at the next instruction
+        let target = self.instruction_count + offset;
+        if !self.func.branch_targets.contains(&target) {
+            self.func.branch_targets.push(target);
+        }
+        self.line(format_args!("setp.{compare}.s64 %p, %r{a}, {b};"));
+        self.line(format_args!("@%p bra l{target};"));
+    }
+
+    fn rotate(&mut self, dst: u8, src: u8, left: bool, amount: u8) {
+        if amount == 0 {
+            self.line(format_args!("mov.b64 %r{dst}, %r{src};"));
+        } else {
+            let (first, second) = if left {
+                ("shl.b64", "shr.u64")
+            } else {
+                ("shr.u64", "shl.b64")
+            };
+            self.line(format_args!("{first} %t0, %r{src}, {amount};"));
+            self.line(format_args!("{second} %t1, %r{src}, {};", 64 - amount));
+            self.line(format_args!("or.b64 %r{dst}, %t0, %t1;"));
+        }
+    }
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        self.place_branch_target();
+        self.instruction_count += 1;
+    }
+
+    fn finalize(mut self) {
+        self.place_branch_target();
+        self.func.instruction_count = self.instruction_count;
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.line(format_args!(
+            "{{ .param .u64 arg; st.param.u64 [arg], %base; call function_{idx}, (arg); }}"
+        ));
+    }
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, _id: u32) {
+        panic!("host calls are not supported by the CUDA code generator");
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("add.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("sub.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("mul.lo.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("mul.hi.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("mul.hi.u64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("neg.s64 %r{dst}, %r{src};"));
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        // abs.s64 leaves the most negative value undefined, this wraps like the other backends
+        self.line(format_args!("neg.s64 %t0, %r{src};"));
+        self.line(format_args!("max.s64 %r{dst}, %r{src}, %t0;"));
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.line(format_args!("add.s64 %r{dst}, %r{dst}, 1;"));
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.line(format_args!("sub.s64 %r{dst}, %r{dst}, 1;"));
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("min.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("max.s64 %r{dst}, %r{a}, %r{b};"));
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("or.b64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("and.b64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.line(format_args!("xor.b64 %r{dst}, %r{a}, %r{b};"));
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("not.b64 %r{dst}, %r{src};"));
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!("shl.b64 %r{dst}, %r{src}, {amount};"));
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.line(format_args!("shr.s64 %r{dst}, %r{src}, {amount};"));
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.rotate(dst, src, true, amount);
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.rotate(dst, src, false, amount);
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.line(format_args!("and.b64 %t0, %r{a}, %r{mask};"));
+        self.line(format_args!("not.b64 %t1, %r{mask};"));
+        self.line(format_args!("and.b64 %t1, %r{b}, %t1;"));
+        self.line(format_args!("or.b64 %r{dst}, %t0, %t1;"));
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("popc.b64 %w, %r{src};"));
+        self.line(format_args!("cvt.u64.u32 %r{dst}, %w;"));
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.line(format_args!("brev.b64 %r{dst}, %r{src};"));
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        let compare = match compare_kind {
+            CompareKind::Eq => "eq",
+            CompareKind::Neq => "ne",
+            CompareKind::Gt => "gt",
+            CompareKind::Lt => "lt",
+        };
+        self.branch(compare, a, format_args!("%r{b}"), offset);
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.branch("eq", src, format_args!("0"), offset);
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.branch("ne", src, format_args!("0"), offset);
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        if bank == MemoryBank::Const {
+            let value = self.constants[usize::try_from(addr).unwrap()] as u64;
+            self.line(format_args!("mov.b64 %r{dst}, {value:#X};"));
+            return;
+        }
+        let offset = self.layout.address(bank, addr) * 8;
+        self.line(format_args!("ld.global.u64 %r{dst}, [%base+{offset}];"));
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let offset = self.layout.address(bank, addr) * 8;
+            self.line(format_args!("st.global.u64 [%base+{offset}], %r{src};"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{random_code, random_memory, Xorshift},
+        Compiler,
+    };
+
+    /// Only translates code, so the output can be checked without a GPU.
+    #[derive(Default)]
+    struct Source(Translator);
+
+    impl codegen::private::CodeGeneratorImpl for Source {
+        type Runner = String;
+        type Emitter<'a> = Emitter<'a>;
+
+        fn set_constants(&mut self, constants: &Arc<[i64]>) {
+            self.0.constants = constants.clone();
+        }
+
+        fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+            self.0.begin(function_count, layout);
+        }
+
+        fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+            self.0.begin_function(idx)
+        }
+
+        fn finish(&mut self) -> Self::Runner {
+            self.0.render()
+        }
+    }
+
+    fn random_programs() -> impl Iterator<Item = (Vec<u64>, Vec<i64>)> {
+        let mut rng = Xorshift::default();
+
+        std::iter::repeat_with(move || {
+            let code = random_code(&mut rng, 256);
+            let memories = random_memory(&mut rng, 16 * 64);
+            (code, memories)
+        })
+    }
+
+    #[test]
+    fn branch_labels() {
+        let mut compiler = Compiler::new(Source::default());
+        let mut branches = 0;
+        for (code, _) in random_programs().take(20) {
+            let source = compiler.compile(&code, 3, 8, 4, 4);
+            assert!(source.contains(&format!(".visible .entry {KERNEL}(")));
+
+            // Every branch needs exactly one label in the same function
+            for func in source.split("\n.func ").skip(1) {
+                for target in func
+                    .lines()
+                    .filter_map(|l| l.trim().strip_prefix("@%p bra "))
+                {
+                    let label = format!("\n{}:\n", target.trim_end_matches(';'));
+                    assert_eq!(func.matches(&label).count(), 1, "{label:?} in {func}");
+                    branches += 1;
+                }
+            }
+        }
+        assert!(branches > 0);
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut compiler = match Cuda::new(0) {
+            Ok(gen) => Compiler::new(gen),
+            Err(e) => {
+                eprintln!("skipping, {e}");
+                return;
+            }
+        };
+
+        let mut interpreter = Compiler::new(Interpreter::new());
+        for (code, memories) in random_programs().take(20) {
+            let interpreted = interpreter.compile(&code, 3, 8, 4, 4);
+            let mut expected = memories.clone();
+            interpreted.step_strided(&mut expected, 16);
+            let mut actual = memories;
+            compiler
+                .compile(&code, 3, 8, 4, 4)
+                .step_strided(&mut actual, 16);
+
+            assert_eq!(actual, expected, "code: {code:x?}");
+        }
+    }
+
+    #[test]
+    fn matches_spec() {
+        let mut compiler = match Cuda::new(0) {
+            Ok(gen) => Compiler::new(gen),
+            Err(e) => {
+                eprintln!("skipping, {e}");
+                return;
+            }
+        };
+
+        for vector in crate::spec::test_vectors() {
+            let layout = vector.layout;
+            let runner = compiler.compile(
+                &vector.code,
+                vector.lowest_function_level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            );
+
+            let mut memory = vector.memory.clone();
+            runner.step_strided(&mut memory, vector.memory.len());
+            assert_eq!(memory, vector.expected, "{}", vector.name);
+        }
+    }
+}
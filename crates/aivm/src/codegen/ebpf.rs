@@ -0,0 +1,771 @@
+use crate::{
+    codegen,
+    compile::{CompareKind, REGISTER_COUNT},
+    MemoryBank, MemoryLayout,
+};
+
+use std::{num::NonZeroU32, sync::Arc};
+
+// Instruction classes
+const ALU64: u8 = 0x07;
+const JMP: u8 = 0x05;
+const LDX_DW: u8 = 0x79;
+const STX_DW: u8 = 0x7B;
+const ST_DW: u8 = 0x7A;
+const LD_DW_IMM: u8 = 0x18;
+// Operand sources
+const K: u8 = 0x00;
+const X: u8 = 0x08;
+// ALU operations
+const ADD: u8 = 0x00;
+const SUB: u8 = 0x10;
+const MUL: u8 = 0x20;
+const OR: u8 = 0x40;
+const AND: u8 = 0x50;
+const LSH: u8 = 0x60;
+const RSH: u8 = 0x70;
+const NEG: u8 = 0x80;
+const XOR: u8 = 0xA0;
+const MOV: u8 = 0xB0;
+const ARSH: u8 = 0xC0;
+// Jump operations
+const JEQ: u8 = 0x10;
+const JNE: u8 = 0x50;
+const JSGT: u8 = 0x60;
+const JSGE: u8 = 0x70;
+const CALL: u8 = 0x80;
+const EXIT: u8 = 0x90;
+const JSLT: u8 = 0xC0;
+/// The source register of a call to a function in the same program.
+const PSEUDO_CALL: u8 = 1;
+
+// Register usage, r1 to r5 and r0 are scratch registers
+/// The start of the memory, callee saved.
+const MEMORY: u8 = 6;
+/// The start of the VM registers of the current function, callee saved.
+const FRAME: u8 = 7;
+
+/// A code generator that translates AIVM code to eBPF bytecode, so it can run in the kernel or
+/// in a sandboxed eBPF virtual machine.
+///
+/// The program is called with a pointer to the memory in `r1`, and it always returns 0. eBPF
+/// has too few registers and too little stack for the registers of the VM, so they are kept in
+/// scratch space after the memory. Every function gets its own area, which is zeroed when it is
+/// called. The caller has to provide a buffer of [EbpfProgram::memory_len] values that the
+/// program can read and write, such as the value of an array map.
+///
+/// The generated code only uses bounded forward jumps and calls to functions further down the
+/// call graph, which the kernel verifier accepts.
+#[derive(Default)]
+pub struct Ebpf {
+    layout: MemoryLayout,
+    constants: Arc<[i64]>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Default)]
+struct Function {
+    instructions: Vec<u64>,
+    /// The instructions that call another function, with the index of the callee.
+    calls: Vec<(usize, u32)>,
+    /// The amount of VM instructions, set when the function is finalized.
+    instruction_count: u32,
+}
+
+impl codegen::private::CodeGeneratorImpl for Ebpf {
+    type Runner = EbpfProgram;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        // Memory offsets are 16 bit signed integers
+        assert!(
+            layout.size() as usize * 8 <= i16::MAX as usize + 1,
+            "memory of {} values is too big for eBPF",
+            layout.size(),
+        );
+
+        self.layout = layout;
+        self.functions.clear();
+        self.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            Function::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        let func = &mut self.functions[usize::try_from(idx).unwrap()];
+        func.instructions.clear();
+        func.calls.clear();
+
+        // The offset of the frame is only known once all calls are known
+        func.instructions.extend([
+            alu(MOV | X, MEMORY, 1),
+            alu(MOV | X, FRAME, MEMORY),
+            alu_imm(ADD | K, FRAME, 0),
+        ]);
+        func.instructions
+            .extend((0..REGISTER_COUNT).map(|r| op(ST_DW, FRAME, 0, r as i16 * 8, 0)));
+
+        Emitter {
+            func,
+            layout: self.layout,
+            constants: &self.constants,
+            vm_starts: vec![],
+            branches: vec![],
+        }
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        // A function gets the frame after the frame of every function that can call it. Callees
+        // have a higher index than their caller, so one pass in order finds the deepest level.
+        let mut levels = vec![0; self.functions.len()];
+        for f in 0..self.functions.len() {
+            for &(_, callee) in &self.functions[f].calls {
+                let callee = usize::try_from(callee).unwrap();
+                levels[callee] = levels[callee].max(levels[f] + 1);
+            }
+        }
+        let frames_start = self.layout.size() as usize;
+        let frame_count = levels.iter().max().unwrap() + 1;
+
+        let mut instructions = vec![alu(MOV | X, MEMORY, 1)];
+        if let Some(value) = self.layout.output_init.value() {
+            // Stores of an immediate sign extend 32 bits, other values go through a register
+            let (opcode, imm) = match i32::try_from(value) {
+                Ok(imm) => (ST_DW, imm),
+                Err(_) => {
+                    instructions.extend([
+                        op(LD_DW_IMM, 0, 0, 0, value as i32),
+                        op(0, 0, 0, 0, (value >> 32) as i32),
+                    ]);
+                    (STX_DW, 0)
+                }
+            };
+            for i in self.layout.bank_range(MemoryBank::Output) {
+                instructions.push(op(opcode, MEMORY, 0, i as i16 * 8, imm));
+            }
+        }
+        for i in self.layout.bank_range(MemoryBank::Scratch) {
+            instructions.push(op(ST_DW, MEMORY, 0, i as i16 * 8, 0));
+        }
+        let entry_call = instructions.len() + 1;
+        instructions.extend([
+            alu(MOV | X, 1, MEMORY),
+            op(JMP | CALL, 0, PSEUDO_CALL, 0, 0),
+            alu_imm(MOV | K, 0, 0),
+            op(JMP | EXIT, 0, 0, 0, 0),
+        ]);
+
+        let mut starts = vec![];
+        for (func, level) in self.functions.iter_mut().zip(&levels) {
+            let frame = (frames_start + level * REGISTER_COUNT) * 8;
+            func.instructions[2] = alu_imm(ADD | K, FRAME, i32::try_from(frame).unwrap());
+
+            starts.push(instructions.len());
+            instructions.extend_from_slice(&func.instructions);
+        }
+
+        let mut patch_call = |at: usize, callee: usize| {
+            let offset = starts[callee] as i64 - at as i64 - 1;
+            instructions[at] = op(
+                JMP | CALL,
+                0,
+                PSEUDO_CALL,
+                0,
+                i32::try_from(offset).unwrap(),
+            );
+        };
+        patch_call(entry_call, 0);
+        for (func, &start) in self.functions.iter().zip(&starts) {
+            for &(at, callee) in &func.calls {
+                patch_call(start + at, usize::try_from(callee).unwrap());
+            }
+        }
+
+        EbpfProgram {
+            instructions,
+            function_count: self.functions.len() as u32,
+            instruction_count: self
+                .functions
+                .iter()
+                .map(|func| func.instruction_count as usize)
+                .sum(),
+            layout: self.layout,
+            memory_len: frames_start + frame_count * REGISTER_COUNT,
+        }
+    }
+}
+
+impl Ebpf {
+    /// Create a new generator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// eBPF bytecode generated by the [Ebpf] code generator.
+pub struct EbpfProgram {
+    instructions: Vec<u64>,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+    memory_len: usize,
+}
+
+impl EbpfProgram {
+    /// The instructions of the program, every instruction is 8 bytes with the opcode in the
+    /// lowest byte.
+    pub fn instructions(&self) -> &[u64] {
+        &self.instructions
+    }
+
+    /// The instructions as little endian bytes, as expected by an eBPF virtual machine on a
+    /// little endian host.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.instructions
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect()
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The amount of values the buffer passed to the program must contain: the memory as
+    /// described by the [layout](Self::layout), followed by scratch space for the VM registers.
+    pub fn memory_len(&self) -> usize {
+        self.memory_len
+    }
+}
+
+impl crate::RunnerInfo for EbpfProgram {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Function,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    /// The first eBPF instruction of every VM instruction.
+    vm_starts: Vec<usize>,
+    /// Jumps that need their offset patched, with the VM instruction they jump to.
+    branches: Vec<(usize, u32)>,
+}
+
+fn op(opcode: u8, dst: u8, src: u8, offset: i16, imm: i32) -> u64 {
+    u64::from(opcode)
+        | u64::from(dst | src << 4) << 8
+        | u64::from(offset as u16) << 16
+        | u64::from(imm as u32) << 32
+}
+
+fn alu(opcode: u8, dst: u8, src: u8) -> u64 {
+    op(ALU64 | opcode, dst, src, 0, 0)
+}
+
+fn alu_imm(opcode: u8, dst: u8, imm: i32) -> u64 {
+    op(ALU64 | opcode, dst, 0, 0, imm)
+}
+
+impl<'a> Emitter<'a> {
+    fn push(&mut self, instructions: impl IntoIterator<Item = u64>) {
+        self.func.instructions.extend(instructions);
+    }
+
+    /// Load a VM register into `dst`.
+    fn load(&mut self, dst: u8, vm_reg: u8) {
+        self.push([op(LDX_DW, dst, FRAME, i16::from(vm_reg) * 8, 0)]);
+    }
+
+    /// Store `src` into a VM register.
+    fn store(&mut self, vm_reg: u8, src: u8) {
+        self.push([op(STX_DW, FRAME, src, i16::from(vm_reg) * 8, 0)]);
+    }
+
+    fn load_imm64(&mut self, dst: u8, imm: u64) {
+        self.push([
+            op(LD_DW_IMM, dst, 0, 0, imm as i32),
+            op(0, 0, 0, 0, (imm >> 32) as i32),
+        ]);
+    }
+
+    fn binary(&mut self, opcode: u8, dst: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.push([alu(opcode | X, 2, 3)]);
+        self.store(dst, 2);
+    }
+
+    fn unary(&mut self, dst: u8, src: u8, instructions: impl IntoIterator<Item = u64>) {
+        self.load(2, src);
+        self.push(instructions);
+        self.store(dst, 2);
+    }
+
+    /// Compute the unsigned high half of `r2 * r3` into `r2`.
+    fn mul_high_unsigned(&mut self) {
+        self.push([
+            // The low halves
+            alu(MOV | X, 4, 2),
+            alu_imm(LSH | K, 4, 32),
+            alu_imm(RSH | K, 4, 32),
+            alu(MOV | X, 5, 3),
+            alu_imm(LSH | K, 5, 32),
+            alu_imm(RSH | K, 5, 32),
+            // The high halves
+            alu_imm(RSH | K, 2, 32),
+            alu_imm(RSH | K, 3, 32),
+            // The partial products
+            alu(MOV | X, 0, 4),
+            alu(MUL | X, 0, 5),
+            alu(MOV | X, 1, 2),
+            alu(MUL | X, 1, 5),
+            alu(MUL | X, 4, 3),
+            alu(MUL | X, 2, 3),
+            // The carry out of the low half
+            alu_imm(RSH | K, 0, 32),
+            alu(MOV | X, 5, 1),
+            alu_imm(LSH | K, 5, 32),
+            alu_imm(RSH | K, 5, 32),
+            alu(ADD | X, 0, 5),
+            alu(ADD | X, 0, 4),
+            alu_imm(RSH | K, 0, 32),
+            alu_imm(RSH | K, 1, 32),
+            alu(ADD | X, 2, 1),
+            alu(ADD | X, 2, 0),
+        ]);
+    }
+
+    fn branch(&mut self, jump: u64, target: u32) {
+        self.branches.push((self.func.instructions.len(), target));
+        self.push([jump]);
+    }
+
+    /// The VM instruction after the current one.
+    fn next_instruction(&self) -> u32 {
+        self.vm_starts.len() as u32
+    }
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        self.vm_starts.push(self.func.instructions.len());
+    }
+
+    fn finalize(mut self) {
+        self.func.instruction_count = self.next_instruction();
+        self.vm_starts.push(self.func.instructions.len());
+        self.push([alu_imm(MOV | K, 0, 0), op(JMP | EXIT, 0, 0, 0, 0)]);
+
+        for &(at, target) in &self.branches {
+            let offset = self.vm_starts[target as usize] as i64 - at as i64 - 1;
+            let offset = i16::try_from(offset).expect("function too big for eBPF jumps");
+            let jump = &mut self.func.instructions[at];
+            *jump = *jump & !(0xFFFF << 16) | u64::from(offset as u16) << 16;
+        }
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.push([alu(MOV | X, 1, MEMORY)]);
+        self.func.calls.push((self.func.instructions.len(), idx));
+        self.push([0]);
+    }
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, _id: u32) {
+        panic!("host calls are not supported by eBPF");
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(ADD, dst, a, b);
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(SUB, dst, a, b);
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(MUL, dst, a, b);
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.mul_high_unsigned();
+        // Subtract the other operand for every negative operand
+        for (x, y) in [(a, b), (b, a)] {
+            self.load(3, x);
+            self.push([op(JMP | JSGE | K, 3, 0, 2, 0)]);
+            self.load(4, y);
+            self.push([alu(SUB | X, 2, 4)]);
+        }
+        self.store(dst, 2);
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.mul_high_unsigned();
+        self.store(dst, 2);
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.unary(dst, src, [alu(NEG, 2, 0)]);
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        self.unary(dst, src, [op(JMP | JSGE | K, 2, 0, 1, 0), alu(NEG, 2, 0)]);
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.unary(dst, dst, [alu_imm(ADD | K, 2, 1)]);
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.unary(dst, dst, [alu_imm(SUB | K, 2, 1)]);
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.push([op(JMP | JSLT | X, 2, 3, 1, 0), alu(MOV | X, 2, 3)]);
+        self.store(dst, 2);
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.push([op(JMP | JSGT | X, 2, 3, 1, 0), alu(MOV | X, 2, 3)]);
+        self.store(dst, 2);
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(OR, dst, a, b);
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(AND, dst, a, b);
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.binary(XOR, dst, a, b);
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        // The immediate is sign extended
+        self.unary(dst, src, [alu_imm(XOR | K, 2, -1)]);
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.unary(dst, src, [alu_imm(LSH | K, 2, amount.into())]);
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.unary(dst, src, [alu_imm(ARSH | K, 2, amount.into())]);
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        let amount = i32::from(amount);
+        if amount == 0 {
+            self.unary(dst, src, []);
+        } else {
+            self.unary(
+                dst,
+                src,
+                [
+                    alu(MOV | X, 3, 2),
+                    alu_imm(LSH | K, 2, amount),
+                    alu_imm(RSH | K, 3, 64 - amount),
+                    alu(OR | X, 2, 3),
+                ],
+            );
+        }
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        let amount = i32::from(amount);
+        if amount == 0 {
+            self.unary(dst, src, []);
+        } else {
+            self.unary(
+                dst,
+                src,
+                [
+                    alu(MOV | X, 3, 2),
+                    alu_imm(RSH | K, 2, amount),
+                    alu_imm(LSH | K, 3, 64 - amount),
+                    alu(OR | X, 2, 3),
+                ],
+            );
+        }
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.load(2, a);
+        self.load(3, b);
+        self.load(4, mask);
+        self.push([
+            alu(AND | X, 2, 4),
+            alu_imm(XOR | K, 4, -1),
+            alu(AND | X, 3, 4),
+            alu(OR | X, 2, 3),
+        ]);
+        self.store(dst, 2);
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.load(2, src);
+        // Count the bits in every pair, nibble and byte, then sum the bytes
+        self.load_imm64(4, 0x5555_5555_5555_5555);
+        self.push([
+            alu(MOV | X, 3, 2),
+            alu_imm(RSH | K, 3, 1),
+            alu(AND | X, 3, 4),
+            alu(SUB | X, 2, 3),
+        ]);
+        self.load_imm64(4, 0x3333_3333_3333_3333);
+        self.push([
+            alu(MOV | X, 3, 2),
+            alu_imm(RSH | K, 3, 2),
+            alu(AND | X, 3, 4),
+            alu(AND | X, 2, 4),
+            alu(ADD | X, 2, 3),
+            alu(MOV | X, 3, 2),
+            alu_imm(RSH | K, 3, 4),
+            alu(ADD | X, 2, 3),
+        ]);
+        self.load_imm64(4, 0x0F0F_0F0F_0F0F_0F0F);
+        self.push([alu(AND | X, 2, 4)]);
+        self.load_imm64(4, 0x0101_0101_0101_0101);
+        self.push([alu(MUL | X, 2, 4), alu_imm(RSH | K, 2, 56)]);
+        self.store(dst, 2);
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.load(2, src);
+        // Swap ever larger groups of bits
+        for (shift, mask) in [
+            (1, 0x5555_5555_5555_5555),
+            (2, 0x3333_3333_3333_3333),
+            (4, 0x0F0F_0F0F_0F0F_0F0F),
+            (8, 0x00FF_00FF_00FF_00FF),
+            (16, 0x0000_FFFF_0000_FFFF),
+            (32, 0x0000_0000_FFFF_FFFF),
+        ] {
+            self.load_imm64(4, mask);
+            self.push([
+                alu(MOV | X, 3, 2),
+                alu_imm(RSH | K, 3, shift),
+                alu(AND | X, 3, 4),
+                alu(AND | X, 2, 4),
+                alu_imm(LSH | K, 2, shift),
+                alu(OR | X, 2, 3),
+            ]);
+        }
+        self.store(dst, 2);
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        let jump = match compare_kind {
+            CompareKind::Eq => JEQ,
+            CompareKind::Neq => JNE,
+            CompareKind::Gt => JSGT,
+            CompareKind::Lt => JSLT,
+        };
+        self.load(2, a);
+        self.load(3, b);
+        let target = self.next_instruction() + offset;
+        self.branch(op(JMP | jump | X, 2, 3, 0, 0), target);
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.load(2, src);
+        let target = self.next_instruction() + offset;
+        self.branch(op(JMP | JEQ | K, 2, 0, 0, 0), target);
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.load(2, src);
+        let target = self.next_instruction() + offset;
+        self.branch(op(JMP | JNE | K, 2, 0, 0, 0), target);
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        if bank == MemoryBank::Const {
+            let value = self.constants[usize::try_from(addr).unwrap()];
+            self.load_imm64(2, value as u64);
+            self.store(dst, 2);
+            return;
+        }
+        let offset = self.layout.address(bank, addr) as i16 * 8;
+        self.push([op(LDX_DW, 2, MEMORY, offset, 0)]);
+        self.store(dst, 2);
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let offset = self.layout.address(bank, addr) as i16 * 8;
+            self.load(2, src);
+            self.push([op(STX_DW, MEMORY, 2, offset, 0)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{differential, random_code, random_memory, Backend, Xorshift},
+        Compiler, OutputInit,
+    };
+
+    /// A minimal eBPF virtual machine that supports the instructions the generator emits.
+    fn run(program: &EbpfProgram, memory: &mut [i64]) {
+        let mut scratch = memory.to_vec();
+        scratch.resize(program.memory_len(), 0);
+        let code = program.instructions();
+
+        let mut regs = [0u64; 11];
+        // The memory is addressed from 0
+        regs[1] = 0;
+        let mut calls = vec![];
+        let mut pc = 0;
+        loop {
+            let inst = code[pc];
+            let opcode = inst as u8;
+            let dst = usize::from((inst >> 8) as u8 & 0xF);
+            let src = usize::from((inst >> 12) as u8 & 0xF);
+            let offset = (inst >> 16) as i16;
+            let imm = (inst >> 32) as i32;
+            pc += 1;
+
+            let operand = if opcode & X != 0 {
+                regs[src]
+            } else {
+                imm as i64 as u64
+            };
+            let address = |base: u64| {
+                let address = base.wrapping_add(offset as i64 as u64);
+                assert_eq!(address % 8, 0);
+                usize::try_from(address / 8).unwrap()
+            };
+
+            match opcode & 0x07 {
+                ALU64 => {
+                    let d = regs[dst];
+                    regs[dst] = match opcode & 0xF0 {
+                        ADD => d.wrapping_add(operand),
+                        SUB => d.wrapping_sub(operand),
+                        MUL => d.wrapping_mul(operand),
+                        OR => d | operand,
+                        AND => d & operand,
+                        LSH => d << operand,
+                        RSH => d >> operand,
+                        NEG => d.wrapping_neg(),
+                        XOR => d ^ operand,
+                        MOV => operand,
+                        ARSH => ((d as i64) >> operand) as u64,
+                        _ => panic!("unsupported instruction {inst:#x}"),
+                    };
+                }
+                JMP => {
+                    let (d, s) = (regs[dst], operand);
+                    let taken = match opcode & 0xF0 {
+                        JEQ => d == s,
+                        JNE => d != s,
+                        JSGT => d as i64 > s as i64,
+                        JSGE => d as i64 >= s as i64,
+                        JSLT => (d as i64) < s as i64,
+                        CALL => {
+                            assert_eq!(src, usize::from(PSEUDO_CALL));
+                            calls.push((pc, regs[6..10].to_vec()));
+                            pc = (pc as i64 + i64::from(imm)) as usize;
+                            false
+                        }
+                        EXIT => {
+                            let Some((ret, saved)) = calls.pop() else {
+                                break;
+                            };
+                            pc = ret;
+                            regs[6..10].copy_from_slice(&saved);
+                            false
+                        }
+                        _ => panic!("unsupported instruction {inst:#x}"),
+                    };
+                    if taken {
+                        pc = (pc as i64 + i64::from(offset)) as usize;
+                    }
+                }
+                _ => match opcode {
+                    LDX_DW => regs[dst] = scratch[address(regs[src])] as u64,
+                    STX_DW => scratch[address(regs[dst])] = regs[src] as i64,
+                    ST_DW => scratch[address(regs[dst])] = imm.into(),
+                    LD_DW_IMM => {
+                        let high = (code[pc] >> 32) as u32;
+                        regs[dst] = u64::from(imm as u32) | u64::from(high) << 32;
+                        pc += 1;
+                    }
+                    _ => panic!("unsupported instruction {inst:#x}"),
+                },
+            }
+        }
+
+        memory.copy_from_slice(&scratch[..memory.len()]);
+    }
+
+    #[test]
+    fn matches_spec() {
+        let mut compiler = Compiler::new(Ebpf::new());
+        for vector in crate::spec::test_vectors() {
+            let layout = vector.layout;
+            let program = compiler.compile(
+                &vector.code,
+                vector.lowest_function_level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            );
+
+            let mut memory = vector.memory.clone();
+            run(&program, &mut memory);
+            assert_eq!(memory, vector.expected, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut rng = Xorshift::default();
+
+        let mut ebpf = Compiler::new(Ebpf::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let ebpf = |code: &[u64], level, layout: MemoryLayout, memory: &mut [i64]| {
+            ebpf.set_output_init(layout.output_init);
+            ebpf.set_scratch_size(layout.scratch_size);
+            let program = ebpf.compile(
+                code,
+                level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            );
+            run(&program, memory);
+        };
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::from_fn("ebpf", ebpf),
+        ];
+        for p in 0..100 {
+            let code = random_code(&mut rng, 256);
+            // Every other program has a scratch bank that has to be cleared
+            let scratch_size = p as u32 % 2 * 2;
+            let memory = random_memory(&mut rng, 16 + scratch_size as usize);
+            let output_init = [
+                OutputInit::Clear,
+                OutputInit::Keep,
+                OutputInit::Fill(i64::MIN),
+            ][p % 3];
+            let layout = MemoryLayout::new(8, 4, 4)
+                .with_scratch_size(scratch_size)
+                .with_output_init(output_init);
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+        }
+    }
+}
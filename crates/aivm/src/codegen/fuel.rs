@@ -0,0 +1,184 @@
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+use crate::HostFunctions;
+use crate::{DeadlineExceeded, MemoryBank, MemoryLayout};
+
+use std::time::Instant;
+
+/// The amount of fuel that is consumed between two checks of the clock, when stepping until a
+/// deadline.
+const TIME_CHECK_INTERVAL: u64 = 1 << 16;
+
+/// The fuel of a step in the runners that count instructions themselves.
+pub(crate) struct Fuel {
+    remaining: u64,
+    deadline: Option<Instant>,
+}
+
+impl Fuel {
+    /// Fuel that runs out after `fuel` instructions.
+    pub fn bounded(fuel: u64) -> Self {
+        Self {
+            remaining: fuel,
+            deadline: None,
+        }
+    }
+
+    /// Fuel that runs out once `deadline` has passed.
+    pub fn until(deadline: Instant) -> Self {
+        Self {
+            remaining: TIME_CHECK_INTERVAL,
+            deadline: Some(deadline),
+        }
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Consume one unit of fuel, returning false if it ran out.
+    #[inline(always)]
+    pub fn consume(&mut self) -> bool {
+        match self.remaining.checked_sub(1) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                true
+            }
+            None => self.refuel(),
+        }
+    }
+
+    #[cold]
+    fn refuel(&mut self) -> bool {
+        match self.deadline {
+            Some(deadline) if Instant::now() < deadline => {
+                self.remaining = TIME_CHECK_INTERVAL - 1;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The fuel of a step in generated machine code, which keeps a pointer to it in a register.
+///
+/// Every block subtracts the fuel for all of its instructions when it is entered. When the
+/// remaining fuel wraps around, the code calls `refuel`, which either adds fuel or tells the
+/// code to abort. Aborting returns straight from the entry point, with the stack pointer that
+/// is saved in `entry_stack`.
+///
+/// Host calls also go through the context: the code stores the id of the function in
+/// `host_call_id` and calls `host_call` with the pointer to the memory.
+///
+/// Code compiled with profiling counts the calls and timestamp ticks of every function in the
+/// array that `profile` points to, which has one `[calls, ticks]` pair per function.
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+#[repr(C)]
+pub(crate) struct FuelContext {
+    pub remaining: u64,
+    pub entry_stack: u64,
+    pub refuel: extern "C" fn(&mut FuelContext) -> u64,
+    pub host_call: extern "C" fn(&mut FuelContext, *mut i64),
+    pub host_call_id: u32,
+    pub profile: *mut [u64; 2],
+    memory_size: usize,
+    host: HostFunctions,
+    deadline: Option<Instant>,
+}
+
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+impl FuelContext {
+    /// The offset of [remaining](Self::remaining) in bytes.
+    pub const REMAINING_OFFSET: i32 = 0;
+    /// The offset of [entry_stack](Self::entry_stack) in bytes.
+    pub const ENTRY_STACK_OFFSET: i32 = 8;
+    /// The offset of [refuel](Self::refuel) in bytes.
+    pub const REFUEL_OFFSET: i32 = 16;
+    /// The offset of [host_call](Self::host_call) in bytes.
+    pub const HOST_CALL_OFFSET: i32 = 24;
+    /// The offset of [host_call_id](Self::host_call_id) in bytes.
+    pub const HOST_CALL_ID_OFFSET: i32 = 32;
+    /// The offset of [profile](Self::profile) in bytes.
+    pub const PROFILE_OFFSET: i32 = 40;
+
+    /// Fuel that runs out after `fuel` instructions.
+    pub fn bounded(fuel: u64) -> Self {
+        Self {
+            remaining: fuel,
+            entry_stack: 0,
+            refuel: Self::refuel,
+            host_call: Self::host_call,
+            host_call_id: 0,
+            profile: std::ptr::null_mut(),
+            memory_size: 0,
+            host: HostFunctions::new(),
+            deadline: None,
+        }
+    }
+
+    /// Fuel that runs out once `deadline` has passed.
+    pub fn until(deadline: Instant) -> Self {
+        Self {
+            remaining: TIME_CHECK_INTERVAL,
+            entry_stack: 0,
+            refuel: Self::refuel,
+            host_call: Self::host_call,
+            host_call_id: 0,
+            profile: std::ptr::null_mut(),
+            memory_size: 0,
+            host: HostFunctions::new(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Let the code call the given host functions, with memories of the given layout.
+    pub fn with_host_functions(self, host: &HostFunctions, layout: MemoryLayout) -> Self {
+        Self {
+            memory_size: layout.size() as usize,
+            host: host.clone(),
+            ..self
+        }
+    }
+
+    extern "C" fn host_call(&mut self, memory: *mut i64) {
+        // The code was given a memory of at least the size of the layout
+        let memory = unsafe { std::slice::from_raw_parts_mut(memory, self.memory_size) };
+        self.host.call(self.host_call_id, memory);
+    }
+
+    extern "C" fn refuel(&mut self) -> u64 {
+        match self.deadline {
+            Some(deadline) if Instant::now() < deadline => {
+                // The remaining fuel wrapped around by the amount the block still needs
+                let deficit = self.remaining.wrapping_neg();
+                self.remaining = self
+                    .remaining
+                    .wrapping_add(TIME_CHECK_INTERVAL.max(deficit));
+                1
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Run a step that returns false when it was aborted, and put the memory in a defined state if
+/// it was: the memory and output banks are restored to their values at the start of the step,
+/// and the scratch bank is cleared.
+pub(crate) fn restore_on_abort(
+    layout: MemoryLayout,
+    memory: &mut [i64],
+    step: impl FnOnce(&mut [i64]) -> bool,
+) -> Result<(), DeadlineExceeded> {
+    assert!(layout.size() as usize <= memory.len());
+
+    // The output bank directly follows the memory bank
+    let banks = 0..layout.bank_range(MemoryBank::Output).end;
+    let snapshot = memory[banks.clone()].to_vec();
+    if step(memory) {
+        return Ok(());
+    }
+
+    memory[banks].copy_from_slice(&snapshot);
+    layout.init_step(memory);
+
+    Err(DeadlineExceeded)
+}
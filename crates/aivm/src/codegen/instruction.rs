@@ -0,0 +1,448 @@
+use crate::{codegen::private::Emitter, CompareKind, MemoryBank};
+
+/// A decoded VM instruction, as it is passed to a code generator.
+///
+/// Register operands are indices into the registers of the current function. Branch offsets
+/// are the amount of instructions that are skipped, counted from the instruction after the
+/// branch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    /// Call a function.
+    Call {
+        /// The index of the function.
+        idx: u32,
+    },
+    /// Do nothing.
+    Nop,
+    /// Call a function of the host, see [HostFunctions](crate::HostFunctions).
+    HostCall {
+        /// The id of the host function.
+        id: u32,
+    },
+
+    /// `dst = a + b`, wrapping on overflow.
+    IntAdd {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = a - b`, wrapping on overflow.
+    IntSub {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = a * b`, wrapping on overflow.
+    IntMul {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// The high half of the signed 128 bit product of `a` and `b`.
+    IntMulHigh {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// The high half of the unsigned 128 bit product of `a` and `b`.
+    IntMulHighUnsigned {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = -src`, wrapping on overflow.
+    IntNeg {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+    },
+    /// The absolute value of `src`, wrapping on overflow.
+    IntAbs {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+    },
+    /// `dst = dst + 1`, wrapping on overflow.
+    IntInc {
+        /// The destination register.
+        dst: u8,
+    },
+    /// `dst = dst - 1`, wrapping on overflow.
+    IntDec {
+        /// The destination register.
+        dst: u8,
+    },
+    /// The signed minimum of `a` and `b`.
+    IntMin {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// The signed maximum of `a` and `b`.
+    IntMax {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+
+    /// `dst = a | b`.
+    BitOr {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = a & b`.
+    BitAnd {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = a ^ b`.
+    BitXor {
+        /// The destination register.
+        dst: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// `dst = !src`.
+    BitNot {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+    },
+    /// `dst = src << amount`.
+    BitShiftLeft {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+        /// The amount of bits, smaller than 64.
+        amount: u8,
+    },
+    /// `dst = src >> amount`, shifting in the sign bit.
+    BitShiftRight {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+        /// The amount of bits, smaller than 64.
+        amount: u8,
+    },
+    /// Rotate the bits of `src` to the left.
+    BitRotateLeft {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+        /// The amount of bits, smaller than 64.
+        amount: u8,
+    },
+    /// Rotate the bits of `src` to the right.
+    BitRotateRight {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+        /// The amount of bits, smaller than 64.
+        amount: u8,
+    },
+    /// The bits of `a` where `mask` is set, and the bits of `b` where it is not.
+    BitSelect {
+        /// The destination register.
+        dst: u8,
+        /// The register that selects the bits.
+        mask: u8,
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+    },
+    /// The amount of bits that are set in `src`.
+    BitPopcnt {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+    },
+    /// The bits of `src` in reverse order.
+    BitReverse {
+        /// The destination register.
+        dst: u8,
+        /// The source register.
+        src: u8,
+    },
+
+    /// Skip instructions if the comparison of `a` and `b` is true.
+    BranchCmp {
+        /// The first operand register.
+        a: u8,
+        /// The second operand register.
+        b: u8,
+        /// The comparison of the operands.
+        compare_kind: CompareKind,
+        /// The amount of instructions to skip.
+        offset: u32,
+    },
+    /// Skip instructions if `src` is zero.
+    BranchZero {
+        /// The source register.
+        src: u8,
+        /// The amount of instructions to skip.
+        offset: u32,
+    },
+    /// Skip instructions if `src` is not zero.
+    BranchNonZero {
+        /// The source register.
+        src: u8,
+        /// The amount of instructions to skip.
+        offset: u32,
+    },
+
+    /// Load a value from memory.
+    MemLoad {
+        /// The destination register.
+        dst: u8,
+        /// The memory bank.
+        bank: MemoryBank,
+        /// The address, relative to the start of `bank`.
+        addr: u32,
+    },
+    /// Store a value in memory, which has no effect if the bank is not writable.
+    MemStore {
+        /// The memory bank.
+        bank: MemoryBank,
+        /// The address, relative to the start of `bank`.
+        addr: u32,
+        /// The register with the value to store.
+        src: u8,
+    },
+}
+
+impl Instruction {
+    /// Whether the instruction may skip the instructions after it.
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            Self::BranchCmp { .. } | Self::BranchZero { .. } | Self::BranchNonZero { .. }
+        )
+    }
+
+    /// Call the method of the emitter that emits this instruction.
+    pub(crate) fn emit(self, emitter: &mut impl Emitter) {
+        match self {
+            Self::Call { idx } => emitter.emit_call(idx),
+            Self::Nop => emitter.emit_nop(),
+            Self::HostCall { id } => emitter.emit_host_call(id),
+
+            Self::IntAdd { dst, a, b } => emitter.emit_int_add(dst, a, b),
+            Self::IntSub { dst, a, b } => emitter.emit_int_sub(dst, a, b),
+            Self::IntMul { dst, a, b } => emitter.emit_int_mul(dst, a, b),
+            Self::IntMulHigh { dst, a, b } => emitter.emit_int_mul_high(dst, a, b),
+            Self::IntMulHighUnsigned { dst, a, b } => emitter.emit_int_mul_high_unsigned(dst, a, b),
+            Self::IntNeg { dst, src } => emitter.emit_int_neg(dst, src),
+            Self::IntAbs { dst, src } => emitter.emit_int_abs(dst, src),
+            Self::IntInc { dst } => emitter.emit_int_inc(dst),
+            Self::IntDec { dst } => emitter.emit_int_dec(dst),
+            Self::IntMin { dst, a, b } => emitter.emit_int_min(dst, a, b),
+            Self::IntMax { dst, a, b } => emitter.emit_int_max(dst, a, b),
+
+            Self::BitOr { dst, a, b } => emitter.emit_bit_or(dst, a, b),
+            Self::BitAnd { dst, a, b } => emitter.emit_bit_and(dst, a, b),
+            Self::BitXor { dst, a, b } => emitter.emit_bit_xor(dst, a, b),
+            Self::BitNot { dst, src } => emitter.emit_bit_not(dst, src),
+            Self::BitShiftLeft { dst, src, amount } => {
+                emitter.emit_bit_shift_left(dst, src, amount)
+            }
+            Self::BitShiftRight { dst, src, amount } => {
+                emitter.emit_bit_shift_right(dst, src, amount)
+            }
+            Self::BitRotateLeft { dst, src, amount } => {
+                emitter.emit_bit_rotate_left(dst, src, amount)
+            }
+            Self::BitRotateRight { dst, src, amount } => {
+                emitter.emit_bit_rotate_right(dst, src, amount)
+            }
+            Self::BitSelect { dst, mask, a, b } => emitter.emit_bit_select(dst, mask, a, b),
+            Self::BitPopcnt { dst, src } => emitter.emit_bit_popcnt(dst, src),
+            Self::BitReverse { dst, src } => emitter.emit_bit_reverse(dst, src),
+
+            Self::BranchCmp {
+                a,
+                b,
+                compare_kind,
+                offset,
+            } => emitter.emit_branch_cmp(a, b, compare_kind, offset),
+            Self::BranchZero { src, offset } => emitter.emit_branch_zero(src, offset),
+            Self::BranchNonZero { src, offset } => emitter.emit_branch_non_zero(src, offset),
+
+            Self::MemLoad { dst, bank, addr } => emitter.emit_mem_load(dst, bank, addr),
+            Self::MemStore { bank, addr, src } => emitter.emit_mem_store(bank, addr, src),
+        }
+    }
+}
+
+/// Receives the instructions of a function from a [Decoder].
+pub(crate) trait InstructionSink {
+    fn prepare_emit(&mut self, _code_index: usize) {}
+    fn finalize(self)
+    where
+        Self: Sized,
+    {
+    }
+
+    fn instruction(&mut self, instruction: Instruction);
+}
+
+/// An emitter that turns every emit call into an [Instruction], for code generators that
+/// handle all instructions the same way.
+pub struct Decoder<S>(pub(crate) S);
+
+impl<S: InstructionSink> Emitter for Decoder<S> {
+    fn prepare_emit(&mut self, code_index: usize) {
+        self.0.prepare_emit(code_index);
+    }
+
+    fn finalize(self) {
+        self.0.finalize();
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.0.instruction(Instruction::Call { idx });
+    }
+    fn emit_nop(&mut self) {
+        self.0.instruction(Instruction::Nop);
+    }
+    fn emit_host_call(&mut self, id: u32) {
+        self.0.instruction(Instruction::HostCall { id });
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntAdd { dst, a, b });
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntSub { dst, a, b });
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntMul { dst, a, b });
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntMulHigh { dst, a, b });
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.0
+            .instruction(Instruction::IntMulHighUnsigned { dst, a, b });
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.0.instruction(Instruction::IntNeg { dst, src });
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        self.0.instruction(Instruction::IntAbs { dst, src });
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.0.instruction(Instruction::IntInc { dst });
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.0.instruction(Instruction::IntDec { dst });
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntMin { dst, a, b });
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::IntMax { dst, a, b });
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::BitOr { dst, a, b });
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::BitAnd { dst, a, b });
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.0.instruction(Instruction::BitXor { dst, a, b });
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        self.0.instruction(Instruction::BitNot { dst, src });
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.0
+            .instruction(Instruction::BitShiftLeft { dst, src, amount });
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.0
+            .instruction(Instruction::BitShiftRight { dst, src, amount });
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.0
+            .instruction(Instruction::BitRotateLeft { dst, src, amount });
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.0
+            .instruction(Instruction::BitRotateRight { dst, src, amount });
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.0
+            .instruction(Instruction::BitSelect { dst, mask, a, b });
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.0.instruction(Instruction::BitPopcnt { dst, src });
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.0.instruction(Instruction::BitReverse { dst, src });
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        self.0.instruction(Instruction::BranchCmp {
+            a,
+            b,
+            compare_kind,
+            offset,
+        });
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.0.instruction(Instruction::BranchZero { src, offset });
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.0
+            .instruction(Instruction::BranchNonZero { src, offset });
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        self.0.instruction(Instruction::MemLoad { dst, bank, addr });
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        self.0
+            .instruction(Instruction::MemStore { bank, addr, src });
+    }
+}
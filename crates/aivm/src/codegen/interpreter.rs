@@ -1,42 +1,92 @@
-use crate::{codegen, compile::CompareKind};
+use crate::{
+    codegen::{self, fuel, Coverage, FunctionProfile, Profile},
+    compile::{CompareKind, REGISTER_COUNT},
+    DeadlineExceeded, HostFunctions, MemoryBank, MemoryLayout, MemoryView, StepResult,
+};
 
 use std::{
     convert::TryFrom,
     num::{NonZeroU32, Wrapping},
+    sync::Arc,
+    time::Instant,
 };
 
 /// A code generator for creating a runner that simply interprets VM instructions one by one.
 pub struct Interpreter {
     functions: Vec<Vec<Instruction>>,
+    /// The index in the code of every instruction, for coverage.
+    sources: Vec<Vec<u32>>,
+    layout: MemoryLayout,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+    header: bool,
 }
 
 impl codegen::private::CodeGeneratorImpl for Interpreter {
     type Runner = Runner;
     type Emitter<'a> = Emitter<'a>;
 
-    fn begin(&mut self, function_count: NonZeroU32) {
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.host = host.clone();
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn set_layout_header(&mut self, header: bool) {
+        self.header = header;
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+
         for func in &mut self.functions {
             func.clear();
         }
+        for sources in &mut self.sources {
+            sources.clear();
+        }
 
-        self.functions
-            .resize(usize::try_from(function_count.get()).unwrap(), vec![]);
+        let function_count = usize::try_from(function_count.get()).unwrap();
+        self.functions.resize(function_count, vec![]);
+        self.sources.resize(function_count, vec![]);
     }
 
     fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        let idx = usize::try_from(idx).unwrap();
         Emitter {
-            func: &mut self.functions[usize::try_from(idx).unwrap()],
+            func: &mut self.functions[idx],
+            sources: &mut self.sources[idx],
+            layout: self.layout,
         }
     }
 
-    fn finish(&mut self, memory_size: u32, output_size: u32, input_size: u32) -> Self::Runner {
+    fn finish(&mut self) -> Self::Runner {
         let functions = self.functions.clone();
 
+        // Callees always have a higher index than their caller, so visiting the functions in
+        // reverse order means the depth of every callee is known when it's needed.
+        let mut depths = vec![1; functions.len()];
+        for f in (0..functions.len()).rev() {
+            depths[f] += functions[f]
+                .iter()
+                .filter_map(|inst| match inst {
+                    Instruction::Call { idx } => Some(depths[usize::try_from(*idx).unwrap()]),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+        }
+
         Runner {
-            functions,
-            memory_size,
-            output_size,
-            input_size,
+            functions: functions.into(),
+            sources: self.sources.clone().into(),
+            layout: self.layout,
+            max_call_depth: depths[0],
+            host: self.host.clone(),
+            constants: self.constants.clone(),
+            header: self.header,
         }
     }
 }
@@ -44,7 +94,14 @@ impl codegen::private::CodeGeneratorImpl for Interpreter {
 impl Interpreter {
     /// Create a new generator.
     pub fn new() -> Self {
-        Self { functions: vec![] }
+        Self {
+            functions: vec![],
+            sources: vec![],
+            layout: MemoryLayout::default(),
+            host: HostFunctions::new(),
+            constants: Arc::new([]),
+            header: false,
+        }
     }
 }
 
@@ -54,43 +111,213 @@ impl Default for Interpreter {
     }
 }
 
+/// Runner returned by the [Interpreter] code generator.
+///
+/// Cloning a runner is cheap, the clones share the instructions.
+#[derive(Clone)]
 pub struct Runner {
-    functions: Vec<Vec<Instruction>>,
-    memory_size: u32,
-    output_size: u32,
-    input_size: u32,
+    functions: Arc<[Vec<Instruction>]>,
+    sources: Arc<[Vec<u32>]>,
+    layout: MemoryLayout,
+    max_call_depth: usize,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+    header: bool,
 }
 
 impl crate::Runner for Runner {
     fn step(&self, memory: &mut [i64]) {
-        assert!((self.memory_size + self.output_size + self.input_size) as usize <= memory.len());
+        let (layout, memory) = self.prepare_memory(memory);
+
+        let mut frames = self.alloc_frames();
+        let _ = self.call_function(memory, &layout, &mut frames, 0, &mut Unmetered);
+    }
+
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        let (layout, memory) = self.prepare_memory(memory);
+
+        let mut frames = self.alloc_frames();
+        let mut meter = fuel::Fuel::bounded(fuel);
+        match self.call_function(memory, &layout, &mut frames, 0, &mut meter) {
+            Ok(()) => StepResult::Completed {
+                fuel_used: fuel - meter.remaining(),
+            },
+            Err(OutOfFuel) => StepResult::OutOfFuel,
+        }
+    }
+
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        let (layout, memory) = self.split_header(memory);
+        fuel::restore_on_abort(layout, memory, |memory| {
+            layout.init_step(memory);
+
+            let mut frames = self.alloc_frames();
+            let mut meter = fuel::Fuel::until(deadline);
+            self.call_function(memory, &layout, &mut frames, 0, &mut meter)
+                .is_ok()
+        })
+    }
+
+    fn step_batch(&self, memories: &mut [&mut [i64]]) {
+        // The frames are empty again after every step, so they can be reused
+        let mut frames = self.alloc_frames();
+        for memory in memories {
+            let (layout, memory) = self.prepare_memory(memory);
+            let _ = self.call_function(memory, &layout, &mut frames, 0, &mut Unmetered);
+        }
+    }
 
-        let output_range = memory.len() - self.output_size as usize..;
-        memory[output_range].fill(0);
+    fn step_view(&self, view: &mut MemoryView<'_>) {
+        // A view has no room for a header, the banks have the sizes of its slices instead
+        let layout = if self.header {
+            MemoryLayout {
+                output_init: self.layout.output_init,
+                const_size: self.layout.const_size,
+                ..view.layout()
+            }
+        } else {
+            assert!(
+                view.fits(&self.layout),
+                "memory view does not fit the layout"
+            );
+            self.layout
+        };
+        view.init_step(&layout);
 
-        self.call_function(memory, 0);
+        let mut frames = self.alloc_frames();
+        let _ = self.call_function(view, &layout, &mut frames, 0, &mut Unmetered);
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.functions.len() as u32
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.functions.iter().map(Vec::len).sum()
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
     }
 }
 
 impl Runner {
-    fn call_function(&self, memory: &mut [i64], idx: u32) {
+    /// Like [step](crate::Runner::step), but also count what kind of work was done.
+    pub fn step_with_report(&self, memory: &mut [i64]) -> StepReport {
+        let (layout, memory) = self.prepare_memory(memory);
+
+        let mut frames = self.alloc_frames();
+        let mut report = StepReport::default();
+        let _ = self.call_function(memory, &layout, &mut frames, 0, &mut report);
+
+        report
+    }
+
+    /// Like [step](crate::Runner::step), but also add the calls and time of every function to
+    /// `profile`.
+    pub fn step_with_profile(&self, memory: &mut [i64], profile: &mut Profile) {
+        let (layout, memory) = self.prepare_memory(memory);
+
+        let mut frames = self.alloc_frames();
+        let mut profiler = Profiler {
+            functions: profile.functions_mut(self.functions.len()),
+            starts: Vec::with_capacity(self.max_call_depth),
+        };
+        profiler.enter(0);
+        let _ = self.call_function(memory, &layout, &mut frames, 0, &mut profiler);
+        profiler.leave(0);
+    }
+
+    /// Like [step](crate::Runner::step), but also mark the instructions that were executed in
+    /// `coverage`. Instructions skipped by a branch are not executed, instructions that have no
+    /// effect are.
+    pub fn step_with_coverage(&self, memory: &mut [i64], coverage: &mut Coverage) {
+        let (layout, memory) = self.prepare_memory(memory);
+
+        let mut frames = self.alloc_frames();
+        let mut covered = Covered {
+            sources: &self.sources,
+            coverage,
+        };
+        let _ = self.call_function(memory, &layout, &mut frames, 0, &mut covered);
+    }
+
+    fn prepare_memory<'m>(&self, memory: &'m mut [i64]) -> (MemoryLayout, &'m mut [i64]) {
+        let (layout, memory) = self.split_header(memory);
+        layout.init_step(memory);
+        (layout, memory)
+    }
+
+    /// The layout to run with and the memory slice containing the banks, which follow the
+    /// header if the code was compiled with one.
+    fn split_header<'m>(&self, memory: &'m mut [i64]) -> (MemoryLayout, &'m mut [i64]) {
+        if !self.header {
+            assert!(self.layout.size() as usize <= memory.len());
+            return (self.layout, memory);
+        }
+
+        let layout = MemoryLayout::read_header(memory).expect("invalid layout header");
+        let layout = MemoryLayout {
+            output_init: self.layout.output_init,
+            const_size: self.layout.const_size,
+            ..layout
+        };
+        (layout, &mut memory[MemoryLayout::HEADER_SIZE..])
+    }
+
+    /// Allocate space for the registers of every function that can be active at once.
+    ///
+    /// The registers live on the heap so the amount of them does not affect the size of the
+    /// native stack frames.
+    fn alloc_frames(&self) -> Vec<Wrapping<i64>> {
+        Vec::with_capacity(self.max_call_depth * REGISTER_COUNT)
+    }
+
+    fn call_function<V: Values + ?Sized, M: Meter>(
+        &self,
+        memory: &mut V,
+        layout: &MemoryLayout,
+        frames: &mut Vec<Wrapping<i64>>,
+        idx: u32,
+        meter: &mut M,
+    ) -> Result<(), OutOfFuel> {
         use Instruction::*;
 
-        let mut stack = [Wrapping(0i64); 64];
+        let frame_start = frames.len();
+        frames.resize(frame_start + REGISTER_COUNT, Wrapping(0));
         let mut skip_count = 0;
 
-        for instruction in self.functions[usize::try_from(idx).unwrap()]
+        for (i, instruction) in self.functions[usize::try_from(idx).unwrap()]
             .iter()
             .copied()
+            .enumerate()
         {
             if skip_count > 0 {
                 skip_count -= 1;
                 continue;
             }
 
+            meter.consume(&instruction)?;
+            meter.visit(idx, i);
+
+            if let Call { idx } = instruction {
+                meter.enter(idx);
+                self.call_function(memory, layout, frames, idx, meter)?;
+                meter.leave(idx);
+                continue;
+            }
+
+            let stack = &mut frames[frame_start..];
             match instruction {
-                Call { idx } => self.call_function(memory, idx),
+                Call { .. } => unreachable!(),
                 Nop => (),
+                HostCall { id } => memory.host_call(&self.host, id, layout.size() as usize),
 
                 IntAdd { dst, a, b } => {
                     stack[usize::from(dst)] = stack[usize::from(a)] + stack[usize::from(b)]
@@ -182,31 +409,225 @@ impl Runner {
 
                     if result {
                         skip_count = offset;
+                        meter.branch_taken();
                     }
                 }
                 BranchZero { src, offset } => {
                     if stack[usize::from(src)].0 == 0 {
                         skip_count = offset;
+                        meter.branch_taken();
                     }
                 }
                 BranchNonZero { src, offset } => {
                     if stack[usize::from(src)].0 != 0 {
                         skip_count = offset;
+                        meter.branch_taken();
                     }
                 }
 
                 MemLoad { dst, addr } => {
                     let idx = usize::try_from(addr).unwrap();
-                    stack[usize::from(dst)].0 = memory[idx];
+                    stack[usize::from(dst)].0 = memory.load(idx);
+                }
+                ConstLoad { dst, addr } => {
+                    let idx = usize::try_from(addr).unwrap();
+                    stack[usize::from(dst)].0 = self.constants[idx];
                 }
                 MemStore { addr, src } => {
                     let idx = usize::try_from(addr).unwrap();
-                    memory[idx] = stack[usize::from(src)].0;
+                    memory.store(idx, stack[usize::from(src)].0);
+                }
+                HeaderMemLoad { dst, bank, offset } => {
+                    if let Some(idx) = header_address(layout, bank, offset) {
+                        stack[usize::from(dst)].0 = memory.load(idx);
+                    }
+                }
+                HeaderMemStore { bank, offset, src } => {
+                    if let Some(idx) = header_address(layout, bank, offset) {
+                        memory.store(idx, stack[usize::from(src)].0);
+                    }
                 }
             }
         }
 
         assert_eq!(skip_count, 0);
+        frames.truncate(frame_start);
+
+        Ok(())
+    }
+}
+
+/// The index in the memory slice of `offset` modulo the size of `bank`, or `None` if the bank
+/// is empty.
+#[inline(always)]
+fn header_address(layout: &MemoryLayout, bank: MemoryBank, offset: u32) -> Option<usize> {
+    let size = layout.bank_size(bank);
+    (size != 0).then(|| (layout.bank_start(bank) + offset % size) as usize)
+}
+
+struct OutOfFuel;
+
+/// Execution statistics of a single step, returned by
+/// [step_with_report](Runner::step_with_report).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StepReport {
+    /// The amount of instructions that were executed, including calls.
+    pub instructions: u64,
+    /// The amount of function calls that were made.
+    pub calls: u64,
+    /// The amount of branch instructions whose condition was true.
+    pub branches_taken: u64,
+    /// The amount of values loaded from memory or the constants.
+    pub loads: u64,
+    /// The amount of values stored to memory.
+    pub stores: u64,
+}
+
+impl Meter for StepReport {
+    #[inline(always)]
+    fn consume(&mut self, instruction: &Instruction) -> Result<(), OutOfFuel> {
+        self.instructions += 1;
+        match instruction {
+            Instruction::Call { .. } => self.calls += 1,
+            Instruction::MemLoad { .. }
+            | Instruction::ConstLoad { .. }
+            | Instruction::HeaderMemLoad { .. } => self.loads += 1,
+            Instruction::MemStore { .. } | Instruction::HeaderMemStore { .. } => self.stores += 1,
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn branch_taken(&mut self) {
+        self.branches_taken += 1;
+    }
+}
+
+/// The memory that the interpreter runs on, either one slice or the banks of a [MemoryView].
+trait Values {
+    fn load(&self, address: usize) -> i64;
+    fn store(&mut self, address: usize, value: i64);
+    /// Call a host function with the first `size` values of the memory.
+    fn host_call(&mut self, host: &HostFunctions, id: u32, size: usize);
+}
+
+impl Values for [i64] {
+    #[inline(always)]
+    fn load(&self, address: usize) -> i64 {
+        self[address]
+    }
+
+    #[inline(always)]
+    fn store(&mut self, address: usize, value: i64) {
+        self[address] = value;
+    }
+
+    fn host_call(&mut self, host: &HostFunctions, id: u32, size: usize) {
+        host.call(id, &mut self[..size]);
+    }
+}
+
+impl Values for MemoryView<'_> {
+    #[inline(always)]
+    fn load(&self, address: usize) -> i64 {
+        self.get(address)
+    }
+
+    #[inline(always)]
+    fn store(&mut self, address: usize, value: i64) {
+        self.set(address, value);
+    }
+
+    fn host_call(&mut self, host: &HostFunctions, id: u32, _size: usize) {
+        // Host functions expect one slice, so only they pay for packing the banks
+        let mut memory = self.pack();
+        host.call(id, &mut memory);
+        self.unpack(&memory);
+    }
+}
+
+trait Meter {
+    /// Called before executing an instruction.
+    fn consume(&mut self, instruction: &Instruction) -> Result<(), OutOfFuel>;
+
+    /// Called after the condition of a branch instruction turned out to be true.
+    #[inline(always)]
+    fn branch_taken(&mut self) {}
+
+    /// Called before executing instruction `i` of function `function`.
+    #[inline(always)]
+    fn visit(&mut self, _function: u32, _i: usize) {}
+
+    /// Called before calling the function `idx`.
+    #[inline(always)]
+    fn enter(&mut self, _idx: u32) {}
+
+    /// Called after the function `idx` returned.
+    #[inline(always)]
+    fn leave(&mut self, _idx: u32) {}
+}
+
+struct Unmetered;
+
+struct Covered<'a> {
+    sources: &'a [Vec<u32>],
+    coverage: &'a mut Coverage,
+}
+
+impl Meter for Covered<'_> {
+    #[inline(always)]
+    fn consume(&mut self, _instruction: &Instruction) -> Result<(), OutOfFuel> {
+        Ok(())
+    }
+
+    fn visit(&mut self, function: u32, i: usize) {
+        // Instructions emitted without a source, which only happens in tests, are skipped
+        if let Some(&source) = self.sources[usize::try_from(function).unwrap()].get(i) {
+            self.coverage.mark(usize::try_from(source).unwrap());
+        }
+    }
+}
+
+struct Profiler<'a> {
+    functions: &'a mut [FunctionProfile],
+    /// When each of the active functions was called.
+    starts: Vec<Instant>,
+}
+
+impl Meter for Profiler<'_> {
+    #[inline(always)]
+    fn consume(&mut self, _instruction: &Instruction) -> Result<(), OutOfFuel> {
+        Ok(())
+    }
+
+    fn enter(&mut self, idx: u32) {
+        self.functions[usize::try_from(idx).unwrap()].calls += 1;
+        self.starts.push(Instant::now());
+    }
+
+    fn leave(&mut self, idx: u32) {
+        let start = self.starts.pop().unwrap();
+        self.functions[usize::try_from(idx).unwrap()].time += start.elapsed();
+    }
+}
+
+impl Meter for Unmetered {
+    #[inline(always)]
+    fn consume(&mut self, _instruction: &Instruction) -> Result<(), OutOfFuel> {
+        Ok(())
+    }
+}
+
+impl Meter for fuel::Fuel {
+    #[inline(always)]
+    fn consume(&mut self, _instruction: &Instruction) -> Result<(), OutOfFuel> {
+        if fuel::Fuel::consume(self) {
+            Ok(())
+        } else {
+            Err(OutOfFuel)
+        }
     }
 }
 
@@ -216,6 +637,9 @@ enum Instruction {
         idx: u32,
     },
     Nop,
+    HostCall {
+        id: u32,
+    },
 
     IntAdd {
         dst: u8,
@@ -340,23 +764,47 @@ enum Instruction {
         dst: u8,
         addr: u32,
     },
+    ConstLoad {
+        dst: u8,
+        addr: u32,
+    },
     MemStore {
         addr: u32,
         src: u8,
     },
+    HeaderMemLoad {
+        dst: u8,
+        bank: MemoryBank,
+        offset: u32,
+    },
+    HeaderMemStore {
+        bank: MemoryBank,
+        offset: u32,
+        src: u8,
+    },
 }
 
 pub struct Emitter<'a> {
     func: &'a mut Vec<Instruction>,
+    sources: &'a mut Vec<u32>,
+    layout: MemoryLayout,
 }
 
 impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, code_index: usize) {
+        // Every VM instruction is emitted as exactly one interpreter instruction
+        self.sources.push(u32::try_from(code_index).unwrap());
+    }
+
     fn emit_call(&mut self, idx: u32) {
         self.func.push(Instruction::Call { idx });
     }
     fn emit_nop(&mut self) {
         self.func.push(Instruction::Nop);
     }
+    fn emit_host_call(&mut self, id: u32) {
+        self.func.push(Instruction::HostCall { id });
+    }
 
     fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
         self.func.push(Instruction::IntAdd { dst, a, b });
@@ -446,10 +894,291 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
         self.func.push(Instruction::BranchNonZero { src, offset });
     }
 
-    fn emit_mem_load(&mut self, dst: u8, addr: u32) {
-        self.func.push(Instruction::MemLoad { dst, addr });
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        let addr = self.layout.address(bank, addr);
+        if bank == MemoryBank::Const {
+            self.func.push(Instruction::ConstLoad { dst, addr });
+        } else {
+            self.func.push(Instruction::MemLoad { dst, addr });
+        }
     }
-    fn emit_mem_store(&mut self, addr: u32, src: u8) {
-        self.func.push(Instruction::MemStore { addr, src });
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let addr = self.layout.address(bank, addr);
+            self.func.push(Instruction::MemStore { addr, src });
+        } else {
+            self.func.push(Instruction::Nop);
+        }
+    }
+
+    fn emit_header_mem_load(&mut self, dst: u8, bank: MemoryBank, offset: u32) {
+        self.func
+            .push(Instruction::HeaderMemLoad { dst, bank, offset });
+    }
+    fn emit_header_mem_store(&mut self, bank: MemoryBank, offset: u32, src: u8) {
+        if bank.is_writable() {
+            self.func
+                .push(Instruction::HeaderMemStore { bank, offset, src });
+        } else {
+            self.func.push(Instruction::Nop);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::{
+            private::{CodeGeneratorImpl, Emitter as _},
+            Bytecode,
+        },
+        spec::Op,
+        testing::{random_code, random_memory, Xorshift},
+        Compiler, OutputInit, Runner as _,
+    };
+
+    fn compile_counter(gen: &mut Interpreter) -> Runner {
+        gen.begin(NonZeroU32::new(2).unwrap(), MemoryLayout::new(1, 0, 0));
+        {
+            let mut e = gen.begin_function(0);
+            e.emit_mem_load(0, MemoryBank::Memory, 0);
+            e.emit_int_inc(0);
+            e.emit_mem_store(MemoryBank::Memory, 0, 0);
+            e.emit_call(1);
+        }
+        {
+            let mut e = gen.begin_function(1);
+            e.emit_mem_load(0, MemoryBank::Memory, 0);
+            e.emit_int_inc(0);
+            e.emit_mem_store(MemoryBank::Memory, 0, 0);
+        }
+        gen.finish()
+    }
+
+    #[test]
+    fn fuel_completed() {
+        let runner = compile_counter(&mut Interpreter::new());
+        let mut mem = [0];
+
+        assert_eq!(
+            runner.step_bounded(&mut mem, 100),
+            StepResult::Completed { fuel_used: 7 }
+        );
+        assert_eq!(mem[0], 2);
+        assert_eq!(
+            runner.step_bounded(&mut mem, 7),
+            StepResult::Completed { fuel_used: 7 }
+        );
+        assert_eq!(mem[0], 4);
+    }
+
+    #[test]
+    fn batches() {
+        use crate::BatchRunner as _;
+
+        // Both ways of stepping a batch can be called with both traits in scope
+        let runner = compile_counter(&mut Interpreter::new());
+        let mut memories = [0, 10, 20];
+        runner.step_strided(&mut memories, 1);
+        assert_eq!(memories, [2, 12, 22]);
+
+        let (first, rest) = memories.split_at_mut(1);
+        runner.step_batch(&mut [first, rest]);
+        assert_eq!(memories, [4, 14, 22]);
+    }
+
+    #[test]
+    fn report() {
+        let mut gen = Interpreter::new();
+        gen.begin(NonZeroU32::new(1).unwrap(), MemoryLayout::new(2, 0, 0));
+        {
+            let mut e = gen.begin_function(0);
+            e.emit_mem_load(0, MemoryBank::Memory, 0);
+            e.emit_branch_zero(0, 2);
+            e.emit_mem_store(MemoryBank::Memory, 1, 0);
+            e.emit_nop();
+            e.emit_branch_non_zero(0, 1);
+            e.emit_int_inc(0);
+        }
+        let runner = gen.finish();
+
+        let mut mem = [0, 0];
+        assert_eq!(
+            runner.step_with_report(&mut mem),
+            StepReport {
+                instructions: 4,
+                calls: 0,
+                branches_taken: 1,
+                loads: 1,
+                stores: 0,
+            }
+        );
+
+        let mut mem = [5, 0];
+        assert_eq!(
+            runner.step_with_report(&mut mem),
+            StepReport {
+                instructions: 5,
+                calls: 0,
+                branches_taken: 1,
+                loads: 1,
+                stores: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn profile() {
+        let runner = compile_counter(&mut Interpreter::new());
+        let mut profile = Profile::new();
+        let mut mem = [0];
+        for _ in 0..3 {
+            runner.step_with_profile(&mut mem, &mut profile);
+        }
+
+        assert_eq!(mem[0], 6);
+        let [main, callee] = profile.functions() else {
+            panic!("expected 2 functions");
+        };
+        assert_eq!((main.calls, callee.calls), (3, 3));
+        assert!(main.time >= callee.time);
+    }
+
+    #[test]
+    fn coverage() {
+        // Function 0 skips its second store and calls function 1, function 2 is never called
+        let code = [
+            Op::BranchZero.encode(0, 0, 1),
+            Op::MemStore.encode(0, 0, 0),
+            Op::Call.encode(0, 0, 0),
+            Op::EndFunc.encode(0, 0, 0),
+            Op::IntInc.encode(0, 0, 0),
+            Op::MemStore.encode(0, 0, 0),
+            Op::EndFunc.encode(0, 0, 0),
+            Op::MemStore.encode(0, 0, 0),
+        ];
+        let runner = Compiler::new(Interpreter::new()).compile(&code, 2, 1, 0, 0);
+        let mut coverage = Coverage::new();
+        let mut mem = [5];
+        runner.step_with_coverage(&mut mem, &mut coverage);
+
+        assert_eq!(mem, [1]);
+        assert_eq!(coverage.executed().collect::<Vec<_>>(), [0, 2, 4, 5]);
+        assert_eq!(coverage.fraction(code.len()), 0.5);
+    }
+
+    #[test]
+    fn host_call() {
+        let mut host = HostFunctions::new();
+        host.register(|_| unreachable!());
+        let double = host.register(|memory| {
+            assert_eq!(memory.len(), 3);
+            memory[1] = memory[0] * 2;
+        });
+
+        let mut gen = Interpreter::new();
+        gen.set_host_functions(&host);
+        gen.begin(NonZeroU32::new(1).unwrap(), MemoryLayout::new(1, 1, 1));
+        {
+            let mut e = gen.begin_function(0);
+            e.emit_mem_load(0, MemoryBank::Input, 0);
+            e.emit_mem_store(MemoryBank::Memory, 0, 0);
+            e.emit_host_call(double);
+        }
+        let runner = gen.finish();
+
+        let mut mem = [0, 0, 21, 7];
+        runner.step(&mut mem);
+        assert_eq!(mem, [21, 42, 21, 7]);
+
+        let (mut memory, mut output) = ([0], [0]);
+        runner.step_view(&mut MemoryView::new(&mut memory, &mut output, &[21]));
+        assert_eq!((memory, output), ([21], [42]));
+    }
+
+    #[test]
+    fn step_view() {
+        let mut rng = Xorshift::default();
+
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut bytecode = Compiler::new(Bytecode::new());
+        for output_init in [OutputInit::Clear, OutputInit::Keep] {
+            interpreter.set_output_init(output_init);
+            interpreter.set_scratch_size(2);
+            bytecode.set_output_init(output_init);
+            bytecode.set_scratch_size(2);
+            for _ in 0..50 {
+                let code = random_code(&mut rng, 256);
+                let packed = random_memory(&mut rng, 18);
+                let mut expected = packed.clone();
+                interpreter.compile(&code, 3, 8, 4, 4).step(&mut expected);
+
+                // The interpreter runs on the slices directly, the bytecode packs them
+                let interpreter_runner = interpreter.compile(&code, 3, 8, 4, 4);
+                let bytecode_runner = bytecode.compile(&code, 3, 8, 4, 4);
+                let runners: [&dyn crate::Runner; 2] = [&interpreter_runner, &bytecode_runner];
+                for runner in runners {
+                    let mut memory = packed[..8].to_vec();
+                    let mut output = packed[8..12].to_vec();
+                    let mut scratch = packed[16..].to_vec();
+                    let mut view = MemoryView::new(&mut memory, &mut output, &packed[12..16])
+                        .with_scratch(&mut scratch);
+                    runner.step_view(&mut view);
+                    assert_eq!(view.pack(), expected, "code: {code:x?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn layout_header() {
+        let mut rng = Xorshift::default();
+
+        let mut header_compiler = Compiler::new(Interpreter::new());
+        header_compiler.set_layout_header(true);
+        let mut compiler = Compiler::new(Interpreter::new());
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            // One runner for every layout, compiled with sizes that match none of them
+            let header_runner = header_compiler.compile(&code, 3, 1, 1, 1);
+            for layout in [
+                MemoryLayout::new(8, 4, 4),
+                MemoryLayout::new(3, 0, 9).with_scratch_size(2),
+            ] {
+                compiler.set_scratch_size(layout.scratch_size);
+                let runner = compiler.compile(
+                    &code,
+                    3,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                );
+
+                let mut expected = random_memory(&mut rng, layout.size() as usize);
+                let mut memory = vec![0; MemoryLayout::HEADER_SIZE];
+                layout.write_header(&mut memory);
+                memory.extend_from_slice(&expected);
+
+                runner.step(&mut expected);
+                header_runner.step(&mut memory);
+                assert_eq!(
+                    memory[MemoryLayout::HEADER_SIZE..],
+                    expected,
+                    "code: {code:x?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fuel_exhausted() {
+        let runner = compile_counter(&mut Interpreter::new());
+        let mut mem = [0];
+
+        assert_eq!(runner.step_bounded(&mut mem, 6), StepResult::OutOfFuel);
+        assert_eq!(mem[0], 1);
+        assert_eq!(runner.step_bounded(&mut mem, 0), StepResult::OutOfFuel);
+        assert_eq!(mem[0], 1);
     }
 }
@@ -1,4 +1,7 @@
-use super::{ir::InstructionKind, regalloc::RegAllocInstruction};
+use super::{
+    ir::InstructionKind,
+    regalloc::{RegAllocAction, RegAllocInstruction},
+};
 
 use dynasmrt::{relocations, DynamicLabel, DynasmLabelApi};
 
@@ -8,16 +11,42 @@ mod x86_64;
 pub use x86_64::Target;
 
 #[cfg(not(any(target_arch = "x86_64")))]
-compile_error!("unsupported architecture for light_jit");
+compile_error!("unsupported architecture for the jit backend");
 
 pub trait TargetInterface {
     type Relocation: relocations::Relocation;
 
-    const MAX_INSTRUCTION_REGS: usize;
     const REGISTER_COUNT: usize;
 
     fn supports_mem_operand(kind: InstructionKind) -> bool;
+    /// The registers that are used as scratch space by the given instruction, as a mask of
+    /// register indices. They can not hold variables while the instruction executes.
+    fn clobbered_regs(kind: InstructionKind) -> u64;
+    /// Convert a mask of register indices to a mask of the hardware encodings of the registers.
+    fn hardware_regs(mask: u64) -> u64;
+    /// Describes the instruction set and calling convention the generated code depends on.
+    /// Saved code is only loaded on a host with the same fingerprint.
+    fn isa_fingerprint() -> String;
+    /// Fill unused space in a code buffer with instructions that stop the process.
+    fn fill_trap(code: &mut [u8]);
+    /// Make sure the processor does not execute stale instructions from `code`, after it has
+    /// been written.
+    fn flush_icache(code: &[u8]);
+    /// Read the timestamp counter that the profiling instructions read.
+    fn timestamp() -> u64;
 
+    /// Emit the entry point of the generated code, it adapts the calling convention of the host
+    /// to the one used by the generated functions and then calls `main`.
+    ///
+    /// The entry point takes the start and end of an array of memory pointers, a
+    /// [FuelContext](crate::codegen::fuel::FuelContext) and whether to meter fuel. It calls
+    /// `main`, or `unmetered_main` when not metering, on every memory in turn, and returns
+    /// whether the fuel ran out.
+    fn emit_entry<A: DynasmLabelApi<Relocation = Self::Relocation>>(
+        ops: &mut A,
+        main: DynamicLabel,
+        unmetered_main: DynamicLabel,
+    );
     fn emit_prologue<A: DynasmLabelApi<Relocation = Self::Relocation>>(
         ops: &mut A,
         stack_size: u32,
@@ -29,10 +58,19 @@ pub trait TargetInterface {
         used_regs_mask: u64,
     );
 
+    /// Emit the moves of a taken branch that can not jump to its target block directly,
+    /// followed by a jump to `target`.
+    fn emit_edge<A: DynasmLabelApi<Relocation = Self::Relocation>>(
+        ops: &mut A,
+        moves: &[RegAllocAction],
+        target: DynamicLabel,
+    );
     fn emit_instruction<A: DynasmLabelApi<Relocation = Self::Relocation>>(
         ops: &mut A,
-        inst: RegAllocInstruction,
+        inst: &RegAllocInstruction,
         func_labels: &[DynamicLabel],
         block_labels: &[DynamicLabel],
+        edge_labels: &[DynamicLabel],
+        metered: bool,
     );
 }
@@ -1,8 +1,11 @@
 use crate::{
-    codegen::jit::{
-        arch::TargetInterface,
-        ir::InstructionKind,
-        regalloc::{PhysicalVar, RegAllocAction, RegAllocInstruction},
+    codegen::{
+        fuel::FuelContext,
+        jit::{
+            arch::TargetInterface,
+            ir::InstructionKind,
+            regalloc::{PhysicalVar, RegAllocAction, RegAllocInstruction},
+        },
     },
     compile::CompareKind,
 };
@@ -18,7 +21,6 @@ pub struct Target {}
 impl TargetInterface for Target {
     type Relocation = X64Relocation;
 
-    const MAX_INSTRUCTION_REGS: usize = 4;
     const REGISTER_COUNT: usize = REGISTERS.len();
 
     fn supports_mem_operand(kind: InstructionKind) -> bool {
@@ -43,6 +45,159 @@ impl TargetInterface for Target {
         )
     }
 
+    fn clobbered_regs(kind: InstructionKind) -> u64 {
+        use InstructionKind::*;
+        match kind {
+            IntMul => reg_mask(Rq::RAX),
+            IntMulHigh
+            | IntMulHighUnsigned
+            | BitReverse
+            | ProfileEnter { .. }
+            | ProfileLeave { .. } => reg_mask(Rq::RAX) | reg_mask(Rq::RDX),
+            _ => 0,
+        }
+    }
+
+    fn hardware_regs(mask: u64) -> u64 {
+        REGISTERS
+            .into_iter()
+            .enumerate()
+            .filter(|(r, _)| mask & (1 << r) != 0)
+            .fold(0, |hw_mask, (_, reg)| hw_mask | 1 << reg)
+    }
+
+    fn isa_fingerprint() -> String {
+        let mut fingerprint = String::from(if cfg!(windows) {
+            "x86_64-windows"
+        } else {
+            "x86_64-sysv"
+        });
+        // Used by BitPopcnt
+        if std::arch::is_x86_feature_detected!("popcnt") {
+            fingerprint.push_str("+popcnt");
+        }
+
+        fingerprint
+    }
+
+    fn fill_trap(code: &mut [u8]) {
+        // int3
+        code.fill(0xCC);
+    }
+
+    fn flush_icache(_code: &[u8]) {
+        // The instruction cache is coherent with stores on x86
+    }
+
+    fn timestamp() -> u64 {
+        unsafe { std::arch::x86_64::_rdtsc() }
+    }
+
+    fn emit_entry<A: DynasmLabelApi<Relocation = Self::Relocation>>(
+        ops: &mut A,
+        main: dynasmrt::DynamicLabel,
+        unmetered_main: dynasmrt::DynamicLabel,
+    ) {
+        let abi = HOST_ABI;
+        for &r in abi.preserve {
+            dynasm!(ops; push Rq(r));
+        }
+        // Remember the stack pointer, so running out of fuel can return from here right away.
+        // The generated code only calls into the host through the refuel and host call stubs,
+        // which take care of shadow space and stack alignment themselves.
+        //
+        // The functions save every register they use, so the cursor and end of the batch can
+        // stay in registers that the entry point preserved for the host.
+        dynasm!(ops
+            ; mov Rq(FUEL_REG), Rq(abi.args[2])
+            ; mov Rq(BATCH_CURSOR_REG), Rq(abi.args[0])
+            ; mov Rq(BATCH_END_REG), Rq(abi.args[1])
+            ; mov [Rq(FUEL_REG) + FuelContext::ENTRY_STACK_OFFSET], rsp
+            ; test Rq(abi.args[3]), Rq(abi.args[3])
+            ; jz >check_unmetered
+            ; jmp >check
+            ; next:
+            ; mov Rq(MEM_REG), [Rq(BATCH_CURSOR_REG)]
+            ; call =>main
+            ; add Rq(BATCH_CURSOR_REG), 8
+            ; check:
+            ; cmp Rq(BATCH_CURSOR_REG), Rq(BATCH_END_REG)
+            ; jb <next
+            ; jmp >done
+            ; next_unmetered:
+            ; mov Rq(MEM_REG), [Rq(BATCH_CURSOR_REG)]
+            ; call =>unmetered_main
+            ; add Rq(BATCH_CURSOR_REG), 8
+            ; check_unmetered:
+            ; cmp Rq(BATCH_CURSOR_REG), Rq(BATCH_END_REG)
+            ; jb <next_unmetered
+            ; done:
+            ; xor eax, eax
+            ; ->exit:
+        );
+        for &r in abi.preserve.iter().rev() {
+            dynasm!(ops; pop Rq(r));
+        }
+        dynasm!(ops
+            ; ret
+            ; ->out_of_fuel:
+            ; mov rsp, [Rq(FUEL_REG) + FuelContext::ENTRY_STACK_OFFSET]
+            ; mov eax, 1
+            ; jmp ->exit
+        );
+
+        // Called by a block whose fuel wrapped around. Every register of the generated code
+        // can be live here, so save the ones the host can clobber.
+        dynasm!(ops; ->refuel:);
+        for &r in abi.clobber {
+            dynasm!(ops; push Rq(r));
+        }
+        dynasm!(ops
+            ; mov rax, rsp
+            ; and rsp, -16
+            ; sub rsp, 8
+            ; push rax
+            ; sub rsp, abi.shadow_space
+            ; mov Rq(abi.args[0]), Rq(FUEL_REG)
+            ; call QWORD [Rq(FUEL_REG) + FuelContext::REFUEL_OFFSET]
+            ; add rsp, abi.shadow_space
+            ; pop rsp
+            ; test rax, rax
+        );
+        // Popping does not change the flags
+        for &r in abi.clobber.iter().rev() {
+            dynasm!(ops; pop Rq(r));
+        }
+        dynasm!(ops
+            ; jz ->out_of_fuel
+            ; ret
+        );
+
+        // Called by a host call instruction, after it stored the id of the function in the
+        // context. Like refuel, but the host also gets the memory. The memory register is
+        // the first argument register on some hosts, so it is moved before the context.
+        dynasm!(ops; ->host_call:);
+        for &r in abi.clobber {
+            dynasm!(ops; push Rq(r));
+        }
+        dynasm!(ops
+            ; mov rax, rsp
+            ; and rsp, -16
+            ; sub rsp, 8
+            ; push rax
+            ; sub rsp, abi.shadow_space
+            ; mov Rq(abi.args[1]), Rq(MEM_REG)
+            ; mov Rq(abi.args[0]), Rq(FUEL_REG)
+            ; call QWORD [Rq(FUEL_REG) + FuelContext::HOST_CALL_OFFSET]
+            ; add rsp, abi.shadow_space
+            ; pop rsp
+        );
+        for &r in abi.clobber.iter().rev() {
+            dynasm!(ops; pop Rq(r));
+        }
+        dynasm!(ops; ret);
+    }
+
     fn emit_prologue<A: DynasmApi>(ops: &mut A, stack_size: u32, used_regs_mask: u64) {
         for reg in REGISTERS
             .into_iter()
@@ -76,60 +231,60 @@ impl TargetInterface for Target {
         dynasm!(ops; ret);
     }
 
+    fn emit_edge<A: DynasmLabelApi<Relocation = Self::Relocation>>(
+        ops: &mut A,
+        moves: &[RegAllocAction],
+        target: dynasmrt::DynamicLabel,
+    ) {
+        emit_actions(ops, moves, &[]);
+        dynasm!(ops; jmp =>target);
+    }
+
     fn emit_instruction<A: DynasmLabelApi<Relocation = Self::Relocation>>(
         ops: &mut A,
-        inst: RegAllocInstruction,
+        inst: &RegAllocInstruction,
         func_labels: &[dynasmrt::DynamicLabel],
         block_labels: &[dynasmrt::DynamicLabel],
+        edge_labels: &[dynasmrt::DynamicLabel],
+        metered: bool,
     ) {
         use InstructionKind::*;
 
-        let mut branch_exit = None;
-        for action in inst.actions {
-            match action {
-                RegAllocAction::RegToStack(s, r) => {
-                    dynasm!(ops; mov [rsp + (s * 8) as i32], Rq(REGISTERS[r as usize]))
-                }
-                RegAllocAction::StackToReg(r, s) => {
-                    dynasm!(ops; mov Rq(REGISTERS[r as usize]), [rsp + (s * 8) as i32])
-                }
-                RegAllocAction::BlockStart(b) => dynasm!(ops; =>block_labels[b.0 as usize]),
-                RegAllocAction::BranchExit(b) => branch_exit = Some(b.0 as usize),
-            }
-        }
+        let branch_exit = emit_actions(ops, &inst.actions, block_labels);
+        let branch_label = || edge_labels[branch_exit.unwrap()];
 
-        let d = inst.defs;
-        let u = inst.uses;
+        let d = &inst.defs;
+        let u = &inst.uses;
 
         macro_rules! dyn_op {
             ($inst:ident $a:ident, $b:expr) => {
-                if !$b.is_stack() {
+                if $b.is_register() {
                     dynasm!(ops; $inst $a, Rq(reg($b)));
                 } else {
-                    dynasm!(ops; $inst $a, [rsp + $b.offset()]);
+                    dynasm!(ops; $inst $a, [Rq(base($b)) + $b.offset()]);
                 }
             };
             ($inst:ident $a:expr, $b:ident) => {
-                if !$a.is_stack() {
+                if $a.is_register() {
                     dynasm!(ops; $inst Rq(reg($a)), $b);
                 } else {
-                    dynasm!(ops; $inst [rsp + $a.offset()], $b);
+                    dynasm!(ops; $inst [Rq(base($a)) + $a.offset()], $b);
                 }
             };
             ($inst:ident $a:expr) => {
-                if !$a.is_stack() {
+                if $a.is_register() {
                     dynasm!(ops; $inst Rq(reg($a)));
                 } else {
-                    dynasm!(ops; $inst QWORD [rsp + $a.offset()]);
+                    dynasm!(ops; $inst QWORD [Rq(base($a)) + $a.offset()]);
                 }
             };
             ($inst:ident $a:expr, $b:expr) => {
-                if !$a.is_stack() && !$b.is_stack() {
+                if $a.is_register() && $b.is_register() {
                     dynasm!(ops; $inst Rq(reg($a)), Rq(reg($b)));
-                } else if !$a.is_stack() && $b.is_stack() {
-                    dynasm!(ops; $inst Rq(reg($a)), [rsp + $b.offset()]);
-                } else if $a.is_stack() && !$b.is_stack() {
-                    dynasm!(ops; $inst [rsp + $a.offset()], Rq(reg($b)));
+                } else if $a.is_register() && !$b.is_register() {
+                    dynasm!(ops; $inst Rq(reg($a)), [Rq(base($b)) + $b.offset()]);
+                } else if !$a.is_register() && $b.is_register() {
+                    dynasm!(ops; $inst [Rq(base($a)) + $a.offset()], Rq(reg($b)));
                 } else {
                     unreachable!();
                 }
@@ -139,26 +294,55 @@ impl TargetInterface for Target {
         match inst.kind {
             Jump => unreachable!(),
             Return => (),
+            Fuel { cost } if metered => dynasm!(ops
+                ; sub QWORD [Rq(FUEL_REG) + FuelContext::REMAINING_OFFSET], i32::try_from(cost).unwrap()
+                ; jae >enough
+                ; call ->refuel
+                ; enough:
+            ),
+            Fuel { .. } => (),
             InitVar => {
                 dynasm!(ops; xor Rq(reg(d[0])), Rq(reg(d[0])));
             }
             Call { idx } => dynasm!(ops; call =>func_labels[idx as usize]),
+            HostCall { id } => dynasm!(ops
+                ; mov DWORD [Rq(FUEL_REG) + FuelContext::HOST_CALL_ID_OFFSET], id as i32
+                ; call ->host_call
+            ),
+            ProfileEnter { idx } | ProfileLeave { idx } => {
+                let calls = i32::try_from(u64::from(idx) * 16).unwrap();
+                let ticks = calls.checked_add(8).unwrap();
+                dynasm!(ops
+                    ; rdtsc
+                    ; shl rdx, 32
+                    ; or rdx, rax
+                    ; mov rax, [Rq(FUEL_REG) + FuelContext::PROFILE_OFFSET]
+                );
+                if let ProfileEnter { .. } = inst.kind {
+                    dynasm!(ops
+                        ; add QWORD [rax + calls], 1
+                        ; sub [rax + ticks], rdx
+                    );
+                } else {
+                    dynasm!(ops; add [rax + ticks], rdx);
+                }
+            }
             BranchCmp { compare_kind } => {
                 dyn_op!(cmp u[0], u[1]);
                 match compare_kind {
-                    CompareKind::Eq => dynasm!(ops; je =>block_labels[branch_exit.unwrap()]),
-                    CompareKind::Neq => dynasm!(ops; jne =>block_labels[branch_exit.unwrap()]),
-                    CompareKind::Gt => dynasm!(ops; jg =>block_labels[branch_exit.unwrap()]),
-                    CompareKind::Lt => dynasm!(ops; jl =>block_labels[branch_exit.unwrap()]),
+                    CompareKind::Eq => dynasm!(ops; je =>branch_label()),
+                    CompareKind::Neq => dynasm!(ops; jne =>branch_label()),
+                    CompareKind::Gt => dynasm!(ops; jg =>branch_label()),
+                    CompareKind::Lt => dynasm!(ops; jl =>branch_label()),
                 }
             }
             BranchZero => dynasm!(ops;
                 test Rq(reg(u[0])), Rq(reg(u[0]));
-                je =>block_labels[branch_exit.unwrap()]
+                je =>branch_label()
             ),
             BranchNonZero => dynasm!(ops;
                 test Rq(reg(u[0])), Rq(reg(u[0]));
-                jne =>block_labels[branch_exit.unwrap()]
+                jne =>branch_label()
             ),
             IntAdd => dynasm!(ops; lea Rq(reg(d[0])), [Rq(reg(u[0])) + Rq(reg(u[1]))]),
             IntSub => {
@@ -168,14 +352,14 @@ impl TargetInterface for Target {
                 dyn_op!(sub d[0], u[1]);
             }
             IntMul => {
-                if d[0].is_stack() {
+                if !d[0].is_register() {
                     dyn_op!(mov rax, u[0]);
-                    dyn_op!(imul u[1]);
+                    dyn_op!(imul rax, u[1]);
                     dyn_op!(mov d[0], rax);
                 } else {
                     dyn_op!(mov d[0], u[0]);
-                    if u[1].is_stack() {
-                        dynasm!(ops; imul Rq(reg(d[0])), [rsp + u[1].offset()])
+                    if !u[1].is_register() {
+                        dynasm!(ops; imul Rq(reg(d[0])), [Rq(base(u[1])) + u[1].offset()])
                     } else {
                         dynasm!(ops; imul Rq(reg(d[0])), Rq(reg(u[1])))
                     }
@@ -202,8 +386,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0]);
                 }
                 dyn_op!(neg d[0]);
-                if u[0].is_stack() {
-                    dynasm!(ops; cmovs Rq(reg(d[0])), [rsp + u[0].offset()]);
+                if !u[0].is_register() {
+                    dynasm!(ops; cmovs Rq(reg(d[0])), [Rq(base(u[0])) + u[0].offset()]);
                 } else {
                     dynasm!(ops; cmovs Rq(reg(d[0])), Rq(reg(u[0])));
                 }
@@ -219,8 +403,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0]);
                 }
                 dyn_op!(cmp u[0], u[1]);
-                if u[1].is_stack() {
-                    dynasm!(ops; cmovg Rq(reg(d[0])), [rsp + u[1].offset()]);
+                if !u[1].is_register() {
+                    dynasm!(ops; cmovg Rq(reg(d[0])), [Rq(base(u[1])) + u[1].offset()]);
                 } else {
                     dynasm!(ops; cmovg Rq(reg(d[0])), Rq(reg(u[1])));
                 }
@@ -230,8 +414,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[1]);
                 }
                 dyn_op!(cmp u[0], u[1]);
-                if u[0].is_stack() {
-                    dynasm!(ops; cmovg Rq(reg(d[0])), [rsp + u[0].offset()]);
+                if !u[0].is_register() {
+                    dynasm!(ops; cmovg Rq(reg(d[0])), [Rq(base(u[0])) + u[0].offset()]);
                 } else {
                     dynasm!(ops; cmovg Rq(reg(d[0])), Rq(reg(u[0])));
                 }
@@ -265,8 +449,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0])
                 }
                 if amount != 0 {
-                    if d[0].is_stack() {
-                        dynasm!(ops; shl [rsp + d[0].offset()], amount as i8);
+                    if !d[0].is_register() {
+                        dynasm!(ops; shl QWORD [Rq(base(d[0])) + d[0].offset()], amount as i8);
                     } else {
                         dynasm!(ops; shl Rq(reg(d[0])), amount as i8);
                     }
@@ -277,8 +461,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0])
                 }
                 if amount != 0 {
-                    if d[0].is_stack() {
-                        dynasm!(ops; sar [rsp + d[0].offset()], amount as i8);
+                    if !d[0].is_register() {
+                        dynasm!(ops; sar QWORD [Rq(base(d[0])) + d[0].offset()], amount as i8);
                     } else {
                         dynasm!(ops; sar Rq(reg(d[0])), amount as i8);
                     }
@@ -289,8 +473,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0])
                 }
                 if amount != 0 {
-                    if d[0].is_stack() {
-                        dynasm!(ops; rol [rsp + d[0].offset()], amount as i8);
+                    if !d[0].is_register() {
+                        dynasm!(ops; rol QWORD [Rq(base(d[0])) + d[0].offset()], amount as i8);
                     } else {
                         dynasm!(ops; rol Rq(reg(d[0])), amount as i8);
                     }
@@ -301,8 +485,8 @@ impl TargetInterface for Target {
                     dyn_op!(mov d[0], u[0])
                 }
                 if amount != 0 {
-                    if d[0].is_stack() {
-                        dynasm!(ops; ror [rsp + d[0].offset()], amount as i8);
+                    if !d[0].is_register() {
+                        dynasm!(ops; ror QWORD [Rq(base(d[0])) + d[0].offset()], amount as i8);
                     } else {
                         dynasm!(ops; ror Rq(reg(d[0])), amount as i8);
                     }
@@ -318,15 +502,15 @@ impl TargetInterface for Target {
                 dyn_op!(xor d[0], u[2]);
             }
             BitPopcnt => {
-                debug_assert!(!d[0].is_stack());
-                if u[0].is_stack() {
-                    dynasm!(ops; popcnt Rq(reg(d[0])), [rsp + u[0].offset()]);
+                debug_assert!(d[0].is_register());
+                if !u[0].is_register() {
+                    dynasm!(ops; popcnt Rq(reg(d[0])), [Rq(base(u[0])) + u[0].offset()]);
                 } else {
                     dynasm!(ops; popcnt Rq(reg(d[0])), Rq(reg(u[0])));
                 }
             }
             BitReverse => {
-                debug_assert!(!d[0].is_stack());
+                debug_assert!(d[0].is_register());
                 let dst = reg(d[0]);
                 dyn_op!(mov rax, u[0]);
                 dynasm!(ops
@@ -353,26 +537,100 @@ impl TargetInterface for Target {
                 )
             }
             MemLoad { addr } => {
-                debug_assert!(!d[0].is_stack());
-                let dst = reg(d[0]);
-                dynasm!(ops
-                    ; mov Rq(dst), addr as i32 * 8
-                    ; mov Rq(dst), [rdi + Rq(dst)]
-                );
+                debug_assert!(d[0].is_register());
+                dynasm!(ops; mov Rq(reg(d[0])), [Rq(MEM_REG) + addr as i32 * 8]);
+            }
+            Const { value } => {
+                debug_assert!(d[0].is_register());
+                dynasm!(ops; mov Rq(reg(d[0])), QWORD value);
             }
             MemStore { addr } => {
-                debug_assert!(!u[0].is_stack());
-                dynasm!(ops
-                    ; mov rax, addr as i32 * 8
-                    ; mov [rdi + rax], Rq(reg(u[0]))
-                );
+                debug_assert!(u[0].is_register());
+                dynasm!(ops; mov [Rq(MEM_REG) + addr as i32 * 8], Rq(reg(u[0])));
             }
         }
     }
 }
 
-// TODO: use rax and rdx, they need special handling because of the MulHigh instructions
-const REGISTERS: [u8; 12] = [
+/// The register that holds the pointer to the VM memory in the generated code.
+const MEM_REG: u8 = Rq::RDI as u8;
+/// The register that holds the pointer to the [FuelContext] in the generated code.
+const FUEL_REG: u8 = Rq::RBX as u8;
+
+/// The register that points to the next memory pointer of the batch in the entry point.
+const BATCH_CURSOR_REG: u8 = Rq::R12 as u8;
+/// The register that points past the last memory pointer of the batch in the entry point.
+const BATCH_END_REG: u8 = Rq::R13 as u8;
+
+/// The parts of the host calling convention that matter for calling the generated code.
+struct Abi {
+    /// The registers that contain the first four arguments.
+    args: [u8; 4],
+    /// Callee saved registers, which the entry point saves itself.
+    ///
+    /// The functions save every register they allocate, but running out of fuel returns
+    /// straight from the entry point without running their epilogues.
+    preserve: &'static [u8],
+    /// Caller saved registers, which the refuel and host call stubs save around the call into
+    /// the host.
+    clobber: &'static [u8],
+    /// The amount of bytes a caller reserves on the stack for the callee.
+    shadow_space: i32,
+}
+
+#[cfg(not(windows))]
+const HOST_ABI: Abi = Abi {
+    args: [Rq::RDI as u8, Rq::RSI as u8, Rq::RDX as u8, Rq::RCX as u8],
+    preserve: &[
+        Rq::RBX as u8,
+        Rq::RBP as u8,
+        Rq::R12 as u8,
+        Rq::R13 as u8,
+        Rq::R14 as u8,
+        Rq::R15 as u8,
+    ],
+    clobber: &[
+        Rq::RAX as u8,
+        Rq::RCX as u8,
+        Rq::RDX as u8,
+        Rq::RSI as u8,
+        Rq::RDI as u8,
+        Rq::R8 as u8,
+        Rq::R9 as u8,
+        Rq::R10 as u8,
+        Rq::R11 as u8,
+    ],
+    shadow_space: 0,
+};
+
+#[cfg(windows)]
+const HOST_ABI: Abi = Abi {
+    args: [Rq::RCX as u8, Rq::RDX as u8, Rq::R8 as u8, Rq::R9 as u8],
+    preserve: &[
+        Rq::RBX as u8,
+        Rq::RBP as u8,
+        Rq::RDI as u8,
+        Rq::RSI as u8,
+        Rq::R12 as u8,
+        Rq::R13 as u8,
+        Rq::R14 as u8,
+        Rq::R15 as u8,
+    ],
+    clobber: &[
+        Rq::RAX as u8,
+        Rq::RCX as u8,
+        Rq::RDX as u8,
+        Rq::R8 as u8,
+        Rq::R9 as u8,
+        Rq::R10 as u8,
+        Rq::R11 as u8,
+    ],
+    shadow_space: 32,
+};
+
+/// Allocatable registers, in order of preference. `rax` and `rdx` come last since some
+/// instructions need them as scratch registers, see [clobbered_regs](Target::clobbered_regs).
+const REGISTERS: [u8; 13] = [
     Rq::R15 as u8,
     Rq::R14 as u8,
     Rq::R13 as u8,
@@ -384,10 +642,250 @@ const REGISTERS: [u8; 12] = [
     Rq::RBP as u8,
     Rq::RSI as u8,
     Rq::RCX as u8,
-    Rq::RBX as u8,
+    Rq::RAX as u8,
+    Rq::RDX as u8,
 ];
 
+/// The mask of the register index of `r`.
+const fn reg_mask(r: Rq) -> u64 {
+    let mut i = 0;
+    while i < REGISTERS.len() {
+        if REGISTERS[i] == r as u8 {
+            return 1 << i;
+        }
+        i += 1;
+    }
+    panic!("not an allocatable register");
+}
+
 #[inline]
 fn reg(v: PhysicalVar) -> u8 {
     REGISTERS[v.idx() as usize]
 }
+
+/// The base register of the address of a variable that is not in a register.
+#[inline]
+fn base(v: PhysicalVar) -> u8 {
+    if v.is_memory() {
+        MEM_REG
+    } else {
+        Rq::RSP as u8
+    }
+}
+
+/// Emit the actions of the register allocator, returning the edge of a branch exit if there is
+/// one.
+fn emit_actions<A: DynasmLabelApi<Relocation = X64Relocation>>(
+    ops: &mut A,
+    actions: &[RegAllocAction],
+    block_labels: &[dynasmrt::DynamicLabel],
+) -> Option<usize> {
+    // Values pushed by the actions move the stack slots further away from rsp
+    let mut depth = 0;
+    let slot = |v: PhysicalVar, depth: i32| v.offset() + depth * 8;
+
+    let mut branch_exit = None;
+    for &action in actions {
+        match action {
+            RegAllocAction::RegToStack(s, r) => {
+                dynasm!(ops; mov [rsp + (s * 8) as i32], Rq(REGISTERS[r as usize]))
+            }
+            RegAllocAction::StackToReg(r, s) => {
+                dynasm!(ops; mov Rq(REGISTERS[r as usize]), [rsp + (s * 8) as i32])
+            }
+            RegAllocAction::MemoryToReg(r, addr) => {
+                dynasm!(ops; mov Rq(REGISTERS[r as usize]), [Rq(MEM_REG) + (addr * 8) as i32])
+            }
+            RegAllocAction::BlockStart(b) => dynasm!(ops; =>block_labels[b.0 as usize]),
+            RegAllocAction::BranchExit(e) => branch_exit = Some(e),
+            RegAllocAction::Move(from, to) => match (from.is_stack(), to.is_stack()) {
+                (false, false) => dynasm!(ops; mov Rq(reg(to)), Rq(reg(from))),
+                (true, false) => dynasm!(ops; mov Rq(reg(to)), [rsp + slot(from, depth)]),
+                (false, true) => dynasm!(ops; mov [rsp + slot(to, depth)], Rq(reg(from))),
+                (true, true) => dynasm!(ops
+                    ; push QWORD [rsp + slot(from, depth)]
+                    ; pop QWORD [rsp + slot(to, depth)]
+                ),
+            },
+            RegAllocAction::Push(from) => {
+                if from.is_stack() {
+                    dynasm!(ops; push QWORD [rsp + slot(from, depth)]);
+                } else {
+                    dynasm!(ops; push Rq(reg(from)));
+                }
+                depth += 1;
+            }
+            RegAllocAction::Pop(to) => {
+                depth -= 1;
+                if to.is_stack() {
+                    dynasm!(ops; pop QWORD [rsp + slot(to, depth)]);
+                } else {
+                    dynasm!(ops; pop Rq(reg(to)));
+                }
+            }
+        }
+    }
+    debug_assert_eq!(depth, 0);
+
+    branch_exit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::jit::code::ExecutableCode, HostFunctions, MemoryLayout};
+
+    use dynasmrt::VecAssembler;
+
+    use std::{
+        mem::transmute,
+        time::{Duration, Instant},
+    };
+
+    /// The value `main` puts in the allocatable register `i`.
+    fn register_value(i: usize) -> i64 {
+        0x0101_0101_0101_0101 * (i as i64 + 1)
+    }
+
+    /// The value the thunk puts in the callee saved register `i` of the host.
+    fn preserved_value(i: usize) -> i64 {
+        -0x1234_5678 * (i as i64 + 1)
+    }
+
+    /// Runs the entry point of the generated code on one memory, with every callee saved
+    /// register of the host holding a known value.
+    type Thunk =
+        extern "sysv64" fn(*const *mut i64, *const *mut i64, *mut FuelContext, *mut i64, u64);
+
+    /// Assemble the entry point, a main function that fills every allocatable register, calls
+    /// the host call and refuel stubs and stores the registers in the memory, and a thunk that
+    /// calls the entry point and stores the callee saved registers of the host and the return
+    /// value in its fourth argument. The last argument is passed on as whether to meter fuel.
+    fn assemble() -> (ExecutableCode, usize) {
+        let mut ops = VecAssembler::<X64Relocation>::new(0);
+        let (entry, main) = (ops.new_dynamic_label(), ops.new_dynamic_label());
+        dynasm!(ops; =>entry);
+        Target::emit_entry(&mut ops, main, main);
+
+        let all_regs = (1 << REGISTERS.len()) - 1;
+        dynasm!(ops; =>main);
+        Target::emit_prologue(&mut ops, 0, all_regs);
+        for (i, &r) in REGISTERS.iter().enumerate() {
+            dynasm!(ops; mov Rq(r), QWORD register_value(i));
+        }
+        dynasm!(ops
+            ; mov DWORD [Rq(FUEL_REG) + FuelContext::HOST_CALL_ID_OFFSET], 0
+            ; call ->host_call
+            ; sub QWORD [Rq(FUEL_REG) + FuelContext::REMAINING_OFFSET], 1
+            ; jae >enough
+            ; call ->refuel
+            ; enough:
+        );
+        for (i, &r) in REGISTERS.iter().enumerate() {
+            dynasm!(ops; mov [Rq(MEM_REG) + i as i32 * 8], Rq(r));
+        }
+        Target::emit_epilogue(&mut ops, 0, all_regs);
+
+        // An even amount of callee saved registers keeps the stack aligned for the call
+        let abi = HOST_ABI;
+        assert_eq!(abi.preserve.len() % 2, 0);
+        let thunk = ops.offset().0;
+        for &r in abi.preserve {
+            dynasm!(ops; push Rq(r));
+        }
+        dynasm!(ops
+            ; push rcx
+            ; mov Rq(abi.args[3]), r8
+            ; mov Rq(abi.args[2]), rdx
+            ; mov Rq(abi.args[1]), rsi
+            ; mov Rq(abi.args[0]), rdi
+        );
+        for (i, &r) in abi.preserve.iter().enumerate() {
+            dynasm!(ops; mov Rq(r), QWORD preserved_value(i));
+        }
+        dynasm!(ops
+            ; sub rsp, abi.shadow_space
+            ; call =>entry
+            ; add rsp, abi.shadow_space
+            ; pop rcx
+        );
+        for (i, &r) in abi.preserve.iter().enumerate() {
+            dynasm!(ops; mov [rcx + i as i32 * 8], Rq(r));
+        }
+        dynasm!(ops; mov [rcx + abi.preserve.len() as i32 * 8], rax);
+        for &r in abi.preserve.iter().rev() {
+            dynasm!(ops; pop Rq(r));
+        }
+        dynasm!(ops; ret);
+
+        (
+            ExecutableCode::new(&ops.finalize().unwrap()).unwrap(),
+            thunk,
+        )
+    }
+
+    /// Run the code on a memory of 16 values with `context`, returning the memory, the callee
+    /// saved registers of the host after the call and whether the fuel ran out.
+    fn run(mut context: FuelContext) -> (Vec<i64>, Vec<i64>, bool) {
+        let (code, thunk) = assemble();
+        let thunk: Thunk = unsafe { transmute(code.ptr(thunk)) };
+
+        let mut memory = vec![0; 16];
+        let mut results = vec![0; HOST_ABI.preserve.len() + 1];
+        let pointers = [memory.as_mut_ptr()];
+        let range = pointers.as_ptr_range();
+        thunk(
+            range.start,
+            range.end,
+            &mut context,
+            results.as_mut_ptr(),
+            1,
+        );
+
+        let out_of_fuel = results.pop().unwrap() != 0;
+        (memory, results, out_of_fuel)
+    }
+
+    fn host_functions() -> HostFunctions {
+        let mut host = HostFunctions::new();
+        host.register(|memory| {
+            // Use plenty of registers, any caller saved register may be clobbered
+            let sum: i64 = memory.iter().map(|v| std::hint::black_box(v * 3)).sum();
+            memory[15] = sum + 42;
+        });
+        host
+    }
+
+    #[test]
+    fn stubs_preserve_registers() {
+        let mut context = FuelContext::until(Instant::now() + Duration::from_secs(3600))
+            .with_host_functions(&host_functions(), MemoryLayout::new(16, 0, 0));
+        // The first block runs out of fuel, but the deadline is far away
+        context.remaining = 0;
+        let (memory, preserved, out_of_fuel) = run(context);
+
+        assert!(!out_of_fuel);
+        // The host call ran before the registers were stored, and none of them were clobbered
+        // by the calls into the host
+        let expected: Vec<_> = (0..REGISTERS.len()).map(register_value).collect();
+        assert_eq!(memory[..REGISTERS.len()], expected);
+        assert_eq!(memory[15], 42);
+        let expected: Vec<_> = (0..HOST_ABI.preserve.len()).map(preserved_value).collect();
+        assert_eq!(preserved, expected);
+    }
+
+    #[test]
+    fn out_of_fuel_restores_registers() {
+        let context = FuelContext::bounded(0)
+            .with_host_functions(&host_functions(), MemoryLayout::new(16, 0, 0));
+        let (memory, preserved, out_of_fuel) = run(context);
+
+        // Returning straight from the entry point skips the epilogue of main, but the callee
+        // saved registers of the host are still restored
+        assert!(out_of_fuel);
+        assert_eq!(memory[..REGISTERS.len()], vec![0; REGISTERS.len()]);
+        assert_eq!(memory[15], 42);
+        let expected: Vec<_> = (0..HOST_ABI.preserve.len()).map(preserved_value).collect();
+        assert_eq!(preserved, expected);
+    }
+}
@@ -0,0 +1,76 @@
+use super::arch::{Target, TargetInterface};
+
+use dynasmrt::{mmap::MutableBuffer, AssemblyOffset, ExecutableBuffer};
+
+use std::{io, ops::Deref};
+
+/// The granularity of memory protection.
+#[cfg(feature = "jit-hardened")]
+const PAGE_SIZE: usize = 4096;
+
+/// Generated machine code in executable memory.
+///
+/// The code is copied into a fresh mapping that only becomes executable once the copy is
+/// complete, and it never becomes writable again. The memory is never writable and executable
+/// at the same time.
+pub struct ExecutableCode {
+    buffer: ExecutableBuffer,
+    len: usize,
+}
+
+impl ExecutableCode {
+    pub fn new(code: &[u8]) -> io::Result<Self> {
+        // Fill the rest of the last page with trap instructions, so a jump past the end of the
+        // code stops the process instead of running whatever the page contains.
+        #[cfg(feature = "jit-hardened")]
+        let size = code.len().next_multiple_of(PAGE_SIZE);
+        #[cfg(not(feature = "jit-hardened"))]
+        let size = code.len();
+
+        let mut buffer = MutableBuffer::new(size)?;
+        buffer.set_len(size);
+        let (text, padding) = buffer.split_at_mut(code.len());
+        text.copy_from_slice(code);
+        Target::fill_trap(padding);
+        Target::flush_icache(&buffer);
+
+        Ok(Self {
+            buffer: buffer.make_exec()?,
+            len: code.len(),
+        })
+    }
+
+    /// A pointer to the code at the given offset.
+    pub fn ptr(&self, offset: usize) -> *const u8 {
+        debug_assert!(offset < self.len);
+        self.buffer.ptr(AssemblyOffset(offset))
+    }
+}
+
+impl Deref for ExecutableCode {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_code() {
+        let code = ExecutableCode::new(&[0x90, 0xC3]).unwrap();
+        assert_eq!(&*code, [0x90, 0xC3]);
+    }
+
+    #[cfg(feature = "jit-hardened")]
+    #[test]
+    fn pads_with_traps() {
+        let code = ExecutableCode::new(&[0x90, 0xC3]).unwrap();
+        // The whole page is mapped readable
+        let page = unsafe { std::slice::from_raw_parts(code.ptr(0), PAGE_SIZE) };
+        assert!(page[2..].iter().all(|&b| b == 0xCC));
+    }
+}
@@ -0,0 +1,382 @@
+use super::{
+    arch::{Target, TargetInterface},
+    ir::{Block, LiveRange, Var},
+};
+
+use bitvec::prelude::*;
+
+use std::collections::HashMap;
+
+/// Assign registers to live ranges by coloring their interference graph, using iterated
+/// register coalescing to give block params the same register as the values passed to them.
+///
+/// Returns the register of every range that could be colored, the other ranges should be
+/// spilled. `live_ranges` must be sorted in order of increasing start point.
+pub fn color(blocks: &[Block], live_ranges: &[LiveRange]) -> HashMap<Var, u32> {
+    let mut graph = Graph::new(live_ranges.len());
+
+    for (a, range_a) in live_ranges.iter().enumerate() {
+        for (b, range_b) in live_ranges.iter().enumerate().skip(a + 1) {
+            if range_b.start >= range_a.end {
+                break;
+            }
+            graph.add_edge(a, b);
+        }
+    }
+
+    // Variables that are live while an instruction executes can not use its scratch registers
+    for (i, inst) in blocks
+        .iter()
+        .flat_map(|block| block.instructions.iter())
+        .enumerate()
+    {
+        let clobbered_regs = Target::clobbered_regs(inst.kind);
+        if clobbered_regs == 0 {
+            continue;
+        }
+
+        let i = i as u32;
+        for (node, _) in live_ranges
+            .iter()
+            .enumerate()
+            .take_while(|(_, r)| r.start <= i)
+            .filter(|(_, r)| i < r.end)
+        {
+            graph.forbidden[node] |= clobbered_regs;
+        }
+    }
+
+    let nodes: HashMap<Var, usize> = live_ranges
+        .iter()
+        .enumerate()
+        .map(|(node, r)| (r.var, node))
+        .collect();
+    for block in blocks {
+        for inputs in &block.param_inputs {
+            for (param, input) in block.params.iter().zip(inputs) {
+                if let (Some(&a), Some(&b)) = (nodes.get(param), nodes.get(input)) {
+                    graph.add_move(a, b);
+                }
+            }
+        }
+    }
+
+    graph.build_worklists();
+    graph.reduce(live_ranges);
+    let colors = graph.select();
+
+    live_ranges
+        .iter()
+        .zip(colors)
+        .filter_map(|(r, color)| color.map(|c| (r.var, c)))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeState {
+    /// Not significant and not move related, can be removed from the graph.
+    Simplify,
+    /// Not significant, but move related.
+    Freeze,
+    /// Significant.
+    Spill,
+    Coalesced,
+    /// Removed from the graph and pushed on the select stack.
+    Selected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MoveState {
+    /// Might be coalesced.
+    Worklist,
+    /// Not ready to be coalesced yet.
+    Active,
+    /// Coalesced, constrained or frozen.
+    Done,
+}
+
+struct Graph {
+    node_count: usize,
+    matrix: BitVec,
+    adjacent: Vec<Vec<usize>>,
+    /// The amount of neighbors that are still in the graph.
+    degree: Vec<usize>,
+    /// Registers a node can not be colored with, as a mask of register indices.
+    forbidden: Vec<u64>,
+    state: Vec<NodeState>,
+    alias: Vec<usize>,
+    moves: Vec<(usize, usize)>,
+    move_state: Vec<MoveState>,
+    move_list: Vec<Vec<usize>>,
+    // Worklists can contain stale entries, the state is checked when popping
+    simplify_worklist: Vec<usize>,
+    freeze_worklist: Vec<usize>,
+    move_worklist: Vec<usize>,
+    select_stack: Vec<usize>,
+}
+
+impl Graph {
+    fn new(node_count: usize) -> Self {
+        Self {
+            node_count,
+            matrix: bitvec![0; node_count * node_count],
+            adjacent: vec![vec![]; node_count],
+            degree: vec![0; node_count],
+            forbidden: vec![0; node_count],
+            state: vec![NodeState::Spill; node_count],
+            alias: (0..node_count).collect(),
+            moves: vec![],
+            move_state: vec![],
+            move_list: vec![vec![]; node_count],
+            simplify_worklist: vec![],
+            freeze_worklist: vec![],
+            move_worklist: vec![],
+            select_stack: vec![],
+        }
+    }
+
+    fn interferes(&self, a: usize, b: usize) -> bool {
+        self.matrix[a * self.node_count + b]
+    }
+
+    fn add_edge(&mut self, a: usize, b: usize) {
+        if a == b || self.interferes(a, b) {
+            return;
+        }
+
+        self.matrix.set(a * self.node_count + b, true);
+        self.matrix.set(b * self.node_count + a, true);
+        self.adjacent[a].push(b);
+        self.adjacent[b].push(a);
+        self.degree[a] += 1;
+        self.degree[b] += 1;
+    }
+
+    fn add_move(&mut self, a: usize, b: usize) {
+        let m = self.moves.len();
+        self.moves.push((a, b));
+        self.move_state.push(MoveState::Worklist);
+        self.move_list[a].push(m);
+        self.move_list[b].push(m);
+        self.move_worklist.push(m);
+    }
+
+    /// The amount of registers a node can be colored with.
+    fn colors(&self, node: usize) -> usize {
+        Target::REGISTER_COUNT - self.forbidden[node].count_ones() as usize
+    }
+
+    fn is_significant(&self, node: usize) -> bool {
+        self.degree[node] >= self.colors(node)
+    }
+
+    fn find(&self, mut node: usize) -> usize {
+        while self.state[node] == NodeState::Coalesced {
+            node = self.alias[node];
+        }
+        node
+    }
+
+    /// The neighbors that are still in the graph.
+    fn adjacent(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adjacent[node]
+            .iter()
+            .copied()
+            .filter(|&t| !matches!(self.state[t], NodeState::Coalesced | NodeState::Selected))
+    }
+
+    fn is_move_related(&self, node: usize) -> bool {
+        self.move_list[node]
+            .iter()
+            .any(|&m| self.move_state[m] != MoveState::Done)
+    }
+
+    fn set_state(&mut self, node: usize, state: NodeState) {
+        self.state[node] = state;
+        match state {
+            NodeState::Simplify => self.simplify_worklist.push(node),
+            NodeState::Freeze => self.freeze_worklist.push(node),
+            _ => (),
+        }
+    }
+
+    fn build_worklists(&mut self) {
+        for node in 0..self.node_count {
+            let state = if self.is_significant(node) {
+                NodeState::Spill
+            } else if self.is_move_related(node) {
+                NodeState::Freeze
+            } else {
+                NodeState::Simplify
+            };
+            self.set_state(node, state);
+        }
+    }
+
+    fn reduce(&mut self, live_ranges: &[LiveRange]) {
+        loop {
+            if let Some(node) = self.simplify_worklist.pop() {
+                if self.state[node] == NodeState::Simplify {
+                    self.simplify(node);
+                }
+            } else if let Some(m) = self.move_worklist.pop() {
+                if self.move_state[m] == MoveState::Worklist {
+                    self.coalesce(m);
+                }
+            } else if let Some(node) = self.freeze_worklist.pop() {
+                if self.state[node] == NodeState::Freeze {
+                    self.set_state(node, NodeState::Simplify);
+                    self.freeze_moves(node);
+                }
+            } else if let Some(node) = (0..self.node_count)
+                .filter(|&n| self.state[n] == NodeState::Spill)
+                .max_by_key(|&n| live_ranges[n].end - live_ranges[n].start)
+            {
+                // Optimistically push the longest range, it only gets spilled if there is no
+                // color left for it when selecting
+                self.set_state(node, NodeState::Simplify);
+                self.freeze_moves(node);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn simplify(&mut self, node: usize) {
+        self.state[node] = NodeState::Selected;
+        self.select_stack.push(node);
+        for t in self.adjacent(node).collect::<Vec<_>>() {
+            self.decrement_degree(t);
+        }
+    }
+
+    fn decrement_degree(&mut self, node: usize) {
+        self.degree[node] -= 1;
+
+        if self.state[node] == NodeState::Spill && !self.is_significant(node) {
+            self.enable_moves(node);
+            for t in self.adjacent(node).collect::<Vec<_>>() {
+                self.enable_moves(t);
+            }
+
+            if self.is_move_related(node) {
+                self.set_state(node, NodeState::Freeze);
+            } else {
+                self.set_state(node, NodeState::Simplify);
+            }
+        }
+    }
+
+    fn enable_moves(&mut self, node: usize) {
+        for i in 0..self.move_list[node].len() {
+            let m = self.move_list[node][i];
+            if self.move_state[m] == MoveState::Active {
+                self.move_state[m] = MoveState::Worklist;
+                self.move_worklist.push(m);
+            }
+        }
+    }
+
+    /// Make a node that can no longer be coalesced available for simplification.
+    fn add_worklist(&mut self, node: usize) {
+        if self.state[node] == NodeState::Freeze
+            && !self.is_move_related(node)
+            && !self.is_significant(node)
+        {
+            self.set_state(node, NodeState::Simplify);
+        }
+    }
+
+    /// Briggs' conservative test, the combined node must have less significant neighbors than
+    /// colors so it can always be simplified.
+    fn can_combine(&self, a: usize, b: usize) -> bool {
+        let colors =
+            Target::REGISTER_COUNT - (self.forbidden[a] | self.forbidden[b]).count_ones() as usize;
+        let significant = self
+            .adjacent(a)
+            .chain(self.adjacent(b).filter(|&t| !self.interferes(t, a)))
+            .filter(|&t| self.is_significant(t))
+            .count();
+
+        significant < colors
+    }
+
+    fn coalesce(&mut self, m: usize) {
+        let (a, b) = self.moves[m];
+        let (a, b) = (self.find(a), self.find(b));
+
+        if a == b {
+            self.move_state[m] = MoveState::Done;
+            self.add_worklist(a);
+        } else if self.interferes(a, b) {
+            self.move_state[m] = MoveState::Done;
+            self.add_worklist(a);
+            self.add_worklist(b);
+        } else if self.can_combine(a, b) {
+            self.move_state[m] = MoveState::Done;
+            self.combine(a, b);
+            self.add_worklist(a);
+        } else {
+            self.move_state[m] = MoveState::Active;
+        }
+    }
+
+    fn combine(&mut self, a: usize, b: usize) {
+        self.state[b] = NodeState::Coalesced;
+        self.alias[b] = a;
+        let moves = std::mem::take(&mut self.move_list[b]);
+        self.move_list[a].extend(moves);
+        self.forbidden[a] |= self.forbidden[b];
+        self.enable_moves(a);
+
+        for t in self.adjacent(b).collect::<Vec<_>>() {
+            self.add_edge(t, a);
+            self.decrement_degree(t);
+        }
+
+        if self.state[a] == NodeState::Freeze && self.is_significant(a) {
+            self.set_state(a, NodeState::Spill);
+        }
+    }
+
+    /// Give up on coalescing the moves of a node.
+    fn freeze_moves(&mut self, node: usize) {
+        for i in 0..self.move_list[node].len() {
+            let m = self.move_list[node][i];
+            if self.move_state[m] == MoveState::Done {
+                continue;
+            }
+            self.move_state[m] = MoveState::Done;
+
+            let (a, b) = self.moves[m];
+            let other = if self.find(a) == node {
+                self.find(b)
+            } else {
+                self.find(a)
+            };
+            self.add_worklist(other);
+        }
+    }
+
+    fn select(&mut self) -> Vec<Option<u32>> {
+        let mut colors = vec![None; self.node_count];
+
+        while let Some(node) = self.select_stack.pop() {
+            let used = self.adjacent[node]
+                .iter()
+                .filter_map(|&t| colors[self.find(t)])
+                .fold(self.forbidden[node], |used, c: u32| used | (1 << c));
+
+            let color = used.trailing_ones();
+            if (color as usize) < Target::REGISTER_COUNT {
+                colors[node] = Some(color);
+            }
+        }
+
+        for node in 0..self.node_count {
+            colors[node] = colors[self.find(node)];
+        }
+
+        colors
+    }
+}
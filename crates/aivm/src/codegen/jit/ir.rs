@@ -1,36 +1,57 @@
-use std::fmt::Debug;
-
-use bitvec::prelude::*;
+use std::{collections::HashMap, fmt::Debug};
 
 use crate::{
-    codegen::{self, jit::regalloc::RegAllocations},
+    codegen::{
+        self,
+        jit::{regalloc::RegAllocations, RegisterAllocator},
+        ssa::Dominators,
+    },
     compile::CompareKind,
+    MemoryBank, MemoryLayout,
 };
 
 pub struct Emitter<'a> {
     func: &'a mut Function,
+    layout: MemoryLayout,
+    constants: &'a [i64],
     instruction_count: u32,
     branch_targets: Vec<PendingBranchTarget>,
     cur_block: Block,
+    /// The amount of VM instructions in the current block, including ones that emit nothing.
+    cur_block_cost: u32,
+    /// The index in the compiled code of the instruction being emitted.
+    code_index: u32,
+    /// Whether calls are surrounded by profiling instructions.
+    profile: bool,
 }
 
 impl<'a> Emitter<'a> {
-    pub fn new(func: &'a mut Function) -> Self {
+    pub fn new(
+        func: &'a mut Function,
+        layout: MemoryLayout,
+        constants: &'a [i64],
+        profile: bool,
+    ) -> Self {
         Self {
             func,
+            layout,
+            constants,
+            profile,
             instruction_count: 0,
             branch_targets: vec![],
             cur_block: Block {
-                instructions: (0..64)
-                    .map(|i| Instruction {
-                        kind: InstructionKind::InitVar,
-                        dst: [Var::new(i)],
-                        ..Instruction::default()
-                    })
-                    .collect(),
+                // Variables start out as zero, which is the value of the variable a read
+                // resolves to when there is no assignment before it.
+                instructions: vec![Instruction {
+                    kind: InstructionKind::InitVar,
+                    dst: [Var::ZERO],
+                    ..Instruction::default()
+                }],
                 var_def_mask: VarMask::ALL,
                 ..Block::default()
             },
+            cur_block_cost: 0,
+            code_index: Instruction::NO_SOURCE,
         }
     }
 
@@ -43,18 +64,38 @@ impl<'a> Emitter<'a> {
     }
 
     fn finish_block(&mut self) {
+        // Charge the fuel for the whole block when entering it, a block is never left halfway
+        // unless the fuel runs out.
+        let cost = std::mem::take(&mut self.cur_block_cost);
+        if cost != 0 {
+            self.cur_block.instructions.insert(
+                0,
+                Instruction {
+                    kind: InstructionKind::Fuel { cost },
+                    ..Instruction::default()
+                },
+            );
+        }
+
         let mut block = Block::default();
         std::mem::swap(&mut self.cur_block, &mut block);
         self.func.blocks.push(block);
     }
 
+    fn push_instruction(&mut self, inst: Instruction) {
+        self.cur_block.instructions.push(Instruction {
+            source: self.code_index,
+            ..inst
+        });
+    }
+
     fn finish_block_with_branch(&mut self, inst: Instruction, offset: u32) {
         let block_name = self.cur_block_name();
         let fall_through_proxy_block_name = BlockName(block_name.0 + 1);
         let branch_proxy_block_name = BlockName(block_name.0 + 2);
         let next_block_name = BlockName(block_name.0 + 3);
 
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
         self.cur_block.exit = fall_through_proxy_block_name;
         self.cur_block.branch_exit = branch_proxy_block_name;
         self.finish_block();
@@ -104,8 +145,9 @@ impl<'a> Emitter<'a> {
                     ..
                 } = self.branch_targets.swap_remove(i);
 
-                // Begin new block for branch to jump to
-                if !self.cur_block.instructions.is_empty() {
+                // Begin new block for branch to jump to, also after instructions that only cost
+                // fuel, since the branch skips them
+                if !self.cur_block.instructions.is_empty() || self.cur_block_cost != 0 {
                     self.finish_block_with_fall_through();
                 }
 
@@ -128,224 +170,55 @@ impl<'a> Emitter<'a> {
 }
 
 impl<'a> codegen::private::Emitter for Emitter<'a> {
-    fn prepare_emit(&mut self) {
+    fn prepare_emit(&mut self, code_index: usize) {
+        self.code_index = u32::try_from(code_index).unwrap();
         self.create_branch_targets();
         self.instruction_count += 1;
+        self.cur_block_cost += 1;
     }
 
-    fn finalize(&mut self) {
+    fn finalize(mut self) {
         self.create_branch_targets();
+        self.func.instruction_count = self.instruction_count;
 
         self.cur_block.instructions.push(Instruction::return_());
         self.finish_block();
+    }
 
-        // Initialize dominators array
-        // The blocks array is naturally in reverse post order
-        let mut doms = vec![BlockName::INVALID; self.func.blocks.len()];
-        doms[0] = BlockName(0);
-        let mut changed = true;
-        while changed {
-            changed = false;
-
-            for (b, block) in self.func.blocks.iter().enumerate().skip(1) {
-                let mut new_idom = block
-                    .predecessors
-                    .iter()
-                    .copied()
-                    .find(|p| doms[p.0 as usize].is_valid())
-                    .unwrap();
-                let initial_idom = new_idom;
-
-                for predecessor in block
-                    .predecessors
-                    .iter()
-                    .copied()
-                    .filter(|&p| p != initial_idom)
-                {
-                    if doms[predecessor.0 as usize].is_valid() {
-                        let mut finger1 = predecessor.0;
-                        let mut finger2 = new_idom.0;
-
-                        while finger1 != finger2 {
-                            while finger1 > finger2 {
-                                finger1 = doms[finger1 as usize].0;
-                            }
-                            while finger2 > finger1 {
-                                finger2 = doms[finger2 as usize].0;
-                            }
-                        }
-
-                        new_idom = BlockName(finger1);
-                    }
-                }
-
-                changed = doms[b] != new_idom;
-                doms[b] = new_idom;
-            }
-        }
-
-        // Build dominance frontier sets
-        let mut dominance_frontiers = vec![vec![]; self.func.blocks.len()];
-        for (b, block) in self
-            .func
-            .blocks
-            .iter()
-            .enumerate()
-            .filter(|(_, b)| b.predecessors.len() > 1)
-        {
-            let b = BlockName(b as u32);
-            for p in block.predecessors.iter().copied() {
-                let mut runner = p;
-                while runner != doms[b.0 as usize] {
-                    let dominance_frontier = &mut dominance_frontiers[runner.0 as usize];
-                    if !dominance_frontier.contains(&b) {
-                        dominance_frontier.push(b);
-                    }
-                    runner = doms[runner.0 as usize];
-                }
-            }
-        }
-
-        // Insert block params where necessary
-        let mut processed_blocks = bitvec![0; self.func.blocks.len()];
-        let mut pushed_blocks = bitvec![0; self.func.blocks.len()];
-        let mut block_stack = vec![];
-        for v in 0..64 {
-            processed_blocks.set_elements(0);
-            pushed_blocks.set_elements(0);
-            block_stack.clear();
-
-            for b in self
-                .func
-                .blocks
-                .iter()
-                .enumerate()
-                .filter_map(|(b, block)| {
-                    block
-                        .var_def_mask
-                        .contains(v)
-                        .then_some(BlockName(b as u32))
-                })
-            {
-                block_stack.push(b);
-                pushed_blocks.set(b.0 as usize, true);
-            }
-
-            while let Some(b) = block_stack.pop() {
-                for &f in &dominance_frontiers[b.0 as usize] {
-                    if !processed_blocks[f.0 as usize] {
-                        self.func.blocks[f.0 as usize].params.push(Var::new(v));
-                        self.func.blocks[f.0 as usize].var_def_mask.insert(v);
-                        processed_blocks.set(f.0 as usize, true);
-
-                        if !pushed_blocks[f.0 as usize] {
-                            pushed_blocks.set(f.0 as usize, true);
-                            block_stack.push(f);
-                        }
-                    }
-                }
-            }
-        }
-
-        let mut version_counters = [0; 64];
-        // Should be a stack array but Vec doesn't implement Copy
-        let mut var_stacks = vec![vec![]; 64];
-        let mut block_stack = vec![];
-        let mut live_ranges = vec![];
-
-        let mut gen_name =
-            |v: &mut Var, var_stacks: &mut [Vec<(u32, u32, u32)>], cur_instruction: u32| {
-                let counter = &mut version_counters[v.name() as usize];
-                v.set_version(*counter);
-                var_stacks[v.name() as usize].push((*counter, cur_instruction, 0));
-                *counter += 1;
+    fn emit_call(&mut self, idx: u32) {
+        let call = [
+            InstructionKind::ProfileEnter { idx },
+            InstructionKind::Call { idx },
+            InstructionKind::ProfileLeave { idx },
+        ];
+        let kinds = if self.profile { &call[..] } else { &call[1..2] };
+        for &kind in kinds {
+            let inst = Instruction {
+                kind,
+                ..Instruction::default()
             };
-
-        block_stack.push((BlockName(0), BlockName(0)));
-        while let Some((b, last_child)) = block_stack.pop() {
-            let instructions_start = self
-                .func
-                .blocks
-                .iter()
-                .take(b.0 as usize)
-                .map(|b| b.instructions.len() as u32)
-                .sum();
-            let block = &mut self.func.blocks[b.0 as usize];
-            if b == last_child {
-                for var in &mut block.params {
-                    gen_name(var, &mut var_stacks, instructions_start);
-                }
-
-                for (i, inst) in (instructions_start..).zip(block.instructions.iter_mut()) {
-                    for src in inst.src_iter_mut() {
-                        let stack_entry = var_stacks[src.name() as usize].last_mut().unwrap();
-                        // Update the live interval to include the current latest usage
-                        debug_assert!(i + 1 >= stack_entry.2);
-                        stack_entry.2 = i + 1;
-                        src.set_version(stack_entry.0);
-                    }
-                    for dst in inst.dst_iter_mut() {
-                        gen_name(dst, &mut var_stacks, i);
-                    }
-                }
-            }
-
-            // Visit children in dominator tree
-            if let Some(child) = doms
-                .iter()
-                .copied()
-                .enumerate()
-                .skip(1 + last_child.0 as usize)
-                .find_map(|(c, p)| (p == b).then_some(BlockName(c as u32)))
-            {
-                block_stack.push((b, child));
-                block_stack.push((child, child));
-                continue;
-            }
-
-            // Pop from stack in reverse order to match var versions
-            // Since the same variable name cannot appear twice in either the instruction
-            // destinations or the block parameters, we don't have to reverse those
-            for var in block
-                .instructions
-                .iter()
-                .rev()
-                .flat_map(|inst| inst.dst_iter())
-                .chain(block.params.iter().copied())
-            {
-                let (version, start, end) = var_stacks[var.name() as usize].pop().unwrap();
-                debug_assert_eq!(var.version(), version);
-
-                live_ranges.push(LiveRange { var, start, end });
-            }
-        }
-
-        live_ranges.sort_unstable_by_key(|r| if r.end == 0 { u32::MAX } else { r.start });
-        // Don't need variables that never get read
-        if let Some(last_live) = live_ranges.iter().rposition(|r| r.end != 0) {
-            live_ranges.truncate(last_live + 1);
+            self.push_instruction(inst);
         }
-
-        RegAllocations::run(self.func, live_ranges);
     }
 
-    fn emit_call(&mut self, idx: u32) {
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, id: u32) {
         let inst = Instruction {
-            kind: InstructionKind::Call { idx },
+            kind: InstructionKind::HostCall { id },
             ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
-    fn emit_nop(&mut self) {}
-
     fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
         let inst = Instruction {
             kind: InstructionKind::IntAdd,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
@@ -353,8 +226,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntSub,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
@@ -362,8 +236,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntMul,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
@@ -371,8 +246,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntMulHigh,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
@@ -380,8 +256,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntMulHighUnsigned,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_neg(&mut self, dst: u8, src: u8) {
@@ -389,8 +266,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntNeg,
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_abs(&mut self, dst: u8, src: u8) {
@@ -398,8 +276,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntAbs,
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_inc(&mut self, dst: u8) {
@@ -407,8 +286,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntInc,
             dst: [self.def_var(dst)],
             src: [self.use_var(dst), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_dec(&mut self, dst: u8) {
@@ -416,8 +296,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntDec,
             dst: [self.def_var(dst)],
             src: [self.use_var(dst), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
@@ -425,8 +306,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntMin,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
@@ -434,8 +316,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::IntMax,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
@@ -443,8 +326,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitOr,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
@@ -452,8 +336,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitAnd,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
@@ -461,8 +346,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitXor,
             dst: [self.def_var(dst)],
             src: [self.use_var(a), self.use_var(b), Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_not(&mut self, dst: u8, src: u8) {
@@ -470,8 +356,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitNot,
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
@@ -479,8 +366,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitShiftLeft { amount },
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
@@ -488,8 +376,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitShiftRight { amount },
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
@@ -497,8 +386,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitRotateLeft { amount },
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
@@ -506,8 +396,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitRotateRight { amount },
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
@@ -515,8 +406,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitSelect,
             dst: [self.def_var(dst)],
             src: [self.use_var(mask), self.use_var(a), self.use_var(b)],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
@@ -524,8 +416,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitPopcnt,
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
@@ -533,8 +426,9 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
             kind: InstructionKind::BitReverse,
             dst: [self.def_var(dst)],
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
+            ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
     fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
@@ -564,22 +458,35 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
         self.finish_block_with_branch(inst, offset);
     }
 
-    fn emit_mem_load(&mut self, dst: u8, addr: u32) {
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        let addr = self.layout.address(bank, addr);
+        let kind = if bank == MemoryBank::Const {
+            InstructionKind::Const {
+                value: self.constants[addr as usize],
+            }
+        } else {
+            InstructionKind::MemLoad { addr }
+        };
         let inst = Instruction {
-            kind: InstructionKind::MemLoad { addr },
+            kind,
             dst: [self.def_var(dst)],
             ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 
-    fn emit_mem_store(&mut self, addr: u32, src: u8) {
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if !bank.is_writable() {
+            return;
+        }
+
+        let addr = self.layout.address(bank, addr);
         let inst = Instruction {
             kind: InstructionKind::MemStore { addr },
             src: [self.use_var(src), Var::INVALID, Var::INVALID],
             ..Instruction::default()
         };
-        self.cur_block.instructions.push(inst);
+        self.push_instruction(inst);
     }
 }
 
@@ -587,12 +494,288 @@ impl<'a> codegen::private::Emitter for Emitter<'a> {
 pub struct Function {
     pub blocks: Vec<Block>,
     pub reg_allocs: RegAllocations,
+    /// The amount of VM instructions the function was emitted from.
+    pub instruction_count: u32,
+}
+
+impl Function {
+    /// Replace calls to functions that consist of a single block with at most `threshold`
+    /// instructions by the body of the function.
+    ///
+    /// `callees` are the functions following this one, starting at index `first_callee`. Calls
+    /// always go to a function with a higher index, so the callees have already been compiled
+    /// when they are inlined. The fuel check of the callee is inlined along with its body.
+    pub fn inline_calls(&mut self, callees: &[Function], first_callee: u32, threshold: u32) {
+        let mut versions = [Var::ZERO.version() + 1; Var::NAME_COUNT];
+        let mut renames = HashMap::new();
+
+        for block in &mut self.blocks {
+            let mut i = 0;
+            while i < block.instructions.len() {
+                let InstructionKind::Call { idx } = block.instructions[i].kind else {
+                    i += 1;
+                    continue;
+                };
+                let callee = &callees[(idx - first_callee) as usize];
+                if !callee.is_inlinable(threshold) {
+                    i += 1;
+                    continue;
+                }
+
+                // The callee starts with zeroed variables, so it can not observe the variables
+                // of the caller. Give its variables new versions that do not clash with the
+                // versions the caller gets later.
+                renames.clear();
+                let mut rename = |var: &mut Var| {
+                    if *var == Var::ZERO {
+                        return;
+                    }
+                    *var = *renames.entry(*var).or_insert_with(|| {
+                        let version = &mut versions[var.name() as usize];
+                        debug_assert!(*version <= Var::MAX_VERSION);
+                        let mut renamed = *var;
+                        renamed.set_version(*version);
+                        *version += 1;
+                        renamed
+                    });
+                };
+                let body: Vec<_> = callee.blocks[0]
+                    .instructions
+                    .iter()
+                    .filter(|inst| {
+                        !matches!(
+                            inst.kind,
+                            InstructionKind::InitVar | InstructionKind::Return
+                        )
+                    })
+                    .map(|&inst| {
+                        let mut inst = inst;
+                        inst.src_iter_mut().for_each(&mut rename);
+                        inst.dst_iter_mut().for_each(&mut rename);
+                        inst
+                    })
+                    .collect();
+
+                let len = body.len();
+                block.instructions.splice(i..i + 1, body);
+                i += len;
+            }
+        }
+    }
+
+    fn is_inlinable(&self, threshold: u32) -> bool {
+        let [block] = &self.blocks[..] else {
+            return false;
+        };
+        let size = block
+            .instructions
+            .iter()
+            .filter(|inst| {
+                !matches!(
+                    inst.kind,
+                    InstructionKind::InitVar
+                        | InstructionKind::Return
+                        | InstructionKind::Fuel { .. }
+                )
+            })
+            .count();
+
+        size <= threshold as usize
+    }
+
+    /// Convert the function to SSA form and assign the variables to registers or the stack.
+    pub fn allocate_registers(&mut self, allocator: RegisterAllocator) {
+        // The blocks array is naturally in reverse post order
+        let dominators = Dominators::compute(self.blocks.len(), |b| {
+            self.blocks[b].predecessors.iter().map(|p| p.0 as usize)
+        });
+
+        // Insert block params where necessary
+        // Every variable is defined in the entry block, but it has no predecessors, so only the
+        // variables that are also defined in other blocks can need params.
+        debug_assert!(dominators.frontier(0).is_empty());
+        let mut defined_vars = VarMask::EMPTY;
+        for block in &self.blocks[1..] {
+            defined_vars.union(block.var_def_mask);
+        }
+        for v in defined_vars.iter() {
+            let def_blocks = self
+                .blocks
+                .iter()
+                .enumerate()
+                .filter_map(|(b, block)| block.var_def_mask.contains(v).then_some(b));
+            let mut param_blocks = vec![];
+            dominators.place_params(def_blocks, |b| param_blocks.push(b));
+
+            for b in param_blocks {
+                self.blocks[b].params.push(Var::new(v));
+                self.blocks[b].var_def_mask.insert(v);
+            }
+        }
+
+        let mut version_counters = [0; Var::NAME_COUNT];
+        // Should be a stack array but Vec doesn't implement Copy
+        let mut var_stacks = vec![vec![]; Var::NAME_COUNT];
+        let mut block_stack = vec![];
+        let mut live_ranges = vec![];
+        // Variables with a preassigned version are already in SSA form
+        let mut preassigned_ranges: Vec<LiveRange> = vec![];
+        let mut preassigned_range_indices: HashMap<Var, usize> = HashMap::new();
+
+        let mut gen_name =
+            |v: &mut Var, var_stacks: &mut [Vec<(u32, u32, u32)>], cur_instruction: u32| {
+                let counter = &mut version_counters[v.name() as usize];
+                debug_assert!(*counter < Var::PREASSIGNED_VERSION);
+                v.set_version(*counter);
+                var_stacks[v.name() as usize].push((*counter, cur_instruction, 0));
+                *counter += 1;
+            };
+
+        let block_starts: Vec<u32> = self
+            .blocks
+            .iter()
+            .scan(0, |start, block| {
+                let block_start = *start;
+                *start += block.instructions.len() as u32;
+                Some(block_start)
+            })
+            .collect();
+
+        block_stack.push((BlockName(0), BlockName(0)));
+        while let Some((b, last_child)) = block_stack.pop() {
+            let instructions_start = block_starts[b.0 as usize];
+            let block = &mut self.blocks[b.0 as usize];
+            if b == last_child {
+                for var in &mut block.params {
+                    gen_name(var, &mut var_stacks, instructions_start);
+                }
+
+                for (i, inst) in (instructions_start..).zip(block.instructions.iter_mut()) {
+                    for src in inst.src_iter_mut() {
+                        if !src.is_preassigned() {
+                            match var_stacks[src.name() as usize].last_mut() {
+                                Some(stack_entry) => {
+                                    // Update the live interval to include the current latest usage
+                                    stack_entry.2 = stack_entry.2.max(i + 1);
+                                    src.set_version(stack_entry.0);
+                                    continue;
+                                }
+                                // Not assigned on any path to this instruction
+                                None => *src = Var::ZERO,
+                            }
+                        }
+
+                        let range = &mut preassigned_ranges[preassigned_range_indices[src]];
+                        range.end = range.end.max(i + 1);
+                    }
+                    for dst in inst.dst_iter_mut() {
+                        if dst.is_preassigned() {
+                            preassigned_range_indices.insert(*dst, preassigned_ranges.len());
+                            preassigned_ranges.push(LiveRange {
+                                var: *dst,
+                                start: i,
+                                end: 0,
+                            });
+                        } else {
+                            gen_name(dst, &mut var_stacks, i);
+                        }
+                    }
+                }
+
+                // Record the values that flow into the params of the successors. They are used
+                // on the edge, so keep them alive until the end of it. The edge from the first
+                // predecessor is a fall through, which ends right before the successor.
+                let block_end = instructions_start + block.instructions.len() as u32;
+                for s in [block.exit, block.branch_exit]
+                    .into_iter()
+                    .filter(|s| s.is_valid())
+                {
+                    let successor = &mut self.blocks[s.0 as usize];
+                    if successor.params.is_empty() {
+                        continue;
+                    }
+
+                    let p = successor.predecessors.iter().position(|&p| p == b).unwrap();
+                    let edge_end = if p == 0 {
+                        block_starts[s.0 as usize]
+                    } else {
+                        block_end
+                    };
+
+                    let inputs = successor
+                        .params
+                        .iter()
+                        .map(|param| match var_stacks[param.name() as usize].last_mut() {
+                            Some(stack_entry) => {
+                                stack_entry.2 = stack_entry.2.max(edge_end);
+                                let mut input = *param;
+                                input.set_version(stack_entry.0);
+                                input
+                            }
+                            None => {
+                                let range =
+                                    &mut preassigned_ranges[preassigned_range_indices[&Var::ZERO]];
+                                range.end = range.end.max(edge_end);
+                                Var::ZERO
+                            }
+                        })
+                        .collect();
+
+                    let predecessor_count = successor.predecessors.len();
+                    successor
+                        .param_inputs
+                        .resize_with(predecessor_count, Vec::new);
+                    successor.param_inputs[p] = inputs;
+                }
+            }
+
+            // Visit children in dominator tree
+            if let Some(child) = (1 + last_child.0 as usize..self.blocks.len())
+                .find(|&c| dominators.idom(c) == b.0 as usize)
+                .map(|c| BlockName(c as u32))
+            {
+                block_stack.push((b, child));
+                block_stack.push((child, child));
+                continue;
+            }
+
+            // Pop from stack in reverse order to match var versions
+            // Since the same variable name cannot appear twice in either the instruction
+            // destinations or the block parameters, we don't have to reverse those
+            let block = &self.blocks[b.0 as usize];
+            for var in block
+                .instructions
+                .iter()
+                .rev()
+                .flat_map(|inst| inst.dst_iter())
+                .filter(|var| !var.is_preassigned())
+                .chain(block.params.iter().copied())
+            {
+                let (version, start, end) = var_stacks[var.name() as usize].pop().unwrap();
+                debug_assert_eq!(var.version(), version);
+
+                live_ranges.push(LiveRange { var, start, end });
+            }
+        }
+
+        live_ranges.append(&mut preassigned_ranges);
+        live_ranges.sort_unstable_by_key(|r| if r.end == 0 { u32::MAX } else { r.start });
+        // Don't need variables that never get read
+        if let Some(last_live) = live_ranges.iter().rposition(|r| r.end != 0) {
+            live_ranges.truncate(last_live + 1);
+        }
+
+        RegAllocations::run(self, live_ranges, allocator);
+    }
 }
 
 #[derive(Debug)]
 pub struct Block {
-    predecessors: Vec<BlockName>,
-    params: Vec<Var>,
+    /// The first predecessor is the one that falls through into this block.
+    pub predecessors: Vec<BlockName>,
+    pub params: Vec<Var>,
+    /// For every predecessor, the variables that are passed as the params of this block.
+    pub param_inputs: Vec<Vec<Var>>,
     var_def_mask: VarMask,
     pub instructions: Vec<Instruction>,
     pub exit: BlockName,
@@ -604,6 +787,7 @@ impl Default for Block {
         Self {
             predecessors: vec![],
             params: vec![],
+            param_inputs: vec![],
             var_def_mask: VarMask::EMPTY,
             instructions: vec![],
             exit: BlockName::INVALID,
@@ -612,14 +796,24 @@ impl Default for Block {
     }
 }
 
+/// A variable in SSA form, the name is in the upper 8 bits and the version in the rest.
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Var(u32);
 
 impl Var {
+    /// The amount of different names a variable can have.
+    const NAME_COUNT: usize = 1 << u8::BITS;
+    const NAME_SHIFT: u32 = 24;
+    const MAX_VERSION: u32 = (1 << Self::NAME_SHIFT) - 1;
     const INVALID: Self = Self(u32::MAX);
+    /// Versions with this bit set are assigned before the function is converted to SSA form.
+    /// They belong to [ZERO](Self::ZERO) or to variables of an inlined function.
+    const PREASSIGNED_VERSION: u32 = 1 << (Self::NAME_SHIFT - 1);
+    /// The value of variables that are read before they are assigned.
+    const ZERO: Self = Self(Self::PREASSIGNED_VERSION);
 
     fn new(name: u8) -> Self {
-        Self((name as u32) << 26)
+        Self(u32::from(name) << Self::NAME_SHIFT)
     }
 
     fn is_valid(self) -> bool {
@@ -628,18 +822,23 @@ impl Var {
 
     #[inline]
     fn name(self) -> u8 {
-        (self.0 >> 26) as u8
+        (self.0 >> Self::NAME_SHIFT) as u8
     }
 
     #[inline]
     fn version(self) -> u32 {
-        self.0 & 0x03FFFFFF
+        self.0 & Self::MAX_VERSION
+    }
+
+    #[inline]
+    fn is_preassigned(self) -> bool {
+        self.version() & Self::PREASSIGNED_VERSION != 0
     }
 
     #[inline]
     fn set_version(&mut self, version: u32) {
-        self.0 &= 0xFC000000;
-        self.0 |= 0x03FFFFFF & version;
+        self.0 &= !Self::MAX_VERSION;
+        self.0 |= Self::MAX_VERSION & version;
     }
 }
 
@@ -653,21 +852,32 @@ impl Debug for Var {
     }
 }
 
+/// A set of variable names.
 #[derive(Debug, Clone, Copy)]
-struct VarMask(u64);
+struct VarMask([u64; Var::NAME_COUNT / 64]);
 
 impl VarMask {
-    const ALL: Self = Self(u64::MAX);
-    const EMPTY: Self = Self(0);
+    const ALL: Self = Self([u64::MAX; Var::NAME_COUNT / 64]);
+    const EMPTY: Self = Self([0; Var::NAME_COUNT / 64]);
 
     #[inline]
     fn insert(&mut self, var_name: u8) {
-        self.0 |= 1 << var_name;
+        self.0[usize::from(var_name / 64)] |= 1 << (var_name % 64);
     }
 
     #[inline]
     fn contains(self, var_name: u8) -> bool {
-        self.0 & (1 << var_name) != 0
+        self.0[usize::from(var_name / 64)] & (1 << (var_name % 64)) != 0
+    }
+
+    fn union(&mut self, other: Self) {
+        for (word, other) in self.0.iter_mut().zip(other.0) {
+            *word |= other;
+        }
+    }
+
+    fn iter(self) -> impl Iterator<Item = u8> {
+        (0..=u8::MAX).filter(move |&name| self.contains(name))
     }
 }
 
@@ -678,7 +888,7 @@ pub struct LiveRange {
     pub end: u32,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BlockName(pub u32);
 
 impl BlockName {
@@ -699,9 +909,13 @@ pub struct Instruction {
     pub kind: InstructionKind,
     dst: [Var; 1],
     src: [Var; 3],
+    /// The index of the VM instruction in the compiled code, if there is one.
+    pub source: u32,
 }
 
 impl Instruction {
+    pub const NO_SOURCE: u32 = u32::MAX;
+
     fn jump() -> Self {
         Self {
             kind: InstructionKind::Jump,
@@ -739,6 +953,7 @@ impl Default for Instruction {
             kind: InstructionKind::Return,
             dst: [Var::INVALID; 1],
             src: [Var::INVALID; 3],
+            source: Self::NO_SOURCE,
         }
     }
 }
@@ -748,9 +963,29 @@ pub enum InstructionKind {
     Return,
     Jump,
     InitVar,
-
-    Call { idx: u32 },
-    BranchCmp { compare_kind: CompareKind },
+    /// Consume fuel for the instructions of a block, or abort execution if there is not enough.
+    Fuel {
+        cost: u32,
+    },
+
+    Call {
+        idx: u32,
+    },
+    /// Call a host function, which can read and write the whole memory.
+    HostCall {
+        id: u32,
+    },
+    /// Count a call to function `idx` and subtract the timestamp from its ticks.
+    ProfileEnter {
+        idx: u32,
+    },
+    /// Add the timestamp to the ticks of function `idx`, after a call to it returned.
+    ProfileLeave {
+        idx: u32,
+    },
+    BranchCmp {
+        compare_kind: CompareKind,
+    },
     BranchZero,
     BranchNonZero,
     IntAdd,
@@ -768,13 +1003,29 @@ pub enum InstructionKind {
     BitAnd,
     BitXor,
     BitNot,
-    BitShiftLeft { amount: u8 },
-    BitShiftRight { amount: u8 },
-    BitRotateLeft { amount: u8 },
-    BitRotateRight { amount: u8 },
+    BitShiftLeft {
+        amount: u8,
+    },
+    BitShiftRight {
+        amount: u8,
+    },
+    BitRotateLeft {
+        amount: u8,
+    },
+    BitRotateRight {
+        amount: u8,
+    },
     BitSelect,
     BitPopcnt,
     BitReverse,
-    MemLoad { addr: u32 },
-    MemStore { addr: u32 },
+    MemLoad {
+        addr: u32,
+    },
+    /// Load a constant that is embedded in the code.
+    Const {
+        value: i64,
+    },
+    MemStore {
+        addr: u32,
+    },
 }
@@ -1,92 +1,893 @@
-use crate::codegen::{
-    self,
-    jit::arch::{Target, TargetInterface},
+use crate::{
+    codegen::{
+        self,
+        fuel::{self, FuelContext},
+        jit::arch::{Target, TargetInterface},
+        CompileReport, FunctionReport, Profile,
+    },
+    DeadlineExceeded, HostFunctions, MemoryLayout, StepResult,
 };
 
-use dynasmrt::{dynasm, Assembler, AssemblyOffset, DynasmLabelApi, ExecutableBuffer};
+use dynasmrt::{dynasm, AssemblyOffset, DynasmApi, DynasmLabelApi, VecAssembler};
 
-use std::mem::transmute;
+use std::{
+    mem::transmute,
+    ops::Range,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+pub use persist::LoadError;
 
 mod arch;
+mod code;
+mod coloring;
 mod ir;
+mod persist;
 mod regalloc;
 
 /// A code generator that does minimal optimization and generates machine code.
-#[derive(Default)]
+///
+/// The generated code is never writable and executable at the same time. The `jit-hardened`
+/// feature also fills the unused end of the code pages with trap instructions.
 pub struct Jit {
     functions: Vec<ir::Function>,
+    layout: MemoryLayout,
+    allocator: RegisterAllocator,
+    inline_threshold: u32,
+    compile_start: Instant,
+    report: CompileReport,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+    profiling: bool,
+}
+
+impl Default for Jit {
+    fn default() -> Self {
+        Self {
+            functions: vec![],
+            layout: MemoryLayout::default(),
+            allocator: RegisterAllocator::default(),
+            inline_threshold: Self::DEFAULT_INLINE_THRESHOLD,
+            compile_start: Instant::now(),
+            report: CompileReport::default(),
+            host: HostFunctions::new(),
+            constants: Arc::new([]),
+            profiling: false,
+        }
+    }
+}
+
+/// The algorithm the [Jit] uses to assign variables to registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RegisterAllocator {
+    /// Linear scan, which allocates in a single pass over the code.
+    #[default]
+    LinearScan,
+    /// Graph coloring with iterated register coalescing. It spills less and needs fewer moves
+    /// between blocks, but compiling takes longer, which makes it a better fit when deploying a
+    /// trained agent.
+    GraphColoring,
 }
 
 impl codegen::private::CodeGeneratorImpl for Jit {
     type Emitter<'a> = ir::Emitter<'a>;
     type Runner = Runner;
 
-    fn begin(&mut self, function_count: std::num::NonZeroU32) {
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.host = host.clone();
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: std::num::NonZeroU32, layout: MemoryLayout) {
+        self.compile_start = Instant::now();
+        self.layout = layout;
         self.functions
             .resize_with(function_count.get() as usize, Default::default);
     }
 
     fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
-        ir::Emitter::new(&mut self.functions[idx as usize])
+        ir::Emitter::new(
+            &mut self.functions[idx as usize],
+            self.layout,
+            &self.constants,
+            self.profiling,
+        )
     }
 
-    fn finish(&mut self, memory_size: u32, output_size: u32, input_size: u32) -> Self::Runner {
-        let mut ops = Assembler::<<Target as TargetInterface>::Relocation>::new().unwrap();
+    fn finish(&mut self) -> Self::Runner {
+        let instruction_count = self
+            .functions
+            .iter()
+            .map(|func| func.instruction_count as usize)
+            .sum();
+
+        // Callees have a higher index than their caller, so they are finished before they get
+        // inlined.
+        for f in (0..self.functions.len()).rev() {
+            let (func, callees) = self.functions[f..].split_first_mut().unwrap();
+            func.inline_calls(callees, f as u32 + 1, self.inline_threshold);
+            func.allocate_registers(self.allocator);
+        }
+
+        let mut ops = VecAssembler::<<Target as TargetInterface>::Relocation>::new(0);
         let func_labels: Vec<_> = (0..self.functions.len())
             .map(|_| ops.new_dynamic_label())
             .collect();
+        let unmetered_labels: Vec<_> = (0..self.functions.len())
+            .map(|_| ops.new_dynamic_label())
+            .collect();
         let mut block_labels = vec![];
+        let mut edge_labels = vec![];
+
+        Target::emit_entry(&mut ops, func_labels[0], unmetered_labels[0]);
 
+        let mut function_offsets = vec![];
+        let mut source_map = vec![];
+        self.report.functions.clear();
         for (f, func) in self.functions.drain(..).enumerate() {
+            function_offsets.push(ops.offset());
             let reg_allocs = func.reg_allocs;
-            block_labels.clear();
-            block_labels.extend((0..func.blocks.len()).map(|_| ops.new_dynamic_label()));
+            self.report.functions.push(FunctionReport {
+                code_size: 0,
+                stack_size: reg_allocs.stack_size * 8,
+                spills: Some(reg_allocs.spills),
+                used_regs_mask: Some(Target::hardware_regs(reg_allocs.used_regs_mask)),
+            });
+
+            // Every function is emitted twice: without fuel checks for steps that run until
+            // the code returns, and with them for bounded steps. Only the first copy is in
+            // the source map.
+            for (labels, metered) in [(&unmetered_labels, false), (&func_labels, true)] {
+                block_labels.clear();
+                block_labels.extend((0..func.blocks.len()).map(|_| ops.new_dynamic_label()));
+                // Branches without moves jump straight to their target block
+                edge_labels.clear();
+                edge_labels.extend(reg_allocs.edges.iter().map(|edge| {
+                    if edge.moves.is_empty() {
+                        block_labels[edge.target.0 as usize]
+                    } else {
+                        ops.new_dynamic_label()
+                    }
+                }));
+
+                dynasm!(ops; =>labels[f]);
+                Target::emit_prologue(&mut ops, reg_allocs.stack_size, reg_allocs.used_regs_mask);
+
+                for inst in &reg_allocs.instructions {
+                    let start = ops.offset().0;
+                    Target::emit_instruction(
+                        &mut ops,
+                        inst,
+                        labels,
+                        &block_labels,
+                        &edge_labels,
+                        metered,
+                    );
 
-            dynasm!(ops; =>func_labels[f]);
-            Target::emit_prologue(&mut ops, reg_allocs.stack_size, reg_allocs.used_regs_mask);
+                    if !metered && inst.source != ir::Instruction::NO_SOURCE {
+                        source_map.push(SourceMapEntry {
+                            instruction: inst.source as usize,
+                            code: start..ops.offset().0,
+                        });
+                    }
+                }
 
-            for inst in reg_allocs.instructions {
-                Target::emit_instruction(&mut ops, inst, &func_labels, &block_labels);
+                Target::emit_epilogue(&mut ops, reg_allocs.stack_size, reg_allocs.used_regs_mask);
+
+                for (edge, &label) in reg_allocs.edges.iter().zip(&edge_labels) {
+                    if !edge.moves.is_empty() {
+                        dynasm!(ops; =>label);
+                        Target::emit_edge(
+                            &mut ops,
+                            &edge.moves,
+                            block_labels[edge.target.0 as usize],
+                        );
+                    }
+                }
             }
 
-            Target::emit_epilogue(&mut ops, reg_allocs.stack_size, reg_allocs.used_regs_mask);
+            self.report.functions[f].code_size = ops.offset().0 - function_offsets[f].0;
         }
 
-        let code = ops.finalize().unwrap();
-        //println!("{:02x?}", &code[..]);
+        let code = code::ExecutableCode::new(&ops.finalize().unwrap()).unwrap();
+        self.report.compile_time = self.compile_start.elapsed();
+
+        #[cfg(feature = "perf-map")]
+        {
+            let ends = function_offsets
+                .iter()
+                .skip(1)
+                .map(|o| o.0)
+                .chain([code.len()]);
+            let entry = (0, function_offsets[0].0, "aivm::jit::entry".to_string());
+            let functions =
+                function_offsets
+                    .iter()
+                    .zip(ends)
+                    .enumerate()
+                    .map(|(f, (start, end))| {
+                        (start.0, end - start.0, format!("aivm::jit::function_{f}"))
+                    });
+
+            codegen::perf_map::append(
+                [entry]
+                    .into_iter()
+                    .chain(functions)
+                    .map(|(start, size, name)| (code.ptr(start), size, name)),
+            );
+        }
 
         Runner {
-            memory_size,
-            output_size,
-            input_size,
-            code,
+            layout: self.layout,
+            code: Arc::new(code),
+            function_offsets: function_offsets.into(),
+            source_map: source_map.into(),
+            instruction_count,
+            host: self.host.clone(),
+            host_function_count: self.host.len(),
+            profiling: self.profiling,
         }
     }
 }
 
 impl Jit {
+    /// The inline threshold of a generator created with [new](Self::new).
+    pub const DEFAULT_INLINE_THRESHOLD: u32 = 16;
+
     /// Create a new generator.
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Create a new generator that uses the given register allocator.
+    pub fn with_register_allocator(allocator: RegisterAllocator) -> Self {
+        Self {
+            allocator,
+            ..Self::default()
+        }
+    }
+
+    /// Set the maximum amount of instructions of a function that gets inlined into its callers.
+    ///
+    /// Only functions without branches are inlined. Inlining removes the overhead of the call,
+    /// which is significant for the many tiny functions in typical VM code. A threshold of 0
+    /// disables inlining.
+    pub fn set_inline_threshold(&mut self, instructions: u32) {
+        self.inline_threshold = instructions;
+    }
+
+    /// Set whether code compiled from now on counts the calls and time of every function, for
+    /// [Runner::step_with_profile]. The default is `false`.
+    ///
+    /// The time is measured by reading the timestamp counter around every call, which slows
+    /// down calls considerably. Inlined functions are still measured.
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profiling = profiling;
+    }
+
+    /// Statistics about the code generated by the last compilation.
+    ///
+    /// The code size of a function includes the moves on the edges between its blocks, and
+    /// both the copy with and without fuel checks. The entry point that calls function 0 is not
+    /// part of any function.
+    pub fn report(&self) -> &CompileReport {
+        &self.report
+    }
 }
 
+/// Runs the machine code generated by the [Jit].
+///
+/// Cloning a runner is cheap, the clones share the machine code.
+#[derive(Clone)]
 pub struct Runner {
-    memory_size: u32,
-    output_size: u32,
-    input_size: u32,
-    code: ExecutableBuffer,
+    layout: MemoryLayout,
+    code: Arc<code::ExecutableCode>,
+    function_offsets: Arc<[AssemblyOffset]>,
+    source_map: Arc<[SourceMapEntry]>,
+    instruction_count: usize,
+    host: HostFunctions,
+    host_function_count: u32,
+    profiling: bool,
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_offsets.len() as u32
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    /// The size of the machine code, including the entry point.
+    fn code_size(&self) -> Option<usize> {
+        Some(self.code.len())
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+/// The machine code that was generated for a VM instruction, see
+/// [source_map](Runner::source_map).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapEntry {
+    /// The index of the instruction in the compiled code.
+    pub instruction: usize,
+    /// The range of the machine code, as offsets from the start of the generated code.
+    ///
+    /// It includes the moves of variables between registers and the stack that are needed
+    /// to execute the instruction.
+    pub code: Range<usize>,
+}
+
+impl Runner {
+    /// The machine code generated for every VM instruction, in the order of the machine code.
+    ///
+    /// Instructions that were optimized out, such as instructions whose result is never used,
+    /// have no entry. Every function is generated twice, with and without fuel checks, the map
+    /// covers the copy without them that [step](crate::Runner::step) runs.
+    pub fn source_map(&self) -> &[SourceMapEntry] {
+        &self.source_map
+    }
+
+    /// Set the functions that host call instructions call, replacing the ones the code was
+    /// compiled with. Host functions are not saved by [to_bytes](Self::to_bytes), so loaded
+    /// code that calls the host needs them to be set again.
+    ///
+    /// # Panics
+    /// If there are fewer functions than the code was compiled with.
+    pub fn set_host_functions(&mut self, host: HostFunctions) {
+        assert!(host.len() >= self.host_function_count);
+        self.host = host;
+    }
+
+    /// Disassemble the generated machine code, with a label at the start of every function.
+    ///
+    /// Addresses are offsets from the start of the code, which begins with the entry point
+    /// that calls function 0.
+    #[cfg(feature = "jit-disasm")]
+    pub fn dump_code(&self) -> String {
+        use iced_x86::{Decoder, DecoderOptions, Formatter, IntelFormatter};
+        use std::fmt::Write;
+
+        let mut decoder = Decoder::with_ip(64, &self.code, 0, DecoderOptions::NONE);
+        let mut formatter = IntelFormatter::new();
+        let mut functions = self.function_offsets.iter().enumerate().peekable();
+        let mut sources = self.source_map.iter().peekable();
+
+        let mut dump = String::from("entry:\n");
+        let mut asm = String::new();
+        for inst in &mut decoder {
+            while let Some((f, _)) = functions.next_if(|(_, offset)| offset.0 as u64 <= inst.ip()) {
+                writeln!(dump, "\nfunction {f}:").unwrap();
+            }
+            while let Some(entry) = sources.next_if(|e| e.code.start as u64 <= inst.ip()) {
+                writeln!(dump, "    ; instruction {}", entry.instruction).unwrap();
+            }
+
+            asm.clear();
+            formatter.format(&inst, &mut asm);
+            writeln!(dump, "{:8x}: {asm}", inst.ip()).unwrap();
+        }
+
+        dump
+    }
+
+    /// Like [step](crate::Runner::step), but also add the calls and time of every function to
+    /// `profile`.
+    ///
+    /// The timestamp ticks the code counts are converted to time using the duration of the
+    /// whole step. The time of the main function includes the overhead of entering the code.
+    ///
+    /// # Panics
+    /// If the code was compiled without [profiling](Jit::set_profiling).
+    pub fn step_with_profile(&self, memory: &mut [i64], profile: &mut Profile) {
+        assert!(self.profiling, "the code was compiled without profiling");
+
+        let mut counters = vec![[0; 2]; self.function_offsets.len()];
+        let mut context =
+            FuelContext::bounded(u64::MAX).with_host_functions(&self.host, self.layout);
+        context.profile = counters.as_mut_ptr();
+
+        let start = Instant::now();
+        let start_ticks = Target::timestamp();
+        self.run(memory, &mut context, false);
+        let ticks = Target::timestamp().wrapping_sub(start_ticks);
+        let elapsed = start.elapsed();
+
+        // The entry point calls the main function without profiling instructions
+        counters[0] = [1, ticks];
+        let seconds_per_tick = elapsed.as_secs_f64() / ticks.max(1) as f64;
+        let functions = profile.functions_mut(counters.len());
+        for (function, [calls, ticks]) in functions.iter_mut().zip(counters) {
+            function.calls += calls;
+            function.time += Duration::from_secs_f64(ticks as f64 * seconds_per_tick);
+        }
+    }
+
+    /// Run the entry point, returning false if it ran out of fuel. Without `metered`, the
+    /// copy of the code without fuel checks runs, which never runs out.
+    fn run(&self, memory: &mut [i64], context: &mut FuelContext, metered: bool) -> bool {
+        self.run_batch(&mut [memory], context, metered)
+    }
+
+    /// Like [run](Self::run), on every memory in turn.
+    fn run_batch(
+        &self,
+        memories: &mut [&mut [i64]],
+        context: &mut FuelContext,
+        metered: bool,
+    ) -> bool {
+        // Code compiled with profiling always needs somewhere to count
+        let mut counters = vec![];
+        if self.profiling && context.profile.is_null() {
+            counters.resize(self.function_offsets.len(), [0; 2]);
+            context.profile = counters.as_mut_ptr();
+        }
+
+        let pointers: Vec<_> = memories
+            .iter_mut()
+            .map(|memory| {
+                assert!(self.layout.size() as usize <= memory.len());
+                self.layout.init_step(memory);
+                memory.as_mut_ptr()
+            })
+            .collect();
+
+        let entry: extern "C" fn(*const *mut i64, *const *mut i64, *mut FuelContext, u64) -> u64 =
+            unsafe { transmute(self.code.ptr(0)) };
+        let range = pointers.as_ptr_range();
+        entry(range.start, range.end, context, metered.into()) == 0
+    }
 }
 
 impl crate::Runner for Runner {
     fn step(&self, memory: &mut [i64]) {
-        assert!((self.memory_size + self.input_size + self.output_size) as usize <= memory.len());
+        let mut context =
+            FuelContext::bounded(u64::MAX).with_host_functions(&self.host, self.layout);
+        self.run(memory, &mut context, false);
+    }
+
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        let mut context = FuelContext::bounded(fuel).with_host_functions(&self.host, self.layout);
+        if !self.run(memory, &mut context, true) {
+            return StepResult::OutOfFuel;
+        }
+
+        StepResult::Completed {
+            fuel_used: fuel - context.remaining,
+        }
+    }
+
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        fuel::restore_on_abort(self.layout, memory, |memory| {
+            let mut context =
+                FuelContext::until(deadline).with_host_functions(&self.host, self.layout);
+            self.run(memory, &mut context, true)
+        })
+    }
+
+    fn step_batch(&self, memories: &mut [&mut [i64]]) {
+        let mut context =
+            FuelContext::bounded(u64::MAX).with_host_functions(&self.host, self.layout);
+        self.run_batch(memories, &mut context, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{differential, random_code, random_memory, Backend, Xorshift},
+        Compiler, DefaultFrequencies, InstructionFrequencies, MemoryBank, Runner as _,
+    };
+
+    type F = DefaultFrequencies;
+
+    /// Compare the Jit to the interpreter on random programs, where every instruction is passed
+    /// through `map` first.
+    fn check_random_programs(gen: Jit, programs: usize, map: impl Fn(u64) -> u64) {
+        // Random programs use many registers at once, which stresses the register allocator.
+        let mut rng = Xorshift::default();
+
+        let mut jit = Compiler::new(gen);
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::new("jit", &mut jit),
+        ];
+        let layout = MemoryLayout::new(8, 4, 4);
+        for _ in 0..programs {
+            let code: Vec<_> = random_code(&mut rng, 256).into_iter().map(&map).collect();
+            let memory = random_memory(&mut rng, 16);
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_without_branches() {
+        // The branch instructions come right before the memory instructions.
+        let mem_kinds = u32::from(F::MEM_LOAD + F::INPUT_LOAD + F::MEM_STORE + F::OUTPUT_STORE);
+        let branch_kinds = u32::from(F::BRANCH_CMP + F::BRANCH_ZERO + F::BRANCH_NON_ZERO);
+        let branches_end = (1 << 16) - mem_kinds;
+        let branches = branches_end - branch_kinds..branches_end;
+
+        check_random_programs(Jit::new(), 500, |instruction| {
+            if branches.contains(&u32::from(instruction as u16)) {
+                // A branch offset of 0 turns it into a nop
+                instruction & u64::from(u32::MAX)
+            } else {
+                instruction
+            }
+        });
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        // Variables change location between blocks when the allocator runs out of registers.
+        check_random_programs(Jit::new(), 500, |instruction| instruction);
+    }
+
+    #[test]
+    fn matches_interpreter_without_inlining() {
+        let mut gen = Jit::new();
+        gen.set_inline_threshold(0);
+        check_random_programs(gen, 100, |instruction| instruction);
+    }
+
+    #[test]
+    fn profile_matches_interpreter() {
+        let mut gen = Jit::new();
+        gen.set_profiling(true);
+        check_random_programs(gen, 100, |instruction| instruction);
+
+        let mut rng = Xorshift::default();
+
+        let mut gen = Jit::new();
+        gen.set_profiling(true);
+        let mut jit = Compiler::new(gen);
+        let mut interpreter = Compiler::new(Interpreter::new());
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+
+            let mut expected = Profile::new();
+            let mut expected_memory = memory.clone();
+            interpreter
+                .compile(&code, 3, 8, 4, 4)
+                .step_with_profile(&mut expected_memory, &mut expected);
+            let mut actual = Profile::new();
+            let mut actual_memory = memory;
+            jit.compile(&code, 3, 8, 4, 4)
+                .step_with_profile(&mut actual_memory, &mut actual);
+
+            assert_eq!(actual_memory, expected_memory);
+            let calls = |profile: &Profile| -> Vec<_> {
+                profile.functions().iter().map(|f| f.calls).collect()
+            };
+            assert_eq!(calls(&actual), calls(&expected), "code: {code:x?}");
+        }
+    }
+
+    #[test]
+    fn fuel_matches_interpreter() {
+        let mut rng = Xorshift::default();
+
+        let mut jit = Compiler::new(Jit::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        // Without outputs and with calls in the lowest level, many instructions compile to
+        // nothing but still cost fuel
+        for (level, output_size) in [(3, 4), (0, 0)] {
+            let input_start = 8 + output_size as usize;
+            for _ in 0..100 {
+                let code = random_code(&mut rng, 256);
+                let memory = random_memory(&mut rng, input_start + 4);
+
+                let reference = interpreter.compile(&code, level, 8, output_size, 4);
+                let mut expected = memory.clone();
+                let result = reference.step_bounded(&mut expected, u64::MAX);
+                let StepResult::Completed { fuel_used } = result else {
+                    unreachable!()
+                };
+
+                let runner = jit.compile(&code, level, 8, output_size, 4);
+                let mut actual = memory.clone();
+                assert_eq!(runner.step_bounded(&mut actual, fuel_used), result);
+                assert_eq!(actual, expected);
+                if fuel_used > 0 {
+                    let mut actual = memory.clone();
+                    assert_eq!(
+                        runner.step_bounded(&mut actual, fuel_used - 1),
+                        StepResult::OutOfFuel
+                    );
+                    // The input is never modified, not even when running out of fuel
+                    assert_eq!(actual[input_start..], memory[input_start..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clones_share_code() {
+        let mut rng = Xorshift::default();
+
+        let code = random_code(&mut rng, 256);
+        let memory = random_memory(&mut rng, 16);
 
-        let output_range = memory.len() - self.output_size as usize..;
-        memory[output_range].fill(0);
+        let mut expected = memory.clone();
+        Compiler::new(Interpreter::new())
+            .compile(&code, 3, 8, 4, 4)
+            .step(&mut expected);
+
+        // The clones keep the code alive after the original and its compiler are gone
+        let clones = {
+            let runner = Compiler::new(Jit::new()).compile(&code, 3, 8, 4, 4);
+            vec![runner.clone(), runner.clone()]
+        };
+        std::thread::scope(|scope| {
+            for runner in clones {
+                let mut actual = memory.clone();
+                let expected = &expected;
+                scope.spawn(move || {
+                    runner.step(&mut actual);
+                    assert_eq!(&actual, expected);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn batch_matches_step() {
+        let mut rng = Xorshift::default();
+
+        let mut jit = Compiler::new(Jit::new());
+        let mut interpreter = Compiler::new(Interpreter::new());
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memories: Vec<Vec<_>> = (0..8).map(|_| random_memory(&mut rng, 16)).collect();
+
+            let reference = interpreter.compile(&code, 3, 8, 4, 4);
+            let mut expected = memories.clone();
+            for memory in &mut expected {
+                reference.step(memory);
+            }
+
+            let mut interpreted = memories.clone();
+            let mut batch: Vec<_> = interpreted.iter_mut().map(|m| &mut m[..]).collect();
+            reference.step_batch(&mut batch);
+            assert_eq!(interpreted, expected);
+
+            let runner = jit.compile(&code, 3, 8, 4, 4);
+            let mut actual = memories;
+            let mut batch: Vec<_> = actual.iter_mut().map(|m| &mut m[..]).collect();
+            runner.step_batch(&mut batch);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[cfg(feature = "jit-disasm")]
+    #[test]
+    fn dump_code() {
+        // A call in the entry point, and a second function with an addition
+        let code = [u64::from(F::END_FUNC), 0, u64::from(F::END_FUNC + F::CALL)];
+        let mut gen = Jit::new();
+        gen.set_inline_threshold(0);
+        let dump = Compiler::new(gen).compile(&code, 1, 0, 0, 0).dump_code();
+
+        assert!(dump.starts_with("entry:\n"));
+        assert!(dump.contains("\nfunction 0:\n"));
+        assert!(dump.contains("\nfunction 1:\n"));
+        assert!(dump.contains("call"));
+        assert!(dump.contains("ret"));
+    }
+
+    #[cfg(feature = "jit-disasm")]
+    #[test]
+    fn zero_only_unassigned_variables() {
+        // Copy the first input to the first output, and add a variable that is never assigned
+        let load = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE + F::INPUT_LOAD);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let code = [
+            load,
+            store,
+            add | 1 << 16 | 2 << 22,
+            store | 1 << 16 | 1 << 32,
+        ];
+
+        let dump = Compiler::new(Jit::new())
+            .compile(&code, 1, 0, 2, 1)
+            .dump_code();
+        // The entry point clears its return value with a xor too, and the function is emitted
+        // once without and once with fuel checks
+        let function = dump.split("function 0:").nth(1).unwrap();
+        let (unmetered, metered) = function.split_once("ret").unwrap();
+        assert_eq!(unmetered.matches("xor").count(), 1, "{dump}");
+        assert_eq!(metered.matches("xor").count(), 1, "{dump}");
+    }
+
+    #[cfg(feature = "jit-disasm")]
+    #[test]
+    fn fuel_checks_only_when_metered() {
+        // A single block with an addition that stores its result
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let dump = Compiler::new(Jit::new())
+            .compile(&[add, store], 1, 0, 1, 0)
+            .dump_code();
+
+        // The fuel context is only accessed by the fuel check of the block
+        let function = dump.split("function 0:").nth(1).unwrap();
+        let (unmetered, metered) = function.split_once("ret").unwrap();
+        assert!(!unmetered.contains("[rbx]"), "{dump}");
+        assert_eq!(metered.matches("sub qword ptr [rbx]").count(), 1, "{dump}");
+    }
+
+    #[cfg(feature = "jit-disasm")]
+    #[test]
+    fn fuse_loads() {
+        // Subtract the first input from a variable and store the result in the first output
+        let load = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE + F::INPUT_LOAD);
+        let sub = u64::from(F::END_FUNC + F::CALL + F::INT_ADD);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [load, sub | 1 << 16 | 1 << 22, store | 1 << 16];
+
+        let dump = Compiler::new(Jit::new())
+            .compile(&code, 1, 0, 1, 1)
+            .dump_code();
+        // The input is only read by the subtraction, in both copies of the function
+        let reads: Vec<_> = dump.lines().filter(|l| l.contains("[rdi+8]")).collect();
+        assert!(
+            reads.len() == 2 && reads.iter().all(|l| l.contains("sub")),
+            "{dump}"
+        );
+    }
+
+    #[cfg(feature = "perf-map")]
+    #[test]
+    fn perf_map() {
+        let code = [u64::from(F::END_FUNC), 0, u64::from(F::END_FUNC + F::CALL)];
+        Compiler::new(Jit::new()).compile(&code, 1, 0, 0, 0);
+
+        let map = std::fs::read_to_string(format!("/tmp/perf-{}.map", std::process::id())).unwrap();
+        for name in ["entry", "function_0", "function_1"] {
+            assert!(map
+                .lines()
+                .any(|line| line.ends_with(&format!(" aivm::jit::{name}"))));
+        }
+    }
+
+    #[test]
+    fn source_map() {
+        // An addition that stores its result, a dead addition and a call
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [add, store, add, u64::from(F::END_FUNC), 0, add];
+        let mut gen = Jit::new();
+        gen.set_inline_threshold(0);
+        let runner = Compiler::new(gen).compile(&code, 1, 0, 1, 0);
+
+        let map = runner.source_map();
+        let instructions: Vec<_> = map.iter().map(|e| e.instruction).collect();
+        assert_eq!(instructions, [0, 1, 3]);
+        assert!(map.windows(2).all(|w| w[0].code.end <= w[1].code.start));
+        assert!(map.iter().all(|e| !e.code.is_empty()));
+    }
+
+    #[test]
+    fn inline_calls() {
+        // A call to a function that stores an addition
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [u64::from(F::END_FUNC), 0, add, store];
+        let runner = Compiler::new(Jit::new()).compile(&code, 1, 0, 1, 0);
+
+        // The call is replaced by the body of the callee, which is still emitted on its own
+        let instructions: Vec<_> = runner.source_map().iter().map(|e| e.instruction).collect();
+        assert_eq!(instructions, [2, 3, 2, 3]);
+    }
+
+    #[test]
+    fn report() {
+        // A call to a function that stores an addition
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [u64::from(F::END_FUNC), 0, add, store];
+        let mut gen = Jit::new();
+        gen.set_inline_threshold(0);
+        let mut compiler = Compiler::new(gen);
+        compiler.compile(&code, 1, 0, 1, 0);
+
+        let report = compiler.generator().report();
+        assert_eq!(report.functions.len(), 2);
+        for func in &report.functions {
+            assert!(func.code_size > 0);
+            assert_eq!(func.spills, Some(0));
+            assert_eq!(func.stack_size, 0);
+        }
+        // The callee needs a register for the sum
+        assert_ne!(report.functions[1].used_regs_mask, Some(0));
+    }
+
+    #[test]
+    fn more_than_64_variables() {
+        use crate::codegen::private::{CodeGeneratorImpl, Emitter};
+        use std::num::NonZeroU32;
+
+        // The VM only has 64 registers, so emit the instructions directly. The names 6 and 70
+        // must not be confused with each other.
+        let mut gen = Jit::new();
+        gen.begin(NonZeroU32::MIN, MemoryLayout::new(0, 1, 2));
+        let mut emitter = gen.begin_function(0);
+        emitter.emit_mem_load(70, MemoryBank::Input, 0);
+        emitter.emit_mem_load(6, MemoryBank::Input, 1);
+        emitter.emit_int_add(255, 70, 6);
+        emitter.emit_mem_store(MemoryBank::Output, 0, 255);
+        emitter.finalize();
+
+        let mut memory = [0, 3, 4];
+        gen.finish().step(&mut memory);
+        assert_eq!(memory, [7, 3, 4]);
+    }
+
+    #[test]
+    fn scratch_registers_keep_values() {
+        use crate::codegen::private::{CodeGeneratorImpl, Emitter};
+        use std::num::NonZeroU32;
+
+        // More values are live than there are registers, so rax and rdx hold some of them while
+        // instructions that use them as scratch registers execute.
+        fn compile<G: CodeGeneratorImpl>(gen: &mut G) -> G::Runner {
+            gen.begin(NonZeroU32::new(2).unwrap(), MemoryLayout::new(14, 18, 0));
+            let mut emitter = gen.begin_function(0);
+            for i in 0..14 {
+                emitter.emit_mem_load(i, MemoryBank::Memory, i.into());
+            }
+            emitter.emit_int_mul(20, 0, 1);
+            emitter.emit_int_mul_high(21, 2, 3);
+            emitter.emit_int_mul_high_unsigned(22, 4, 5);
+            emitter.emit_bit_reverse(23, 6);
+            emitter.emit_call(1);
+            for (i, var) in (0..14).chain(20..24).enumerate() {
+                emitter.emit_mem_store(MemoryBank::Output, i as u32, var);
+            }
+            emitter.finalize();
+            let mut emitter = gen.begin_function(1);
+            emitter.emit_nop();
+            emitter.finalize();
+            gen.finish()
+        }
+
+        let memory: Vec<_> = (1..=32)
+            .map(|i: i64| i.wrapping_mul(0x1234_5678_9ABC_DEF1))
+            .collect();
+        let mut expected = memory.clone();
+        compile(&mut Interpreter::new()).step(&mut expected);
+
+        let mut gen = Jit::new();
+        gen.set_inline_threshold(0);
+        gen.set_profiling(true);
+        let runner = compile(&mut gen);
+        // rax has hardware encoding 0 and rdx 2
+        let used = gen.report().functions[0].used_regs_mask.unwrap();
+        assert_eq!(used & 0b101, 0b101);
+
+        let mut actual = memory;
+        runner.step(&mut actual);
+        assert_eq!(actual, expected);
+    }
 
-        let entry: extern "sysv64" fn(*mut i64) =
-            unsafe { transmute(self.code.ptr(AssemblyOffset(0))) };
-        entry(memory.as_mut_ptr());
+    #[test]
+    fn graph_coloring_matches_interpreter() {
+        // Coloring is a lot slower to compile, especially without optimizations
+        let gen = Jit::with_register_allocator(RegisterAllocator::GraphColoring);
+        check_random_programs(gen, 100, |instruction| instruction);
     }
 }
@@ -0,0 +1,272 @@
+use super::{
+    arch::{Target, TargetInterface},
+    code::ExecutableCode,
+    Runner, SourceMapEntry,
+};
+use crate::{HostFunctions, MemoryLayout, OutputInit};
+
+use dynasmrt::AssemblyOffset;
+
+use std::{fmt, io, sync::Arc};
+
+const MAGIC: &[u8; 8] = b"AIVMJIT\0";
+/// Saved code is tied to the version of the crate that generated it, because the generated
+/// functions do not follow a stable calling convention.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The error returned when saved [Jit](super::Jit) code can not be loaded.
+#[derive(Debug)]
+pub enum LoadError {
+    /// The bytes were not produced by [Runner::to_bytes], or they are truncated.
+    Malformed,
+    /// The code was saved by a different version of this crate.
+    IncompatibleVersion {
+        /// The version that saved the code.
+        found: String,
+    },
+    /// The code was generated for a host with a different instruction set or calling convention.
+    IncompatibleIsa {
+        /// The fingerprint of the host that generated the code.
+        found: String,
+        /// The fingerprint of the current host.
+        host: String,
+    },
+    /// The memory for the code could not be made executable.
+    Io(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed jit code"),
+            Self::IncompatibleVersion { found } => {
+                write!(f, "jit code saved by version {found}, expected {VERSION}")
+            }
+            Self::IncompatibleIsa { found, host } => {
+                write!(f, "jit code generated for {found}, host is {host}")
+            }
+            Self::Io(e) => write!(f, "failed to map jit code: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Runner {
+    /// Serialize the generated machine code, so it can be loaded with
+    /// [from_bytes](Self::from_bytes) without compiling the VM code again.
+    ///
+    /// The generated code does not depend on the address it is loaded at. The bytes include a
+    /// fingerprint of the host, so they are only accepted by a host with the same instruction
+    /// set and calling convention, running the same version of this crate. Host functions are
+    /// not saved, see [set_host_functions](Self::set_host_functions).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        write_str(&mut bytes, VERSION);
+        write_str(&mut bytes, &Target::isa_fingerprint());
+
+        for size in [
+            self.layout.memory_size,
+            self.layout.output_size,
+            self.layout.input_size,
+            self.layout.const_size,
+            self.layout.scratch_size,
+        ] {
+            write_u64(&mut bytes, size.into());
+        }
+        let (kind, value) = match self.layout.output_init {
+            OutputInit::Clear => (0, 0),
+            OutputInit::Keep => (1, 0),
+            OutputInit::Fill(value) => (2, value),
+        };
+        write_u64(&mut bytes, kind);
+        write_u64(&mut bytes, value as u64);
+        write_u64(&mut bytes, self.host_function_count.into());
+        write_u64(&mut bytes, self.profiling.into());
+
+        write_u64(&mut bytes, self.function_offsets.len() as u64);
+        for offset in self.function_offsets.iter() {
+            write_u64(&mut bytes, offset.0 as u64);
+        }
+        write_u64(&mut bytes, self.instruction_count as u64);
+
+        write_u64(&mut bytes, self.source_map.len() as u64);
+        for entry in self.source_map.iter() {
+            write_u64(&mut bytes, entry.instruction as u64);
+            write_u64(&mut bytes, entry.code.start as u64);
+            write_u64(&mut bytes, entry.code.end as u64);
+        }
+
+        write_u64(&mut bytes, self.code.len() as u64);
+        bytes.extend_from_slice(&self.code);
+
+        bytes
+    }
+
+    /// Load machine code that was serialized with [to_bytes](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(LoadError::Malformed);
+        }
+
+        let version = reader.str()?;
+        if version != VERSION {
+            return Err(LoadError::IncompatibleVersion {
+                found: version.into(),
+            });
+        }
+        let fingerprint = reader.str()?;
+        let host = Target::isa_fingerprint();
+        if fingerprint != host {
+            return Err(LoadError::IncompatibleIsa {
+                found: fingerprint.into(),
+                host,
+            });
+        }
+
+        let layout = MemoryLayout::new(reader.u32()?, reader.u32()?, reader.u32()?)
+            .with_const_size(reader.u32()?)
+            .with_scratch_size(reader.u32()?);
+        let output_init = match (reader.u64()?, reader.u64()?) {
+            (0, _) => OutputInit::Clear,
+            (1, _) => OutputInit::Keep,
+            (2, value) => OutputInit::Fill(value as i64),
+            _ => return Err(LoadError::Malformed),
+        };
+        let layout = layout.with_output_init(output_init);
+        let host_function_count = reader.u32()?;
+        let profiling = match reader.u64()? {
+            0 => false,
+            1 => true,
+            _ => return Err(LoadError::Malformed),
+        };
+
+        let function_count = reader.usize()?;
+        let function_offsets = (0..function_count)
+            .map(|_| reader.usize().map(AssemblyOffset))
+            .collect::<Result<Vec<_>, _>>()?;
+        let instruction_count = reader.usize()?;
+
+        let entry_count = reader.usize()?;
+        let source_map = (0..entry_count)
+            .map(|_| {
+                Ok(SourceMapEntry {
+                    instruction: reader.usize()?,
+                    code: reader.usize()?..reader.usize()?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let code_len = reader.usize()?;
+        let code = reader.take(code_len)?;
+        if !reader.0.is_empty()
+            || function_offsets.is_empty()
+            || function_offsets.iter().any(|o| o.0 >= code.len())
+        {
+            return Err(LoadError::Malformed);
+        }
+
+        let code = ExecutableCode::new(code).map_err(LoadError::Io)?;
+
+        Ok(Self {
+            layout,
+            code: Arc::new(code),
+            function_offsets: function_offsets.into(),
+            source_map: source_map.into(),
+            instruction_count,
+            host: HostFunctions::new(),
+            host_function_count,
+            profiling,
+        })
+    }
+}
+
+fn write_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, value: &str) {
+    write_u64(bytes, value.len() as u64);
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if len > self.0.len() {
+            return Err(LoadError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, LoadError> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        self.u64()?.try_into().map_err(|_| LoadError::Malformed)
+    }
+
+    fn usize(&mut self) -> Result<usize, LoadError> {
+        self.u64()?.try_into().map_err(|_| LoadError::Malformed)
+    }
+
+    fn str(&mut self) -> Result<&'a str, LoadError> {
+        let len = self.usize()?;
+        std::str::from_utf8(self.take(len)?).map_err(|_| LoadError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codegen::Jit, Compiler, DefaultFrequencies, InstructionFrequencies, Runner as _};
+
+    #[test]
+    fn round_trip() {
+        type F = DefaultFrequencies;
+        // Add the first two inputs and store the sum in the first output, in a called function
+        let add = u64::from(F::END_FUNC + F::CALL);
+        let load = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE + F::INPUT_LOAD);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [
+            u64::from(F::END_FUNC),
+            0,
+            load,
+            load | 1 << 16 | 1 << 32,
+            add | 2 << 16 | 1 << 32,
+            store | 2 << 16,
+        ];
+        let runner = Compiler::new(Jit::new()).compile(&code, 1, 0, 1, 2);
+
+        let bytes = runner.to_bytes();
+        let loaded = Runner::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.layout, runner.layout);
+        assert_eq!(loaded.source_map(), runner.source_map());
+
+        let mut expected = [0, 3, 4];
+        runner.step(&mut expected);
+        assert_eq!(expected, [7, 3, 4]);
+        let mut actual = [0, 3, 4];
+        loaded.step(&mut actual);
+        assert_eq!(actual, expected);
+
+        assert!(matches!(
+            Runner::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Malformed)
+        ));
+    }
+}
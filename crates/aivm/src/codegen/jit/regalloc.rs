@@ -1,6 +1,8 @@
 use super::{
     arch::{Target, TargetInterface},
-    ir::{BlockName, Function, InstructionKind, LiveRange, Var},
+    coloring,
+    ir::{Block, BlockName, Function, InstructionKind, LiveRange, Var},
+    RegisterAllocator,
 };
 
 use arrayvec::ArrayVec;
@@ -15,7 +17,7 @@ impl PhysicalVar {
 
     #[inline]
     fn new_register(r: u32) -> Self {
-        Self(r & 0x7FFFFFFF)
+        Self(r & 0x3FFFFFFF)
     }
 
     #[inline]
@@ -23,6 +25,12 @@ impl PhysicalVar {
         Self(slot | 0x80000000)
     }
 
+    /// A value in the VM memory at the given address, see [fused_loads].
+    #[inline]
+    fn new_memory(addr: u32) -> Self {
+        Self(addr | 0x40000000)
+    }
+
     #[inline]
     fn is_valid(self) -> bool {
         self != Self::INVALID
@@ -33,9 +41,19 @@ impl PhysicalVar {
         self.0 & 0x80000000 != 0
     }
 
+    #[inline]
+    pub fn is_memory(self) -> bool {
+        self.0 & 0xC0000000 == 0x40000000
+    }
+
+    #[inline]
+    pub fn is_register(self) -> bool {
+        self.0 & 0xC0000000 == 0
+    }
+
     #[inline]
     pub fn idx(self) -> u32 {
-        self.0 & 0x7FFFFFFF
+        self.0 & 0x3FFFFFFF
     }
 
     #[inline]
@@ -49,7 +67,13 @@ impl Debug for PhysicalVar {
         if !self.is_valid() {
             f.write_str("INVALID")
         } else {
-            let name = if self.is_stack() { "Stack" } else { "Reg" };
+            let name = if self.is_stack() {
+                "Stack"
+            } else if self.is_memory() {
+                "Memory"
+            } else {
+                "Reg"
+            };
             f.debug_tuple(name).field(&self.idx()).finish()
         }
     }
@@ -59,8 +83,16 @@ impl Debug for PhysicalVar {
 struct State {
     live_vars: HashMap<Var, PhysicalVar>,
     active_reg: [Option<LiveRange>; Target::REGISTER_COUNT],
-    active_stack: [Option<LiveRange>; 64 - Target::REGISTER_COUNT],
+    active_stack: [Option<LiveRange>; 64],
+    /// Variables of loads that are fused into their use, they stay in the VM memory.
+    active_memory: Vec<LiveRange>,
     stack_size: u32,
+    used_regs_mask: u64,
+    spills: u32,
+    /// Registers that can not be allocated for the current instruction.
+    blocked_regs_mask: u64,
+    /// The registers assigned by graph coloring, if it is used.
+    colors: Option<HashMap<Var, u32>>,
 }
 
 impl Default for State {
@@ -68,8 +100,13 @@ impl Default for State {
         Self {
             live_vars: HashMap::new(),
             active_reg: Default::default(),
-            active_stack: [None; 64 - Target::REGISTER_COUNT],
+            active_stack: [None; 64],
+            active_memory: vec![],
             stack_size: 0,
+            used_regs_mask: 0,
+            spills: 0,
+            blocked_regs_mask: 0,
+            colors: None,
         }
     }
 }
@@ -80,11 +117,17 @@ impl State {
             .active_reg
             .iter_mut()
             .chain(self.active_stack.iter_mut())
-            .filter(|a| a.map_or(false, |a| a.end == i))
+            .filter(|a| a.is_some_and(|a| a.end == i))
         {
             let range = a.take().unwrap();
             self.live_vars.remove(&range.var);
         }
+        self.active_memory.retain(|a| {
+            if a.end == i {
+                self.live_vars.remove(&a.var);
+            }
+            a.end != i
+        });
     }
 
     fn longest_active_reg(&self) -> Option<(u32, LiveRange)> {
@@ -92,6 +135,7 @@ impl State {
             .iter()
             .copied()
             .enumerate()
+            .filter(|(r, _)| self.blocked_regs_mask & (1 << r) == 0)
             .flat_map(|(r, a)| a.map(|a| (r as u32, a)))
             .max_by_key(|(_, a)| a.end)
     }
@@ -118,29 +162,115 @@ impl State {
     }
 
     fn alloc_reg(&mut self, range: LiveRange) -> Option<u32> {
-        if let Some(r) = self.active_reg.iter().position(Option::is_none) {
-            self.active_reg[r] = Some(range);
+        let blocked = self.blocked_regs_mask;
+        if let Some(r) = self
+            .active_reg
+            .iter()
+            .enumerate()
+            .position(|(r, a)| a.is_none() && blocked & (1 << r) == 0)
+        {
             let r = r as u32;
-            self.live_vars
-                .insert(range.var, PhysicalVar::new_register(r));
+            self.use_reg(r, range);
             Some(r)
         } else {
             None
         }
     }
 
+    /// Allocate the location that graph coloring assigned to the range, returning false if
+    /// there is none or it is not available.
+    fn alloc_colored(&mut self, range: LiveRange) -> bool {
+        let Some(colors) = &self.colors else {
+            return false;
+        };
+
+        match colors.get(&range.var).copied() {
+            Some(r) if self.is_reg_free(r) => self.use_reg(r, range),
+            // Taken by a variable that was reloaded from the stack
+            Some(_) => return false,
+            None => {
+                self.alloc_stack(range);
+            }
+        }
+
+        true
+    }
+
+    fn is_reg_free(&self, reg: u32) -> bool {
+        self.active_reg[reg as usize].is_none() && self.blocked_regs_mask & (1 << reg) == 0
+    }
+
+    /// Allocate a block param, in the preferred location if it is a free register.
+    fn alloc_param(&mut self, range: LiveRange, preferred: Option<PhysicalVar>) {
+        if self.alloc_colored(range) {
+            return;
+        }
+
+        match preferred {
+            Some(phys) if !phys.is_stack() && self.is_reg_free(phys.idx()) => {
+                self.use_reg(phys.idx(), range)
+            }
+            _ => {
+                if self.alloc_reg(range).is_none() {
+                    self.alloc_stack(range);
+                }
+            }
+        }
+    }
+
     fn use_reg(&mut self, reg: u32, range: LiveRange) {
         let target = &mut self.active_reg[reg as usize];
         debug_assert!(target.is_none());
+        debug_assert!(self.blocked_regs_mask & (1 << reg) == 0);
         self.live_vars
             .insert(range.var, PhysicalVar::new_register(reg));
         *target = Some(range);
+        self.used_regs_mask |= 1 << reg;
+    }
+
+    /// Prevent the given registers from being used by the current instruction, spilling the
+    /// variables that occupy them.
+    fn block_regs(&mut self, mask: u64, inst: &mut RegAllocInstruction) {
+        self.blocked_regs_mask = mask;
+        // The caller expects the registers to be preserved like any other register this
+        // function uses
+        self.used_regs_mask |= mask;
+        for r in 0..Target::REGISTER_COUNT as u32 {
+            if mask & (1 << r) != 0 && self.active_reg[r as usize].is_some() {
+                self.spill_reg(r, inst);
+            }
+        }
     }
 
     fn unspill(&mut self, stack_idx: u32, inst: &mut RegAllocInstruction) -> u32 {
         let range = self.active_stack[stack_idx as usize].unwrap();
+        let reg = self.alloc_operand_reg(range, inst);
+
+        self.stack_to_reg(reg, stack_idx, inst);
+        self.active_stack[stack_idx as usize] = None;
+
+        reg
+    }
 
-        let reg = if let Some(reg) = self.alloc_reg(range) {
+    /// Load a variable that stays in the VM memory into a register.
+    fn unfuse(&mut self, var: Var, addr: u32, inst: &mut RegAllocInstruction) -> u32 {
+        let a = self
+            .active_memory
+            .iter()
+            .position(|a| a.var == var)
+            .unwrap();
+        let range = self.active_memory.swap_remove(a);
+        let reg = self.alloc_operand_reg(range, inst);
+
+        inst.actions.push(RegAllocAction::MemoryToReg(reg, addr));
+
+        reg
+    }
+
+    /// Allocate a register for an operand of `inst`, spilling a variable that is not an operand
+    /// if there is no free register.
+    fn alloc_operand_reg(&mut self, range: LiveRange, inst: &mut RegAllocInstruction) -> u32 {
+        if let Some(reg) = self.alloc_reg(range) {
             reg
         } else {
             // Make sure we don't spill a register that's already being used in the current
@@ -160,12 +290,7 @@ impl State {
             self.spill_reg(reg, inst);
             self.use_reg(reg, range);
             reg
-        };
-
-        self.stack_to_reg(reg, stack_idx, inst);
-        self.active_stack[stack_idx as usize] = None;
-
-        reg
+        }
     }
 
     fn reg_to_stack(&mut self, stack_idx: u32, reg: u32, inst: &mut RegAllocInstruction) {
@@ -177,6 +302,7 @@ impl State {
         //     return;
         // }
 
+        self.spills += 1;
         inst.actions
             .push(RegAllocAction::RegToStack(stack_idx, reg));
     }
@@ -198,22 +324,41 @@ impl State {
 #[derive(Debug, Default)]
 pub struct RegAllocations {
     pub instructions: Vec<RegAllocInstruction>,
+    /// The edges where a branch is taken, referenced by [RegAllocAction::BranchExit].
+    pub edges: Vec<Edge>,
     pub used_regs_mask: u64,
     pub stack_size: u32,
+    /// The amount of moves from a register to the stack to free up the register.
+    pub spills: u32,
 }
 
 impl RegAllocations {
     /// `live_ranges` must be sorted in order of increasing start point
-    pub fn run(func: &mut Function, live_ranges: Vec<LiveRange>) {
-        let allocs = &mut func.reg_allocs;
+    pub fn run(func: &mut Function, live_ranges: Vec<LiveRange>, allocator: RegisterAllocator) {
+        let Function {
+            blocks,
+            reg_allocs: allocs,
+            ..
+        } = func;
         allocs.clear();
 
+        let mut state = State {
+            colors: match allocator {
+                RegisterAllocator::LinearScan => None,
+                RegisterAllocator::GraphColoring => Some(coloring::color(blocks, &live_ranges)),
+            },
+            ..State::default()
+        };
+        let fused_loads = fused_loads(blocks);
         let mut live_ranges = live_ranges.into_iter().peekable();
-        let mut state = State::default();
+        let mut new_ranges = vec![];
         let mut last_block = BlockName::INVALID;
+        // The locations of the variables when a branch is taken, by branch proxy block
+        let mut branch_states = HashMap::new();
+        // Actions of instructions that are not emitted are carried over to the next one
+        let mut actions = vec![];
 
-        'func_inst: for (i, (b, func_inst)) in func
-            .blocks
+        for (i, (b, func_inst)) in blocks
             .iter()
             .enumerate()
             .flat_map(|(b, block)| {
@@ -228,17 +373,84 @@ impl RegAllocations {
 
             let mut inst = RegAllocInstruction {
                 kind: func_inst.kind,
-                actions: vec![],
+                source: func_inst.source,
+                actions: std::mem::take(&mut actions),
                 defs: ArrayVec::new(),
                 uses: ArrayVec::new(),
             };
 
-            state.clean_dead_vars(i);
+            let clobbered_regs = Target::clobbered_regs(func_inst.kind);
+            state.blocked_regs_mask = clobbered_regs;
 
-            while let Some(new_range) = live_ranges.next_if(|r| r.start == i) {
-                if let Some(reg) = state.alloc_reg(new_range) {
-                    allocs.used_regs_mask |= 1 << reg;
-                } else {
+            new_ranges.clear();
+            new_ranges.extend(std::iter::from_fn(|| live_ranges.next_if(|r| r.start == i)));
+
+            if b != last_block {
+                debug_assert_eq!(b.0, last_block.0.wrapping_add(1));
+                let block = &blocks[b.0 as usize];
+
+                // Where the variables are at the end of the predecessor that falls through
+                let fall_through_vars = (!block.params.is_empty()).then(|| state.live_vars.clone());
+                state.clean_dead_vars(i);
+
+                // Prefer to put params where the value that falls through already is
+                new_ranges.retain(|&range| {
+                    let Some(p) = block.params.iter().position(|&v| v == range.var) else {
+                        return true;
+                    };
+                    let input = block.param_inputs[0][p];
+                    let preferred = fall_through_vars.as_ref().map(|vars| vars[&input]);
+                    state.alloc_param(range, preferred);
+                    false
+                });
+
+                if let Some(vars) = fall_through_vars {
+                    let moves = block
+                        .params
+                        .iter()
+                        .zip(&block.param_inputs[0])
+                        .filter_map(|(param, input)| {
+                            state.live_vars.get(param).map(|&to| (vars[input], to))
+                        })
+                        .collect();
+                    sequence_moves(moves, &mut inst.actions);
+                }
+                inst.actions.push(RegAllocAction::BlockStart(b));
+
+                // Move the variables to the locations they have in this block when a branch
+                // to it is taken
+                for (p, predecessor) in block.predecessors.iter().enumerate().skip(1) {
+                    let (edge, vars): (usize, HashMap<Var, PhysicalVar>) =
+                        branch_states.remove(predecessor).unwrap();
+                    let moves = state
+                        .live_vars
+                        .iter()
+                        .map(|(var, &to)| {
+                            let input = match block.params.iter().position(|v| v == var) {
+                                Some(param) => block.param_inputs[p][param],
+                                None => *var,
+                            };
+                            (vars[&input], to)
+                        })
+                        .collect();
+                    sequence_moves(moves, &mut allocs.edges[edge].moves);
+                }
+
+                last_block = b;
+            } else {
+                state.clean_dead_vars(i);
+            }
+
+            for &new_range in &new_ranges {
+                if let Some(&addr) = fused_loads.get(&new_range.var) {
+                    state
+                        .live_vars
+                        .insert(new_range.var, PhysicalVar::new_memory(addr));
+                    state.active_memory.push(new_range);
+                    continue;
+                }
+
+                if !state.alloc_colored(new_range) && state.alloc_reg(new_range).is_none() {
                     // Spill the variable with the longest remaining lifetime
                     let (r, active_range) = state.longest_active_reg().unwrap();
 
@@ -254,37 +466,72 @@ impl RegAllocations {
             // Coalesce split blocks and ignore jump instructions since they always jump
             // to the next block, or the block that the previous block's branch instruction
             // jumps to if the branch is taken.
+            let mut branch_proxy = None;
             match func_inst.kind {
-                InstructionKind::Jump => continue,
+                InstructionKind::Jump => {
+                    actions = inst.actions;
+                    continue;
+                }
+                // The value is loaded by the instruction that uses it
+                InstructionKind::MemLoad { .. }
+                    if func_inst.dst_iter().any(|v| fused_loads.contains_key(&v)) =>
+                {
+                    actions = inst.actions;
+                    continue;
+                }
                 InstructionKind::BranchCmp { .. }
                 | InstructionKind::BranchZero
                 | InstructionKind::BranchNonZero => {
-                    let proxy = func.blocks[b.0 as usize].branch_exit;
-                    let target = func.blocks[proxy.0 as usize].exit;
-                    inst.actions.push(RegAllocAction::BranchExit(target));
+                    let proxy = blocks[b.0 as usize].branch_exit;
+                    let target = blocks[proxy.0 as usize].exit;
+                    let edge = allocs.edges.len();
+                    allocs.edges.push(Edge {
+                        target,
+                        moves: vec![],
+                    });
+                    inst.actions.push(RegAllocAction::BranchExit(edge));
+                    branch_proxy = Some((proxy, edge));
                 }
                 _ => (),
             }
 
+            // A bit hacky, but if live_vars does not contain a referenced variable,
+            // that means this instruction is dead and we can discard it
+            if func_inst
+                .dst_iter()
+                .chain(func_inst.src_iter())
+                .any(|v| !state.live_vars.contains_key(&v))
+            {
+                actions = inst.actions;
+                continue;
+            }
+
+            state.block_regs(clobbered_regs, &mut inst);
+
             for (is_dst, virt) in func_inst
                 .dst_iter()
                 .map(|d| (true, d))
                 .chain(func_inst.src_iter().map(|s| (false, s)))
             {
-                // A bit hacky, but if live_vars does not contain a referenced variable,
-                // that means this instruction is dead and we can discard it
-                let mut phys = match state.live_vars.get(&virt) {
-                    Some(phys) => *phys,
-                    None => continue 'func_inst,
-                };
-
-                if phys.is_stack()
-                    && (!Target::supports_mem_operand(inst.kind)
-                        || inst.defs.iter().any(|v| v.is_stack())
-                        || inst.uses.iter().any(|v| v.is_stack()))
-                {
+                let mut phys = state.live_vars[&virt];
+                // Only one operand can be in memory
+                let needs_reg = !Target::supports_mem_operand(inst.kind)
+                    || inst.defs.iter().any(|v| !v.is_register())
+                    || inst.uses.iter().any(|v| !v.is_register());
+
+                if phys.is_memory() && needs_reg {
+                    phys = PhysicalVar::new_register(state.unfuse(virt, phys.idx(), &mut inst));
+                } else if phys.is_stack() && needs_reg {
                     let reg = state.unspill(phys.idx(), &mut inst);
+                    let stack_phys = phys;
                     phys = PhysicalVar::new_register(reg);
+
+                    // The variable might already be an operand of this instruction
+                    for v in inst.defs.iter_mut().chain(inst.uses.iter_mut()) {
+                        if *v == stack_phys {
+                            *v = phys;
+                        }
+                    }
                 }
 
                 if is_dst {
@@ -294,38 +541,138 @@ impl RegAllocations {
                 }
             }
 
-            if b != last_block {
-                let start = last_block.0.wrapping_add(1);
-                inst.actions
-                    .extend((start..=b.0).map(|b| RegAllocAction::BlockStart(BlockName(b))));
+            if let Some((proxy, edge)) = branch_proxy {
+                branch_states.insert(proxy, (edge, state.live_vars.clone()));
             }
-            last_block = b;
 
             allocs.instructions.push(inst);
         }
 
         allocs.stack_size = state.stack_size;
+        allocs.used_regs_mask = state.used_regs_mask;
+        allocs.spills = state.spills;
     }
 
     fn clear(&mut self) {
         self.instructions.clear();
+        self.edges.clear();
         self.stack_size = 0;
         self.used_regs_mask = 0;
+        self.spills = 0;
+    }
+}
+
+/// Find the loads whose value can be used as a memory operand by the only instruction that
+/// uses it, returning their address by variable.
+///
+/// The use has to be in the same block, without a call or a store to the same address in
+/// between.
+fn fused_loads(blocks: &[Block]) -> HashMap<Var, u32> {
+    let mut use_counts = HashMap::new();
+    for var in blocks.iter().flat_map(|block| {
+        block
+            .instructions
+            .iter()
+            .flat_map(|inst| inst.src_iter())
+            .chain(block.param_inputs.iter().flatten().copied())
+    }) {
+        *use_counts.entry(var).or_insert(0u32) += 1;
+    }
+
+    let mut fused = HashMap::new();
+    let mut pending = HashMap::new();
+    for block in blocks {
+        pending.clear();
+        for inst in &block.instructions {
+            for var in inst.src_iter() {
+                if let Some(addr) = pending.remove(&var) {
+                    if Target::supports_mem_operand(inst.kind) {
+                        fused.insert(var, addr);
+                    }
+                }
+            }
+
+            match inst.kind {
+                InstructionKind::MemLoad { addr } => {
+                    let var = inst.dst_iter().next().unwrap();
+                    if use_counts.get(&var) == Some(&1) {
+                        pending.insert(var, addr);
+                    }
+                }
+                InstructionKind::MemStore { addr } => pending.retain(|_, &mut a| a != addr),
+                InstructionKind::Call { .. } | InstructionKind::HostCall { .. } => pending.clear(),
+                _ => (),
+            }
+        }
+    }
+
+    fused
+}
+
+/// Order moves between locations so that no source is overwritten before it is read, the
+/// moves all happen at once. Cycles are broken by saving a value on the machine stack.
+fn sequence_moves(mut moves: Vec<(PhysicalVar, PhysicalVar)>, actions: &mut Vec<RegAllocAction>) {
+    moves.retain(|(from, to)| from != to);
+
+    let mut pushed = vec![];
+    loop {
+        while let Some(&to) = pushed.last() {
+            if moves.iter().any(|&(from, _)| from == to) {
+                break;
+            }
+            pushed.pop();
+            actions.push(RegAllocAction::Pop(to));
+        }
+
+        if moves.is_empty() {
+            break;
+        }
+
+        if let Some(m) = moves
+            .iter()
+            .position(|&(_, to)| !moves.iter().any(|&(from, _)| from == to))
+        {
+            let (from, to) = moves.swap_remove(m);
+            actions.push(RegAllocAction::Move(from, to));
+        } else {
+            let (from, to) = moves.swap_remove(0);
+            actions.push(RegAllocAction::Push(from));
+            pushed.push(to);
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct RegAllocInstruction {
     pub kind: InstructionKind,
+    /// See [Instruction::source](super::ir::Instruction::source).
+    pub source: u32,
     pub defs: ArrayVec<PhysicalVar, 1>,
     pub uses: ArrayVec<PhysicalVar, 3>,
     pub actions: Vec<RegAllocAction>,
 }
 
+/// A taken branch, with the moves needed to get the variables where the target block expects
+/// them.
 #[derive(Debug)]
+pub struct Edge {
+    pub target: BlockName,
+    pub moves: Vec<RegAllocAction>,
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum RegAllocAction {
     RegToStack(u32, u32),
     StackToReg(u32, u32),
+    /// Load a register from an address in the VM memory.
+    MemoryToReg(u32, u32),
     BlockStart(BlockName),
-    BranchExit(BlockName),
+    /// Index of the [Edge] the branch instruction takes.
+    BranchExit(usize),
+    Move(PhysicalVar, PhysicalVar),
+    /// Save a value on the machine stack, until it is restored by a matching [Pop].
+    ///
+    /// [Pop]: RegAllocAction::Pop
+    Push(PhysicalVar),
+    Pop(PhysicalVar),
 }
@@ -1,14 +1,67 @@
+mod bytecode;
+#[cfg(feature = "c-export")]
+mod c_export;
+mod coverage;
 #[cfg(feature = "cranelift")]
 mod cranelift;
+#[cfg(feature = "cuda")]
+mod cuda;
+#[cfg(feature = "ebpf")]
+mod ebpf;
+mod fuel;
+mod instruction;
 mod interpreter;
 #[cfg(feature = "jit")]
 mod jit;
+mod null;
+#[cfg(feature = "perf-map")]
+mod perf_map;
+mod profile;
+mod record;
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+mod report;
+#[cfg(feature = "rust-export")]
+mod rust_export;
+#[cfg(feature = "jit")]
+mod ssa;
+#[cfg(feature = "jit")]
+mod tiered;
+mod verify;
+#[cfg(feature = "wgpu")]
+mod wgpu;
 
+pub use self::bytecode::{Bytecode, LoadError as BytecodeLoadError, Program as BytecodeProgram};
+pub use self::coverage::Coverage;
 #[cfg(feature = "cranelift")]
-pub use self::cranelift::Cranelift;
-pub use interpreter::Interpreter;
+pub use self::cranelift::{Cranelift, OptLevel, UnsupportedTarget};
+#[cfg(feature = "cranelift-object")]
+pub use self::cranelift::{CraneliftObject, ObjectCode};
+pub use self::null::{FunctionStatistics, NullGen, Runner as NullRunner, Statistics};
+pub use self::profile::{FunctionProfile, Profile};
+pub use self::record::{Record, Recorded};
+pub use self::verify::{Runner as VerifyRunner, Verify};
+#[cfg(feature = "wgpu")]
+pub use self::wgpu::{NoDevice, Runner as WgpuRunner, Wgpu};
+#[cfg(feature = "c-export-cc")]
+pub use c_export::Runner as CRunner;
+#[cfg(feature = "c-export")]
+pub use c_export::{CExport, CSource, HostCallUnsupported};
+#[cfg(feature = "cuda")]
+pub use cuda::{Cuda, CudaError, Runner as CudaRunner};
+#[cfg(feature = "ebpf")]
+pub use ebpf::{Ebpf, EbpfProgram};
+pub use instruction::Instruction;
+pub use interpreter::{Interpreter, Runner as InterpreterRunner, StepReport};
+#[cfg(feature = "jit")]
+pub use jit::{
+    Jit, LoadError as JitLoadError, RegisterAllocator, Runner as JitRunner, SourceMapEntry,
+};
+#[cfg(any(feature = "jit", feature = "cranelift"))]
+pub use report::{CompileReport, FunctionReport};
+#[cfg(feature = "rust-export")]
+pub use rust_export::{RustExport, RustSource};
 #[cfg(feature = "jit")]
-pub use jit::Jit;
+pub use tiered::{Runner as TieredRunner, Tiered};
 
 /// A converter to translate VM instructions to a form that can be executed on the host platform.
 ///
@@ -18,27 +71,55 @@ pub trait CodeGenerator: private::CodeGeneratorImpl {}
 impl<T: private::CodeGeneratorImpl> CodeGenerator for T {}
 
 pub(crate) mod private {
-    use crate::{compile::CompareKind, Runner};
+    use crate::{compile::CompareKind, HostFunctions, MemoryBank, MemoryLayout};
 
-    use std::num::NonZeroU32;
+    use std::{num::NonZeroU32, sync::Arc};
 
     pub trait CodeGeneratorImpl {
-        type Runner: Runner + 'static;
+        type Runner: 'static;
         type Emitter<'a>: Emitter + 'a
         where
             Self: 'a;
 
-        fn begin(&mut self, function_count: NonZeroU32);
+        /// Called before `begin` with the functions that `host_call` instructions call. Code
+        /// generators that can not call the host panic when a host call is emitted instead.
+        fn set_host_functions(&mut self, _host: &HostFunctions) {}
+
+        /// Called before `begin` with the values of the `Const` bank. Loads from the bank are
+        /// emitted with an address relative to the start of the constants.
+        fn set_constants(&mut self, constants: &Arc<[i64]>);
+
+        /// Called before `begin` with whether the memory starts with a layout header, see
+        /// [MemoryLayout::write_header](crate::MemoryLayout::write_header). When it does, the
+        /// memory, output, input and scratch banks are accessed with the `header` emit methods
+        /// and the layout passed to `begin` only describes what the runner reports.
+        fn set_layout_header(&mut self, header: bool) {
+            assert!(
+                !header,
+                "{} does not support layout headers",
+                std::any::type_name::<Self>(),
+            );
+        }
+
+        fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout);
         fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_>;
-        fn finish(&mut self, memory_size: u32, output_size: u32, input_size: u32) -> Self::Runner;
+        fn finish(&mut self) -> Self::Runner;
     }
 
     pub trait Emitter {
-        fn prepare_emit(&mut self) {}
-        fn finalize(&mut self) {}
+        /// Called before every instruction, with the index of the instruction in the compiled
+        /// code.
+        fn prepare_emit(&mut self, _code_index: usize) {}
+        fn finalize(self)
+        where
+            Self: Sized,
+        {
+        }
 
         fn emit_call(&mut self, idx: u32);
         fn emit_nop(&mut self);
+        /// `id` is smaller than the amount of host functions.
+        fn emit_host_call(&mut self, id: u32);
 
         fn emit_int_add(&mut self, dst: u8, a: u8, b: u8);
         fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8);
@@ -68,15 +149,37 @@ pub(crate) mod private {
         fn emit_branch_zero(&mut self, src: u8, offset: u32);
         fn emit_branch_non_zero(&mut self, src: u8, offset: u32);
 
-        fn emit_mem_load(&mut self, dst: u8, addr: u32);
-        fn emit_mem_store(&mut self, addr: u32, src: u8);
+        /// `addr` is relative to the start of `bank`.
+        fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32);
+        /// `addr` is relative to the start of `bank`. Stores to a bank that is not writable must
+        /// not have any effect.
+        fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8);
+
+        /// Load from `bank` at `offset` modulo the size of the bank in the layout header, or do
+        /// nothing if the bank is empty. Only used by generators that support layout headers.
+        fn emit_header_mem_load(&mut self, _dst: u8, _bank: MemoryBank, _offset: u32) {
+            unreachable!("layout headers are not supported")
+        }
+        /// Store to `bank` like [emit_header_mem_load](Self::emit_header_mem_load) loads from
+        /// it. Stores to a bank that is not writable must not have any effect.
+        fn emit_header_mem_store(&mut self, _bank: MemoryBank, _offset: u32, _src: u8) {
+            unreachable!("layout headers are not supported")
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::{private::*, *};
-    use crate::{compile::CompareKind, Runner};
+    use crate::{
+        compile::CompareKind, DeadlineExceeded, HostFunctions, MemoryBank, MemoryLayout,
+        OutputInit, Runner,
+    };
+
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
 
     struct Harness<'a, G: CodeGeneratorImpl> {
         gen: G,
@@ -86,8 +189,18 @@ mod tests {
     }
 
     impl<'a, G: CodeGeneratorImpl> Harness<'a, G> {
-        fn new(mut gen: G, func_count: u32, mem: &'a mut [i64]) -> Self {
-            gen.begin(func_count.try_into().unwrap());
+        fn new(gen: G, func_count: u32, mem: &'a mut [i64]) -> Self {
+            let layout = MemoryLayout::new(mem.len() as u32, 0, 0);
+            Self::with_layout(gen, func_count, layout, mem)
+        }
+
+        fn with_layout(
+            mut gen: G,
+            func_count: u32,
+            layout: MemoryLayout,
+            mem: &'a mut [i64],
+        ) -> Self {
+            gen.begin(func_count.try_into().unwrap(), layout);
             Self {
                 gen,
                 next_func: 0,
@@ -96,11 +209,22 @@ mod tests {
             }
         }
 
-        fn run(mut self) {
-            let runner = self.gen.finish(self.mem.len() as u32, 0, 0);
+        fn run(mut self)
+        where
+            G::Runner: Runner,
+        {
+            let runner = self.gen.finish();
             runner.step(self.mem);
         }
 
+        fn run_until(mut self, deadline: Instant) -> Result<(), DeadlineExceeded>
+        where
+            G::Runner: Runner,
+        {
+            let runner = self.gen.finish();
+            runner.step_until(self.mem, deadline)
+        }
+
         fn func<F: FnOnce(&mut G::Emitter<'_>)>(mut self, f: F) -> Self {
             assert!(self.next_func < self.func_count);
             {
@@ -117,8 +241,9 @@ mod tests {
     macro_rules! insts {
         ($e:ident, $($inst:expr);*;) => {
             |$e| {
+                let mut code_indices = 0..;
                 $(
-                    $e.prepare_emit();
+                    $e.prepare_emit(code_indices.next().unwrap());
                     $inst;
                 )*
             }
@@ -135,26 +260,143 @@ mod tests {
                     let mut mem = [0x0DEADBEEDEADBEEF, 0];
                     Harness::new($gen, 1, &mut mem)
                         .func(insts! {e,
-                            e.emit_mem_load(0, 0);
-                            e.emit_mem_store(1, 0);
+                            e.emit_mem_load(0, MemoryBank::Memory, 0);
+                            e.emit_mem_store(MemoryBank::Memory, 1, 0);
                         })
                         .run();
 
                     assert_eq!(mem[1], 0x0DEADBEEDEADBEEF);
                 }
 
+                #[test]
+                fn mem_banks() {
+                    let mut mem = [3, -1, 7];
+                    Harness::with_layout($gen, 1, MemoryLayout::new(1, 1, 1), &mut mem)
+                        .func(insts! {e,
+                            e.emit_mem_load(0, MemoryBank::Input, 0);
+                            e.emit_mem_store(MemoryBank::Output, 0, 0);
+                            e.emit_mem_load(1, MemoryBank::Memory, 0);
+                            e.emit_mem_store(MemoryBank::Input, 0, 1);
+                            e.emit_mem_load(2, MemoryBank::Output, 0);
+                            e.emit_int_add(2, 2, 1);
+                            e.emit_mem_store(MemoryBank::Memory, 0, 2);
+                        })
+                        .run();
+
+                    assert_eq!(mem, [10, 7, 7]);
+                }
+
+                #[test]
+                fn host_call() {
+                    let mut host = HostFunctions::new();
+                    host.register(|_| unreachable!());
+                    let id = host.register(|memory| {
+                        assert_eq!(memory.len(), 4);
+                        memory[1] = memory[0] * 10;
+                    });
+                    let mut gen = $gen;
+                    gen.set_host_functions(&host);
+
+                    let mut mem = [0, 0, 0, 5, 8];
+                    Harness::with_layout(gen, 1, MemoryLayout::new(2, 1, 1), &mut mem)
+                        .func(insts! {e,
+                            e.emit_mem_load(0, MemoryBank::Input, 0);
+                            e.emit_mem_store(MemoryBank::Memory, 0, 0);
+                            e.emit_mem_load(3, MemoryBank::Memory, 1);
+                            e.emit_host_call(id);
+                            e.emit_mem_load(1, MemoryBank::Memory, 1);
+                            e.emit_int_add(2, 1, 0);
+                            e.emit_int_sub(2, 2, 3);
+                            e.emit_mem_store(MemoryBank::Output, 0, 2);
+                        })
+                        .run();
+
+                    // Registers and memory loaded before the call are not affected by it
+                    assert_eq!(mem, [5, 50, 55, 5, 8]);
+                }
+
+                #[test]
+                fn const_bank() {
+                    let mut gen = $gen;
+                    gen.set_constants(&Arc::from([7, -2, i64::MIN]));
+
+                    let layout = MemoryLayout::new(1, 2, 0).with_const_size(3);
+                    let mut mem = [0, 0, 0];
+                    Harness::with_layout(gen, 1, layout, &mut mem)
+                        .func(insts! {e,
+                            e.emit_mem_load(0, MemoryBank::Const, 2);
+                            e.emit_mem_store(MemoryBank::Output, 0, 0);
+                            e.emit_mem_load(1, MemoryBank::Const, 0);
+                            e.emit_mem_load(2, MemoryBank::Const, 1);
+                            e.emit_int_mul(1, 1, 2);
+                            e.emit_mem_store(MemoryBank::Memory, 0, 1);
+                            e.emit_mem_store(MemoryBank::Const, 0, 1);
+                            e.emit_mem_load(3, MemoryBank::Const, 0);
+                            e.emit_mem_store(MemoryBank::Output, 1, 3);
+                        })
+                        .run();
+
+                    assert_eq!(mem, [-14, i64::MIN, 7]);
+                }
+
+                #[test]
+                fn scratch_bank() {
+                    let layout = MemoryLayout::new(1, 1, 0).with_scratch_size(2);
+                    let mut mem = [5, 9, 7, 8];
+                    Harness::with_layout($gen, 1, layout, &mut mem)
+                        .func(insts! {e,
+                            e.emit_mem_load(0, MemoryBank::Scratch, 0);
+                            e.emit_mem_store(MemoryBank::Memory, 0, 0);
+                            e.emit_mem_load(1, MemoryBank::Scratch, 1);
+                            e.emit_int_inc(1);
+                            e.emit_mem_store(MemoryBank::Scratch, 1, 1);
+                            e.emit_mem_load(2, MemoryBank::Scratch, 1);
+                            e.emit_mem_store(MemoryBank::Output, 0, 2);
+                        })
+                        .run();
+
+                    assert_eq!(mem, [0, 1, 0, 1]);
+                }
+
+                #[test]
+                fn output_init() {
+                    fn test_output_init(output_init: OutputInit, expected: [i64; 3]) {
+                        let layout = MemoryLayout::new(1, 2, 0).with_output_init(output_init);
+                        let mut mem = [9, 5, 6];
+                        Harness::with_layout($gen, 1, layout, &mut mem)
+                            .func(insts! {e,
+                                e.emit_mem_load(0, MemoryBank::Output, 0);
+                                e.emit_int_inc(0);
+                                e.emit_mem_store(MemoryBank::Output, 0, 0);
+                                e.emit_mem_load(1, MemoryBank::Output, 1);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 1);
+                            })
+                            .run();
+
+                        assert_eq!(mem, expected, "{output_init:?}");
+                    }
+
+                    test_output_init(OutputInit::Clear, [0, 1, 0]);
+                    test_output_init(OutputInit::Keep, [6, 6, 6]);
+                    test_output_init(OutputInit::Fill(-4), [-4, -3, -4]);
+                    test_output_init(
+                        OutputInit::Fill(i64::MIN),
+                        [i64::MIN, i64::MIN + 1, i64::MIN],
+                    );
+                }
+
                 #[test]
                 fn int_mul_high() {
                     fn test_mul_high(a: i64, b: i64, result: i64) {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_mul_high(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_mul_high(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -179,12 +421,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_mul_high_unsigned(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_mul_high_unsigned(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -211,26 +453,68 @@ mod tests {
                             e.emit_call(1);
                         })
                         .func(insts! {e,
-                            e.emit_mem_load(0, 0);
-                            e.emit_mem_store(1, 0);
+                            e.emit_mem_load(0, MemoryBank::Memory, 0);
+                            e.emit_mem_store(MemoryBank::Memory, 1, 0);
                         })
                         .run();
 
                     assert_eq!(mem[1], 0x0DEADBEEDEADBEEF);
                 }
 
+                #[test]
+                fn deadline() {
+                    // Every function calls the next one twice, so the amount of executed
+                    // instructions doubles with every function
+                    fn run(
+                        function_count: u32,
+                        mem: &mut [i64],
+                        deadline: Instant,
+                    ) -> Result<(), DeadlineExceeded> {
+                        let layout = MemoryLayout::new(1, 1, 1);
+                        let mut harness = Harness::with_layout($gen, function_count, layout, mem);
+                        for idx in 0..function_count {
+                            harness = harness.func(|e| {
+                                e.prepare_emit(0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.prepare_emit(1);
+                                e.emit_int_inc(0);
+                                e.prepare_emit(2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
+                                e.prepare_emit(3);
+                                e.emit_mem_store(MemoryBank::Output, 0, 0);
+                                if idx + 1 < function_count {
+                                    e.prepare_emit(4);
+                                    e.emit_call(idx + 1);
+                                    e.prepare_emit(5);
+                                    e.emit_call(idx + 1);
+                                }
+                            });
+                        }
+                        harness.run_until(deadline)
+                    }
+
+                    let far = Instant::now() + Duration::from_secs(3600);
+                    let mut mem = [5, 7, 3];
+                    assert_eq!(run(16, &mut mem, far), Ok(()));
+                    assert_eq!(mem, [5 + 0xFFFF, 5 + 0xFFFF, 3]);
+
+                    let mut mem = [5, 7, 3];
+                    assert_eq!(run(48, &mut mem, Instant::now()), Err(DeadlineExceeded));
+                    assert_eq!(mem, [5, 0, 3]);
+                }
+
                 #[test]
                 fn int_add() {
                     fn test_add(a: i64, b: i64) {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_add(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_add(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -252,12 +536,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_sub(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_sub(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -279,12 +563,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_mul(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_mul(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -306,9 +590,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_int_neg(0, 0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -326,9 +610,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_int_abs(0, 0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -346,9 +630,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_int_inc(0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -367,9 +651,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_int_dec(0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -388,12 +672,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_min(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_min(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -415,12 +699,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_int_max(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_int_max(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -442,12 +726,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_bit_or(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_bit_or(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -467,12 +751,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_bit_and(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_bit_and(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -492,12 +776,12 @@ mod tests {
                         let mut mem = [a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
                                 e.emit_bit_and(2, 0, 1);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                                 e.emit_bit_and(2, 1, 0);
-                                e.emit_mem_store(1, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 1, 2);
                             })
                             .run();
 
@@ -517,9 +801,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_not(0, 0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -539,9 +823,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_shift_left(0, 0, amount);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -562,9 +846,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_shift_right(0, 0, amount);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -585,9 +869,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_rotate_left(0, 0, amount);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -607,9 +891,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_rotate_right(0, 0, amount);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -629,11 +913,11 @@ mod tests {
                         let mut mem = [mask, a, b];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
-                                e.emit_mem_load(1, 1);
-                                e.emit_mem_load(2, 2);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
+                                e.emit_mem_load(1, MemoryBank::Memory, 1);
+                                e.emit_mem_load(2, MemoryBank::Memory, 2);
                                 e.emit_bit_select(3, 0, 1, 2);
-                                e.emit_mem_store(0, 3);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 3);
                             })
                             .run();
 
@@ -655,9 +939,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_popcnt(0, 0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -680,9 +964,9 @@ mod tests {
                         let mut mem = [a];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 0);
+                                e.emit_mem_load(0, MemoryBank::Memory, 0);
                                 e.emit_bit_reverse(0, 0);
-                                e.emit_mem_store(0, 0);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 0);
                             })
                             .run();
 
@@ -705,11 +989,11 @@ mod tests {
                         let mut mem = [0, a, b, 0x0DEADBEEDEADBEEF];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 1);
-                                e.emit_mem_load(1, 2);
+                                e.emit_mem_load(0, MemoryBank::Memory, 1);
+                                e.emit_mem_load(1, MemoryBank::Memory, 2);
                                 e.emit_branch_cmp(0, 1, kind, 2);
-                                e.emit_mem_load(3, 3);
-                                e.emit_mem_store(0, 3);
+                                e.emit_mem_load(3, MemoryBank::Memory, 3);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 3);
                             })
                             .run();
 
@@ -748,10 +1032,10 @@ mod tests {
                         let mut mem = [0, a, 0x0DEADBEEDEADBEEF];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 1);
                                 e.emit_branch_zero(0, 2);
-                                e.emit_mem_load(2, 2);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_load(2, MemoryBank::Memory, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                             })
                             .run();
 
@@ -771,10 +1055,10 @@ mod tests {
                         let mut mem = [0, a, 0x0DEADBEEDEADBEEF];
                         Harness::new($gen, 1, &mut mem)
                             .func(insts! {e,
-                                e.emit_mem_load(0, 1);
+                                e.emit_mem_load(0, MemoryBank::Memory, 1);
                                 e.emit_branch_non_zero(0, 2);
-                                e.emit_mem_load(2, 2);
-                                e.emit_mem_store(0, 2);
+                                e.emit_mem_load(2, MemoryBank::Memory, 2);
+                                e.emit_mem_store(MemoryBank::Memory, 0, 2);
                             })
                             .run();
 
@@ -794,6 +1078,18 @@ mod tests {
     instruction_tests!(interpreter_inst, Interpreter::new());
     #[cfg(feature = "cranelift")]
     instruction_tests!(cranelift_inst, Cranelift::new());
+    #[cfg(feature = "cranelift")]
+    instruction_tests!(
+        cranelift_opt_inst,
+        Cranelift::with_opt_level(OptLevel::SpeedAndSize)
+    );
     #[cfg(feature = "jit")]
     instruction_tests!(jit_inst, Jit::new());
+    #[cfg(feature = "jit")]
+    instruction_tests!(
+        jit_coloring_inst,
+        Jit::with_register_allocator(RegisterAllocator::GraphColoring)
+    );
+    #[cfg(feature = "jit")]
+    instruction_tests!(tiered_inst, Tiered::with_jit_threshold(0));
 }
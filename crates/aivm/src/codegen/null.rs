@@ -0,0 +1,383 @@
+use crate::{
+    codegen::{
+        self,
+        instruction::{Decoder, InstructionSink},
+        Instruction,
+    },
+    MemoryBank, MemoryLayout,
+};
+
+use std::{num::NonZeroU32, sync::Arc};
+
+/// A code generator that does not generate any code, but only collects [Statistics] about the
+/// code it is given.
+///
+/// Code is decoded exactly like it is for the other code generators, so this is a cheap way to
+/// analyze or validate code. The runner it returns does nothing.
+#[derive(Debug, Default)]
+pub struct NullGen {
+    statistics: Statistics,
+    record_instructions: bool,
+}
+
+impl NullGen {
+    /// Create a new generator that does not record the decoded instructions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set whether the decoded instructions of every function are recorded in
+    /// [FunctionStatistics::instructions].
+    pub fn set_record_instructions(&mut self, record: bool) {
+        self.record_instructions = record;
+    }
+
+    /// The statistics of the last compiled code.
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+}
+
+impl codegen::private::CodeGeneratorImpl for NullGen {
+    type Runner = Runner;
+    type Emitter<'a> = Decoder<Emitter<'a>>;
+
+    fn set_constants(&mut self, _constants: &Arc<[i64]>) {}
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.statistics.layout = layout;
+        self.statistics.functions.clear();
+        self.statistics.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            FunctionStatistics::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Decoder(Emitter {
+            func: &mut self.statistics.functions[usize::try_from(idx).unwrap()],
+            record_instructions: self.record_instructions,
+        })
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        Runner {
+            function_count: self.statistics.functions.len() as u32,
+            instruction_count: self.statistics.instruction_count(),
+            layout: self.statistics.layout,
+        }
+    }
+}
+
+/// The runner of the [NullGen] code generator, which does nothing when it is run.
+#[derive(Debug)]
+pub struct Runner {
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+impl crate::Runner for Runner {
+    fn step(&self, _memory: &mut [i64]) {}
+
+    fn step_bounded(&self, _memory: &mut [i64], _fuel: u64) -> crate::StepResult {
+        crate::StepResult::Completed { fuel_used: 0 }
+    }
+
+    fn step_until(
+        &self,
+        _memory: &mut [i64],
+        _deadline: std::time::Instant,
+    ) -> Result<(), crate::DeadlineExceeded> {
+        Ok(())
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+/// Statistics about compiled code, collected by the [NullGen] code generator.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// The memory layout the code was compiled for.
+    pub layout: MemoryLayout,
+    /// The statistics of every function, indexed by function.
+    pub functions: Vec<FunctionStatistics>,
+}
+
+impl Statistics {
+    /// The total amount of instructions in all functions.
+    pub fn instruction_count(&self) -> usize {
+        self.functions.iter().map(|f| f.instruction_count).sum()
+    }
+}
+
+/// Statistics about a single function, see [Statistics].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionStatistics {
+    /// The amount of instructions, including nops.
+    pub instruction_count: usize,
+    /// The amount of instructions that do nothing.
+    pub nop_count: usize,
+    /// The amount of branches.
+    pub branch_count: usize,
+    /// The indices of the functions that are called, once for every call.
+    pub calls: Vec<u32>,
+    /// The amount of loads from every bank, in the order memory, output, input, const, scratch.
+    pub loads: [usize; 5],
+    /// The amount of stores to every bank, in the order memory, output, input, const, scratch.
+    pub stores: [usize; 5],
+    /// The decoded instructions, only recorded when enabled with
+    /// [NullGen::set_record_instructions].
+    pub instructions: Vec<Instruction>,
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut FunctionStatistics,
+    record_instructions: bool,
+}
+
+fn bank_index(bank: MemoryBank) -> usize {
+    match bank {
+        MemoryBank::Memory => 0,
+        MemoryBank::Output => 1,
+        MemoryBank::Input => 2,
+        MemoryBank::Const => 3,
+        MemoryBank::Scratch => 4,
+    }
+}
+
+impl<'a> InstructionSink for Emitter<'a> {
+    fn instruction(&mut self, instruction: Instruction) {
+        let func = &mut *self.func;
+        func.instruction_count += 1;
+        match instruction {
+            Instruction::Nop => func.nop_count += 1,
+            Instruction::Call { idx } => func.calls.push(idx),
+            Instruction::MemLoad { bank, .. } => func.loads[bank_index(bank)] += 1,
+            Instruction::MemStore { bank, .. } => func.stores[bank_index(bank)] += 1,
+            _ if instruction.is_branch() => func.branch_count += 1,
+            _ => (),
+        }
+
+        if self.record_instructions {
+            func.instructions.push(instruction);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testing::{random_code, Xorshift},
+        Channels, Compiler, DefaultFrequencies, InstructionFrequencies, Runner as _, RunnerInfo,
+    };
+
+    /// Encode an instruction of the kind at the given offset in the default frequency table.
+    fn encode(kind: u16, operands: u64) -> u64 {
+        u64::from(DefaultFrequencies::END_FUNC + kind) | operands
+    }
+
+    #[test]
+    fn statistics() {
+        let mut compiler = Compiler::new(NullGen::new());
+        compiler.generator_mut().set_record_instructions(true);
+
+        let code = [
+            encode(0, 0),
+            encode(DefaultFrequencies::CALL, 1 << 16),
+            // The end of the function
+            0,
+            encode(DefaultFrequencies::CALL, 0),
+        ];
+        let runner = compiler.compile(&code, 1, 1, 1, 1);
+        let mut memory = [1, 2, 3];
+        runner.step(&mut memory);
+        assert_eq!(memory, [1, 2, 3]);
+
+        let statistics = compiler.generator().statistics();
+        assert_eq!(statistics.layout, MemoryLayout::new(1, 1, 1));
+        assert_eq!(statistics.instruction_count(), 3);
+        assert_eq!(statistics.functions.len(), 2);
+
+        let main = &statistics.functions[0];
+        assert_eq!(main.calls, [1]);
+        assert_eq!(
+            main.instructions,
+            [
+                Instruction::Call { idx: 1 },
+                Instruction::IntAdd { dst: 1, a: 0, b: 0 },
+            ]
+        );
+        assert_eq!(statistics.functions[1].instructions.len(), 1);
+    }
+
+    #[test]
+    fn host_call() {
+        struct HostFrequencies;
+        impl InstructionFrequencies for HostFrequencies {
+            const OUTPUT_STORE: u16 = DefaultFrequencies::OUTPUT_STORE - 16;
+            const HOST_CALL: u16 = 16;
+        }
+
+        let host_call = (1 << 16) - u64::from(HostFrequencies::HOST_CALL);
+        let code = [host_call | 5 << 32];
+        let mut compiler = Compiler::new(NullGen::new());
+        compiler.generator_mut().set_record_instructions(true);
+        let decode = |compiler: &mut Compiler<NullGen>| {
+            compiler.compile_with_frequencies::<HostFrequencies>(&code, 1, 1, 1, 1);
+            compiler.generator().statistics().functions[0].instructions[0]
+        };
+
+        // Without host functions the instruction does nothing
+        assert_eq!(decode(&mut compiler), Instruction::Nop);
+        for _ in 0..3 {
+            compiler.register_host_function(|_| ());
+        }
+        assert_eq!(decode(&mut compiler), Instruction::HostCall { id: 2 });
+    }
+
+    #[test]
+    fn const_load() {
+        struct ConstFrequencies;
+        impl InstructionFrequencies for ConstFrequencies {
+            const OUTPUT_STORE: u16 = DefaultFrequencies::OUTPUT_STORE - 16;
+            const CONST_LOAD: u16 = 16;
+        }
+
+        let const_load = (1 << 16) - u64::from(ConstFrequencies::CONST_LOAD);
+        let code = [const_load | 2 << 16 | 7 << 32];
+        let mut compiler = Compiler::new(NullGen::new());
+        compiler.generator_mut().set_record_instructions(true);
+        let decode = |compiler: &mut Compiler<NullGen>| {
+            compiler.compile_with_frequencies::<ConstFrequencies>(&code, 1, 1, 1, 1);
+            compiler.generator().statistics().functions[0].instructions[0]
+        };
+
+        // Without constants the instruction does nothing
+        assert_eq!(decode(&mut compiler), Instruction::Nop);
+        compiler.set_constants([10, 20, 30]);
+        assert_eq!(
+            decode(&mut compiler),
+            Instruction::MemLoad {
+                dst: 2,
+                bank: MemoryBank::Const,
+                addr: 1,
+            },
+        );
+        assert_eq!(
+            compiler.generator().statistics().functions[0].loads,
+            [0, 0, 0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn output_channels() {
+        // The output store instructions are the last group with the default frequencies
+        let group = (1 << 16) - u64::from(DefaultFrequencies::OUTPUT_STORE);
+        let last = (1 << 16) - 1;
+        let code = [group | 7 << 32, (group + 2000) | 7 << 32, last | 7 << 32];
+        let mut compiler = Compiler::new(NullGen::new());
+        compiler.generator_mut().set_record_instructions(true);
+        compiler.set_channels(
+            Channels::new()
+                .with_output("action", 2)
+                .with_output("unused", 0)
+                .with_output("value", 3),
+        );
+        compiler.compile(&code, 1, 1, 5, 1);
+
+        let store = |addr| Instruction::MemStore {
+            bank: MemoryBank::Output,
+            addr,
+            src: 0,
+        };
+        assert_eq!(
+            compiler.generator().statistics().functions[0].instructions,
+            [store(1), Instruction::Nop, store(2 + 1)],
+        );
+    }
+
+    #[test]
+    fn matches_decode() {
+        let mut rng = Xorshift::default();
+
+        let mut compiler = Compiler::new(NullGen::new());
+        compiler.generator_mut().set_record_instructions(true);
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            compiler.compile(&code, 3, 8, 4, 4);
+
+            for func in &compiler.generator().statistics().functions {
+                assert_eq!(func.instructions.len(), func.instruction_count);
+                let count = |f: fn(&Instruction) -> bool| {
+                    func.instructions.iter().filter(|&i| f(i)).count()
+                };
+                assert_eq!(count(|i| *i == Instruction::Nop), func.nop_count);
+                assert_eq!(count(Instruction::is_branch), func.branch_count);
+                assert_eq!(
+                    count(|i| matches!(i, Instruction::MemStore { .. })),
+                    func.stores.iter().sum::<usize>()
+                );
+
+                // Branches never go past the end of the function
+                for (i, instruction) in func.instructions.iter().enumerate() {
+                    if let Instruction::BranchCmp { offset, .. }
+                    | Instruction::BranchZero { offset, .. }
+                    | Instruction::BranchNonZero { offset, .. } = *instruction
+                    {
+                        assert!(i + 1 + offset as usize <= func.instruction_count);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn runner_info() {
+        fn check<G>(gen: G, code: &[u64], statistics: &Statistics)
+        where
+            G: codegen::CodeGenerator + 'static,
+            G::Runner: RunnerInfo,
+        {
+            let info = Compiler::new(gen).compile(code, 3, 8, 4, 4);
+            assert_eq!(info.function_count() as usize, statistics.functions.len());
+            assert_eq!(info.instruction_count(), statistics.instruction_count());
+            assert_eq!(info.layout(), statistics.layout);
+        }
+
+        let mut rng = Xorshift::default();
+
+        let mut compiler = Compiler::new(NullGen::new());
+        for _ in 0..10 {
+            let code = random_code(&mut rng, 256);
+            let runner = compiler.compile(&code, 3, 8, 4, 4);
+            let statistics = compiler.generator().statistics().clone();
+            assert_eq!(runner.instruction_count(), statistics.instruction_count());
+            assert_eq!(runner.code_size(), None);
+
+            check(codegen::Interpreter::new(), &code, &statistics);
+            check(codegen::Bytecode::new(), &code, &statistics);
+            #[cfg(feature = "jit")]
+            check(codegen::Jit::new(), &code, &statistics);
+            #[cfg(feature = "cranelift")]
+            check(codegen::Cranelift::new(), &code, &statistics);
+        }
+    }
+}
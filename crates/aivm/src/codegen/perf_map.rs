@@ -0,0 +1,17 @@
+use std::{fmt::Write as _, fs::OpenOptions, io::Write as _, process};
+
+/// Append symbols for generated code to `/tmp/perf-<pid>.map`, where Linux `perf` looks up
+/// the names of JIT compiled code. Every symbol is a start address, a size in bytes and a name.
+///
+/// Errors are ignored, since profiling information is not worth failing a compilation over.
+pub(crate) fn append(symbols: impl IntoIterator<Item = (*const u8, usize, String)>) {
+    let mut map = String::new();
+    for (start, size, name) in symbols {
+        writeln!(map, "{:x} {size:x} {name}", start as usize).unwrap();
+    }
+
+    let path = format!("/tmp/perf-{}.map", process::id());
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+        let _ = file.write_all(map.as_bytes());
+    }
+}
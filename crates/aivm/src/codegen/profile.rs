@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+/// How often every function was called and how long it ran, collected over any number of steps
+/// by `step_with_profile` of the [Interpreter](super::Interpreter) and `Jit` runners.
+///
+/// This shows which of the evolved functions actually carry the behavior. The time of a
+/// function includes the time of the functions it calls. Measuring it slows down every call,
+/// so the times are most useful compared to each other.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    functions: Vec<FunctionProfile>,
+}
+
+/// The statistics of a single function in a [Profile].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    /// The amount of times the function was called.
+    pub calls: u64,
+    /// The total time spent in the function, including the functions it called.
+    pub time: Duration,
+}
+
+impl Profile {
+    /// Create an empty profile.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The statistics of every function, indexed by function. Function 0 is the main function,
+    /// which is called once every step.
+    pub fn functions(&self) -> &[FunctionProfile] {
+        &self.functions
+    }
+
+    /// The indices of the functions that were called at least once, ordered by the time spent
+    /// in them from high to low.
+    pub fn hottest(&self) -> Vec<usize> {
+        let mut indices: Vec<_> = (0..self.functions.len())
+            .filter(|&f| self.functions[f].calls > 0)
+            .collect();
+        indices.sort_by_key(|&f| std::cmp::Reverse(self.functions[f].time));
+        indices
+    }
+
+    /// Forget everything that was collected so far.
+    pub fn clear(&mut self) {
+        self.functions.clear();
+    }
+
+    /// The statistics to add to for code with `function_count` functions.
+    pub(crate) fn functions_mut(&mut self, function_count: usize) -> &mut [FunctionProfile] {
+        if self.functions.len() < function_count {
+            self.functions
+                .resize(function_count, FunctionProfile::default());
+        }
+        &mut self.functions[..function_count]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hottest() {
+        let mut profile = Profile::new();
+        let functions = profile.functions_mut(3);
+        functions[0] = FunctionProfile {
+            calls: 1,
+            time: Duration::from_millis(5),
+        };
+        functions[2] = FunctionProfile {
+            calls: 4,
+            time: Duration::from_millis(7),
+        };
+        assert_eq!(profile.hottest(), [2, 0]);
+
+        profile.functions_mut(1)[0].calls += 1;
+        assert_eq!(profile.functions().len(), 3);
+        assert_eq!(profile.functions()[0].calls, 2);
+    }
+}
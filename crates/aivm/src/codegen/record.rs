@@ -0,0 +1,184 @@
+use crate::{
+    codegen::{
+        self,
+        instruction::{Decoder, InstructionSink},
+        private::{CodeGeneratorImpl, Emitter as _},
+        CodeGenerator, Instruction,
+    },
+    HostFunctions, MemoryLayout,
+};
+
+use std::{num::NonZeroU32, sync::Arc};
+
+/// A code generator that passes everything on to another code generator, while recording the
+/// instructions it emits.
+///
+/// This shows exactly what a code generator was given, which helps to find out whether a
+/// difference between code generators comes from the decoding of the code or from the code
+/// generator itself.
+#[derive(Debug, Default)]
+pub struct Record<G: CodeGenerator> {
+    gen: G,
+    layout: MemoryLayout,
+    log: Vec<Recorded>,
+}
+
+/// An instruction that was passed to the code generator wrapped by [Record].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Recorded {
+    /// The index of the function the instruction is in.
+    pub function: u32,
+    /// The index of the instruction in the compiled code.
+    pub code_index: usize,
+    /// The instruction itself.
+    pub instruction: Instruction,
+}
+
+impl<G: CodeGenerator> Record<G> {
+    /// Create a generator that passes everything on to `gen`.
+    pub fn new(gen: G) -> Self {
+        Self {
+            gen,
+            layout: MemoryLayout::default(),
+            log: vec![],
+        }
+    }
+
+    /// The instructions of the last compiled code, in the order they were emitted.
+    pub fn log(&self) -> &[Recorded] {
+        &self.log
+    }
+
+    /// The memory layout of the last compiled code.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The wrapped code generator.
+    pub fn inner(&self) -> &G {
+        &self.gen
+    }
+
+    /// Mutable access to the wrapped code generator.
+    pub fn inner_mut(&mut self) -> &mut G {
+        &mut self.gen
+    }
+
+    /// Take the wrapped code generator.
+    pub fn into_inner(self) -> G {
+        self.gen
+    }
+}
+
+impl<G: CodeGenerator> codegen::private::CodeGeneratorImpl for Record<G> {
+    type Runner = G::Runner;
+    type Emitter<'a>
+        = Decoder<Emitter<'a, G>>
+    where
+        Self: 'a;
+
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.gen.set_host_functions(host);
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.gen.set_constants(constants);
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.log.clear();
+        self.gen.begin(function_count, layout);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Decoder(Emitter {
+            inner: self.gen.begin_function(idx),
+            log: &mut self.log,
+            function: idx,
+            code_index: 0,
+        })
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        self.gen.finish()
+    }
+}
+
+pub struct Emitter<'a, G: CodeGeneratorImpl + 'a> {
+    inner: G::Emitter<'a>,
+    log: &'a mut Vec<Recorded>,
+    function: u32,
+    code_index: usize,
+}
+
+impl<'a, G: CodeGeneratorImpl + 'a> InstructionSink for Emitter<'a, G> {
+    fn prepare_emit(&mut self, code_index: usize) {
+        self.code_index = code_index;
+        self.inner.prepare_emit(code_index);
+    }
+
+    fn finalize(self) {
+        self.inner.finalize();
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        self.log.push(Recorded {
+            function: self.function,
+            code_index: self.code_index,
+            instruction,
+        });
+        instruction.emit(&mut self.inner);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::{Interpreter, NullGen},
+        testing::{random_code, random_memory, Xorshift},
+        Compiler, DefaultFrequencies, InstructionFrequencies, Runner as _,
+    };
+
+    #[test]
+    fn forwards_and_records() {
+        let mut rng = Xorshift::default();
+
+        let mut recording = Compiler::new(Record::new(Interpreter::new()));
+        let mut plain = Compiler::new(Interpreter::new());
+        let mut null = NullGen::new();
+        null.set_record_instructions(true);
+        let mut null = Compiler::new(null);
+
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+
+            let mut expected = memory.clone();
+            plain.compile(&code, 3, 8, 4, 4).step(&mut expected);
+            let mut actual = memory;
+            recording.compile(&code, 3, 8, 4, 4).step(&mut actual);
+            assert_eq!(actual, expected);
+
+            null.compile(&code, 3, 8, 4, 4);
+            let statistics = null.generator().statistics();
+            let record = recording.generator();
+            assert_eq!(record.layout(), statistics.layout);
+
+            let mut log = record.log().iter();
+            for (f, func) in statistics.functions.iter().enumerate() {
+                let mut last_index = None;
+                for &instruction in &func.instructions {
+                    let recorded = log.next().unwrap();
+                    assert_eq!(recorded.function as usize, f);
+                    assert_eq!(recorded.instruction, instruction);
+                    assert!(code[recorded.code_index] as u16 >= DefaultFrequencies::END_FUNC);
+                    assert!(last_index < Some(recorded.code_index));
+                    last_index = Some(recorded.code_index);
+                }
+            }
+            assert!(log.next().is_none());
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+/// Statistics about the last compilation of a code generator that generates machine code.
+///
+/// Training frameworks can use it to keep track of the compile overhead, and to penalize code
+/// that is expensive to compile or run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompileReport {
+    /// The statistics of every function, indexed by function.
+    pub functions: Vec<FunctionReport>,
+    /// The wall-clock time from the start of the compilation until the machine code was ready to
+    /// run.
+    pub compile_time: Duration,
+}
+
+/// Statistics about the machine code generated for a single function, see [CompileReport].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionReport {
+    /// The size of the machine code in bytes.
+    pub code_size: usize,
+    /// The size in bytes of the stack frame that holds variables that did not fit in
+    /// registers.
+    pub stack_size: u32,
+    /// The number of times a variable was moved from a register to the stack to free up the
+    /// register, or `None` if the code generator does not report it.
+    pub spills: Option<u32>,
+    /// The registers that the function uses, as a mask of their hardware encodings, or `None`
+    /// if the code generator does not report it.
+    pub used_regs_mask: Option<u64>,
+}
+
+impl CompileReport {
+    /// The total size of the machine code of all functions in bytes.
+    pub fn code_size(&self) -> usize {
+        self.functions.iter().map(|f| f.code_size).sum()
+    }
+}
@@ -0,0 +1,579 @@
+use crate::{
+    codegen,
+    compile::{CompareKind, REGISTER_COUNT},
+    MemoryBank, MemoryLayout,
+};
+
+use std::{fmt::Write, num::NonZeroU32, sync::Arc};
+
+/// A code generator that translates AIVM code into safe Rust source.
+///
+/// Instead of a runner that can be called directly, compiling produces a [RustSource] that can
+/// be included in another crate, which then does not need to depend on this crate to run a
+/// trained agent.
+#[derive(Default)]
+pub struct RustExport {
+    layout: MemoryLayout,
+    constants: Arc<[i64]>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Default)]
+struct Function {
+    lines: Vec<Line>,
+    /// The instructions that are the target of a branch.
+    branch_targets: Vec<u32>,
+    calls: Vec<u32>,
+    // Unused variables and needless `mut` cause warnings in the generated code
+    reads_registers: bool,
+    writes_registers: bool,
+    uses_memory: bool,
+    /// The amount of VM instructions, set when the function is finalized.
+    instruction_count: u32,
+}
+
+#[derive(Clone)]
+enum Line {
+    Statement(String),
+    /// The end of the innermost block that is still open, a branch to the instruction after it
+    /// breaks out of the block.
+    BranchTarget,
+}
+
+impl codegen::private::CodeGeneratorImpl for RustExport {
+    type Runner = RustSource;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.functions.clear();
+        self.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            Function::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Emitter {
+            func: &mut self.functions[usize::try_from(idx).unwrap()],
+            layout: self.layout,
+            constants: &self.constants,
+            instruction_count: 0,
+        }
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        let layout = self.layout;
+        let mut source = format!("// Generated by aivm {}.\n", env!("CARGO_PKG_VERSION"));
+
+        let output = layout.bank_range(MemoryBank::Output);
+        write!(
+            source,
+            "\n/// Run the agent once, like `aivm::Runner::step`.\n\
+             ///\n\
+             /// # Panics\n\
+             /// If the memory contains less than {} values.\n\
+             pub fn step(memory: &mut [i64]) {{\n    \
+             assert!(memory.len() >= {});\n",
+            layout.size(),
+            layout.size(),
+        )
+        .unwrap();
+        if let (Some(value), false) = (layout.output_init.value(), output.is_empty()) {
+            writeln!(
+                source,
+                "    memory[{}..{}].fill({value});",
+                output.start, output.end
+            )
+            .unwrap();
+        }
+        let scratch = layout.bank_range(MemoryBank::Scratch);
+        if !scratch.is_empty() {
+            writeln!(
+                source,
+                "    memory[{}..{}].fill(0);",
+                scratch.start, scratch.end
+            )
+            .unwrap();
+        }
+        writeln!(source, "    function_0(memory);\n}}").unwrap();
+
+        // Leave out functions that are never called, rustc warns about them
+        let mut reachable = vec![false; self.functions.len()];
+        let mut stack = vec![0];
+        reachable[0] = true;
+        while let Some(f) = stack.pop() {
+            for &callee in &self.functions[f].calls {
+                let callee = usize::try_from(callee).unwrap();
+                if !reachable[callee] {
+                    reachable[callee] = true;
+                    stack.push(callee);
+                }
+            }
+        }
+
+        for (f, func) in self
+            .functions
+            .iter()
+            .enumerate()
+            .filter(|&(f, _)| reachable[f])
+        {
+            func.render(f, &mut source);
+        }
+
+        RustSource {
+            source,
+            function_count: self.functions.len() as u32,
+            instruction_count: self
+                .functions
+                .iter()
+                .map(|func| func.instruction_count as usize)
+                .sum(),
+            layout,
+        }
+    }
+}
+
+impl RustExport {
+    /// Create a new generator.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Function {
+    fn render(&self, idx: usize, source: &mut String) {
+        let memory = if self.uses_memory {
+            "memory"
+        } else {
+            "_memory"
+        };
+        source.push('\n');
+        if self.writes_registers {
+            // Evolved code is full of values that are overwritten before they are read
+            source.push_str("#[allow(unused_assignments)]\n");
+        }
+        writeln!(source, "fn function_{idx}({memory}: &mut [i64]) {{").unwrap();
+        if self.reads_registers || self.writes_registers {
+            let mutable = if self.writes_registers { "mut " } else { "" };
+            writeln!(source, "    let {mutable}r = [0i64; {REGISTER_COUNT}];").unwrap();
+        }
+
+        // Rust has no goto, a branch breaks out of a labeled block that ends right before its
+        // target. Branches only go forward, so all blocks can start at the top of the function,
+        // with the block of the furthest target on the outside.
+        let mut targets = self.branch_targets.clone();
+        targets.sort_unstable();
+        let mut depth = 1;
+        for &target in targets.iter().rev() {
+            writeln!(source, "{:1$}'l{target}: {{", "", depth * 4).unwrap();
+            depth += 1;
+        }
+        for line in &self.lines {
+            match line {
+                Line::Statement(statement) => {
+                    writeln!(source, "{:1$}{statement}", "", depth * 4).unwrap()
+                }
+                Line::BranchTarget => {
+                    depth -= 1;
+                    writeln!(source, "{:1$}}}", "", depth * 4).unwrap();
+                }
+            }
+        }
+
+        source.push_str("}\n");
+    }
+}
+
+/// Rust source code generated by the [RustExport] code generator.
+///
+/// The source contains a single public function with the signature
+/// `fn step(memory: &mut [i64])`, which behaves like [Runner::step](crate::Runner::step). It
+/// can be included in a module of another crate with the `include!` macro.
+pub struct RustSource {
+    source: String,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+impl RustSource {
+    /// The generated Rust source.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Take the generated Rust source.
+    pub fn into_source(self) -> String {
+        self.source
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl crate::RunnerInfo for RustSource {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Function,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    instruction_count: u32,
+}
+
+impl<'a> Emitter<'a> {
+    /// Assign an expression that reads registers to a register.
+    fn assign(&mut self, dst: u8, expr: std::fmt::Arguments) {
+        self.func.reads_registers = true;
+        self.func.writes_registers = true;
+        self.statement(format_args!("r[{dst}] = {expr};"));
+    }
+
+    fn statement(&mut self, statement: std::fmt::Arguments) {
+        self.func.lines.push(Line::Statement(statement.to_string()));
+    }
+
+    fn place_branch_target(&mut self) {
+        let i = self.instruction_count;
+        if self.func.branch_targets.contains(&i) {
+            self.func.lines.push(Line::BranchTarget);
+        }
+    }
+
+    fn branch(&mut self, condition: std::fmt::Arguments, offset: u32) {
+        // The instruction counter already points at the next instruction
+        let target = self.instruction_count + offset;
+        if !self.func.branch_targets.contains(&target) {
+            self.func.branch_targets.push(target);
+        }
+        self.func.reads_registers = true;
+        self.statement(format_args!("if {condition} {{ break 'l{target}; }}"));
+    }
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        self.place_branch_target();
+        self.instruction_count += 1;
+    }
+
+    fn finalize(mut self) {
+        self.place_branch_target();
+        self.func.instruction_count = self.instruction_count;
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.func.calls.push(idx);
+        self.func.uses_memory = true;
+        self.statement(format_args!("function_{idx}(memory);"));
+    }
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, _id: u32) {
+        panic!("host calls are not supported by the Rust export");
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}].wrapping_add(r[{b}])"));
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}].wrapping_sub(r[{b}])"));
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}].wrapping_mul(r[{b}])"));
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("((i128::from(r[{a}]) * i128::from(r[{b}])) >> 64) as i64"),
+        );
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("((u128::from(r[{a}] as u64) * u128::from(r[{b}] as u64)) >> 64) as i64"),
+        );
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("r[{src}].wrapping_neg()"));
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("r[{src}].wrapping_abs()"));
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.assign(dst, format_args!("r[{dst}].wrapping_add(1)"));
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.assign(dst, format_args!("r[{dst}].wrapping_sub(1)"));
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}].min(r[{b}])"));
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}].max(r[{b}])"));
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] | r[{b}]"));
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] & r[{b}]"));
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] ^ r[{b}]"));
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("!r[{src}]"));
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("r[{src}] << {amount}"));
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("r[{src}] >> {amount}"));
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("r[{src}].rotate_left({amount})"));
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("r[{src}].rotate_right({amount})"));
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("(r[{a}] & r[{mask}]) | (r[{b}] & !r[{mask}])"),
+        );
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("i64::from(r[{src}].count_ones())"));
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("r[{src}].reverse_bits()"));
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        let op = match compare_kind {
+            CompareKind::Eq => "==",
+            CompareKind::Neq => "!=",
+            CompareKind::Gt => ">",
+            CompareKind::Lt => "<",
+        };
+        self.branch(format_args!("r[{a}] {op} r[{b}]"), offset);
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("r[{src}] == 0"), offset);
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("r[{src}] != 0"), offset);
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        self.func.writes_registers = true;
+        if bank == MemoryBank::Const {
+            let value = self.constants[usize::try_from(addr).unwrap()];
+            self.statement(format_args!("r[{dst}] = {value};"));
+            return;
+        }
+        let addr = self.layout.address(bank, addr);
+        self.func.uses_memory = true;
+        self.statement(format_args!("r[{dst}] = memory[{addr}];"));
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let addr = self.layout.address(bank, addr);
+            self.func.reads_registers = true;
+            self.func.uses_memory = true;
+            self.statement(format_args!("memory[{addr}] = r[{src}];"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{random_code, random_memory, Xorshift},
+        Compiler, DefaultFrequencies, InstructionFrequencies, OutputInit, Runner as _,
+    };
+
+    use std::{fs, process::Command};
+
+    /// Compile the sources into a program that runs every source on its memory and prints the
+    /// results, one line per source. Returns `None` if rustc can not be run.
+    fn run_with_rustc(name: &str, programs: &[(RustSource, Vec<i64>)]) -> Option<Vec<Vec<i64>>> {
+        let dir = std::env::temp_dir().join(format!("aivm-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut main = String::new();
+        for (p, (code, _)) in programs.iter().enumerate() {
+            writeln!(main, "mod program_{p} {{\n{}}}", code.source()).unwrap();
+        }
+        main.push_str("fn main() {\n");
+        for (p, (_, memory)) in programs.iter().enumerate() {
+            writeln!(
+                main,
+                "    let mut memory: Vec<i64> = vec!{memory:?};\n    \
+                 program_{p}::step(&mut memory);\n    \
+                 println!(\"{{memory:?}}\");",
+            )
+            .unwrap();
+        }
+        main.push_str("}\n");
+
+        let main_path = dir.join("main.rs");
+        fs::write(&main_path, main).unwrap();
+
+        let exe = dir.join("main");
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+        let Ok(status) = Command::new(rustc)
+            .args(["--edition", "2021", "-D", "warnings", "-o"])
+            .arg(&exe)
+            .arg(&main_path)
+            .status()
+        else {
+            eprintln!("skipping, rustc not found");
+            return None;
+        };
+        assert!(status.success(), "rustc failed on {}", dir.display());
+
+        let output = Command::new(&exe).output().unwrap();
+        assert!(output.status.success());
+        fs::remove_dir_all(&dir).unwrap();
+
+        Some(
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    line.trim_matches(['[', ']'])
+                        .split(", ")
+                        .filter(|v| !v.is_empty())
+                        .map(|v| v.parse().unwrap())
+                        .collect()
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn golden() {
+        type F = DefaultFrequencies;
+        // Store the first input in the first output if it is not zero
+        let load = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE + F::INPUT_LOAD);
+        let branch_zero = load - u64::from(F::MEM_LOAD + F::BRANCH_NON_ZERO + F::BRANCH_ZERO);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE);
+        let code = [load, branch_zero | 1 << 32, store];
+
+        let source = Compiler::new(RustExport::new())
+            .compile(&code, 1, 0, 1, 1)
+            .into_source();
+        let expected = "\
+// Generated by aivm VERSION.
+
+/// Run the agent once, like `aivm::Runner::step`.
+///
+/// # Panics
+/// If the memory contains less than 2 values.
+pub fn step(memory: &mut [i64]) {
+    assert!(memory.len() >= 2);
+    memory[0..1].fill(0);
+    function_0(memory);
+}
+
+#[allow(unused_assignments)]
+fn function_0(memory: &mut [i64]) {
+    let mut r = [0i64; 64];
+    'l3: {
+        r[0] = memory[1];
+        if r[0] == 0 { break 'l3; }
+        memory[0] = r[0];
+    }
+}
+";
+        assert_eq!(
+            source,
+            expected.replace("VERSION", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn matches_spec() {
+        let vectors = crate::spec::test_vectors();
+        let mut compiler = Compiler::new(RustExport::new());
+        let programs: Vec<_> = vectors
+            .iter()
+            .map(|vector| {
+                let layout = vector.layout;
+                let code = compiler.compile(
+                    &vector.code,
+                    vector.lowest_function_level,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                );
+                (code, vector.memory.clone())
+            })
+            .collect();
+
+        let Some(results) = run_with_rustc("rust-export-spec", &programs) else {
+            return;
+        };
+        for (vector, actual) in vectors.iter().zip(results) {
+            assert_eq!(actual, vector.expected, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut rng = Xorshift::default();
+
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let mut compiler = Compiler::new(RustExport::new());
+        let mut programs = vec![];
+        let mut expected = vec![];
+        for p in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+            let output_init = [
+                OutputInit::Clear,
+                OutputInit::Keep,
+                OutputInit::Fill(i64::MIN),
+            ][p % 3];
+            interpreter.set_output_init(output_init);
+            compiler.set_output_init(output_init);
+
+            let mut result = memory.clone();
+            interpreter.compile(&code, 3, 8, 4, 4).step(&mut result);
+            expected.push(result);
+
+            programs.push((compiler.compile(&code, 3, 8, 4, 4), memory));
+        }
+
+        let Some(results) = run_with_rustc("rust-export-random", &programs) else {
+            return;
+        };
+        assert_eq!(results, expected);
+    }
+}
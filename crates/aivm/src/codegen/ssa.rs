@@ -0,0 +1,181 @@
+use bitvec::prelude::*;
+
+/// The dominator tree and dominance frontiers of a control flow graph, which are needed to
+/// convert code to SSA form.
+///
+/// Blocks are identified by their index. The blocks must be in reverse post order, so the
+/// entry block is block 0 and every other block comes after at least one of its predecessors.
+#[derive(Debug)]
+pub struct Dominators {
+    idoms: Vec<usize>,
+    frontiers: Vec<Vec<usize>>,
+}
+
+impl Dominators {
+    /// Compute the dominators of a graph with `block_count` blocks, where `predecessors` returns
+    /// the predecessors of a block.
+    ///
+    /// Uses the algorithm from "A Simple, Fast Dominance Algorithm" by Cooper, Harvey and
+    /// Kennedy.
+    pub fn compute<P>(block_count: usize, predecessors: impl Fn(usize) -> P) -> Self
+    where
+        P: Iterator<Item = usize> + Clone,
+    {
+        const UNKNOWN: usize = usize::MAX;
+
+        let mut idoms = vec![UNKNOWN; block_count];
+        idoms[0] = 0;
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for b in 1..block_count {
+                let mut new_idom = predecessors(b).find(|&p| idoms[p] != UNKNOWN).unwrap();
+                let initial_idom = new_idom;
+
+                for p in predecessors(b).filter(|&p| p != initial_idom) {
+                    if idoms[p] != UNKNOWN {
+                        let mut finger1 = p;
+                        let mut finger2 = new_idom;
+
+                        while finger1 != finger2 {
+                            while finger1 > finger2 {
+                                finger1 = idoms[finger1];
+                            }
+                            while finger2 > finger1 {
+                                finger2 = idoms[finger2];
+                            }
+                        }
+
+                        new_idom = finger1;
+                    }
+                }
+
+                changed |= idoms[b] != new_idom;
+                idoms[b] = new_idom;
+            }
+        }
+
+        let mut frontiers = vec![vec![]; block_count];
+        for b in 1..block_count {
+            let preds = predecessors(b);
+            if preds.clone().nth(1).is_none() {
+                continue;
+            }
+
+            for p in preds {
+                let mut runner = p;
+                while runner != idoms[b] {
+                    let frontier: &mut Vec<_> = &mut frontiers[runner];
+                    if !frontier.contains(&b) {
+                        frontier.push(b);
+                    }
+                    runner = idoms[runner];
+                }
+            }
+        }
+
+        Self { idoms, frontiers }
+    }
+
+    /// The immediate dominator of a block, the entry block is its own immediate dominator.
+    pub fn idom(&self, block: usize) -> usize {
+        self.idoms[block]
+    }
+
+    /// The blocks where the dominance of `block` ends.
+    pub fn frontier(&self, block: usize) -> &[usize] {
+        &self.frontiers[block]
+    }
+
+    /// Call `place` for every block that needs a param for a variable that is defined in
+    /// `def_blocks`, which is the iterated dominance frontier of those blocks.
+    pub fn place_params(
+        &self,
+        def_blocks: impl IntoIterator<Item = usize>,
+        mut place: impl FnMut(usize),
+    ) {
+        let mut processed = bitvec![0; self.idoms.len()];
+        let mut pushed = bitvec![0; self.idoms.len()];
+        let mut stack: Vec<_> = def_blocks.into_iter().collect();
+        for &b in &stack {
+            pushed.set(b, true);
+        }
+
+        while let Some(b) = stack.pop() {
+            for &f in &self.frontiers[b] {
+                if !processed[f] {
+                    place(f);
+                    processed.set(f, true);
+
+                    if !pushed[f] {
+                        pushed.set(f, true);
+                        stack.push(f);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compute(predecessors: &[&[usize]]) -> Dominators {
+        Dominators::compute(predecessors.len(), |b| predecessors[b].iter().copied())
+    }
+
+    #[test]
+    fn diamond() {
+        // 0 branches to 1 and 2, which both fall through into 3
+        let doms = compute(&[&[], &[0], &[0], &[1, 2]]);
+
+        assert!((0..4).all(|b| doms.idom(b) == 0));
+        assert_eq!(doms.frontier(0), []);
+        assert_eq!(doms.frontier(1), [3]);
+        assert_eq!(doms.frontier(2), [3]);
+        assert_eq!(doms.frontier(3), []);
+    }
+
+    #[test]
+    fn loop_() {
+        // 1 is the header of a loop with body 2, and exits to 3
+        let doms = compute(&[&[], &[0, 2], &[1], &[1]]);
+
+        assert_eq!(
+            (0..4).map(|b| doms.idom(b)).collect::<Vec<_>>(),
+            [0, 0, 1, 1]
+        );
+        assert_eq!(doms.frontier(1), [1]);
+        assert_eq!(doms.frontier(2), [1]);
+    }
+
+    #[test]
+    fn converges() {
+        // 0 branches to 1 and 4, which both lead to 3, and 1 and 3 both lead to 2. Block 4 comes
+        // after 3, so the first pass only sees 1 as a predecessor of 3. The second pass corrects
+        // the dominator of 3, and the third the one of 2, while the last block stays the same.
+        let doms = compute(&[&[], &[0], &[3, 1], &[1, 4], &[0]]);
+
+        assert!((0..5).all(|b| doms.idom(b) == 0));
+        assert_eq!(doms.frontier(1), [2, 3]);
+        assert_eq!(doms.frontier(3), [2]);
+        assert_eq!(doms.frontier(4), [3]);
+    }
+
+    #[test]
+    fn place_params() {
+        // Two diamonds after each other, a definition only needs a param at the join of its own
+        // diamond
+        let doms = compute(&[&[], &[0], &[0], &[1, 2], &[3], &[3], &[4, 5]]);
+
+        let mut placed = vec![];
+        doms.place_params([1], |b| placed.push(b));
+        assert_eq!(placed, [3]);
+
+        placed.clear();
+        doms.place_params([0, 4], |b| placed.push(b));
+        assert_eq!(placed, [6]);
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{
+    codegen::{
+        self, interpreter,
+        private::{CodeGeneratorImpl, Emitter as _},
+        Interpreter, Jit,
+    },
+    compile::CompareKind,
+    DeadlineExceeded, HostFunctions, MemoryBank, MemoryLayout, StepResult,
+};
+
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, OnceLock,
+    },
+    time::Instant,
+};
+
+/// A code generator that starts out interpreting and switches to the [Jit] once the code has
+/// run often enough.
+///
+/// Most programs in a training loop are only run a few times, which is not enough to earn back
+/// the time spent compiling them to machine code. The runner interprets the first steps, and
+/// compiles the code with the [Jit] when the amount of steps reaches the threshold.
+pub struct Tiered {
+    interpreter: Interpreter,
+    recording: Recording,
+    jit_threshold: u32,
+}
+
+impl codegen::private::CodeGeneratorImpl for Tiered {
+    type Runner = Runner;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.interpreter.set_host_functions(host);
+        self.recording.host = host.clone();
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.interpreter.set_constants(constants);
+        self.recording.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.interpreter.begin(function_count, layout);
+
+        self.recording.function_count = function_count;
+        self.recording.layout = layout;
+        self.recording.functions.clear();
+        self.recording
+            .functions
+            .resize(usize::try_from(function_count.get()).unwrap(), vec![]);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Emitter {
+            interpreter: self.interpreter.begin_function(idx),
+            ops: &mut self.recording.functions[usize::try_from(idx).unwrap()],
+        }
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        Runner {
+            interpreter: self.interpreter.finish(),
+            recording: self.recording.clone(),
+            jit_threshold: self.jit_threshold,
+            steps: AtomicU32::new(0),
+            jit: OnceLock::new(),
+        }
+    }
+}
+
+impl Tiered {
+    /// The amount of steps that are interpreted by a generator created with [new](Self::new).
+    pub const DEFAULT_JIT_THRESHOLD: u32 = 8;
+
+    /// Create a new generator that compiles to machine code after
+    /// [DEFAULT_JIT_THRESHOLD](Self::DEFAULT_JIT_THRESHOLD) steps.
+    pub fn new() -> Self {
+        Self::with_jit_threshold(Self::DEFAULT_JIT_THRESHOLD)
+    }
+
+    /// Create a new generator that interprets the first `steps` steps of a runner, and runs
+    /// machine code for the steps after that.
+    pub fn with_jit_threshold(steps: u32) -> Self {
+        Self {
+            interpreter: Interpreter::new(),
+            recording: Recording {
+                function_count: NonZeroU32::MIN,
+                layout: MemoryLayout::default(),
+                functions: vec![],
+                host: HostFunctions::new(),
+                constants: Arc::new([]),
+            },
+            jit_threshold: steps,
+        }
+    }
+}
+
+impl Default for Tiered {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runner returned by the [Tiered] code generator.
+pub struct Runner {
+    interpreter: interpreter::Runner,
+    recording: Recording,
+    jit_threshold: u32,
+    steps: AtomicU32,
+    jit: OnceLock<codegen::JitRunner>,
+}
+
+impl crate::Runner for Runner {
+    fn step(&self, memory: &mut [i64]) {
+        self.tier().step(memory);
+    }
+
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        self.tier().step_bounded(memory, fuel)
+    }
+
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        self.tier().step_until(memory, deadline)
+    }
+
+    fn step_batch(&self, memories: &mut [&mut [i64]]) {
+        self.tier().step_batch(memories);
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.interpreter.function_count()
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.interpreter.instruction_count()
+    }
+
+    /// The size of the machine code once the code has been compiled.
+    fn code_size(&self) -> Option<usize> {
+        self.jit.get().and_then(|jit| jit.code_size())
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.interpreter.layout()
+    }
+}
+
+impl Runner {
+    /// The runner to use for the current step, compiling the code once the threshold is
+    /// reached.
+    fn tier(&self) -> &dyn crate::Runner {
+        if let Some(jit) = self.jit.get() {
+            jit
+        } else if self.steps.fetch_add(1, Ordering::Relaxed) < self.jit_threshold {
+            &self.interpreter
+        } else {
+            self.jit.get_or_init(|| self.recording.compile())
+        }
+    }
+
+    /// Whether the code has been compiled to machine code.
+    pub fn is_jit_compiled(&self) -> bool {
+        self.jit.get().is_some()
+    }
+}
+
+/// The emitted instructions, so they can be replayed into the [Jit] later.
+#[derive(Clone)]
+struct Recording {
+    function_count: NonZeroU32,
+    layout: MemoryLayout,
+    functions: Vec<Vec<Op>>,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+}
+
+impl Recording {
+    fn compile(&self) -> codegen::JitRunner {
+        let mut jit = Jit::new();
+        jit.set_host_functions(&self.host);
+        jit.set_constants(&self.constants);
+        jit.begin(self.function_count, self.layout);
+
+        for (f, ops) in self.functions.iter().enumerate() {
+            let mut emitter = jit.begin_function(u32::try_from(f).unwrap());
+            for &op in ops {
+                op.replay(&mut emitter);
+            }
+            emitter.finalize();
+        }
+
+        jit.finish()
+    }
+}
+
+pub struct Emitter<'a> {
+    interpreter: interpreter::Emitter<'a>,
+    ops: &'a mut Vec<Op>,
+}
+
+macro_rules! recorded_ops {
+    ($($method:ident($($arg:ident: $ty:ty),*) => $op:ident;)*) => {
+        #[derive(Debug, Clone, Copy)]
+        enum Op {
+            $($op($($ty),*),)*
+        }
+
+        impl Op {
+            fn replay(self, emitter: &mut impl codegen::private::Emitter) {
+                match self {
+                    $(Self::$op($($arg),*) => emitter.$method($($arg),*),)*
+                }
+            }
+        }
+
+        impl<'a> codegen::private::Emitter for Emitter<'a> {
+            fn finalize(self) {
+                self.interpreter.finalize();
+            }
+
+            $(
+                fn $method(&mut self, $($arg: $ty),*) {
+                    self.interpreter.$method($($arg),*);
+                    self.ops.push(Op::$op($($arg),*));
+                }
+            )*
+        }
+    };
+}
+
+recorded_ops! {
+    prepare_emit(code_index: usize) => Prepare;
+    emit_call(idx: u32) => Call;
+    emit_nop() => Nop;
+    emit_host_call(id: u32) => HostCall;
+    emit_int_add(dst: u8, a: u8, b: u8) => IntAdd;
+    emit_int_sub(dst: u8, a: u8, b: u8) => IntSub;
+    emit_int_mul(dst: u8, a: u8, b: u8) => IntMul;
+    emit_int_mul_high(dst: u8, a: u8, b: u8) => IntMulHigh;
+    emit_int_mul_high_unsigned(dst: u8, a: u8, b: u8) => IntMulHighUnsigned;
+    emit_int_neg(dst: u8, src: u8) => IntNeg;
+    emit_int_abs(dst: u8, src: u8) => IntAbs;
+    emit_int_inc(dst: u8) => IntInc;
+    emit_int_dec(dst: u8) => IntDec;
+    emit_int_min(dst: u8, a: u8, b: u8) => IntMin;
+    emit_int_max(dst: u8, a: u8, b: u8) => IntMax;
+    emit_bit_or(dst: u8, a: u8, b: u8) => BitOr;
+    emit_bit_and(dst: u8, a: u8, b: u8) => BitAnd;
+    emit_bit_xor(dst: u8, a: u8, b: u8) => BitXor;
+    emit_bit_not(dst: u8, src: u8) => BitNot;
+    emit_bit_shift_left(dst: u8, src: u8, amount: u8) => BitShiftLeft;
+    emit_bit_shift_right(dst: u8, src: u8, amount: u8) => BitShiftRight;
+    emit_bit_rotate_left(dst: u8, src: u8, amount: u8) => BitRotateLeft;
+    emit_bit_rotate_right(dst: u8, src: u8, amount: u8) => BitRotateRight;
+    emit_bit_select(dst: u8, mask: u8, a: u8, b: u8) => BitSelect;
+    emit_bit_popcnt(dst: u8, src: u8) => BitPopcnt;
+    emit_bit_reverse(dst: u8, src: u8) => BitReverse;
+    emit_branch_cmp(a: u8, b: u8, compare_kind: CompareKind, offset: u32) => BranchCmp;
+    emit_branch_zero(src: u8, offset: u32) => BranchZero;
+    emit_branch_non_zero(src: u8, offset: u32) => BranchNonZero;
+    emit_mem_load(dst: u8, bank: MemoryBank, addr: u32) => MemLoad;
+    emit_mem_store(bank: MemoryBank, addr: u32, src: u8) => MemStore;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Compiler, DefaultFrequencies, InstructionFrequencies, Runner as _};
+
+    #[test]
+    fn switches_to_jit() {
+        type F = DefaultFrequencies;
+        // Increment the first value in memory
+        let load = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE + F::INPUT_LOAD + 1);
+        let inc = u64::from(F::END_FUNC + F::CALL + F::INT_ADD + F::INT_SUB + F::INT_MUL)
+            + u64::from(F::INT_MUL_HIGH + F::INT_MUL_HIGH_UNSIGNED + F::INT_NEG + F::INT_ABS);
+        let store = (1 << 16) - u64::from(F::OUTPUT_STORE + F::MEM_STORE);
+
+        let mut compiler = Compiler::new(Tiered::with_jit_threshold(2));
+        let runner = compiler.compile(&[load, inc, store], 0, 1, 0, 0);
+
+        let mut memory = [0];
+        for step in 1..=4 {
+            assert_eq!(runner.is_jit_compiled(), step > 3);
+            runner.step(&mut memory);
+            assert_eq!(memory, [step]);
+        }
+        assert!(runner.is_jit_compiled());
+    }
+}
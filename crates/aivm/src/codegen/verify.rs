@@ -0,0 +1,239 @@
+use crate::{
+    codegen::{
+        self,
+        instruction::{Decoder, InstructionSink},
+        interpreter,
+        private::{CodeGeneratorImpl, Emitter as _},
+        CodeGenerator, Instruction, Interpreter,
+    },
+    DeadlineExceeded, HostFunctions, MemoryLayout, StepResult,
+};
+
+use std::{num::NonZeroU32, sync::Arc, time::Instant};
+
+/// A code generator that runs every step on both another code generator and the
+/// [Interpreter], and panics if the resulting memories differ.
+///
+/// This is meant for development, when a miscompile is suspected on a particular platform or
+/// combination of features: wrap the `Jit` in it and the first step that goes wrong panics,
+/// listing the values that differ. Every step runs twice, so host functions are also called twice, once on a
+/// copy of the memory.
+#[derive(Default)]
+pub struct Verify<G: CodeGenerator> {
+    gen: G,
+    interpreter: Interpreter,
+}
+
+impl<G: CodeGenerator> Verify<G> {
+    /// Create a generator that checks the code generated by `gen` against the interpreter.
+    pub fn new(gen: G) -> Self {
+        Self {
+            gen,
+            interpreter: Interpreter::new(),
+        }
+    }
+
+    /// The wrapped code generator.
+    pub fn inner(&self) -> &G {
+        &self.gen
+    }
+
+    /// Mutable access to the wrapped code generator.
+    pub fn inner_mut(&mut self) -> &mut G {
+        &mut self.gen
+    }
+
+    /// Take the wrapped code generator.
+    pub fn into_inner(self) -> G {
+        self.gen
+    }
+}
+
+impl<G: CodeGenerator> codegen::private::CodeGeneratorImpl for Verify<G> {
+    type Runner = Runner<G::Runner>;
+    type Emitter<'a>
+        = Decoder<Emitter<'a, G>>
+    where
+        Self: 'a;
+
+    fn set_host_functions(&mut self, host: &HostFunctions) {
+        self.gen.set_host_functions(host);
+        self.interpreter.set_host_functions(host);
+    }
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.gen.set_constants(constants);
+        self.interpreter.set_constants(constants);
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.gen.begin(function_count, layout);
+        self.interpreter.begin(function_count, layout);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        Decoder(Emitter {
+            inner: self.gen.begin_function(idx),
+            interpreter: self.interpreter.begin_function(idx),
+        })
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        Runner {
+            inner: self.gen.finish(),
+            interpreter: self.interpreter.finish(),
+        }
+    }
+}
+
+/// Runner returned by the [Verify] code generator.
+pub struct Runner<R> {
+    inner: R,
+    interpreter: interpreter::Runner,
+}
+
+impl<R: crate::Runner> crate::Runner for Runner<R> {
+    fn step(&self, memory: &mut [i64]) {
+        let mut expected = memory.to_vec();
+        self.interpreter.step(&mut expected);
+        self.inner.step(memory);
+        self.check(memory, &expected);
+    }
+
+    /// Panics if the results differ. The memories are only compared when both runners
+    /// completed, since running out of fuel leaves them unspecified.
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult {
+        let mut expected = memory.to_vec();
+        let expected_result = self.interpreter.step_bounded(&mut expected, fuel);
+        let result = self.inner.step_bounded(memory, fuel);
+        assert_eq!(
+            result,
+            expected_result,
+            "{} used a different amount of fuel than the interpreter",
+            std::any::type_name::<R>(),
+        );
+        if let StepResult::Completed { .. } = result {
+            self.check(memory, &expected);
+        }
+
+        result
+    }
+
+    /// The interpreter is only run when the wrapped runner completed before the deadline.
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded> {
+        let mut expected = memory.to_vec();
+        self.inner.step_until(memory, deadline)?;
+        self.interpreter.step(&mut expected);
+        self.check(memory, &expected);
+
+        Ok(())
+    }
+}
+
+impl<R: crate::RunnerInfo> crate::RunnerInfo for Runner<R> {
+    fn function_count(&self) -> u32 {
+        self.inner.function_count()
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.inner.instruction_count()
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        self.inner.code_size()
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.inner.layout()
+    }
+}
+
+impl<R> Runner<R> {
+    /// The runner of the wrapped code generator.
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    fn check(&self, actual: &[i64], expected: &[i64]) {
+        if actual == expected {
+            return;
+        }
+
+        let differences: Vec<_> = actual
+            .iter()
+            .zip(expected)
+            .enumerate()
+            .filter(|(_, (actual, expected))| actual != expected)
+            .map(|(i, (actual, expected))| format!("memory[{i}]: {actual} != {expected}"))
+            .collect();
+        panic!(
+            "{} diverged from the interpreter: {}",
+            std::any::type_name::<R>(),
+            differences.join(", "),
+        );
+    }
+}
+
+pub struct Emitter<'a, G: CodeGeneratorImpl + 'a> {
+    inner: G::Emitter<'a>,
+    interpreter: interpreter::Emitter<'a>,
+}
+
+impl<'a, G: CodeGeneratorImpl + 'a> InstructionSink for Emitter<'a, G> {
+    fn prepare_emit(&mut self, code_index: usize) {
+        self.inner.prepare_emit(code_index);
+        self.interpreter.prepare_emit(code_index);
+    }
+
+    fn finalize(self) {
+        self.inner.finalize();
+        self.interpreter.finalize();
+    }
+
+    fn instruction(&mut self, instruction: Instruction) {
+        instruction.emit(&mut self.inner);
+        instruction.emit(&mut self.interpreter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Bytecode,
+        testing::{random_code, random_memory, Xorshift},
+        Compiler, Runner as _,
+    };
+
+    #[test]
+    fn matching_runners() {
+        let mut rng = Xorshift::default();
+
+        let mut compiler = Compiler::new(Verify::new(Bytecode::new()));
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let mut memory = random_memory(&mut rng, 16);
+            let runner = compiler.compile(&code, 3, 8, 4, 4);
+            runner.step(&mut memory);
+            runner.step_bounded(&mut memory, 100);
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit() {
+        let mut compiler = Compiler::new(Verify::new(codegen::Jit::new()));
+        for vector in crate::spec::test_vectors() {
+            vector.run(&mut compiler);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn divergence() {
+        // Null runners don't run the code at all
+        let mut compiler = Compiler::new(Verify::new(codegen::NullGen::new()));
+        let code = [u64::MAX; 16];
+        compiler.compile(&code, 0, 1, 1, 0).step(&mut [0, 7]);
+    }
+}
@@ -0,0 +1,819 @@
+use crate::{
+    codegen,
+    compile::{CompareKind, REGISTER_COUNT},
+    BatchRunner, MemoryBank, MemoryLayout,
+};
+
+use std::{fmt, fmt::Write, num::NonZeroU32, sync::Arc};
+
+/// The amount of memories one workgroup runs on.
+const WORKGROUP_SIZE: u32 = 64;
+
+/// WGSL has no 64 bit integers in general, so values are pairs of 32 bit integers with the low
+/// half first, which matches the layout of an `i64` in a little endian buffer.
+const HELPERS: &str = "\
+fn add64(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    let low = a.x + b.x;
+    return vec2<u32>(low, a.y + b.y + select(0u, 1u, low < a.x));
+}
+
+fn sub64(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    return vec2<u32>(a.x - b.x, a.y - b.y - select(0u, 1u, a.x < b.x));
+}
+
+fn neg64(a: vec2<u32>) -> vec2<u32> {
+    return sub64(vec2<u32>(0u), a);
+}
+
+fn is_negative(a: vec2<u32>) -> bool {
+    return (a.y >> 31u) != 0u;
+}
+
+fn lt64(a: vec2<u32>, b: vec2<u32>) -> bool {
+    if a.y == b.y {
+        return a.x < b.x;
+    }
+    return bitcast<i32>(a.y) < bitcast<i32>(b.y);
+}
+
+fn mul_wide(a: u32, b: u32) -> vec2<u32> {
+    let a0 = a & 0xFFFFu;
+    let a1 = a >> 16u;
+    let b0 = b & 0xFFFFu;
+    let b1 = b >> 16u;
+    let p00 = a0 * b0;
+    let p01 = a0 * b1;
+    let p10 = a1 * b0;
+    let p11 = a1 * b1;
+    let mid = (p00 >> 16u) + (p01 & 0xFFFFu) + (p10 & 0xFFFFu);
+    return vec2<u32>((p00 & 0xFFFFu) | (mid << 16u), p11 + (p01 >> 16u) + (p10 >> 16u) + (mid >> 16u));
+}
+
+fn mul64(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    let low = mul_wide(a.x, b.x);
+    return vec2<u32>(low.x, low.y + a.x * b.y + a.y * b.x);
+}
+
+fn mul_high_unsigned64(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    let p00 = mul_wide(a.x, b.x);
+    let p01 = mul_wide(a.x, b.y);
+    let p10 = mul_wide(a.y, b.x);
+    let p11 = mul_wide(a.y, b.y);
+    let mid = add64(add64(vec2<u32>(p00.y, 0u), vec2<u32>(p01.x, 0u)), vec2<u32>(p10.x, 0u));
+    let high = add64(add64(p11, vec2<u32>(p01.y, 0u)), vec2<u32>(p10.y, 0u));
+    return add64(high, vec2<u32>(mid.y, 0u));
+}
+
+fn mul_high64(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    var high = mul_high_unsigned64(a, b);
+    if is_negative(a) {
+        high = sub64(high, b);
+    }
+    if is_negative(b) {
+        high = sub64(high, a);
+    }
+    return high;
+}
+
+fn shl64(a: vec2<u32>, n: u32) -> vec2<u32> {
+    if n == 0u {
+        return a;
+    }
+    if n < 32u {
+        return vec2<u32>(a.x << n, (a.y << n) | (a.x >> (32u - n)));
+    }
+    return vec2<u32>(0u, a.x << (n - 32u));
+}
+
+fn shr64(a: vec2<u32>, n: u32) -> vec2<u32> {
+    if n == 0u {
+        return a;
+    }
+    if n < 32u {
+        return vec2<u32>((a.x >> n) | (a.y << (32u - n)), a.y >> n);
+    }
+    return vec2<u32>(a.y >> (n - 32u), 0u);
+}
+
+fn sar64(a: vec2<u32>, n: u32) -> vec2<u32> {
+    let high = bitcast<i32>(a.y);
+    if n == 0u {
+        return a;
+    }
+    if n < 32u {
+        return vec2<u32>((a.x >> n) | (a.y << (32u - n)), bitcast<u32>(high >> n));
+    }
+    return vec2<u32>(bitcast<u32>(high >> (n - 32u)), bitcast<u32>(high >> 31u));
+}
+";
+
+/// The error returned when no GPU can be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoDevice {
+    reason: String,
+}
+
+impl NoDevice {
+    fn new(reason: impl fmt::Display) -> Self {
+        Self {
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for NoDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no usable GPU: {}", self.reason)
+    }
+}
+
+impl std::error::Error for NoDevice {}
+
+/// A code generator that translates AIVM code to a WGSL compute shader, which runs the code on
+/// thousands of memories at once on the GPU.
+///
+/// Code is run through the [BatchRunner] trait, every invocation of the shader runs the code once
+/// on its own memory. This only pays off for big batches, since the memories have to be copied
+/// to the GPU and back for every batch.
+pub struct Wgpu {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    translator: Translator,
+}
+
+impl Wgpu {
+    /// Create a new generator that runs code on the default GPU of the system.
+    pub fn new() -> Result<Self, NoDevice> {
+        let instance = wgpu::Instance::default();
+        let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        }))
+        .map_err(NoDevice::new)?;
+        let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+            label: Some("aivm"),
+            // Bigger batches need the biggest buffers the GPU allows
+            required_limits: adapter.limits(),
+            ..Default::default()
+        }))
+        .map_err(NoDevice::new)?;
+
+        Ok(Self::with_device(device, queue))
+    }
+
+    /// Create a new generator that runs code on a device that was created by the caller.
+    pub fn with_device(device: wgpu::Device, queue: wgpu::Queue) -> Self {
+        Self {
+            device,
+            queue,
+            translator: Translator::default(),
+        }
+    }
+}
+
+impl codegen::private::CodeGeneratorImpl for Wgpu {
+    type Runner = Runner;
+    type Emitter<'a> = Emitter<'a>;
+
+    fn set_constants(&mut self, constants: &Arc<[i64]>) {
+        self.translator.constants = constants.clone();
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.translator.begin(function_count, layout);
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+        self.translator.begin_function(idx)
+    }
+
+    fn finish(&mut self) -> Self::Runner {
+        let source = self.translator.render();
+        let module = self
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("aivm"),
+                source: wgpu::ShaderSource::Wgsl(source.as_str().into()),
+            });
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("aivm"),
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+
+        Runner {
+            device: self.device.clone(),
+            queue: self.queue.clone(),
+            pipeline,
+            source,
+            function_count: self.translator.functions.len() as u32,
+            instruction_count: self.translator.instruction_count(),
+            layout: self.translator.layout,
+        }
+    }
+}
+
+/// Runs code generated by the [Wgpu] code generator on the GPU.
+pub struct Runner {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    pipeline: wgpu::ComputePipeline,
+    source: String,
+    function_count: u32,
+    instruction_count: usize,
+    layout: MemoryLayout,
+}
+
+impl Runner {
+    /// The WGSL source of the compute shader.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// The memory layout the code was compiled for.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl crate::RunnerInfo for Runner {
+    fn function_count(&self) -> u32 {
+        self.function_count
+    }
+
+    fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    fn code_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+}
+
+impl BatchRunner for Runner {
+    fn step_strided(&self, memories: &mut [i64], stride: usize) {
+        assert!(stride >= self.layout.size() as usize);
+        assert_eq!(memories.len() % stride, 0);
+        let count = memories.len() / stride;
+        if count == 0 {
+            return;
+        }
+
+        let bytes: Vec<_> = memories.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let size = bytes.len() as u64;
+        let limit = self.device.limits().max_storage_buffer_binding_size;
+        assert!(
+            size <= limit,
+            "batch of {size} bytes is too big for the GPU"
+        );
+        let params = [
+            u32::try_from(stride).unwrap(),
+            u32::try_from(count).unwrap(),
+        ];
+
+        let buffer = |usage, size| {
+            self.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("aivm"),
+                size,
+                usage,
+                mapped_at_creation: false,
+            })
+        };
+        let storage = buffer(
+            wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            size,
+        );
+        let readback = buffer(
+            wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            size,
+        );
+        let uniform = buffer(
+            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            8,
+        );
+        self.queue.write_buffer(&storage, 0, &bytes);
+        self.queue
+            .write_buffer(&uniform, 0, &params.map(u32::to_le_bytes).concat());
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("aivm"),
+            layout: &self.pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: storage.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: uniform.as_entire_binding(),
+                },
+            ],
+        });
+
+        // Spread the workgroups over two dimensions, one is not enough for big batches
+        let groups = params[1].div_ceil(WORKGROUP_SIZE);
+        let x = groups.min(self.device.limits().max_compute_workgroups_per_dimension);
+        let y = groups.div_ceil(x);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("aivm"),
+            });
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(x, y, 1);
+        }
+        encoder.copy_buffer_to_buffer(&storage, 0, &readback, 0, size);
+        self.queue.submit([encoder.finish()]);
+
+        let slice = readback.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        self.device
+            .poll(wgpu::PollType::wait_indefinitely())
+            .unwrap();
+
+        let view = slice.get_mapped_range().unwrap();
+        for (value, bytes) in memories.iter_mut().zip(view.chunks_exact(8)) {
+            *value = i64::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+}
+
+/// Translates code to WGSL, separate from the device so it can be tested without a GPU.
+#[derive(Default)]
+struct Translator {
+    layout: MemoryLayout,
+    constants: Arc<[i64]>,
+    functions: Vec<Function>,
+}
+
+#[derive(Clone, Default)]
+struct Function {
+    lines: Vec<Line>,
+    /// The instructions that are the target of a branch.
+    branch_targets: Vec<u32>,
+    /// The amount of VM instructions, set when the function is finalized.
+    instruction_count: u32,
+}
+
+#[derive(Clone)]
+enum Line {
+    Statement(String),
+    /// The start of a block that is skipped when a branch jumped past the instruction.
+    Block(u32),
+}
+
+impl Translator {
+    fn instruction_count(&self) -> usize {
+        self.functions
+            .iter()
+            .map(|func| func.instruction_count as usize)
+            .sum()
+    }
+
+    fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+        self.layout = layout;
+        self.functions.clear();
+        self.functions.resize(
+            usize::try_from(function_count.get()).unwrap(),
+            Function::default(),
+        );
+    }
+
+    fn begin_function(&mut self, idx: u32) -> Emitter<'_> {
+        let func = &mut self.functions[usize::try_from(idx).unwrap()];
+        func.lines.clear();
+        func.branch_targets.clear();
+
+        Emitter {
+            func,
+            layout: self.layout,
+            constants: &self.constants,
+            instruction_count: 0,
+            after_branch: false,
+        }
+    }
+
+    fn render(&self) -> String {
+        // The loop that initializes the output is empty if the output is kept
+        let (output, value) = match self.layout.output_init.value() {
+            Some(value) => (self.layout.bank_range(MemoryBank::Output), value as u64),
+            None => (0..0, 0),
+        };
+        let scratch = self.layout.bank_range(MemoryBank::Scratch);
+        let mut source = format!("// Generated by aivm {}.\n\n", env!("CARGO_PKG_VERSION"));
+        source.push_str(HELPERS);
+        write!(
+            source,
+            "\nstruct Params {{\n    stride: u32,\n    count: u32,\n}}\n\n\
+             @group(0) @binding(0) var<storage, read_write> memories: array<vec2<u32>>;\n\
+             @group(0) @binding(1) var<uniform> params: Params;\n\n\
+             @compute @workgroup_size({WORKGROUP_SIZE})\n\
+             fn main(\n    \
+             @builtin(global_invocation_id) id: vec3<u32>,\n    \
+             @builtin(num_workgroups) groups: vec3<u32>,\n) {{\n    \
+             let index = id.x + id.y * groups.x * {WORKGROUP_SIZE}u;\n    \
+             if index >= params.count {{\n        return;\n    }}\n    \
+             let base = index * params.stride;\n    \
+             for (var i = {}u; i < {}u; i++) {{\n        \
+             memories[base + i] = vec2<u32>({}u, {}u);\n    }}\n    \
+             for (var i = {}u; i < {}u; i++) {{\n        \
+             memories[base + i] = vec2<u32>(0u);\n    }}\n    \
+             function_0(base);\n}}\n",
+            output.start,
+            output.end,
+            value as u32,
+            (value >> 32) as u32,
+            scratch.start,
+            scratch.end,
+        )
+        .unwrap();
+
+        for (idx, func) in self.functions.iter().enumerate() {
+            func.render(idx, &mut source);
+        }
+
+        source
+    }
+}
+
+impl Function {
+    fn render(&self, idx: usize, source: &mut String) {
+        writeln!(
+            source,
+            "\nfn function_{idx}(base: u32) {{\n    \
+             var r: array<vec2<u32>, {REGISTER_COUNT}>;"
+        )
+        .unwrap();
+        // WGSL has no goto, a branch sets the instruction to continue at and every block that
+        // comes before it is skipped. Branches only go forward so this is all that is needed.
+        if !self.branch_targets.is_empty() {
+            source.push_str("    var next = 0u;\n");
+        }
+
+        let mut indent = 4;
+        for line in &self.lines {
+            match line {
+                Line::Statement(statement) => {
+                    writeln!(source, "{:1$}{statement}", "", indent).unwrap()
+                }
+                Line::Block(start) => {
+                    if indent > 4 {
+                        source.push_str("    }\n");
+                    }
+                    writeln!(source, "    if next <= {start}u {{").unwrap();
+                    indent = 8;
+                }
+            }
+        }
+        if indent > 4 {
+            source.push_str("    }\n");
+        }
+
+        source.push_str("}\n");
+    }
+}
+
+pub struct Emitter<'a> {
+    func: &'a mut Function,
+    layout: MemoryLayout,
+    constants: &'a [i64],
+    instruction_count: u32,
+    /// Whether the previous instruction was a branch, the instructions after it need to be in
+    /// a new block.
+    after_branch: bool,
+}
+
+impl<'a> Emitter<'a> {
+    fn assign(&mut self, dst: u8, expr: std::fmt::Arguments) {
+        self.statement(format_args!("r[{dst}] = {expr};"));
+    }
+
+    fn statement(&mut self, statement: std::fmt::Arguments) {
+        self.func.lines.push(Line::Statement(statement.to_string()));
+    }
+
+    fn branch(&mut self, condition: std::fmt::Arguments, offset: u32) {
+        // The instruction counter already points at the next instruction
+        let target = self.instruction_count + offset;
+        if !self.func.branch_targets.contains(&target) {
+            self.func.branch_targets.push(target);
+        }
+        self.statement(format_args!("if {condition} {{ next = {target}u; }}"));
+        self.after_branch = true;
+    }
+}
+
+impl<'a> codegen::private::Emitter for Emitter<'a> {
+    fn prepare_emit(&mut self, _code_index: usize) {
+        let i = self.instruction_count;
+        if self.after_branch || self.func.branch_targets.contains(&i) {
+            self.func.lines.push(Line::Block(i));
+            self.after_branch = false;
+        }
+        self.instruction_count += 1;
+    }
+
+    fn finalize(self) {
+        self.func.instruction_count = self.instruction_count;
+    }
+
+    fn emit_call(&mut self, idx: u32) {
+        self.statement(format_args!("function_{idx}(base);"));
+    }
+    fn emit_nop(&mut self) {}
+
+    fn emit_host_call(&mut self, _id: u32) {
+        panic!("host calls are not supported by the wgpu code generator");
+    }
+
+    fn emit_int_add(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("add64(r[{a}], r[{b}])"));
+    }
+    fn emit_int_sub(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("sub64(r[{a}], r[{b}])"));
+    }
+    fn emit_int_mul(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("mul64(r[{a}], r[{b}])"));
+    }
+    fn emit_int_mul_high(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("mul_high64(r[{a}], r[{b}])"));
+    }
+    fn emit_int_mul_high_unsigned(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("mul_high_unsigned64(r[{a}], r[{b}])"));
+    }
+    fn emit_int_neg(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("neg64(r[{src}])"));
+    }
+    fn emit_int_abs(&mut self, dst: u8, src: u8) {
+        self.assign(
+            dst,
+            format_args!("select(r[{src}], neg64(r[{src}]), is_negative(r[{src}]))"),
+        );
+    }
+    fn emit_int_inc(&mut self, dst: u8) {
+        self.assign(dst, format_args!("add64(r[{dst}], vec2<u32>(1u, 0u))"));
+    }
+    fn emit_int_dec(&mut self, dst: u8) {
+        self.assign(dst, format_args!("sub64(r[{dst}], vec2<u32>(1u, 0u))"));
+    }
+    fn emit_int_min(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("select(r[{b}], r[{a}], lt64(r[{a}], r[{b}]))"),
+        );
+    }
+    fn emit_int_max(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("select(r[{b}], r[{a}], lt64(r[{b}], r[{a}]))"),
+        );
+    }
+
+    fn emit_bit_or(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] | r[{b}]"));
+    }
+    fn emit_bit_and(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] & r[{b}]"));
+    }
+    fn emit_bit_xor(&mut self, dst: u8, a: u8, b: u8) {
+        self.assign(dst, format_args!("r[{a}] ^ r[{b}]"));
+    }
+    fn emit_bit_not(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("~r[{src}]"));
+    }
+    fn emit_bit_shift_left(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("shl64(r[{src}], {amount}u)"));
+    }
+    fn emit_bit_shift_right(&mut self, dst: u8, src: u8, amount: u8) {
+        self.assign(dst, format_args!("sar64(r[{src}], {amount}u)"));
+    }
+    fn emit_bit_rotate_left(&mut self, dst: u8, src: u8, amount: u8) {
+        if amount == 0 {
+            self.assign(dst, format_args!("r[{src}]"));
+        } else {
+            self.assign(
+                dst,
+                format_args!(
+                    "shl64(r[{src}], {amount}u) | shr64(r[{src}], {}u)",
+                    64 - amount
+                ),
+            );
+        }
+    }
+    fn emit_bit_rotate_right(&mut self, dst: u8, src: u8, amount: u8) {
+        if amount == 0 {
+            self.assign(dst, format_args!("r[{src}]"));
+        } else {
+            self.assign(
+                dst,
+                format_args!(
+                    "shr64(r[{src}], {amount}u) | shl64(r[{src}], {}u)",
+                    64 - amount
+                ),
+            );
+        }
+    }
+    fn emit_bit_select(&mut self, dst: u8, mask: u8, a: u8, b: u8) {
+        self.assign(
+            dst,
+            format_args!("(r[{a}] & r[{mask}]) | (r[{b}] & ~r[{mask}])"),
+        );
+    }
+    fn emit_bit_popcnt(&mut self, dst: u8, src: u8) {
+        self.assign(
+            dst,
+            format_args!("vec2<u32>(countOneBits(r[{src}].x) + countOneBits(r[{src}].y), 0u)"),
+        );
+    }
+    fn emit_bit_reverse(&mut self, dst: u8, src: u8) {
+        self.assign(dst, format_args!("reverseBits(r[{src}].yx)"));
+    }
+
+    fn emit_branch_cmp(&mut self, a: u8, b: u8, compare_kind: CompareKind, offset: u32) {
+        let condition = match compare_kind {
+            CompareKind::Eq => format!("all(r[{a}] == r[{b}])"),
+            CompareKind::Neq => format!("any(r[{a}] != r[{b}])"),
+            CompareKind::Gt => format!("lt64(r[{b}], r[{a}])"),
+            CompareKind::Lt => format!("lt64(r[{a}], r[{b}])"),
+        };
+        self.branch(format_args!("{condition}"), offset);
+    }
+    fn emit_branch_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("all(r[{src}] == vec2<u32>(0u))"), offset);
+    }
+    fn emit_branch_non_zero(&mut self, src: u8, offset: u32) {
+        self.branch(format_args!("any(r[{src}] != vec2<u32>(0u))"), offset);
+    }
+
+    fn emit_mem_load(&mut self, dst: u8, bank: MemoryBank, addr: u32) {
+        if bank == MemoryBank::Const {
+            let value = self.constants[usize::try_from(addr).unwrap()] as u64;
+            let (low, high) = (value as u32, (value >> 32) as u32);
+            self.assign(dst, format_args!("vec2<u32>({low}u, {high}u)"));
+            return;
+        }
+        let addr = self.layout.address(bank, addr);
+        self.assign(dst, format_args!("memories[base + {addr}u]"));
+    }
+    fn emit_mem_store(&mut self, bank: MemoryBank, addr: u32, src: u8) {
+        if bank.is_writable() {
+            let addr = self.layout.address(bank, addr);
+            self.statement(format_args!("memories[base + {addr}u] = r[{src}];"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{differential, random_code, random_memory, Backend, Xorshift},
+        Compiler,
+    };
+
+    use wgpu::naga;
+
+    /// Only translates code, so the shaders can be checked without a GPU.
+    #[derive(Default)]
+    struct Source(Translator);
+
+    impl codegen::private::CodeGeneratorImpl for Source {
+        type Runner = String;
+        type Emitter<'a> = Emitter<'a>;
+
+        fn set_constants(&mut self, constants: &Arc<[i64]>) {
+            self.0.constants = constants.clone();
+        }
+
+        fn begin(&mut self, function_count: NonZeroU32, layout: MemoryLayout) {
+            self.0.begin(function_count, layout);
+        }
+
+        fn begin_function(&mut self, idx: u32) -> Self::Emitter<'_> {
+            self.0.begin_function(idx)
+        }
+
+        fn finish(&mut self) -> Self::Runner {
+            self.0.render()
+        }
+    }
+
+    fn validate(source: &str) {
+        let module = naga::front::wgsl::parse_str(source)
+            .unwrap_or_else(|e| panic!("{}\n{source}", e.emit_to_string(source)));
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            naga::valid::Capabilities::empty(),
+        )
+        .validate(&module)
+        .unwrap_or_else(|e| panic!("{}\n{source}", e.emit_to_string(source)));
+    }
+
+    fn random_programs() -> impl Iterator<Item = (Vec<u64>, Vec<i64>)> {
+        let mut rng = Xorshift::default();
+
+        std::iter::repeat_with(move || {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 16);
+            (code, memory)
+        })
+    }
+
+    #[test]
+    fn valid_wgsl() {
+        let mut compiler = Compiler::new(Source::default());
+        for vector in crate::spec::test_vectors() {
+            let layout = vector.layout;
+            validate(&compiler.compile(
+                &vector.code,
+                vector.lowest_function_level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            ));
+        }
+        for (code, _) in random_programs().take(20) {
+            validate(&compiler.compile(&code, 3, 8, 4, 4));
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let mut compiler = match Wgpu::new() {
+            Ok(gen) => Compiler::new(gen),
+            Err(e) => {
+                eprintln!("skipping, {e}");
+                return;
+            }
+        };
+
+        let mut interpreter = Compiler::new(Interpreter::new());
+        let wgpu = |code: &[u64], level, layout: MemoryLayout, memory: &mut [i64]| {
+            compiler.set_output_init(layout.output_init);
+            compiler.set_scratch_size(layout.scratch_size);
+            let runner = compiler.compile(
+                code,
+                level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            );
+
+            // Every memory in the batch is the same, so every result must be too
+            let mut batch = memory.repeat(100);
+            runner.step_strided(&mut batch, memory.len());
+            let (first, rest) = batch.split_at(memory.len());
+            assert!(rest.chunks_exact(memory.len()).all(|other| other == first));
+            memory.copy_from_slice(first);
+        };
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::from_fn("wgpu", wgpu),
+        ];
+        for (code, memory) in random_programs().take(20) {
+            differential(&code, 3, MemoryLayout::new(8, 4, 4), &memory, &mut backends).unwrap();
+        }
+    }
+
+    #[test]
+    fn matches_spec() {
+        let mut compiler = match Wgpu::new() {
+            Ok(gen) => Compiler::new(gen),
+            Err(e) => {
+                eprintln!("skipping, {e}");
+                return;
+            }
+        };
+
+        for vector in crate::spec::test_vectors() {
+            let layout = vector.layout;
+            let runner = compiler.compile(
+                &vector.code,
+                vector.lowest_function_level,
+                layout.memory_size,
+                layout.output_size,
+                layout.input_size,
+            );
+
+            let mut memory = vector.memory.clone();
+            runner.step_strided(&mut memory, vector.memory.len());
+            assert_eq!(memory, vector.expected, "{}", vector.name);
+        }
+    }
+}
@@ -1,15 +1,29 @@
+#[cfg(doc)]
+use crate::KIND_NAMES;
 use crate::{
     codegen::{private::Emitter, CodeGenerator},
-    DefaultFrequencies, InstructionFrequencies, Runner,
+    Channel, Channels, DefaultFrequencies, HostFunctions, InstructionFrequencies, MemoryBank,
+    MemoryLayout, OutputInit, KIND_COUNT,
 };
 
-use std::num::NonZeroU32;
+use std::{num::NonZeroU32, ops::Range, sync::Arc};
 
-#[derive(Debug, Clone, Copy)]
+/// The amount of bits used to encode a register operand in an instruction.
+const OPERAND_BITS: u32 = 6;
+const OPERAND_MASK: u8 = (1 << OPERAND_BITS) - 1;
+/// The amount of registers, or stack slots, that every function has access to.
+pub const REGISTER_COUNT: usize = 1 << OPERAND_BITS;
+
+/// The comparison of a conditional branch, on signed values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CompareKind {
+    /// Equal.
     Eq,
+    /// Not equal.
     Neq,
+    /// Greater than.
     Gt,
+    /// Less than.
     Lt,
 }
 
@@ -19,12 +33,132 @@ pub enum CompareKind {
 pub struct Compiler<G: CodeGenerator> {
     gen: G,
     funcs: Vec<Function>,
+    output_init: OutputInit,
+    scratch_size: u32,
+    host: HostFunctions,
+    constants: Arc<[i64]>,
+    channels: Channels,
+    layout_header: bool,
 }
 
 impl<G: CodeGenerator + 'static> Compiler<G> {
     /// Create a [Compiler] that will use the given code generator.
     pub fn new(gen: G) -> Self {
-        Self { gen, funcs: vec![] }
+        Self {
+            gen,
+            funcs: vec![],
+            output_init: OutputInit::Clear,
+            scratch_size: 0,
+            host: HostFunctions::new(),
+            constants: Arc::new([]),
+            channels: Channels::new(),
+            layout_header: false,
+        }
+    }
+
+    /// Split the input and output of code compiled from now on into named channels.
+    ///
+    /// The input load and output store instructions are divided into one equally likely group
+    /// per channel, and each group only accesses its own channel. Channels of size 0 turn their
+    /// group into nops. A bank without channels is a single channel, like the default.
+    ///
+    /// The `input_size` and `output_size` passed to [compile](Self::compile) must match the
+    /// combined size of the channels of a bank that has any, [Channels::layout] gives a layout
+    /// that does.
+    pub fn set_channels(&mut self, channels: Channels) {
+        self.channels = channels;
+    }
+
+    /// The channels set with [set_channels](Self::set_channels).
+    pub fn channels(&self) -> &Channels {
+        &self.channels
+    }
+
+    /// Set the values of the [Const](MemoryBank::Const) bank for code compiled from now on.
+    ///
+    /// The constants are embedded in the runner, so unlike the memory they do not have to be
+    /// passed to every step. They are read by the `const_load` instruction, which does nothing
+    /// when there are no constants.
+    pub fn set_constants(&mut self, constants: impl Into<Arc<[i64]>>) {
+        self.constants = constants.into();
+    }
+
+    /// The constants set with [set_constants](Self::set_constants).
+    pub fn constants(&self) -> &[i64] {
+        &self.constants
+    }
+
+    /// Register a function that code compiled from now on can call with the `host_call`
+    /// instruction, returning its id. See [HostFunctions].
+    ///
+    /// The instruction picks a function by taking its operand modulo the amount of registered
+    /// functions, and does nothing when there are none.
+    pub fn register_host_function<F>(&mut self, function: F) -> u32
+    where
+        F: Fn(&mut [i64]) + Send + Sync + 'static,
+    {
+        self.host.register(function)
+    }
+
+    /// The functions registered with [register_host_function](Self::register_host_function).
+    pub fn host_functions(&self) -> &HostFunctions {
+        &self.host
+    }
+
+    /// Set what happens to the output at the start of every step of the code that is compiled
+    /// from now on. The default is [OutputInit::Clear].
+    pub fn set_output_init(&mut self, output_init: OutputInit) {
+        self.output_init = output_init;
+    }
+
+    /// Set the size of the [Scratch](MemoryBank::Scratch) bank of code that is compiled from
+    /// now on, which follows the input in the memory slice. The default is 0.
+    ///
+    /// The scratch bank is cleared at the start of every step, and is accessed by the
+    /// `scratch_load` and `scratch_store` instructions, which do nothing when it is empty.
+    pub fn set_scratch_size(&mut self, scratch_size: u32) {
+        self.scratch_size = scratch_size;
+    }
+
+    /// Set whether code compiled from now on reads the sizes of the banks from a header at the
+    /// start of the memory, written with [MemoryLayout::write_header]. The default is `false`.
+    ///
+    /// Addresses are normally taken modulo the sizes passed to [compile](Self::compile), so the
+    /// runner only works on that layout. With a header they are taken modulo the sizes in the
+    /// header when the code runs instead, so one runner can be used with inputs and outputs of
+    /// any size, at the cost of slower memory accesses. The sizes passed to `compile` are only
+    /// reported by [RunnerInfo::layout](crate::RunnerInfo::layout), the output init and
+    /// constants still apply.
+    ///
+    /// Only the [Interpreter](crate::codegen::Interpreter) supports layout headers, compiling
+    /// with another code generator panics. Channels can not be combined with a header either.
+    pub fn set_layout_header(&mut self, layout_header: bool) {
+        self.layout_header = layout_header;
+    }
+
+    /// The code generator used by this compiler.
+    pub fn generator(&self) -> &G {
+        &self.gen
+    }
+
+    /// Mutable access to the code generator used by this compiler.
+    pub fn generator_mut(&mut self) -> &mut G {
+        &mut self.gen
+    }
+
+    /// A compiler with the same settings as this one, such as the constants, host functions
+    /// and channels, but using the code generator `gen`.
+    pub fn with_generator<H: CodeGenerator + 'static>(&self, gen: H) -> Compiler<H> {
+        Compiler {
+            gen,
+            funcs: vec![],
+            output_init: self.output_init,
+            scratch_size: self.scratch_size,
+            host: self.host.clone(),
+            constants: self.constants.clone(),
+            channels: self.channels.clone(),
+            layout_header: self.layout_header,
+        }
     }
 
     /// Compile the given code to a runner.
@@ -35,7 +169,8 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
     /// other levels .
     ///
     /// # Panics
-    /// If `function_levels == u32::MAX`.
+    /// If `function_levels == u32::MAX`, if the input or output channels do not add up to
+    /// `input_size` or `output_size`, or if channels are combined with a layout header.
     pub fn compile(
         &mut self,
         code: &[u64],
@@ -43,7 +178,7 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
         memory_size: u32,
         output_size: u32,
         input_size: u32,
-    ) -> impl Runner + 'static {
+    ) -> G::Runner {
         self.compile_with_frequencies::<DefaultFrequencies>(
             code,
             lowest_function_level,
@@ -61,37 +196,71 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
         memory_size: u32,
         output_size: u32,
         input_size: u32,
-    ) -> impl Runner + 'static {
-        assert_ne!(lowest_function_level, u32::MAX);
-
-        self.clear();
-
-        // Count the amount of functions and how many instructions they contain.
-        self.funcs.push(Function::new(0));
-        for (i, instruction) in code.iter().copied().enumerate() {
-            let kind = instruction as u16;
-
-            if kind < F::END_FUNC {
-                self.funcs.push(Function::new(i + 1));
-                continue;
-            }
+    ) -> G::Runner {
+        self.compile_with_table(
+            code,
+            lowest_function_level,
+            memory_size,
+            output_size,
+            input_size,
+            &F::table(),
+        )
+    }
 
-            self.funcs.last_mut().unwrap().instruction_count += 1;
+    /// Like [compile_with_frequencies](Self::compile_with_frequencies), but with the frequency
+    /// of every instruction kind in a table indexed like [KIND_NAMES], for frequencies that are
+    /// only known at runtime.
+    ///
+    /// # Panics
+    /// Like [compile](Self::compile), and if the frequencies add up to less than 2^16.
+    pub fn compile_with_table(
+        &mut self,
+        code: &[u64],
+        lowest_function_level: u32,
+        memory_size: u32,
+        output_size: u32,
+        input_size: u32,
+        frequencies: &[u16; KIND_COUNT],
+    ) -> G::Runner {
+        assert_ne!(lowest_function_level, u32::MAX);
+        assert!(
+            !self.layout_header || self.channels == Channels::new(),
+            "channels can't be combined with a layout header",
+        );
+        if !self.channels.inputs().is_empty() {
+            assert_eq!(
+                self.channels.input_size(),
+                input_size,
+                "input channels don't add up to the input size",
+            );
         }
-
-        self.funcs.retain(|func| func.instruction_count > 0);
-        if self.funcs.is_empty() {
-            self.funcs.push(Function::new(0));
+        if !self.channels.outputs().is_empty() {
+            assert_eq!(
+                self.channels.output_size(),
+                output_size,
+                "output channels don't add up to the output size",
+            );
         }
 
+        let layout = MemoryLayout::new(memory_size, output_size, input_size)
+            .with_output_init(self.output_init)
+            .with_scratch_size(self.scratch_size)
+            .with_const_size(u32::try_from(self.constants.len()).unwrap());
+        // Make sure all addresses fit in a u32
+        layout.size();
+
+        self.clear();
+
+        let [end_func, call, int_add, int_sub, int_mul, int_mul_high, int_mul_high_unsigned, int_neg, int_abs, int_inc, int_dec, int_min, int_max, bit_or, bit_and, bit_xor, bit_not, bit_shift_l, bit_shift_r, bit_rot_l, bit_rot_r, bit_select, bit_popcnt, bit_reverse, branch_cmp, branch_zero, branch_non_zero, mem_load, input_load, mem_store, output_store, host_call, const_load, scratch_load, scratch_store] =
+            *frequencies;
+        split_functions(code, end_func, &mut self.funcs);
         let func_count = u32::try_from(self.funcs.len()).unwrap();
-        let (level_size, _last_level_size) = if lowest_function_level == 0 {
-            (0, 0)
-        } else {
-            ceil_div_rem(func_count - 1, lowest_function_level)
-        };
+        let level_size = level_size(func_count, lowest_function_level);
 
-        self.gen.begin(NonZeroU32::new(func_count).unwrap());
+        self.gen.set_host_functions(&self.host);
+        self.gen.set_constants(&self.constants);
+        self.gen.set_layout_header(self.layout_header);
+        self.gen.begin(NonZeroU32::new(func_count).unwrap(), layout);
 
         for (f, func) in self
             .funcs
@@ -99,11 +268,7 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
             .enumerate()
             .map(|(f, func)| (f as u32, func))
         {
-            let cur_level = if f == 0 || level_size == 0 {
-                0
-            } else {
-                1 + (f - 1) / level_size
-            };
+            let cur_level = level_of(f, level_size);
             let mut emitter = self.gen.begin_function(f);
 
             let start = func.first_instruction;
@@ -111,79 +276,69 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
             for (i, instruction) in code[start..end].iter().copied().enumerate() {
                 let mut kind = instruction as u16;
 
-                let a = (instruction >> 16) as u8 & 0x3f;
-                let b = (instruction >> 22) as u8 & 0x3f;
+                let a = (instruction >> 16) as u8 & OPERAND_MASK;
+                let b = (instruction >> 22) as u8 & OPERAND_MASK;
                 // 4 bits unused
                 let imm = (instruction >> 32) as u32;
 
-                let c = (instruction >> 32) as u8 & 0x3f;
-                let d = (instruction >> 46) as u8 & 0x3f;
+                let c = (instruction >> 32) as u8 & OPERAND_MASK;
+                let d = (instruction >> 46) as u8 & OPERAND_MASK;
 
-                emitter.prepare_emit();
+                emitter.prepare_emit(start + i);
 
                 // Never included in the function body.
-                kind -= F::END_FUNC;
+                kind -= end_func;
 
-                if cmp_freq(&mut kind, F::CALL) {
-                    if level_size == 0 {
-                        // Can never call the entry point
-                        emitter.emit_nop();
-                    } else {
-                        let min_idx = 1 + cur_level * level_size;
-                        // Saturating sub to handle the last, potentially partially filled, level
-                        let callable_count = func_count.saturating_sub(min_idx);
-                        if callable_count == 0 {
-                            emitter.emit_nop();
-                        } else {
-                            let offset = imm % callable_count;
-                            emitter.emit_call(min_idx + offset);
-                        }
+                if cmp_freq(&mut kind, call) {
+                    match call_target(func_count, level_size, cur_level, imm) {
+                        Some(target) => emitter.emit_call(target),
+                        None => emitter.emit_nop(),
                     }
-                } else if cmp_freq(&mut kind, F::INT_ADD) {
+                } else if cmp_freq(&mut kind, int_add) {
                     emitter.emit_int_add(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_SUB) {
+                } else if cmp_freq(&mut kind, int_sub) {
                     emitter.emit_int_sub(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_MUL) {
+                } else if cmp_freq(&mut kind, int_mul) {
                     emitter.emit_int_mul(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_MUL_HIGH) {
+                } else if cmp_freq(&mut kind, int_mul_high) {
                     emitter.emit_int_mul_high(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_MUL_HIGH_UNSIGNED) {
+                } else if cmp_freq(&mut kind, int_mul_high_unsigned) {
                     emitter.emit_int_mul_high_unsigned(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_NEG) {
+                } else if cmp_freq(&mut kind, int_neg) {
                     emitter.emit_int_neg(a, b);
-                } else if cmp_freq(&mut kind, F::INT_ABS) {
+                } else if cmp_freq(&mut kind, int_abs) {
                     emitter.emit_int_abs(a, b);
-                } else if cmp_freq(&mut kind, F::INT_INC) {
+                } else if cmp_freq(&mut kind, int_inc) {
                     emitter.emit_int_inc(a);
-                } else if cmp_freq(&mut kind, F::INT_DEC) {
+                } else if cmp_freq(&mut kind, int_dec) {
                     emitter.emit_int_dec(a);
-                } else if cmp_freq(&mut kind, F::INT_MIN) {
+                } else if cmp_freq(&mut kind, int_min) {
                     emitter.emit_int_min(a, b, c);
-                } else if cmp_freq(&mut kind, F::INT_MAX) {
+                } else if cmp_freq(&mut kind, int_max) {
                     emitter.emit_int_max(a, b, c);
-                } else if cmp_freq(&mut kind, F::BIT_OR) {
+                } else if cmp_freq(&mut kind, bit_or) {
                     emitter.emit_bit_or(a, b, c);
-                } else if cmp_freq(&mut kind, F::BIT_AND) {
+                } else if cmp_freq(&mut kind, bit_and) {
                     emitter.emit_bit_and(a, b, c);
-                } else if cmp_freq(&mut kind, F::BIT_XOR) {
+                } else if cmp_freq(&mut kind, bit_xor) {
                     emitter.emit_bit_xor(a, b, c);
-                } else if cmp_freq(&mut kind, F::BIT_NOT) {
+                } else if cmp_freq(&mut kind, bit_not) {
                     emitter.emit_bit_not(a, b);
-                } else if cmp_freq(&mut kind, F::BIT_SHIFT_L) {
+                } else if cmp_freq(&mut kind, bit_shift_l) {
                     emitter.emit_bit_shift_left(a, b, c & 0x3F);
-                } else if cmp_freq(&mut kind, F::BIT_SHIFT_R) {
+                } else if cmp_freq(&mut kind, bit_shift_r) {
                     emitter.emit_bit_shift_right(a, b, c & 0x3F);
-                } else if cmp_freq(&mut kind, F::BIT_ROT_L) {
+                } else if cmp_freq(&mut kind, bit_rot_l) {
                     emitter.emit_bit_rotate_left(a, b, c & 0x3F);
-                } else if cmp_freq(&mut kind, F::BIT_ROT_R) {
+                } else if cmp_freq(&mut kind, bit_rot_r) {
                     emitter.emit_bit_rotate_right(a, b, c & 0x3F);
-                } else if cmp_freq(&mut kind, F::BIT_SELECT) {
+                } else if cmp_freq(&mut kind, bit_select) {
                     emitter.emit_bit_select(a, b, c, d);
-                } else if cmp_freq(&mut kind, F::BIT_POPCNT) {
+                } else if cmp_freq(&mut kind, bit_popcnt) {
                     emitter.emit_bit_popcnt(a, b);
-                } else if cmp_freq(&mut kind, F::BIT_REVERSE) {
+                } else if cmp_freq(&mut kind, bit_reverse) {
                     emitter.emit_bit_reverse(a, b);
-                } else if cmp_freq(&mut kind, F::BRANCH_CMP) {
+                } else if cmp_freq(&mut kind, branch_cmp) {
                     if let Some(offset) = branch_offset(imm, func, i as u32) {
                         let compare_kind = match a & 3 {
                             0 => CompareKind::Eq,
@@ -196,43 +351,86 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::BRANCH_ZERO) {
+                } else if cmp_freq(&mut kind, branch_zero) {
                     if let Some(offset) = branch_offset(imm, func, i as u32) {
                         emitter.emit_branch_zero(a, offset);
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::BRANCH_NON_ZERO) {
+                } else if cmp_freq(&mut kind, branch_non_zero) {
                     if let Some(offset) = branch_offset(imm, func, i as u32) {
                         emitter.emit_branch_non_zero(a, offset);
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::MEM_LOAD) {
-                    if memory_size != 0 {
+                } else if cmp_freq(&mut kind, mem_load) {
+                    if self.layout_header {
+                        emitter.emit_header_mem_load(a, MemoryBank::Memory, imm);
+                    } else if memory_size != 0 {
                         let addr = imm % memory_size;
-                        emitter.emit_mem_load(a, addr);
+                        emitter.emit_mem_load(a, MemoryBank::Memory, addr);
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::INPUT_LOAD) {
-                    if input_size != 0 {
-                        let addr = imm % input_size;
-                        emitter.emit_mem_load(a, memory_size + output_size + addr);
+                } else if cmp_freq(&mut kind, input_load) {
+                    let channels = self.channels.inputs();
+                    if self.layout_header {
+                        emitter.emit_header_mem_load(a, MemoryBank::Input, imm);
+                    } else if let Some(addr) =
+                        channel_address(channels, input_size, kind, input_load, imm)
+                    {
+                        emitter.emit_mem_load(a, MemoryBank::Input, addr);
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::MEM_STORE) {
-                    if memory_size != 0 {
+                } else if cmp_freq(&mut kind, mem_store) {
+                    if self.layout_header {
+                        emitter.emit_header_mem_store(MemoryBank::Memory, imm, a);
+                    } else if memory_size != 0 {
                         let addr = imm % memory_size;
-                        emitter.emit_mem_store(addr, a);
+                        emitter.emit_mem_store(MemoryBank::Memory, addr, a);
+                    } else {
+                        emitter.emit_nop();
+                    }
+                } else if cmp_freq(&mut kind, output_store) {
+                    let channels = self.channels.outputs();
+                    if self.layout_header {
+                        emitter.emit_header_mem_store(MemoryBank::Output, imm, a);
+                    } else if let Some(addr) =
+                        channel_address(channels, output_size, kind, output_store, imm)
+                    {
+                        emitter.emit_mem_store(MemoryBank::Output, addr, a);
+                    } else {
+                        emitter.emit_nop();
+                    }
+                } else if cmp_freq(&mut kind, host_call) {
+                    if self.host.is_empty() {
+                        emitter.emit_nop();
+                    } else {
+                        emitter.emit_host_call(imm % self.host.len());
+                    }
+                } else if cmp_freq(&mut kind, const_load) {
+                    if layout.const_size != 0 {
+                        let addr = imm % layout.const_size;
+                        emitter.emit_mem_load(a, MemoryBank::Const, addr);
                     } else {
                         emitter.emit_nop();
                     }
-                } else if cmp_freq(&mut kind, F::OUTPUT_STORE) {
-                    if output_size != 0 {
-                        let addr = imm % output_size;
-                        emitter.emit_mem_store(memory_size + addr, a);
+                } else if cmp_freq(&mut kind, scratch_load) {
+                    if self.layout_header {
+                        emitter.emit_header_mem_load(a, MemoryBank::Scratch, imm);
+                    } else if self.scratch_size != 0 {
+                        let addr = imm % self.scratch_size;
+                        emitter.emit_mem_load(a, MemoryBank::Scratch, addr);
+                    } else {
+                        emitter.emit_nop();
+                    }
+                } else if cmp_freq(&mut kind, scratch_store) {
+                    if self.layout_header {
+                        emitter.emit_header_mem_store(MemoryBank::Scratch, imm, a);
+                    } else if self.scratch_size != 0 {
+                        let addr = imm % self.scratch_size;
+                        emitter.emit_mem_store(MemoryBank::Scratch, addr, a);
                     } else {
                         emitter.emit_nop();
                     }
@@ -244,7 +442,7 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
             emitter.finalize();
         }
 
-        self.gen.finish(memory_size, input_size, output_size)
+        self.gen.finish()
     }
 
     fn clear(&mut self) {
@@ -252,6 +450,159 @@ impl<G: CodeGenerator + 'static> Compiler<G> {
     }
 }
 
+/// The functions of code and the functions they call, as the compiler sees them.
+///
+/// Functions are numbered like in the compiled code, so function 0 is the entry point and
+/// functions without instructions are skipped. Code in functions that can not be reached from
+/// the entry point never runs, so it can be removed from a program without changing what it
+/// does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+    instructions: Vec<Range<usize>>,
+    callees: Vec<Vec<u32>>,
+}
+
+impl CallGraph {
+    /// Analyze `code` as it would be compiled with `lowest_function_level`, see
+    /// [Compiler::compile].
+    pub fn new(code: &[u64], lowest_function_level: u32) -> Self {
+        Self::with_frequencies::<DefaultFrequencies>(code, lowest_function_level)
+    }
+
+    /// Like [new](Self::new), but using custom instruction frequencies.
+    pub fn with_frequencies<F: InstructionFrequencies>(
+        code: &[u64],
+        lowest_function_level: u32,
+    ) -> Self {
+        Self::with_table(code, lowest_function_level, &F::table())
+    }
+
+    /// Like [with_frequencies](Self::with_frequencies), but with the frequencies in a table
+    /// like [Compiler::compile_with_table].
+    pub fn with_table(
+        code: &[u64],
+        lowest_function_level: u32,
+        frequencies: &[u16; KIND_COUNT],
+    ) -> Self {
+        let (end_func, call) = (frequencies[0], frequencies[1]);
+        let mut funcs = vec![];
+        split_functions(code, end_func, &mut funcs);
+        let func_count = u32::try_from(funcs.len()).unwrap();
+        let level_size = level_size(func_count, lowest_function_level);
+
+        let instructions: Vec<_> = funcs
+            .iter()
+            .map(|func| {
+                let end = func.first_instruction + usize::try_from(func.instruction_count).unwrap();
+                func.first_instruction..end
+            })
+            .collect();
+        let callees = instructions
+            .iter()
+            .enumerate()
+            .map(|(f, range)| {
+                let mut callees: Vec<_> = code[range.clone()]
+                    .iter()
+                    .filter(|&&instruction| (instruction as u16 - end_func) < call)
+                    .filter_map(|&instruction| {
+                        let imm = (instruction >> 32) as u32;
+                        call_target(func_count, level_size, level_of(f as u32, level_size), imm)
+                    })
+                    .collect();
+                callees.sort_unstable();
+                callees.dedup();
+                callees
+            })
+            .collect();
+
+        Self {
+            instructions,
+            callees,
+        }
+    }
+
+    /// The amount of functions.
+    pub fn function_count(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// The indices in the code of the instructions of `function`, which do not include the
+    /// words that end functions.
+    pub fn instructions(&self, function: usize) -> Range<usize> {
+        self.instructions[function].clone()
+    }
+
+    /// The functions `function` calls, in ascending order.
+    pub fn callees(&self, function: usize) -> &[u32] {
+        &self.callees[function]
+    }
+
+    /// Whether every function can be reached from the entry point through calls.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.function_count()];
+        let mut stack = vec![0];
+        while let Some(function) = stack.pop() {
+            if !std::mem::replace(&mut reachable[function], true) {
+                stack.extend(self.callees[function].iter().map(|&callee| callee as usize));
+            }
+        }
+
+        reachable
+    }
+}
+
+/// Split `code` into the functions the compiler emits, dropping the ones without instructions.
+fn split_functions(code: &[u64], end_func: u16, funcs: &mut Vec<Function>) {
+    funcs.push(Function::new(0));
+    for (i, instruction) in code.iter().copied().enumerate() {
+        let kind = instruction as u16;
+
+        if kind < end_func {
+            funcs.push(Function::new(i + 1));
+            continue;
+        }
+
+        funcs.last_mut().unwrap().instruction_count += 1;
+    }
+
+    funcs.retain(|func| func.instruction_count > 0);
+    if funcs.is_empty() {
+        funcs.push(Function::new(0));
+    }
+}
+
+/// The amount of functions in every level but the entry point's, or 0 if the entry point is
+/// the only level.
+fn level_size(func_count: u32, lowest_function_level: u32) -> u32 {
+    if lowest_function_level == 0 {
+        0
+    } else {
+        ceil_div_rem(func_count - 1, lowest_function_level).0
+    }
+}
+
+fn level_of(func: u32, level_size: u32) -> u32 {
+    if func == 0 || level_size == 0 {
+        0
+    } else {
+        1 + (func - 1) / level_size
+    }
+}
+
+/// The function called by a `call` with `imm` in a function of `cur_level`, or `None` if
+/// nothing can be called from there.
+fn call_target(func_count: u32, level_size: u32, cur_level: u32, imm: u32) -> Option<u32> {
+    if level_size == 0 {
+        // Can never call the entry point
+        return None;
+    }
+
+    let min_idx = 1 + cur_level * level_size;
+    // Saturating sub to handle the last, potentially partially filled, level
+    let callable_count = func_count.saturating_sub(min_idx);
+    (callable_count != 0).then(|| min_idx + imm % callable_count)
+}
+
 #[inline]
 fn ceil_div_rem(x: u32, y: u32) -> (u32, u32) {
     let div = x / y;
@@ -286,6 +637,29 @@ fn branch_offset(imm: u32, func: &Function, cur_instruction: u32) -> Option<u32>
     None
 }
 
+/// The address in a bank accessed by an input load or output store, or `None` if it accesses
+/// an empty channel.
+///
+/// `kind` is the offset of the instruction kind within its group of `group_freq` kinds, which
+/// is split evenly between the channels.
+#[inline]
+fn channel_address(
+    channels: &[Channel],
+    bank_size: u32,
+    kind: u16,
+    group_freq: u16,
+    imm: u32,
+) -> Option<u32> {
+    let (offset, size) = if channels.is_empty() {
+        (0, bank_size)
+    } else {
+        let channel = &channels[usize::from(kind) * channels.len() / usize::from(group_freq)];
+        (channel.offset(), channel.size())
+    };
+
+    (size != 0).then(|| offset + imm % size)
+}
+
 struct Function {
     first_instruction: usize,
     instruction_count: u32,
@@ -299,3 +673,70 @@ impl Function {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{random_code, random_memory, Xorshift},
+        Runner as _,
+    };
+
+    const END: u64 = 0;
+    const NOP: u64 = 0xA000;
+
+    fn call(imm: u32) -> u64 {
+        u64::from(DefaultFrequencies::END_FUNC) | u64::from(imm) << 32
+    }
+
+    #[test]
+    fn call_graph() {
+        let code = [call(0), NOP, END, NOP, call(5), END, END, NOP];
+        let graph = CallGraph::new(&code, 1);
+        assert_eq!(graph.function_count(), 3);
+        assert_eq!(graph.instructions(1), 3..5);
+        // Function 0 calls function 1, which is in the lowest level and can not call anything
+        assert_eq!(graph.callees(0), [1]);
+        assert!(graph.callees(1).is_empty());
+        assert_eq!(graph.reachable(), [true, true, false]);
+
+        let graph = CallGraph::new(&code, 0);
+        assert!(graph.callees(0).is_empty());
+        assert_eq!(graph.reachable(), [true, false, false]);
+    }
+
+    #[test]
+    fn table() {
+        struct LongFunctions;
+        impl InstructionFrequencies for LongFunctions {
+            const END_FUNC: u16 = 1000;
+            const INT_ADD: u16 = 565;
+        }
+        assert_eq!(LongFunctions::sum_delta(), 0);
+        let table = LongFunctions::table();
+
+        let code = [NOP, 500, NOP, call(1), END];
+        assert_eq!(
+            CallGraph::with_table(&code, 1, &table),
+            CallGraph::with_frequencies::<LongFunctions>(&code, 1)
+        );
+        assert_eq!(CallGraph::with_table(&code, 1, &table).function_count(), 2);
+
+        let mut rng = Xorshift::default();
+        let mut original = Compiler::new(Interpreter::new());
+        original.set_constants([5, -3]);
+        let mut compiler = original.with_generator(Interpreter::new());
+        assert_eq!(compiler.constants(), [5, -3]);
+        for _ in 0..20 {
+            let code = random_code(&mut rng, 64);
+            let memory = random_memory(&mut rng, MemoryLayout::new(8, 2, 2).size() as usize);
+            let expected = compiler.compile_with_frequencies::<LongFunctions>(&code, 2, 8, 2, 2);
+            let runner = compiler.compile_with_table(&code, 2, 8, 2, 2, &table);
+            let (mut a, mut b) = (memory.clone(), memory);
+            expected.step_bounded(&mut a, 1000);
+            runner.step_bounded(&mut b, 1000);
+            assert_eq!(a, b);
+        }
+    }
+}
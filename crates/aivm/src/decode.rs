@@ -0,0 +1,110 @@
+use std::cmp::Reverse;
+
+/// The index of the highest value, the first one if several are equally high, or `None` if
+/// `values` is empty.
+pub fn argmax(values: &[i64]) -> Option<usize> {
+    values
+        .iter()
+        .enumerate()
+        .max_by_key(|&(i, &value)| (value, Reverse(i)))
+        .map(|(i, _)| i)
+}
+
+/// The indices of the `k` highest values, from high to low. Equal values are ranked by their
+/// index, and fewer indices are returned if there are fewer than `k` values.
+pub fn top_k(values: &[i64], k: usize) -> Vec<usize> {
+    let mut indices: Vec<_> = (0..values.len()).collect();
+    indices.sort_by_key(|&i| Reverse(values[i]));
+    indices.truncate(k);
+    indices
+}
+
+/// Whether each value is above `threshold`, for example to decide which of a set of
+/// independent buttons are pressed.
+pub fn above(values: &[i64], threshold: i64) -> impl Iterator<Item = bool> + '_ {
+    values.iter().map(move |&value| value > threshold)
+}
+
+/// Decode a value as -1, 0 or 1 by its sign, where values within `dead_zone` of zero count as
+/// 0. This suits actions such as steering left, straight or right.
+pub fn sign(value: i64, dead_zone: u64) -> i8 {
+    if value.unsigned_abs() <= dead_zone {
+        0
+    } else {
+        value.signum() as i8
+    }
+}
+
+/// Turn values into probabilities that add up to 1, in proportion to how far each value is
+/// above the lowest one.
+///
+/// Unlike a softmax this needs no exponentials, which would overflow for the large values VM
+/// code tends to produce. If all values are equal, every index is equally likely. Returns an
+/// empty vector for empty `values`.
+pub fn weights(values: &[i64]) -> Vec<f64> {
+    let Some(&min) = values.iter().min() else {
+        return vec![];
+    };
+    // Differences are computed in i128 so they can not overflow
+    let shifted: Vec<_> = values
+        .iter()
+        .map(|&value| (i128::from(value) - i128::from(min)) as f64)
+        .collect();
+    let total: f64 = shifted.iter().sum();
+    if total == 0.0 {
+        return vec![1.0 / values.len() as f64; values.len()];
+    }
+
+    shifted.into_iter().map(|weight| weight / total).collect()
+}
+
+/// Pick an index with the probabilities of [weights], given `uniform`, a random number in
+/// `0.0..1.0`. Returns `None` if `values` is empty.
+pub fn sample(values: &[i64], uniform: f64) -> Option<usize> {
+    let weights = weights(values);
+    let mut remaining = uniform;
+    for (i, &weight) in weights.iter().enumerate() {
+        if remaining < weight {
+            return Some(i);
+        }
+        remaining -= weight;
+    }
+
+    // Rounding errors can leave a little bit of probability for after the last index
+    weights.iter().rposition(|&weight| weight > 0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rankings() {
+        assert_eq!(argmax(&[3, 9, -1, 9]), Some(1));
+        assert_eq!(argmax(&[]), None);
+        assert_eq!(top_k(&[3, 9, -1, 9], 3), [1, 3, 0]);
+        assert_eq!(top_k(&[5], 2), [0]);
+    }
+
+    #[test]
+    fn thresholds() {
+        let pressed: Vec<_> = above(&[-1, 0, 1], 0).collect();
+        assert_eq!(pressed, [false, false, true]);
+        assert_eq!(sign(-100, 10), -1);
+        assert_eq!(sign(-10, 10), 0);
+        assert_eq!(sign(i64::MIN, u64::MAX), 0);
+        assert_eq!(sign(11, 10), 1);
+    }
+
+    #[test]
+    fn probabilities() {
+        assert_eq!(weights(&[1, 3, 2]), [0.0, 2.0 / 3.0, 1.0 / 3.0]);
+        assert_eq!(weights(&[4, 4]), [0.5, 0.5]);
+        assert_eq!(weights(&[i64::MIN, i64::MAX]), [0.0, 1.0]);
+
+        assert_eq!(sample(&[1, 3, 2], 0.0), Some(1));
+        assert_eq!(sample(&[1, 3, 2], 0.7), Some(2));
+        assert_eq!(sample(&[1, 3, 2], 1.0), Some(2));
+        assert_eq!(sample(&[], 0.5), None);
+    }
+}
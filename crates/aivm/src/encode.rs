@@ -0,0 +1,186 @@
+use crate::fixed::Fixed;
+
+/// The amount of values [bytes] needs for `len` bytes.
+pub const fn bytes_len(len: usize) -> usize {
+    len.div_ceil(8)
+}
+
+/// The amount of values [bits] needs for `len` bits.
+pub const fn bits_len(len: usize) -> usize {
+    len.div_ceil(64)
+}
+
+/// Encode a categorical value as 1 at index `category` and 0 everywhere else.
+///
+/// # Panics
+/// If `category` is out of bounds of `out`.
+pub fn one_hot(category: usize, out: &mut [i64]) {
+    assert!(
+        category < out.len(),
+        "category {category} out of bounds for {} values",
+        out.len(),
+    );
+    out.fill(0);
+    out[category] = 1;
+}
+
+/// Pack a byte string into values of 8 little endian bytes each, setting the values after it
+/// to 0.
+///
+/// # Panics
+/// If `out` is shorter than [bytes_len] of the amount of bytes.
+pub fn bytes(bytes: &[u8], out: &mut [i64]) {
+    assert!(
+        bytes_len(bytes.len()) <= out.len(),
+        "{} bytes do not fit in {} values",
+        bytes.len(),
+        out.len(),
+    );
+    out.fill(0);
+    for (out, chunk) in out.iter_mut().zip(bytes.chunks(8)) {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        *out = i64::from_le_bytes(word);
+    }
+}
+
+/// Pack bits, such as the cells of a bitboard, into values of 64 bits each, setting the values
+/// after them to 0. Bit `i` ends up in bit `i % 64` of value `i / 64`.
+///
+/// # Panics
+/// If there are more bits than fit in `out`.
+pub fn bits(bits: impl IntoIterator<Item = bool>, out: &mut [i64]) {
+    out.fill(0);
+    for (i, bit) in bits.into_iter().enumerate() {
+        assert!(
+            i / 64 < out.len(),
+            "bits do not fit in {} values",
+            out.len()
+        );
+        out[i / 64] |= i64::from(bit) << (i % 64);
+    }
+}
+
+/// Map `value` from the range `min..=max` to fixed point between 0.0 and 1.0, clamping values
+/// outside of the range.
+///
+/// # Panics
+/// If `min` is not less than `max`.
+pub fn normalized(value: f64, min: f64, max: f64, fixed: Fixed) -> i64 {
+    assert!(min < max, "empty range {min}..={max}");
+    fixed.from_f64(((value - min) / (max - min)).clamp(0.0, 1.0))
+}
+
+/// Writes encoded observations one after another, for example into
+/// [MemoryBuffer::input_mut](crate::MemoryBuffer::input_mut) or an input channel.
+///
+/// Every method panics if the encoding does not fit in the remaining values.
+#[derive(Debug)]
+pub struct Encoder<'a> {
+    out: &'a mut [i64],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    /// Create an encoder that writes to `out`, starting at the first value.
+    pub fn new(out: &'a mut [i64]) -> Self {
+        Self { out, len: 0 }
+    }
+
+    /// The amount of values written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether no values have been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The amount of values that can still be written.
+    pub fn remaining(&self) -> usize {
+        self.out.len() - self.len
+    }
+
+    fn take(&mut self, count: usize) -> &mut [i64] {
+        assert!(
+            count <= self.remaining(),
+            "{count} values do not fit in the remaining {}",
+            self.remaining(),
+        );
+        self.len += count;
+        &mut self.out[self.len - count..self.len]
+    }
+
+    /// Write a value as it is.
+    pub fn value(&mut self, value: i64) -> &mut Self {
+        self.take(1)[0] = value;
+        self
+    }
+
+    /// Write a categorical value out of `categories` with [one_hot].
+    pub fn one_hot(&mut self, category: usize, categories: usize) -> &mut Self {
+        one_hot(category, self.take(categories));
+        self
+    }
+
+    /// Write a byte string with [bytes], taking [bytes_len] values.
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self::bytes(bytes, self.take(bytes_len(bytes.len())));
+        self
+    }
+
+    /// Write `len` bits with [bits], taking [bits_len] values.
+    ///
+    /// # Panics
+    /// If `bits` yields more bits than fit in those values.
+    pub fn bits(&mut self, bits: impl IntoIterator<Item = bool>, len: usize) -> &mut Self {
+        self::bits(bits, self.take(bits_len(len)));
+        self
+    }
+
+    /// Write a value in the range `min..=max` with [normalized].
+    pub fn normalized(&mut self, value: f64, min: f64, max: f64, fixed: Fixed) -> &mut Self {
+        self.value(normalized(value, min, max, fixed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryBuffer, MemoryLayout};
+
+    #[test]
+    fn encoders() {
+        let mut out = [7; 3];
+        one_hot(1, &mut out);
+        assert_eq!(out, [0, 1, 0]);
+
+        bytes(b"abcdefghi", &mut out);
+        assert_eq!(out, [i64::from_le_bytes(*b"abcdefgh"), i64::from(b'i'), 0]);
+
+        let board = (0..130).map(|i| i % 64 == 0 || i == 129);
+        bits(board, &mut out);
+        assert_eq!(out, [1, 1, 0b11]);
+
+        let fixed = Fixed::with_scale(100.0);
+        assert_eq!(normalized(5.0, 0.0, 20.0, fixed), 25);
+        assert_eq!(normalized(-5.0, 0.0, 20.0, fixed), 0);
+        assert_eq!(normalized(50.0, 0.0, 20.0, fixed), 100);
+    }
+
+    #[test]
+    fn encoder_into_buffer() {
+        let mut buffer = MemoryBuffer::new(MemoryLayout::new(1, 1, 5));
+        let mut encoder = Encoder::new(buffer.input_mut());
+        encoder.one_hot(2, 3).bits([true, false, true], 3).value(-9);
+        assert_eq!(encoder.remaining(), 0);
+        assert_eq!(buffer.input(), [0, 0, 1, 0b101, -9]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn encoder_overflow() {
+        Encoder::new(&mut [0; 2]).bytes(&[1; 9]).value(0);
+    }
+}
@@ -0,0 +1,142 @@
+/// Converts floats to fixed-point integers and back, by multiplying them with a scale.
+///
+/// A scale that is a power of two, created with [with_frac_bits](Self::with_frac_bits), keeps
+/// the values cheap to work with in VM code: multiplying two of them and shifting the result
+/// right by the amount of fractional bits gives their product in the same format.
+///
+/// Conversions to integers round to the nearest value and saturate at the bounds of `i64`,
+/// `NaN` becomes 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fixed {
+    scale: f64,
+}
+
+impl Fixed {
+    /// A format where 1.0 is represented by `scale`.
+    ///
+    /// # Panics
+    /// If `scale` is not a positive, finite number.
+    pub fn with_scale(scale: f64) -> Self {
+        assert!(
+            scale.is_finite() && scale > 0.0,
+            "invalid fixed-point scale {scale}",
+        );
+        Self { scale }
+    }
+
+    /// A format with `frac_bits` fractional bits, so 1.0 is represented by `1 << frac_bits`.
+    ///
+    /// # Panics
+    /// If `frac_bits` is not less than 63.
+    pub fn with_frac_bits(frac_bits: u32) -> Self {
+        assert!(frac_bits < 63, "too many fractional bits: {frac_bits}");
+        Self::with_scale((1u64 << frac_bits) as f64)
+    }
+
+    /// The integer that represents 1.0.
+    pub fn scale(self) -> f64 {
+        self.scale
+    }
+
+    /// Convert a float to fixed point.
+    pub fn from_f64(self, value: f64) -> i64 {
+        // Float to integer casts saturate, and turn NaN into 0
+        (value * self.scale).round() as i64
+    }
+
+    /// Convert a float to fixed point.
+    pub fn from_f32(self, value: f32) -> i64 {
+        self.from_f64(f64::from(value))
+    }
+
+    /// Convert a fixed-point value to a float.
+    pub fn to_f64(self, value: i64) -> f64 {
+        value as f64 / self.scale
+    }
+
+    /// Convert a fixed-point value to a float.
+    pub fn to_f32(self, value: i64) -> f32 {
+        self.to_f64(value) as f32
+    }
+
+    /// Convert every float in `values` to fixed point, for example to fill the input.
+    ///
+    /// # Panics
+    /// If `values` and `out` have different lengths.
+    pub fn encode_f64(self, values: &[f64], out: &mut [i64]) {
+        assert_eq!(values.len(), out.len());
+        for (out, &value) in out.iter_mut().zip(values) {
+            *out = self.from_f64(value);
+        }
+    }
+
+    /// Convert every float in `values` to fixed point, see [encode_f64](Self::encode_f64).
+    ///
+    /// # Panics
+    /// If `values` and `out` have different lengths.
+    pub fn encode_f32(self, values: &[f32], out: &mut [i64]) {
+        assert_eq!(values.len(), out.len());
+        for (out, &value) in out.iter_mut().zip(values) {
+            *out = self.from_f32(value);
+        }
+    }
+
+    /// Convert every fixed-point value in `values` to a float, for example to read the output.
+    ///
+    /// # Panics
+    /// If `values` and `out` have different lengths.
+    pub fn decode_f64(self, values: &[i64], out: &mut [f64]) {
+        assert_eq!(values.len(), out.len());
+        for (out, &value) in out.iter_mut().zip(values) {
+            *out = self.to_f64(value);
+        }
+    }
+
+    /// Convert every fixed-point value in `values` to a float, see
+    /// [decode_f64](Self::decode_f64).
+    ///
+    /// # Panics
+    /// If `values` and `out` have different lengths.
+    pub fn decode_f32(self, values: &[i64], out: &mut [f32]) {
+        assert_eq!(values.len(), out.len());
+        for (out, &value) in out.iter_mut().zip(values) {
+            *out = self.to_f32(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let fixed = Fixed::with_frac_bits(16);
+        assert_eq!(fixed.from_f64(1.0), 1 << 16);
+        assert_eq!(fixed.from_f64(-0.5), -(1 << 15));
+        assert_eq!(fixed.to_f64(3 << 15), 1.5);
+
+        let values = [0.25, -3.75, 1000.125];
+        let mut encoded = [0; 3];
+        fixed.encode_f32(&values, &mut encoded);
+        let mut decoded = [0.0; 3];
+        fixed.decode_f32(&encoded, &mut decoded);
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn rounding_and_saturation() {
+        let fixed = Fixed::with_scale(10.0);
+        assert_eq!(fixed.from_f64(0.26), 3);
+        assert_eq!(fixed.from_f64(-0.26), -3);
+        assert_eq!(fixed.from_f32(f32::INFINITY), i64::MAX);
+        assert_eq!(fixed.from_f64(-1e300), i64::MIN);
+        assert_eq!(fixed.from_f64(f64::NAN), 0);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_scale() {
+        Fixed::with_scale(0.0);
+    }
+}
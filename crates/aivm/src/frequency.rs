@@ -1,3 +1,47 @@
+/// The amount of instruction kinds a code word can decode to, see
+/// [kind_of](InstructionFrequencies::kind_of).
+pub const KIND_COUNT: usize = 35;
+
+/// The names of the instruction kinds, indexed by kind as returned by
+/// [kind_of](InstructionFrequencies::kind_of).
+pub const KIND_NAMES: [&str; KIND_COUNT] = [
+    "end_func",
+    "call",
+    "add",
+    "sub",
+    "mul",
+    "mul_high",
+    "mul_high_unsigned",
+    "neg",
+    "abs",
+    "inc",
+    "dec",
+    "int_min",
+    "int_max",
+    "or",
+    "and",
+    "xor",
+    "not",
+    "shift_left",
+    "shift_right",
+    "rotate_left",
+    "rotate_right",
+    "bit_select",
+    "popcnt",
+    "bit_reverse",
+    "branch_cmp",
+    "branch_zero",
+    "branch_non_zero",
+    "mem_load",
+    "input_load",
+    "mem_store",
+    "output_store",
+    "host_call",
+    "const_load",
+    "scratch_load",
+    "scratch_store",
+];
+
 /// Constants controlling the frequency of different instructions in the VM code.
 ///
 /// A frequency value translates to an estimate percentage of the total instructions which
@@ -71,6 +115,80 @@ pub trait InstructionFrequencies {
     /// The frequency of the `output_store` instruction.
     const OUTPUT_STORE: u16 = 4748; // 0.7
 
+    /// The frequency of the `host_call` instruction, which calls one of the
+    /// [HostFunctions](crate::HostFunctions) registered with the compiler. It is 0 by default,
+    /// so code only calls the host when the frequencies are customized.
+    const HOST_CALL: u16 = 0;
+    /// The frequency of the `const_load` instruction, which loads one of the constants set with
+    /// [Compiler::set_constants](crate::Compiler::set_constants). It is 0 by default, so code
+    /// only reads constants when the frequencies are customized.
+    const CONST_LOAD: u16 = 0;
+    /// The frequency of the `scratch_load` instruction, which loads a value from the
+    /// [Scratch](crate::MemoryBank::Scratch) bank. It is 0 by default, like `scratch_store`.
+    const SCRATCH_LOAD: u16 = 0;
+    /// The frequency of the `scratch_store` instruction, which stores a value in the
+    /// [Scratch](crate::MemoryBank::Scratch) bank.
+    const SCRATCH_STORE: u16 = 0;
+
+    /// The frequencies of all instruction kinds, indexed by kind in the order of
+    /// [KIND_NAMES].
+    fn table() -> [u16; KIND_COUNT] {
+        [
+            Self::END_FUNC,
+            Self::CALL,
+            Self::INT_ADD,
+            Self::INT_SUB,
+            Self::INT_MUL,
+            Self::INT_MUL_HIGH,
+            Self::INT_MUL_HIGH_UNSIGNED,
+            Self::INT_NEG,
+            Self::INT_ABS,
+            Self::INT_INC,
+            Self::INT_DEC,
+            Self::INT_MIN,
+            Self::INT_MAX,
+            Self::BIT_OR,
+            Self::BIT_AND,
+            Self::BIT_XOR,
+            Self::BIT_NOT,
+            Self::BIT_SHIFT_L,
+            Self::BIT_SHIFT_R,
+            Self::BIT_ROT_L,
+            Self::BIT_ROT_R,
+            Self::BIT_SELECT,
+            Self::BIT_POPCNT,
+            Self::BIT_REVERSE,
+            Self::BRANCH_CMP,
+            Self::BRANCH_ZERO,
+            Self::BRANCH_NON_ZERO,
+            Self::MEM_LOAD,
+            Self::INPUT_LOAD,
+            Self::MEM_STORE,
+            Self::OUTPUT_STORE,
+            Self::HOST_CALL,
+            Self::CONST_LOAD,
+            Self::SCRATCH_LOAD,
+            Self::SCRATCH_STORE,
+        ]
+    }
+
+    /// The kind of instruction a code word decodes to, as an index into [KIND_NAMES].
+    ///
+    /// Only the lowest 16 bits of the code word select the kind, the other bits hold the
+    /// operands. Kind 0 is the `end_func` marker that separates functions.
+    fn kind_of(code_word: u64) -> usize {
+        let mut selector = code_word as u16;
+        for (kind, frequency) in Self::table().into_iter().enumerate() {
+            if selector < frequency {
+                return kind;
+            }
+            selector -= frequency;
+        }
+
+        // Only reachable when the frequencies add up to less than 2^16
+        KIND_COUNT - 1
+    }
+
     /// Takes the sum of all frequencies, and subtracts it from 2^16. The result must be 0
     /// or the VM compiler will panic on certain input values.
     ///
@@ -107,7 +225,11 @@ pub trait InstructionFrequencies {
                 + i32::from(Self::MEM_LOAD)
                 + i32::from(Self::INPUT_LOAD)
                 + i32::from(Self::MEM_STORE)
-                + i32::from(Self::OUTPUT_STORE))
+                + i32::from(Self::OUTPUT_STORE)
+                + i32::from(Self::HOST_CALL)
+                + i32::from(Self::CONST_LOAD)
+                + i32::from(Self::SCRATCH_LOAD)
+                + i32::from(Self::SCRATCH_STORE))
     }
 }
 
@@ -124,4 +246,17 @@ mod tests {
     fn validate_default_sum() {
         assert_eq!(DefaultFrequencies::sum_delta(), 0);
     }
+
+    #[test]
+    fn kinds() {
+        let table = DefaultFrequencies::table();
+        assert_eq!(table.iter().map(|&f| u32::from(f)).sum::<u32>(), 1 << 16);
+        assert_eq!(DefaultFrequencies::kind_of(0), 0);
+        assert_eq!(DefaultFrequencies::kind_of(u64::from(table[0])), 1);
+        assert_eq!(DefaultFrequencies::kind_of(0xFFFF_FFFF_0000_0000), 0);
+        assert_eq!(
+            KIND_NAMES[DefaultFrequencies::kind_of(u64::MAX)],
+            "output_store"
+        );
+    }
 }
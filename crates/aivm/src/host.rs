@@ -0,0 +1,96 @@
+use std::{fmt, sync::Arc};
+
+type HostFunction = dyn Fn(&mut [i64]) + Send + Sync;
+
+/// Functions of the host that VM code can call with the `host_call` instruction.
+///
+/// Every function gets the memory of the step, with exactly as many values as the
+/// [MemoryLayout](crate::MemoryLayout) the code was compiled for. This lets code query features
+/// of an environment or act on it in the middle of a step. Host functions are trusted, so they
+/// can also modify the input.
+///
+/// The `host_call` instruction has a frequency of 0 in
+/// [DefaultFrequencies](crate::DefaultFrequencies), so it only appears in code decoded with
+/// custom [InstructionFrequencies](crate::InstructionFrequencies). Cloning is cheap, the clones
+/// share the functions.
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: Arc<Vec<Arc<HostFunction>>>,
+}
+
+impl HostFunctions {
+    /// Create an empty set of functions.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function, returning its id.
+    ///
+    /// Functions must not panic when they are called by native code, which would abort the
+    /// process.
+    pub fn register<F>(&mut self, function: F) -> u32
+    where
+        F: Fn(&mut [i64]) + Send + Sync + 'static,
+    {
+        let functions = Arc::make_mut(&mut self.functions);
+        functions.push(Arc::new(function));
+        u32::try_from(functions.len() - 1).unwrap()
+    }
+
+    /// The amount of functions, ids go from 0 up to this amount.
+    pub fn len(&self) -> u32 {
+        self.functions.len() as u32
+    }
+
+    /// Whether no functions were registered.
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Call the function with the given id.
+    ///
+    /// # Panics
+    /// If no function with the id was registered.
+    pub fn call(&self, id: u32, memory: &mut [i64]) {
+        let function = self
+            .functions
+            .get(id as usize)
+            .unwrap_or_else(|| panic!("host function {id} is not registered"));
+        function(memory);
+    }
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostFunctions")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_call() {
+        let mut host = HostFunctions::new();
+        assert!(host.is_empty());
+        let double = host.register(|memory| memory[0] *= 2);
+        let snapshot = host.clone();
+        let inc = host.register(|memory| memory[1] += 1);
+        assert_eq!((double, inc), (0, 1));
+        assert_eq!((snapshot.len(), host.len()), (1, 2));
+
+        let mut memory = [3, 4];
+        host.call(double, &mut memory);
+        host.call(inc, &mut memory);
+        assert_eq!(memory, [6, 5]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn call_unregistered() {
+        HostFunctions::new().call(0, &mut []);
+    }
+}
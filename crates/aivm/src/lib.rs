@@ -34,20 +34,179 @@
 //! runner.step(&mut memory);
 //! ```
 
+mod channel;
 /// The different code generators available.
+///
+/// - [Interpreter](codegen::Interpreter) is always available and runs code on any host.
+/// - [NullGen](codegen::NullGen) is always available and does not run code at all, it only
+///   collects statistics about the code, to analyze or validate it cheaply.
+/// - [Record](codegen::Record) is always available and wraps another code generator, recording
+///   the instructions that it is given.
+/// - [Verify](codegen::Verify) is always available and wraps another code generator, running
+///   every step on the [Interpreter](codegen::Interpreter) too and panicking if the results
+///   differ, to track down miscompiles.
+/// - [Bytecode](codegen::Bytecode) is always available and lowers code to a portable bytecode
+///   with a stable serialization, which suits saving trained agents for a long time.
+/// - `Jit` (feature `jit`) compiles code to machine code.
+/// - `Cranelift` (feature `cranelift`) compiles code to machine code with Cranelift.
+/// - `Tiered` (feature `jit`) interprets code first and only compiles it with the `Jit` once it
+///   has run a number of times, which avoids compiling code that is only run once.
+/// - `CExport` (feature `c-export`) translates code to C source, which can be compiled into
+///   programs and firmware without a dependency on this crate. Code that calls the host can not
+///   be exported. With feature `c-export-cc` the source can be compiled and run as a `CRunner`.
+/// - `RustExport` (feature `rust-export`) translates code to safe Rust source, which can be
+///   included in another crate without a dependency on this crate.
+/// - `Ebpf` (feature `ebpf`) translates code to eBPF bytecode, which can be loaded into the
+///   kernel or run in a sandboxed eBPF virtual machine.
+/// - `Wgpu` (feature `wgpu`) translates code to a compute shader, which runs it on thousands of
+///   memories at once on the GPU through the [BatchRunner] trait.
+/// - `Cuda` (feature `cuda`) translates code to PTX, which runs it on batches of memories on
+///   NVIDIA GPUs through the [BatchRunner] trait.
 pub mod codegen;
 mod compile;
+/// Standard ways to turn output values into actions, such as argmax, rankings and thresholds.
+pub mod decode;
+/// Standard encodings of observations as input values, such as one-hot categories, byte strings
+/// and bitboards.
+pub mod encode;
+/// Helpers for encoding floats as the fixed-point integers VM code works with.
+pub mod fixed;
 mod frequency;
+mod host;
+mod memory;
+/// Helpers for running VM code on many cores with the `parallel` feature, and from async code
+/// with the `tokio` feature.
+#[cfg(any(feature = "parallel", feature = "tokio"))]
+pub mod runner;
+/// A conformance suite describing the semantics of the VM, for testing code generators.
+pub mod spec;
+/// Differential testing, which runs the same code on several code generators and reports where
+/// their results first differ.
+pub mod testing;
 
-pub use compile::Compiler;
-pub use frequency::{DefaultFrequencies, InstructionFrequencies};
+pub use channel::{Channel, Channels};
+pub use compile::{CallGraph, CompareKind, Compiler};
+pub use frequency::{DefaultFrequencies, InstructionFrequencies, KIND_COUNT, KIND_NAMES};
+pub use host::HostFunctions;
+pub use memory::{MemoryBank, MemoryBuffer, MemoryLayout, MemorySnapshot, MemoryView, OutputInit};
+
+use std::{fmt, time::Instant};
 
 /// Returned by a code generator to run VM code.
-pub trait Runner {
-    /// Run the VM code, clearing the output and then calling into the main function once.
+///
+/// Runners are [Send] and [Sync], so a compiled program can be shared by many threads that each
+/// step their own memory.
+pub trait Runner: Send + Sync {
+    /// Run the VM code, initializing the output and then calling into the main function once.
+    ///
+    /// The output is cleared unless the code was compiled with a different [OutputInit], the
+    /// [Scratch](MemoryBank::Scratch) bank is always cleared.
     ///
-    /// The provided memory slice is interpreted as the concatenation of the
-    /// memory, output and input in that order. It must be at least as big
-    /// as the sum of the sizes that were used while compiling the code.
+    /// The provided memory slice is interpreted as the concatenation of the memory, output, input
+    /// and scratch in that order (see [MemoryLayout]). It must be at least as big as the sum of
+    /// the sizes that were used while compiling the code. The input is never modified by the VM
+    /// code.
     fn step(&self, memory: &mut [i64]);
+
+    /// Like [step](Self::step), but stop executing once `fuel` instructions have been executed.
+    ///
+    /// Every executed instruction consumes one unit of fuel, including calls and instructions
+    /// that have no effect. Instructions skipped by a branch do not consume fuel. When the
+    /// fuel runs out, execution is aborted and the memory is left in an unspecified state,
+    /// though the input is still never modified.
+    fn step_bounded(&self, memory: &mut [i64], fuel: u64) -> StepResult;
+
+    /// Like [step](Self::step), but abort execution if it is still running once `deadline` has
+    /// passed.
+    ///
+    /// The clock is only checked every so many instructions, so execution can run slightly past
+    /// the deadline. When it is aborted, the memory and output are restored to the values they
+    /// had at the start of the step, so one pathological program cannot stall its caller.
+    fn step_until(&self, memory: &mut [i64], deadline: Instant) -> Result<(), DeadlineExceeded>;
+
+    /// Run the VM code once on every memory in `memories`, like [step](Self::step).
+    ///
+    /// This applies one program to many environment states at once. Runners can override it to
+    /// avoid overhead that [step](Self::step) has on every call, the default implementation
+    /// simply steps the memories one after the other.
+    fn step_batch(&self, memories: &mut [&mut [i64]]) {
+        for memory in memories {
+            self.step(memory);
+        }
+    }
+
+    /// Like [step](Self::step), but on banks that are stored in separate slices.
+    ///
+    /// The slices of `view` must have exactly the sizes the code was compiled for. The default
+    /// implementation [packs](MemoryView::pack) the banks into one slice and copies the results
+    /// back, runners that can address the slices directly override it to avoid the copies.
+    fn step_view(&self, view: &mut MemoryView<'_>) {
+        let mut memory = view.pack();
+        self.step(&mut memory);
+        view.unpack(&memory);
+    }
+}
+
+/// Information about compiled code, implemented by what every code generator returns.
+///
+/// This can be used to validate memories before running code, or to log the size of a model.
+pub trait RunnerInfo {
+    /// The amount of functions in the code.
+    fn function_count(&self) -> u32;
+
+    /// The amount of VM instructions in all functions together.
+    ///
+    /// Instructions that can not have an effect, such as stores to the input, are included.
+    fn instruction_count(&self) -> usize;
+
+    /// The size of the generated machine code in bytes, or `None` if no machine code was
+    /// generated.
+    fn code_size(&self) -> Option<usize>;
+
+    /// The memory layout the code was compiled for.
+    fn layout(&self) -> MemoryLayout;
+}
+
+/// Returned by [step_until](Runner::step_until) when execution was aborted at the deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("step did not complete before the deadline")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The result of [step_bounded](Runner::step_bounded).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The main function returned before the fuel ran out.
+    Completed {
+        /// The amount of instructions that were executed.
+        fuel_used: u64,
+    },
+    /// Execution was aborted because it needed more fuel than was given.
+    OutOfFuel,
+}
+
+/// Returned by a code generator to run VM code on many memories at once.
+///
+/// Every [Runner] is a batch runner through [Runner::step_batch].
+pub trait BatchRunner {
+    /// Run the VM code once on every memory in the batch, like [Runner::step].
+    ///
+    /// `memories` is the concatenation of the memories, which are all `stride` values long. The
+    /// stride must be at least as big as the sum of the sizes that were used while compiling the
+    /// code, and the length of `memories` must be a multiple of it.
+    fn step_strided(&self, memories: &mut [i64], stride: usize);
+}
+
+impl<R: Runner> BatchRunner for R {
+    fn step_strided(&self, memories: &mut [i64], stride: usize) {
+        assert_eq!(memories.len() % stride, 0);
+        let mut memories: Vec<_> = memories.chunks_exact_mut(stride).collect();
+        Runner::step_batch(self, &mut memories);
+    }
 }
@@ -0,0 +1,703 @@
+use crate::{Channel, Channels};
+
+use std::ops::Range;
+
+/// The different regions of the memory passed to [Runner::step](crate::Runner::step).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryBank {
+    /// Persistent memory that can be read and written by the VM code.
+    Memory,
+    /// Values written by the VM code. At the start of every step they are cleared, kept or
+    /// filled with a constant, depending on the [OutputInit] of the layout.
+    Output,
+    /// Values provided by the caller, the VM code can only read them.
+    Input,
+    /// Temporary values that can be read and written by the VM code, cleared at the start of
+    /// every step.
+    ///
+    /// Unlike the [Memory](Self::Memory) bank, code does not have to clear it itself to avoid
+    /// state leaking from one step into the next.
+    Scratch,
+    /// Constants supplied when compiling, see [Compiler::set_constants](crate::Compiler::set_constants).
+    ///
+    /// The constants are embedded in the runner instead of being part of the memory slice, so
+    /// they can be shared by every thread that steps the code. The VM code can only read them.
+    Const,
+}
+
+impl MemoryBank {
+    /// Whether VM code is allowed to store values in this bank.
+    pub fn is_writable(self) -> bool {
+        !matches!(self, Self::Input | Self::Const)
+    }
+}
+
+/// What happens to the [Output](MemoryBank::Output) bank at the start of every step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OutputInit {
+    /// Set every output value to zero, so the output only contains values written by the step.
+    #[default]
+    Clear,
+    /// Keep the values from before the step, so the code can accumulate outputs across steps.
+    Keep,
+    /// Set every output value to the given constant.
+    Fill(i64),
+}
+
+impl OutputInit {
+    /// The value every output is set to at the start of a step, or `None` if the output is
+    /// kept.
+    pub fn value(self) -> Option<i64> {
+        match self {
+            Self::Clear => Some(0),
+            Self::Keep => None,
+            Self::Fill(value) => Some(value),
+        }
+    }
+}
+
+/// The sizes of the memory banks that VM code is compiled for, and how the output is
+/// initialized.
+///
+/// The banks are laid out next to each other in the memory slice in the order memory, output,
+/// input, scratch. The [Const](MemoryBank::Const) bank is not part of the memory slice, its indices are
+/// indices into the constants. All sizes are in units of 8 byte values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MemoryLayout {
+    /// The size of the [Memory](MemoryBank::Memory) bank.
+    pub memory_size: u32,
+    /// The size of the [Output](MemoryBank::Output) bank.
+    pub output_size: u32,
+    /// The size of the [Input](MemoryBank::Input) bank.
+    pub input_size: u32,
+    /// What happens to the [Output](MemoryBank::Output) bank at the start of every step.
+    pub output_init: OutputInit,
+    /// The size of the [Const](MemoryBank::Const) bank.
+    pub const_size: u32,
+    /// The size of the [Scratch](MemoryBank::Scratch) bank.
+    pub scratch_size: u32,
+}
+
+impl MemoryLayout {
+    /// The amount of values in the header that code compiled with
+    /// [Compiler::set_layout_header](crate::Compiler::set_layout_header) reads the layout from.
+    pub const HEADER_SIZE: usize = 4;
+
+    /// Create a new layout from the sizes of the banks, which clears the output at the start
+    /// of every step.
+    pub const fn new(memory_size: u32, output_size: u32, input_size: u32) -> Self {
+        Self {
+            memory_size,
+            output_size,
+            input_size,
+            output_init: OutputInit::Clear,
+            const_size: 0,
+            scratch_size: 0,
+        }
+    }
+
+    /// The same layout, but with the output initialized according to `output_init`.
+    pub const fn with_output_init(self, output_init: OutputInit) -> Self {
+        Self {
+            output_init,
+            ..self
+        }
+    }
+
+    /// The same layout, but with `const_size` constants.
+    pub const fn with_const_size(self, const_size: u32) -> Self {
+        Self { const_size, ..self }
+    }
+
+    /// The same layout, but with a [Scratch](MemoryBank::Scratch) bank of `scratch_size`
+    /// values.
+    pub const fn with_scratch_size(self, scratch_size: u32) -> Self {
+        Self {
+            scratch_size,
+            ..self
+        }
+    }
+
+    /// The total amount of values in the banks of the memory slice, which excludes the
+    /// constants.
+    ///
+    /// # Panics
+    /// If the total size does not fit in a `u32`.
+    pub fn size(&self) -> u32 {
+        self.memory_size
+            .checked_add(self.output_size)
+            .and_then(|s| s.checked_add(self.input_size))
+            .and_then(|s| s.checked_add(self.scratch_size))
+            .expect("memory layout too large")
+    }
+
+    /// The amount of values in the given bank.
+    pub fn bank_size(&self, bank: MemoryBank) -> u32 {
+        match bank {
+            MemoryBank::Memory => self.memory_size,
+            MemoryBank::Output => self.output_size,
+            MemoryBank::Input => self.input_size,
+            MemoryBank::Scratch => self.scratch_size,
+            MemoryBank::Const => self.const_size,
+        }
+    }
+
+    /// The index of the first value of the given bank, the constants start at 0.
+    pub fn bank_start(&self, bank: MemoryBank) -> u32 {
+        match bank {
+            MemoryBank::Memory => 0,
+            MemoryBank::Output => self.memory_size,
+            MemoryBank::Input => self.memory_size + self.output_size,
+            MemoryBank::Scratch => self.memory_size + self.output_size + self.input_size,
+            MemoryBank::Const => 0,
+        }
+    }
+
+    /// The range of indices in the memory slice occupied by the given bank, or in the constants
+    /// for the [Const](MemoryBank::Const) bank.
+    pub fn bank_range(&self, bank: MemoryBank) -> Range<usize> {
+        let start = self.bank_start(bank) as usize;
+        start..start + self.bank_size(bank) as usize
+    }
+
+    /// Translate an offset inside a bank to an index in the memory slice, or in the constants
+    /// for the [Const](MemoryBank::Const) bank.
+    ///
+    /// # Panics
+    /// If `offset` is outside of the bank.
+    pub fn address(&self, bank: MemoryBank, offset: u32) -> u32 {
+        assert!(
+            offset < self.bank_size(bank),
+            "offset {offset} out of bounds for {bank:?} bank",
+        );
+        self.bank_start(bank) + offset
+    }
+
+    /// Initialize the output bank of `memory` for a step, according to
+    /// [output_init](Self::output_init).
+    pub fn init_output(&self, memory: &mut [i64]) {
+        if let Some(value) = self.output_init.value() {
+            memory[self.bank_range(MemoryBank::Output)].fill(value);
+        }
+    }
+
+    /// Prepare `memory` for a step: initialize the output with
+    /// [init_output](Self::init_output) and clear the [Scratch](MemoryBank::Scratch) bank.
+    pub fn init_step(&self, memory: &mut [i64]) {
+        self.init_output(memory);
+        memory[self.bank_range(MemoryBank::Scratch)].fill(0);
+    }
+
+    /// Write the sizes of the banks to the header at the start of `memory`, for code compiled
+    /// with [Compiler::set_layout_header](crate::Compiler::set_layout_header). The banks follow
+    /// the header.
+    ///
+    /// The header holds the sizes of the memory, output, input and scratch banks, in that order.
+    pub fn write_header(&self, memory: &mut [i64]) {
+        memory[..Self::HEADER_SIZE].copy_from_slice(&[
+            self.memory_size.into(),
+            self.output_size.into(),
+            self.input_size.into(),
+            self.scratch_size.into(),
+        ]);
+    }
+
+    /// Read the sizes of the banks from the header at the start of `memory`, see
+    /// [write_header](Self::write_header).
+    ///
+    /// Returns `None` if the header is cut off, contains a size that is negative or too large,
+    /// or describes banks that do not fit in the rest of `memory`.
+    pub fn read_header(memory: &[i64]) -> Option<Self> {
+        let header = memory.get(..Self::HEADER_SIZE)?;
+        let mut sizes = [0; Self::HEADER_SIZE];
+        for (size, &value) in sizes.iter_mut().zip(header) {
+            *size = u32::try_from(value).ok()?;
+        }
+        let [memory_size, output_size, input_size, scratch_size] = sizes;
+
+        let layout =
+            Self::new(memory_size, output_size, input_size).with_scratch_size(scratch_size);
+        let total = sizes
+            .into_iter()
+            .try_fold(0u32, |total, size| total.checked_add(size))?;
+        (total as usize <= memory.len() - Self::HEADER_SIZE).then_some(layout)
+    }
+
+    /// The bank that contains the given index in the memory slice, if any. This is never the
+    /// [Const](MemoryBank::Const) bank.
+    pub fn bank_of(&self, address: u32) -> Option<MemoryBank> {
+        [
+            MemoryBank::Memory,
+            MemoryBank::Output,
+            MemoryBank::Input,
+            MemoryBank::Scratch,
+        ]
+        .into_iter()
+        .find(|&bank| self.bank_range(bank).contains(&(address as usize)))
+    }
+}
+
+/// Owned memory for VM code, sized and split into banks according to a [MemoryLayout].
+///
+/// This saves hand-slicing a flat `[i64]`: the banks can be accessed by name, and the whole
+/// memory can be saved with [snapshot](Self::snapshot) and put back with
+/// [restore](Self::restore), for example to reset an environment at the start of an episode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryBuffer {
+    layout: MemoryLayout,
+    channels: Channels,
+    values: Vec<i64>,
+}
+
+impl MemoryBuffer {
+    /// Create a buffer for the given layout with all values set to zero.
+    pub fn new(layout: MemoryLayout) -> Self {
+        Self::with_channels(layout, Channels::new())
+    }
+
+    /// Create a buffer for the given layout with all values set to zero, whose input and output
+    /// can be accessed per channel.
+    ///
+    /// # Panics
+    /// If a channel does not fit in its bank.
+    pub fn with_channels(layout: MemoryLayout, channels: Channels) -> Self {
+        assert!(
+            channels.input_size() <= layout.input_size
+                && channels.output_size() <= layout.output_size,
+            "channels do not fit in the memory layout",
+        );
+        Self {
+            layout,
+            channels,
+            values: vec![0; layout.size() as usize],
+        }
+    }
+
+    /// The layout of the buffer.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The channels of the buffer.
+    pub fn channels(&self) -> &Channels {
+        &self.channels
+    }
+
+    /// All values, to be passed to [Runner::step](crate::Runner::step).
+    pub fn as_slice(&self) -> &[i64] {
+        &self.values
+    }
+
+    /// All values mutably, to be passed to [Runner::step](crate::Runner::step).
+    pub fn as_mut_slice(&mut self) -> &mut [i64] {
+        &mut self.values
+    }
+
+    /// The values of the given bank.
+    ///
+    /// # Panics
+    /// If `bank` is [Const](MemoryBank::Const), the constants are not part of the memory.
+    pub fn bank(&self, bank: MemoryBank) -> &[i64] {
+        assert_ne!(
+            bank,
+            MemoryBank::Const,
+            "the constants are not part of the memory"
+        );
+        &self.values[self.layout.bank_range(bank)]
+    }
+
+    /// The values of the given bank mutably.
+    ///
+    /// # Panics
+    /// If `bank` is [Const](MemoryBank::Const), the constants are not part of the memory.
+    pub fn bank_mut(&mut self, bank: MemoryBank) -> &mut [i64] {
+        assert_ne!(
+            bank,
+            MemoryBank::Const,
+            "the constants are not part of the memory"
+        );
+        &mut self.values[self.layout.bank_range(bank)]
+    }
+
+    /// The values of the [Memory](MemoryBank::Memory) bank.
+    pub fn memory(&self) -> &[i64] {
+        self.bank(MemoryBank::Memory)
+    }
+
+    /// The values of the [Memory](MemoryBank::Memory) bank mutably.
+    pub fn memory_mut(&mut self) -> &mut [i64] {
+        self.bank_mut(MemoryBank::Memory)
+    }
+
+    /// The values of the [Output](MemoryBank::Output) bank, as written by the last step.
+    pub fn output(&self) -> &[i64] {
+        self.bank(MemoryBank::Output)
+    }
+
+    /// The values of the [Input](MemoryBank::Input) bank.
+    pub fn input(&self) -> &[i64] {
+        self.bank(MemoryBank::Input)
+    }
+
+    /// The values of the [Input](MemoryBank::Input) bank mutably, to provide the input of the
+    /// next step.
+    pub fn input_mut(&mut self) -> &mut [i64] {
+        self.bank_mut(MemoryBank::Input)
+    }
+
+    /// The values of the input channel with the given name.
+    ///
+    /// # Panics
+    /// If there is no input channel with that name.
+    pub fn input_channel(&self, name: &str) -> &[i64] {
+        let range = self.channel_range(self.channels.input(name), name);
+        &self.values[range]
+    }
+
+    /// The values of the input channel with the given name mutably, to provide the input of the
+    /// next step.
+    ///
+    /// # Panics
+    /// If there is no input channel with that name.
+    pub fn input_channel_mut(&mut self, name: &str) -> &mut [i64] {
+        let range = self.channel_range(self.channels.input(name), name);
+        &mut self.values[range]
+    }
+
+    /// The values of the output channel with the given name, as written by the last step.
+    ///
+    /// # Panics
+    /// If there is no output channel with that name.
+    pub fn output_channel(&self, name: &str) -> &[i64] {
+        let range = self.channel_range(self.channels.output(name), name);
+        &self.values[range]
+    }
+
+    fn channel_range(&self, channel: Option<&Channel>, name: &str) -> Range<usize> {
+        channel
+            .unwrap_or_else(|| panic!("no channel named `{name}`"))
+            .range(&self.layout)
+    }
+
+    /// Save the values of all banks.
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            layout: self.layout,
+            values: self.values.clone().into(),
+        }
+    }
+
+    /// Set all values to the ones saved in `snapshot`.
+    ///
+    /// # Panics
+    /// If the snapshot was taken of a buffer with a different layout.
+    pub fn restore(&mut self, snapshot: &MemorySnapshot) {
+        assert_eq!(
+            self.layout, snapshot.layout,
+            "snapshot of a different memory layout"
+        );
+        self.values.copy_from_slice(&snapshot.values);
+    }
+}
+
+impl AsRef<[i64]> for MemoryBuffer {
+    fn as_ref(&self) -> &[i64] {
+        self.as_slice()
+    }
+}
+
+impl AsMut<[i64]> for MemoryBuffer {
+    fn as_mut(&mut self) -> &mut [i64] {
+        self.as_mut_slice()
+    }
+}
+
+/// The banks of the memory of a step as separate slices, so they can live in buffers the caller
+/// already has, such as a row of an observation tensor and an action buffer.
+///
+/// Pass it to [Runner::step_view](crate::Runner::step_view) instead of packing the banks into
+/// one slice before every step. The slices must have exactly the sizes of the banks in the
+/// [MemoryLayout] the code was compiled for. The input is only borrowed immutably, because VM
+/// code never modifies it.
+#[derive(Debug)]
+pub struct MemoryView<'a> {
+    memory: &'a mut [i64],
+    output: &'a mut [i64],
+    input: &'a [i64],
+    scratch: &'a mut [i64],
+}
+
+impl<'a> MemoryView<'a> {
+    /// Create a view of the memory, output and input banks, with an empty scratch bank.
+    pub fn new(memory: &'a mut [i64], output: &'a mut [i64], input: &'a [i64]) -> Self {
+        Self {
+            memory,
+            output,
+            input,
+            scratch: &mut [],
+        }
+    }
+
+    /// The same view, but with the given [Scratch](MemoryBank::Scratch) bank.
+    pub fn with_scratch(self, scratch: &'a mut [i64]) -> Self {
+        Self { scratch, ..self }
+    }
+
+    /// The values of the [Memory](MemoryBank::Memory) bank.
+    pub fn memory(&self) -> &[i64] {
+        self.memory
+    }
+
+    /// The values of the [Output](MemoryBank::Output) bank.
+    pub fn output(&self) -> &[i64] {
+        self.output
+    }
+
+    /// The values of the [Input](MemoryBank::Input) bank.
+    pub fn input(&self) -> &[i64] {
+        self.input
+    }
+
+    /// The values of the [Scratch](MemoryBank::Scratch) bank.
+    pub fn scratch(&self) -> &[i64] {
+        self.scratch
+    }
+
+    /// Whether the slices have the sizes of the banks in `layout`.
+    pub fn fits(&self, layout: &MemoryLayout) -> bool {
+        self.memory.len() == layout.memory_size as usize
+            && self.output.len() == layout.output_size as usize
+            && self.input.len() == layout.input_size as usize
+            && self.scratch.len() == layout.scratch_size as usize
+    }
+
+    /// A layout with the sizes of the slices.
+    ///
+    /// # Panics
+    /// If a slice is longer than `u32::MAX`.
+    pub fn layout(&self) -> MemoryLayout {
+        let size = |bank: &[i64]| u32::try_from(bank.len()).unwrap();
+        MemoryLayout::new(size(self.memory), size(self.output), size(self.input))
+            .with_scratch_size(size(self.scratch))
+    }
+
+    /// Copy the banks into one slice laid out like the memory passed to
+    /// [Runner::step](crate::Runner::step).
+    pub fn pack(&self) -> Vec<i64> {
+        [&*self.memory, &*self.output, self.input, &*self.scratch].concat()
+    }
+
+    /// Copy the writable banks back from a slice created by [pack](Self::pack).
+    pub fn unpack(&mut self, values: &[i64]) {
+        let (memory, values) = values.split_at(self.memory.len());
+        let (output, values) = values.split_at(self.output.len());
+        let scratch = &values[self.input.len()..][..self.scratch.len()];
+        self.memory.copy_from_slice(memory);
+        self.output.copy_from_slice(output);
+        self.scratch.copy_from_slice(scratch);
+    }
+
+    /// Initialize the banks for a step, like [MemoryLayout::init_step].
+    pub(crate) fn init_step(&mut self, layout: &MemoryLayout) {
+        if let Some(value) = layout.output_init.value() {
+            self.output.fill(value);
+        }
+        self.scratch.fill(0);
+    }
+
+    /// The value at an index of the packed memory slice.
+    #[inline]
+    pub(crate) fn get(&self, address: usize) -> i64 {
+        let mut address = address;
+        for bank in [&*self.memory, &*self.output, self.input] {
+            if address < bank.len() {
+                return bank[address];
+            }
+            address -= bank.len();
+        }
+        self.scratch[address]
+    }
+
+    /// Set the value at an index of the packed memory slice, which must not be in the input.
+    #[inline]
+    pub(crate) fn set(&mut self, address: usize, value: i64) {
+        let mut address = address;
+        if address < self.memory.len() {
+            self.memory[address] = value;
+            return;
+        }
+        address -= self.memory.len();
+        if address < self.output.len() {
+            self.output[address] = value;
+            return;
+        }
+        address -= self.output.len() + self.input.len();
+        self.scratch[address] = value;
+    }
+}
+
+/// The values of a [MemoryBuffer] at one point in time, see [MemoryBuffer::snapshot].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySnapshot {
+    layout: MemoryLayout,
+    values: Box<[i64]>,
+}
+
+impl MemorySnapshot {
+    /// The layout of the buffer the snapshot was taken of.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The saved values of all banks.
+    pub fn as_slice(&self) -> &[i64] {
+        &self.values
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banks_are_disjoint() {
+        let layout = MemoryLayout::new(3, 2, 4);
+
+        assert_eq!(layout.size(), 9);
+        assert_eq!(layout.bank_range(MemoryBank::Memory), 0..3);
+        assert_eq!(layout.bank_range(MemoryBank::Output), 3..5);
+        assert_eq!(layout.bank_range(MemoryBank::Input), 5..9);
+
+        assert_eq!(layout.address(MemoryBank::Output, 1), 4);
+        assert_eq!(layout.bank_of(4), Some(MemoryBank::Output));
+        assert_eq!(layout.bank_of(8), Some(MemoryBank::Input));
+        assert_eq!(layout.bank_of(9), None);
+    }
+
+    #[test]
+    fn const_bank() {
+        let layout = MemoryLayout::new(1, 1, 1).with_const_size(2);
+
+        assert_eq!(layout.size(), 3);
+        assert_eq!(layout.address(MemoryBank::Const, 1), 1);
+        assert_eq!(layout.bank_of(1), Some(MemoryBank::Output));
+        assert!(!MemoryBank::Const.is_writable());
+    }
+
+    #[test]
+    fn init_output() {
+        let layout = MemoryLayout::new(1, 2, 1);
+        let mut memory = [1, 2, 3, 4];
+        layout.init_output(&mut memory);
+        assert_eq!(memory, [1, 0, 0, 4]);
+
+        let mut memory = [1, 2, 3, 4];
+        layout
+            .with_output_init(OutputInit::Keep)
+            .init_output(&mut memory);
+        assert_eq!(memory, [1, 2, 3, 4]);
+
+        layout
+            .with_output_init(OutputInit::Fill(-7))
+            .init_output(&mut memory);
+        assert_eq!(memory, [1, -7, -7, 4]);
+    }
+
+    #[test]
+    fn scratch_bank() {
+        let layout = MemoryLayout::new(1, 1, 1).with_scratch_size(2);
+
+        assert_eq!(layout.size(), 5);
+        assert_eq!(layout.bank_range(MemoryBank::Scratch), 3..5);
+        assert_eq!(layout.bank_of(4), Some(MemoryBank::Scratch));
+        assert!(MemoryBank::Scratch.is_writable());
+
+        let mut memory = [1, 2, 3, 4, 5];
+        layout.init_step(&mut memory);
+        assert_eq!(memory, [1, 0, 3, 0, 0]);
+    }
+
+    #[test]
+    fn header() {
+        let layout = MemoryLayout::new(2, 1, 3).with_scratch_size(1);
+        let mut memory = [0; 11];
+        layout.write_header(&mut memory);
+        assert_eq!(memory[..4], [2, 1, 3, 1]);
+        assert_eq!(MemoryLayout::read_header(&memory), Some(layout));
+
+        // The banks have to fit after the header
+        assert_eq!(MemoryLayout::read_header(&memory[..10]), None);
+        assert_eq!(MemoryLayout::read_header(&memory[..3]), None);
+        memory[1] = -1;
+        assert_eq!(MemoryLayout::read_header(&memory), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn address_out_of_bank() {
+        MemoryLayout::new(3, 0, 4).address(MemoryBank::Output, 0);
+    }
+
+    #[test]
+    fn buffer_snapshot() {
+        let mut buffer = MemoryBuffer::new(MemoryLayout::new(2, 1, 2));
+        assert_eq!(buffer.as_slice(), [0; 5]);
+
+        buffer.memory_mut()[1] = 3;
+        buffer.input_mut().copy_from_slice(&[4, 5]);
+        buffer.bank_mut(MemoryBank::Output)[0] = 6;
+        assert_eq!(buffer.as_slice(), [0, 3, 6, 4, 5]);
+        assert_eq!(buffer.output(), [6]);
+        assert_eq!(buffer.input(), [4, 5]);
+
+        let snapshot = buffer.snapshot();
+        buffer.as_mut_slice().fill(7);
+        buffer.restore(&snapshot);
+        assert_eq!(buffer.as_slice(), [0, 3, 6, 4, 5]);
+    }
+
+    #[test]
+    fn buffer_channels() {
+        let channels = Channels::new()
+            .with_input("vision", 2)
+            .with_input("touch", 1)
+            .with_output("action", 1);
+        let mut buffer = MemoryBuffer::with_channels(channels.layout(1), channels);
+
+        buffer.input_channel_mut("touch")[0] = 5;
+        buffer.input_channel_mut("vision").copy_from_slice(&[3, 4]);
+        buffer.bank_mut(MemoryBank::Output)[0] = 6;
+        assert_eq!(buffer.as_slice(), [0, 6, 3, 4, 5]);
+        assert_eq!(buffer.input_channel("vision"), [3, 4]);
+        assert_eq!(buffer.output_channel("action"), [6]);
+    }
+
+    #[test]
+    fn view_pack() {
+        let (mut memory, mut output, mut scratch) = ([1, 2], [3], [6]);
+        let mut view =
+            MemoryView::new(&mut memory, &mut output, &[4, 5]).with_scratch(&mut scratch);
+
+        assert!(view.fits(&MemoryLayout::new(2, 1, 2).with_scratch_size(1)));
+        assert!(!view.fits(&MemoryLayout::new(2, 1, 2)));
+        let mut packed = view.pack();
+        assert_eq!(packed, [1, 2, 3, 4, 5, 6]);
+        assert_eq!((0..6).map(|i| view.get(i)).collect::<Vec<_>>(), packed);
+
+        view.set(5, -6);
+        assert_eq!(view.scratch(), [-6]);
+
+        // The input is never copied back
+        packed[1] = -2;
+        packed[3] = -4;
+        view.unpack(&packed);
+        assert_eq!(view.pack(), [1, -2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn restore_other_layout() {
+        let snapshot = MemoryBuffer::new(MemoryLayout::new(1, 1, 0)).snapshot();
+        MemoryBuffer::new(MemoryLayout::new(0, 1, 1)).restore(&snapshot);
+    }
+}
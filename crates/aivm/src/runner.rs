@@ -0,0 +1,144 @@
+use crate::Runner;
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "tokio")]
+use std::sync::Arc;
+
+/// Run the VM code once on every memory in `memories`, spreading them over the threads of the
+/// rayon thread pool.
+///
+/// The memories are split into one shard per thread, and every shard is run with
+/// [step_batch](Runner::step_batch). Runners are [Sync], so all threads use the same compiled
+/// code.
+#[cfg(feature = "parallel")]
+pub fn par_step_all<R: Runner>(runner: &R, memories: &mut [&mut [i64]]) {
+    let shard_size = memories.len().div_ceil(rayon::current_num_threads()).max(1);
+    memories
+        .par_chunks_mut(shard_size)
+        .for_each(|shard| runner.step_batch(shard));
+}
+
+/// Steps a [Runner] on the blocking thread pool of tokio, so code can be run from async code
+/// without stalling the executor.
+///
+/// The runner is shared with the blocking threads, so cloning the adapter is cheap and every
+/// clone can step its own memory at the same time.
+#[cfg(feature = "tokio")]
+pub struct AsyncRunner<R> {
+    runner: Arc<R>,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: Runner + 'static> AsyncRunner<R> {
+    /// Create an adapter that steps `runner`.
+    pub fn new(runner: R) -> Self {
+        Self::from_arc(Arc::new(runner))
+    }
+
+    /// Create an adapter that steps a runner which is already shared.
+    pub fn from_arc(runner: Arc<R>) -> Self {
+        Self { runner }
+    }
+
+    /// The runner that is stepped.
+    pub fn runner(&self) -> &Arc<R> {
+        &self.runner
+    }
+
+    /// Like [Runner::step], but run on the blocking thread pool of the current tokio runtime.
+    ///
+    /// The memory is moved to the blocking thread and returned once the step is done, it can be
+    /// anything that holds the values, such as a `Vec<i64>` or a
+    /// [MemoryBuffer](crate::MemoryBuffer).
+    ///
+    /// # Panics
+    /// If it is not called from a tokio runtime, or if the step panics.
+    pub async fn step_async<M>(&self, mut memory: M) -> M
+    where
+        M: AsMut<[i64]> + Send + 'static,
+    {
+        let runner = self.runner.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            runner.step(memory.as_mut());
+            memory
+        });
+        match task.await {
+            Ok(memory) => memory,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R> Clone for AsyncRunner<R> {
+    fn clone(&self) -> Self {
+        Self {
+            runner: self.runner.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::Interpreter,
+        testing::{random_code, random_memory, Xorshift},
+        Compiler,
+    };
+    #[cfg(feature = "tokio")]
+    use crate::{MemoryBuffer, MemoryLayout};
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn matches_step() {
+        let mut rng = Xorshift::default();
+
+        let mut compiler = Compiler::new(Interpreter::new());
+        for count in [0, 1, 7, 100] {
+            let code = random_code(&mut rng, 256);
+            let memories: Vec<Vec<_>> = (0..count).map(|_| random_memory(&mut rng, 16)).collect();
+
+            let runner = compiler.compile(&code, 3, 8, 4, 4);
+            let mut expected = memories.clone();
+            for memory in &mut expected {
+                runner.step(memory);
+            }
+
+            let mut actual = memories;
+            let mut batch: Vec<_> = actual.iter_mut().map(|m| &mut m[..]).collect();
+            par_step_all(&runner, &mut batch);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn step_async() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut compiler = Compiler::new(Interpreter::new());
+        let code: Vec<_> = (0..64)
+            .map(|i: u64| i.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .collect();
+        let runner = compiler.compile(&code, 3, 8, 4, 4);
+
+        let memory: Vec<_> = (0..16).collect();
+        let mut expected = memory.clone();
+        runner.step(&mut expected);
+        let mut expected_zeroed = [0; 16];
+        runner.step(&mut expected_zeroed);
+
+        let runner = AsyncRunner::new(runner);
+        let clone = runner.clone();
+        let (a, b) = runtime.block_on(async {
+            let a = runner.step_async(memory);
+            let b = clone.step_async(MemoryBuffer::new(MemoryLayout::new(8, 4, 4)));
+            (a.await, b.await)
+        });
+        assert_eq!(a, expected);
+        assert_eq!(b.as_slice(), expected_zeroed);
+    }
+}
@@ -0,0 +1,491 @@
+use crate::{
+    codegen::CodeGenerator, Compiler, DefaultFrequencies, InstructionFrequencies, MemoryLayout,
+    Runner,
+};
+
+use std::fmt;
+
+/// A single program together with the memory it starts with and the memory it must leave
+/// behind after one step.
+#[derive(Debug, Clone)]
+pub struct TestVector {
+    /// A short description of what is being tested.
+    pub name: String,
+    /// The code words, encoded with [DefaultFrequencies].
+    pub code: Vec<u64>,
+    /// The `lowest_function_level` to compile the code with.
+    pub lowest_function_level: u32,
+    /// The layout to compile the code with.
+    pub layout: MemoryLayout,
+    /// The memory before calling [step](Runner::step).
+    pub memory: Vec<i64>,
+    /// The memory after calling [step](Runner::step).
+    pub expected: Vec<i64>,
+}
+
+impl TestVector {
+    /// Compile and run the code once, returning the resulting memory.
+    pub fn run<G>(&self, compiler: &mut Compiler<G>) -> Vec<i64>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+    {
+        let runner = compiler.compile(
+            &self.code,
+            self.lowest_function_level,
+            self.layout.memory_size,
+            self.layout.output_size,
+            self.layout.input_size,
+        );
+
+        let mut memory = self.memory.clone();
+        runner.step(&mut memory);
+
+        memory
+    }
+}
+
+/// A test vector that did not produce the expected memory.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    /// The test vector that failed.
+    pub vector: TestVector,
+    /// The memory that was produced instead.
+    pub actual: Vec<i64>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: expected {:?}, got {:?} (initial memory {:?})",
+            self.vector.name, self.vector.expected, self.actual, self.vector.memory,
+        )
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Run all [test vectors](test_vectors) with the given code generator, returning the first
+/// one that failed.
+pub fn check<G>(gen: G) -> Result<(), Box<Mismatch>>
+where
+    G: CodeGenerator + 'static,
+    G::Runner: Runner,
+{
+    let mut compiler = Compiler::new(gen);
+
+    for vector in test_vectors() {
+        let actual = vector.run(&mut compiler);
+        if actual != vector.expected {
+            return Err(Box::new(Mismatch { vector, actual }));
+        }
+    }
+
+    Ok(())
+}
+
+/// The canonical test vectors that describe the semantics of every instruction.
+pub fn test_vectors() -> Vec<TestVector> {
+    let mut vectors = vec![];
+
+    let binary: [(Op, BinaryFn); 10] = [
+        (Op::IntAdd, i64::wrapping_add),
+        (Op::IntSub, i64::wrapping_sub),
+        (Op::IntMul, i64::wrapping_mul),
+        (Op::IntMulHigh, |a, b| {
+            ((a as i128 * b as i128) >> 64) as i64
+        }),
+        (Op::IntMulHighUnsigned, |a, b| {
+            ((a as u64 as u128 * b as u64 as u128) >> 64) as i64
+        }),
+        (Op::IntMin, i64::min),
+        (Op::IntMax, i64::max),
+        (Op::BitOr, |a, b| a | b),
+        (Op::BitAnd, |a, b| a & b),
+        (Op::BitXor, |a, b| a ^ b),
+    ];
+    for (op, f) in binary {
+        for (a, b) in BINARY_OPERANDS {
+            vectors.push(TestVector {
+                name: format!("{op:?}({a}, {b})"),
+                code: vec![
+                    Op::MemLoad.encode(0, 0, 0),
+                    Op::MemLoad.encode(1, 0, 1),
+                    op.encode(2, 0, 1),
+                    Op::MemStore.encode(2, 0, 2),
+                ],
+                lowest_function_level: 0,
+                layout: MemoryLayout::new(3, 0, 0),
+                memory: vec![a, b, 0],
+                expected: vec![a, b, f(a, b)],
+            });
+        }
+    }
+
+    let unary: [(Op, UnaryFn); 5] = [
+        (Op::IntNeg, i64::wrapping_neg),
+        (Op::IntAbs, i64::wrapping_abs),
+        (Op::BitNot, |a| !a),
+        (Op::BitPopcnt, |a| a.count_ones().into()),
+        (Op::BitReverse, i64::reverse_bits),
+    ];
+    for (op, f) in unary {
+        for a in UNARY_OPERANDS {
+            vectors.push(TestVector {
+                name: format!("{op:?}({a})"),
+                code: vec![
+                    Op::MemLoad.encode(0, 0, 0),
+                    op.encode(1, 0, 0),
+                    Op::MemStore.encode(1, 0, 1),
+                ],
+                lowest_function_level: 0,
+                layout: MemoryLayout::new(2, 0, 0),
+                memory: vec![a, 0],
+                expected: vec![a, f(a)],
+            });
+        }
+    }
+
+    let in_place: [(Op, UnaryFn); 2] = [
+        (Op::IntInc, |a| a.wrapping_add(1)),
+        (Op::IntDec, |a| a.wrapping_sub(1)),
+    ];
+    for (op, f) in in_place {
+        for a in UNARY_OPERANDS {
+            vectors.push(TestVector {
+                name: format!("{op:?}({a})"),
+                code: vec![
+                    Op::MemLoad.encode(5, 0, 0),
+                    op.encode(5, 0, 0),
+                    Op::MemStore.encode(5, 0, 0),
+                ],
+                lowest_function_level: 0,
+                layout: MemoryLayout::new(1, 0, 0),
+                memory: vec![a],
+                expected: vec![f(a)],
+            });
+        }
+    }
+
+    let shifts: [(Op, ShiftFn); 4] = [
+        (Op::BitShiftLeft, |a, n| a << n),
+        (Op::BitShiftRight, |a, n| a >> n),
+        (Op::BitRotateLeft, i64::rotate_left),
+        (Op::BitRotateRight, i64::rotate_right),
+    ];
+    for (op, f) in shifts {
+        for a in SHIFT_OPERANDS {
+            for amount in [0, 1, 7, 31, 32, 63] {
+                vectors.push(TestVector {
+                    name: format!("{op:?}({a}, {amount})"),
+                    code: vec![
+                        Op::MemLoad.encode(0, 0, 0),
+                        // The amount is taken from the lowest 6 bits of the immediate
+                        op.encode(1, 0, amount | 0x40),
+                        Op::MemStore.encode(1, 0, 1),
+                    ],
+                    lowest_function_level: 0,
+                    layout: MemoryLayout::new(2, 0, 0),
+                    memory: vec![a, 0],
+                    expected: vec![a, f(a, amount)],
+                });
+            }
+        }
+    }
+
+    for (mask, a, b) in [
+        (0, 0x1F, 0x0F),
+        (-1, 0x1F, 0x0F),
+        (i64::MIN, i64::MAX, -1),
+        (
+            0xAAAAAAAAAAAAAAAAu64 as i64,
+            0xDDDDDDDDDDDDDDDDu64 as i64,
+            0x6666666666666666,
+        ),
+    ] {
+        vectors.push(TestVector {
+            name: format!("BitSelect({mask}, {a}, {b})"),
+            code: vec![
+                Op::MemLoad.encode(0, 0, 0),
+                Op::MemLoad.encode(1, 0, 1),
+                Op::MemLoad.encode(2, 0, 2),
+                // mask, a and b are encoded in b, c and d respectively
+                Op::BitSelect.encode(3, 0, 1 | (2 << 14)),
+                Op::MemStore.encode(3, 0, 3),
+            ],
+            lowest_function_level: 0,
+            layout: MemoryLayout::new(4, 0, 0),
+            memory: vec![mask, a, b, 0],
+            expected: vec![mask, a, b, (a & mask) | (b & !mask)],
+        });
+    }
+
+    const MARKER: i64 = 0x0DEADBEEDEADBEEF;
+    for (kind, name, f) in [
+        (0, "Eq", i64::eq as fn(&i64, &i64) -> bool),
+        (1, "Neq", i64::ne),
+        (2, "Gt", i64::gt),
+        (3, "Lt", i64::lt),
+    ] {
+        for (a, b) in BINARY_OPERANDS {
+            // The branch is the third of five instructions, skipping the last two when taken.
+            // The second operand shares its bits with the offset.
+            let imm = branch_imm(1, 2, 3);
+            vectors.push(TestVector {
+                name: format!("BranchCmp{name}({a}, {b})"),
+                code: vec![
+                    Op::MemLoad.encode(0, 0, 0),
+                    Op::MemLoad.encode(1, 0, 1),
+                    Op::BranchCmp.encode(kind, 0, imm),
+                    Op::MemLoad.encode(2, 0, 2),
+                    Op::MemStore.encode(2, 0, 0),
+                ],
+                lowest_function_level: 0,
+                layout: MemoryLayout::new(3, 0, 0),
+                memory: vec![a, b, MARKER],
+                expected: vec![if f(&a, &b) { a } else { MARKER }, b, MARKER],
+            });
+        }
+    }
+
+    for (op, f) in [
+        (Op::BranchZero, (|a| a == 0) as fn(i64) -> bool),
+        (Op::BranchNonZero, |a| a != 0),
+    ] {
+        for a in [0, 1, -1, i64::MIN] {
+            vectors.push(TestVector {
+                name: format!("{op:?}({a})"),
+                code: vec![
+                    Op::MemLoad.encode(0, 0, 0),
+                    op.encode(0, 0, branch_imm(0, 2, 3)),
+                    Op::MemLoad.encode(1, 0, 1),
+                    Op::MemStore.encode(1, 0, 0),
+                ],
+                lowest_function_level: 0,
+                layout: MemoryLayout::new(2, 0, 0),
+                memory: vec![a, MARKER],
+                expected: vec![if f(a) { a } else { MARKER }, MARKER],
+            });
+        }
+    }
+
+    // A branch offset of 0 is compiled as a no-op.
+    vectors.push(TestVector {
+        name: "BranchZeroOffset".into(),
+        code: vec![
+            Op::BranchZero.encode(0, 0, 3),
+            Op::MemLoad.encode(0, 0, 1),
+            Op::MemStore.encode(0, 0, 0),
+        ],
+        lowest_function_level: 0,
+        layout: MemoryLayout::new(2, 0, 0),
+        memory: vec![0, MARKER],
+        expected: vec![MARKER, MARKER],
+    });
+
+    vectors.push(TestVector {
+        name: "Banks".into(),
+        code: vec![
+            Op::InputLoad.encode(0, 0, 1),
+            Op::OutputStore.encode(0, 0, 2),
+            Op::MemLoad.encode(1, 0, 3),
+            Op::IntAdd.encode(1, 1, 0),
+            Op::MemStore.encode(1, 0, 4),
+        ],
+        lowest_function_level: 0,
+        layout: MemoryLayout::new(2, 3, 2),
+        memory: vec![10, 20, 1, 2, 3, 4, 5],
+        expected: vec![25, 20, 0, 0, 5, 4, 5],
+    });
+
+    vectors.push(TestVector {
+        name: "RegistersStartAtZero".into(),
+        code: vec![
+            Op::IntInc.encode(63, 0, 0),
+            Op::MemStore.encode(63, 0, 0),
+            Op::MemStore.encode(7, 0, 1),
+        ],
+        lowest_function_level: 0,
+        layout: MemoryLayout::new(2, 0, 0),
+        memory: vec![5, 5],
+        expected: vec![1, 0],
+    });
+
+    // Functions are separated by `end_func`, empty functions are removed.
+    vectors.push(TestVector {
+        name: "Call".into(),
+        code: vec![
+            Op::IntInc.encode(0, 0, 0),
+            Op::Call.encode(0, 0, 0),
+            Op::MemStore.encode(0, 0, 0),
+            Op::EndFunc.encode(0, 0, 0),
+            Op::EndFunc.encode(0, 0, 0),
+            Op::MemLoad.encode(0, 0, 1),
+            Op::MemStore.encode(0, 0, 2),
+            Op::IntInc.encode(1, 0, 0),
+            Op::MemStore.encode(1, 0, 1),
+        ],
+        lowest_function_level: 1,
+        layout: MemoryLayout::new(3, 0, 0),
+        memory: vec![0, 9, 0],
+        expected: vec![1, 1, 9],
+    });
+
+    // Without any function levels there is nothing to call, so calls are no-ops.
+    vectors.push(TestVector {
+        name: "CallSameLevel".into(),
+        code: vec![
+            Op::Call.encode(0, 0, 0),
+            Op::EndFunc.encode(0, 0, 0),
+            Op::Call.encode(0, 0, 1),
+            Op::MemLoad.encode(0, 0, 0),
+            Op::IntInc.encode(0, 0, 0),
+            Op::MemStore.encode(0, 0, 0),
+        ],
+        lowest_function_level: 0,
+        layout: MemoryLayout::new(1, 0, 0),
+        memory: vec![0],
+        expected: vec![0],
+    });
+
+    vectors
+}
+
+type BinaryFn = fn(i64, i64) -> i64;
+type UnaryFn = fn(i64) -> i64;
+type ShiftFn = fn(i64, u32) -> i64;
+
+const BINARY_OPERANDS: [(i64, i64); 12] = [
+    (31, 11),
+    (31, -11),
+    (11, -31),
+    (-31, -11),
+    (0, -1),
+    (-1, -1),
+    (i64::MIN, -1),
+    (i64::MAX, 1),
+    (i64::MAX, -16),
+    (i64::MIN, 16),
+    (i64::MIN, i64::MIN),
+    (i64::MIN, i64::MAX),
+];
+
+const UNARY_OPERANDS: [i64; 8] = [0, 1, -1, 1000, -93, i64::MIN, i64::MAX, 0x0123456789ABCDEF];
+
+const SHIFT_OPERANDS: [i64; 5] = [1, -1, -93, i64::MIN, i64::MAX];
+
+/// Find an immediate value that produces the given branch offset while keeping the lowest 6
+/// bits equal to `low`.
+fn branch_imm(low: u32, offset: u32, offset_end: u32) -> u32 {
+    (0..)
+        .map(|i| low + (i << 6))
+        .find(|imm| imm % offset_end == offset)
+        .unwrap()
+}
+
+/// The instructions in the order they are decoded.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    EndFunc,
+    Call,
+    IntAdd,
+    IntSub,
+    IntMul,
+    IntMulHigh,
+    IntMulHighUnsigned,
+    IntNeg,
+    IntAbs,
+    IntInc,
+    IntDec,
+    IntMin,
+    IntMax,
+    BitOr,
+    BitAnd,
+    BitXor,
+    BitNot,
+    BitShiftLeft,
+    BitShiftRight,
+    BitRotateLeft,
+    BitRotateRight,
+    BitSelect,
+    BitPopcnt,
+    BitReverse,
+    BranchCmp,
+    BranchZero,
+    BranchNonZero,
+    MemLoad,
+    InputLoad,
+    MemStore,
+    OutputStore,
+}
+
+impl Op {
+    fn kind(self) -> u16 {
+        type F = DefaultFrequencies;
+        let frequencies = [
+            F::END_FUNC,
+            F::CALL,
+            F::INT_ADD,
+            F::INT_SUB,
+            F::INT_MUL,
+            F::INT_MUL_HIGH,
+            F::INT_MUL_HIGH_UNSIGNED,
+            F::INT_NEG,
+            F::INT_ABS,
+            F::INT_INC,
+            F::INT_DEC,
+            F::INT_MIN,
+            F::INT_MAX,
+            F::BIT_OR,
+            F::BIT_AND,
+            F::BIT_XOR,
+            F::BIT_NOT,
+            F::BIT_SHIFT_L,
+            F::BIT_SHIFT_R,
+            F::BIT_ROT_L,
+            F::BIT_ROT_R,
+            F::BIT_SELECT,
+            F::BIT_POPCNT,
+            F::BIT_REVERSE,
+            F::BRANCH_CMP,
+            F::BRANCH_ZERO,
+            F::BRANCH_NON_ZERO,
+            F::MEM_LOAD,
+            F::INPUT_LOAD,
+            F::MEM_STORE,
+            F::OUTPUT_STORE,
+        ];
+
+        frequencies[..self as usize].iter().sum()
+    }
+
+    /// Encode an instruction, `c` and `d` are taken from the lowest 6 bits and bits 14 to 20
+    /// of `imm`.
+    pub(crate) fn encode(self, a: u8, b: u8, imm: u32) -> u64 {
+        u64::from(self.kind()) | u64::from(a) << 16 | u64::from(b) << 22 | u64::from(imm) << 32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen;
+
+    #[test]
+    fn interpreter() {
+        check(codegen::Interpreter::new()).unwrap();
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit() {
+        check(codegen::Jit::new()).unwrap();
+    }
+
+    #[cfg(feature = "cranelift")]
+    #[test]
+    fn cranelift() {
+        check(codegen::Cranelift::new()).unwrap();
+    }
+}
@@ -0,0 +1,337 @@
+use crate::{
+    codegen::CodeGenerator, Compiler, DefaultFrequencies, InstructionFrequencies, MemoryLayout,
+    Runner,
+};
+
+use std::{fmt, marker::PhantomData};
+
+type Run<'a> = dyn FnMut(&[u64], u32, MemoryLayout, &mut [i64]) + 'a;
+
+/// A named compiler to compare with others in a [differential] test.
+pub struct Backend<'a, F: InstructionFrequencies = DefaultFrequencies> {
+    name: String,
+    run: Box<Run<'a>>,
+    frequencies: PhantomData<F>,
+}
+
+impl<'a, F: InstructionFrequencies> Backend<'a, F> {
+    /// A backend that compiles code with `compiler`.
+    ///
+    /// The output init and scratch size of the compiler are set to the ones of the layout that
+    /// is tested, its constants, host functions and other settings are used as they are.
+    pub fn new<G>(name: impl Into<String>, compiler: &'a mut Compiler<G>) -> Self
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+    {
+        let run =
+            move |code: &[u64], lowest_function_level, layout: MemoryLayout, memory: &mut [i64]| {
+                compiler.set_output_init(layout.output_init);
+                compiler.set_scratch_size(layout.scratch_size);
+                let runner = compiler.compile_with_frequencies::<F>(
+                    code,
+                    lowest_function_level,
+                    layout.memory_size,
+                    layout.output_size,
+                    layout.input_size,
+                );
+                runner.step(memory);
+            };
+
+        Self::from_fn(name, run)
+    }
+
+    /// A backend that runs code with a function, for code generators whose runners do not
+    /// implement [Runner].
+    ///
+    /// `run` gets the code, the lowest function level and the layout to compile with, and the
+    /// memory to step. It has to use the instruction frequencies `F` to compile the code.
+    pub fn from_fn<R>(name: impl Into<String>, run: R) -> Self
+    where
+        R: FnMut(&[u64], u32, MemoryLayout, &mut [i64]) + 'a,
+    {
+        Self {
+            name: name.into(),
+            run: Box::new(run),
+            frequencies: PhantomData,
+        }
+    }
+
+    /// The name of the backend.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Two backends that left different memories behind after one step, returned by
+/// [differential].
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// The name of the backend that the others are compared with.
+    pub reference: String,
+    /// The name of the backend that did not match the reference.
+    pub backend: String,
+    /// The length of a prefix of the code that the backends already diverge on, while they
+    /// agree on the prefix that is one code word shorter. Unless it is 0, the code word at
+    /// `prefix_len - 1` triggers the divergence, and the memories below are the results of
+    /// running just the prefix.
+    pub prefix_len: usize,
+    /// The memory before the step.
+    pub memory: Vec<i64>,
+    /// The memory after the step of the reference.
+    pub expected: Vec<i64>,
+    /// The memory after the step of the diverging backend.
+    pub actual: Vec<i64>,
+}
+
+impl Divergence {
+    /// The index, expected value and actual value of every value that differs.
+    pub fn differences(&self) -> impl Iterator<Item = (usize, i64, i64)> + '_ {
+        self.expected
+            .iter()
+            .zip(&self.actual)
+            .enumerate()
+            .filter(|(_, (expected, actual))| expected != actual)
+            .map(|(i, (&expected, &actual))| (i, expected, actual))
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} diverges from {} on the first {} code words:",
+            self.backend, self.reference, self.prefix_len,
+        )?;
+        for (i, expected, actual) in self.differences() {
+            write!(f, " memory[{i}] expected {expected}, got {actual};")?;
+        }
+        write!(f, " initial memory {:?}", self.memory)
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Run one step of the same code on the same memory with every backend, and compare the
+/// resulting memories with the ones of the first backend.
+///
+/// When the backends diverge, the prefixes of the code are bisected for one that they diverge on
+/// while they agree on the prefix that is one code word shorter, which points at the code word
+/// that triggers the difference. Compiling a prefix can change which functions calls go to, so
+/// this is a hint rather than a guarantee.
+///
+/// # Panics
+/// If `backends` is empty, or if `memory` is smaller than `layout`.
+pub fn differential(
+    code: &[u64],
+    lowest_function_level: u32,
+    layout: MemoryLayout,
+    memory: &[i64],
+    backends: &mut [Backend<'_>],
+) -> Result<(), Box<Divergence>> {
+    differential_with_frequencies(code, lowest_function_level, layout, memory, backends)
+}
+
+/// Like [differential], but using custom instruction frequencies.
+pub fn differential_with_frequencies<F: InstructionFrequencies>(
+    code: &[u64],
+    lowest_function_level: u32,
+    layout: MemoryLayout,
+    memory: &[i64],
+    backends: &mut [Backend<'_, F>],
+) -> Result<(), Box<Divergence>> {
+    assert!(!backends.is_empty(), "no backends to compare");
+    assert!(
+        layout.size() as usize <= memory.len(),
+        "memory does not fit the layout"
+    );
+
+    let mut diverges = |prefix_len| {
+        compare(
+            &code[..prefix_len],
+            lowest_function_level,
+            layout,
+            memory,
+            backends,
+        )
+    };
+    let Some(mut divergence) = diverges(code.len()) else {
+        return Ok(());
+    };
+    if let Some(empty) = diverges(0) {
+        return Err(Box::new(empty));
+    }
+
+    // The backends agree on the first `agree` code words
+    let mut agree = 0;
+    while divergence.prefix_len - agree > 1 {
+        let mid = agree + (divergence.prefix_len - agree) / 2;
+        match diverges(mid) {
+            Some(shorter) => divergence = shorter,
+            None => agree = mid,
+        }
+    }
+    Err(Box::new(divergence))
+}
+
+/// A xorshift random number generator, which always produces the same values for the same
+/// seed. Used to generate code and memories for tests, see [random_code].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xorshift {
+    state: u64,
+}
+
+impl Xorshift {
+    /// Create a generator with the given seed.
+    ///
+    /// # Panics
+    /// If `seed` is 0, which xorshift never moves away from.
+    pub fn new(seed: u64) -> Self {
+        assert_ne!(seed, 0, "the seed of a xorshift generator can not be 0");
+        Self { state: seed }
+    }
+
+    /// Generate the next value.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl Default for Xorshift {
+    /// The generator the tests of this crate use.
+    fn default() -> Self {
+        Self::new(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+/// Generate `len` random code words.
+///
+/// Every code word decodes to some instruction, so random code is a cheap way to cover many
+/// combinations of instructions and operands when comparing backends.
+pub fn random_code(rng: &mut Xorshift, len: usize) -> Vec<u64> {
+    (0..len).map(|_| rng.next_u64()).collect()
+}
+
+/// Generate a memory of `len` random values.
+pub fn random_memory(rng: &mut Xorshift, len: usize) -> Vec<i64> {
+    (0..len).map(|_| rng.next_u64() as i64).collect()
+}
+
+fn compare<F: InstructionFrequencies>(
+    code: &[u64],
+    lowest_function_level: u32,
+    layout: MemoryLayout,
+    memory: &[i64],
+    backends: &mut [Backend<'_, F>],
+) -> Option<Divergence> {
+    let run = |backend: &mut Backend<'_, F>| {
+        let mut memory = memory.to_vec();
+        (backend.run)(code, lowest_function_level, layout, &mut memory);
+        memory
+    };
+
+    let (reference, others) = backends.split_first_mut().unwrap();
+    let expected = run(reference);
+    others.iter_mut().find_map(|backend| {
+        let actual = run(backend);
+        (actual != expected).then(|| Divergence {
+            reference: reference.name.clone(),
+            backend: backend.name.clone(),
+            prefix_len: code.len(),
+            memory: memory.to_vec(),
+            expected: expected.clone(),
+            actual,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codegen;
+
+    #[test]
+    fn find_divergence() {
+        let mut rng = Xorshift::default();
+        let mut interpreter = Compiler::new(codegen::Interpreter::new());
+        let mut bytecode = Compiler::new(codegen::Bytecode::new());
+        // Null runners never touch the memory, so they diverge on any code that stores
+        let mut null = Compiler::new(codegen::NullGen::new());
+        let layout = MemoryLayout::new(8, 4, 4);
+        let mut divergences = 0;
+        for _ in 0..20 {
+            let code = random_code(&mut rng, 256);
+            let mut memory = random_memory(&mut rng, 16);
+            // Null runners don't clear the output either
+            memory[8..12].fill(0);
+
+            let mut backends = [
+                Backend::new("interpreter", &mut interpreter),
+                Backend::new("bytecode", &mut bytecode),
+            ];
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+
+            backends[1] = Backend::new("null", &mut null);
+            if let Err(divergence) = differential(&code, 3, layout, &memory, &mut backends) {
+                assert_eq!(divergence.backend, "null");
+                assert!(divergence.differences().next().is_some());
+                let prefix = &code[..divergence.prefix_len - 1];
+                differential(prefix, 3, layout, &memory, &mut backends).unwrap();
+                divergences += 1;
+            }
+        }
+        assert!(divergences > 0);
+    }
+
+    #[test]
+    fn bisect() {
+        // A backend that only goes wrong once the code is 100 words long
+        let mut runs = 0;
+        let mut backends = [
+            Backend::from_fn("reference", |_: &[u64], _, _, _: &mut [i64]| ()),
+            Backend::from_fn("broken", |code: &[u64], _, _, memory: &mut [i64]| {
+                runs += 1;
+                memory[0] = (code.len() >= 100).into();
+            }),
+        ];
+        let code = vec![0; 1000];
+        let divergence =
+            differential(&code, 1, MemoryLayout::new(1, 0, 0), &[0], &mut backends).unwrap_err();
+
+        assert_eq!(divergence.prefix_len, 100);
+        assert_eq!(divergence.differences().collect::<Vec<_>>(), [(0, 0, 1)]);
+        // The whole code, the empty prefix and 10 steps of bisection
+        drop(backends);
+        assert_eq!(runs, 12);
+    }
+
+    #[test]
+    fn xorshift() {
+        let mut rng = Xorshift::default();
+        let code = random_code(&mut rng, 4);
+        assert_eq!(code.len(), 4);
+        assert!(code.windows(2).all(|w| w[0] != w[1]));
+        assert_eq!(random_code(&mut Xorshift::default(), 4), code);
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit() {
+        let mut rng = Xorshift::default();
+        let mut interpreter = Compiler::new(codegen::Interpreter::new());
+        let mut jit = Compiler::new(codegen::Jit::new());
+        let mut backends = [
+            Backend::new("interpreter", &mut interpreter),
+            Backend::new("jit", &mut jit),
+        ];
+        let layout = MemoryLayout::new(8, 4, 4).with_scratch_size(2);
+        for _ in 0..50 {
+            let code = random_code(&mut rng, 256);
+            let memory = random_memory(&mut rng, 18);
+            differential(&code, 3, layout, &memory, &mut backends).unwrap();
+        }
+    }
+}
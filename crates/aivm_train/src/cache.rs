@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A hash of expanded code and memory that stays the same across runs, platforms and versions
+/// of Rust, so it can identify programs in saved files as well.
+pub fn program_hash(code: &[u64], memory: &[i64]) -> u64 {
+    const K: u64 = 0x517C_C1B7_2722_0A95;
+
+    let mut hash = code.len() as u64;
+    let words = code.iter().copied().chain(memory.iter().map(|&v| v as u64));
+    for word in words {
+        hash = (hash.rotate_left(5) ^ word).wrapping_mul(K);
+    }
+
+    // The finalizer of SplitMix64, so every input bit affects every output bit
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94D0_49BB_1331_11EB);
+    hash ^ (hash >> 31)
+}
+
+/// How often a [FitnessCache] was able to answer a lookup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The amount of lookups that found a fitness.
+    pub hits: u64,
+    /// The amount of lookups that did not.
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// The share of lookups that found a fitness, between 0.0 and 1.0, or 0.0 if nothing was
+    /// looked up yet.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// The fitness of recently evaluated programs, keyed by their [program_hash].
+///
+/// Under elitism and low mutation rates many genomes of a generation expand to exactly the same
+/// program, which then only has to be evaluated once. When the cache is full the least recently
+/// used fitness is forgotten.
+///
+/// A cached fitness is the result of the first evaluation, so with a noisy [Fitness](crate::fitness::Fitness)
+/// the same program keeps the score of its first episodes.
+#[derive(Debug, Clone)]
+pub struct FitnessCache {
+    capacity: usize,
+    entries: HashMap<u64, (f64, u64)>,
+    recency: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl FitnessCache {
+    /// Create an empty cache that remembers at most `capacity` fitness values.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// The maximum amount of fitness values remembered.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The amount of fitness values remembered.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no fitness is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The hits and misses of all lookups so far.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Start counting hits and misses from zero, for example to report the hit rate of every
+    /// generation separately.
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+    }
+
+    /// The fitness of the program with `hash`, marking it as recently used.
+    pub fn get(&mut self, hash: u64) -> Option<f64> {
+        let Some((fitness, used)) = self.entries.get_mut(&hash) else {
+            self.stats.misses += 1;
+            return None;
+        };
+
+        self.stats.hits += 1;
+        self.recency.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.recency.insert(self.clock, hash);
+        Some(*fitness)
+    }
+
+    /// Remember the fitness of the program with `hash`, forgetting the least recently used one
+    /// if the cache is full.
+    pub fn insert(&mut self, hash: u64, fitness: f64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.clock += 1;
+        if let Some((_, used)) = self.entries.insert(hash, (fitness, self.clock)) {
+            self.recency.remove(&used);
+        } else if self.entries.len() > self.capacity {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            self.entries.remove(&oldest);
+        }
+        self.recency.insert(self.clock, hash);
+    }
+
+    /// Forget every fitness, for example after changing the constants of the compiler. The
+    /// statistics are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used() {
+        let mut cache = FitnessCache::new(2);
+        cache.insert(1, 1.0);
+        cache.insert(2, 2.0);
+        assert_eq!(cache.get(1), Some(1.0));
+        cache.insert(3, 3.0);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(1.0));
+        assert_eq!(cache.get(3), Some(3.0));
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 1 });
+        assert_eq!(cache.stats().hit_rate(), 0.75);
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(program_hash(&[1, 2], &[3]), program_hash(&[1, 2], &[3]));
+        // Moving a word from the code to the memory is a different program
+        assert_ne!(program_hash(&[1, 2], &[3]), program_hash(&[1], &[2, 3]));
+        assert_ne!(program_hash(&[1, 2], &[3]), program_hash(&[1, 2], &[4]));
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{
+    evolution::{persist, LoadError},
+    seed::RunSeed,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 8] = b"AIVMCKPT";
+/// The version of the serialized format, which only changes when saved checkpoints can no
+/// longer be loaded by an older version of this crate.
+const FORMAT_VERSION: u16 = 1;
+
+/// The state of a training run, such as a [Population](crate::evolution::Population) or
+/// [Islands](crate::evolution::Islands), together with the hyperparameters it was trained with.
+///
+/// Populations include their random generators, generation counter and hall of fame, so a run
+/// that is resumed from a checkpoint continues exactly like an uninterrupted one would have.
+/// [Speciation](crate::evolution::Speciation) is not saved, it has to be set again after
+/// resuming.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint<S, H = ()> {
+    /// The state of the run.
+    pub state: S,
+    /// The settings of the run, such as the population size and mutation rate.
+    pub hyperparameters: H,
+    /// The seed all randomness of the run was derived from, if it was recorded.
+    pub seed: Option<RunSeed>,
+}
+
+impl<S: Serialize, H: Serialize> Checkpoint<S, H> {
+    /// Write the checkpoint to `path`, replacing an earlier checkpoint.
+    ///
+    /// The checkpoint is first written to a temporary file next to `path`, which then replaces
+    /// it, so a crash while saving never destroys the previous checkpoint.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let bytes = persist::serialize(MAGIC, FORMAT_VERSION, self).map_err(invalid_data)?;
+
+        let temp = temp_path(path);
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(temp, path)
+    }
+}
+
+impl<S: DeserializeOwned, H: DeserializeOwned> Checkpoint<S, H> {
+    /// Load a checkpoint that was written with [save](Self::save), to resume the run.
+    pub fn resume_from(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut bytes = vec![];
+        fs::File::open(path)?.read_to_end(&mut bytes)?;
+
+        persist::deserialize(MAGIC, FORMAT_VERSION, &bytes).map_err(|error| match error {
+            LoadError::Malformed => invalid_data("malformed checkpoint"),
+            LoadError::UnsupportedVersion { found } => invalid_data(format!(
+                "checkpoint saved in format version {found}, supported up to {FORMAT_VERSION}"
+            )),
+        })
+    }
+}
+
+/// Saves checkpoints of a run to a file at a fixed interval of generations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpointer {
+    path: PathBuf,
+    interval: u64,
+    seed: Option<RunSeed>,
+}
+
+impl Checkpointer {
+    /// Save to `path` every `interval` generations.
+    ///
+    /// # Panics
+    /// If `interval` is 0.
+    pub fn new(path: impl Into<PathBuf>, interval: u64) -> Self {
+        assert!(interval > 0, "invalid checkpoint interval");
+        Self {
+            path: path.into(),
+            interval,
+            seed: None,
+        }
+    }
+
+    /// The same checkpointer, but recording `seed` in every checkpoint.
+    pub fn with_seed(self, seed: RunSeed) -> Self {
+        Self {
+            seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// The file checkpoints are saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Save a checkpoint of `state` and `hyperparameters` if `generation` is a multiple of the
+    /// interval. Returns whether a checkpoint was saved.
+    pub fn save_if_due<S: Serialize, H: Serialize>(
+        &self,
+        generation: u64,
+        state: &S,
+        hyperparameters: &H,
+    ) -> io::Result<bool> {
+        if !generation.is_multiple_of(self.interval) {
+            return Ok(false);
+        }
+
+        Checkpoint {
+            state,
+            hyperparameters,
+            seed: self.seed,
+        }
+        .save(&self.path)?;
+        Ok(true)
+    }
+
+    /// Load the last saved checkpoint, see [Checkpoint::resume_from].
+    pub fn resume<S: DeserializeOwned, H: DeserializeOwned>(&self) -> io::Result<Checkpoint<S, H>> {
+        Checkpoint::resume_from(&self.path)
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        evolution::{fill_mutate_bits, AppendSeed, HallOfFame, Population, Tournament},
+        fitness::Evaluator,
+        seed::Stream,
+    };
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout, Runner};
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn resume() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness = |runner: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| {
+            runner.step(memory.as_mut_slice());
+            memory.output()[0] as f64
+        };
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, fitness);
+
+        let path = std::env::temp_dir().join(format!("aivm-checkpoint-{}", std::process::id()));
+        let checkpointer = Checkpointer::new(&path, 2).with_seed(RunSeed(3));
+        let mut population = Population::new(8, RunSeed(3).derive(Stream::Population));
+        population.set_elites(1);
+        population.set_hall_of_fame(HallOfFame::new(2));
+        let mut step = |population: &mut Population| {
+            population.step_generation(&mut evaluator, &mut Tournament::new(2), &mut AppendSeed)
+        };
+        let mut last = None;
+        for _ in 0..3 {
+            last = Some(step(&mut population));
+            checkpointer
+                .save_if_due(population.generation(), &population, &"settings")
+                .unwrap();
+        }
+
+        let checkpoint: Checkpoint<Population, String> = checkpointer.resume().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.hyperparameters, "settings");
+        assert_eq!(checkpoint.seed, Some(RunSeed(3)));
+        let mut resumed = checkpoint.state;
+        assert_eq!(resumed.generation(), 2);
+
+        // The third generation is the same as without the interruption
+        assert_eq!(Some(step(&mut resumed)), last);
+        assert_eq!(resumed.genomes(), population.genomes());
+        assert_eq!(resumed.hall_of_fame(), population.hall_of_fame());
+    }
+
+    #[test]
+    fn newer_version() {
+        let path = std::env::temp_dir().join(format!("aivm-versions-{}", std::process::id()));
+        Checkpoint {
+            state: 1u32,
+            hyperparameters: (),
+            seed: None,
+        }
+        .save(&path)
+        .unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[8..10].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let error = Checkpoint::<u32>::resume_from(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            "checkpoint saved in format version 2, supported up to 1"
+        );
+    }
+}
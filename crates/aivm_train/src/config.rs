@@ -0,0 +1,527 @@
+use crate::{
+    checkpoint::Checkpointer,
+    evolution::{
+        fill_mutate_bits, Constant, ExponentialDecay, OneFifthRule, Population, Schedule,
+        Selection, StopCriteria, Tournament, Truncation, MUTATION_OFFSET_BITS,
+    },
+    fitness::{Evaluator, Fitness},
+    seed::{RunSeed, Stream},
+    tuning::FrequencyTable,
+};
+
+use aivm::{codegen::CodeGenerator, Compiler, MemoryLayout, Runner};
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::Path, path::PathBuf, time::Duration};
+
+/// The hyperparameters of a training run in one place, so a run can be described by a TOML or
+/// JSON file and recorded in its checkpoints, as the hyperparameters of a
+/// [Checkpoint](crate::checkpoint::Checkpoint).
+///
+/// Fields that are left out of a file get their [default](Default) value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainConfig {
+    /// The amount of genomes of every generation.
+    pub population_size: usize,
+    /// The amount of fittest genomes copied unchanged into the next generation.
+    pub elites: usize,
+    /// The amount of code words of a genome.
+    pub code_len: usize,
+    /// The amount of words of the mutate bits, which must be longer than the code and memory.
+    /// Windows of code start in the first 2<sup>24</sup> words, see
+    /// [MUTATION_OFFSET_BITS](crate::evolution::MUTATION_OFFSET_BITS), so any more bits than
+    /// that plus the code length are never used.
+    pub mutate_bits_len: usize,
+    /// The lowest function level code is compiled with.
+    pub lowest_function_level: u32,
+    /// The amount of episodes the fitness of a genome is averaged over.
+    pub episodes: u32,
+    /// How the mutation probability changes over the run.
+    pub schedule: ScheduleConfig,
+    /// How parents are picked.
+    pub selection: SelectionConfig,
+    /// When the run is done.
+    pub budget: Budget,
+    /// The code generator code is compiled with.
+    pub backend: Backend,
+    /// The frequencies of the instruction kinds code is generated and compiled with, the
+    /// [DefaultFrequencies](aivm::DefaultFrequencies) by default. A table tuned with a
+    /// [FrequencyTuner](crate::tuning::FrequencyTuner) makes the next run generate more of
+    /// the instructions that proved useful.
+    pub frequencies: FrequencyTable,
+    /// The seed all randomness of the run is derived from, or `None` to take one from the
+    /// clock.
+    pub seed: Option<RunSeed>,
+    /// Where and how often checkpoints are saved, if at all.
+    pub checkpoint: Option<CheckpointConfig>,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            population_size: 256,
+            elites: 1,
+            code_len: 256,
+            mutate_bits_len: 1 << 16,
+            lowest_function_level: 1,
+            episodes: 1,
+            schedule: ScheduleConfig::Constant { p_mutate: 1000 },
+            selection: SelectionConfig::Tournament {
+                size: 4,
+                replacement: true,
+            },
+            budget: Budget::default(),
+            backend: Backend::Interpreter,
+            frequencies: FrequencyTable::default(),
+            seed: None,
+            checkpoint: None,
+        }
+    }
+}
+
+/// How the mutation probability changes, see [Schedule].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleConfig {
+    /// See [Constant].
+    Constant {
+        /// The probability of every generation.
+        p_mutate: u16,
+    },
+    /// See [ExponentialDecay].
+    ExponentialDecay {
+        /// The probability of the first generation.
+        initial: u16,
+        /// The factor the probability is multiplied by every generation.
+        factor: f64,
+        /// The lowest probability.
+        min: u16,
+    },
+    /// See [OneFifthRule].
+    OneFifthRule {
+        /// The probability of the first generation.
+        initial: u16,
+        /// The factor the probability is multiplied or divided by.
+        factor: f64,
+        /// The amount of generations between adaptations.
+        window: u32,
+        /// The highest probability, which the mutate bits are filled with.
+        max: u16,
+    },
+}
+
+impl ScheduleConfig {
+    /// Create the schedule, starting at the first generation.
+    ///
+    /// # Panics
+    /// If the parameters are invalid, see [TrainConfig::validate].
+    pub fn build(&self) -> ScheduleState {
+        match *self {
+            Self::Constant { p_mutate } => ScheduleState::Constant(Constant(p_mutate)),
+            Self::ExponentialDecay {
+                initial,
+                factor,
+                min,
+            } => ScheduleState::ExponentialDecay(
+                ExponentialDecay::new(initial, factor).with_min(min),
+            ),
+            Self::OneFifthRule {
+                initial,
+                factor,
+                window,
+                max,
+            } => ScheduleState::OneFifthRule(
+                OneFifthRule::new(initial, factor)
+                    .with_window(window)
+                    .with_max(max),
+            ),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        match *self {
+            Self::Constant { .. } => Ok(()),
+            Self::ExponentialDecay { factor, .. } if !(0.0..=1.0).contains(&factor) => {
+                Err(ConfigError::Invalid("decay factor must be between 0 and 1"))
+            }
+            Self::OneFifthRule { factor, .. } if factor.is_nan() || factor < 1.0 => {
+                Err(ConfigError::Invalid("adaptation factor must be at least 1"))
+            }
+            Self::OneFifthRule { window: 0, .. } => {
+                Err(ConfigError::Invalid("adaptation window must not be empty"))
+            }
+            Self::OneFifthRule { initial, max, .. } if max < initial => Err(ConfigError::Invalid(
+                "highest mutation probability must be at least the initial one",
+            )),
+            Self::ExponentialDecay { .. } | Self::OneFifthRule { .. } => Ok(()),
+        }
+    }
+}
+
+/// A schedule built from a [ScheduleConfig], which a run saves in its checkpoints so it
+/// continues where it was when the run is resumed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScheduleState {
+    /// See [Constant].
+    Constant(Constant),
+    /// See [ExponentialDecay].
+    ExponentialDecay(ExponentialDecay),
+    /// See [OneFifthRule].
+    OneFifthRule(OneFifthRule),
+}
+
+impl Schedule for ScheduleState {
+    fn p_mutate(&self) -> u16 {
+        match self {
+            Self::Constant(schedule) => schedule.p_mutate(),
+            Self::ExponentialDecay(schedule) => schedule.p_mutate(),
+            Self::OneFifthRule(schedule) => schedule.p_mutate(),
+        }
+    }
+
+    fn max_p_mutate(&self) -> u16 {
+        match self {
+            Self::Constant(schedule) => schedule.max_p_mutate(),
+            Self::ExponentialDecay(schedule) => schedule.max_p_mutate(),
+            Self::OneFifthRule(schedule) => schedule.max_p_mutate(),
+        }
+    }
+
+    fn advance(&mut self, improved: bool) {
+        match self {
+            Self::Constant(schedule) => schedule.advance(improved),
+            Self::ExponentialDecay(schedule) => schedule.advance(improved),
+            Self::OneFifthRule(schedule) => schedule.advance(improved),
+        }
+    }
+}
+
+/// How parents are picked, which is a [Selection] itself.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionConfig {
+    /// See [Truncation].
+    Truncation {
+        /// The share of the generation parents are picked from.
+        fraction: f64,
+    },
+    /// See [Tournament].
+    Tournament {
+        /// The amount of genomes of a tournament.
+        size: usize,
+        /// Whether genomes are drawn with replacement.
+        replacement: bool,
+    },
+}
+
+impl SelectionConfig {
+    fn validate(&self) -> Result<(), ConfigError> {
+        match *self {
+            Self::Truncation { fraction } if !(0.0..=1.0).contains(&fraction) => Err(
+                ConfigError::Invalid("truncation fraction must be between 0 and 1"),
+            ),
+            Self::Tournament { size: 0, .. } => {
+                Err(ConfigError::Invalid("tournaments must not be empty"))
+            }
+            Self::Truncation { .. } | Self::Tournament { .. } => Ok(()),
+        }
+    }
+}
+
+impl Selection for SelectionConfig {
+    fn select(&mut self, fitness: &[f64], count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        match *self {
+            Self::Truncation { fraction } => Truncation::new(fraction).select(fitness, count, rng),
+            Self::Tournament { size, replacement } => Tournament::new(size)
+                .with_replacement(replacement)
+                .select(fitness, count, rng),
+        }
+    }
+}
+
+/// When a run is done, every limit is optional.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budget {
+    /// The maximum amount of generations.
+    pub generations: Option<u64>,
+    /// The fitness to stop at, see [StopCriteria::with_target].
+    pub target_fitness: Option<f64>,
+    /// The generations without improvement to stop after, see [StopCriteria::with_patience].
+    pub patience: Option<u64>,
+    /// The maximum duration of the run in seconds, see [StopCriteria::with_budget].
+    pub seconds: Option<f64>,
+}
+
+impl Budget {
+    /// The criteria for every limit but the [generations](Self::generations), which are not
+    /// known to [StopCriteria]. The clock starts now.
+    pub fn stop_criteria(&self) -> StopCriteria {
+        let mut criteria = StopCriteria::new();
+        if let Some(target) = self.target_fitness {
+            criteria = criteria.with_target(target);
+        }
+        if let Some(patience) = self.patience {
+            criteria = criteria.with_patience(patience);
+        }
+        if let Some(seconds) = self.seconds {
+            criteria = criteria.with_budget(Duration::from_secs_f64(seconds));
+        }
+        criteria
+    }
+}
+
+/// A code generator of [aivm::codegen]. Some are only available with a feature of `aivm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// The [Interpreter](aivm::codegen::Interpreter), which runs everywhere.
+    #[default]
+    Interpreter,
+    /// The x86-64 JIT compiler of the `jit` feature.
+    Jit,
+    /// The Cranelift JIT compiler of the `cranelift` feature.
+    Cranelift,
+}
+
+/// Where and how often checkpoints are saved, see [Checkpointer].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CheckpointConfig {
+    /// The file checkpoints are written to.
+    pub path: PathBuf,
+    /// The amount of generations between checkpoints.
+    pub interval: u64,
+}
+
+impl TrainConfig {
+    /// Parse a configuration in TOML, and [validate](Self::validate) it.
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(toml).map_err(ConfigError::Toml)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a configuration in JSON, and [validate](Self::validate) it.
+    pub fn from_json(json: &str) -> Result<Self, ConfigError> {
+        let config: Self = serde_json::from_str(json).map_err(ConfigError::Json)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Load a configuration from a file, in JSON if its extension is `json` and in TOML
+    /// otherwise.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        }
+    }
+
+    /// The configuration in TOML.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("configurations are valid TOML")
+    }
+
+    /// Check that the values make sense together, so a run does not fail halfway.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.population_size == 0 {
+            return Err(ConfigError::Invalid("population must not be empty"));
+        }
+        if self.elites > self.population_size {
+            return Err(ConfigError::Invalid("more elites than genomes"));
+        }
+        if self.mutate_bits_len <= self.code_len {
+            return Err(ConfigError::Invalid(
+                "mutate bits must be longer than the code",
+            ));
+        }
+        if self.mutate_bits_len - self.code_len > 1 << MUTATION_OFFSET_BITS {
+            return Err(ConfigError::Invalid(
+                "mutate bits past the last window of code are never used",
+            ));
+        }
+        if self.episodes == 0 {
+            return Err(ConfigError::Invalid("at least one episode is needed"));
+        }
+        if self.checkpoint.as_ref().is_some_and(|c| c.interval == 0) {
+            return Err(ConfigError::Invalid("checkpoint interval must not be 0"));
+        }
+        self.schedule.validate()?;
+        self.selection.validate()
+    }
+
+    /// The seed of the run, taken from the clock if none was set.
+    pub fn run_seed(&self) -> RunSeed {
+        self.seed.unwrap_or_else(RunSeed::from_clock)
+    }
+
+    /// A population of random genomes with the configured size and elites, seeded from the
+    /// [Population](Stream::Population) stream of `seed`.
+    pub fn population(&self, seed: RunSeed) -> Population {
+        let mut population = Population::new(self.population_size, seed.derive(Stream::Population));
+        population.set_elites(self.elites);
+        population
+    }
+
+    /// An evaluator of genomes of the configured size, with mutate bits filled with the
+    /// highest probability of the schedule and the [MutateBits](Stream::MutateBits) stream of
+    /// `seed`, which compiles code with the configured frequencies. See [Evaluator::new] for
+    /// the other settings.
+    ///
+    /// # Panics
+    /// If the mutate bits are not longer than the memory bank of `layout`.
+    pub fn evaluator<G, F>(
+        &self,
+        compiler: Compiler<G>,
+        layout: MemoryLayout,
+        fitness: F,
+        seed: RunSeed,
+    ) -> Evaluator<G, F>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+    {
+        let mut mutate_bits = vec![0; self.mutate_bits_len];
+        let p_mutate = self.schedule.build().max_p_mutate();
+        fill_mutate_bits(&mut mutate_bits, seed.derive(Stream::MutateBits), p_mutate);
+        Evaluator::new(compiler, layout, self.code_len, mutate_bits, fitness)
+            .with_episodes(self.episodes)
+            .with_lowest_function_level(self.lowest_function_level)
+            .with_frequencies(self.frequencies)
+    }
+
+    /// The checkpointer of the run, if checkpoints are configured.
+    pub fn checkpointer(&self, seed: RunSeed) -> Option<Checkpointer> {
+        self.checkpoint
+            .as_ref()
+            .map(|c| Checkpointer::new(&c.path, c.interval).with_seed(seed))
+    }
+}
+
+/// Returned when a [TrainConfig] can not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The TOML could not be parsed into a configuration.
+    Toml(toml::de::Error),
+    /// The JSON could not be parsed into a configuration.
+    Json(serde_json::Error),
+    /// The values do not make sense.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "could not read configuration: {error}"),
+            Self::Toml(error) => write!(f, "invalid TOML configuration: {error}"),
+            Self::Json(error) => write!(f, "invalid JSON configuration: {error}"),
+            Self::Invalid(reason) => write!(f, "invalid configuration: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Toml(error) => Some(error),
+            Self::Json(error) => Some(error),
+            Self::Invalid(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::Checkpoint;
+
+    #[test]
+    fn parse() {
+        let config = TrainConfig::from_toml(
+            r#"
+            population_size = 64
+            seed = 7
+
+            [schedule.exponential_decay]
+            initial = 2000
+            factor = 0.99
+            min = 100
+
+            [selection.truncation]
+            fraction = 0.25
+
+            [budget]
+            generations = 100
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.population_size, 64);
+        assert_eq!(config.code_len, TrainConfig::default().code_len);
+        assert_eq!(config.seed, Some(RunSeed(7)));
+        assert_eq!(config.budget.generations, Some(100));
+        assert_eq!(config.schedule.build().p_mutate(), 2000);
+
+        assert_eq!(TrainConfig::from_toml(&config.to_toml()).unwrap(), config);
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(TrainConfig::from_json(&json).unwrap(), config);
+
+        assert!(matches!(
+            TrainConfig::from_toml("population_size = 0"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            TrainConfig::from_toml(
+                "[schedule.one_fifth_rule]\ninitial = 2000\nfactor = 1.5\nwindow = 10\nmax = 1000"
+            ),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(matches!(
+            TrainConfig::from_toml("code_len = 16\nmutate_bits_len = 16777233"),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(TrainConfig::from_toml("code_len = 16\nmutate_bits_len = 16777232").is_ok());
+        assert!(matches!(
+            TrainConfig::from_toml("populaton_size = 8"),
+            Err(ConfigError::Toml(_))
+        ));
+        assert!(matches!(
+            TrainConfig::from_toml("frequencies = [65535, 1]"),
+            Err(ConfigError::Toml(_))
+        ));
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut frequencies = FrequencyTable::default().frequencies();
+        frequencies.swap(2, 3);
+        let config = TrainConfig {
+            frequencies: FrequencyTable::new(frequencies).unwrap(),
+            ..TrainConfig::default()
+        };
+        config.validate().unwrap();
+
+        let path = std::env::temp_dir().join(format!("aivm-config-{}", std::process::id()));
+        Checkpoint {
+            state: (),
+            hyperparameters: config.clone(),
+            seed: None,
+        }
+        .save(&path)
+        .unwrap();
+        let checkpoint = Checkpoint::<(), TrainConfig>::resume_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(checkpoint.hyperparameters, config);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    evolution::{GenerationStats, MutateBits, Mutation, Population, Selection},
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// When a level of a [Curriculum] is passed or failed, in terms of the median fitness of a
+/// generation at that level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stage {
+    /// The median fitness at which the next level is unlocked.
+    pub advance: f64,
+    /// The median fitness below which the population has collapsed, and is returned to the
+    /// previous level.
+    pub collapse: f64,
+}
+
+impl Stage {
+    /// Create a stage that advances at a median fitness of `advance` and rolls back below
+    /// `collapse`.
+    pub fn new(advance: f64, collapse: f64) -> Self {
+        Self { advance, collapse }
+    }
+}
+
+/// A change of the level of a [Curriculum].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelChange {
+    /// The population passed the previous level, and is now at this one.
+    Advanced(usize),
+    /// The population collapsed at the next level, and is now back at this one.
+    RolledBack(usize),
+}
+
+/// The current level of a [Curriculum], shared with the environments or fitness that should
+/// get harder as the level rises.
+///
+/// It can be cloned into the closure of a [Rollout](crate::environment::Rollout), and read
+/// from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct Level(Arc<AtomicUsize>);
+
+impl Level {
+    /// The current level, starting at 0.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Raises the difficulty of the training task as the population gets better at it.
+///
+/// Level `i` is passed once the median fitness of a generation reaches the
+/// [advance](Stage::advance) of stage `i`. When the median fitness stays below the
+/// [collapse](Stage::collapse) of the current stage for a number of generations in a row, the
+/// population is returned to the previous level. Since the fitness at different levels is
+/// measured differently, every stage has thresholds of its own.
+#[derive(Debug)]
+pub struct Curriculum {
+    stages: Vec<Stage>,
+    level: Level,
+    patience: u32,
+    collapsed: u32,
+}
+
+impl Curriculum {
+    /// Create a curriculum with a level for every stage, starting at level 0. The
+    /// [advance](Stage::advance) of the last stage is never used, and neither is the
+    /// [collapse](Stage::collapse) of the first one.
+    ///
+    /// # Panics
+    /// If `stages` is empty.
+    pub fn new(stages: Vec<Stage>) -> Self {
+        assert!(!stages.is_empty(), "a curriculum needs at least one stage");
+        Self {
+            stages,
+            level: Level::default(),
+            patience: 3,
+            collapsed: 0,
+        }
+    }
+
+    /// The same curriculum, but only rolling back after `patience` collapsed generations in a
+    /// row. The default is 3, so a single unlucky generation does not undo a level.
+    ///
+    /// # Panics
+    /// If `patience` is 0.
+    pub fn with_patience(self, patience: u32) -> Self {
+        assert!(patience > 0, "patience must be at least 1");
+        Self { patience, ..self }
+    }
+
+    /// The stages of every level.
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// The current level, starting at 0.
+    pub fn level(&self) -> usize {
+        self.level.get()
+    }
+
+    /// A handle to read the current level from, for example in an environment.
+    pub fn level_handle(&self) -> Level {
+        self.level.clone()
+    }
+
+    /// Move to `level`, for example when resuming a run.
+    ///
+    /// # Panics
+    /// If there is no stage for `level`.
+    pub fn set_level(&mut self, level: usize) {
+        assert!(level < self.stages.len(), "level out of range");
+        self.level.0.store(level, Ordering::Relaxed);
+        self.collapsed = 0;
+    }
+
+    /// Update the level with the median fitness of a generation at the current level, returns
+    /// the change of level, if any.
+    pub fn update(&mut self, median_fitness: f64) -> Option<LevelChange> {
+        let level = self.level();
+        let stage = self.stages[level];
+        if level + 1 < self.stages.len() && median_fitness >= stage.advance {
+            self.set_level(level + 1);
+            return Some(LevelChange::Advanced(level + 1));
+        }
+
+        let collapsed = median_fitness.is_nan() || median_fitness < stage.collapse;
+        if level > 0 && collapsed {
+            self.collapsed += 1;
+            if self.collapsed >= self.patience {
+                self.set_level(level - 1);
+                return Some(LevelChange::RolledBack(level - 1));
+            }
+        } else {
+            self.collapsed = 0;
+        }
+
+        None
+    }
+
+    /// Step a generation of `population` like
+    /// [step_generation](Population::step_generation), and update the level with its median
+    /// fitness.
+    ///
+    /// When the level changes, the fitness cache of `evaluator` is cleared, since the fitness
+    /// of every program changes with it.
+    pub fn step_generation<G, F, B>(
+        &mut self,
+        population: &mut Population,
+        evaluator: &mut Evaluator<G, F, B>,
+        selection: &mut impl Selection,
+        mutation: &mut impl Mutation,
+    ) -> (GenerationStats, Option<LevelChange>)
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let stats = population.step_generation(evaluator, selection, mutation);
+        let change = self.update(stats.median_fitness);
+        if change.is_some() {
+            if let Some(cache) = evaluator.cache_mut() {
+                cache.clear();
+            }
+        }
+
+        (stats, change)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels() {
+        let mut curriculum = Curriculum::new(vec![
+            Stage::new(10.0, 0.0),
+            Stage::new(20.0, 5.0),
+            Stage::new(30.0, 5.0),
+        ])
+        .with_patience(2);
+        let level = curriculum.level_handle();
+
+        assert_eq!(curriculum.update(9.0), None);
+        assert_eq!(curriculum.update(10.0), Some(LevelChange::Advanced(1)));
+        assert_eq!(level.get(), 1);
+
+        // A single collapsed generation is forgiven
+        assert_eq!(curriculum.update(4.0), None);
+        assert_eq!(curriculum.update(6.0), None);
+        assert_eq!(curriculum.update(f64::NAN), None);
+        assert_eq!(curriculum.update(4.0), Some(LevelChange::RolledBack(0)));
+        assert_eq!(level.get(), 0);
+
+        // The first level never rolls back, the last never advances
+        assert_eq!(curriculum.update(-100.0), None);
+        curriculum.set_level(2);
+        assert_eq!(curriculum.update(1000.0), None);
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{
+    evolution::{persist, Genome, MutateBits},
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use bincode::Options;
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// The largest message that is accepted, to not allocate huge buffers for corrupted lengths.
+const MAX_MESSAGE_LEN: u32 = 1 << 26;
+
+/// A connection between a [Coordinator] and a [Worker], which delivers whole messages in order.
+pub trait Transport {
+    /// Send one message.
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    /// Wait for the next message. Returns an error of kind
+    /// [UnexpectedEof](io::ErrorKind::UnexpectedEof) when the other side has disconnected.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+}
+
+/// A [Transport] over a byte stream such as a `TcpStream`, where every message is prefixed by
+/// its length.
+#[derive(Debug)]
+pub struct Framed<S>(pub S);
+
+impl<S: Read + Write> Transport for Framed<S> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let len = u32::try_from(message.len())
+            .ok()
+            .filter(|&len| len <= MAX_MESSAGE_LEN)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+        self.0.write_all(&len.to_le_bytes())?;
+        self.0.write_all(message)?;
+        self.0.flush()
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        self.0.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len);
+        if len > MAX_MESSAGE_LEN {
+            return Err(invalid_data());
+        }
+
+        let mut message = vec![0; len as usize];
+        self.0.read_exact(&mut message)?;
+        Ok(message)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Evaluate { id: u64, seed: u64, genome: Genome },
+    Shutdown,
+}
+
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    persist::options()
+        .serialize(message)
+        .expect("messages can always be serialized")
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    persist::options()
+        .deserialize(bytes)
+        .map_err(|_| invalid_data())
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed message")
+}
+
+/// Evaluates genomes on behalf of a [Coordinator].
+///
+/// Only the seeds of genomes are sent over the connection, so the worker must be set up with
+/// an evaluator that has the same settings and mutate bits as the one of the coordinator, and
+/// compiles the code itself.
+pub struct Worker<G: CodeGenerator, F, B = Vec<u64>> {
+    evaluator: Evaluator<G, F, B>,
+}
+
+impl<G, F, B> Worker<G, F, B>
+where
+    G: CodeGenerator + 'static,
+    G::Runner: Runner,
+    F: Fitness,
+    B: MutateBits,
+{
+    /// Create a worker that evaluates genomes with `evaluator`.
+    pub fn new(evaluator: Evaluator<G, F, B>) -> Self {
+        Self { evaluator }
+    }
+
+    /// The evaluator genomes are evaluated with.
+    pub fn evaluator(&self) -> &Evaluator<G, F, B> {
+        &self.evaluator
+    }
+
+    /// Evaluate the genomes sent over `transport` until the coordinator shuts the worker down
+    /// or disconnects.
+    pub fn run(&mut self, transport: &mut impl Transport) -> io::Result<()> {
+        loop {
+            let message = match transport.recv() {
+                Ok(message) => message,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match decode(&message)? {
+                Request::Evaluate { id, seed, genome } => {
+                    let fitness = self
+                        .evaluator
+                        .evaluate(&genome, &mut Pcg64::seed_from_u64(seed));
+                    transport.send(&encode(&(id, fitness)))?;
+                }
+                Request::Shutdown => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Distributes the evaluation of genomes over [Workers](Worker), for example on other machines.
+///
+/// Genomes are sent as their seeds, so no compiled code goes over the wire. Every worker is
+/// given a new genome as soon as it returns the fitness of the previous one, so faster workers
+/// evaluate more genomes.
+pub struct Coordinator<T> {
+    transports: Vec<T>,
+}
+
+impl<T: Transport + Send> Coordinator<T> {
+    /// Create a coordinator with a connection to every worker.
+    ///
+    /// # Panics
+    /// If `transports` is empty.
+    pub fn new(transports: Vec<T>) -> Self {
+        assert!(!transports.is_empty(), "no workers");
+        Self { transports }
+    }
+
+    /// The amount of workers.
+    pub fn worker_count(&self) -> usize {
+        self.transports.len()
+    }
+
+    /// Evaluate `genomes` on the workers, seeding the random generator of every genome with
+    /// the seed at the same index in `seeds`. This fits
+    /// [Population::step_generation_with](crate::evolution::Population::step_generation_with).
+    ///
+    /// # Panics
+    /// If there are not as many seeds as genomes.
+    pub fn evaluate(&mut self, genomes: &[Genome], seeds: &[u64]) -> io::Result<Vec<f64>> {
+        assert_eq!(genomes.len(), seeds.len(), "every genome needs a seed");
+
+        let next = AtomicUsize::new(0);
+        let fitness = Mutex::new(vec![f64::NAN; genomes.len()]);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .transports
+                .iter_mut()
+                .map(|transport| {
+                    let (next, fitness) = (&next, &fitness);
+                    scope.spawn(move || -> io::Result<()> {
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            if i >= genomes.len() {
+                                return Ok(());
+                            }
+                            let request = Request::Evaluate {
+                                id: i as u64,
+                                seed: seeds[i],
+                                genome: genomes[i].clone(),
+                            };
+                            transport.send(&encode(&request))?;
+                            let (id, value): (u64, f64) = decode(&transport.recv()?)?;
+                            if id != i as u64 {
+                                return Err(invalid_data());
+                            }
+                            fitness.lock().unwrap()[i] = value;
+                        }
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap())
+        })?;
+
+        Ok(fitness.into_inner().unwrap())
+    }
+
+    /// Tell every worker to stop.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        for transport in &mut self.transports {
+            transport.send(&encode(&Request::Shutdown))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, AppendSeed, Population, Tournament};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+    use std::net::{TcpListener, TcpStream};
+
+    type TestFitness = fn(&dyn Runner, &mut MemoryBuffer, &mut Pcg64) -> f64;
+
+    fn evaluator() -> Evaluator<Interpreter, TestFitness> {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness: TestFitness = |runner, memory, rng| {
+            rng.fill(memory.input_mut());
+            runner.step(memory.as_mut_slice());
+            memory.output()[0] as f64
+        };
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 2);
+        Evaluator::new(compiler, layout, 64, mutate_bits, fitness)
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    let stream = TcpStream::connect(address).unwrap();
+                    Worker::new(evaluator()).run(&mut Framed(stream)).unwrap();
+                })
+            })
+            .collect();
+        let transports = (0..2)
+            .map(|_| Framed(listener.accept().unwrap().0))
+            .collect();
+        let mut coordinator = Coordinator::new(transports);
+
+        let mut local = Population::new(16, 4);
+        let mut remote = Population::new(16, 4);
+        let mut evaluator = evaluator();
+        for _ in 0..3 {
+            let expected =
+                local.step_generation(&mut evaluator, &mut Tournament::new(2), &mut AppendSeed);
+            let stats = remote
+                .step_generation_with(
+                    |genomes, seeds| coordinator.evaluate(genomes, seeds),
+                    &mut Tournament::new(2),
+                    &mut AppendSeed,
+                )
+                .unwrap();
+            assert_eq!(stats, expected);
+        }
+        assert_eq!(remote.genomes(), local.genomes());
+
+        coordinator.shutdown().unwrap();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(decode::<Request>(&[2, 0, 0, 0]).is_err());
+        assert!(decode::<Request>(&[0, 0, 0, 0, 1, 2]).is_err());
+        assert!(decode::<(u64, f64)>(&[0; 15]).is_err());
+        assert!(decode::<(u64, f64)>(&[0; 17]).is_err());
+        let mut too_long = Framed(io::Cursor::new(u32::MAX.to_le_bytes().to_vec()));
+        assert_eq!(
+            too_long.recv().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
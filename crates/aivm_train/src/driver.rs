@@ -0,0 +1,355 @@
+use crate::{
+    checkpoint::Checkpoint,
+    config::{Backend, ConfigError, ScheduleState, TrainConfig},
+    evolution::{AppendScaledSeed, Champion, HallOfFame, Population, Schedule},
+    fitness::Fitness,
+    metrics::{Metrics, MetricsSink},
+    observe::Observer,
+};
+
+use aivm::{
+    codegen::{CodeGenerator, Interpreter},
+    Compiler, MemoryLayout, Runner,
+};
+use std::{fmt, io};
+
+/// Train agents with the settings of `config` until its budget runs out, and return the
+/// fittest genome ever evaluated.
+///
+/// Agents run on memories of `layout` and are scored by `fitness`, for example a
+/// [Rollout](crate::environment::Rollout) of an environment. See [Trainer] to also record
+/// metrics or observe the progress.
+pub fn train<F: Fitness>(
+    config: &TrainConfig,
+    layout: MemoryLayout,
+    fitness: F,
+) -> Result<Champion, TrainError> {
+    Trainer::new(config.clone(), layout, fitness).run()
+}
+
+/// A complete training run: expands, compiles and evaluates generations of genomes, selects
+/// and mutates them, and saves checkpoints and metrics, all as configured by a [TrainConfig].
+///
+/// Genomes are mutated with [AppendScaledSeed], scaled to the probability of the mutation
+/// [schedule](TrainConfig::schedule) from mutate bits that stay the same for the whole run.
+/// When the configured checkpoint file exists, the run resumes from it, together with the
+/// schedule, so it continues like it would have without the interruption.
+pub struct Trainer<'a, F> {
+    config: TrainConfig,
+    layout: MemoryLayout,
+    fitness: F,
+    sink: Option<Box<dyn MetricsSink + 'a>>,
+    observer: Option<Box<dyn Observer + Send>>,
+}
+
+impl<'a, F: Fitness> Trainer<'a, F> {
+    /// Create a run of `config`, on memories of `layout` scored by `fitness`.
+    pub fn new(config: TrainConfig, layout: MemoryLayout, fitness: F) -> Self {
+        Self {
+            config,
+            layout,
+            fitness,
+            sink: None,
+            observer: None,
+        }
+    }
+
+    /// The same run, but recording the [Metrics] of every generation in `sink`.
+    pub fn with_metrics(self, sink: impl MetricsSink + 'a) -> Self {
+        Self {
+            sink: Some(Box::new(sink)),
+            ..self
+        }
+    }
+
+    /// The same run, but notifying `observer` of its progress.
+    pub fn with_observer(self, observer: impl Observer + Send + 'static) -> Self {
+        Self {
+            observer: Some(Box::new(observer)),
+            ..self
+        }
+    }
+
+    /// Train until the budget runs out, and return the fittest genome ever evaluated.
+    pub fn run(self) -> Result<Champion, TrainError> {
+        self.config.validate()?;
+        let budget = self.config.budget;
+        if budget.generations.is_none()
+            && budget.target_fitness.is_none()
+            && budget.patience.is_none()
+            && budget.seconds.is_none()
+        {
+            return Err(ConfigError::Invalid("a run needs a budget").into());
+        }
+
+        match self.config.backend {
+            Backend::Interpreter => self.run_with(Interpreter::new()),
+            #[cfg(feature = "jit")]
+            Backend::Jit => self.run_with(aivm::codegen::Jit::new()),
+            #[cfg(feature = "cranelift")]
+            Backend::Cranelift => self.run_with(aivm::codegen::Cranelift::new()),
+            #[allow(unreachable_patterns)]
+            backend => Err(TrainError::UnsupportedBackend(backend)),
+        }
+    }
+
+    fn run_with<G>(mut self, generator: G) -> Result<Champion, TrainError>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+    {
+        let config = &self.config;
+        let checkpointer = config.checkpointer(config.run_seed());
+        let (mut population, mut schedule, seed) = match &checkpointer {
+            Some(checkpointer) if checkpointer.path().exists() => {
+                let checkpoint: Checkpoint<(Population, ScheduleState), TrainConfig> =
+                    checkpointer.resume()?;
+                let seed = checkpoint.seed.unwrap_or_else(|| config.run_seed());
+                let (population, schedule) = checkpoint.state;
+                (population, schedule, seed)
+            }
+            _ => {
+                let seed = config.run_seed();
+                (config.population(seed), config.schedule.build(), seed)
+            }
+        };
+        let checkpointer = checkpointer.map(|checkpointer| checkpointer.with_seed(seed));
+        if population.hall_of_fame().is_none() {
+            population.set_hall_of_fame(HallOfFame::new(1));
+        }
+        if let Some(observer) = self.observer.take() {
+            population.set_observer(observer);
+        }
+
+        let compiler = Compiler::new(generator);
+        // The bits are filled with the highest probability of the schedule, which doesn't change
+        let mut evaluator = config.evaluator(compiler, self.layout, self.fitness, seed);
+        let mut selection = config.selection;
+        let mut criteria = config.budget.stop_criteria();
+        let mut best = population
+            .hall_of_fame()
+            .and_then(HallOfFame::best)
+            .map_or(f64::NEG_INFINITY, |champion| champion.fitness);
+        while config
+            .budget
+            .generations
+            .is_none_or(|generations| population.generation() < generations)
+        {
+            let mut mutation =
+                AppendScaledSeed::from_probabilities(schedule.p_mutate(), schedule.max_p_mutate());
+            let stats = population.step_generation(&mut evaluator, &mut selection, &mut mutation);
+            if let Some(sink) = &mut self.sink {
+                let metrics = Metrics::collect(&stats, &population, &mut evaluator).with_seed(seed);
+                sink.record(&metrics)?;
+            }
+
+            let improved = stats.best_fitness > best;
+            best = best.max(stats.best_fitness);
+            schedule.advance(improved);
+            if let Some(checkpointer) = &checkpointer {
+                population.save_checkpoint_with_if_due(checkpointer, &schedule, config)?;
+            }
+
+            if criteria.check(&stats).is_some() {
+                break;
+            }
+        }
+
+        population
+            .take_hall_of_fame()
+            .and_then(|hall_of_fame| hall_of_fame.best().cloned())
+            .ok_or(TrainError::NoChampion)
+    }
+}
+
+/// Returned when a [Trainer] can not complete a run.
+#[derive(Debug)]
+pub enum TrainError {
+    /// The configuration is invalid.
+    Config(ConfigError),
+    /// The configured code generator is not enabled by the features of this crate.
+    UnsupportedBackend(Backend),
+    /// A checkpoint or metrics could not be written or read.
+    Io(io::Error),
+    /// No genome got a fitness that is not NaN.
+    NoChampion,
+}
+
+impl fmt::Display for TrainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(error) => error.fmt(f),
+            Self::UnsupportedBackend(backend) => {
+                write!(
+                    f,
+                    "backend {backend:?} is not enabled, see the crate features"
+                )
+            }
+            Self::Io(error) => write!(f, "training failed: {error}"),
+            Self::NoChampion => write!(f, "no genome was evaluated to a valid fitness"),
+        }
+    }
+}
+
+impl std::error::Error for TrainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Config(error) => Some(error),
+            Self::Io(error) => Some(error),
+            Self::UnsupportedBackend(_) | Self::NoChampion => None,
+        }
+    }
+}
+
+impl From<ConfigError> for TrainError {
+    fn from(error: ConfigError) -> Self {
+        Self::Config(error)
+    }
+}
+
+impl From<io::Error> for TrainError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Budget, CheckpointConfig, ScheduleConfig},
+        seed::RunSeed,
+        tuning::FrequencyTable,
+    };
+    use aivm::MemoryBuffer;
+    use rand::SeedableRng;
+    use rand_pcg::Pcg64;
+
+    fn first_output(runner: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64) -> f64 {
+        runner.step(memory.as_mut_slice());
+        memory.output()[0] as f64
+    }
+
+    #[test]
+    fn run() {
+        let config = TrainConfig {
+            population_size: 16,
+            code_len: 32,
+            mutate_bits_len: 1024,
+            budget: Budget {
+                generations: Some(4),
+                ..Budget::default()
+            },
+            seed: Some(RunSeed(1)),
+            ..TrainConfig::default()
+        };
+        let layout = MemoryLayout::new(4, 1, 0);
+
+        let mut metrics = vec![];
+        let champion = Trainer::new(config.clone(), layout, first_output)
+            .with_metrics(|m: &Metrics| {
+                metrics.push(*m);
+                Ok(())
+            })
+            .run()
+            .unwrap();
+        assert_eq!(metrics.len(), 4);
+        let best = metrics
+            .iter()
+            .map(|m| m.best_fitness)
+            .fold(f64::MIN, f64::max);
+        assert_eq!(champion.fitness, best);
+        assert_eq!(train(&config, layout, first_output).unwrap(), champion);
+
+        // Other frequencies, such as a tuned table, are generated and compiled with
+        let mut frequencies = FrequencyTable::default().frequencies();
+        frequencies.swap(2, 27);
+        let tuned = TrainConfig {
+            frequencies: FrequencyTable::new(frequencies).unwrap(),
+            ..config.clone()
+        };
+        let champion = train(&tuned, layout, first_output).unwrap();
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = tuned.evaluator(compiler, layout, first_output, RunSeed(1));
+        let mut rng = Pcg64::seed_from_u64(0);
+        assert_eq!(
+            evaluator.evaluate(&champion.genome, &mut rng),
+            champion.fitness
+        );
+
+        let unlimited = TrainConfig {
+            budget: Budget::default(),
+            ..config
+        };
+        assert!(matches!(
+            train(&unlimited, layout, first_output),
+            Err(TrainError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn schedule() {
+        let config = TrainConfig {
+            population_size: 16,
+            elites: 2,
+            code_len: 32,
+            mutate_bits_len: 1024,
+            schedule: ScheduleConfig::ExponentialDecay {
+                initial: 4000,
+                factor: 0.7,
+                min: 100,
+            },
+            budget: Budget {
+                generations: Some(6),
+                ..Budget::default()
+            },
+            seed: Some(RunSeed(2)),
+            ..TrainConfig::default()
+        };
+        let layout = MemoryLayout::new(4, 1, 0);
+        // The fitness of every generation, without the timings
+        let run = |config: &TrainConfig| {
+            let mut metrics = vec![];
+            let champion = Trainer::new(config.clone(), layout, first_output)
+                .with_metrics(|m: &Metrics| {
+                    metrics.push((m.generation, m.mean_fitness, m.median_fitness));
+                    Ok(())
+                })
+                .run();
+            (champion, metrics)
+        };
+        let (champion, metrics) = run(&config);
+        let champion = champion.unwrap();
+
+        // The champion reproduces its fitness although the probability decayed
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = config.evaluator(compiler, layout, first_output, RunSeed(2));
+        let mut rng = Pcg64::seed_from_u64(0);
+        assert_eq!(
+            evaluator.evaluate(&champion.genome, &mut rng),
+            champion.fitness
+        );
+
+        // A run that is resumed halfway ends the same as the uninterrupted one
+        let path = std::env::temp_dir().join(format!("aivm-driver-{}", std::process::id()));
+        let checkpointed = TrainConfig {
+            checkpoint: Some(CheckpointConfig {
+                path: path.clone(),
+                interval: 3,
+            }),
+            ..config.clone()
+        };
+        let halfway = TrainConfig {
+            budget: Budget {
+                generations: Some(3),
+                ..Budget::default()
+            },
+            ..checkpointed.clone()
+        };
+        train(&halfway, layout, first_output).unwrap();
+        let (resumed, resumed_metrics) = run(&checkpointed);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.unwrap(), champion);
+        assert_eq!(resumed_metrics, metrics[3..]);
+    }
+}
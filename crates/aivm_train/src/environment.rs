@@ -0,0 +1,206 @@
+use crate::fitness::Fitness;
+
+use aivm::{MemoryBuffer, Runner};
+use rand_pcg::Pcg64;
+
+/// A task that code interacts with one step at a time: it observes the environment through
+/// its input and acts on it through its output.
+pub trait Environment {
+    /// Start a new episode. Any randomness of the episode should come from `rng`, so episodes
+    /// can be reproduced.
+    fn reset(&mut self, rng: &mut Pcg64);
+
+    /// Write the observation of the current state to the input of the code.
+    fn observe(&mut self, input: &mut [i64]);
+
+    /// Apply the output of a step of the code to the environment.
+    fn act(&mut self, output: &[i64]) -> Transition;
+}
+
+/// The result of an [act](Environment::act).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    /// The reward for the action, higher is better.
+    pub reward: f64,
+    /// Whether the episode ended.
+    pub done: bool,
+}
+
+/// The result of a [rollout].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Episode {
+    /// The sum of the rewards of every step.
+    pub reward: f64,
+    /// The amount of steps that were run.
+    pub steps: u32,
+}
+
+/// Run an episode of `environment` with `runner`, stepping the code on `memory` until the
+/// environment is done or `max_steps` steps were run.
+///
+/// The memory bank keeps its values from one step to the next, so code can remember what it
+/// observed earlier in the episode.
+pub fn rollout<E: Environment + ?Sized>(
+    environment: &mut E,
+    runner: &dyn Runner,
+    memory: &mut MemoryBuffer,
+    max_steps: u32,
+    rng: &mut Pcg64,
+) -> Episode {
+    rollout_with(environment, runner, memory, max_steps, rng, |_, _| {})
+}
+
+/// Like [rollout], but calling `on_step` with the memory and transition after every step.
+pub(crate) fn rollout_with<E: Environment + ?Sized>(
+    environment: &mut E,
+    runner: &dyn Runner,
+    memory: &mut MemoryBuffer,
+    max_steps: u32,
+    rng: &mut Pcg64,
+    mut on_step: impl FnMut(&MemoryBuffer, Transition),
+) -> Episode {
+    environment.reset(rng);
+
+    let mut episode = Episode {
+        reward: 0.0,
+        steps: 0,
+    };
+    while episode.steps < max_steps {
+        environment.observe(memory.input_mut());
+        runner.step(memory.as_mut_slice());
+        let transition = environment.act(memory.output());
+        on_step(memory, transition);
+        episode.reward += transition.reward;
+        episode.steps += 1;
+        if transition.done {
+            break;
+        }
+    }
+
+    episode
+}
+
+/// A [Fitness] that scores code by the total reward of an episode of an [Environment], so
+/// only the environment has to be written to train on it.
+///
+/// Every episode runs on a fresh environment created by a closure, which lets episodes be
+/// evaluated on many threads at once.
+pub struct Rollout<F> {
+    new_environment: F,
+    max_steps: u32,
+}
+
+impl<F, E> Rollout<F>
+where
+    F: Fn() -> E,
+    E: Environment,
+{
+    /// Create a rollout of at most `max_steps` steps of the environments returned by
+    /// `new_environment`.
+    pub fn new(new_environment: F, max_steps: u32) -> Self {
+        Self {
+            new_environment,
+            max_steps,
+        }
+    }
+
+    /// The maximum amount of steps of an episode.
+    pub fn max_steps(&self) -> u32 {
+        self.max_steps
+    }
+
+    /// Create a fresh environment.
+    pub(crate) fn new_environment(&self) -> E {
+        (self.new_environment)()
+    }
+
+    /// Run `episodes` episodes of `runner`, which all start from the memory `memory` has now.
+    pub fn run(
+        &self,
+        runner: &dyn Runner,
+        memory: &mut MemoryBuffer,
+        episodes: u32,
+        rng: &mut Pcg64,
+    ) -> Vec<Episode> {
+        let initial = memory.snapshot();
+        (0..episodes)
+            .map(|_| {
+                memory.restore(&initial);
+                let mut environment = (self.new_environment)();
+                rollout(&mut environment, runner, memory, self.max_steps, rng)
+            })
+            .collect()
+    }
+}
+
+impl<F, E> Fitness for Rollout<F>
+where
+    F: Fn() -> E,
+    E: Environment,
+{
+    fn evaluate(&self, runner: &dyn Runner, memory: &mut MemoryBuffer, rng: &mut Pcg64) -> f64 {
+        let mut environment = (self.new_environment)();
+        rollout(&mut environment, runner, memory, self.max_steps, rng).reward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{evolution::fill_mutate_bits, evolution::Genome, fitness::Evaluator};
+    use aivm::{codegen::Interpreter, Compiler, MemoryLayout};
+    use rand::prelude::*;
+
+    /// Rewards outputs that match the observed target, for a fixed amount of steps.
+    struct Match {
+        target: i64,
+        remaining: u32,
+    }
+
+    impl Environment for Match {
+        fn reset(&mut self, rng: &mut Pcg64) {
+            self.target = rng.gen_range(-5..5);
+        }
+
+        fn observe(&mut self, input: &mut [i64]) {
+            input[0] = self.target;
+        }
+
+        fn act(&mut self, output: &[i64]) -> Transition {
+            self.remaining -= 1;
+            Transition {
+                reward: if output[0] == self.target { 1.0 } else { 0.0 },
+                done: self.remaining == 0,
+            }
+        }
+    }
+
+    #[test]
+    fn episodes() {
+        let rollout = Rollout::new(
+            || Match {
+                target: 0,
+                remaining: 3,
+            },
+            10,
+        );
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 1, 1);
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, rollout);
+
+        let genome = Genome::new(3);
+        let runner = evaluator.compile(&genome);
+        let mut memory = evaluator.memory(&genome);
+        let episodes =
+            evaluator
+                .fitness()
+                .run(&runner, &mut memory, 4, &mut Pcg64::seed_from_u64(1));
+        assert_eq!(episodes.len(), 4);
+        assert!(episodes.iter().all(|episode| episode.steps == 3));
+
+        let fitness = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1));
+        assert_eq!(fitness, episodes[0].reward);
+    }
+}
@@ -0,0 +1,136 @@
+use super::{Genome, MutateBits};
+
+use aivm::InstructionFrequencies;
+
+/// One contiguous buffer that holds a slot of the same length for every genome of a batch,
+/// such as the code or the memory of a whole generation, see [expand_code_batch].
+///
+/// The buffer is kept when the batch changes, so expanding every generation into the same
+/// arena allocates only when a generation is larger than any before it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Arena<T> {
+    buf: Vec<T>,
+    stride: usize,
+}
+
+impl<T: Copy + Default> Arena<T> {
+    /// Create an empty arena of slots of `stride` values.
+    pub fn new(stride: usize) -> Self {
+        Self {
+            buf: vec![],
+            stride,
+        }
+    }
+
+    /// The amount of values of every slot.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// The amount of slots.
+    pub fn len(&self) -> usize {
+        self.buf.len().checked_div(self.stride).unwrap_or(0)
+    }
+
+    /// Whether there are no slots.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Slot `index`.
+    ///
+    /// # Panics
+    /// If `index` is out of bounds.
+    pub fn get(&self, index: usize) -> &[T] {
+        &self.buf[index * self.stride..(index + 1) * self.stride]
+    }
+
+    /// Mutable access to slot `index`.
+    ///
+    /// # Panics
+    /// If `index` is out of bounds.
+    pub fn get_mut(&mut self, index: usize) -> &mut [T] {
+        &mut self.buf[index * self.stride..(index + 1) * self.stride]
+    }
+
+    /// Every slot, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &[T]> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    /// All slots as one slice, for example to pass to a
+    /// [BatchRunner](aivm::BatchRunner) with the [stride](Self::stride).
+    pub fn as_slice(&self) -> &[T] {
+        &self.buf
+    }
+
+    /// All slots as one mutable slice.
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.buf
+    }
+
+    /// Make room for `len` slots, reusing the buffer. The values of the slots are unspecified.
+    pub fn resize(&mut self, len: usize) {
+        self.buf.resize(len * self.stride, T::default());
+    }
+}
+
+/// Expand the code of every genome of `genomes` into a slot of `arena`, like
+/// [Genome::expand_code] with code of the stride of the arena.
+pub fn expand_code_batch<F: InstructionFrequencies, M: MutateBits + ?Sized>(
+    genomes: &[Genome],
+    mutate_bits: &M,
+    arena: &mut Arena<u64>,
+) {
+    arena.resize(genomes.len());
+    for (i, genome) in genomes.iter().enumerate() {
+        genome.expand_code::<F, _>(mutate_bits, arena.get_mut(i));
+    }
+}
+
+/// Expand the memory of every genome of `genomes` into a slot of `arena`, like
+/// [Genome::expand_memory] with memory of the stride of the arena.
+pub fn expand_memory_batch<M: MutateBits + ?Sized>(
+    genomes: &[Genome],
+    mutate_bits: &M,
+    arena: &mut Arena<i64>,
+) {
+    arena.resize(genomes.len());
+    for (i, genome) in genomes.iter().enumerate() {
+        genome.expand_memory(mutate_bits, arena.get_mut(i));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::fill_mutate_bits;
+    use aivm::DefaultFrequencies;
+
+    #[test]
+    fn batch() {
+        let mut mutate_bits = vec![0; 256];
+        fill_mutate_bits(&mut mutate_bits, 1, 3000);
+        let genomes: Vec<_> = (0..5).map(|i| Genome::new(i).with_mutation(7)).collect();
+
+        let mut code = Arena::new(32);
+        let mut memory = Arena::new(8);
+        expand_code_batch::<DefaultFrequencies, _>(&genomes, &mutate_bits, &mut code);
+        expand_memory_batch(&genomes, &mutate_bits, &mut memory);
+        assert_eq!(code.len(), 5);
+        for (genome, slot) in genomes.iter().zip(code.iter()) {
+            let mut expected = [0; 32];
+            genome.expand_code::<DefaultFrequencies, _>(&mutate_bits, &mut expected);
+            assert_eq!(slot, expected);
+        }
+        let mut expected = [0; 8];
+        genomes[3].expand_memory(&mutate_bits, &mut expected);
+        assert_eq!(memory.get(3), expected);
+
+        // A smaller batch reuses the buffer
+        let buffer = code.as_slice().as_ptr();
+        expand_code_batch::<DefaultFrequencies, _>(&genomes[..2], &mutate_bits, &mut code);
+        assert_eq!(code.len(), 2);
+        assert_eq!(code.as_slice().as_ptr(), buffer);
+    }
+}
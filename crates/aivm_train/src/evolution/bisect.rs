@@ -0,0 +1,125 @@
+use super::{Edit, Genome};
+
+/// Find the mutations of `child` that cause it to behave differently from `parent`, as
+/// indices into the mutation seeds of `child`.
+///
+/// The mutations of the child that come after the ones it shares with the parent are searched
+/// with delta debugging for the smallest subset for which `differs` still holds, which is given
+/// the parent with only that subset of mutations applied, in their original order. `differs`
+/// should hold for the child itself, otherwise all its new mutations are returned. The result
+/// is minimal in that removing any single mutation from it makes `differs` fail.
+///
+/// The edits of the child are kept, each applied after the remaining mutations that were
+/// applied before it in the child.
+///
+/// # Panics
+/// If `parent` and `child` do not share their root seed.
+pub fn bisect_mutations(
+    parent: &Genome,
+    child: &Genome,
+    mut differs: impl FnMut(&Genome) -> bool,
+) -> Vec<usize> {
+    assert_eq!(
+        parent.root_seed, child.root_seed,
+        "not a child of the parent"
+    );
+    let shared = parent
+        .mutation_seeds
+        .iter()
+        .zip(&child.mutation_seeds)
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut candidates: Vec<_> = (shared..child.mutation_seeds.len()).collect();
+    let mut differs = |subset: &[usize]| differs(&with_mutations(child, shared, subset));
+    if !differs(&candidates) {
+        return candidates;
+    }
+
+    let mut granularity = 2;
+    while candidates.len() >= 2 {
+        let chunk = candidates.len().div_ceil(granularity);
+        let chunks: Vec<_> = candidates.chunks(chunk).map(<[usize]>::to_vec).collect();
+
+        if let Some(subset) = chunks.iter().find(|subset| differs(subset)) {
+            candidates = subset.clone();
+            granularity = 2;
+            continue;
+        }
+        let complement = chunks.iter().find_map(|subset| {
+            let complement: Vec<_> = candidates
+                .iter()
+                .copied()
+                .filter(|i| !subset.contains(i))
+                .collect();
+            differs(&complement).then_some(complement)
+        });
+        if let Some(complement) = complement {
+            candidates = complement;
+            granularity = (granularity - 1).max(2);
+            continue;
+        }
+        if granularity >= candidates.len() {
+            break;
+        }
+        granularity = (2 * granularity).min(candidates.len());
+    }
+
+    // A single mutation may still be unnecessary, when differs holds without any
+    if candidates.len() == 1 && differs(&[]) {
+        candidates.clear();
+    }
+    candidates
+}
+
+/// `child` with only the first `shared` mutations and the mutations at the indices in
+/// `subset`.
+fn with_mutations(child: &Genome, shared: usize, subset: &[usize]) -> Genome {
+    let kept = |i: usize| i < shared || subset.contains(&i);
+    Genome {
+        root_seed: child.root_seed,
+        mutation_seeds: (0..child.mutation_seeds.len())
+            .filter(|&i| kept(i))
+            .map(|i| child.mutation_seeds[i])
+            .collect(),
+        edits: child
+            .edits
+            .iter()
+            .map(|edit| {
+                let before = (edit.after as usize).min(child.mutation_seeds.len());
+                Edit {
+                    after: (0..before).filter(|&i| kept(i)).count() as u32,
+                    ..*edit
+                }
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::EditKind;
+
+    #[test]
+    fn culprits() {
+        let parent = Genome::new(1).with_mutation(10);
+        let child = [11, 12, 13, 14, 15, 16]
+            .into_iter()
+            .fold(parent.clone(), Genome::with_mutation)
+            .with_edit(EditKind::SwapFunctions, 3);
+        let has = |genome: &Genome, seed| genome.mutation_seeds.contains(&seed);
+
+        assert_eq!(bisect_mutations(&parent, &child, |g| has(g, 13)), [3]);
+        assert_eq!(
+            bisect_mutations(&parent, &child, |g| has(g, 12) && has(g, 16)),
+            [2, 6]
+        );
+        // The edit stays after the mutations that came before it
+        let culprits = bisect_mutations(&parent, &child, |g| {
+            has(g, 14) && g.edits[0].after == g.mutation_seeds.len() as u32
+        });
+        assert_eq!(culprits, [4]);
+        assert!(bisect_mutations(&parent, &child, |_| true).is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+use super::{GenerationStats, Genome, MutateBits, Mutation, Selection};
+use crate::{
+    fitness::{Evaluator, Fitness},
+    seed::SplitRng,
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// An agent of a [CoEvolution]: the genome of its code, and its initial memory as plain
+/// values.
+///
+/// The memory is kept apart from the genome, so it evolves with operators of its own and can
+/// be recombined between agents, while the code still mutates through its seeds.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Pair {
+    /// The genome the code is expanded from. Its initial memory is not used.
+    pub code: Genome,
+    /// The initial memory of every episode.
+    pub memory: Vec<i64>,
+}
+
+/// Mutates initial memories of a [CoEvolution].
+pub trait MemoryMutation {
+    /// Mutate `memory` in place.
+    fn mutate(&mut self, memory: &mut [i64], rng: &mut Pcg64);
+}
+
+impl<F> MemoryMutation for F
+where
+    F: FnMut(&mut [i64], &mut Pcg64),
+{
+    fn mutate(&mut self, memory: &mut [i64], rng: &mut Pcg64) {
+        self(memory, rng)
+    }
+}
+
+/// Adds a random amount of at most the scale to every value, each with a probability.
+///
+/// Unlike flipping bits, which changes values by powers of 2, this makes small steps that
+/// suit memories holding constants and weights.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Perturb {
+    p: f64,
+    scale: i64,
+}
+
+impl Perturb {
+    /// Change every value with probability `p` by a uniformly random amount in
+    /// `-scale..=scale`.
+    ///
+    /// # Panics
+    /// If `p` is not in `0.0..=1.0` or `scale` is negative.
+    pub fn new(p: f64, scale: i64) -> Self {
+        assert!((0.0..=1.0).contains(&p), "invalid probability");
+        assert!(scale >= 0, "negative scale");
+        Self { p, scale }
+    }
+}
+
+impl MemoryMutation for Perturb {
+    fn mutate(&mut self, memory: &mut [i64], rng: &mut Pcg64) {
+        for value in memory {
+            if rng.gen_bool(self.p) {
+                *value = value.wrapping_add(rng.gen_range(-self.scale..=self.scale));
+            }
+        }
+    }
+}
+
+/// How the memories of two parents are combined into the memory of a child.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MemoryCrossover {
+    /// Take every value from either parent with equal probability.
+    Uniform,
+    /// Pick every value uniformly from the range between the values of the parents, extended
+    /// on both sides by `alpha` times its width, like BLX-alpha.
+    Blend {
+        /// How far values may fall outside of the range of the parents, relative to its width.
+        alpha: f64,
+    },
+}
+
+impl MemoryCrossover {
+    /// The memory of a child of the parents with memories `a` and `b`.
+    ///
+    /// # Panics
+    /// If `a` and `b` have different lengths.
+    pub fn cross(&self, a: &[i64], b: &[i64], rng: &mut Pcg64) -> Vec<i64> {
+        assert_eq!(a.len(), b.len(), "memories of different sizes");
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| match *self {
+                Self::Uniform => {
+                    if rng.gen() {
+                        a
+                    } else {
+                        b
+                    }
+                }
+                Self::Blend { alpha } => {
+                    let (low, high) = (a.min(b) as f64, a.max(b) as f64);
+                    let extend = alpha * (high - low);
+                    let (low, high) = (low - extend, high + extend);
+                    if low < high {
+                        // Casts saturate at the bounds of i64
+                        rng.gen_range(low..high) as i64
+                    } else {
+                        a
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// A generation of agents whose code and initial memory evolve side by side, each with
+/// operators and rates of its own.
+///
+/// Code and memory have very different fitness landscapes: a flipped bit in the code changes
+/// what a program does, while memory often holds constants that are best tuned in small steps
+/// or recombined between good agents. Every child gets the code of a parent, mutated with a
+/// probability, and the memory of that parent or with a probability a
+/// [crossover](MemoryCrossover) of the memories of two parents, mutated with a probability of
+/// its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CoEvolution {
+    pairs: Vec<Pair>,
+    ranked: Vec<(Pair, f64)>,
+    elites: usize,
+    p_code: f64,
+    p_memory: f64,
+    crossover: Option<(MemoryCrossover, f64)>,
+    generation: u64,
+    rng: SplitRng,
+}
+
+impl CoEvolution {
+    /// Create `size` agents with unmutated genomes of random root seeds, starting from the
+    /// initial memory of their genome as expanded by `evaluator`. All randomness of the
+    /// evolution is derived from `seed`.
+    ///
+    /// Code and memory are mutated in every child, without crossover or elites.
+    ///
+    /// # Panics
+    /// If `size` is 0.
+    pub fn new<G, F, B>(size: usize, seed: u64, evaluator: &Evaluator<G, F, B>) -> Self
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        assert!(size > 0, "empty population");
+        let mut rng = SplitRng::new(seed);
+        let pairs = (0..size)
+            .map(|_| {
+                let code = Genome::new(rng.gen());
+                let memory = evaluator.memory(&code).memory().to_vec();
+                Pair { code, memory }
+            })
+            .collect();
+        Self {
+            pairs,
+            ranked: vec![],
+            elites: 0,
+            p_code: 1.0,
+            p_memory: 1.0,
+            crossover: None,
+            generation: 0,
+            rng,
+        }
+    }
+
+    /// The agents of the current generation, which have not been evaluated yet.
+    pub fn pairs(&self) -> &[Pair] {
+        &self.pairs
+    }
+
+    /// The agents of the last evaluated generation with their fitness, ordered from high to
+    /// low fitness. Empty before the first generation was evaluated.
+    pub fn ranked(&self) -> &[(Pair, f64)] {
+        &self.ranked
+    }
+
+    /// The index of the current generation, starting at 0.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Copy the `elites` fittest agents of every generation into the next one unchanged.
+    pub fn set_elites(&mut self, elites: usize) {
+        self.elites = elites;
+    }
+
+    /// Mutate the code of a child with probability `p_code`, and its memory with probability
+    /// `p_memory`.
+    ///
+    /// # Panics
+    /// If a probability is not in `0.0..=1.0`.
+    pub fn set_rates(&mut self, p_code: f64, p_memory: f64) {
+        assert!(
+            (0.0..=1.0).contains(&p_code) && (0.0..=1.0).contains(&p_memory),
+            "invalid probability"
+        );
+        self.p_code = p_code;
+        self.p_memory = p_memory;
+    }
+
+    /// Give a child the `crossover` of the memories of two parents with probability
+    /// `p_crossover`, instead of the memory of its parent.
+    ///
+    /// # Panics
+    /// If `p_crossover` is not in `0.0..=1.0`.
+    pub fn set_crossover(&mut self, crossover: MemoryCrossover, p_crossover: f64) {
+        assert!((0.0..=1.0).contains(&p_crossover), "invalid probability");
+        self.crossover = Some((crossover, p_crossover));
+    }
+
+    /// Evaluate the current generation with `evaluator`, every agent running its own memory
+    /// instead of the one of its genome, and replace it by the elites and the children of the
+    /// parents picked by `selection`.
+    ///
+    /// The code of children is mutated by `code_mutation` and their memory by
+    /// `memory_mutation`. The best genome of the returned statistics is the code of the
+    /// fittest agent, see [ranked](Self::ranked) for its memory.
+    pub fn step_generation<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        selection: &mut impl Selection,
+        code_mutation: &mut impl Mutation,
+        memory_mutation: &mut impl MemoryMutation,
+    ) -> GenerationStats
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        span!("generation", generation = self.generation);
+        let fitness: Vec<_> = self
+            .pairs
+            .iter()
+            .map(|pair| {
+                let mut rng = Pcg64::seed_from_u64(self.rng.split_seed());
+                let runner = evaluator.compile(&pair.code);
+                evaluator.evaluate_memory(&runner, &pair.memory, &mut rng)
+            })
+            .collect();
+
+        let mut ranking: Vec<_> = (0..fitness.len()).collect();
+        ranking.sort_by(|&a, &b| fitness[b].total_cmp(&fitness[a]));
+        self.ranked = ranking
+            .iter()
+            .map(|&i| (self.pairs[i].clone(), fitness[i]))
+            .collect();
+        let stats = GenerationStats::new(self.generation, &self.ranked[0].0.code, &fitness);
+
+        span!("select");
+        let size = self.pairs.len();
+        let elites = self.elites.min(size);
+        let parents = selection.select(&fitness, 2 * (size - elites), self.rng.rng());
+        let mut next: Vec<_> = self.ranked[..elites]
+            .iter()
+            .map(|(pair, _)| pair.clone())
+            .collect();
+        for parents in parents.chunks(2) {
+            let rng = self.rng.rng();
+            let parent = &self.pairs[parents[0]];
+            let code = if rng.gen_bool(self.p_code) {
+                code_mutation.mutate(&parent.code, rng)
+            } else {
+                parent.code.clone()
+            };
+            let mut memory = match self.crossover {
+                Some((crossover, p)) if rng.gen_bool(p) => {
+                    crossover.cross(&parent.memory, &self.pairs[parents[1]].memory, rng)
+                }
+                _ => parent.memory.clone(),
+            };
+            if rng.gen_bool(self.p_memory) {
+                memory_mutation.mutate(&mut memory, rng);
+            }
+            next.push(Pair { code, memory });
+        }
+        self.pairs = next;
+        self.generation += 1;
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, AppendSeed, Truncation};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    #[test]
+    fn crossover() {
+        let mut rng = Pcg64::seed_from_u64(1);
+        let (a, b) = ([0, 10, -5, 7], [4, 10, 5, i64::MAX]);
+        let uniform = MemoryCrossover::Uniform.cross(&a, &b, &mut rng);
+        assert!((0..4).all(|i| uniform[i] == a[i] || uniform[i] == b[i]));
+
+        let blend = MemoryCrossover::Blend { alpha: 0.0 }.cross(&a, &b, &mut rng);
+        assert!((0..4).all(|i| a[i].min(b[i]) <= blend[i] && blend[i] <= a[i].max(b[i])));
+        assert_eq!(blend[1], 10);
+    }
+
+    #[test]
+    fn evolve() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        // Only the memory matters, higher first values are better
+        let fitness =
+            |_: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| memory.memory()[0] as f64;
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, fitness);
+
+        let mut evolution = CoEvolution::new(16, 3, &evaluator);
+        evolution.set_elites(1);
+        evolution.set_rates(0.5, 1.0);
+        evolution.set_crossover(MemoryCrossover::Blend { alpha: 0.5 }, 0.5);
+        let mut truncation = Truncation::new(0.25);
+        let mut perturb = Perturb::new(1.0, 1 << 40);
+
+        let first = evolution.step_generation(
+            &mut evaluator,
+            &mut truncation,
+            &mut AppendSeed,
+            &mut perturb,
+        );
+        let mut last = first.clone();
+        for _ in 0..20 {
+            let stats = evolution.step_generation(
+                &mut evaluator,
+                &mut truncation,
+                &mut AppendSeed,
+                &mut perturb,
+            );
+            assert!(stats.best_fitness >= last.best_fitness);
+            last = stats;
+        }
+        assert!(last.best_fitness > first.best_fitness);
+        assert_eq!(evolution.generation(), 21);
+        assert_eq!(evolution.ranked()[0].1, last.best_fitness);
+        assert_eq!(evolution.ranked()[0].0.code, last.best);
+    }
+}
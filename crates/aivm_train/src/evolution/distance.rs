@@ -0,0 +1,198 @@
+use super::Genome;
+
+use aivm::{DefaultFrequencies, InstructionFrequencies};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, marker::PhantomData};
+
+/// Measures how different two genomes are, 0 for equal genomes.
+pub trait Distance {
+    /// The distance between `a` and `b`.
+    fn distance(&self, a: &Genome, b: &Genome) -> f64;
+}
+
+impl<F> Distance for F
+where
+    F: Fn(&Genome, &Genome) -> f64,
+{
+    fn distance(&self, a: &Genome, b: &Genome) -> f64 {
+        self(a, b)
+    }
+}
+
+/// The distance of genomes by their seeds alone, without expanding them.
+///
+/// Genomes with the same root seed are as far apart as the amount of mutation seeds only one
+/// of them has, like the disjoint genes of NEAT. Genomes with different root seeds share
+/// nothing, so their distance is infinite.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SeedDistance;
+
+impl Distance for SeedDistance {
+    fn distance(&self, a: &Genome, b: &Genome) -> f64 {
+        if a.root_seed != b.root_seed {
+            return f64::INFINITY;
+        }
+
+        let mut counts = HashMap::new();
+        for &seed in &a.mutation_seeds {
+            *counts.entry(seed).or_insert(0i64) += 1;
+        }
+        for &seed in &b.mutation_seeds {
+            *counts.entry(seed).or_insert(0i64) -= 1;
+        }
+        counts.values().map(|c| c.unsigned_abs()).sum::<u64>() as f64
+    }
+}
+
+/// The amount of bits that differ between two codes. Code words that only one of them has
+/// count as 64 differing bits each.
+pub fn hamming(a: &[u64], b: &[u64]) -> u64 {
+    let common: u64 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| u64::from((a ^ b).count_ones()))
+        .sum();
+    common + 64 * a.len().abs_diff(b.len()) as u64
+}
+
+/// The kind of instruction every code word decodes to, as an index into
+/// [KIND_NAMES](aivm::KIND_NAMES).
+pub fn kinds<F: InstructionFrequencies>(code: &[u64]) -> Vec<u8> {
+    code.iter().map(|&word| F::kind_of(word) as u8).collect()
+}
+
+/// The edit distance between the sequences of instruction kinds of two codes: the least
+/// amount of kinds to insert, delete or replace to turn one into the other.
+///
+/// Unlike the [hamming] distance this ignores operands, and an instruction inserted near the
+/// start shifts the rest of the code by only one edit.
+pub fn kind_edit_distance<F: InstructionFrequencies>(a: &[u64], b: &[u64]) -> usize {
+    let (a, b) = (kinds::<F>(a), kinds::<F>(b));
+    let mut previous: Vec<_> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, &a) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, &b) in b.iter().enumerate() {
+            let replace = previous[j] + usize::from(a != b);
+            current[j + 1] = replace.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// The [hamming] distance between the expanded codes of genomes, with their edits applied with
+/// the frequencies `F`.
+#[derive(Debug, Clone)]
+pub struct HammingDistance<F: InstructionFrequencies = DefaultFrequencies> {
+    mutate_bits: Vec<u64>,
+    code_len: usize,
+    frequencies: PhantomData<fn() -> F>,
+}
+
+impl<F: InstructionFrequencies> HammingDistance<F> {
+    /// Compare codes of `code_len` words, expanded with `mutate_bits`.
+    pub fn new(mutate_bits: Vec<u64>, code_len: usize) -> Self {
+        Self {
+            mutate_bits,
+            code_len,
+            frequencies: PhantomData,
+        }
+    }
+}
+
+impl<F: InstructionFrequencies> Distance for HammingDistance<F> {
+    fn distance(&self, a: &Genome, b: &Genome) -> f64 {
+        let (a, b) = expand_pair::<F>(&self.mutate_bits, self.code_len, a, b);
+        hamming(&a, &b) as f64
+    }
+}
+
+/// The [kind_edit_distance] between the expanded codes of genomes, with the instruction kinds
+/// of the frequencies `F`.
+#[derive(Debug, Clone)]
+pub struct KindDistance<F: InstructionFrequencies = DefaultFrequencies> {
+    mutate_bits: Vec<u64>,
+    code_len: usize,
+    frequencies: PhantomData<fn() -> F>,
+}
+
+impl<F: InstructionFrequencies> KindDistance<F> {
+    /// Compare codes of `code_len` words, expanded with `mutate_bits`.
+    pub fn new(mutate_bits: Vec<u64>, code_len: usize) -> Self {
+        Self {
+            mutate_bits,
+            code_len,
+            frequencies: PhantomData,
+        }
+    }
+}
+
+impl<F: InstructionFrequencies> Distance for KindDistance<F> {
+    fn distance(&self, a: &Genome, b: &Genome) -> f64 {
+        let (a, b) = expand_pair::<F>(&self.mutate_bits, self.code_len, a, b);
+        kind_edit_distance::<F>(&a, &b) as f64
+    }
+}
+
+fn expand_pair<F: InstructionFrequencies>(
+    mutate_bits: &[u64],
+    code_len: usize,
+    a: &Genome,
+    b: &Genome,
+) -> (Vec<u64>, Vec<u64>) {
+    let mut codes = (vec![0; code_len], vec![0; code_len]);
+    a.expand_code::<F, _>(mutate_bits, &mut codes.0);
+    b.expand_code::<F, _>(mutate_bits, &mut codes.1);
+    codes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::fill_mutate_bits;
+
+    #[test]
+    fn seed_distance() {
+        let a = Genome::new(1).with_mutation(3).with_mutation(4);
+        let b = Genome::new(1)
+            .with_mutation(4)
+            .with_mutation(5)
+            .with_mutation(5);
+        assert_eq!(SeedDistance.distance(&a, &b), 3.0);
+        assert_eq!(SeedDistance.distance(&a, &a), 0.0);
+        assert_eq!(SeedDistance.distance(&a, &Genome::new(2)), f64::INFINITY);
+    }
+
+    #[test]
+    fn code_distances() {
+        assert_eq!(hamming(&[0b1011, 7], &[0b0001, 7, 0]), 66);
+
+        // Kind 0 is end_func, the highest selector an output store
+        let code = [0, u64::MAX, 0];
+        assert_eq!(kinds::<DefaultFrequencies>(&code), [0, 30, 0]);
+        let deleted = [u64::MAX, 0];
+        assert_eq!(kind_edit_distance::<DefaultFrequencies>(&code, &deleted), 1);
+        // Operands are ignored
+        let operands = [0xFFFF_0000, 0xFFFF_FFFF_0000_FFFF, 0];
+        assert_eq!(
+            kind_edit_distance::<DefaultFrequencies>(&code, &operands),
+            0
+        );
+        assert_eq!(kind_edit_distance::<DefaultFrequencies>(&code, &[]), 3);
+    }
+
+    #[test]
+    fn genome_distances() {
+        let mut mutate_bits = vec![0; 128];
+        fill_mutate_bits(&mut mutate_bits, 2, 8000);
+        let a = Genome::new(1);
+        let b = a.clone().with_mutation(5);
+
+        let hamming = HammingDistance::<DefaultFrequencies>::new(mutate_bits.clone(), 32);
+        assert_eq!(hamming.distance(&a, &a), 0.0);
+        assert!(hamming.distance(&a, &b) > 0.0);
+        let kinds = KindDistance::<DefaultFrequencies>::new(mutate_bits, 32);
+        assert!(kinds.distance(&a, &b) <= 32.0);
+    }
+}
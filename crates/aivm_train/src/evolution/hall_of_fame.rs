@@ -0,0 +1,157 @@
+use super::{
+    persist::{self, LoadError},
+    Genome,
+};
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"AIVMHOF\0";
+/// The version of the serialized format, which only changes when saved archives can no longer
+/// be loaded by an older version of this crate.
+const FORMAT_VERSION: u16 = 1;
+
+/// A genome together with the fitness it was evaluated to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Champion {
+    /// The seeds the genome is made of.
+    pub genome: Genome,
+    /// The highest fitness the genome was evaluated to.
+    pub fitness: f64,
+}
+
+/// An archive of the fittest genomes ever seen, so the best agent of a long run is never lost
+/// to an unlucky generation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HallOfFame {
+    capacity: usize,
+    champions: Vec<Champion>,
+}
+
+impl HallOfFame {
+    /// Create an empty archive that keeps the `capacity` fittest genomes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            champions: vec![],
+        }
+    }
+
+    /// The maximum amount of genomes kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The archived genomes, ordered by fitness from high to low.
+    pub fn champions(&self) -> &[Champion] {
+        &self.champions
+    }
+
+    /// The fittest genome ever seen, if any.
+    pub fn best(&self) -> Option<&Champion> {
+        self.champions.first()
+    }
+
+    /// Offer a genome to the archive, returns whether it was added or its fitness raised.
+    ///
+    /// A genome that is already archived keeps the highest fitness it was evaluated to. NaN
+    /// fitness is never archived.
+    pub fn insert(&mut self, genome: &Genome, fitness: f64) -> bool {
+        if fitness.is_nan() {
+            return false;
+        }
+
+        if let Some(i) = self.champions.iter().position(|c| c.genome == *genome) {
+            if fitness <= self.champions[i].fitness {
+                return false;
+            }
+            self.champions.remove(i);
+        } else if self.champions.len() >= self.capacity
+            && self
+                .champions
+                .last()
+                .is_none_or(|worst| fitness <= worst.fitness)
+        {
+            return false;
+        }
+
+        let i = self.champions.partition_point(|c| c.fitness >= fitness);
+        self.champions.insert(
+            i,
+            Champion {
+                genome: genome.clone(),
+                fitness,
+            },
+        );
+        self.champions.truncate(self.capacity);
+        true
+    }
+
+    /// Serialize the archive, so it can be loaded with [from_bytes](Self::from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        persist::serialize(MAGIC, FORMAT_VERSION, self).expect("archives can always be serialized")
+    }
+
+    /// Load an archive that was serialized with [to_bytes](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let hall: Self = persist::deserialize(MAGIC, FORMAT_VERSION, bytes)?;
+        if hall.champions.len() > hall.capacity
+            || !hall
+                .champions
+                .windows(2)
+                .all(|w| w[0].fitness >= w[1].fitness)
+        {
+            return Err(LoadError::Malformed);
+        }
+
+        Ok(hall)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::EditKind;
+
+    #[test]
+    fn insert() {
+        let mut hall = HallOfFame::new(2);
+        assert!(hall.insert(&Genome::new(1), 3.0));
+        assert!(hall.insert(&Genome::new(2), 5.0));
+        assert!(!hall.insert(&Genome::new(3), 1.0));
+        assert!(!hall.insert(&Genome::new(3), f64::NAN));
+        assert!(!hall.insert(&Genome::new(2), 4.0));
+        assert!(hall.insert(&Genome::new(1), 6.0));
+
+        let seeds: Vec<_> = hall
+            .champions()
+            .iter()
+            .map(|c| c.genome.root_seed)
+            .collect();
+        assert_eq!(seeds, [1, 2]);
+        assert_eq!(hall.best().unwrap().fitness, 6.0);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut hall = HallOfFame::new(3);
+        let edited = Genome::new(1)
+            .with_mutation(7)
+            .with_edit(EditKind::SwapFunctions, 3)
+            .with_mutation(9);
+        hall.insert(&edited, -2.5);
+        hall.insert(&Genome::new(u64::MAX), f64::INFINITY);
+        let bytes = hall.to_bytes();
+        assert_eq!(HallOfFame::from_bytes(&bytes), Ok(hall));
+
+        assert_eq!(
+            HallOfFame::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Malformed)
+        );
+        let mut newer = bytes.clone();
+        newer[8] = 2;
+        assert_eq!(
+            HallOfFame::from_bytes(&newer),
+            Err(LoadError::UnsupportedVersion { found: 2 })
+        );
+    }
+}
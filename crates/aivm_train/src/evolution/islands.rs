@@ -0,0 +1,237 @@
+use super::{GenerationStats, Genome, MutateBits, Mutation, Population, Selection};
+use crate::{
+    fitness::{Evaluator, Fitness},
+    seed::SplitRng,
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Which islands send migrants to which.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Topology {
+    /// Every island sends migrants to the next one, and the last one to the first.
+    Ring,
+    /// Every island sends migrants to all other islands.
+    Complete,
+    /// Island `i` sends migrants to the islands listed at index `i`.
+    Custom(Vec<Vec<usize>>),
+}
+
+impl Topology {
+    /// The islands that island `island` of `count` islands sends migrants to.
+    pub fn targets(&self, island: usize, count: usize) -> Vec<usize> {
+        match self {
+            Self::Ring if count > 1 => vec![(island + 1) % count],
+            Self::Ring => vec![],
+            Self::Complete => (0..count).filter(|&i| i != island).collect(),
+            Self::Custom(targets) => targets.get(island).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// Several populations that evolve independently on their own thread, and periodically send
+/// their fittest genomes to each other.
+///
+/// Isolated populations converge to different solutions, migration then spreads good building
+/// blocks between them. This keeps more diversity than one big population, and uses every
+/// core of the machine.
+#[derive(Serialize, Deserialize)]
+pub struct Islands {
+    populations: Vec<Population>,
+    topology: Topology,
+    interval: u64,
+    migrants: usize,
+}
+
+impl Islands {
+    /// Create `count` islands of `size` genomes each, which every 10 generations send their
+    /// fittest genome to the next island in a [Ring](Topology::Ring).
+    ///
+    /// Every island gets its own split of a [SplitRng] seeded with `seed`.
+    ///
+    /// # Panics
+    /// If `count` or `size` is 0.
+    pub fn new(count: usize, size: usize, seed: u64) -> Self {
+        assert!(count > 0, "no islands");
+        let mut root = SplitRng::new(seed);
+        let populations = (0..count)
+            .map(|_| {
+                let mut rng = root.split();
+                let genomes = (0..size).map(|_| Genome::new(rng.gen())).collect();
+                Population::with_genomes(genomes, rng)
+            })
+            .collect();
+
+        Self {
+            populations,
+            topology: Topology::Ring,
+            interval: 10,
+            migrants: 1,
+        }
+    }
+
+    /// The same islands, but migrating along `topology`.
+    pub fn with_topology(self, topology: Topology) -> Self {
+        Self { topology, ..self }
+    }
+
+    /// The same islands, but migrating every `interval` generations.
+    ///
+    /// # Panics
+    /// If `interval` is 0.
+    pub fn with_interval(self, interval: u64) -> Self {
+        assert!(interval > 0, "invalid migration interval");
+        Self { interval, ..self }
+    }
+
+    /// The same islands, but sending the `migrants` fittest genomes to every target.
+    pub fn with_migrants(self, migrants: usize) -> Self {
+        Self { migrants, ..self }
+    }
+
+    /// The population of every island.
+    pub fn populations(&self) -> &[Population] {
+        &self.populations
+    }
+
+    /// Mutable access to the population of every island, for example to set elitism.
+    pub fn populations_mut(&mut self) -> &mut [Population] {
+        &mut self.populations
+    }
+
+    /// Step every island one generation on its own thread with its own evaluator, then
+    /// migrate if the migration interval has passed. Returns the statistics of every island.
+    ///
+    /// # Panics
+    /// If there are not as many evaluators as islands.
+    pub fn step_generation<G, F, B, S, M>(
+        &mut self,
+        evaluators: &mut [Evaluator<G, F, B>],
+        selection: &S,
+        mutation: &M,
+    ) -> Vec<GenerationStats>
+    where
+        G: CodeGenerator + Send + 'static,
+        G::Runner: Runner,
+        F: Fitness + Send,
+        B: MutateBits + Send,
+        S: Selection + Clone + Send,
+        M: Mutation + Clone + Send,
+    {
+        assert_eq!(
+            evaluators.len(),
+            self.populations.len(),
+            "every island needs an evaluator",
+        );
+
+        let stats = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .populations
+                .iter_mut()
+                .zip(evaluators)
+                .map(|(population, evaluator)| {
+                    let mut selection = selection.clone();
+                    let mut mutation = mutation.clone();
+                    scope.spawn(move || {
+                        population.step_generation(evaluator, &mut selection, &mut mutation)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        if self.populations[0]
+            .generation()
+            .is_multiple_of(self.interval)
+        {
+            self.migrate();
+        }
+
+        stats
+    }
+
+    /// Send the fittest genomes of the last evaluated generation of every island to its
+    /// targets, where they replace bred genomes.
+    pub fn migrate(&mut self) {
+        let count = self.populations.len();
+        let emigrants: Vec<Vec<_>> = self
+            .populations
+            .iter()
+            .map(|population| {
+                population
+                    .ranked()
+                    .iter()
+                    .take(self.migrants)
+                    .map(|champion| champion.genome.clone())
+                    .collect()
+            })
+            .collect();
+
+        let mut immigrants = vec![vec![]; count];
+        for (island, emigrants) in emigrants.iter().enumerate() {
+            for target in self.topology.targets(island, count) {
+                immigrants[target].extend_from_slice(emigrants);
+            }
+        }
+        for (population, immigrants) in self.populations.iter_mut().zip(&immigrants) {
+            population.immigrate(immigrants);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, AppendSeed, Tournament};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn topology() {
+        assert_eq!(Topology::Ring.targets(2, 3), [0]);
+        assert!(Topology::Ring.targets(0, 1).is_empty());
+        assert_eq!(Topology::Complete.targets(1, 3), [0, 2]);
+        assert_eq!(Topology::Custom(vec![vec![], vec![0]]).targets(1, 2), [0]);
+    }
+
+    #[test]
+    fn migration() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness = |runner: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| {
+            runner.step(memory.as_mut_slice());
+            memory.output()[0] as f64
+        };
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluators: Vec<_> = (0..3)
+            .map(|_| {
+                let compiler = Compiler::new(Interpreter::new());
+                Evaluator::new(compiler, layout, 64, mutate_bits.clone(), fitness)
+            })
+            .collect();
+
+        let mut islands = Islands::new(3, 8, 1).with_interval(2).with_migrants(2);
+        let stats = islands.step_generation(&mut evaluators, &Tournament::new(2), &AppendSeed);
+        assert_eq!(stats.len(), 3);
+        // Different streams give every island other genomes
+        assert_ne!(stats[0].best, stats[1].best);
+
+        let stats = islands.step_generation(&mut evaluators, &Tournament::new(2), &AppendSeed);
+        for (island, population) in islands.populations().iter().enumerate() {
+            let source = &islands.populations()[(island + 2) % 3];
+            assert_eq!(
+                population.genomes()[6..],
+                [
+                    source.ranked()[0].genome.clone(),
+                    source.ranked()[1].genome.clone(),
+                ]
+            );
+            assert_eq!(source.ranked()[0].genome, stats[(island + 2) % 3].best);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::{
+    persist::{self, LoadError},
+    Champion, Genome, Mutation,
+};
+
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+const MAGIC: &[u8; 8] = b"AIVMMAPE";
+/// The version of the serialized format, which only changes when saved archives can no longer
+/// be loaded by an older version of this crate.
+const FORMAT_VERSION: u16 = 1;
+
+/// An axis of the behavior space of a [MapElites] archive, such as the distance an agent
+/// travelled or how often it used a particular action.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dimension {
+    /// The lowest value of the behavior, lower values fall into the first bin.
+    pub min: f64,
+    /// The highest value of the behavior, higher values fall into the last bin.
+    pub max: f64,
+    /// The amount of equally sized bins the range is divided into.
+    pub bins: usize,
+}
+
+impl Dimension {
+    /// Divide `min..max` into `bins` bins.
+    ///
+    /// # Panics
+    /// If `bins` is 0 or `min` is not lower than `max`.
+    pub fn new(min: f64, max: f64, bins: usize) -> Self {
+        assert!(bins > 0, "dimension without bins");
+        assert!(min < max, "empty behavior range");
+        Self { min, max, bins }
+    }
+
+    /// The bin `value` falls into, or `None` if it is NaN.
+    pub fn bin(&self, value: f64) -> Option<usize> {
+        if value.is_nan() {
+            return None;
+        }
+        let bin = ((value - self.min) / (self.max - self.min) * self.bins as f64).floor();
+        Some((bin.max(0.0) as usize).min(self.bins - 1))
+    }
+}
+
+/// A MAP-Elites archive, which keeps the fittest genome for every cell of a grid over
+/// user-defined behavior dimensions.
+///
+/// Rather than only the single fittest genome, this collects a diverse set of genomes that each
+/// solve the task in their own way, which also gives evolution stepping stones that a single
+/// population would lose. New genomes are bred by mutating [sampled](Self::sample) elites,
+/// evaluating them and [inserting](Self::insert) them with their behavior.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapElites {
+    dimensions: Vec<Dimension>,
+    cells: BTreeMap<usize, Champion>,
+}
+
+impl MapElites {
+    /// Create an empty archive over the given dimensions.
+    ///
+    /// # Panics
+    /// If `dimensions` is empty or the grid has more than `usize::MAX` cells.
+    pub fn new(dimensions: Vec<Dimension>) -> Self {
+        assert!(!dimensions.is_empty(), "no behavior dimensions");
+        dimensions
+            .iter()
+            .try_fold(1usize, |cells, d| cells.checked_mul(d.bins))
+            .expect("too many cells");
+
+        Self {
+            dimensions,
+            cells: BTreeMap::new(),
+        }
+    }
+
+    /// The behavior dimensions of the grid.
+    pub fn dimensions(&self) -> &[Dimension] {
+        &self.dimensions
+    }
+
+    /// The amount of cells in the grid.
+    pub fn cell_count(&self) -> usize {
+        self.dimensions.iter().map(|d| d.bins).product()
+    }
+
+    /// The amount of cells that hold a genome.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Whether no cell holds a genome yet.
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// The cell of a behavior, given one value per dimension, as the bin in every dimension.
+    /// Returns `None` if a value is NaN.
+    ///
+    /// # Panics
+    /// If there are not as many values as dimensions.
+    pub fn cell(&self, behavior: &[f64]) -> Option<Vec<usize>> {
+        assert_eq!(behavior.len(), self.dimensions.len(), "wrong behavior size");
+        self.dimensions
+            .iter()
+            .zip(behavior)
+            .map(|(d, &value)| d.bin(value))
+            .collect()
+    }
+
+    /// The genome in the cell of `behavior`, if any.
+    pub fn get(&self, behavior: &[f64]) -> Option<&Champion> {
+        self.index(behavior).and_then(|i| self.cells.get(&i))
+    }
+
+    /// Offer a genome with the given behavior to the archive, returns whether it became the
+    /// elite of its cell. NaN fitness or behavior is never archived.
+    pub fn insert(&mut self, genome: &Genome, fitness: f64, behavior: &[f64]) -> bool {
+        let Some(i) = self.index(behavior) else {
+            return false;
+        };
+        if fitness.is_nan() || self.cells.get(&i).is_some_and(|c| c.fitness >= fitness) {
+            return false;
+        }
+
+        self.cells.insert(
+            i,
+            Champion {
+                genome: genome.clone(),
+                fitness,
+            },
+        );
+        true
+    }
+
+    /// Every elite with the bins of its cell.
+    pub fn elites(&self) -> impl Iterator<Item = (Vec<usize>, &Champion)> + '_ {
+        self.cells
+            .iter()
+            .map(|(&i, champion)| (self.unflatten(i), champion))
+    }
+
+    /// The fittest genome of all cells, if any.
+    pub fn best(&self) -> Option<&Champion> {
+        self.cells
+            .values()
+            .max_by(|a, b| a.fitness.total_cmp(&b.fitness))
+    }
+
+    /// An elite picked uniformly from the filled cells, or `None` if the archive is empty.
+    pub fn sample(&self, rng: &mut Pcg64) -> Option<&Champion> {
+        if self.cells.is_empty() {
+            return None;
+        }
+        self.cells.values().nth(rng.gen_range(0..self.cells.len()))
+    }
+
+    /// Breed `count` genomes by mutating [sampled](Self::sample) elites, or none if the archive
+    /// is empty.
+    pub fn offspring(
+        &self,
+        count: usize,
+        mutation: &mut impl Mutation,
+        rng: &mut Pcg64,
+    ) -> Vec<Genome> {
+        if self.cells.is_empty() {
+            return vec![];
+        }
+        (0..count)
+            .map(|_| {
+                let parent = &self.sample(rng).unwrap().genome;
+                mutation.mutate(parent, rng)
+            })
+            .collect()
+    }
+
+    /// Serialize the archive, so it can be loaded with [from_bytes](Self::from_bytes).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        persist::serialize(MAGIC, FORMAT_VERSION, self).expect("archives can always be serialized")
+    }
+
+    /// Load an archive that was serialized with [to_bytes](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LoadError> {
+        let archive: Self = persist::deserialize(MAGIC, FORMAT_VERSION, bytes)?;
+        let valid = archive
+            .dimensions
+            .iter()
+            .all(|d| d.bins > 0 && d.min.partial_cmp(&d.max) == Some(Ordering::Less));
+        let cell_count = archive
+            .dimensions
+            .iter()
+            .try_fold(1usize, |cells, d| cells.checked_mul(d.bins))
+            .filter(|_| valid && !archive.dimensions.is_empty())
+            .ok_or(LoadError::Malformed)?;
+        if archive.cells.keys().any(|&i| i >= cell_count) {
+            return Err(LoadError::Malformed);
+        }
+
+        Ok(archive)
+    }
+
+    fn index(&self, behavior: &[f64]) -> Option<usize> {
+        let cell = self.cell(behavior)?;
+        Some(
+            cell.iter()
+                .zip(&self.dimensions)
+                .fold(0, |index, (&bin, d)| index * d.bins + bin),
+        )
+    }
+
+    fn unflatten(&self, mut index: usize) -> Vec<usize> {
+        let mut cell = vec![0; self.dimensions.len()];
+        for (bin, d) in cell.iter_mut().zip(&self.dimensions).rev() {
+            *bin = index % d.bins;
+            index /= d.bins;
+        }
+        cell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::AppendSeed;
+
+    fn archive() -> MapElites {
+        let mut archive = MapElites::new(vec![
+            Dimension::new(0.0, 1.0, 4),
+            Dimension::new(-10.0, 10.0, 2),
+        ]);
+        assert!(archive.insert(&Genome::new(1), 1.0, &[0.1, -5.0]));
+        assert!(archive.insert(&Genome::new(2), 2.0, &[0.9, 5.0]));
+        assert!(!archive.insert(&Genome::new(3), 0.5, &[0.2, -1.0]));
+        assert!(archive.insert(&Genome::new(4), 3.0, &[0.15, -100.0]));
+        assert!(!archive.insert(&Genome::new(5), 3.0, &[f64::NAN, 0.0]));
+        archive
+    }
+
+    #[test]
+    fn cells() {
+        let archive = archive();
+        assert_eq!(archive.cell_count(), 8);
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive.cell(&[2.0, 0.0]), Some(vec![3, 1]));
+        assert_eq!(archive.get(&[0.0, -3.0]).unwrap().genome.root_seed, 4);
+        assert_eq!(archive.best().unwrap().fitness, 3.0);
+
+        let elites: Vec<_> = archive
+            .elites()
+            .map(|(cell, c)| (cell, c.fitness))
+            .collect();
+        assert_eq!(elites, [(vec![0, 0], 3.0), (vec![3, 1], 2.0)]);
+
+        let mut rng = Pcg64::seed_from_u64(1);
+        let offspring = archive.offspring(16, &mut AppendSeed, &mut rng);
+        assert!(offspring
+            .iter()
+            .all(|g| (g.root_seed == 2 || g.root_seed == 4) && g.mutation_seeds.len() == 1));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let archive = archive();
+        let bytes = archive.to_bytes();
+        assert_eq!(MapElites::from_bytes(&bytes), Ok(archive));
+        assert_eq!(
+            MapElites::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Malformed)
+        );
+    }
+}
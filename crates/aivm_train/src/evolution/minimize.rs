@@ -0,0 +1,142 @@
+use super::prune_with_table;
+
+use aivm::{CallGraph, InstructionFrequencies, KIND_COUNT};
+
+/// The code word that ends functions in minimized code, and pads the code after it.
+const END_FUNC_WORD: u64 = 0;
+
+/// Remove as many instructions from `code` as possible while `keeps` holds for the reduced
+/// code, for example while a program behaves the same or its fitness stays within a tolerance.
+/// Returns the amount of code words still in use, the rest is padded with words that end a
+/// function, like [prune](super::prune) which is applied first.
+///
+/// This is delta debugging: chunks of instructions are removed while `keeps` holds, and the
+/// chunks are halved until single instructions are tried, which repeats until no single
+/// instruction can be removed. `keeps` is given code of the same length as `code`, compiled with
+/// `lowest_function_level` and the instruction frequencies `F`, and should hold for `code`
+/// itself. Every function keeps at least one instruction, so calls keep their targets.
+pub fn minimize<F: InstructionFrequencies>(
+    code: &mut [u64],
+    lowest_function_level: u32,
+    keeps: impl FnMut(&[u64]) -> bool,
+) -> usize {
+    minimize_with_table(code, lowest_function_level, &F::table(), keeps)
+}
+
+/// Like [minimize], but with the frequencies in a table like
+/// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+pub fn minimize_with_table(
+    code: &mut [u64],
+    lowest_function_level: u32,
+    frequencies: &[u16; KIND_COUNT],
+    mut keeps: impl FnMut(&[u64]) -> bool,
+) -> usize {
+    prune_with_table(code, lowest_function_level, frequencies);
+    let graph = CallGraph::with_table(code, lowest_function_level, frequencies);
+    let functions: Vec<_> = (0..graph.function_count())
+        .map(|f| graph.instructions(f))
+        .collect();
+    let original = code.to_vec();
+
+    // The code without the removed instructions and its length, or nothing if a function
+    // would be left empty
+    let build = |removed: &[bool]| {
+        let mut reduced = Vec::with_capacity(original.len());
+        for (f, instructions) in functions.iter().enumerate() {
+            if f > 0 {
+                reduced.push(END_FUNC_WORD);
+            }
+            let start = reduced.len();
+            reduced.extend(
+                instructions
+                    .clone()
+                    .filter(|&i| !removed[i])
+                    .map(|i| original[i]),
+            );
+            if reduced.len() == start && !instructions.is_empty() {
+                return None;
+            }
+        }
+        let len = reduced.len();
+        reduced.resize(original.len(), END_FUNC_WORD);
+        Some((reduced, len))
+    };
+
+    let mut removed = vec![false; original.len()];
+    let mut candidates: Vec<_> = functions.iter().flat_map(|f| f.clone()).collect();
+    let mut chunk = candidates.len().div_ceil(2).max(1);
+    loop {
+        let mut changed = false;
+        let mut start = 0;
+        while start < candidates.len() {
+            let end = (start + chunk).min(candidates.len());
+            for &i in &candidates[start..end] {
+                removed[i] = true;
+            }
+            match build(&removed) {
+                Some((reduced, _)) if keeps(&reduced) => {
+                    candidates.drain(start..end);
+                    changed = true;
+                }
+                _ => {
+                    for &i in &candidates[start..end] {
+                        removed[i] = false;
+                    }
+                    start = end;
+                }
+            }
+        }
+
+        if chunk == 1 && !changed {
+            break;
+        }
+        chunk = (chunk / 2).max(1);
+    }
+
+    let (reduced, len) = build(&removed).expect("functions are never emptied");
+    code.copy_from_slice(&reduced);
+    len
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aivm::{codegen::Interpreter, Compiler, DefaultFrequencies, Runner};
+    use rand::prelude::*;
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn same_behavior() {
+        let mut rng = Pcg64::seed_from_u64(5);
+        let mut code = vec![0u64; 128];
+        rng.fill(&mut code[..]);
+        for word in code.iter_mut().step_by(17) {
+            *word = END_FUNC_WORD;
+        }
+
+        let mut compiler = Compiler::new(Interpreter::new());
+        let inputs: Vec<Vec<i64>> = (0..4)
+            .map(|_| (0..24).map(|_| rng.gen_range(-100..100)).collect())
+            .collect();
+        let mut behavior = |code: &[u64]| -> Vec<Vec<i64>> {
+            let runner = compiler.compile(code, 4, 16, 4, 4);
+            inputs
+                .iter()
+                .map(|input| {
+                    let mut memory = input.clone();
+                    runner.step(&mut memory);
+                    memory
+                })
+                .collect()
+        };
+
+        let expected = behavior(&code);
+        let mut minimized = code.clone();
+        let len =
+            minimize::<DefaultFrequencies>(&mut minimized, 4, |code| behavior(code) == expected);
+        assert!(len < code.len());
+        assert_eq!(behavior(&minimized), expected);
+        assert_eq!(behavior(&minimized[..len]), expected);
+        assert!(minimized[len..].iter().all(|&word| word == END_FUNC_WORD));
+    }
+}
@@ -1,26 +1,165 @@
+use aivm::{InstructionFrequencies, KIND_COUNT};
 use rand::prelude::*;
 use rand_pcg::{Pcg32, Pcg64};
+use serde::{Deserialize, Serialize};
 
+mod arena;
+mod bisect;
+mod coevolution;
+mod distance;
+mod hall_of_fame;
+mod islands;
+mod map_elites;
+mod minimize;
 mod mutate;
+mod opponents;
+pub(crate) mod persist;
+mod population;
+mod prune;
+mod schedule;
+mod selection;
+mod shaping;
+mod speciation;
+mod stopping;
+mod structure;
 
-pub use mutate::fill_mutate_bits;
+pub use arena::{expand_code_batch, expand_memory_batch, Arena};
+pub use bisect::bisect_mutations;
+pub use coevolution::{CoEvolution, MemoryCrossover, MemoryMutation, Pair, Perturb};
+pub use distance::{
+    hamming, kind_edit_distance, kinds, Distance, HammingDistance, KindDistance, SeedDistance,
+};
+pub use hall_of_fame::{Champion, HallOfFame};
+pub use islands::{Islands, Topology};
+pub use map_elites::{Dimension, MapElites};
+pub use minimize::{minimize, minimize_with_table};
+pub use mutate::{
+    fill_mutate_bits, fill_mutate_bits_with_rates, fill_mutate_fields, mutation_scale,
+    mutation_seed, AppendScaledSeed, AppendSeed, FieldRates, MutateBits, Mutation, ProceduralBits,
+    RegionRates, MUTATION_OFFSET_BITS,
+};
+pub use opponents::{Opponent, OpponentPool, Sampling};
+pub use persist::LoadError;
+pub use population::{GenerationStats, Population};
+pub use prune::{
+    prune, prune_with_table, reachable_instructions, reachable_instructions_with_table,
+};
+pub use schedule::{Constant, ExponentialDecay, OneFifthRule, Schedule};
+pub use selection::{Lexicase, Selection, Tournament, Truncation};
+pub use shaping::{centered_ranks, Baseline, FitnessSharing, RankNormalization, Shaping};
+pub use speciation::{Speciation, Species};
+pub use stopping::{Stagnation, StopCriteria, StopReason};
+pub use structure::{Edit, EditKind, StructuralMutation};
 
-pub fn expand_code(root_seed: u64, mutation_seeds: &[u32], mutate_bits: &[u64], buf: &mut [u64]) {
+use mutate::Thinning;
+
+/// An agent, described by the seed of the random code and memory it starts from and the seeds
+/// of the mutations applied on top of it, see [expand_code] and [expand_memory], and the
+/// structural [edits](Edit) of its code.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Genome {
+    /// The seed of the code and memory before any mutation.
+    pub root_seed: u64,
+    /// The seeds of the mutations, in the order they are applied. A seed also holds the share
+    /// of the mutate bits its mutation flips, see [mutation_seed].
+    pub mutation_seeds: Vec<u32>,
+    /// The structural edits of the code, ordered by the amount of mutation seeds applied before
+    /// them.
+    pub edits: Vec<Edit>,
+}
+
+impl Genome {
+    /// Create a genome without mutations.
+    pub fn new(root_seed: u64) -> Self {
+        Self {
+            root_seed,
+            mutation_seeds: vec![],
+            edits: vec![],
+        }
+    }
+
+    /// The same genome, with one more mutation applied.
+    pub fn with_mutation(mut self, seed: u32) -> Self {
+        self.mutation_seeds.push(seed);
+        self
+    }
+
+    /// The same genome, with an edit of `kind` applied after the mutations so far.
+    pub fn with_edit(mut self, kind: EditKind, seed: u32) -> Self {
+        self.edits.push(Edit {
+            after: self.mutation_seeds.len() as u32,
+            kind,
+            seed,
+        });
+        self
+    }
+
+    /// Fill `buf` with the code of the genome, see [expand_code]. The edits are applied in
+    /// between the mutations, with the instruction frequencies `F` the code is compiled with.
+    pub fn expand_code<F: InstructionFrequencies, M: MutateBits + ?Sized>(
+        &self,
+        mutate_bits: &M,
+        buf: &mut [u64],
+    ) {
+        self.expand_code_with_table(mutate_bits, buf, &F::table());
+    }
+
+    /// Like [expand_code](Self::expand_code), but with the frequencies in a table like
+    /// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+    pub fn expand_code_with_table<M: MutateBits + ?Sized>(
+        &self,
+        mutate_bits: &M,
+        buf: &mut [u64],
+        frequencies: &[u16; KIND_COUNT],
+    ) {
+        let mut applied = 0;
+        expand_code(self.root_seed, &[], mutate_bits, buf);
+        for edit in &self.edits {
+            let until = (edit.after as usize).clamp(applied, self.mutation_seeds.len());
+            mutate_code(&self.mutation_seeds[applied..until], mutate_bits, buf);
+            edit.apply_with_table(buf, frequencies);
+            applied = until;
+        }
+        mutate_code(&self.mutation_seeds[applied..], mutate_bits, buf);
+    }
+
+    /// Fill `buf` with the initial memory of the genome, see [expand_memory].
+    pub fn expand_memory<M: MutateBits + ?Sized>(&self, mutate_bits: &M, buf: &mut [i64]) {
+        expand_memory(self.root_seed, &self.mutation_seeds, mutate_bits, buf);
+    }
+}
+
+pub fn expand_code<M: MutateBits + ?Sized>(
+    root_seed: u64,
+    mutation_seeds: &[u32],
+    mutate_bits: &M,
+    buf: &mut [u64],
+) {
     assert!(mutate_bits.len() >= buf.len());
 
     Pcg64::seed_from_u64(root_seed).fill(buf);
+    mutate_code(mutation_seeds, mutate_bits, buf);
+}
 
+fn mutate_code<M: MutateBits + ?Sized>(mutation_seeds: &[u32], mutate_bits: &M, buf: &mut [u64]) {
     let max_offset = u32::try_from(mutate_bits.len() - buf.len()).unwrap_or(u32::MAX);
     for seed in mutation_seeds.iter().copied() {
-        let start = usize::try_from(seed % max_offset).unwrap();
-        let end = start + buf.len();
-        for (chunk, mutation) in buf.iter_mut().zip(&mutate_bits[start..end]) {
-            *chunk ^= mutation;
-        }
+        let thinning = Thinning::new(seed);
+        let start = usize::try_from(thinning.offset() % max_offset).unwrap();
+        let mut position = 0;
+        mutate_bits.apply(start, buf, |chunk, mutation| {
+            *chunk ^= thinning.apply(position, mutation);
+            position += 1;
+        });
     }
 }
 
-pub fn expand_memory(root_seed: u64, mutation_seeds: &[u32], mutate_bits: &[u64], buf: &mut [i64]) {
+pub fn expand_memory<M: MutateBits + ?Sized>(
+    root_seed: u64,
+    mutation_seeds: &[u32],
+    mutate_bits: &M,
+    buf: &mut [i64],
+) {
     assert!(mutate_bits.len() >= buf.len());
 
     let mut rng = Pcg64::seed_from_u64(root_seed);
@@ -28,11 +167,15 @@ pub fn expand_memory(root_seed: u64, mutation_seeds: &[u32], mutate_bits: &[u64]
 
     let max_offset = u32::try_from(mutate_bits.len() - buf.len()).unwrap_or(u32::MAX);
     for seed in mutation_seeds.iter().copied() {
-        let seed = Pcg32::seed_from_u64(u64::from(seed)).gen::<u32>();
+        let thinning = Thinning::new(seed);
+        // Hashed for another window than the code, but without the scale, so a scaled mutation
+        // flips a share of the bits of the unscaled one in the memory too
+        let seed = Pcg32::seed_from_u64(u64::from(thinning.offset())).gen::<u32>();
         let start = usize::try_from(seed % max_offset).unwrap();
-        let end = start + buf.len();
-        for (chunk, mutation) in buf.iter_mut().zip(mutate_bits[start..end].iter().copied()) {
-            *chunk ^= mutation as i64;
-        }
+        let mut position = 0;
+        mutate_bits.apply(start, buf, |chunk, mutation| {
+            *chunk ^= thinning.apply(position, mutation) as i64;
+            position += 1;
+        });
     }
 }
@@ -1,8 +1,227 @@
+use super::Genome;
+use crate::seed::splitmix64;
+
 use rand::prelude::*;
 use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// Produces a child genome from a parent.
+pub trait Mutation {
+    /// Create a mutated copy of `parent`.
+    fn mutate(&mut self, parent: &Genome, rng: &mut Pcg64) -> Genome;
+}
+
+impl<F> Mutation for F
+where
+    F: FnMut(&Genome, &mut Pcg64) -> Genome,
+{
+    fn mutate(&mut self, parent: &Genome, rng: &mut Pcg64) -> Genome {
+        self(parent, rng)
+    }
+}
+
+/// Mutates genomes by appending a random mutation seed, so every child differs from its parent
+/// by one application of the mutate bits.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AppendSeed;
+
+impl Mutation for AppendSeed {
+    fn mutate(&mut self, parent: &Genome, rng: &mut Pcg64) -> Genome {
+        parent.clone().with_mutation(mutation_seed(rng.gen(), 1.0))
+    }
+}
+
+/// Mutates genomes like [AppendSeed], but with mutations that flip only a share of the bits of
+/// their window, see [mutation_seed].
+///
+/// This is how a [Schedule](super::Schedule) lowers the mutation probability: the mutate bits
+/// stay the same for the whole run, so the genomes that were already evaluated keep expanding to
+/// the same code.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AppendScaledSeed {
+    scale: f64,
+}
+
+impl AppendScaledSeed {
+    /// Append mutations of `scale`.
+    pub fn new(scale: f64) -> Self {
+        Self { scale }
+    }
+
+    /// Append mutations that flip bits with a probability of `p_mutate`, of mutate bits that
+    /// were filled with a probability of `filled`, both in the units of [fill_mutate_bits].
+    pub fn from_probabilities(p_mutate: u16, filled: u16) -> Self {
+        Self::new(f64::from(p_mutate) / f64::from(filled))
+    }
 
+    /// The scale of the mutations.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+}
+
+impl Mutation for AppendScaledSeed {
+    fn mutate(&mut self, parent: &Genome, rng: &mut Pcg64) -> Genome {
+        parent
+            .clone()
+            .with_mutation(mutation_seed(rng.gen(), self.scale))
+    }
+}
+
+/// The amount of low bits of a mutation seed that pick the offset of its window of mutate bits,
+/// the 8 bits above them hold its scale, see [mutation_seed]. Windows of code therefore start
+/// in the first 2<sup>24</sup> words of the bits, which
+/// [TrainConfig::validate](crate::config::TrainConfig::validate) checks.
+pub const MUTATION_OFFSET_BITS: u32 = 24;
+
+const OFFSET_MASK: u32 = (1 << MUTATION_OFFSET_BITS) - 1;
+
+/// The mutation seed with the window at `offset` that flips `scale` times as many bits as the
+/// window holds.
+///
+/// Every set bit of the window is kept with a probability of `scale`, decided by a hash of the
+/// seed and the position of the bit, so a mutation only depends on its seed and the mutate bits.
+/// The scale is stored as a power of 2<sup>-1/16</sup>, which rounds it by at most 2.2%, down to
+/// 2<sup>-255/16</sup>. Scales above 1 are clamped to 1, so the bits have to be filled with the
+/// highest probability a mutation needs.
+pub fn mutation_seed(offset: u32, scale: f64) -> u32 {
+    let level = (-scale.log2() * 16.0).round().clamp(0.0, 255.0) as u32;
+    level << MUTATION_OFFSET_BITS | offset & OFFSET_MASK
+}
+
+/// The scale of the mutation of `seed`, see [mutation_seed].
+pub fn mutation_scale(seed: u32) -> f64 {
+    (-f64::from(seed >> MUTATION_OFFSET_BITS) / 16.0).exp2()
+}
+
+/// Which set bits of the window of a mutation it flips, see [mutation_seed].
+pub(super) struct Thinning {
+    seed: u64,
+    threshold: Option<u64>,
+}
+
+impl Thinning {
+    pub(super) fn new(seed: u32) -> Self {
+        let threshold = (seed >> MUTATION_OFFSET_BITS != 0)
+            .then(|| (mutation_scale(seed) * 2f64.powi(64)) as u64);
+        Self {
+            seed: u64::from(seed),
+            threshold,
+        }
+    }
+
+    /// The window offset of the seed.
+    pub(super) fn offset(&self) -> u32 {
+        self.seed as u32 & OFFSET_MASK
+    }
+
+    /// The bits of `word`, the word at `position` in the window, that are flipped.
+    pub(super) fn apply(&self, position: usize, word: u64) -> u64 {
+        let Some(threshold) = self.threshold else {
+            return word;
+        };
+
+        let mut rest = word;
+        let mut kept = 0;
+        while rest != 0 {
+            let bit = rest.trailing_zeros();
+            rest &= rest - 1;
+            let hash = splitmix64(self.seed << 32 ^ (position as u64 * 64 + u64::from(bit)));
+            if hash < threshold {
+                kept |= 1 << bit;
+            }
+        }
+        kept
+    }
+}
+
+/// Mutation probabilities for the regions of a code word, in units of 1/65536 per bit.
+///
+/// The lowest 16 bits of a code word select the instruction kind, the next 16 hold the register
+/// operands and the highest 32 the immediate, or two more register operands. Mutating the kind
+/// changes what an instruction does altogether, so it usually pays to mutate it less often than
+/// the operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct RegionRates {
+    /// The probability for bits 0 to 15, which select the instruction kind.
+    pub opcode: u16,
+    /// The probability for bits 16 to 31, which hold the register operands.
+    pub operands: u16,
+    /// The probability for bits 32 to 63, which hold the immediate.
+    pub immediate: u16,
+}
+
+impl RegionRates {
+    /// The same probability for every bit.
+    pub const fn uniform(p_mutate: u16) -> Self {
+        Self {
+            opcode: p_mutate,
+            operands: p_mutate,
+            immediate: p_mutate,
+        }
+    }
+}
+
+/// Mutation probabilities for the fields of an instruction, in units of 1/65536 per field of
+/// every code word.
+///
+/// Where [RegionRates] flips single bits, a mutated field gets a new random value altogether.
+/// The kind is selected by bits 0 to 15, the two register operands are stored in bits 16 to 21
+/// and 22 to 27, and the immediate in bits 32 to 63, which instructions that take four registers
+/// read their other two operands from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct FieldRates {
+    /// The probability of choosing another instruction kind.
+    pub kind: u16,
+    /// The probability of choosing another register, for each of the two operands.
+    pub operands: u16,
+    /// The probability of choosing another immediate.
+    pub immediate: u16,
+}
+
+/// Fill `buf` with masks that give fields of code words a new random value, with the
+/// probabilities of `rates`. The memory is mutated with the same bits, so its values change
+/// too.
+pub fn fill_mutate_fields(buf: &mut [u64], seed: u64, rates: FieldRates) {
+    let mut rng = Pcg64::seed_from_u64(seed);
+    // The shift, width and probability of every field
+    let fields = [
+        (0, 16, rates.kind),
+        (16, 6, rates.operands),
+        (22, 6, rates.operands),
+        (32, 32, rates.immediate),
+    ];
+
+    for chunk in buf {
+        let rand = rng.next_u64();
+        *chunk = 0;
+        for (i, (shift, width, threshold)) in fields.into_iter().enumerate() {
+            if ((rand >> (i * 16)) as u16) < threshold {
+                // A nonzero mask, so the field always changes
+                let mask = rng.gen_range(1..=u64::MAX >> (64 - width));
+                *chunk |= mask << shift;
+            }
+        }
+    }
+}
+
+/// Fill `buf` with masks of the bits to flip, where every bit is set with a probability of
+/// `p_mutate / 65536`.
 pub fn fill_mutate_bits(buf: &mut [u64], seed: u64, p_mutate: u16) {
+    fill_mutate_bits_with_rates(buf, seed, RegionRates::uniform(p_mutate));
+}
+
+/// Like [fill_mutate_bits], but with a separate probability for every region of a code word.
+/// The memory is mutated with the same bits, so its values are affected by the rates too.
+pub fn fill_mutate_bits_with_rates(buf: &mut [u64], seed: u64, rates: RegionRates) {
     let mut rng = Pcg64::seed_from_u64(seed);
+    // Lane i of every random number decides a bit of bits 16 * i to 16 * i + 15
+    let thresholds = [
+        rates.opcode,
+        rates.operands,
+        rates.immediate,
+        rates.immediate,
+    ];
 
     for chunk in buf {
         let mut mutations = 0;
@@ -12,9 +231,9 @@ pub fn fill_mutate_bits(buf: &mut [u64], seed: u64, p_mutate: u16) {
 
             // TODO: use simd when it's stable
             mutations <<= 1;
-            for i in 0..4 {
+            for (i, threshold) in thresholds.into_iter().enumerate() {
                 let shift_amount = i * 16;
-                let mutate_bit = ((rand >> shift_amount) as u16) < p_mutate;
+                let mutate_bit = ((rand >> shift_amount) as u16) < threshold;
                 mutations |= (mutate_bit as u64) << shift_amount;
             }
         }
@@ -23,9 +242,197 @@ pub fn fill_mutate_bits(buf: &mut [u64], seed: u64, p_mutate: u16) {
     }
 }
 
+/// Bits that genomes are mutated with, see [expand_code](super::expand_code).
+///
+/// A mutation applies a window of the bits the length of the code or memory, at an offset
+/// picked by its seed. The bits are usually a pool filled with [fill_mutate_bits], which has to
+/// be longer than the genome. [ProceduralBits] computes them when they are needed instead.
+pub trait MutateBits {
+    /// The amount of words of bits.
+    fn len(&self) -> usize;
+
+    /// Whether there are no bits at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Call `f` with every value of `buf` and the word at the same position of the window of
+    /// bits that starts at `offset`.
+    ///
+    /// # Panics
+    /// If the window ends past [len](Self::len).
+    fn apply<T>(&self, offset: usize, buf: &mut [T], f: impl FnMut(&mut T, u64));
+}
+
+impl MutateBits for [u64] {
+    fn len(&self) -> usize {
+        <[u64]>::len(self)
+    }
+
+    fn apply<T>(&self, offset: usize, buf: &mut [T], mut f: impl FnMut(&mut T, u64)) {
+        let window = &self[offset..offset + buf.len()];
+        for (value, word) in buf.iter_mut().zip(window.iter().copied()) {
+            f(value, word);
+        }
+    }
+}
+
+impl MutateBits for Vec<u64> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn apply<T>(&self, offset: usize, buf: &mut [T], f: impl FnMut(&mut T, u64)) {
+        self.as_slice().apply(offset, buf, f);
+    }
+}
+
+/// Mutate bits that are generated on the fly instead of stored, so very long genomes and large
+/// pools take no memory.
+///
+/// The bits are split in blocks of [BLOCK_LEN](Self::BLOCK_LEN) words, which are each filled
+/// like [fill_mutate_bits_with_rates] with a seed of their own derived from the seed of the
+/// pool. Generating bits is a lot slower than reading them, so expanding a genome takes more
+/// time than with a stored pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ProceduralBits {
+    seed: u64,
+    len: usize,
+    rates: RegionRates,
+}
+
+impl ProceduralBits {
+    /// The amount of words that are generated at once.
+    pub const BLOCK_LEN: usize = 64;
+
+    /// Create `len` words of bits, where every bit is set with a probability of
+    /// `p_mutate / 65536`, like [fill_mutate_bits].
+    pub fn new(len: usize, seed: u64, p_mutate: u16) -> Self {
+        Self {
+            seed,
+            len,
+            rates: RegionRates::uniform(p_mutate),
+        }
+    }
+
+    /// The same bits, but with a separate probability for every region of a code word.
+    pub fn with_rates(self, rates: RegionRates) -> Self {
+        Self { rates, ..self }
+    }
+
+    /// The probabilities bits are set with.
+    pub fn rates(&self) -> RegionRates {
+        self.rates
+    }
+
+    /// Fill `buf` with block `index`.
+    fn fill_block(&self, index: usize, buf: &mut [u64; Self::BLOCK_LEN]) {
+        const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+        let seed = splitmix64(
+            self.seed
+                .wrapping_add((index as u64).wrapping_add(1).wrapping_mul(GAMMA)),
+        );
+        fill_mutate_bits_with_rates(buf, seed, self.rates);
+    }
+}
+
+impl MutateBits for ProceduralBits {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn apply<T>(&self, offset: usize, buf: &mut [T], mut f: impl FnMut(&mut T, u64)) {
+        assert!(offset + buf.len() <= self.len, "window out of bounds");
+
+        let mut block = [0; Self::BLOCK_LEN];
+        for (position, value) in (offset..).zip(buf) {
+            let (index, word) = (position / Self::BLOCK_LEN, position % Self::BLOCK_LEN);
+            if word == 0 || position == offset {
+                self.fill_block(index, &mut block);
+            }
+            f(value, block[word]);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use aivm::DefaultFrequencies;
+
+    #[test]
+    fn procedural() {
+        let bits = ProceduralBits::new(1000, 7, 2000);
+        let mut all = vec![0; 1000];
+        bits.apply(0, &mut all, |value, word| *value = word);
+        assert!(all.iter().any(|&word| word != 0));
+
+        // Windows see the same bits wherever they start
+        let mut window = vec![0; 100];
+        bits.apply(130, &mut window, |value, word| *value = word);
+        assert_eq!(window, all[130..230]);
+        let mut block = [0; ProceduralBits::BLOCK_LEN];
+        bits.fill_block(1, &mut block);
+        assert_eq!(block, all[64..128]);
+
+        let ones = all.iter().map(|word| word.count_ones()).sum::<u32>();
+        let expected = 1000.0 * 64.0 * 2000.0 / 65536.0;
+        assert!((f64::from(ones) - expected).abs() < expected * 0.1);
+
+        // Genomes expand the same as with a pool of the generated bits
+        let genome = Genome::new(2).with_mutation(9).with_mutation(400);
+        let (mut a, mut b) = (vec![0; 300], vec![0; 300]);
+        genome.expand_code::<DefaultFrequencies, _>(&bits, &mut a);
+        genome.expand_code::<DefaultFrequencies, _>(&all, &mut b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn scaled_mutations() {
+        assert_eq!(mutation_seed(1234, 1.0), 1234);
+        assert_eq!(
+            mutation_seed(u32::MAX, 2.0),
+            (1 << MUTATION_OFFSET_BITS) - 1
+        );
+        let seed = mutation_seed(1234, 0.25);
+        assert_eq!(seed & ((1 << MUTATION_OFFSET_BITS) - 1), 1234);
+        assert_eq!(mutation_scale(seed), 0.25);
+        assert!((mutation_scale(mutation_seed(0, 0.3)) / 0.3 - 1.0).abs() < 0.022);
+        assert_eq!(
+            mutation_scale(mutation_seed(0, 0.0)),
+            (-255.0f64 / 16.0).exp2()
+        );
+
+        let mut bits = vec![0; 2048];
+        fill_mutate_bits(&mut bits, 3, 8000);
+        // The bits the mutation of `seed` flips in the code and in the memory
+        let flipped = |seed| {
+            let (genome, original) = (Genome::new(1).with_mutation(seed), Genome::new(1));
+            let (mut code, mut original_code) = (vec![0; 1024], vec![0; 1024]);
+            genome.expand_code::<DefaultFrequencies, _>(&bits, &mut code);
+            original.expand_code::<DefaultFrequencies, _>(&bits, &mut original_code);
+            let (mut memory, mut original_memory) = (vec![0; 1024], vec![0; 1024]);
+            genome.expand_memory(&bits, &mut memory);
+            original.expand_memory(&bits, &mut original_memory);
+
+            let code = code.iter().zip(original_code).map(|(a, b)| a ^ b);
+            let memory = memory
+                .iter()
+                .zip(original_memory)
+                .map(|(a, b)| (a ^ b) as u64);
+            (code.collect::<Vec<_>>(), memory.collect::<Vec<_>>())
+        };
+
+        // A scaled mutation flips a share of the bits of the full one, in both
+        let (full_code, full_memory) = flipped(mutation_seed(77, 1.0));
+        let (scaled_code, scaled_memory) = flipped(mutation_seed(77, 0.25));
+        let ones = |words: &[u64]| f64::from(words.iter().map(|w| w.count_ones()).sum::<u32>());
+        for (full, scaled) in [(full_code, scaled_code), (full_memory, scaled_memory)] {
+            assert!(full.iter().zip(&scaled).all(|(f, s)| s & !f == 0));
+            let share = ones(&scaled) / ones(&full);
+            assert!((0.2..0.3).contains(&share), "{share}");
+        }
+    }
 
     #[test]
     fn mutation_determinism() {
@@ -73,4 +480,40 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    fn region_rates() {
+        let mut bits = [0; 64];
+        let rates = RegionRates {
+            opcode: 0,
+            operands: u16::MAX,
+            immediate: 0,
+        };
+        fill_mutate_bits_with_rates(&mut bits, 1, rates);
+        assert!(bits.iter().all(|&b| b & !0xFFFF_0000 == 0));
+        assert!(bits.iter().any(|&b| b != 0));
+
+        let mut uniform = [0; 64];
+        fill_mutate_bits(&mut uniform, 1, 1024);
+        fill_mutate_bits_with_rates(&mut bits, 1, RegionRates::uniform(1024));
+        assert_eq!(bits, uniform);
+    }
+
+    #[test]
+    fn field_rates() {
+        let mut bits = [0; 256];
+        let rates = FieldRates {
+            kind: 0,
+            operands: u16::MAX,
+            immediate: 8192,
+        };
+        fill_mutate_fields(&mut bits, 1, rates);
+        // Both operands change in every word, the kind and the unused bits never
+        assert!(bits
+            .iter()
+            .all(|&b| b & 0x3F_0000 != 0 && b & 0xFC0_0000 != 0));
+        assert!(bits.iter().all(|&b| b & 0xF000_FFFF == 0));
+        let immediates = bits.iter().filter(|&&b| b >> 32 != 0).count();
+        assert!((8..64).contains(&immediates));
+    }
 }
@@ -0,0 +1,219 @@
+use super::Genome;
+
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// How an [OpponentPool] picks the opponents genomes are evaluated against.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Sampling {
+    /// Every opponent in the pool with equal probability, so agents keep beating the
+    /// opponents of the whole history.
+    Uniform,
+    /// Opponents with a higher win rate are picked more often, with a weight of their win rate
+    /// to the power of the exponent. Opponents that have not played yet count as winning every
+    /// game, so every opponent gets played.
+    Prioritized {
+        /// How strongly strong opponents are preferred, 0.0 for uniform sampling.
+        exponent: f64,
+    },
+    /// Only the `k` opponents that were added last, with equal probability, which suits
+    /// environments where only recent strategies matter.
+    Latest {
+        /// The amount of recent opponents to pick from.
+        k: usize,
+    },
+}
+
+/// An agent in an [OpponentPool], with the results of the games it played.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Opponent {
+    /// The genome of the agent.
+    pub genome: Genome,
+    /// The total score of the opponent over all games it played, 1.0 for every win and 0.5 for
+    /// every draw.
+    pub score: f64,
+    /// The amount of games the opponent played.
+    pub games: u64,
+}
+
+impl Opponent {
+    /// The mean score of the opponent per game, or `None` before it played.
+    pub fn win_rate(&self) -> Option<f64> {
+        (self.games > 0).then(|| self.score / self.games as f64)
+    }
+}
+
+/// The opponents of a competitive environment, kept apart from the population that is
+/// evolved against them.
+///
+/// Genomes are [added](Self::add) to the pool, for example the champion of every few
+/// generations, and the oldest opponent is dropped when the pool is full. Opponents are
+/// [sampled](Self::sample) as configured by a [Sampling], and the outcomes of their games are
+/// [recorded](Self::record) to prioritize them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpponentPool {
+    opponents: Vec<Opponent>,
+    capacity: usize,
+    sampling: Sampling,
+}
+
+impl OpponentPool {
+    /// Create an empty pool that keeps the `capacity` opponents that were added last, and
+    /// samples them uniformly.
+    ///
+    /// # Panics
+    /// If `capacity` is 0.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "empty opponent pool");
+        Self {
+            opponents: vec![],
+            capacity,
+            sampling: Sampling::Uniform,
+        }
+    }
+
+    /// The same pool, but sampling opponents with `sampling`.
+    ///
+    /// # Panics
+    /// If `sampling` picks from the latest 0 opponents.
+    pub fn with_sampling(self, sampling: Sampling) -> Self {
+        assert!(
+            !matches!(sampling, Sampling::Latest { k: 0 }),
+            "no opponents to pick from"
+        );
+        Self { sampling, ..self }
+    }
+
+    /// The opponents in the pool, from oldest to newest.
+    pub fn opponents(&self) -> &[Opponent] {
+        &self.opponents
+    }
+
+    /// The amount of opponents in the pool.
+    pub fn len(&self) -> usize {
+        self.opponents.len()
+    }
+
+    /// Whether no opponent was added yet.
+    pub fn is_empty(&self) -> bool {
+        self.opponents.is_empty()
+    }
+
+    /// Add `genome` as the newest opponent, dropping the oldest one if the pool is full. A
+    /// genome that is already in the pool is not added again.
+    ///
+    /// The indices of the opponents change when one is dropped.
+    pub fn add(&mut self, genome: &Genome) {
+        if self
+            .opponents
+            .iter()
+            .any(|opponent| opponent.genome == *genome)
+        {
+            return;
+        }
+        if self.opponents.len() == self.capacity {
+            self.opponents.remove(0);
+        }
+        self.opponents.push(Opponent {
+            genome: genome.clone(),
+            score: 0.0,
+            games: 0,
+        });
+    }
+
+    /// Pick `count` opponents, as indices into [opponents](Self::opponents). An opponent can
+    /// be picked more than once.
+    ///
+    /// # Panics
+    /// If the pool is empty.
+    pub fn sample(&self, count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        assert!(!self.is_empty(), "no opponents to pick from");
+        let len = self.opponents.len();
+        match self.sampling {
+            Sampling::Uniform => (0..count).map(|_| rng.gen_range(0..len)).collect(),
+            Sampling::Latest { k } => {
+                let start = len.saturating_sub(k);
+                (0..count).map(|_| rng.gen_range(start..len)).collect()
+            }
+            Sampling::Prioritized { exponent } => {
+                let weights: Vec<_> = self
+                    .opponents
+                    .iter()
+                    .map(|opponent| opponent.win_rate().unwrap_or(1.0).powf(exponent))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                // Without any strong opponent, every one is as good as the others
+                if total <= 0.0 || !total.is_finite() {
+                    return (0..count).map(|_| rng.gen_range(0..len)).collect();
+                }
+
+                (0..count)
+                    .map(|_| {
+                        let mut target = rng.gen_range(0.0..total);
+                        weights
+                            .iter()
+                            .position(|&weight| {
+                                target -= weight;
+                                target < 0.0
+                            })
+                            .unwrap_or(len - 1)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Record the outcome of a game of the opponent at `index`, with the `score` of the
+    /// opponent: 1.0 if it won, 0.5 for a draw and 0.0 if it lost.
+    ///
+    /// # Panics
+    /// If there is no opponent at `index`, or `score` is not in `0.0..=1.0`.
+    pub fn record(&mut self, index: usize, score: f64) {
+        assert!((0.0..=1.0).contains(&score), "invalid score {score}");
+        let opponent = &mut self.opponents[index];
+        opponent.score += score;
+        opponent.games += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let mut pool = OpponentPool::new(4);
+        for seed in 0..6 {
+            pool.add(&Genome::new(seed));
+        }
+        pool.add(&Genome::new(5));
+        // The oldest were dropped, and genomes are only added once
+        let seeds: Vec<_> = pool
+            .opponents()
+            .iter()
+            .map(|o| o.genome.root_seed)
+            .collect();
+        assert_eq!(seeds, [2, 3, 4, 5]);
+
+        let picked = pool.sample(200, &mut rng);
+        assert!((0..4).all(|i| picked.contains(&i)));
+
+        let latest = pool.clone().with_sampling(Sampling::Latest { k: 2 });
+        assert!(latest.sample(200, &mut rng).iter().all(|&i| i >= 2));
+
+        let mut prioritized = pool.with_sampling(Sampling::Prioritized { exponent: 2.0 });
+        for _ in 0..10 {
+            prioritized.record(0, 1.0);
+            prioritized.record(1, 0.25);
+            prioritized.record(2, 0.0);
+            prioritized.record(3, 0.0);
+        }
+        assert_eq!(prioritized.opponents()[1].win_rate(), Some(0.25));
+        let picked = prioritized.sample(200, &mut rng);
+        let count = |i| picked.iter().filter(|&&p| p == i).count();
+        assert!(count(0) > 150 && count(1) > 0);
+        assert_eq!(count(2) + count(3), 0);
+    }
+}
@@ -0,0 +1,68 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+/// Error returned when loading a serialized archive fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The bytes were not produced by serializing the same kind of archive, or are truncated.
+    Malformed,
+    /// The archive was saved in a newer format than this version of the crate supports.
+    UnsupportedVersion {
+        /// The format version of the saved archive.
+        found: u16,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed archive"),
+            Self::UnsupportedVersion { found } => write!(
+                f,
+                "archive saved in format version {found}, which this version does not support"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// The bincode settings every archive and message of this crate is serialized with, which
+/// produce the same bytes as `bincode::serialize`.
+pub(crate) fn options() -> impl Options {
+    bincode::DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Serialize `value` after a header of the magic bytes and format version.
+pub(crate) fn serialize<T: Serialize>(
+    magic: &[u8; 8],
+    version: u16,
+    value: &T,
+) -> bincode::Result<Vec<u8>> {
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&version.to_le_bytes());
+    options().serialize_into(&mut bytes, value)?;
+    Ok(bytes)
+}
+
+/// Load a value that was serialized with [serialize], in a format version of at most
+/// `version`.
+pub(crate) fn deserialize<T: DeserializeOwned>(
+    magic: &[u8; 8],
+    version: u16,
+    bytes: &[u8],
+) -> Result<T, LoadError> {
+    let (found, payload) = bytes
+        .strip_prefix(magic.as_slice())
+        .and_then(|rest| rest.split_first_chunk::<2>())
+        .ok_or(LoadError::Malformed)?;
+    let found = u16::from_le_bytes(*found);
+    if found > version {
+        return Err(LoadError::UnsupportedVersion { found });
+    }
+
+    options()
+        .deserialize(payload)
+        .map_err(|_| LoadError::Malformed)
+}
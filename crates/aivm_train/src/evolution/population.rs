@@ -0,0 +1,551 @@
+use super::{
+    Champion, Genome, HallOfFame, Lexicase, MutateBits, Mutation, Selection, Shaping, Speciation,
+};
+use crate::{
+    checkpoint::Checkpointer,
+    fitness::{Evaluator, Fitness},
+    observe::Observer,
+    seed::SplitRng,
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::{convert::Infallible, io};
+
+/// A generation of genomes, evolved one generation at a time with
+/// [step_generation](Self::step_generation).
+#[derive(Serialize, Deserialize)]
+pub struct Population {
+    genomes: Vec<Genome>,
+    ranked: Vec<Champion>,
+    size: usize,
+    elites: usize,
+    hall_of_fame: Option<HallOfFame>,
+    #[serde(skip)]
+    shaping: Option<Box<dyn Shaping + Send>>,
+    #[serde(skip)]
+    speciation: Option<Speciation>,
+    #[serde(skip)]
+    observer: Option<Box<dyn Observer + Send>>,
+    #[serde(skip)]
+    observed_best: Option<f64>,
+    generation: u64,
+    rng: SplitRng,
+}
+
+/// A summary of an evaluated generation, returned by
+/// [step_generation](Population::step_generation).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationStats {
+    /// The index of the generation, starting at 0.
+    pub generation: u64,
+    /// The genome with the highest fitness.
+    pub best: Genome,
+    /// The fitness of [best](Self::best).
+    pub best_fitness: f64,
+    /// The mean fitness of all genomes.
+    pub mean_fitness: f64,
+    /// The median fitness of all genomes, which unlike the mean is not dragged down by a few
+    /// genomes that fail completely.
+    pub median_fitness: f64,
+    /// The standard deviation of the fitness of all genomes.
+    pub std_fitness: f64,
+}
+
+impl Population {
+    /// Create a population of `size` unmutated genomes with random root seeds. All randomness
+    /// of the evolution is derived from `seed`.
+    ///
+    /// # Panics
+    /// If `size` is 0.
+    pub fn new(size: usize, seed: u64) -> Self {
+        let mut rng = SplitRng::new(seed);
+        let genomes = (0..size).map(|_| Genome::new(rng.gen())).collect();
+        Self::with_genomes(genomes, rng)
+    }
+
+    /// Create a population from existing genomes, for example to continue from the genomes of
+    /// an earlier run. Later generations have as many genomes.
+    ///
+    /// # Panics
+    /// If `genomes` is empty.
+    pub fn with_genomes(genomes: Vec<Genome>, rng: SplitRng) -> Self {
+        assert!(!genomes.is_empty(), "empty population");
+        Self {
+            size: genomes.len(),
+            genomes,
+            ranked: vec![],
+            elites: 0,
+            hall_of_fame: None,
+            shaping: None,
+            speciation: None,
+            observer: None,
+            observed_best: None,
+            generation: 0,
+            rng,
+        }
+    }
+
+    /// The genomes of the current generation, which have not been evaluated yet.
+    pub fn genomes(&self) -> &[Genome] {
+        &self.genomes
+    }
+
+    /// The genomes of the last evaluated generation with their fitness, ordered from high to
+    /// low fitness. Empty before the first generation was evaluated.
+    pub fn ranked(&self) -> &[Champion] {
+        &self.ranked
+    }
+
+    /// Replace the last genomes of the current generation by `immigrants`, for example the
+    /// fittest genomes of another population. The first genomes are the elites, so they are
+    /// only replaced if there are more immigrants than bred genomes.
+    pub fn immigrate(&mut self, immigrants: &[Genome]) {
+        let start = self.genomes.len().saturating_sub(immigrants.len());
+        let immigrants = &immigrants[..self.genomes.len() - start];
+        self.genomes[start..].clone_from_slice(immigrants);
+    }
+
+    /// The amount of genomes in the next generations.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Change the amount of genomes bred for the next generations.
+    ///
+    /// # Panics
+    /// If `size` is 0.
+    pub fn set_size(&mut self, size: usize) {
+        assert!(size > 0, "empty population");
+        self.size = size;
+    }
+
+    /// The amount of fittest genomes copied unchanged into the next generation.
+    pub fn elites(&self) -> usize {
+        self.elites
+    }
+
+    /// Copy the `elites` fittest genomes of every generation unchanged into the next one, the
+    /// rest of the next generation is bred as usual. They are evaluated again, so a lucky
+    /// evaluation of a noisy fitness does not keep a genome around forever.
+    pub fn set_elites(&mut self, elites: usize) {
+        self.elites = elites;
+    }
+
+    /// The archive of the fittest genomes of all generations, if one was set.
+    pub fn hall_of_fame(&self) -> Option<&HallOfFame> {
+        self.hall_of_fame.as_ref()
+    }
+
+    /// Offer every evaluated genome to `hall_of_fame` from now on.
+    pub fn set_hall_of_fame(&mut self, hall_of_fame: HallOfFame) {
+        self.hall_of_fame = Some(hall_of_fame);
+    }
+
+    /// Remove the archive of the fittest genomes, if any.
+    pub fn take_hall_of_fame(&mut self) -> Option<HallOfFame> {
+        self.hall_of_fame.take()
+    }
+
+    /// Select parents by the fitness shaped with `shaping` instead of the raw fitness. The
+    /// statistics, ranking and hall of fame still use the raw fitness. Shaping is applied
+    /// before speciation, and like it is not part of a saved population.
+    pub fn set_shaping(&mut self, shaping: impl Shaping + Send + 'static) {
+        self.shaping = Some(Box::new(shaping));
+    }
+
+    /// Stop shaping the fitness, and return the shaping.
+    pub fn take_shaping(&mut self) -> Option<Box<dyn Shaping + Send>> {
+        self.shaping.take()
+    }
+
+    /// The species of the last evaluated generation, if speciation was set.
+    pub fn speciation(&self) -> Option<&Speciation> {
+        self.speciation.as_ref()
+    }
+
+    /// Divide every generation into species with `speciation`, and select parents by the
+    /// fitness shared within species instead of the raw fitness.
+    pub fn set_speciation(&mut self, speciation: Speciation) {
+        self.speciation = Some(speciation);
+    }
+
+    /// Notify `observer` of the progress of the following generations. Like the speciation,
+    /// it is not part of a saved population.
+    pub fn set_observer(&mut self, observer: impl Observer + Send + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Stop notifying the observer, and return it.
+    pub fn take_observer(&mut self) -> Option<Box<dyn Observer + Send>> {
+        self.observer.take()
+    }
+
+    /// Save a checkpoint of the population and `hyperparameters` with `checkpointer` if it is
+    /// due for the current generation, and notify the observer if it was saved.
+    pub fn save_checkpoint_if_due<H: Serialize>(
+        &mut self,
+        checkpointer: &Checkpointer,
+        hyperparameters: &H,
+    ) -> io::Result<bool> {
+        let saved = checkpointer.save_if_due(self.generation, &*self, hyperparameters)?;
+        self.checkpoint_saved(checkpointer, saved);
+        Ok(saved)
+    }
+
+    /// Like [save_checkpoint_if_due](Self::save_checkpoint_if_due), but with other state of
+    /// the run next to the population, such as its [Schedule](super::Schedule). The state of
+    /// the checkpoint is a `(Population, T)`.
+    pub fn save_checkpoint_with_if_due<T: Serialize, H: Serialize>(
+        &mut self,
+        checkpointer: &Checkpointer,
+        other: &T,
+        hyperparameters: &H,
+    ) -> io::Result<bool> {
+        let state = (&*self, other);
+        let saved = checkpointer.save_if_due(self.generation, &state, hyperparameters)?;
+        self.checkpoint_saved(checkpointer, saved);
+        Ok(saved)
+    }
+
+    fn checkpoint_saved(&mut self, checkpointer: &Checkpointer, saved: bool) {
+        if let (true, Some(observer)) = (saved, &mut self.observer) {
+            observer.checkpoint(checkpointer.path(), self.generation);
+        }
+    }
+
+    /// The index of the current generation.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Evaluate the current generation with `evaluator`, and replace it by the
+    /// [elites](Self::set_elites) and children of the parents picked by `selection`, mutated by
+    /// `mutation`.
+    ///
+    /// Every genome is evaluated with its own random generator, seeded from the one of the
+    /// population, so the fitness of a genome does not depend on the ones evaluated before it.
+    pub fn step_generation<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        selection: &mut impl Selection,
+        mutation: &mut impl Mutation,
+    ) -> GenerationStats
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let evaluate = |genomes: &[Genome], seeds: &[u64]| {
+            let fitness = genomes
+                .iter()
+                .zip(seeds)
+                .map(|(genome, &seed)| evaluator.evaluate(genome, &mut Pcg64::seed_from_u64(seed)))
+                .collect();
+            Ok::<_, Infallible>(fitness)
+        };
+        match self.step_generation_with(evaluate, selection, mutation) {
+            Ok(stats) => stats,
+        }
+    }
+
+    /// Like [step_generation](Self::step_generation), but evaluating the generation with
+    /// `evaluate`, for example on other machines.
+    ///
+    /// `evaluate` is given the genomes and the seeds of their random generators, and returns
+    /// their fitness in the same order. If it fails, the population is left unchanged.
+    ///
+    /// # Panics
+    /// If `evaluate` does not return a fitness for every genome.
+    pub fn step_generation_with<E>(
+        &mut self,
+        evaluate: impl FnOnce(&[Genome], &[u64]) -> Result<Vec<f64>, E>,
+        selection: &mut impl Selection,
+        mutation: &mut impl Mutation,
+    ) -> Result<GenerationStats, E> {
+        span!("generation", generation = self.generation);
+        let fitness = self.evaluate_generation(evaluate)?;
+        Ok(self.breed(fitness, mutation, |population, fitness, count| {
+            let shaped;
+            let fitness = match &mut population.shaping {
+                Some(shaping) => {
+                    shaped = shaping.shape(&population.genomes, fitness);
+                    &shaped
+                }
+                None => fitness,
+            };
+            match &mut population.speciation {
+                Some(speciation) => {
+                    speciation.speciate(&population.genomes);
+                    let shared = speciation.share(fitness);
+                    selection.select(&shared, count, population.rng.rng())
+                }
+                None => selection.select(fitness, count, population.rng.rng()),
+            }
+        }))
+    }
+
+    /// Like [step_generation_with](Self::step_generation_with), but with a score for every
+    /// test case of every genome, and picking parents by [Lexicase] selection over the cases.
+    ///
+    /// The fitness of a genome, as reported in the statistics and hall of fame, is the mean
+    /// of its scores. Shaping and speciation are not applied.
+    ///
+    /// # Panics
+    /// If `evaluate` does not return scores for every genome, or a different amount of scores
+    /// for some genomes.
+    pub fn step_generation_cases<E>(
+        &mut self,
+        evaluate: impl FnOnce(&[Genome], &[u64]) -> Result<Vec<Vec<f64>>, E>,
+        lexicase: &mut Lexicase,
+        mutation: &mut impl Mutation,
+    ) -> Result<GenerationStats, E> {
+        span!("generation", generation = self.generation);
+        let cases = self.evaluate_generation(evaluate)?;
+        let fitness = cases
+            .iter()
+            .map(|cases| cases.iter().sum::<f64>() / cases.len() as f64)
+            .collect();
+        Ok(self.breed(fitness, mutation, |population, _, count| {
+            lexicase.select_cases(&cases, count, population.rng.rng())
+        }))
+    }
+
+    fn evaluate_generation<T, E>(
+        &mut self,
+        evaluate: impl FnOnce(&[Genome], &[u64]) -> Result<Vec<T>, E>,
+    ) -> Result<Vec<T>, E> {
+        if let Some(observer) = &mut self.observer {
+            observer.generation_start(self.generation);
+        }
+        let mut rng = self.rng.clone();
+        let seeds: Vec<_> = self.genomes.iter().map(|_| rng.split_seed()).collect();
+        let fitness = evaluate(&self.genomes, &seeds)?;
+        assert_eq!(fitness.len(), self.genomes.len(), "missing fitness");
+        self.rng = rng;
+        Ok(fitness)
+    }
+
+    /// Rank the evaluated generation and replace it by the elites and the children of the
+    /// parents picked by `select`, given the fitness and amount of parents to pick.
+    fn breed(
+        &mut self,
+        fitness: Vec<f64>,
+        mutation: &mut impl Mutation,
+        select: impl FnOnce(&mut Self, &[f64], usize) -> Vec<usize>,
+    ) -> GenerationStats {
+        if let Some(hall_of_fame) = &mut self.hall_of_fame {
+            for (genome, &fitness) in self.genomes.iter().zip(&fitness) {
+                hall_of_fame.insert(genome, fitness);
+            }
+        }
+
+        let mut ranking: Vec<_> = (0..fitness.len()).collect();
+        ranking.sort_by(|&a, &b| fitness[b].total_cmp(&fitness[a]));
+        self.ranked = ranking
+            .iter()
+            .map(|&i| Champion {
+                genome: self.genomes[i].clone(),
+                fitness: fitness[i],
+            })
+            .collect();
+        let stats = GenerationStats::new(self.generation, &self.ranked[0].genome, &fitness);
+
+        if let Some(observer) = &mut self.observer {
+            let best = &self.ranked[0];
+            if self
+                .observed_best
+                .is_none_or(|observed| best.fitness > observed)
+            {
+                self.observed_best = Some(best.fitness);
+                observer.new_champion(best);
+            }
+        }
+
+        span!("select");
+        let elites = self.elites.min(self.size).min(ranking.len());
+        let parents = select(self, &fitness, self.size - elites);
+        let mut next: Vec<_> = self.ranked[..elites]
+            .iter()
+            .map(|elite| elite.genome.clone())
+            .collect();
+        next.extend(
+            parents
+                .into_iter()
+                .map(|parent| mutation.mutate(&self.genomes[parent], self.rng.rng())),
+        );
+        self.genomes = next;
+        self.generation += 1;
+        if let Some(observer) = &mut self.observer {
+            observer.generation_end(&stats);
+        }
+
+        stats
+    }
+}
+
+impl GenerationStats {
+    /// The statistics of generation `generation`, given the `fitness` of all its genomes and
+    /// the genome with the highest fitness.
+    pub(crate) fn new(generation: u64, best: &Genome, fitness: &[f64]) -> Self {
+        let mut sorted = fitness.to_vec();
+        sorted.sort_by(|a, b| b.total_cmp(a));
+        let middle = sorted.len() / 2;
+        let median_fitness = if sorted.len() % 2 == 1 {
+            sorted[middle]
+        } else {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        };
+        let mean_fitness = fitness.iter().sum::<f64>() / fitness.len() as f64;
+        let variance = fitness
+            .iter()
+            .map(|&fitness| (fitness - mean_fitness).powi(2))
+            .sum::<f64>()
+            / fitness.len() as f64;
+
+        Self {
+            generation,
+            best: best.clone(),
+            best_fitness: sorted[0],
+            mean_fitness,
+            median_fitness,
+            std_fitness: variance.sqrt(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{
+        fill_mutate_bits, mutation_scale, AppendScaledSeed, AppendSeed, ExponentialDecay, Schedule,
+        Truncation,
+    };
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    type TestFitness = fn(&dyn Runner, &mut MemoryBuffer, &mut Pcg64) -> f64;
+
+    fn evaluator() -> Evaluator<Interpreter, TestFitness> {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        // Reward outputs close to 1000
+        let fitness: TestFitness = |runner, memory, _| {
+            runner.step(memory.as_mut_slice());
+            -(memory.output()[0] as f64 - 1000.0).abs()
+        };
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        Evaluator::new(compiler, layout, 64, mutate_bits, fitness)
+    }
+
+    #[test]
+    fn evolve() {
+        let mut evaluator = evaluator();
+
+        let mut population = Population::new(32, 1);
+        let mut truncation = Truncation::new(0.25);
+        for generation in 0..10 {
+            let stats =
+                population.step_generation(&mut evaluator, &mut truncation, &mut AppendSeed);
+            assert_eq!(stats.generation, generation);
+            // Every generation adds one mutation
+            assert_eq!(stats.best.mutation_seeds.len(), generation as usize);
+            assert!(stats.best_fitness >= stats.mean_fitness);
+            assert!(stats.best_fitness >= stats.median_fitness);
+        }
+        assert_eq!(population.generation(), 10);
+
+        population.set_size(8);
+        population.step_generation(&mut evaluator, &mut truncation, &mut AppendSeed);
+        assert_eq!(population.genomes().len(), 8);
+    }
+
+    #[test]
+    fn elitism() {
+        let mut evaluator = evaluator();
+        let mut population = Population::new(16, 2);
+        population.set_elites(2);
+        population.set_hall_of_fame(HallOfFame::new(4));
+        let mut truncation = Truncation::new(0.5);
+
+        let mut best = f64::NEG_INFINITY;
+        for _ in 0..10 {
+            let stats =
+                population.step_generation(&mut evaluator, &mut truncation, &mut AppendSeed);
+            // The fitness is deterministic, so the best genome survives
+            assert!(stats.best_fitness >= best);
+            best = stats.best_fitness;
+            assert_eq!(population.genomes()[0], stats.best);
+        }
+
+        let hall_of_fame = population.take_hall_of_fame().unwrap();
+        assert_eq!(hall_of_fame.champions().len(), 4);
+        assert_eq!(hall_of_fame.best().unwrap().fitness, best);
+    }
+
+    #[test]
+    fn rate_change() {
+        let mut evaluator = evaluator();
+        let mut population = Population::new(16, 4);
+        population.set_elites(1);
+        let mut truncation = Truncation::new(0.5);
+        let mut schedule = ExponentialDecay::new(4000, 0.5);
+        let mut rng = Pcg64::seed_from_u64(0);
+
+        let mut elite: Option<(Genome, f64)> = None;
+        for _ in 0..6 {
+            let mut mutation =
+                AppendScaledSeed::from_probabilities(schedule.p_mutate(), schedule.max_p_mutate());
+            let stats = population.step_generation(&mut evaluator, &mut truncation, &mut mutation);
+            // The elite keeps its fitness although new mutations flip fewer bits
+            if let Some((genome, fitness)) = &elite {
+                assert_eq!(evaluator.evaluate(genome, &mut rng), *fitness);
+                assert!(stats.best_fitness >= *fitness);
+            }
+            elite = Some((stats.best, stats.best_fitness));
+            schedule.advance(false);
+        }
+
+        assert!(population
+            .genomes()
+            .iter()
+            .flat_map(|genome| &genome.mutation_seeds)
+            .any(|&seed| mutation_scale(seed) < 1.0));
+    }
+
+    #[derive(Default)]
+    struct Events {
+        generations: Vec<u64>,
+        champions: Vec<f64>,
+    }
+
+    impl Observer for Events {
+        fn generation_start(&mut self, generation: u64) {
+            self.generations.push(generation);
+        }
+
+        fn new_champion(&mut self, champion: &Champion) {
+            self.champions.push(champion.fitness);
+        }
+    }
+
+    #[test]
+    fn observe() {
+        let mut evaluator = evaluator();
+        let mut population = Population::new(16, 3);
+        let events = std::sync::Arc::new(std::sync::Mutex::new(Events::default()));
+        population.set_observer(events.clone());
+        let mut truncation = Truncation::new(0.5);
+        for _ in 0..5 {
+            population.step_generation(&mut evaluator, &mut truncation, &mut AppendSeed);
+        }
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.generations, [0, 1, 2, 3, 4]);
+        assert!(!events.champions.is_empty());
+        assert!(events.champions.windows(2).all(|w| w[0] < w[1]));
+    }
+}
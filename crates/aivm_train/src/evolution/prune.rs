@@ -0,0 +1,115 @@
+use aivm::{CallGraph, InstructionFrequencies, KIND_COUNT};
+
+/// The code word that ends functions in pruned code, and pads the code after it.
+const END_FUNC_WORD: u64 = 0;
+
+/// Remove the functions of `code` that can not be reached from the entry point, when compiled
+/// with `lowest_function_level` and the instruction frequencies `F`, and pack the remaining
+/// functions at the start of the code. Returns the amount of code words still in use, the rest
+/// is padded with words that end a function.
+///
+/// Calls address functions by their index, so unreachable functions are reduced to a single
+/// instruction instead of removed completely, which keeps the index of every function. The
+/// pruned code compiles to a program that behaves exactly like the original one. To shrink a
+/// champion before deploying it, truncate its code to the returned length.
+pub fn prune<F: InstructionFrequencies>(code: &mut [u64], lowest_function_level: u32) -> usize {
+    prune_with_table(code, lowest_function_level, &F::table())
+}
+
+/// Like [prune], but with the frequencies in a table like
+/// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+pub fn prune_with_table(
+    code: &mut [u64],
+    lowest_function_level: u32,
+    frequencies: &[u16; KIND_COUNT],
+) -> usize {
+    let graph = CallGraph::with_table(code, lowest_function_level, frequencies);
+    let reachable = graph.reachable();
+
+    let mut pruned = Vec::with_capacity(code.len());
+    for (f, reachable) in reachable.into_iter().enumerate() {
+        let mut instructions = graph.instructions(f);
+        if !reachable {
+            instructions.end = instructions.end.min(instructions.start + 1);
+        }
+        if f > 0 {
+            pruned.push(END_FUNC_WORD);
+        }
+        pruned.extend_from_slice(&code[instructions]);
+    }
+
+    // Functions are only separated, so the pruned code is never longer than the original
+    let len = pruned.len();
+    code[..len].copy_from_slice(&pruned);
+    code[len..].fill(END_FUNC_WORD);
+    len
+}
+
+/// The amount of instructions in the functions of `code` that can be reached from the entry
+/// point, which is a measure of the size of a program that ignores dead code. See [prune].
+pub fn reachable_instructions<F: InstructionFrequencies>(
+    code: &[u64],
+    lowest_function_level: u32,
+) -> usize {
+    reachable_instructions_with_table(code, lowest_function_level, &F::table())
+}
+
+/// Like [reachable_instructions], but with the frequencies in a table like
+/// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+pub fn reachable_instructions_with_table(
+    code: &[u64],
+    lowest_function_level: u32,
+    frequencies: &[u16; KIND_COUNT],
+) -> usize {
+    let graph = CallGraph::with_table(code, lowest_function_level, frequencies);
+    graph
+        .reachable()
+        .into_iter()
+        .enumerate()
+        .filter(|&(_, reachable)| reachable)
+        .map(|(f, _)| graph.instructions(f).len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aivm::{codegen::Interpreter, Compiler, DefaultFrequencies, Runner};
+    use rand::prelude::*;
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn same_behavior() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let mut code = vec![0u64; 512];
+        rng.fill(&mut code[..]);
+        // Random code hardly ever ends a function, so add some more
+        for word in code.iter_mut().step_by(13) {
+            *word = END_FUNC_WORD;
+        }
+
+        let mut pruned = code.clone();
+        let len = prune::<DefaultFrequencies>(&mut pruned, 4);
+        assert!(len < code.len());
+        assert_eq!(
+            reachable_instructions::<DefaultFrequencies>(&pruned, 4),
+            reachable_instructions::<DefaultFrequencies>(&code, 4),
+        );
+        assert_eq!(
+            CallGraph::new(&pruned, 4).function_count(),
+            CallGraph::new(&code, 4).function_count(),
+        );
+
+        let mut compiler = Compiler::new(Interpreter::new());
+        let original = compiler.compile(&code, 4, 16, 4, 4);
+        let pruned = compiler.compile(&pruned[..len], 4, 16, 4, 4);
+        for _ in 0..8 {
+            let mut a = vec![0; 24];
+            rng.fill(&mut a[..]);
+            let mut b = a.clone();
+            original.step(&mut a);
+            pruned.step(&mut b);
+            assert_eq!(a, b);
+        }
+    }
+}
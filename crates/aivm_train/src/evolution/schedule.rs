@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+
+/// Decides the mutation probability of every generation, in units of 1/65536 per bit like the
+/// `p_mutate` of [fill_mutate_bits](super::fill_mutate_bits).
+///
+/// Genomes only store offsets into the mutate bits, so the bits stay the same for the whole run
+/// and are filled with [max_p_mutate](Self::max_p_mutate). Lower probabilities are reached by
+/// mutations that flip a share of the bits, see [AppendScaledSeed](super::AppendScaledSeed), so
+/// every genome keeps expanding to the same code when the probability changes.
+pub trait Schedule {
+    /// The mutation probability for the current generation.
+    fn p_mutate(&self) -> u16;
+
+    /// The highest probability of any generation, which the mutate bits are filled with.
+    fn max_p_mutate(&self) -> u16;
+
+    /// Move on to the next generation, `improved` tells whether the current generation
+    /// improved on the best fitness so far.
+    fn advance(&mut self, improved: bool);
+}
+
+impl<S: Schedule + ?Sized> Schedule for Box<S> {
+    fn p_mutate(&self) -> u16 {
+        (**self).p_mutate()
+    }
+
+    fn max_p_mutate(&self) -> u16 {
+        (**self).max_p_mutate()
+    }
+
+    fn advance(&mut self, improved: bool) {
+        (**self).advance(improved);
+    }
+}
+
+/// The same probability for every generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Constant(pub u16);
+
+impl Schedule for Constant {
+    fn p_mutate(&self) -> u16 {
+        self.0
+    }
+
+    fn max_p_mutate(&self) -> u16 {
+        self.0
+    }
+
+    fn advance(&mut self, _improved: bool) {}
+}
+
+/// A probability that is multiplied by a factor every generation, so the search moves from
+/// exploration to fine tuning.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ExponentialDecay {
+    initial: f64,
+    p_mutate: f64,
+    factor: f64,
+    min: f64,
+}
+
+impl ExponentialDecay {
+    /// Start at `initial` and multiply by `factor` every generation, down to a probability of 1.
+    ///
+    /// # Panics
+    /// If `factor` is not in `0.0..=1.0`.
+    pub fn new(initial: u16, factor: f64) -> Self {
+        assert!((0.0..=1.0).contains(&factor), "invalid decay factor");
+        Self {
+            initial: initial.into(),
+            p_mutate: initial.into(),
+            factor,
+            min: 1.0,
+        }
+    }
+
+    /// The same schedule, but never decaying below `min`.
+    pub fn with_min(self, min: u16) -> Self {
+        Self {
+            min: min.into(),
+            ..self
+        }
+    }
+}
+
+impl Schedule for ExponentialDecay {
+    fn p_mutate(&self) -> u16 {
+        self.p_mutate.max(self.min) as u16
+    }
+
+    fn max_p_mutate(&self) -> u16 {
+        self.initial.max(self.min) as u16
+    }
+
+    fn advance(&mut self, _improved: bool) {
+        self.p_mutate = (self.p_mutate * self.factor).max(self.min);
+    }
+}
+
+/// Rechenberg's 1/5 success rule: when more than a fifth of the generations in a window
+/// improved, the probability is raised to take bigger steps, when fewer did it is lowered to
+/// search closer to the current best.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OneFifthRule {
+    p_mutate: f64,
+    max: f64,
+    factor: f64,
+    window: u32,
+    generations: u32,
+    improvements: u32,
+}
+
+impl OneFifthRule {
+    /// Start at `initial` and adapt every 10 generations by multiplying or dividing by
+    /// `factor`.
+    ///
+    /// # Panics
+    /// If `factor` is smaller than 1.
+    pub fn new(initial: u16, factor: f64) -> Self {
+        assert!(factor >= 1.0, "invalid adaptation factor");
+        Self {
+            p_mutate: initial.into(),
+            max: u16::MAX.into(),
+            factor,
+            window: 10,
+            generations: 0,
+            improvements: 0,
+        }
+    }
+
+    /// The same schedule, but adapting every `window` generations.
+    ///
+    /// # Panics
+    /// If `window` is 0.
+    pub fn with_window(self, window: u32) -> Self {
+        assert!(window > 0, "empty window");
+        Self { window, ..self }
+    }
+
+    /// The same schedule, but never raising the probability above `max`. The mutate bits are
+    /// filled with this probability, so it should not be much higher than needed.
+    pub fn with_max(self, max: u16) -> Self {
+        let max = f64::from(max.max(1));
+        Self {
+            p_mutate: self.p_mutate.min(max),
+            max,
+            ..self
+        }
+    }
+}
+
+impl Schedule for OneFifthRule {
+    fn p_mutate(&self) -> u16 {
+        self.p_mutate as u16
+    }
+
+    fn max_p_mutate(&self) -> u16 {
+        self.max as u16
+    }
+
+    fn advance(&mut self, improved: bool) {
+        self.generations += 1;
+        self.improvements += u32::from(improved);
+        if self.generations < self.window {
+            return;
+        }
+
+        let rate = f64::from(self.improvements) / f64::from(self.generations);
+        if rate > 0.2 {
+            self.p_mutate *= self.factor;
+        } else if rate < 0.2 {
+            self.p_mutate /= self.factor;
+        }
+        self.p_mutate = self.p_mutate.clamp(1.0, self.max);
+        self.generations = 0;
+        self.improvements = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decay() {
+        let mut schedule = ExponentialDecay::new(1000, 0.5).with_min(200);
+        let rates: Vec<_> = (0..4)
+            .map(|_| {
+                let p_mutate = schedule.p_mutate();
+                schedule.advance(false);
+                p_mutate
+            })
+            .collect();
+        assert_eq!(rates, [1000, 500, 250, 200]);
+    }
+
+    #[test]
+    fn one_fifth() {
+        let mut schedule = OneFifthRule::new(1000, 2.0).with_window(5);
+        for improved in [true, true, false, false, false] {
+            assert_eq!(schedule.p_mutate(), 1000);
+            schedule.advance(improved);
+        }
+        assert_eq!(schedule.p_mutate(), 2000);
+
+        for _ in 0..5 {
+            schedule.advance(false);
+        }
+        assert_eq!(schedule.p_mutate(), 1000);
+
+        schedule.advance(true);
+        for _ in 0..4 {
+            schedule.advance(false);
+        }
+        assert_eq!(schedule.p_mutate(), 1000);
+    }
+}
@@ -0,0 +1,293 @@
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+
+/// Chooses which genomes of a generation become parents of the next one.
+pub trait Selection {
+    /// Pick `count` parents, as indices into `fitness`, the fitness of every genome of the
+    /// generation. Higher fitness is better, and a genome can be picked more than once.
+    fn select(&mut self, fitness: &[f64], count: usize, rng: &mut Pcg64) -> Vec<usize>;
+}
+
+impl<F> Selection for F
+where
+    F: FnMut(&[f64], usize, &mut Pcg64) -> Vec<usize>,
+{
+    fn select(&mut self, fitness: &[f64], count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        self(fitness, count, rng)
+    }
+}
+
+/// Picks parents uniformly from the fittest share of the generation.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Truncation {
+    fraction: f64,
+}
+
+impl Truncation {
+    /// Select from the `fraction` of the generation with the highest fitness, at least one
+    /// genome is always eligible.
+    ///
+    /// # Panics
+    /// If `fraction` is not in `0.0..=1.0`.
+    pub fn new(fraction: f64) -> Self {
+        assert!((0.0..=1.0).contains(&fraction), "invalid fraction");
+        Self { fraction }
+    }
+}
+
+impl Selection for Truncation {
+    fn select(&mut self, fitness: &[f64], count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        let mut ranking: Vec<_> = (0..fitness.len()).collect();
+        ranking.sort_by(|&a, &b| fitness[b].total_cmp(&fitness[a]));
+        let eligible = ((fitness.len() as f64 * self.fraction) as usize).clamp(1, ranking.len());
+        ranking.truncate(eligible);
+
+        (0..count).map(|_| *ranking.choose(rng).unwrap()).collect()
+    }
+}
+
+/// Picks every parent as the fittest of a few genomes drawn at random.
+///
+/// Larger tournaments favor the fittest genomes more strongly. Only the ranking of the fitness
+/// matters, not its scale.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Tournament {
+    size: usize,
+    replacement: bool,
+}
+
+impl Tournament {
+    /// Hold tournaments between `size` genomes, which are drawn with replacement, so a genome
+    /// can compete against itself.
+    ///
+    /// # Panics
+    /// If `size` is 0.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "empty tournament");
+        Self {
+            size,
+            replacement: true,
+        }
+    }
+
+    /// The same selection, but drawing the genomes of a tournament with or without
+    /// replacement. Without replacement, tournaments larger than the generation consist of the
+    /// whole generation.
+    pub fn with_replacement(self, replacement: bool) -> Self {
+        Self {
+            replacement,
+            ..self
+        }
+    }
+}
+
+impl Selection for Tournament {
+    fn select(&mut self, fitness: &[f64], count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        assert!(!fitness.is_empty(), "no genomes to select from");
+
+        let fitter = |a: usize, b: usize| {
+            if fitness[b] > fitness[a] {
+                b
+            } else {
+                a
+            }
+        };
+        let mut indices: Vec<_> = (0..fitness.len()).collect();
+        (0..count)
+            .map(|_| {
+                if self.replacement {
+                    (1..self.size).fold(rng.gen_range(0..fitness.len()), |winner, _| {
+                        fitter(winner, rng.gen_range(0..fitness.len()))
+                    })
+                } else {
+                    // Partial Fisher-Yates shuffle, the first `size` indices are the contestants
+                    let size = self.size.min(indices.len());
+                    for i in 0..size {
+                        let j = rng.gen_range(i..indices.len());
+                        indices.swap(i, j);
+                    }
+                    indices[1..size]
+                        .iter()
+                        .fold(indices[0], |winner, &i| fitter(winner, i))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Picks every parent by filtering the generation on one test case after another, in a random
+/// order, keeping only the genomes with the best score on each case.
+///
+/// Programs often solve some cases and fail others, which an aggregate fitness hides. Lexicase
+/// selection rewards genomes that are the best at any combination of cases, which keeps
+/// specialists around. With an epsilon, scores within the epsilon of the best also pass a case,
+/// which suits cases with continuous scores.
+///
+/// It needs the score of every case instead of one fitness per genome, see
+/// [Population::step_generation_cases](super::Population::step_generation_cases).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Lexicase {
+    epsilon: Epsilon,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Epsilon {
+    Fixed(f64),
+    MedianAbsoluteDeviation,
+}
+
+impl Default for Lexicase {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lexicase {
+    /// Create selection that only passes the genomes with exactly the best score on a case.
+    pub fn new() -> Self {
+        Self {
+            epsilon: Epsilon::Fixed(0.0),
+        }
+    }
+
+    /// The same selection, but passing every genome within `epsilon` of the best score.
+    pub fn with_epsilon(self, epsilon: f64) -> Self {
+        Self {
+            epsilon: Epsilon::Fixed(epsilon),
+        }
+    }
+
+    /// The same selection, but with an epsilon for every case that is the median absolute
+    /// deviation of the scores of the generation on it, which adapts to the scale of the case.
+    pub fn with_automatic_epsilon(self) -> Self {
+        Self {
+            epsilon: Epsilon::MedianAbsoluteDeviation,
+        }
+    }
+
+    /// Pick `count` parents, as indices into `cases`, which holds the scores of every genome
+    /// on every case. Higher scores are better, and NaN scores never pass a case.
+    ///
+    /// # Panics
+    /// If `cases` is empty, or genomes have different amounts of scores.
+    pub fn select_cases(&self, cases: &[Vec<f64>], count: usize, rng: &mut Pcg64) -> Vec<usize> {
+        assert!(!cases.is_empty(), "no genomes to select from");
+        let case_count = cases[0].len();
+        assert!(
+            cases.iter().all(|scores| scores.len() == case_count),
+            "genomes have different amounts of cases",
+        );
+
+        let epsilons: Vec<_> = (0..case_count)
+            .map(|case| match self.epsilon {
+                Epsilon::Fixed(epsilon) => epsilon,
+                Epsilon::MedianAbsoluteDeviation => {
+                    median_absolute_deviation(cases.iter().map(|scores| scores[case]))
+                }
+            })
+            .collect();
+
+        let mut order: Vec<_> = (0..case_count).collect();
+        let mut candidates = Vec::with_capacity(cases.len());
+        (0..count)
+            .map(|_| {
+                order.shuffle(rng);
+                candidates.clear();
+                candidates.extend(0..cases.len());
+                for &case in &order {
+                    let best = candidates
+                        .iter()
+                        .map(|&i| cases[i][case])
+                        .filter(|score| !score.is_nan())
+                        .fold(f64::NEG_INFINITY, f64::max);
+                    let passing = candidates
+                        .iter()
+                        .filter(|&&i| cases[i][case] >= best - epsilons[case])
+                        .count();
+                    // When every candidate has a NaN score nobody passes, skip the case then
+                    if passing > 0 {
+                        candidates.retain(|&i| cases[i][case] >= best - epsilons[case]);
+                    }
+                    if candidates.len() == 1 {
+                        break;
+                    }
+                }
+                *candidates.choose(rng).unwrap()
+            })
+            .collect()
+    }
+}
+
+fn median_absolute_deviation(scores: impl Iterator<Item = f64>) -> f64 {
+    let median = |values: &mut [f64]| {
+        values.sort_by(f64::total_cmp);
+        let middle = values.len() / 2;
+        if values.len() % 2 == 1 {
+            values[middle]
+        } else {
+            (values[middle - 1] + values[middle]) / 2.0
+        }
+    };
+    let mut scores: Vec<_> = scores.filter(|score| !score.is_nan()).collect();
+    if scores.is_empty() {
+        return 0.0;
+    }
+    let center = median(&mut scores);
+    let mut deviations: Vec<_> = scores.iter().map(|score| (score - center).abs()).collect();
+    median(&mut deviations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let fitness = [1.0, 4.0, -2.0, 3.0];
+        let parents = Truncation::new(0.5).select(&fitness, 32, &mut rng);
+        assert_eq!(parents.len(), 32);
+        assert!(parents.iter().all(|&p| p == 1 || p == 3));
+        assert_eq!(Truncation::new(0.0).select(&fitness, 2, &mut rng), [1, 1]);
+    }
+
+    #[test]
+    fn tournament() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        let fitness = [1.0, 4.0, -2.0, 3.0];
+        let mut tournament = Tournament::new(4).with_replacement(false);
+        assert_eq!(tournament.select(&fitness, 3, &mut rng), [1, 1, 1]);
+
+        let parents = Tournament::new(1).select(&fitness, 400, &mut rng);
+        assert!((0..4).all(|i| parents.contains(&i)));
+        let parents = Tournament::new(3).select(&fitness, 400, &mut rng);
+        let count = |i| parents.iter().filter(|&&p| p == i).count();
+        assert!(count(1) > count(3) && count(3) > count(0) && count(0) > count(2));
+    }
+
+    #[test]
+    fn lexicase() {
+        let mut rng = Pcg64::seed_from_u64(3);
+        // The specialists 0 and 1 each solve a case, 2 is mediocre on both and 3 is dominated
+        let cases = [
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![0.6, 0.6],
+            vec![0.5, 0.5],
+        ];
+        let parents = Lexicase::new().select_cases(&cases, 200, &mut rng);
+        assert!(parents.iter().all(|&p| p == 0 || p == 1));
+        assert!(parents.contains(&0) && parents.contains(&1));
+
+        // Within the epsilon of the specialist on either case, 2 is the best on the other one
+        let parents = Lexicase::new()
+            .with_epsilon(0.45)
+            .select_cases(&cases, 200, &mut rng);
+        assert!(parents.iter().all(|&p| p == 2));
+        let parents = Lexicase::new()
+            .with_automatic_epsilon()
+            .select_cases(&cases, 200, &mut rng);
+        assert_eq!(parents.len(), 200);
+    }
+}
@@ -0,0 +1,221 @@
+use super::{Distance, Genome, SeedDistance};
+
+use serde::{Deserialize, Serialize};
+
+/// Transforms the raw fitness of a generation, such as the summed returns of its episodes,
+/// into the fitness parents are selected by.
+///
+/// Raw rewards often have scales that make selection unstable, such as a few outliers that
+/// dwarf all other differences. See
+/// [Population::set_shaping](super::Population::set_shaping).
+pub trait Shaping {
+    /// The shaped fitness of every genome of the generation, given the raw `fitness` of
+    /// `genomes`. NaN fitness stays NaN.
+    fn shape(&mut self, genomes: &[Genome], fitness: &[f64]) -> Vec<f64>;
+}
+
+impl<A: Shaping, B: Shaping> Shaping for (A, B) {
+    fn shape(&mut self, genomes: &[Genome], fitness: &[f64]) -> Vec<f64> {
+        let shaped = self.0.shape(genomes, fitness);
+        self.1.shape(genomes, &shaped)
+    }
+}
+
+/// The rank of every fitness, scaled to `-0.5..=0.5` from the lowest to the highest. Equal
+/// fitness gets the mean of their ranks, and NaN fitness is not ranked and stays NaN.
+pub fn centered_ranks(fitness: &[f64]) -> Vec<f64> {
+    let mut order: Vec<_> = (0..fitness.len())
+        .filter(|&i| !fitness[i].is_nan())
+        .collect();
+    order.sort_by(|&a, &b| fitness[a].total_cmp(&fitness[b]));
+
+    let mut ranks = vec![f64::NAN; fitness.len()];
+    let max_rank = (order.len().max(2) - 1) as f64;
+    let mut start = 0;
+    for ties in order.chunk_by(|&a, &b| fitness[a] == fitness[b]) {
+        let rank = (2 * start + ties.len() - 1) as f64 / 2.0;
+        for &i in ties {
+            ranks[i] = rank / max_rank - 0.5;
+        }
+        start += ties.len();
+    }
+    ranks
+}
+
+/// Replaces fitness by its [centered rank](centered_ranks), so only the order of the fitness
+/// matters and not its scale.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RankNormalization;
+
+impl Shaping for RankNormalization {
+    fn shape(&mut self, _: &[Genome], fitness: &[f64]) -> Vec<f64> {
+        centered_ranks(fitness)
+    }
+}
+
+/// Divides the fitness of every genome by the amount of genomes near it, so genomes in
+/// crowded regions of the search space compete for their share of fitness.
+///
+/// Genomes within the radius of a genome add `1 - (distance / radius)^alpha` to its crowding,
+/// including the genome itself. Fitness is shifted so the lowest is 0 before it is divided.
+/// Unlike [Speciation](super::Speciation), genomes are not grouped, but every pair of genomes
+/// of the generation is compared.
+pub struct FitnessSharing {
+    distance: Box<dyn Distance + Send>,
+    radius: f64,
+    alpha: f64,
+}
+
+impl FitnessSharing {
+    /// Share fitness between genomes within `radius` of each other, measured with
+    /// [SeedDistance], with an alpha of 1.
+    ///
+    /// # Panics
+    /// If `radius` is not positive.
+    pub fn new(radius: f64) -> Self {
+        assert!(radius > 0.0, "radius must be positive");
+        Self {
+            distance: Box::new(SeedDistance),
+            radius,
+            alpha: 1.0,
+        }
+    }
+
+    /// The same sharing, but measuring distances with `distance`.
+    pub fn with_distance(self, distance: impl Distance + Send + 'static) -> Self {
+        Self {
+            distance: Box::new(distance),
+            ..self
+        }
+    }
+
+    /// The same sharing, but with the exponent `alpha` of the relative distance. Higher
+    /// values share more with distant genomes.
+    pub fn with_alpha(self, alpha: f64) -> Self {
+        Self { alpha, ..self }
+    }
+}
+
+impl Shaping for FitnessSharing {
+    fn shape(&mut self, genomes: &[Genome], fitness: &[f64]) -> Vec<f64> {
+        assert_eq!(
+            genomes.len(),
+            fitness.len(),
+            "fitness of another generation"
+        );
+
+        let min = fitness.iter().copied().fold(f64::INFINITY, f64::min);
+        genomes
+            .iter()
+            .zip(fitness)
+            .map(|(genome, &fitness)| {
+                let crowding: f64 = genomes
+                    .iter()
+                    .map(|other| self.distance.distance(genome, other) / self.radius)
+                    .filter(|&relative| relative < 1.0)
+                    .map(|relative| 1.0 - relative.powf(self.alpha))
+                    .sum();
+                (fitness - min) / crowding.max(1.0)
+            })
+            .collect()
+    }
+}
+
+/// Subtracts a baseline from the fitness, the mean fitness of the generation or a moving
+/// average of it over the generations.
+///
+/// This centers the fitness around 0 without changing its scale, which keeps shaping that
+/// divides fitness, such as [FitnessSharing], from being dominated by a large offset that all
+/// genomes share.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Baseline {
+    decay: f64,
+    value: Option<f64>,
+}
+
+impl Default for Baseline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Baseline {
+    /// Subtract the mean fitness of every generation.
+    pub fn new() -> Self {
+        Self {
+            decay: 0.0,
+            value: None,
+        }
+    }
+
+    /// The same baseline, but moving towards the mean fitness of every generation as an
+    /// exponential moving average that keeps `decay` of the previous baseline.
+    ///
+    /// # Panics
+    /// If `decay` is not in `0.0..1.0`.
+    pub fn with_decay(self, decay: f64) -> Self {
+        assert!((0.0..1.0).contains(&decay), "invalid decay");
+        Self { decay, ..self }
+    }
+
+    /// The baseline subtracted from the last generation, if any.
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+impl Shaping for Baseline {
+    fn shape(&mut self, _: &[Genome], fitness: &[f64]) -> Vec<f64> {
+        let valid = fitness.iter().filter(|fitness| !fitness.is_nan());
+        let count = valid.clone().count();
+        if count > 0 {
+            let mean = valid.sum::<f64>() / count as f64;
+            self.value = Some(match self.value {
+                Some(value) => self.decay * value + (1.0 - self.decay) * mean,
+                None => mean,
+            });
+        }
+
+        let baseline = self.value.unwrap_or(0.0);
+        fitness.iter().map(|fitness| fitness - baseline).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks() {
+        let ranks = centered_ranks(&[3.0, f64::NAN, -1.0, 7.0, 3.0, 1e12]);
+        assert!(ranks[1].is_nan());
+        assert_eq!(
+            [ranks[0], ranks[2], ranks[3], ranks[4], ranks[5]],
+            [-0.125, -0.5, 0.25, -0.125, 0.5]
+        );
+        assert_eq!(centered_ranks(&[2.0]), [-0.5]);
+    }
+
+    #[test]
+    fn sharing() {
+        let genomes = [
+            Genome::new(1),
+            Genome::new(1).with_mutation(9),
+            Genome::new(2),
+        ];
+        let mut sharing = FitnessSharing::new(2.0);
+        // The first two are a distance of 1 apart, so they share half of each other
+        assert_eq!(sharing.shape(&genomes, &[4.0, 1.0, 2.0]), [2.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn baseline() {
+        let mut baseline = Baseline::new().with_decay(0.5);
+        assert_eq!(baseline.shape(&[], &[1.0, 3.0]), [-1.0, 1.0]);
+        assert_eq!(baseline.shape(&[], &[4.0, f64::NAN, 6.0])[2], 2.5);
+        assert_eq!(baseline.value(), Some(3.5));
+
+        let shaped = (Baseline::new(), RankNormalization).shape(&[], &[5.0, 1.0]);
+        assert_eq!(shaped, [0.5, -0.5]);
+    }
+}
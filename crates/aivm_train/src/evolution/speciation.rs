@@ -0,0 +1,138 @@
+use super::{Distance, Genome, SeedDistance};
+
+use serde::{Deserialize, Serialize};
+
+/// A group of similar genomes of a generation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Species {
+    /// The genome new genomes are compared with to decide whether they belong to the species.
+    pub representative: Genome,
+    /// The indices of the genomes of the generation that belong to the species.
+    pub members: Vec<usize>,
+}
+
+/// Clusters genomes into species of similar genomes and shares fitness within them, like
+/// NEAT.
+///
+/// Dividing the fitness of a genome by the size of its species keeps one large species from
+/// taking over the population, which protects innovative genomes that are not yet as fit as
+/// the established ones.
+pub struct Speciation {
+    distance: Box<dyn Distance + Send>,
+    threshold: f64,
+    species: Vec<Species>,
+}
+
+impl Speciation {
+    /// Put genomes within `threshold` of the representative of a species into that species,
+    /// measured with [SeedDistance].
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            distance: Box::new(SeedDistance),
+            threshold,
+            species: vec![],
+        }
+    }
+
+    /// The same speciation, but measuring distances with `distance`.
+    pub fn with_distance(self, distance: impl Distance + Send + 'static) -> Self {
+        Self {
+            distance: Box::new(distance),
+            ..self
+        }
+    }
+
+    /// The species of the last [speciated](Self::speciate) generation.
+    pub fn species(&self) -> &[Species] {
+        &self.species
+    }
+
+    /// Divide `genomes` into species. Every genome joins the first species whose
+    /// representative is within the threshold, or founds a new species.
+    ///
+    /// Species are kept across generations: the representatives of the previous generation
+    /// are compared with first, and are then replaced by the first member of their species.
+    /// Species without members die out.
+    pub fn speciate(&mut self, genomes: &[Genome]) -> &[Species] {
+        for species in &mut self.species {
+            species.members.clear();
+        }
+
+        for (i, genome) in genomes.iter().enumerate() {
+            let species = self.species.iter_mut().find(|species| {
+                self.distance.distance(&species.representative, genome) <= self.threshold
+            });
+            match species {
+                Some(species) => species.members.push(i),
+                None => self.species.push(Species {
+                    representative: genome.clone(),
+                    members: vec![i],
+                }),
+            }
+        }
+
+        self.species.retain(|species| !species.members.is_empty());
+        for species in &mut self.species {
+            species.representative = genomes[species.members[0]].clone();
+        }
+
+        &self.species
+    }
+
+    /// The fitness of every genome of the last speciated generation, shifted so the lowest
+    /// is 0 and divided by the size of its species.
+    ///
+    /// # Panics
+    /// If `fitness` does not belong to the last speciated generation.
+    pub fn share(&self, fitness: &[f64]) -> Vec<f64> {
+        assert_eq!(
+            fitness.len(),
+            self.species.iter().map(|s| s.members.len()).sum::<usize>(),
+            "fitness of another generation",
+        );
+
+        let min = fitness.iter().copied().fold(f64::INFINITY, f64::min);
+        let mut shared = vec![0.0; fitness.len()];
+        for species in &self.species {
+            for &i in &species.members {
+                shared[i] = (fitness[i] - min) / species.members.len() as f64;
+            }
+        }
+
+        shared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share() {
+        let genomes = [
+            Genome::new(1),
+            Genome::new(2),
+            Genome::new(1).with_mutation(9),
+            Genome::new(1).with_mutation(9).with_mutation(8),
+        ];
+        let mut speciation = Speciation::new(1.0);
+        let members: Vec<_> = speciation
+            .speciate(&genomes)
+            .iter()
+            .map(|s| s.members.clone())
+            .collect();
+        assert_eq!(members, [vec![0, 2], vec![1], vec![3]]);
+        assert_eq!(
+            speciation.share(&[3.0, 1.0, 5.0, 2.0]),
+            [1.0, 0.0, 2.0, 1.0]
+        );
+
+        // The representatives carry over to the next generation
+        let members: Vec<_> = speciation
+            .speciate(&genomes[2..])
+            .iter()
+            .map(|s| s.members.clone())
+            .collect();
+        assert_eq!(members, [vec![0], vec![1]]);
+    }
+}
@@ -0,0 +1,271 @@
+use super::{GenerationStats, Genome, Population};
+
+use rand::prelude::*;
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Why a run should stop, returned by [StopCriteria::check].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The best fitness reached the target.
+    TargetReached,
+    /// The best fitness did not improve for too many generations.
+    Stagnated,
+    /// The run took longer than its budget.
+    OutOfTime,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TargetReached => write!(f, "target fitness reached"),
+            Self::Stagnated => write!(f, "no improvement for too many generations"),
+            Self::OutOfTime => write!(f, "time budget exhausted"),
+        }
+    }
+}
+
+/// Decides when an unattended run is done, checked after every generation.
+///
+/// No criterion is set by default, so a run only stops once at least one is added.
+#[derive(Debug, Clone)]
+pub struct StopCriteria {
+    target: Option<f64>,
+    patience: Option<u64>,
+    budget: Option<Duration>,
+    start: Instant,
+    best: f64,
+    stale: u64,
+}
+
+impl Default for StopCriteria {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StopCriteria {
+    /// Create criteria that never stop a run. The clock of the time budget starts now.
+    pub fn new() -> Self {
+        Self {
+            target: None,
+            patience: None,
+            budget: None,
+            start: Instant::now(),
+            best: f64::NEG_INFINITY,
+            stale: 0,
+        }
+    }
+
+    /// The same criteria, but also stopping once the best fitness of a generation is at least
+    /// `target`.
+    pub fn with_target(self, target: f64) -> Self {
+        Self {
+            target: Some(target),
+            ..self
+        }
+    }
+
+    /// The same criteria, but also stopping after `patience` generations in a row that did not
+    /// improve on the best fitness so far.
+    pub fn with_patience(self, patience: u64) -> Self {
+        Self {
+            patience: Some(patience),
+            ..self
+        }
+    }
+
+    /// The same criteria, but also stopping once `budget` has passed since they were created.
+    /// The generation that runs out of time is still completed.
+    pub fn with_budget(self, budget: Duration) -> Self {
+        Self {
+            budget: Some(budget),
+            ..self
+        }
+    }
+
+    /// The time since the criteria were created.
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// The amount of generations in a row that did not improve on the best fitness so far.
+    pub fn stale_generations(&self) -> u64 {
+        self.stale
+    }
+
+    /// Take the statistics of a generation into account, returns why the run should stop, if
+    /// it should.
+    pub fn check(&mut self, stats: &GenerationStats) -> Option<StopReason> {
+        if stats.best_fitness > self.best {
+            self.best = stats.best_fitness;
+            self.stale = 0;
+        } else {
+            self.stale += 1;
+        }
+
+        if self.target.is_some_and(|target| self.best >= target) {
+            Some(StopReason::TargetReached)
+        } else if self.patience.is_some_and(|patience| self.stale >= patience) {
+            Some(StopReason::Stagnated)
+        } else if self.budget.is_some_and(|budget| self.elapsed() >= budget) {
+            Some(StopReason::OutOfTime)
+        } else {
+            None
+        }
+    }
+}
+
+/// Tries to get a population that stopped improving moving again, by raising the mutation
+/// probability and replacing part of it by random genomes.
+///
+/// Every `patience` generations without improvement the mutation probability is multiplied by
+/// the boost factor once more, and the immigrants are injected. As soon as the best fitness
+/// improves, the probability returns to its base.
+#[derive(Debug, Clone)]
+pub struct Stagnation {
+    patience: u64,
+    boost: f64,
+    immigrants: usize,
+    best: f64,
+    stale: u64,
+    boosts: u32,
+}
+
+impl Stagnation {
+    /// Create a response to `patience` generations without improvement, which does nothing
+    /// until a boost or immigrants are added.
+    ///
+    /// # Panics
+    /// If `patience` is 0.
+    pub fn new(patience: u64) -> Self {
+        assert!(patience > 0, "patience must be at least 1");
+        Self {
+            patience,
+            boost: 1.0,
+            immigrants: 0,
+            best: f64::NEG_INFINITY,
+            stale: 0,
+            boosts: 0,
+        }
+    }
+
+    /// The same response, but multiplying the mutation probability by `factor` every time the
+    /// population stagnates.
+    pub fn with_mutation_boost(self, factor: f64) -> Self {
+        Self {
+            boost: factor,
+            ..self
+        }
+    }
+
+    /// The same response, but replacing `count` genomes of the population by random ones every
+    /// time it stagnates.
+    pub fn with_immigrants(self, count: usize) -> Self {
+        Self {
+            immigrants: count,
+            ..self
+        }
+    }
+
+    /// The amount of times the population stagnated since the best fitness last improved.
+    pub fn boosts(&self) -> u32 {
+        self.boosts
+    }
+
+    /// The mutation probability to use instead of `base`, in the units of
+    /// [fill_mutate_bits](super::fill_mutate_bits).
+    pub fn p_mutate(&self, base: u16) -> u16 {
+        let p = f64::from(base) * self.boost.powi(self.boosts as i32);
+        p.clamp(0.0, f64::from(u16::MAX)) as u16
+    }
+
+    /// Take the statistics of the generation `population` just stepped into account, and
+    /// inject random immigrants into it if it stagnated, with root seeds from `rng`.
+    ///
+    /// Returns whether [p_mutate](Self::p_mutate) changed, in which case new mutations need
+    /// another scale, see [Schedule](super::Schedule).
+    pub fn update(
+        &mut self,
+        stats: &GenerationStats,
+        population: &mut Population,
+        rng: &mut impl Rng,
+    ) -> bool {
+        if stats.best_fitness > self.best {
+            self.best = stats.best_fitness;
+            self.stale = 0;
+            let boosted = self.boosts > 0;
+            self.boosts = 0;
+            return boosted && self.boost != 1.0;
+        }
+
+        self.stale += 1;
+        if self.stale < self.patience {
+            return false;
+        }
+
+        self.stale = 0;
+        self.boosts += 1;
+        let immigrants: Vec<_> = (0..self.immigrants)
+            .map(|_| Genome::new(rng.gen()))
+            .collect();
+        population.immigrate(&immigrants);
+        self.boost != 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_pcg::Pcg64;
+
+    fn stats(best_fitness: f64) -> GenerationStats {
+        GenerationStats {
+            generation: 0,
+            best: Genome::new(0),
+            best_fitness,
+            mean_fitness: best_fitness,
+            median_fitness: best_fitness,
+            std_fitness: 0.0,
+        }
+    }
+
+    #[test]
+    fn criteria() {
+        let mut criteria = StopCriteria::new().with_target(10.0).with_patience(2);
+        assert_eq!(criteria.check(&stats(1.0)), None);
+        assert_eq!(criteria.check(&stats(1.0)), None);
+        assert_eq!(criteria.check(&stats(0.5)), Some(StopReason::Stagnated));
+        assert_eq!(
+            criteria.check(&stats(10.0)),
+            Some(StopReason::TargetReached)
+        );
+
+        let mut criteria = StopCriteria::new().with_budget(Duration::ZERO);
+        assert_eq!(criteria.check(&stats(1.0)), Some(StopReason::OutOfTime));
+        assert_eq!(StopCriteria::new().check(&stats(f64::NAN)), None);
+    }
+
+    #[test]
+    fn recover() {
+        let mut population = Population::new(8, 1);
+        let before = population.genomes().to_vec();
+        let mut stagnation = Stagnation::new(2)
+            .with_mutation_boost(2.0)
+            .with_immigrants(3);
+        let mut rng = Pcg64::seed_from_u64(5);
+
+        assert!(!stagnation.update(&stats(1.0), &mut population, &mut rng));
+        assert!(!stagnation.update(&stats(1.0), &mut population, &mut rng));
+        assert!(stagnation.update(&stats(1.0), &mut population, &mut rng));
+        assert_eq!(stagnation.p_mutate(1000), 2000);
+        assert_eq!(population.genomes()[..5], before[..5]);
+        assert_ne!(population.genomes()[5..], before[5..]);
+
+        assert!(stagnation.update(&stats(2.0), &mut population, &mut rng));
+        assert_eq!(stagnation.p_mutate(1000), 1000);
+        assert_eq!(stagnation.p_mutate(u16::MAX), u16::MAX);
+    }
+}
@@ -0,0 +1,254 @@
+use super::{mutation_seed, Genome, Mutation};
+
+use aivm::{InstructionFrequencies, KIND_COUNT};
+use rand::prelude::*;
+use rand_pcg::{Pcg32, Pcg64};
+use serde::{Deserialize, Serialize};
+
+/// The code word that ends functions inserted by an [Edit], and pads code that got shorter.
+const END_FUNC_WORD: u64 = 0;
+/// The maximum amount of instructions of a function inserted by an [Edit].
+const MAX_INSERTED_LEN: u32 = 8;
+
+/// A change to the structure of code, at the level of functions and instructions instead of
+/// bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EditKind {
+    /// Insert a new function of random instructions before one of the functions, other than
+    /// the main function.
+    InsertFunction,
+    /// Remove a function other than the main function.
+    DeleteFunction,
+    /// Insert a copy of a function right after it.
+    DuplicateFunction,
+    /// Insert a random instruction into a function.
+    SpliceInstruction,
+    /// Swap two functions other than the main function.
+    SwapFunctions,
+}
+
+impl EditKind {
+    /// Every kind of edit.
+    pub const ALL: [Self; 5] = [
+        Self::InsertFunction,
+        Self::DeleteFunction,
+        Self::DuplicateFunction,
+        Self::SpliceInstruction,
+        Self::SwapFunctions,
+    ];
+}
+
+/// A structural mutation of a [Genome], applied to its code after the first
+/// [after](Self::after) mutation seeds.
+///
+/// The functions and instructions an edit affects are picked with its seed when it is applied,
+/// so like mutation seeds an edit takes a few bytes no matter how large the code is. Code
+/// keeps its length: words pushed past the end are dropped, and code that got shorter is
+/// padded with words that end a function, which are ignored by the compiler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Edit {
+    /// The amount of mutation seeds of the genome applied before the edit.
+    pub after: u32,
+    /// What the edit does.
+    pub kind: EditKind,
+    /// The seed that picks the functions and instructions to edit.
+    pub seed: u32,
+}
+
+impl Edit {
+    /// Apply the edit to `code`, which has functions ended by the `end_func` instruction of
+    /// the frequencies `F`.
+    pub fn apply<F: InstructionFrequencies>(&self, code: &mut [u64]) {
+        self.apply_with_table(code, &F::table());
+    }
+
+    /// Like [apply](Self::apply), but with the frequencies in a table like
+    /// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+    pub fn apply_with_table(&self, code: &mut [u64], frequencies: &[u16; KIND_COUNT]) {
+        let end_func = frequencies[0];
+        let mut rng = Pcg32::seed_from_u64(u64::from(self.seed));
+        let mut functions = split(code, end_func);
+        let count = functions.len();
+        match self.kind {
+            EditKind::InsertFunction => {
+                let len = rng.gen_range(1..=MAX_INSERTED_LEN);
+                let mut function: Vec<_> = (0..len)
+                    .map(|_| random_instruction(&mut rng, end_func))
+                    .collect();
+                function.push(END_FUNC_WORD);
+                functions.insert(rng.gen_range(1..=count), function);
+            }
+            EditKind::DeleteFunction if count > 1 => {
+                functions.remove(rng.gen_range(1..count));
+            }
+            EditKind::DuplicateFunction => {
+                let f = rng.gen_range(0..count);
+                functions.insert(f + 1, functions[f].clone());
+            }
+            EditKind::SpliceInstruction => {
+                let function = &mut functions[rng.gen_range(0..count)];
+                let at = rng.gen_range(0..function.len());
+                function.insert(at, random_instruction(&mut rng, end_func));
+            }
+            EditKind::SwapFunctions if count > 2 => {
+                let a = rng.gen_range(1..count);
+                let b = rng.gen_range(1..count);
+                functions.swap(a, b);
+            }
+            EditKind::DeleteFunction | EditKind::SwapFunctions => {}
+        }
+
+        let words = functions
+            .into_iter()
+            .flatten()
+            .chain(std::iter::repeat(END_FUNC_WORD));
+        for (word, edited) in code.iter_mut().zip(words) {
+            *word = edited;
+        }
+    }
+}
+
+/// Split code into its functions, each ended by an `end_func` instruction. The last function
+/// gets one if the code does not end with one.
+fn split(code: &[u64], end_func: u16) -> Vec<Vec<u64>> {
+    let mut functions = vec![vec![]];
+    for &word in code {
+        functions.last_mut().unwrap().push(word);
+        if (word as u16) < end_func {
+            functions.push(vec![]);
+        }
+    }
+
+    let last = functions.pop().unwrap();
+    if !last.is_empty() || functions.is_empty() {
+        functions.push(last);
+        functions.last_mut().unwrap().push(END_FUNC_WORD);
+    }
+    functions
+}
+
+fn random_instruction(rng: &mut Pcg32, end_func: u16) -> u64 {
+    loop {
+        let word: u64 = rng.gen();
+        if word as u16 >= end_func {
+            return word;
+        }
+    }
+}
+
+/// Mutates genomes by appending a random mutation seed like [AppendSeed](super::AppendSeed),
+/// or with a probability by appending an [Edit], which makes coarser moves through program
+/// space than flipping bits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructuralMutation {
+    p_edit: f64,
+    kinds: Vec<EditKind>,
+}
+
+impl StructuralMutation {
+    /// Create a mutation that appends an edit of a random kind with a probability of `p_edit`.
+    pub fn new(p_edit: f64) -> Self {
+        Self {
+            p_edit,
+            kinds: EditKind::ALL.to_vec(),
+        }
+    }
+
+    /// The same mutation, but only making edits of `kinds`, picked with equal probability.
+    ///
+    /// # Panics
+    /// If `kinds` is empty.
+    pub fn with_kinds(self, kinds: &[EditKind]) -> Self {
+        assert!(!kinds.is_empty(), "no kinds of edits to make");
+        Self {
+            kinds: kinds.to_vec(),
+            ..self
+        }
+    }
+}
+
+impl Mutation for StructuralMutation {
+    fn mutate(&mut self, parent: &Genome, rng: &mut Pcg64) -> Genome {
+        if rng.gen_bool(self.p_edit.clamp(0.0, 1.0)) {
+            let kind = self.kinds[rng.gen_range(0..self.kinds.len())];
+            parent.clone().with_edit(kind, rng.gen())
+        } else {
+            parent.clone().with_mutation(mutation_seed(rng.gen(), 1.0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aivm::DefaultFrequencies;
+
+    const END: u64 = 1;
+    // Any word with a selector past the end_func frequency is a regular instruction
+    const A: u64 = 0xA000;
+    const B: u64 = 0xB000;
+    const C: u64 = 0xC000;
+
+    fn edit(kind: EditKind, seed: u32, code: &[u64]) -> Vec<u64> {
+        let mut code = code.to_vec();
+        Edit {
+            after: 0,
+            kind,
+            seed,
+        }
+        .apply::<DefaultFrequencies>(&mut code);
+        code
+    }
+
+    #[test]
+    fn functions() {
+        let code = [A, END, B, B, END, C];
+        assert_eq!(
+            split(&code, DefaultFrequencies::END_FUNC),
+            [vec![A, END], vec![B, B, END], vec![C, END_FUNC_WORD]]
+        );
+
+        // Only the main function, which is never removed
+        assert_eq!(edit(EditKind::DeleteFunction, 1, &[A, B]), [A, B]);
+        // The copy of the last function is cut off at the end of the code
+        let duplicated = edit(EditKind::DuplicateFunction, 7, &code);
+        assert!(
+            duplicated == [A, END, A, END, B, B]
+                || duplicated == [A, END, B, B, END, B]
+                || duplicated == [A, END, B, B, END, C]
+        );
+        for seed in 0..20 {
+            let deleted = edit(EditKind::DeleteFunction, seed, &code);
+            assert!(deleted == [A, END, C, 0, 0, 0] || deleted == [A, END, B, B, END, 0]);
+            let swapped = edit(EditKind::SwapFunctions, seed, &code);
+            assert!(swapped == code || swapped == [A, END, C, 0, B, B]);
+            let spliced = edit(EditKind::SpliceInstruction, seed, &code);
+            assert_eq!(spliced.iter().filter(|&&w| w == END).count(), 2);
+        }
+    }
+
+    #[test]
+    fn genome_edits() {
+        let mut mutate_bits = vec![0; 128];
+        super::super::fill_mutate_bits(&mut mutate_bits, 1, 5000);
+        let expand = |genome: &Genome| {
+            let mut code = vec![0; 64];
+            genome.expand_code::<DefaultFrequencies, _>(&mutate_bits, &mut code);
+            code
+        };
+
+        let parent = Genome::new(3).with_mutation(1);
+        let child = parent.clone().with_edit(EditKind::SpliceInstruction, 9);
+        assert_eq!(child.edits[0].after, 1);
+        assert_ne!(expand(&parent), expand(&child));
+
+        let mut code = expand(&parent);
+        child.edits[0].apply::<DefaultFrequencies>(&mut code);
+        assert_eq!(expand(&child), code);
+
+        let mut mutation = StructuralMutation::new(1.0).with_kinds(&[EditKind::SwapFunctions]);
+        let mutant = mutation.mutate(&child, &mut Pcg64::seed_from_u64(0));
+        assert_eq!(mutant.edits.len(), 2);
+        assert_eq!(mutant.edits[1].kind, EditKind::SwapFunctions);
+    }
+}
@@ -0,0 +1,516 @@
+use crate::{
+    cache::{program_hash, FitnessCache},
+    evolution::{self, Genome, MutateBits},
+    tuning::FrequencyTable,
+};
+
+use aivm::{codegen::CodeGenerator, Compiler, MemoryBuffer, MemoryLayout, Runner};
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+use std::time::{Duration, Instant};
+
+/// Measures how well compiled code performs in an environment.
+pub trait Fitness {
+    /// Run an episode of `runner` on `memory` and score it, higher is better.
+    ///
+    /// The memory bank of `memory` holds the initial memory of the genome, the output and
+    /// input are zeroed. Any randomness of the environment should come from `rng`, so
+    /// evaluations can be reproduced.
+    fn evaluate(&self, runner: &dyn Runner, memory: &mut MemoryBuffer, rng: &mut Pcg64) -> f64;
+}
+
+impl<F> Fitness for F
+where
+    F: Fn(&dyn Runner, &mut MemoryBuffer, &mut Pcg64) -> f64,
+{
+    fn evaluate(&self, runner: &dyn Runner, memory: &mut MemoryBuffer, rng: &mut Pcg64) -> f64 {
+        self(runner, memory, rng)
+    }
+}
+
+/// The time an [Evaluator] spent on the genomes it evaluated since its timings were last
+/// [taken](Evaluator::take_timings).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// The time spent compiling code.
+    pub compile: Duration,
+    /// The time spent running episodes.
+    pub evaluate: Duration,
+    /// The amount of genomes evaluated, including the ones found in the cache.
+    pub evaluations: u64,
+}
+
+/// Turns genomes into fitness values, by expanding and compiling their code, setting up their
+/// memory and running a number of episodes with a [Fitness].
+///
+/// Genomes are mutated with a pool of bits by default, see [MutateBits] for alternatives.
+/// Their code is generated and compiled with the [DefaultFrequencies](aivm::DefaultFrequencies)
+/// unless other [frequencies](Self::with_frequencies) are set.
+pub struct Evaluator<G: CodeGenerator, F, B = Vec<u64>> {
+    compiler: Compiler<G>,
+    frequencies: FrequencyTable,
+    fitness: F,
+    layout: MemoryLayout,
+    lowest_function_level: u32,
+    episodes: u32,
+    parsimony: f64,
+    mutate_bits: B,
+    code: Vec<u64>,
+    memory: Vec<i64>,
+    buffer: MemoryBuffer,
+    cache: Option<FitnessCache>,
+    timings: Timings,
+}
+
+impl<G, F, B> Evaluator<G, F, B>
+where
+    G: CodeGenerator + 'static,
+    G::Runner: Runner,
+    F: Fitness,
+    B: MutateBits,
+{
+    /// Create an evaluator for genomes of `code_len` code words that run on memories of
+    /// `layout`, mutated with `mutate_bits` (see [fill_mutate_bits](crate::evolution::fill_mutate_bits)
+    /// and [ProceduralBits](crate::evolution::ProceduralBits)).
+    ///
+    /// The output init and scratch size of `compiler` are set to the ones of `layout`, other
+    /// settings such as constants and host functions are used as they are. Genomes are
+    /// evaluated with a single episode and a lowest function level of 1 by default.
+    ///
+    /// # Panics
+    /// If `mutate_bits` is not longer than both the code and the memory bank.
+    pub fn new(
+        mut compiler: Compiler<G>,
+        layout: MemoryLayout,
+        code_len: usize,
+        mutate_bits: B,
+        fitness: F,
+    ) -> Self {
+        assert!(
+            mutate_bits.len() > code_len && mutate_bits.len() > layout.memory_size as usize,
+            "not enough mutate bits",
+        );
+        compiler.set_output_init(layout.output_init);
+        compiler.set_scratch_size(layout.scratch_size);
+
+        Self {
+            compiler,
+            frequencies: FrequencyTable::default(),
+            fitness,
+            layout,
+            lowest_function_level: 1,
+            episodes: 1,
+            parsimony: 0.0,
+            mutate_bits,
+            code: vec![0; code_len],
+            memory: vec![0; layout.memory_size as usize],
+            buffer: MemoryBuffer::new(layout),
+            cache: None,
+            timings: Timings::default(),
+        }
+    }
+
+    /// The same evaluator, but averaging the fitness over `episodes` episodes.
+    ///
+    /// # Panics
+    /// If `episodes` is 0.
+    pub fn with_episodes(self, episodes: u32) -> Self {
+        assert!(episodes > 0, "at least one episode is needed");
+        Self { episodes, ..self }
+    }
+
+    /// The same evaluator, but generating and compiling code with the instruction frequencies
+    /// of `frequencies`, for example a table tuned with a
+    /// [FrequencyTuner](crate::tuning::FrequencyTuner).
+    pub fn with_frequencies(self, frequencies: FrequencyTable) -> Self {
+        Self {
+            frequencies,
+            ..self
+        }
+    }
+
+    /// The same evaluator, but compiling code with the given lowest function level.
+    pub fn with_lowest_function_level(self, lowest_function_level: u32) -> Self {
+        Self {
+            lowest_function_level,
+            ..self
+        }
+    }
+
+    /// The same evaluator, but subtracting `penalty` from the fitness for every instruction
+    /// that can be reached from the entry point, so smaller programs are preferred among
+    /// equally fit ones. Dead code is not penalized, see
+    /// [reachable_instructions](evolution::reachable_instructions).
+    pub fn with_parsimony(self, penalty: f64) -> Self {
+        Self {
+            parsimony: penalty,
+            ..self
+        }
+    }
+
+    /// The same evaluator, but remembering the fitness of the last `capacity` evaluated
+    /// programs in a [FitnessCache], so genomes that expand to the same code and memory are
+    /// only evaluated once.
+    pub fn with_cache(self, capacity: usize) -> Self {
+        Self {
+            cache: Some(FitnessCache::new(capacity)),
+            ..self
+        }
+    }
+
+    /// The cache of evaluated programs, if any, for example to report its hit rate.
+    pub fn cache(&self) -> Option<&FitnessCache> {
+        self.cache.as_ref()
+    }
+
+    /// Mutable access to the cache of evaluated programs, if any. It must be cleared when the
+    /// compiler or fitness changes in a way that changes the fitness of programs.
+    pub fn cache_mut(&mut self) -> Option<&mut FitnessCache> {
+        self.cache.as_mut()
+    }
+
+    /// The time spent on evaluations since the timings were last taken.
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// Take the timings so far and start from zero, for example to report the time spent on
+    /// every generation separately.
+    pub fn take_timings(&mut self) -> Timings {
+        std::mem::take(&mut self.timings)
+    }
+
+    /// The compiler the code of genomes is compiled with.
+    pub fn compiler(&self) -> &Compiler<G> {
+        &self.compiler
+    }
+
+    /// Mutable access to the compiler, for example to change the constants.
+    pub fn compiler_mut(&mut self) -> &mut Compiler<G> {
+        &mut self.compiler
+    }
+
+    /// The instruction frequencies code is generated and compiled with.
+    pub fn frequencies(&self) -> FrequencyTable {
+        self.frequencies
+    }
+
+    /// The fitness genomes are scored with.
+    pub fn fitness(&self) -> &F {
+        &self.fitness
+    }
+
+    /// The layout of the memories genomes run on.
+    pub fn layout(&self) -> MemoryLayout {
+        self.layout
+    }
+
+    /// The amount of code words of a genome.
+    pub fn code_len(&self) -> usize {
+        self.code.len()
+    }
+
+    /// The bits genomes are mutated with.
+    pub fn mutate_bits(&self) -> &B {
+        &self.mutate_bits
+    }
+
+    /// Mutate genomes with other bits from now on. Genomes expand to different code with other
+    /// bits, so a [Schedule](crate::evolution::Schedule) scales the mutations instead, see
+    /// [AppendScaledSeed](crate::evolution::AppendScaledSeed).
+    ///
+    /// # Panics
+    /// If `mutate_bits` is not longer than both the code and the memory bank.
+    pub fn set_mutate_bits(&mut self, mutate_bits: B) {
+        assert!(
+            mutate_bits.len() > self.code.len() && mutate_bits.len() > self.memory.len(),
+            "not enough mutate bits",
+        );
+        self.mutate_bits = mutate_bits;
+    }
+
+    /// The code of `genome`, expanded like it is when the genome is evaluated.
+    pub fn code(&self, genome: &Genome) -> Vec<u64> {
+        let mut code = vec![0; self.code.len()];
+        genome.expand_code_with_table(
+            &self.mutate_bits,
+            &mut code,
+            &self.frequencies.frequencies(),
+        );
+        code
+    }
+
+    /// Expand and compile the code of `genome`, for example to replay a champion.
+    pub fn compile(&mut self, genome: &Genome) -> G::Runner {
+        genome.expand_code_with_table(
+            &self.mutate_bits,
+            &mut self.code,
+            &self.frequencies.frequencies(),
+        );
+        self.compile_expanded()
+    }
+
+    /// Expand the code of `genome` and compile it with `generator` instead of the code
+    /// generator of the evaluator, but with the same settings. For example, the
+    /// [Interpreter](aivm::codegen::Interpreter) can record which instructions run.
+    pub fn compile_with<H: CodeGenerator + 'static>(
+        &self,
+        generator: H,
+        genome: &Genome,
+    ) -> H::Runner {
+        self.compiler.with_generator(generator).compile_with_table(
+            &self.code(genome),
+            self.lowest_function_level,
+            self.layout.memory_size,
+            self.layout.output_size,
+            self.layout.input_size,
+            &self.frequencies.frequencies(),
+        )
+    }
+
+    /// The code of `genome` without its unreachable functions, truncated to the words still in
+    /// use. It compiles to a program that behaves like the one of the genome, which makes it
+    /// smaller to deploy, see [prune](evolution::prune).
+    pub fn pruned_code(&mut self, genome: &Genome) -> Vec<u64> {
+        let mut code = self.code(genome);
+        let len = evolution::prune_with_table(
+            &mut code,
+            self.lowest_function_level,
+            &self.frequencies.frequencies(),
+        );
+        code.truncate(len);
+        code
+    }
+
+    /// The code of `genome` with as many instructions removed as possible while its fitness
+    /// stays within `tolerance` of the fitness of the original code, truncated to the words
+    /// still in use, see [minimize](evolution::minimize). This finds the instructions that
+    /// carry the behavior, for analysis or to deploy the smallest program.
+    ///
+    /// Every evaluation runs with a random generator seeded by `seed`, so the fitness only
+    /// differs because of the code.
+    pub fn minimized_code(&mut self, genome: &Genome, tolerance: f64, seed: u64) -> Vec<u64> {
+        let mut code = self.code(genome);
+        let memory = self.memory(genome).memory().to_vec();
+        let lowest_function_level = self.lowest_function_level;
+        let frequencies = self.frequencies.frequencies();
+        let mut fitness = |code: &[u64]| {
+            self.code.copy_from_slice(code);
+            let runner = self.compile_expanded();
+            self.evaluate_memory(&runner, &memory, &mut Pcg64::seed_from_u64(seed))
+        };
+
+        let original = fitness(&code);
+        let len = evolution::minimize_with_table(
+            &mut code,
+            lowest_function_level,
+            &frequencies,
+            |code| (fitness(code) - original).abs() <= tolerance,
+        );
+        code.truncate(len);
+        code
+    }
+
+    fn compile_expanded(&mut self) -> G::Runner {
+        self.compiler.compile_with_table(
+            &self.code,
+            self.lowest_function_level,
+            self.layout.memory_size,
+            self.layout.output_size,
+            self.layout.input_size,
+            &self.frequencies.frequencies(),
+        )
+    }
+
+    /// A fresh memory for an episode of `genome`, like the one passed to the [Fitness].
+    pub fn memory(&self, genome: &Genome) -> MemoryBuffer {
+        let mut buffer = MemoryBuffer::new(self.layout);
+        genome.expand_memory(&self.mutate_bits, buffer.memory_mut());
+        buffer
+    }
+
+    /// The mean fitness of `genome` over all episodes, which all start from the initial memory
+    /// of the genome, minus the [parsimony](Self::with_parsimony) penalty. With a cache, the
+    /// fitness of a program that was evaluated before is returned without running it.
+    pub fn evaluate(&mut self, genome: &Genome, rng: &mut Pcg64) -> f64 {
+        genome.expand_code_with_table(
+            &self.mutate_bits,
+            &mut self.code,
+            &self.frequencies.frequencies(),
+        );
+        let mut memory = std::mem::take(&mut self.memory);
+        genome.expand_memory(&self.mutate_bits, &mut memory);
+        let hash = program_hash(&self.code, &memory);
+        let cached = self.cache.as_mut().and_then(|cache| cache.get(hash));
+
+        self.timings.evaluations += 1;
+        let fitness = cached.unwrap_or_else(|| {
+            let start = Instant::now();
+            let runner = {
+                span!("compile");
+                self.compile_expanded()
+            };
+            let compiled = Instant::now();
+            let fitness = {
+                span!("evaluate");
+                self.evaluate_memory(&runner, &memory, rng)
+            };
+            self.timings.compile += compiled - start;
+            self.timings.evaluate += compiled.elapsed();
+            if let Some(cache) = &mut self.cache {
+                cache.insert(hash, fitness);
+            }
+            fitness
+        });
+        self.memory = memory;
+
+        if self.parsimony == 0.0 {
+            fitness
+        } else {
+            let size = evolution::reachable_instructions_with_table(
+                &self.code,
+                self.lowest_function_level,
+                &self.frequencies.frequencies(),
+            );
+            fitness - self.parsimony * size as f64
+        }
+    }
+
+    /// The mean fitness of already compiled code over all episodes, which all start from
+    /// `memory` instead of the memory of a genome. This allows tuning the memory of a program
+    /// while keeping its code fixed.
+    ///
+    /// # Panics
+    /// If `memory` does not have the size of the memory bank.
+    pub fn evaluate_memory(&mut self, runner: &dyn Runner, memory: &[i64], rng: &mut Pcg64) -> f64 {
+        assert_eq!(
+            memory.len(),
+            self.layout.memory_size as usize,
+            "wrong memory size"
+        );
+
+        let mut total = 0.0;
+        for _ in 0..self.episodes {
+            self.buffer.as_mut_slice().fill(0);
+            self.buffer.memory_mut().copy_from_slice(memory);
+            total += self.fitness.evaluate(runner, &mut self.buffer, rng);
+        }
+
+        total / f64::from(self.episodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, EditKind};
+    use aivm::{codegen::Interpreter, DefaultFrequencies, InstructionFrequencies};
+    use rand::prelude::*;
+
+    fn sum_output(runner: &dyn Runner, memory: &mut MemoryBuffer, rng: &mut Pcg64) -> f64 {
+        rng.fill(memory.input_mut());
+        runner.step(memory.as_mut_slice());
+        memory.output().iter().map(|&v| v as f64).sum()
+    }
+
+    #[test]
+    fn deterministic() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 4, 4);
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = Evaluator::new(compiler, layout, 256, mutate_bits, sum_output)
+            .with_episodes(3)
+            .with_lowest_function_level(3);
+
+        let genome = Genome::new(7).with_mutation(11);
+        let a = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1));
+        let b = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1));
+        assert_eq!(a, b);
+
+        // The memory of the first episode is not carried over to the next
+        let runner = evaluator.compile(&genome);
+        let mut rng = Pcg64::seed_from_u64(1);
+        let episodes: f64 = (0..3)
+            .map(|_| sum_output(&runner, &mut evaluator.memory(&genome), &mut rng))
+            .sum();
+        assert_eq!(a, episodes / 3.0);
+    }
+
+    #[test]
+    fn cache() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 4, 4);
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator =
+            Evaluator::new(compiler, layout, 256, mutate_bits, sum_output).with_cache(16);
+
+        let genome = Genome::new(7);
+        let a = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1));
+        // A different rng would change the input, but the program was already evaluated
+        let b = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(2));
+        assert_eq!(a, b);
+        evaluator.evaluate(&Genome::new(8), &mut Pcg64::seed_from_u64(1));
+
+        let stats = evaluator.cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+    }
+
+    #[test]
+    fn parsimony() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 4, 4);
+        let new = || {
+            let compiler = Compiler::new(Interpreter::new());
+            Evaluator::new(compiler, layout, 256, mutate_bits.clone(), sum_output)
+        };
+
+        let genome = Genome::new(7);
+        let mut evaluator = new().with_parsimony(0.5);
+        let code = evaluator.pruned_code(&genome);
+        let size = evolution::reachable_instructions::<DefaultFrequencies>(&code, 1);
+        assert!(size > 0);
+        let fitness = new().evaluate(&genome, &mut Pcg64::seed_from_u64(1));
+        assert_eq!(
+            evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1)),
+            fitness - 0.5 * size as f64
+        );
+
+        let minimized = evaluator.minimized_code(&genome, 0.0, 1);
+        assert!(minimized.len() <= code.len());
+    }
+
+    #[test]
+    fn frequencies() {
+        struct LongFunctions;
+        impl InstructionFrequencies for LongFunctions {
+            const END_FUNC: u16 = 1000;
+            const INT_ADD: u16 = 565;
+        }
+
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 4, 4);
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = Evaluator::new(compiler, layout, 256, mutate_bits.clone(), sum_output)
+            .with_frequencies(FrequencyTable::of::<LongFunctions>());
+
+        // The same as generating and compiling with the frequencies as a type
+        let genome = Genome::new(7)
+            .with_edit(EditKind::InsertFunction, 3)
+            .with_mutation(11);
+        let mut code = vec![0; 256];
+        genome.expand_code::<LongFunctions, _>(&mutate_bits, &mut code);
+        assert_eq!(evaluator.code(&genome), code);
+        let runner = Compiler::new(Interpreter::new())
+            .compile_with_frequencies::<LongFunctions>(&code, 1, 8, 4, 4);
+        let mut memory = evaluator.memory(&genome);
+        let expected = sum_output(&runner, &mut memory, &mut Pcg64::seed_from_u64(1));
+        assert_eq!(
+            evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(1)),
+            expected
+        );
+    }
+}
@@ -1 +1,28 @@
+/// Enter a `tracing` span until the end of the scope, if the `tracing` feature is enabled.
+///
+/// Defined before every module, so all of them can use it.
+macro_rules! span {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($arg)*).entered();
+    };
+}
+
+pub mod cache;
+pub mod checkpoint;
+pub mod config;
+pub mod curriculum;
+pub mod distributed;
+pub mod driver;
+pub mod environment;
 pub mod evolution;
+pub mod fitness;
+pub mod metrics;
+pub mod observe;
+pub mod optimize;
+pub mod replay;
+pub mod seed;
+pub mod surrogate;
+pub mod tuning;
+
+pub use driver::train;
@@ -0,0 +1,402 @@
+use crate::{
+    evolution::{hamming, GenerationStats, Genome, MutateBits, Population},
+    fitness::{Evaluator, Fitness, Timings},
+    seed::RunSeed,
+};
+
+use aivm::{codegen::CodeGenerator, Runner, KIND_COUNT};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+};
+
+/// Measurements of a single generation, to follow a run from a dashboard or to compare runs
+/// afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    /// The index of the generation, starting at 0.
+    pub generation: u64,
+    /// The highest fitness of the generation.
+    pub best_fitness: f64,
+    /// The mean fitness of the generation.
+    pub mean_fitness: f64,
+    /// The median fitness of the generation.
+    pub median_fitness: f64,
+    /// The standard deviation of the fitness of the generation.
+    pub std_fitness: f64,
+    /// The share of genomes of the generation that are unique, between 0.0 and 1.0.
+    pub diversity: f64,
+    /// The mean [hamming] distance between the codes of every pair of genomes of the
+    /// generation, see [Diversity].
+    pub mean_distance: f64,
+    /// The amount of distinct behaviors of the generation, see [Diversity].
+    pub unique_behaviors: Option<u64>,
+    /// The entropy in bits of the instruction kinds of all code of the generation, see
+    /// [Diversity].
+    pub kind_entropy: f64,
+    /// The seconds spent compiling code.
+    pub compile_seconds: f64,
+    /// The seconds spent running episodes.
+    pub evaluate_seconds: f64,
+    /// The share of evaluations answered by the fitness cache, between 0.0 and 1.0.
+    pub cache_hit_rate: f64,
+    /// The seed all randomness of the run was derived from, if it was recorded.
+    pub seed: Option<RunSeed>,
+}
+
+impl Metrics {
+    /// The names of the fields, in the order of the columns written by [CsvSink].
+    pub const FIELDS: [&'static str; 13] = [
+        "generation",
+        "best_fitness",
+        "mean_fitness",
+        "median_fitness",
+        "std_fitness",
+        "diversity",
+        "mean_distance",
+        "unique_behaviors",
+        "kind_entropy",
+        "compile_seconds",
+        "evaluate_seconds",
+        "cache_hit_rate",
+        "seed",
+    ];
+
+    /// The metrics in `stats`, with NaN or nothing for the diversity and zero for the time and
+    /// cache hit rate, which are not known from the statistics alone.
+    pub fn new(stats: &GenerationStats) -> Self {
+        Self {
+            generation: stats.generation,
+            best_fitness: stats.best_fitness,
+            mean_fitness: stats.mean_fitness,
+            median_fitness: stats.median_fitness,
+            std_fitness: stats.std_fitness,
+            diversity: f64::NAN,
+            mean_distance: f64::NAN,
+            unique_behaviors: None,
+            kind_entropy: f64::NAN,
+            compile_seconds: 0.0,
+            evaluate_seconds: 0.0,
+            cache_hit_rate: 0.0,
+            seed: None,
+        }
+    }
+
+    /// The same metrics, but recording the seed of the run.
+    pub fn with_seed(self, seed: RunSeed) -> Self {
+        Self {
+            seed: Some(seed),
+            ..self
+        }
+    }
+
+    /// The same metrics, but with the measured `diversity`.
+    pub fn with_diversity(self, diversity: Diversity) -> Self {
+        Self {
+            mean_distance: diversity.mean_distance,
+            unique_behaviors: Some(diversity.unique_behaviors),
+            kind_entropy: diversity.kind_entropy,
+            ..self
+        }
+    }
+
+    /// The same metrics, but with the time spent according to `timings`.
+    pub fn with_timings(self, timings: Timings) -> Self {
+        Self {
+            compile_seconds: timings.compile.as_secs_f64(),
+            evaluate_seconds: timings.evaluate.as_secs_f64(),
+            ..self
+        }
+    }
+
+    /// The metrics of the generation `population` just evaluated with `evaluator`, as returned
+    /// in `stats` by its [step_generation](Population::step_generation).
+    ///
+    /// The timings and cache statistics of `evaluator` are reset, so the next call only
+    /// measures the next generation. The [Diversity] is measured with `evaluator` as well.
+    pub fn collect<G, F, B>(
+        stats: &GenerationStats,
+        population: &Population,
+        evaluator: &mut Evaluator<G, F, B>,
+    ) -> Self
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let ranked = population.ranked();
+        let unique: HashSet<_> = ranked.iter().map(|champion| &champion.genome).collect();
+        let cache_hit_rate = evaluator.cache_mut().map_or(0.0, |cache| {
+            let hit_rate = cache.stats().hit_rate();
+            cache.reset_stats();
+            hit_rate
+        });
+        let timings = evaluator.take_timings();
+        let genomes: Vec<_> = ranked
+            .iter()
+            .map(|champion| champion.genome.clone())
+            .collect();
+
+        Self {
+            diversity: unique.len() as f64 / ranked.len().max(1) as f64,
+            cache_hit_rate,
+            ..Self::new(stats)
+                .with_timings(timings)
+                .with_diversity(Diversity::measure(&genomes, evaluator))
+        }
+    }
+
+    fn values(&self) -> [String; 13] {
+        [
+            self.generation.to_string(),
+            self.best_fitness.to_string(),
+            self.mean_fitness.to_string(),
+            self.median_fitness.to_string(),
+            self.std_fitness.to_string(),
+            self.diversity.to_string(),
+            self.mean_distance.to_string(),
+            self.unique_behaviors
+                .map_or(String::new(), |count| count.to_string()),
+            self.kind_entropy.to_string(),
+            self.compile_seconds.to_string(),
+            self.evaluate_seconds.to_string(),
+            self.cache_hit_rate.to_string(),
+            self.seed.map_or(String::new(), |seed| seed.0.to_string()),
+        ]
+    }
+}
+
+/// Measurements of how varied the genomes of a generation are, which drop when the population
+/// converges prematurely.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Diversity {
+    /// The mean [hamming] distance between the expanded codes of every pair of genomes, in
+    /// bits.
+    pub mean_distance: f64,
+    /// The amount of distinct behaviors, where the behavior of a genome is the output of its
+    /// program after one step from its initial memory without input.
+    pub unique_behaviors: u64,
+    /// The Shannon entropy in bits of the distribution of instruction kinds over the codes of
+    /// all genomes, with the frequencies of the evaluator. It is 0 when all instructions are of
+    /// the same kind.
+    pub kind_entropy: f64,
+}
+
+impl Diversity {
+    /// Measure the diversity of `genomes`, expanded and run with `evaluator`.
+    ///
+    /// This expands the code of every genome, compares every pair of codes and compiles the
+    /// code of every distinct genome, which takes about as long as evaluating every genome for
+    /// a single step.
+    pub fn measure<G, F, B>(genomes: &[Genome], evaluator: &mut Evaluator<G, F, B>) -> Self
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let codes: Vec<_> = genomes
+            .iter()
+            .map(|genome| evaluator.code(genome))
+            .collect();
+
+        let mut total_distance = 0;
+        for (i, a) in codes.iter().enumerate() {
+            for b in &codes[i + 1..] {
+                total_distance += hamming(a, b);
+            }
+        }
+        let pairs = codes.len() * codes.len().saturating_sub(1) / 2;
+
+        let frequencies = evaluator.frequencies();
+        let mut counts = [0u64; KIND_COUNT];
+        for code in &codes {
+            for &word in code {
+                counts[frequencies.kind_of(word)] += 1;
+            }
+        }
+        let total = counts.iter().sum::<u64>() as f64;
+        let kind_entropy = counts
+            .iter()
+            .filter(|&&count| count > 0)
+            .map(|&count| {
+                let p = count as f64 / total;
+                -p * p.log2()
+            })
+            .sum::<f64>();
+
+        let distinct: HashSet<_> = genomes.iter().collect();
+        let behaviors: HashSet<_> = distinct
+            .into_iter()
+            .map(|genome| {
+                let runner = evaluator.compile(genome);
+                let mut memory = evaluator.memory(genome);
+                runner.step(memory.as_mut_slice());
+                memory.output().to_vec()
+            })
+            .collect();
+
+        Self {
+            mean_distance: if pairs == 0 {
+                0.0
+            } else {
+                total_distance as f64 / pairs as f64
+            },
+            unique_behaviors: behaviors.len() as u64,
+            kind_entropy: kind_entropy.max(0.0),
+        }
+    }
+}
+
+/// Receives the [Metrics] of every generation, for example to push them to a dashboard.
+pub trait MetricsSink {
+    /// Record the metrics of a generation.
+    fn record(&mut self, metrics: &Metrics) -> io::Result<()>;
+}
+
+impl<F> MetricsSink for F
+where
+    F: FnMut(&Metrics) -> io::Result<()>,
+{
+    fn record(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self(metrics)
+    }
+}
+
+impl MetricsSink for Vec<Metrics> {
+    fn record(&mut self, metrics: &Metrics) -> io::Result<()> {
+        self.push(*metrics);
+        Ok(())
+    }
+}
+
+/// Writes metrics as comma separated values, with a header of the
+/// [field names](Metrics::FIELDS) before the first row.
+pub struct CsvSink<W> {
+    writer: W,
+    header_written: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Create a sink that writes to `writer`, which should be buffered.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            header_written: false,
+        }
+    }
+
+    /// Take the writer back, for example to flush it.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> MetricsSink for CsvSink<W> {
+    fn record(&mut self, metrics: &Metrics) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(self.writer, "{}", Metrics::FIELDS.join(","))?;
+            self.header_written = true;
+        }
+
+        writeln!(self.writer, "{}", metrics.values().join(","))
+    }
+}
+
+/// Writes metrics as one JSON object per line. Non-finite values such as an unknown
+/// diversity are written as `null`.
+pub struct JsonLinesSink<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    /// Create a sink that writes to `writer`, which should be buffered.
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    /// Take the writer back, for example to flush it.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> MetricsSink for JsonLinesSink<W> {
+    fn record(&mut self, metrics: &Metrics) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, metrics)?;
+        writeln!(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::fill_mutate_bits;
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+    use rand_pcg::Pcg64;
+
+    fn metrics(generation: u64) -> Metrics {
+        let stats = GenerationStats {
+            generation,
+            best: Genome::new(0),
+            best_fitness: 3.0,
+            mean_fitness: 1.5,
+            median_fitness: 1.0,
+            std_fitness: 0.5,
+        };
+        Metrics::new(&stats)
+    }
+
+    #[test]
+    fn diversity() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness = |_: &dyn Runner, _: &mut MemoryBuffer, _: &mut Pcg64| 0.0;
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, fitness);
+
+        let clones = [Genome::new(1), Genome::new(1)];
+        let diversity = Diversity::measure(&clones, &mut evaluator);
+        assert_eq!(diversity.mean_distance, 0.0);
+        assert_eq!(diversity.unique_behaviors, 1);
+        assert!(diversity.kind_entropy > 0.0);
+
+        let varied: Vec<_> = (0..8).map(Genome::new).collect();
+        let diversity = Diversity::measure(&varied, &mut evaluator);
+        // Random codes differ in about half of their bits
+        assert!((diversity.mean_distance - 32.0 * 64.0).abs() < 200.0);
+        assert!((1..=8).contains(&diversity.unique_behaviors));
+    }
+
+    #[test]
+    fn csv() {
+        let mut sink = CsvSink::new(vec![]);
+        sink.record(&metrics(0)).unwrap();
+        sink.record(&metrics(1)).unwrap();
+
+        let csv = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("generation,best_fitness"));
+        assert_eq!(lines[2], "1,3,1.5,1,0.5,NaN,NaN,,NaN,0,0,0,");
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut sink = JsonLinesSink::new(vec![]);
+        sink.record(&metrics(4).with_seed(RunSeed(u64::MAX)))
+            .unwrap();
+
+        let json = String::from_utf8(sink.into_inner()).unwrap();
+        assert!(json.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["generation"], 4);
+        assert_eq!(value["diversity"], serde_json::Value::Null);
+        assert_eq!(value["unique_behaviors"], serde_json::Value::Null);
+        // Seeds are written as integers, which do not lose precision like floats
+        assert_eq!(value["seed"], u64::MAX);
+    }
+}
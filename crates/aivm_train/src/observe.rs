@@ -0,0 +1,70 @@
+use crate::evolution::{Champion, GenerationStats};
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Gets notified of the progress of training, for example to print it or show it in a user
+/// interface. Every method does nothing by default.
+///
+/// An observer is set on a [Population](crate::evolution::Population) with
+/// [set_observer](crate::evolution::Population::set_observer). To keep observing it from
+/// elsewhere, share it in an `Arc<Mutex<_>>`, which is an observer too.
+pub trait Observer {
+    /// A generation is about to be evaluated.
+    fn generation_start(&mut self, generation: u64) {
+        let _ = generation;
+    }
+
+    /// A generation was evaluated and the next one bred.
+    fn generation_end(&mut self, stats: &GenerationStats) {
+        let _ = stats;
+    }
+
+    /// A genome reached a higher fitness than any before it in the run.
+    fn new_champion(&mut self, champion: &Champion) {
+        let _ = champion;
+    }
+
+    /// A checkpoint of `generation` was saved to `path`.
+    fn checkpoint(&mut self, path: &Path, generation: u64) {
+        let _ = (path, generation);
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for Box<O> {
+    fn generation_start(&mut self, generation: u64) {
+        (**self).generation_start(generation);
+    }
+
+    fn generation_end(&mut self, stats: &GenerationStats) {
+        (**self).generation_end(stats);
+    }
+
+    fn new_champion(&mut self, champion: &Champion) {
+        (**self).new_champion(champion);
+    }
+
+    fn checkpoint(&mut self, path: &Path, generation: u64) {
+        (**self).checkpoint(path, generation);
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for Arc<Mutex<O>> {
+    fn generation_start(&mut self, generation: u64) {
+        self.lock().unwrap().generation_start(generation);
+    }
+
+    fn generation_end(&mut self, stats: &GenerationStats) {
+        self.lock().unwrap().generation_end(stats);
+    }
+
+    fn new_champion(&mut self, champion: &Champion) {
+        self.lock().unwrap().new_champion(champion);
+    }
+
+    fn checkpoint(&mut self, path: &Path, generation: u64) {
+        self.lock().unwrap().checkpoint(path, generation);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    evolution::{Champion, Genome, MutateBits, Mutation},
+    fitness::{Evaluator, Fitness},
+    seed::SplitRng,
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+
+/// Simulated annealing of a single genome: every step mutates the current genome, and the
+/// mutant replaces it if it is fitter, or with a probability that shrinks as the temperature
+/// cools down if it is not.
+///
+/// This is a cheap baseline to compare evolution with, and a way to refine an evolved
+/// champion locally.
+pub struct SimulatedAnnealing {
+    current: Genome,
+    current_fitness: Option<f64>,
+    best: Option<Champion>,
+    temperature: f64,
+    cooling: f64,
+    rng: SplitRng,
+}
+
+impl SimulatedAnnealing {
+    /// Start from `genome` at `temperature`, which cools down by a factor of 0.99 every step.
+    /// All randomness is derived from `seed`.
+    ///
+    /// The temperature is in units of fitness: a mutant that is worse by the temperature is
+    /// accepted with a probability of `1 / e`.
+    ///
+    /// # Panics
+    /// If `temperature` is negative or NaN.
+    pub fn new(genome: Genome, temperature: f64, seed: u64) -> Self {
+        assert!(temperature >= 0.0, "invalid temperature {temperature}");
+        Self {
+            current: genome,
+            current_fitness: None,
+            best: None,
+            temperature,
+            cooling: 0.99,
+            rng: SplitRng::new(seed),
+        }
+    }
+
+    /// The same annealing, but multiplying the temperature by `cooling` every step.
+    ///
+    /// # Panics
+    /// If `cooling` is not in `0.0..=1.0`.
+    pub fn with_cooling(self, cooling: f64) -> Self {
+        assert!((0.0..=1.0).contains(&cooling), "invalid cooling factor");
+        Self { cooling, ..self }
+    }
+
+    /// The genome the search is currently at.
+    pub fn current(&self) -> &Genome {
+        &self.current
+    }
+
+    /// The fitness of the current genome, once it has been evaluated.
+    pub fn current_fitness(&self) -> Option<f64> {
+        self.current_fitness
+    }
+
+    /// The fittest genome evaluated so far, if any.
+    pub fn best(&self) -> Option<&Champion> {
+        self.best.as_ref()
+    }
+
+    /// The current temperature.
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    /// Evaluate a mutant of the current genome with `evaluator`, decide whether it replaces
+    /// the current genome and cool down. Returns whether the mutant was accepted.
+    ///
+    /// The first step also evaluates the starting genome.
+    pub fn step<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        mutation: &mut impl Mutation,
+    ) -> bool
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let current_fitness = match self.current_fitness {
+            Some(fitness) => fitness,
+            None => {
+                let fitness = self.evaluate(evaluator, &self.current.clone());
+                self.current_fitness = Some(fitness);
+                fitness
+            }
+        };
+
+        let mutant = mutation.mutate(&self.current, self.rng.rng());
+        let fitness = self.evaluate(evaluator, &mutant);
+        // NaN fitness is never accepted
+        let accept = fitness >= current_fitness
+            || self.rng.gen::<f64>() < ((fitness - current_fitness) / self.temperature).exp();
+        if accept {
+            self.current = mutant;
+            self.current_fitness = Some(fitness);
+        }
+        self.temperature *= self.cooling;
+
+        accept
+    }
+
+    fn evaluate<G, F, B>(&mut self, evaluator: &mut Evaluator<G, F, B>, genome: &Genome) -> f64
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let fitness = evaluator.evaluate(genome, &mut Pcg64::seed_from_u64(self.rng.split_seed()));
+        if !fitness.is_nan() && self.best.as_ref().is_none_or(|best| fitness > best.fitness) {
+            self.best = Some(Champion {
+                genome: genome.clone(),
+                fitness,
+            });
+        }
+        fitness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, AppendSeed};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    #[test]
+    fn anneal() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness = |runner: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| {
+            runner.step(memory.as_mut_slice());
+            -(memory.output()[0] as f64 - 1000.0).abs()
+        };
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, fitness);
+
+        let mut annealing = SimulatedAnnealing::new(Genome::new(3), 100.0, 1).with_cooling(0.9);
+        let mut accepted = 0;
+        for _ in 0..50 {
+            accepted += u32::from(annealing.step(&mut evaluator, &mut AppendSeed));
+        }
+
+        assert_eq!(annealing.current().mutation_seeds.len(), accepted as usize);
+        assert!(annealing.temperature() < 1.0);
+        let best = annealing.best().unwrap();
+        assert!(best.fitness >= annealing.current_fitness().unwrap());
+        assert_eq!(
+            evaluator.evaluate(&best.genome, &mut Pcg64::seed_from_u64(0)),
+            best.fitness
+        );
+    }
+}
@@ -0,0 +1,441 @@
+use super::gaussian;
+use crate::{
+    evolution::MutateBits,
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, fixed::Fixed, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+
+/// The covariance matrix adaptation evolution strategy, for fine tuning the memory of a program
+/// whose code is frozen, such as the champion of an evolution run.
+///
+/// The memory values are treated as fixed-point numbers, which are sampled from a multivariate
+/// normal distribution. Its mean moves towards the fittest samples, and its covariance adapts
+/// to the shape of the fitness landscape, so correlated values are tuned together.
+///
+/// When the search converges, or the fitness stops telling samples apart, it restarts from the
+/// best values found so far with a population twice as large (IPOP-CMA-ES), up to a maximum
+/// amount of restarts.
+pub struct CmaEs {
+    fixed: Fixed,
+    initial_sigma: f64,
+    max_restarts: u32,
+    restarts: u32,
+    best: Option<(Vec<f64>, f64)>,
+    state: State,
+    rng: Pcg64,
+}
+
+struct State {
+    n: usize,
+    lambda: usize,
+    weights: Vec<f64>,
+    mueff: f64,
+    cc: f64,
+    cs: f64,
+    c1: f64,
+    cmu: f64,
+    damps: f64,
+    chin: f64,
+    mean: Vec<f64>,
+    sigma: f64,
+    pc: Vec<f64>,
+    ps: Vec<f64>,
+    /// The covariance matrix, row major.
+    c: Vec<f64>,
+    /// The eigenvectors of the covariance matrix as columns, row major.
+    b: Vec<f64>,
+    /// The square roots of the eigenvalues of the covariance matrix.
+    d: Vec<f64>,
+    generation: u64,
+    eigen_generation: u64,
+}
+
+impl CmaEs {
+    /// Start from the memory `initial`, interpreted with `fixed`, sampling with a standard
+    /// deviation of `sigma`. All randomness is derived from `seed`.
+    ///
+    /// The population size defaults to `4 + 3 ln(n)` for `n` memory values, and the search
+    /// restarts up to 4 times.
+    ///
+    /// # Panics
+    /// If `initial` is empty or `sigma` is not a positive, finite number.
+    pub fn new(initial: &[i64], fixed: Fixed, sigma: f64, seed: u64) -> Self {
+        assert!(!initial.is_empty(), "no values to optimize");
+        assert!(sigma.is_finite() && sigma > 0.0, "invalid sigma {sigma}");
+        let mut mean = vec![0.0; initial.len()];
+        fixed.decode_f64(initial, &mut mean);
+        let lambda = 4 + (3.0 * (initial.len() as f64).ln()) as usize;
+
+        Self {
+            fixed,
+            initial_sigma: sigma,
+            max_restarts: 4,
+            restarts: 0,
+            best: None,
+            state: State::new(mean, sigma, lambda),
+            rng: Pcg64::seed_from_u64(seed),
+        }
+    }
+
+    /// The same optimizer, but sampling `lambda` points every generation before the first
+    /// restart.
+    ///
+    /// # Panics
+    /// If `lambda` is smaller than 2.
+    pub fn with_population_size(self, lambda: usize) -> Self {
+        assert!(lambda >= 2, "population too small");
+        let state = State::new(self.state.mean.clone(), self.initial_sigma, lambda);
+        Self { state, ..self }
+    }
+
+    /// The same optimizer, but restarting at most `max_restarts` times.
+    pub fn with_max_restarts(self, max_restarts: u32) -> Self {
+        Self {
+            max_restarts,
+            ..self
+        }
+    }
+
+    /// The mean of the sampling distribution, as floats.
+    pub fn mean(&self) -> &[f64] {
+        &self.state.mean
+    }
+
+    /// The current step size.
+    pub fn sigma(&self) -> f64 {
+        self.state.sigma
+    }
+
+    /// The amount of points sampled every generation.
+    pub fn population_size(&self) -> usize {
+        self.state.lambda
+    }
+
+    /// The amount of restarts so far.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// The highest fitness found so far, if any generation was evaluated.
+    pub fn best_fitness(&self) -> Option<f64> {
+        self.best.as_ref().map(|(_, fitness)| *fitness)
+    }
+
+    /// The memory with the highest fitness found so far, if any generation was evaluated.
+    pub fn best_memory(&self) -> Option<Vec<i64>> {
+        self.best.as_ref().map(|(params, _)| {
+            let mut memory = vec![0; params.len()];
+            self.fixed.encode_f64(params, &mut memory);
+            memory
+        })
+    }
+
+    /// Sample and evaluate a generation by running `runner` with `evaluator`, and adapt the
+    /// distribution to it. Returns the highest fitness of the generation.
+    ///
+    /// All points of a generation are evaluated with the same random numbers, so the
+    /// differences between them are caused by the values and not by the environment.
+    ///
+    /// # Panics
+    /// If the memory does not have the size of the memory bank of `evaluator`.
+    pub fn step<G, F, B>(&mut self, evaluator: &mut Evaluator<G, F, B>, runner: &dyn Runner) -> f64
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let state = &mut self.state;
+        let n = state.n;
+        let eval_seed = self.rng.gen();
+        let mut memory = vec![0; n];
+
+        let mut samples: Vec<(Vec<f64>, Vec<f64>, f64)> = (0..state.lambda)
+            .map(|_| {
+                let z: Vec<_> = (0..n).map(|_| gaussian(&mut self.rng)).collect();
+                let y: Vec<_> = (0..n)
+                    .map(|i| (0..n).map(|j| state.b[i * n + j] * state.d[j] * z[j]).sum())
+                    .collect();
+                let x: Vec<_> = state
+                    .mean
+                    .iter()
+                    .zip(&y)
+                    .map(|(m, y)| m + state.sigma * y)
+                    .collect();
+                self.fixed.encode_f64(&x, &mut memory);
+                let fitness = evaluator.evaluate_memory(
+                    runner,
+                    &memory,
+                    &mut Pcg64::seed_from_u64(eval_seed),
+                );
+                (x, y, fitness)
+            })
+            .collect();
+        samples.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let best = samples[0].2;
+        if self
+            .best
+            .as_ref()
+            .is_none_or(|(_, fitness)| best > *fitness)
+        {
+            self.best = Some((samples[0].0.clone(), best));
+        }
+        let flat = best == samples[samples.len() - 1].2;
+
+        state.update(&samples);
+        let converged =
+            state.sigma * state.d.iter().copied().fold(0.0, f64::max) < 1e-12 * self.initial_sigma;
+        let d_max = state.d.iter().copied().fold(0.0, f64::max);
+        let d_min = state.d.iter().copied().fold(f64::INFINITY, f64::min);
+        let ill_conditioned = d_max > 1e7 * d_min;
+        if (flat || converged || ill_conditioned || !state.sigma.is_finite())
+            && self.restarts < self.max_restarts
+        {
+            self.restarts += 1;
+            let mean = self.best.as_ref().unwrap().0.clone();
+            *state = State::new(mean, self.initial_sigma, 2 * state.lambda);
+        }
+
+        best
+    }
+}
+
+impl State {
+    fn new(mean: Vec<f64>, sigma: f64, lambda: usize) -> Self {
+        let n = mean.len();
+        let nf = n as f64;
+        let mu = lambda / 2;
+        let weights: Vec<_> = (1..=mu)
+            .map(|i| (mu as f64 + 0.5).ln() - (i as f64).ln())
+            .collect();
+        let total: f64 = weights.iter().sum();
+        let weights: Vec<_> = weights.iter().map(|w| w / total).collect();
+        let mueff = 1.0 / weights.iter().map(|w| w * w).sum::<f64>();
+
+        let cc = (4.0 + mueff / nf) / (nf + 4.0 + 2.0 * mueff / nf);
+        let cs = (mueff + 2.0) / (nf + mueff + 5.0);
+        let c1 = 2.0 / ((nf + 1.3).powi(2) + mueff);
+        let cmu = (1.0 - c1).min(2.0 * (mueff - 2.0 + 1.0 / mueff) / ((nf + 2.0).powi(2) + mueff));
+        let damps = 1.0 + 2.0 * (((mueff - 1.0) / (nf + 1.0)).sqrt() - 1.0).max(0.0) + cs;
+        let chin = nf.sqrt() * (1.0 - 1.0 / (4.0 * nf) + 1.0 / (21.0 * nf * nf));
+
+        let identity: Vec<_> = (0..n * n)
+            .map(|i| if i % (n + 1) == 0 { 1.0 } else { 0.0 })
+            .collect();
+        Self {
+            n,
+            lambda,
+            weights,
+            mueff,
+            cc,
+            cs,
+            c1,
+            cmu,
+            damps,
+            chin,
+            mean,
+            sigma,
+            pc: vec![0.0; n],
+            ps: vec![0.0; n],
+            c: identity.clone(),
+            b: identity,
+            d: vec![1.0; n],
+            generation: 0,
+            eigen_generation: 0,
+        }
+    }
+
+    /// Adapt the distribution to samples of `(x, y, fitness)` sorted from high to low fitness,
+    /// where `x = mean + sigma * y`.
+    fn update(&mut self, samples: &[(Vec<f64>, Vec<f64>, f64)]) {
+        let n = self.n;
+        let selected = &samples[..self.weights.len()];
+        let mut y_w = vec![0.0; n];
+        for ((_, y, _), &w) in selected.iter().zip(&self.weights) {
+            for (y_w, y) in y_w.iter_mut().zip(y) {
+                *y_w += w * y;
+            }
+        }
+        for (m, y_w) in self.mean.iter_mut().zip(&y_w) {
+            *m += self.sigma * y_w;
+        }
+
+        // C^(-1/2) * y_w = B * D^-1 * B^T * y_w
+        let bt_y: Vec<_> = (0..n)
+            .map(|j| (0..n).map(|i| self.b[i * n + j] * y_w[i]).sum::<f64>() / self.d[j])
+            .collect();
+        let ps_scale = (self.cs * (2.0 - self.cs) * self.mueff).sqrt();
+        for i in 0..n {
+            let c_y: f64 = (0..n).map(|j| self.b[i * n + j] * bt_y[j]).sum();
+            self.ps[i] = (1.0 - self.cs) * self.ps[i] + ps_scale * c_y;
+        }
+
+        self.generation += 1;
+        let ps_norm = self.ps.iter().map(|p| p * p).sum::<f64>().sqrt();
+        let hsig =
+            ps_norm / (1.0 - (1.0 - self.cs).powf(2.0 * self.generation as f64)).sqrt() / self.chin
+                < 1.4 + 2.0 / (n as f64 + 1.0);
+        let pc_scale = f64::from(u8::from(hsig)) * (self.cc * (2.0 - self.cc) * self.mueff).sqrt();
+        for (pc, y_w) in self.pc.iter_mut().zip(&y_w) {
+            *pc = (1.0 - self.cc) * *pc + pc_scale * y_w;
+        }
+
+        let correction = f64::from(u8::from(!hsig)) * self.cc * (2.0 - self.cc);
+        for i in 0..n {
+            for j in 0..=i {
+                let rank_mu: f64 = selected
+                    .iter()
+                    .zip(&self.weights)
+                    .map(|((_, y, _), w)| w * y[i] * y[j])
+                    .sum();
+                let value = (1.0 - self.c1 - self.cmu) * self.c[i * n + j]
+                    + self.c1 * (self.pc[i] * self.pc[j] + correction * self.c[i * n + j])
+                    + self.cmu * rank_mu;
+                self.c[i * n + j] = value;
+                self.c[j * n + i] = value;
+            }
+        }
+
+        self.sigma *= ((self.cs / self.damps) * (ps_norm / self.chin - 1.0)).exp();
+
+        // Decomposing is O(n^3), so it is only done often enough to follow the adaptation
+        let interval = (self.lambda as f64 / ((self.c1 + self.cmu) * n as f64 * 10.0)).max(1.0);
+        if (self.generation - self.eigen_generation) as f64 >= interval {
+            self.eigen_generation = self.generation;
+            let (eigenvalues, eigenvectors) = jacobi_eigen(&self.c, n);
+            self.d = eigenvalues.iter().map(|e| e.max(1e-300).sqrt()).collect();
+            self.b = eigenvectors;
+        }
+    }
+}
+
+/// The eigenvalues and eigenvectors, as the columns of a row major matrix, of the symmetric
+/// row major `n` by `n` matrix `a`, using the cyclic Jacobi method.
+fn jacobi_eigen(a: &[f64], n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut a = a.to_vec();
+    let mut v: Vec<_> = (0..n * n)
+        .map(|i| if i % (n + 1) == 0 { 1.0 } else { 0.0 })
+        .collect();
+
+    for _ in 0..100 {
+        let off: f64 = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i * n + j] * a[i * n + j])
+            .sum();
+        if off < 1e-30 {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq.abs() < 1e-300 {
+                    continue;
+                }
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for k in 0..n {
+                    let akp = a[k * n + p];
+                    let akq = a[k * n + q];
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let apk = a[p * n + k];
+                    let aqk = a[q * n + k];
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let vkp = v[k * n + p];
+                    let vkq = v[k * n + q];
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ((0..n).map(|i| a[i * n + i]).collect(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, Genome};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    #[test]
+    fn eigen() {
+        let (values, vectors) = jacobi_eigen(&[2.0, 1.0, 1.0, 2.0], 2);
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+        assert!((sorted[0] - 1.0).abs() < 1e-12 && (sorted[1] - 3.0).abs() < 1e-12);
+        // A * v = lambda * v for every column
+        for (k, &value) in values.iter().enumerate() {
+            let (x, y) = (vectors[k], vectors[2 + k]);
+            assert!((2.0 * x + y - value * x).abs() < 1e-12);
+            assert!((x + 2.0 * y - value * y).abs() < 1e-12);
+        }
+    }
+
+    fn evaluator(fitness: fn(&[f64]) -> f64) -> Evaluator<Interpreter, impl Fitness> {
+        let fitness = move |_: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| {
+            let mut values = [0.0; 4];
+            Fixed::with_frac_bits(24).decode_f64(memory.memory(), &mut values);
+            fitness(&values)
+        };
+        let mut mutate_bits = vec![0; 64];
+        fill_mutate_bits(&mut mutate_bits, 1, 1000);
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        Evaluator::new(compiler, layout, 16, mutate_bits, fitness)
+    }
+
+    #[test]
+    fn ellipsoid() {
+        // Badly scaled and correlated, which plain evolution strategies struggle with
+        let mut evaluator = evaluator(|v| {
+            let a = v[0] + v[1] - 1.0;
+            let b = v[0] - v[1];
+            -(a * a + 100.0 * b * b + (v[2] - 2.0).powi(2) + 1000.0 * (v[3] + 0.5).powi(2))
+        });
+        let runner = evaluator.compile(&Genome::new(1));
+
+        let fixed = Fixed::with_frac_bits(24);
+        let mut cma = CmaEs::new(&[0; 4], fixed, 0.5, 3).with_max_restarts(0);
+        for _ in 0..200 {
+            cma.step(&mut evaluator, &runner);
+        }
+
+        let mut best = [0.0; 4];
+        fixed.decode_f64(&cma.best_memory().unwrap(), &mut best);
+        for (value, target) in best.iter().zip([0.5, 0.5, 2.0, -0.5]) {
+            assert!((value - target).abs() < 1e-3, "{value} != {target}");
+        }
+        assert!(cma.best_fitness().unwrap() > -1e-6);
+    }
+
+    #[test]
+    fn restart() {
+        let mut evaluator = evaluator(|_| 1.0);
+        let runner = evaluator.compile(&Genome::new(1));
+
+        let mut cma = CmaEs::new(&[0; 4], Fixed::with_frac_bits(24), 1.0, 3)
+            .with_population_size(6)
+            .with_max_restarts(2);
+        for _ in 0..3 {
+            cma.step(&mut evaluator, &runner);
+        }
+        assert_eq!(cma.restarts(), 2);
+        assert_eq!(cma.population_size(), 24);
+        assert_eq!(cma.best_fitness(), Some(1.0));
+    }
+}
@@ -0,0 +1,190 @@
+use super::gaussian;
+use crate::{
+    evolution::MutateBits,
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, fixed::Fixed, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+
+/// An evolution strategy in the style of OpenAI-ES, which keeps the code of a program fixed and
+/// tunes its initial memory.
+///
+/// The memory values are treated as fixed-point numbers. Every step, the fitness is estimated
+/// at pairs of points mirrored around the current values, and the values move in the direction
+/// of the ranks of those fitness values. Using ranks makes the steps independent of the scale
+/// of the fitness. This is much cheaper than evolving whole genomes when only the "weights" in
+/// the memory need tuning.
+pub struct EvolutionStrategy {
+    params: Vec<f64>,
+    fixed: Fixed,
+    sigma: f64,
+    learning_rate: f64,
+    pairs: usize,
+    rng: Pcg64,
+}
+
+impl EvolutionStrategy {
+    /// Start from the memory `initial`, interpreted with `fixed`. All randomness is derived
+    /// from `seed`.
+    ///
+    /// By default 8 pairs of points are sampled every step, with a noise of 0.1 and a learning
+    /// rate of 0.01.
+    pub fn new(initial: &[i64], fixed: Fixed, seed: u64) -> Self {
+        let mut params = vec![0.0; initial.len()];
+        fixed.decode_f64(initial, &mut params);
+
+        Self {
+            params,
+            fixed,
+            sigma: 0.1,
+            learning_rate: 0.01,
+            pairs: 8,
+            rng: Pcg64::seed_from_u64(seed),
+        }
+    }
+
+    /// The same optimizer, but with a standard deviation of `sigma` for the noise added to
+    /// the values.
+    ///
+    /// # Panics
+    /// If `sigma` is not a positive, finite number.
+    pub fn with_sigma(self, sigma: f64) -> Self {
+        assert!(sigma.is_finite() && sigma > 0.0, "invalid sigma {sigma}");
+        Self { sigma, ..self }
+    }
+
+    /// The same optimizer, but with the given learning rate.
+    pub fn with_learning_rate(self, learning_rate: f64) -> Self {
+        Self {
+            learning_rate,
+            ..self
+        }
+    }
+
+    /// The same optimizer, but evaluating `pairs` mirrored pairs of points every step.
+    ///
+    /// # Panics
+    /// If `pairs` is 0.
+    pub fn with_pairs(self, pairs: usize) -> Self {
+        assert!(pairs > 0, "no pairs to sample");
+        Self { pairs, ..self }
+    }
+
+    /// The current values, as floats.
+    pub fn params(&self) -> &[f64] {
+        &self.params
+    }
+
+    /// The current values, as memory.
+    pub fn memory(&self) -> Vec<i64> {
+        let mut memory = vec![0; self.params.len()];
+        self.fixed.encode_f64(&self.params, &mut memory);
+        memory
+    }
+
+    /// Estimate the gradient around the current values by running `runner` with `evaluator`,
+    /// and take a step along it. Returns the mean fitness of the sampled points.
+    ///
+    /// All points of a step are evaluated with the same random numbers, so the differences
+    /// between them are caused by the values and not by the environment.
+    ///
+    /// # Panics
+    /// If the memory does not have the size of the memory bank of `evaluator`.
+    pub fn step<G, F, B>(&mut self, evaluator: &mut Evaluator<G, F, B>, runner: &dyn Runner) -> f64
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let eval_seed = self.rng.gen();
+        let mut memory = vec![0; self.params.len()];
+        let mut point = vec![0.0; self.params.len()];
+        let mut evaluate = |params: &[f64], sign: f64, noise: &[f64]| {
+            for ((p, &param), &n) in point.iter_mut().zip(params).zip(noise) {
+                *p = param + sign * self.sigma * n;
+            }
+            self.fixed.encode_f64(&point, &mut memory);
+            evaluator.evaluate_memory(runner, &memory, &mut Pcg64::seed_from_u64(eval_seed))
+        };
+
+        let mut noises = Vec::with_capacity(self.pairs);
+        let mut fitness = Vec::with_capacity(2 * self.pairs);
+        for _ in 0..self.pairs {
+            let noise: Vec<_> = (0..self.params.len())
+                .map(|_| gaussian(&mut self.rng))
+                .collect();
+            fitness.push(evaluate(&self.params, 1.0, &noise));
+            fitness.push(evaluate(&self.params, -1.0, &noise));
+            noises.push(noise);
+        }
+
+        let ranks = centered_ranks(&fitness);
+        let scale = self.learning_rate / (2.0 * self.pairs as f64 * self.sigma);
+        for (noise, ranks) in noises.iter().zip(ranks.chunks(2)) {
+            let weight = scale * (ranks[0] - ranks[1]);
+            for (param, &n) in self.params.iter_mut().zip(noise) {
+                *param += weight * n;
+            }
+        }
+
+        fitness.iter().sum::<f64>() / fitness.len() as f64
+    }
+}
+
+/// The rank of every value, scaled to `-0.5..=0.5` from the lowest to the highest value.
+fn centered_ranks(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<_> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    let mut ranks = vec![0.0; values.len()];
+    let max_rank = (values.len() - 1).max(1) as f64;
+    for (rank, i) in order.into_iter().enumerate() {
+        ranks[i] = rank as f64 / max_rank - 0.5;
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, Genome};
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    #[test]
+    fn ranks() {
+        assert_eq!(centered_ranks(&[3.0, -1.0, 7.0]), [0.0, -0.5, 0.5]);
+    }
+
+    #[test]
+    fn converge() {
+        const TARGET: [f64; 3] = [1.0, -2.0, 0.5];
+        let fixed = Fixed::with_frac_bits(16);
+        let fitness = move |_: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| {
+            let mut values = [0.0; 3];
+            fixed.decode_f64(memory.memory(), &mut values);
+            -values
+                .iter()
+                .zip(TARGET)
+                .map(|(v, t)| (v - t) * (v - t))
+                .sum::<f64>()
+        };
+
+        let mut mutate_bits = vec![0; 64];
+        fill_mutate_bits(&mut mutate_bits, 1, 1000);
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(3, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 16, mutate_bits, fitness);
+        let runner = evaluator.compile(&Genome::new(1));
+
+        let mut es = EvolutionStrategy::new(&[0; 3], fixed, 7).with_learning_rate(0.05);
+        for _ in 0..300 {
+            es.step(&mut evaluator, &runner);
+        }
+        for (param, target) in es.params().iter().zip(TARGET) {
+            assert!((param - target).abs() < 0.2, "{param} != {target}");
+        }
+        assert_eq!(es.memory().len(), 3);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::{
+    evolution::MutateBits,
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, fixed::Fixed, MemoryBuffer, Runner};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+
+/// The gradients of the outputs of one step of `runner` with respect to every memory slot,
+/// estimated with central differences of `delta` around the memory of `memory`.
+///
+/// Memory and outputs are treated as fixed-point numbers of `fixed`, and `delta` is in the same
+/// units. Element `[slot][output]` of the result is the change of the output per change of the
+/// memory slot, divided by the distance of the points after rounding them to fixed point, or
+/// 0 if they round to the same value. `memory` itself is not changed.
+///
+/// # Panics
+/// If `delta` is not a positive, finite number.
+pub fn output_gradients(
+    runner: &dyn Runner,
+    memory: &MemoryBuffer,
+    fixed: Fixed,
+    delta: f64,
+) -> Vec<Vec<f64>> {
+    assert!(delta.is_finite() && delta > 0.0, "invalid delta {delta}");
+    // The outputs and the value of the slot after rounding it to fixed point
+    let outputs = |slot: usize, offset: f64| {
+        let mut buffer = memory.clone();
+        let value = fixed.from_f64(fixed.to_f64(buffer.memory()[slot]) + offset);
+        buffer.memory_mut()[slot] = value;
+        runner.step(buffer.as_mut_slice());
+        let mut outputs = vec![0.0; buffer.output().len()];
+        fixed.decode_f64(buffer.output(), &mut outputs);
+        (outputs, fixed.to_f64(value))
+    };
+
+    (0..memory.memory().len())
+        .map(|slot| {
+            let ((up, high), (down, low)) = (outputs(slot, delta), outputs(slot, -delta));
+            up.iter()
+                .zip(down)
+                .map(|(up, down)| {
+                    if high > low {
+                        (up - down) / (high - low)
+                    } else {
+                        0.0
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Gradient ascent on the initial memory of a program with a fixed code, with the gradient of
+/// the fitness estimated by central differences.
+///
+/// Every step evaluates the fitness twice for every tuned memory slot, with the slot moved
+/// up and down by the delta, and moves all slots along the estimated gradient. Unlike an
+/// [EvolutionStrategy](super::EvolutionStrategy) the estimate is exact for smooth fitness,
+/// but it takes as many evaluations as there are slots, so it suits the fine tuning of a
+/// few constants of an evolved champion.
+pub struct FiniteDifference {
+    params: Vec<f64>,
+    fixed: Fixed,
+    delta: f64,
+    learning_rate: f64,
+    slots: Vec<usize>,
+    rng: Pcg64,
+}
+
+impl FiniteDifference {
+    /// Start from the memory `initial`, interpreted with `fixed`, tuning every slot. All
+    /// randomness is derived from `seed`.
+    ///
+    /// By default the delta is 0.01 and the learning rate 0.01.
+    pub fn new(initial: &[i64], fixed: Fixed, seed: u64) -> Self {
+        let mut params = vec![0.0; initial.len()];
+        fixed.decode_f64(initial, &mut params);
+
+        Self {
+            slots: (0..params.len()).collect(),
+            params,
+            fixed,
+            delta: 0.01,
+            learning_rate: 0.01,
+            rng: Pcg64::seed_from_u64(seed),
+        }
+    }
+
+    /// The same optimizer, but moving the slots by `delta` to estimate the gradient.
+    ///
+    /// # Panics
+    /// If `delta` is not a positive, finite number.
+    pub fn with_delta(self, delta: f64) -> Self {
+        assert!(delta.is_finite() && delta > 0.0, "invalid delta {delta}");
+        Self { delta, ..self }
+    }
+
+    /// The same optimizer, but with the given learning rate.
+    pub fn with_learning_rate(self, learning_rate: f64) -> Self {
+        Self {
+            learning_rate,
+            ..self
+        }
+    }
+
+    /// The same optimizer, but only tuning the memory slots in `slots`.
+    ///
+    /// # Panics
+    /// If a slot is outside of the memory.
+    pub fn with_slots(self, slots: &[usize]) -> Self {
+        assert!(
+            slots.iter().all(|&slot| slot < self.params.len()),
+            "slot outside of the memory"
+        );
+        Self {
+            slots: slots.to_vec(),
+            ..self
+        }
+    }
+
+    /// The current values, as floats.
+    pub fn params(&self) -> &[f64] {
+        &self.params
+    }
+
+    /// The current values, as memory.
+    pub fn memory(&self) -> Vec<i64> {
+        let mut memory = vec![0; self.params.len()];
+        self.fixed.encode_f64(&self.params, &mut memory);
+        memory
+    }
+
+    /// Estimate the gradient of the fitness around the current values by running `runner`
+    /// with `evaluator`, indexed like the memory. Slots that are not tuned, or whose points
+    /// round to the same fixed-point value, get 0.
+    ///
+    /// All points are evaluated with the same random numbers, so the differences between them
+    /// are caused by the values and not by the environment.
+    ///
+    /// # Panics
+    /// If the memory does not have the size of the memory bank of `evaluator`.
+    pub fn gradient<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        runner: &dyn Runner,
+    ) -> Vec<f64>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let eval_seed = self.rng.gen();
+        let mut point = self.params.clone();
+        let mut memory = vec![0; self.params.len()];
+        let mut evaluate = |point: &[f64]| {
+            self.fixed.encode_f64(point, &mut memory);
+            evaluator.evaluate_memory(runner, &memory, &mut Pcg64::seed_from_u64(eval_seed))
+        };
+
+        // The distance between the points after rounding them to fixed point
+        let fixed = self.fixed;
+        let width = |param: f64| {
+            fixed.to_f64(fixed.from_f64(param + self.delta))
+                - fixed.to_f64(fixed.from_f64(param - self.delta))
+        };
+
+        let mut gradient = vec![0.0; self.params.len()];
+        for &slot in &self.slots {
+            point[slot] = self.params[slot] + self.delta;
+            let up = evaluate(&point);
+            point[slot] = self.params[slot] - self.delta;
+            let down = evaluate(&point);
+            point[slot] = self.params[slot];
+            let width = width(self.params[slot]);
+            if width > 0.0 {
+                gradient[slot] = (up - down) / width;
+            }
+        }
+        gradient
+    }
+
+    /// Estimate the [gradient](Self::gradient) and take a step along it, times the learning
+    /// rate. Returns the gradient.
+    ///
+    /// # Panics
+    /// If the memory does not have the size of the memory bank of `evaluator`.
+    pub fn step<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        runner: &dyn Runner,
+    ) -> Vec<f64>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let gradient = self.gradient(evaluator, runner);
+        for (param, g) in self.params.iter_mut().zip(&gradient) {
+            *param += self.learning_rate * g;
+        }
+        gradient
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::{fill_mutate_bits, Genome};
+    use aivm::{codegen::Interpreter, Compiler, MemoryLayout};
+
+    /// A fitness that only depends on the memory, highest at 2.0 for the first slot.
+    fn parabola(_: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64) -> f64 {
+        let x = Fixed::with_frac_bits(16).to_f64(memory.memory()[0]);
+        -(x - 2.0).powi(2)
+    }
+
+    #[test]
+    fn ascent() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(2, 1, 0);
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = Evaluator::new(compiler, layout, 32, mutate_bits, parabola);
+        let runner = evaluator.compile(&Genome::new(1));
+
+        let mut optimizer = FiniteDifference::new(&[0, 0], Fixed::with_frac_bits(16), 3)
+            .with_learning_rate(0.25)
+            .with_slots(&[0]);
+        let gradient = optimizer.step(&mut evaluator, &runner);
+        assert!((gradient[0] - 4.0).abs() < 1e-3);
+        assert_eq!(gradient[1], 0.0);
+        for _ in 0..20 {
+            optimizer.step(&mut evaluator, &runner);
+        }
+        assert!((optimizer.params()[0] - 2.0).abs() < 1e-3);
+        assert_eq!(optimizer.params()[1], 0.0);
+    }
+
+    #[test]
+    fn outputs() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(4, 2, 0);
+        let fitness = |_: &dyn Runner, _: &mut MemoryBuffer, _: &mut Pcg64| 0.0;
+        let compiler = Compiler::new(Interpreter::new());
+        let mut evaluator = Evaluator::new(compiler, layout, 32, mutate_bits, fitness);
+        let genome = Genome::new(7);
+        let runner = evaluator.compile(&genome);
+
+        let memory = evaluator.memory(&genome);
+        let gradients = output_gradients(&runner, &memory, Fixed::with_frac_bits(16), 0.5);
+        assert_eq!(gradients.len(), 4);
+        assert!(gradients.iter().all(|slot| slot.len() == 2));
+        assert!(gradients.iter().flatten().all(|g| g.is_finite()));
+        assert_eq!(memory, evaluator.memory(&genome));
+    }
+}
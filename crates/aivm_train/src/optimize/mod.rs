@@ -0,0 +1,20 @@
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+
+mod annealing;
+mod cma_es;
+mod es;
+mod gradient;
+
+pub use annealing::SimulatedAnnealing;
+pub use cma_es::CmaEs;
+pub use es::EvolutionStrategy;
+pub use gradient::{output_gradients, FiniteDifference};
+
+/// A sample of the standard normal distribution, using the Box-Muller transform.
+pub(crate) fn gaussian(rng: &mut Pcg64) -> f64 {
+    // 1 - x is in 0.0..1.0 exclusive of 0, so the logarithm is finite
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+}
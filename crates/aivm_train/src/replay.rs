@@ -0,0 +1,200 @@
+use crate::{
+    environment::{rollout_with, Environment, Rollout},
+    evolution::{Genome, MutateBits},
+    fitness::Evaluator,
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"AIVMTRCE";
+/// The version of the serialized format, which only changes when saved traces can no longer be
+/// loaded by an older version of this crate.
+const FORMAT_VERSION: u16 = 1;
+
+/// A single step of a recorded episode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// The input the code observed.
+    pub input: Vec<i64>,
+    /// The output of the code.
+    pub output: Vec<i64>,
+    /// The memory bank after the step.
+    pub memory: Vec<i64>,
+    /// The reward for the output.
+    pub reward: f64,
+}
+
+/// A recorded episode of an [Environment].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EpisodeTrace {
+    /// The memory bank at the start of the episode.
+    pub initial_memory: Vec<i64>,
+    /// Every step that was run, in order.
+    pub steps: Vec<Step>,
+}
+
+impl EpisodeTrace {
+    /// The sum of the rewards of every step.
+    pub fn reward(&self) -> f64 {
+        self.steps.iter().map(|step| step.reward).sum()
+    }
+}
+
+/// Everything a genome observed and did in a number of episodes, so its behavior can be
+/// replayed and inspected after a run without simulating it again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    /// The genome that was recorded.
+    pub genome: Genome,
+    /// The recorded episodes.
+    pub episodes: Vec<EpisodeTrace>,
+}
+
+impl Trace {
+    /// Record the episodes `evaluator` runs to evaluate `genome`, for example the best genome
+    /// of a run.
+    ///
+    /// Given an `rng` in the same state, the recorded episodes are the ones the genome was
+    /// scored on by [evaluate](Evaluator::evaluate), as long as the evaluator has no
+    /// [cache](Evaluator::with_cache) hit for it.
+    pub fn record<G, F, E, B>(
+        evaluator: &mut Evaluator<G, Rollout<F>, B>,
+        genome: &Genome,
+        episodes: u32,
+        rng: &mut Pcg64,
+    ) -> Self
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fn() -> E,
+        E: Environment,
+        B: MutateBits,
+    {
+        let runner = evaluator.compile(genome);
+        let mut memory = evaluator.memory(genome);
+        let initial = memory.snapshot();
+        let rollout = evaluator.fitness();
+        let episodes = (0..episodes)
+            .map(|_| {
+                memory.restore(&initial);
+                let mut trace = EpisodeTrace {
+                    initial_memory: memory.memory().to_vec(),
+                    steps: vec![],
+                };
+                let mut environment = rollout.new_environment();
+                rollout_with(
+                    &mut environment,
+                    &runner,
+                    &mut memory,
+                    rollout.max_steps(),
+                    rng,
+                    |memory, transition| {
+                        trace.steps.push(Step {
+                            input: memory.input().to_vec(),
+                            output: memory.output().to_vec(),
+                            memory: memory.memory().to_vec(),
+                            reward: transition.reward,
+                        })
+                    },
+                );
+                trace
+            })
+            .collect();
+
+        Self {
+            genome: genome.clone(),
+            episodes,
+        }
+    }
+
+    /// Write the trace to `writer` in a compact binary format, see [read_from](Self::read_from).
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        bincode::serialize_into(writer, self).map_err(invalid_data)
+    }
+
+    /// Read a trace that was written with [write_to](Self::write_to).
+    pub fn read_from(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a trace"));
+        }
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version > FORMAT_VERSION {
+            return Err(invalid_data(format!(
+                "trace saved in format version {version}, supported up to {FORMAT_VERSION}"
+            )));
+        }
+
+        bincode::deserialize_from(reader).map_err(invalid_data)
+    }
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{environment::Transition, evolution::fill_mutate_bits};
+    use aivm::{codegen::Interpreter, Compiler, MemoryLayout};
+    use rand::prelude::*;
+
+    /// Rewards the first output, for a random amount of steps.
+    struct Echo {
+        remaining: u32,
+    }
+
+    impl Environment for Echo {
+        fn reset(&mut self, rng: &mut Pcg64) {
+            self.remaining = rng.gen_range(1..5);
+        }
+
+        fn observe(&mut self, input: &mut [i64]) {
+            input[0] = i64::from(self.remaining);
+        }
+
+        fn act(&mut self, output: &[i64]) -> Transition {
+            self.remaining -= 1;
+            Transition {
+                reward: output[0] as f64,
+                done: self.remaining == 0,
+            }
+        }
+    }
+
+    #[test]
+    fn record() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 1000);
+        let layout = MemoryLayout::new(8, 1, 1);
+        let compiler = Compiler::new(Interpreter::new());
+        let rollout = Rollout::new(|| Echo { remaining: 0 }, 10);
+        let mut evaluator =
+            Evaluator::new(compiler, layout, 64, mutate_bits, rollout).with_episodes(3);
+
+        let genome = Genome::new(4);
+        let trace = Trace::record(&mut evaluator, &genome, 3, &mut Pcg64::seed_from_u64(2));
+        assert_eq!(trace.episodes.len(), 3);
+        let first = &trace.episodes[0];
+        assert_eq!(first.steps[0].input, [first.steps.len() as i64]);
+        assert_eq!(first.initial_memory, evaluator.memory(&genome).memory());
+
+        let fitness = evaluator.evaluate(&genome, &mut Pcg64::seed_from_u64(2));
+        let rewards: f64 = trace.episodes.iter().map(EpisodeTrace::reward).sum();
+        assert_eq!(fitness, rewards / 3.0);
+
+        let mut bytes = vec![];
+        trace.write_to(&mut bytes).unwrap();
+        assert_eq!(Trace::read_from(&bytes[..]).unwrap(), trace);
+        assert!(Trace::read_from(&bytes[1..]).is_err());
+    }
+}
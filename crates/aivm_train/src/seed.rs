@@ -0,0 +1,206 @@
+use rand::{RngCore, SeedableRng};
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A source of randomness of a training run, each with its own stream of a [RunSeed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stream {
+    /// The initial genomes and the breeding of a
+    /// [Population](crate::evolution::Population), see
+    /// [Population::new](crate::evolution::Population::new).
+    Population,
+    /// The bits genomes are mutated with, see
+    /// [fill_mutate_bits](crate::evolution::fill_mutate_bits).
+    MutateBits,
+    /// The episodes of an environment that are not driven by a population, such as the ones of
+    /// an optimizer.
+    Environment,
+    /// The order genomes are evaluated in, for example when spreading them over workers.
+    EvaluationOrder,
+    /// A stream for anything else, identified by a number of its own.
+    Custom(u64),
+}
+
+impl Stream {
+    fn index(self) -> u64 {
+        match self {
+            Self::Population => 0,
+            Self::MutateBits => 1,
+            Self::Environment => 2,
+            Self::EvaluationOrder => 3,
+            // Custom streams never overlap with the named ones
+            Self::Custom(index) => index.wrapping_add(1 << 32),
+        }
+    }
+}
+
+/// The single seed all randomness of a training run is derived from, so a run can be
+/// reproduced exactly from the seed recorded in its checkpoints and metrics.
+///
+/// Every [Stream] gets a seed of its own: stream number `k` (0 for the population, 1 for the
+/// mutate bits, 2 for the environment, 3 for the evaluation order and `2^32 + n` for custom
+/// stream `n`) gets the SplitMix64 output for the state `master + (k + 1) * 0x9E3779B97F4A7C15`.
+/// This never changes between versions, so streams stay independent of each other and of how
+/// many values are taken from the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RunSeed(pub u64);
+
+impl RunSeed {
+    /// A seed taken from the system clock, for runs that should differ every time. Record it
+    /// to reproduce the run later.
+    pub fn from_clock() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(splitmix64(now.as_nanos() as u64))
+    }
+
+    /// The seed of `stream`.
+    pub fn derive(self, stream: Stream) -> u64 {
+        splitmix64(
+            self.0
+                .wrapping_add(stream.index().wrapping_add(1).wrapping_mul(GAMMA)),
+        )
+    }
+
+    /// A random generator for `stream`.
+    pub fn rng(self, stream: Stream) -> Pcg64 {
+        Pcg64::seed_from_u64(self.derive(stream))
+    }
+
+    /// A splittable random generator for `stream`.
+    pub fn split_rng(self, stream: Stream) -> SplitRng {
+        SplitRng::new(self.derive(stream))
+    }
+}
+
+impl fmt::Display for RunSeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#018x}", self.0)
+    }
+}
+
+/// A random generator that splits off independent generators, and can be saved in a
+/// checkpoint to continue exactly where it left off.
+///
+/// Split `n` gets the seed `splitmix64(seed + n * 0x9E3779B97F4A7C15)`, the same derivation as
+/// the streams of a [RunSeed], so it only depends on how many splits came before it and not on
+/// how many values were drawn. Work that gets a split of its own, such as the evaluation of a
+/// genome, is therefore reproduced exactly when a run is resumed, whatever the order it is done
+/// in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitRng {
+    seed: u64,
+    splits: u64,
+    rng: Pcg64,
+}
+
+impl SplitRng {
+    /// Create a generator from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            splits: 0,
+            rng: Pcg64::seed_from_u64(seed),
+        }
+    }
+
+    /// The seed of the next split, for work that seeds its own generator.
+    pub fn split_seed(&mut self) -> u64 {
+        self.splits += 1;
+        splitmix64(self.seed.wrapping_add(self.splits.wrapping_mul(GAMMA)))
+    }
+
+    /// Split off a generator.
+    pub fn split(&mut self) -> Self {
+        Self::new(self.split_seed())
+    }
+
+    /// The amount of splits so far.
+    pub fn splits(&self) -> u64 {
+        self.splits
+    }
+
+    /// The generator values are drawn from, for the traits that take a [Pcg64].
+    pub fn rng(&mut self) -> &mut Pcg64 {
+        &mut self.rng
+    }
+}
+
+impl RngCore for SplitRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest);
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// The finalizer of SplitMix64, which maps every state to a well mixed output.
+pub(crate) fn splitmix64(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams() {
+        let seed = RunSeed(42);
+        // The derivation is part of the documented behavior, so it must never change
+        assert_eq!(seed.derive(Stream::Population), 0xBDD7_3226_2FEB_6E95);
+
+        let streams = [
+            Stream::Population,
+            Stream::MutateBits,
+            Stream::Environment,
+            Stream::EvaluationOrder,
+            Stream::Custom(0),
+            Stream::Custom(1),
+        ];
+        for (i, a) in streams.iter().enumerate() {
+            for b in &streams[i + 1..] {
+                assert_ne!(seed.derive(*a), seed.derive(*b));
+            }
+            assert_ne!(seed.derive(*a), RunSeed(43).derive(*a));
+        }
+        assert_eq!(seed.to_string(), "0x000000000000002a");
+    }
+
+    #[test]
+    fn split() {
+        let mut a = RunSeed(42).split_rng(Stream::Population);
+        let mut b = a.clone();
+        // Drawing values does not change the splits
+        b.next_u64();
+        assert_eq!(a.split_seed(), b.split_seed());
+        assert_ne!(a.split_seed(), a.split_seed());
+        assert_eq!(a.splits(), 3);
+
+        // A saved generator continues where it left off
+        let bytes = bincode::serialize(&a).unwrap();
+        let mut resumed: SplitRng = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(resumed.next_u64(), a.next_u64());
+        assert_eq!(resumed.split().next_u64(), a.split().next_u64());
+    }
+}
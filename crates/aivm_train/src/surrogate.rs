@@ -0,0 +1,211 @@
+use crate::{
+    evolution::{Genome, MutateBits},
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{codegen::CodeGenerator, Runner};
+use rand::SeedableRng;
+use rand_pcg::Pcg64;
+
+/// A cheap model of the fitness of genomes, used by [Screening] to skip the full evaluation
+/// of genomes it predicts to be poor.
+pub trait Surrogate {
+    /// Predict the fitness of `genome`, whose expanded code is `code`.
+    fn predict(&mut self, genome: &Genome, code: &[u64]) -> f64;
+
+    /// Learn from the true `fitness` of `genome`, whose expanded code is `code`.
+    fn calibrate(&mut self, genome: &Genome, code: &[u64], fitness: f64);
+}
+
+/// Pre-screens generations with a [Surrogate]: only the genomes with the highest predicted
+/// fitness are evaluated, the others get the lowest fitness of the evaluated genomes.
+///
+/// Every evaluated genome calibrates the surrogate. Every few generations, the whole generation
+/// is evaluated instead, which calibrates the surrogate on the genomes it would have skipped
+/// and measures how well it predicts. Call [evaluate](Self::evaluate) from the evaluation of
+/// [step_generation_with](crate::evolution::Population::step_generation_with).
+#[derive(Debug, Clone)]
+pub struct Screening<S> {
+    surrogate: S,
+    evaluated_share: f64,
+    calibration_interval: u64,
+    generations: u64,
+    skipped: u64,
+    calibration_error: Option<f64>,
+}
+
+impl<S: Surrogate> Screening<S> {
+    /// Evaluate the `evaluated_share` of every generation with the highest predicted fitness,
+    /// at least one genome, and every 10th generation all genomes.
+    ///
+    /// # Panics
+    /// If `evaluated_share` is not in `0.0..=1.0`.
+    pub fn new(surrogate: S, evaluated_share: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&evaluated_share),
+            "invalid evaluated share"
+        );
+        Self {
+            surrogate,
+            evaluated_share,
+            calibration_interval: 10,
+            generations: 0,
+            skipped: 0,
+            calibration_error: None,
+        }
+    }
+
+    /// The same screening, but evaluating all genomes every `interval` generations, starting
+    /// with the first.
+    ///
+    /// # Panics
+    /// If `interval` is 0.
+    pub fn with_calibration_interval(self, interval: u64) -> Self {
+        assert!(interval > 0, "calibration interval must be positive");
+        Self {
+            calibration_interval: interval,
+            ..self
+        }
+    }
+
+    /// The surrogate model.
+    pub fn surrogate(&self) -> &S {
+        &self.surrogate
+    }
+
+    /// The amount of genomes that were not evaluated so far.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// The mean absolute difference between the predicted and true fitness of the genomes of
+    /// the last generation that was evaluated in full, if any. NaN fitness is left out.
+    pub fn calibration_error(&self) -> Option<f64> {
+        self.calibration_error
+    }
+
+    /// The fitness of every genome in `genomes`, evaluated with `evaluator` and the random
+    /// generators seeded by `seeds`, or the lowest fitness of the evaluated genomes for the
+    /// ones that were skipped.
+    ///
+    /// # Panics
+    /// If `genomes` and `seeds` have different lengths.
+    pub fn evaluate<G, F, B>(
+        &mut self,
+        evaluator: &mut Evaluator<G, F, B>,
+        genomes: &[Genome],
+        seeds: &[u64],
+    ) -> Vec<f64>
+    where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        assert_eq!(genomes.len(), seeds.len(), "missing seeds");
+        let calibrating = self.generations.is_multiple_of(self.calibration_interval);
+        self.generations += 1;
+
+        let codes: Vec<_> = genomes
+            .iter()
+            .map(|genome| evaluator.code(genome))
+            .collect();
+        let predictions: Vec<_> = genomes
+            .iter()
+            .zip(&codes)
+            .map(|(genome, code)| self.surrogate.predict(genome, code))
+            .collect();
+
+        let mut ranking: Vec<_> = (0..genomes.len()).collect();
+        ranking.sort_by(|&a, &b| predictions[b].total_cmp(&predictions[a]));
+        let evaluated = if calibrating {
+            genomes.len()
+        } else {
+            ((genomes.len() as f64 * self.evaluated_share).ceil() as usize).clamp(1, genomes.len())
+        };
+        self.skipped += (genomes.len() - evaluated) as u64;
+
+        let mut fitness = vec![f64::NAN; genomes.len()];
+        for &i in &ranking[..evaluated] {
+            fitness[i] = evaluator.evaluate(&genomes[i], &mut Pcg64::seed_from_u64(seeds[i]));
+            self.surrogate.calibrate(&genomes[i], &codes[i], fitness[i]);
+        }
+
+        if calibrating {
+            let errors: Vec<_> = predictions
+                .iter()
+                .zip(&fitness)
+                .map(|(predicted, fitness)| (predicted - fitness).abs())
+                .filter(|error| !error.is_nan())
+                .collect();
+            self.calibration_error =
+                (!errors.is_empty()).then(|| errors.iter().sum::<f64>() / errors.len() as f64);
+        }
+
+        let lowest = ranking[..evaluated]
+            .iter()
+            .map(|&i| fitness[i])
+            .filter(|fitness| !fitness.is_nan())
+            .fold(f64::INFINITY, f64::min);
+        // Without a valid fitness to go by, skipped genomes are worse than any other
+        let lowest = if lowest == f64::INFINITY {
+            f64::NEG_INFINITY
+        } else {
+            lowest
+        };
+        for &i in &ranking[evaluated..] {
+            fitness[i] = lowest;
+        }
+        fitness
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::fill_mutate_bits;
+    use aivm::{codegen::Interpreter, Compiler, MemoryBuffer, MemoryLayout};
+
+    /// Predicts that genomes with higher root seeds are fitter, and remembers what it learned.
+    #[derive(Default)]
+    struct BySeed {
+        calibrations: Vec<(u64, f64)>,
+    }
+
+    impl Surrogate for BySeed {
+        fn predict(&mut self, genome: &Genome, _: &[u64]) -> f64 {
+            genome.root_seed as f64
+        }
+
+        fn calibrate(&mut self, genome: &Genome, _: &[u64], fitness: f64) {
+            self.calibrations.push((genome.root_seed, fitness));
+        }
+    }
+
+    #[test]
+    fn screening() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness =
+            |_: &dyn Runner, memory: &mut MemoryBuffer, _: &mut Pcg64| memory.memory()[0] as f64;
+        let compiler = Compiler::new(Interpreter::new());
+        let layout = MemoryLayout::new(4, 1, 0);
+        let mut evaluator = Evaluator::new(compiler, layout, 64, mutate_bits, fitness);
+
+        let genomes: Vec<_> = (0..8).map(Genome::new).collect();
+        let seeds = [0; 8];
+        let mut screening = Screening::new(BySeed::default(), 0.25).with_calibration_interval(2);
+
+        // The first generation is evaluated in full
+        let full = screening.evaluate(&mut evaluator, &genomes, &seeds);
+        assert_eq!(screening.skipped(), 0);
+        assert_eq!(screening.surrogate().calibrations.len(), 8);
+        assert!(screening.calibration_error().is_some());
+
+        let screened = screening.evaluate(&mut evaluator, &genomes, &seeds);
+        assert_eq!(screening.skipped(), 6);
+        assert_eq!(screened[6..], full[6..]);
+        let lowest = full[6].min(full[7]);
+        assert!(screened[..6].iter().all(|&fitness| fitness == lowest));
+    }
+}
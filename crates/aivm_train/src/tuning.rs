@@ -0,0 +1,377 @@
+use crate::{
+    evolution::{Genome, MutateBits},
+    fitness::{Evaluator, Fitness},
+};
+
+use aivm::{
+    codegen::{CodeGenerator, Coverage, Interpreter},
+    DefaultFrequencies, InstructionFrequencies, Runner, KIND_COUNT, KIND_NAMES,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The frequencies of all instruction kinds, indexed by kind in the order of
+/// [KIND_NAMES], checked to add up to 2^16 like the ones of [InstructionFrequencies].
+///
+/// A table is applied at runtime with [Evaluator::with_frequencies], or for a whole run with
+/// [TrainConfig::frequencies](crate::config::TrainConfig::frequencies), which compile code with
+/// [Compiler::compile_with_table](aivm::Compiler::compile_with_table).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u16>", into = "Vec<u16>")]
+pub struct FrequencyTable([u16; KIND_COUNT]);
+
+impl FrequencyTable {
+    /// Check that `frequencies` add up to 2^16 and that functions can end.
+    pub fn new(frequencies: [u16; KIND_COUNT]) -> Result<Self, FrequencyError> {
+        let sum = frequencies.iter().map(|&f| u32::from(f)).sum::<u32>();
+        if sum != 1 << 16 {
+            return Err(FrequencyError::Sum(sum));
+        }
+        if frequencies[0] == 0 {
+            return Err(FrequencyError::NoEndFunc);
+        }
+        Ok(Self(frequencies))
+    }
+
+    /// The table of the frequencies `F`.
+    ///
+    /// # Panics
+    /// If the frequencies of `F` are invalid.
+    pub fn of<F: InstructionFrequencies>() -> Self {
+        Self::new(F::table()).expect("invalid instruction frequencies")
+    }
+
+    /// The frequency of every kind, indexed by kind.
+    pub fn frequencies(&self) -> [u16; KIND_COUNT] {
+        self.0
+    }
+
+    /// The kind of instruction a code word decodes to with these frequencies, like
+    /// [kind_of](InstructionFrequencies::kind_of).
+    pub fn kind_of(&self, code_word: u64) -> usize {
+        let mut selector = code_word as u16;
+        for (kind, &frequency) in self.0.iter().enumerate() {
+            if selector < frequency {
+                return kind;
+            }
+            selector -= frequency;
+        }
+        unreachable!("frequencies add up to 2^16")
+    }
+}
+
+impl Default for FrequencyTable {
+    fn default() -> Self {
+        Self::of::<DefaultFrequencies>()
+    }
+}
+
+impl TryFrom<Vec<u16>> for FrequencyTable {
+    type Error = FrequencyError;
+
+    fn try_from(frequencies: Vec<u16>) -> Result<Self, FrequencyError> {
+        let len = frequencies.len();
+        let frequencies = frequencies
+            .try_into()
+            .map_err(|_| FrequencyError::Len(len))?;
+        Self::new(frequencies)
+    }
+}
+
+impl From<FrequencyTable> for Vec<u16> {
+    fn from(table: FrequencyTable) -> Self {
+        table.0.to_vec()
+    }
+}
+
+impl fmt::Display for FrequencyTable {
+    /// Write one line per kind with its name and frequency.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, frequency) in KIND_NAMES.iter().zip(self.0) {
+            writeln!(f, "{name}: {frequency}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returned when frequencies do not form a valid [FrequencyTable].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencyError {
+    /// The amount of frequencies is not the amount of instruction kinds.
+    Len(usize),
+    /// The frequencies add up to this sum instead of 2^16.
+    Sum(u32),
+    /// The frequency of `end_func` is 0, so code has only one function.
+    NoEndFunc,
+}
+
+impl fmt::Display for FrequencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Len(len) => write!(f, "got {len} frequencies instead of {KIND_COUNT}"),
+            Self::Sum(sum) => write!(f, "frequencies add up to {sum} instead of 65536"),
+            Self::NoEndFunc => write!(f, "the frequency of end_func must not be 0"),
+        }
+    }
+}
+
+impl std::error::Error for FrequencyError {}
+
+/// How many instructions of every kind were executed by the programs it was given, typically
+/// the champions of earlier runs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KindProfile {
+    counts: Vec<u64>,
+}
+
+impl Default for KindProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KindProfile {
+    /// Create a profile where nothing has been executed.
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; KIND_COUNT],
+        }
+    }
+
+    /// The amount of executed instructions of every kind, indexed by kind.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// The amount of executed instructions of all kinds.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Count the instructions of `code` executed according to `coverage`, decoded with
+    /// `table`. Every instruction counts once, however often it was executed.
+    pub fn record(&mut self, code: &[u64], coverage: &Coverage, table: &FrequencyTable) {
+        for i in coverage.executed().take_while(|&i| i < code.len()) {
+            self.counts[table.kind_of(code[i])] += 1;
+        }
+    }
+
+    /// Run the program of `genome` for `steps` steps from its initial memory without input,
+    /// and count the instructions it executed with the frequencies of `evaluator`.
+    ///
+    /// The program runs on an [Interpreter] with the settings of `evaluator`, whatever its
+    /// code generator, since only the interpreter records coverage.
+    pub fn record_genome<G, F, B>(
+        &mut self,
+        evaluator: &Evaluator<G, F, B>,
+        genome: &Genome,
+        steps: u32,
+    ) where
+        G: CodeGenerator + 'static,
+        G::Runner: Runner,
+        F: Fitness,
+        B: MutateBits,
+    {
+        let runner = evaluator.compile_with(Interpreter::new(), genome);
+        let mut memory = evaluator.memory(genome);
+        let mut coverage = Coverage::new();
+        for _ in 0..steps {
+            runner.step_with_coverage(memory.as_mut_slice(), &mut coverage);
+        }
+
+        self.record(&evaluator.code(genome), &coverage, &evaluator.frequencies());
+    }
+}
+
+/// Adjusts a [FrequencyTable] towards the instruction kinds that are executed by good
+/// programs, so the next run generates more of the instructions that proved useful.
+///
+/// The share of every kind moves from its share of the table towards its share of the
+/// executed instructions by the rate. Kinds with a frequency of 0 stay disabled, as they may
+/// need support such as host functions, and every other kind keeps at least the floor so it
+/// can still be discovered. `end_func` is never executed, so its frequency is kept as is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FrequencyTuner {
+    rate: f64,
+    floor: u16,
+}
+
+impl FrequencyTuner {
+    /// Move the shares of the kinds by `rate` towards the executed shares, with a floor of 64.
+    ///
+    /// # Panics
+    /// If `rate` is not in `0.0..=1.0`.
+    pub fn new(rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "invalid rate");
+        Self { rate, floor: 64 }
+    }
+
+    /// The same tuner, but keeping at least `floor` as the frequency of every enabled kind.
+    pub fn with_floor(self, floor: u16) -> Self {
+        Self { floor, ..self }
+    }
+
+    /// The table `table` adjusted to the executed instructions in `profile`, which was recorded
+    /// with `table`. Without executed instructions, the table is returned as is.
+    pub fn tune(&self, table: &FrequencyTable, profile: &KindProfile) -> FrequencyTable {
+        let frequencies = table.frequencies();
+        // Every kind but end_func that is enabled is tuned
+        let tuned: Vec<_> = (1..KIND_COUNT).filter(|&k| frequencies[k] > 0).collect();
+        let executed: u64 = tuned.iter().map(|&k| profile.counts()[k]).sum();
+        let budget = (1u32 << 16) - u32::from(frequencies[0]);
+        let floor = f64::from(self.floor);
+        if executed == 0 || floor * tuned.len() as f64 > f64::from(budget) {
+            return *table;
+        }
+
+        let table_total: u32 = tuned.iter().map(|&k| u32::from(frequencies[k])).sum();
+        let shares: Vec<_> = tuned
+            .iter()
+            .map(|&k| {
+                let current = f64::from(frequencies[k]) / f64::from(table_total);
+                let target = profile.counts()[k] as f64 / executed as f64;
+                current + self.rate * (target - current)
+            })
+            .collect();
+
+        // Kinds that would fall below the floor get the floor, the others divide what is left
+        // by share, until no more kinds fall below it
+        let mut floored = vec![false; tuned.len()];
+        let exact = loop {
+            let free_budget = f64::from(budget)
+                - floor * floored.iter().filter(|&&floored| floored).count() as f64;
+            let free_share: f64 = (0..tuned.len())
+                .filter(|&i| !floored[i])
+                .map(|i| shares[i])
+                .sum();
+            let exact: Vec<_> = (0..tuned.len())
+                .map(|i| {
+                    if floored[i] {
+                        floor
+                    } else {
+                        shares[i] / free_share * free_budget
+                    }
+                })
+                .collect();
+            let below: Vec<_> = (0..tuned.len())
+                .filter(|&i| !floored[i] && exact[i] < floor)
+                .collect();
+            if below.is_empty() {
+                break exact;
+            }
+            for i in below {
+                floored[i] = true;
+            }
+        };
+
+        // Round to whole frequencies that add up to the budget by largest remainder
+        let mut tuned_frequencies: Vec<_> = exact.iter().map(|e| e.floor() as u32).collect();
+        let mut missing = budget - tuned_frequencies.iter().sum::<u32>();
+        let mut by_remainder: Vec<_> = (0..tuned.len()).collect();
+        by_remainder.sort_by(|&a, &b| {
+            (exact[b] - exact[b].floor()).total_cmp(&(exact[a] - exact[a].floor()))
+        });
+        for &i in by_remainder.iter().cycle() {
+            if missing == 0 {
+                break;
+            }
+            tuned_frequencies[i] += 1;
+            missing -= 1;
+        }
+
+        let mut result = frequencies;
+        for (&k, frequency) in tuned.iter().zip(tuned_frequencies) {
+            result[k] = frequency as u16;
+        }
+        FrequencyTable::new(result).expect("tuned frequencies add up to 2^16")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::evolution::fill_mutate_bits;
+    use aivm::{codegen::NullGen, Compiler, MemoryBuffer, MemoryLayout};
+    use rand_pcg::Pcg64;
+
+    #[test]
+    fn table() {
+        let table = FrequencyTable::default();
+        assert_eq!(table.frequencies(), DefaultFrequencies::table());
+        for word in [0, 55, 1234, u64::MAX] {
+            assert_eq!(table.kind_of(word), DefaultFrequencies::kind_of(word));
+        }
+
+        assert_eq!(
+            FrequencyTable::try_from(vec![1; 3]),
+            Err(FrequencyError::Len(3))
+        );
+        let mut frequencies = table.frequencies();
+        frequencies[2] += 1;
+        assert_eq!(
+            FrequencyTable::new(frequencies),
+            Err(FrequencyError::Sum(65537))
+        );
+
+        let json = serde_json::to_string(&table).unwrap();
+        assert_eq!(
+            serde_json::from_str::<FrequencyTable>(&json).unwrap(),
+            table
+        );
+    }
+
+    #[test]
+    fn tune() {
+        let table = FrequencyTable::default();
+        let add = KIND_NAMES.iter().position(|&name| name == "add").unwrap();
+        let mut profile = KindProfile::new();
+        profile.counts[add] = 10;
+
+        let tuned = FrequencyTuner::new(0.5).tune(&table, &profile);
+        let (before, after) = (table.frequencies(), tuned.frequencies());
+        assert!(after[add] > before[add]);
+        assert_eq!(after[0], before[0]);
+        for kind in 1..KIND_COUNT {
+            // Disabled kinds stay disabled, the others keep the floor
+            assert_eq!(after[kind] == 0, before[kind] == 0);
+            assert!(kind == add || after[kind] <= before[kind]);
+            assert!(before[kind] == 0 || after[kind] >= 64);
+        }
+
+        assert_eq!(
+            FrequencyTuner::new(1.0).tune(&table, &KindProfile::new()),
+            table
+        );
+        assert_eq!(FrequencyTuner::new(0.0).tune(&table, &profile), table);
+    }
+
+    #[test]
+    fn record_genome() {
+        let mut mutate_bits = vec![0; 1024];
+        fill_mutate_bits(&mut mutate_bits, 5, 4000);
+        let fitness = |_: &dyn Runner, _: &mut MemoryBuffer, _: &mut Pcg64| 0.0;
+        let layout = MemoryLayout::new(4, 1, 0);
+        let compiler = Compiler::new(Interpreter::new());
+        let evaluator = Evaluator::new(compiler, layout, 64, mutate_bits.clone(), fitness);
+
+        let mut profile = KindProfile::new();
+        profile.record_genome(&evaluator, &Genome::new(3), 2);
+        assert!(profile.total() > 0);
+        assert_eq!(profile.counts()[0], 0);
+
+        // The next run generates code with the tuned table, on any code generator
+        let tuned = FrequencyTuner::new(1.0).tune(&evaluator.frequencies(), &profile);
+        let evaluator = Evaluator::new(
+            Compiler::new(NullGen::new()),
+            layout,
+            64,
+            mutate_bits,
+            fitness,
+        )
+        .with_frequencies(tuned);
+        let mut next = KindProfile::new();
+        next.record_genome(&evaluator, &Genome::new(3), 2);
+        assert!(next.total() > 0);
+        assert_ne!(next, profile);
+    }
+}